@@ -0,0 +1,48 @@
+import time
+from concurrent.futures import ThreadPoolExecutor
+
+from mistralrs import Runner, Which, ChatCompletionRequest, Architecture
+
+runner = Runner(
+    which=Which.Plain(
+        model_id="mistralai/Mistral-7B-Instruct-v0.1",
+        arch=Architecture.Mistral,
+    ),
+)
+
+
+def ask(prompt: str) -> str:
+    res = runner.send_chat_completion_request(
+        ChatCompletionRequest(
+            model="mistral",
+            messages=[{"role": "user", "content": prompt}],
+            max_tokens=128,
+            temperature=0.0,
+        )
+    )
+    return res.choices[0].message.content
+
+
+prompts = [
+    "Tell me a story about the Rust type system.",
+    "Explain the borrow checker in one paragraph.",
+]
+
+# Warm up, so that the timings do not include the first compilation of the kernels.
+ask(prompts[0])
+
+start = time.perf_counter()
+for prompt in prompts:
+    ask(prompt)
+serial = time.perf_counter() - start
+
+# The GIL is released while each thread waits, so the engine batches both requests.
+start = time.perf_counter()
+with ThreadPoolExecutor(max_workers=len(prompts)) as pool:
+    answers = list(pool.map(ask, prompts))
+concurrent = time.perf_counter() - start
+
+print(f"Serial: {serial:.2f}s, concurrent: {concurrent:.2f}s")
+assert concurrent < serial, "Requests from several threads did not overlap."
+for answer in answers:
+    print(answer)
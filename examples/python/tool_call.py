@@ -78,7 +78,7 @@ res = runner.send_chat_completion_request(
         top_p=0.1,
         temperature=0.1,
         tool_schemas=tools,
-        tool_choice=ToolChoice.Auto,
+        tool_choice=ToolChoice.Auto(),
     )
 )
 # print(res.choices[0].message)
@@ -109,7 +109,7 @@ if tool_called.name in functions:
             top_p=0.1,
             temperature=0.1,
             tool_schemas=tools,
-            tool_choice=ToolChoice.Auto,
+            tool_choice=ToolChoice.Auto(),
         )
     )
     # print(res.usage)
@@ -3,9 +3,10 @@ use clap::Parser;
 use cli_table::{format::Justify, print_stdout, Cell, CellStruct, Style, Table};
 use mistralrs_core::{
     initialize_logging, paged_attn_supported, Constraint, DefaultSchedulerMethod,
-    DeviceLayerMapMetadata, DeviceMapMetadata, Loader, LoaderBuilder, MemoryGpuConfig, MistralRs,
-    MistralRsBuilder, ModelDType, ModelSelected, NormalRequest, PagedAttentionConfig, Request,
-    RequestMessage, Response, SamplingParams, SchedulerConfig, TokenSource, Usage,
+    DeviceLayerMapMetadata, DeviceMapMetadata, GreedyTieBreak, Loader, LoaderBuilder,
+    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, ModelSelected, NormalRequest,
+    PagedAttentionConfig, Request, RequestMessage, Response, SamplingParams, SchedulerConfig,
+    TemperatureOrder, TokenSource, Usage,
 };
 use std::sync::Arc;
 use std::{fmt::Display, num::NonZeroUsize};
@@ -57,13 +58,30 @@ fn run_bench(
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
+        mirostat_tau: None,
+        mirostat_eta: 0.1,
+        dry_multiplier: None,
+        dry_base: 1.75,
+        dry_allowed_length: 2,
+        dry_sequence_breakers: Vec::new(),
+        temperature_order: TemperatureOrder::default(),
+        greedy_tie_break_by: GreedyTieBreak::default(),
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_penalty_range: 0,
         max_len: Some(n_gen),
+        min_len: None,
         stop_toks: None,
+        stop_token_ids: None,
+        stop_token_strings: None,
+        stop_token_seqs: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
+        suppress_special_tokens: false,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -81,6 +99,12 @@ fn run_bench(
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        early_exit_layer: None,
+        xlora_global_scaling: None,
+        include_usage: false,
+        token_healing: false,
+        truncate_prompt: false,
+        add_generation_prompt: true,
     });
 
     let mut usages = Vec::new();
@@ -112,6 +136,8 @@ fn run_bench(
                         usages.push(res.usage);
                     }
                     Response::CompletionChunk(_) => unreachable!(),
+                    Response::Tokenize(_) => unreachable!(),
+                    Response::Detokenize(_) => unreachable!(),
                 },
                 None => unreachable!("Expected a Done response, got None",),
             }
@@ -220,13 +246,30 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
+        mirostat_tau: None,
+        mirostat_eta: 0.1,
+        dry_multiplier: None,
+        dry_base: 1.75,
+        dry_allowed_length: 2,
+        dry_sequence_breakers: Vec::new(),
+        temperature_order: TemperatureOrder::default(),
+        greedy_tie_break_by: GreedyTieBreak::default(),
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_penalty_range: 0,
         max_len: Some(5),
+        min_len: None,
         stop_toks: None,
+        stop_token_ids: None,
+        stop_token_strings: None,
+        stop_token_seqs: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
+        suppress_special_tokens: false,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -248,6 +291,12 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        early_exit_layer: None,
+        xlora_global_scaling: None,
+        include_usage: false,
+        token_healing: false,
+        truncate_prompt: false,
+        add_generation_prompt: true,
     });
 
     sender
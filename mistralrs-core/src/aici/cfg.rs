@@ -7,7 +7,7 @@ use cfgrammar::{
 };
 use lrtable::{from_yacc, Action, Minimiser, StIdx, StateTable};
 use rustc_hash::FxHashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 use std::vec;
 use vob::{vob, Vob};
 
@@ -28,19 +28,26 @@ struct CfgStats {
     states_pushed: usize,
 }
 
-pub struct CfgParser {
+/// A compiled yacc grammar, which can be shared by the [`CfgParser`]s of many sequences.
+pub struct CfgGrammar {
     grm: YaccGrammar<StorageT>,
     stable: StateTable<StorageT>,
     lexer: Lexer,
-    byte_states: Vec<ByteState>,
     pat_idx_to_tidx: Vec<TIdx<u32>>,
     vobset: VobSet,
-    stats: RwLock<CfgStats>,
     tidx_to_pat_idx: FxHashMap<TIdx<u32>, usize>,
-    parse_stacks: Vec<Vec<StIdx<u32>>>,
     skip_patterns: Vob,
     friendly_pattern_names: Vec<String>,
     viable_vobidx_by_state: Vec<VobIdx>,
+    initial_byte_state: ByteState,
+    start_state: StIdx<StorageT>,
+}
+
+pub struct CfgParser {
+    grammar: Arc<CfgGrammar>,
+    byte_states: Vec<ByteState>,
+    stats: RwLock<CfgStats>,
+    parse_stacks: Vec<Vec<StIdx<u32>>>,
 }
 
 fn is_rx(name: &str) -> bool {
@@ -112,7 +119,7 @@ pub(crate) fn parse_yacc(yacc: &str) -> Result<YaccGrammar> {
     Ok(grm)
 }
 
-impl CfgParser {
+impl CfgGrammar {
     pub fn from_yacc(yacc: &str) -> Result<Self> {
         let grm = parse_yacc(yacc)?;
         // TIME: all these annotation are for native release x86 build for C grammar
@@ -196,7 +203,6 @@ impl CfgParser {
         let dfa = Lexer::from(patterns, &mut vobset);
 
         let cfg_start = stable.start_state();
-        let parse_stacks = vec![vec![cfg_start]];
 
         let byte_state = ByteState {
             lexer_state: dfa.file_start_state(),
@@ -227,47 +233,58 @@ impl CfgParser {
             })
             .collect::<Vec<_>>();
 
-        let mut cfg = CfgParser {
+        vobset.pre_compute();
+
+        let mut grammar = CfgGrammar {
             grm,
             stable,
             lexer: dfa,
-            byte_states: vec![byte_state],
             pat_idx_to_tidx,
             tidx_to_pat_idx,
             viable_vobidx_by_state,
             skip_patterns,
             friendly_pattern_names,
-            parse_stacks,
             vobset,
-            stats: RwLock::new(CfgStats {
-                yacc_actions: 0,
-                states_pushed: 0,
-            }),
+            initial_byte_state: byte_state,
+            start_state: cfg_start,
         };
 
-        cfg.vobset.pre_compute();
-
         // compute viable set of initial tokens
-        cfg.byte_states[0].viable = cfg.viable_vobidx(cfg_start);
+        grammar.initial_byte_state.viable = grammar.viable_vobidx(cfg_start);
         if LOG_PARSER {
             println!(
                 "initial viable: {:?}",
-                cfg.vobset.resolve(cfg.byte_states[0].viable)
+                grammar.vobset.resolve(grammar.initial_byte_state.viable)
             );
         }
 
-        Ok(cfg)
+        Ok(grammar)
     }
 
     fn viable_vobidx(&self, stidx: StIdx<StorageT>) -> VobIdx {
         self.viable_vobidx_by_state[stidx.as_storaget() as usize]
     }
+}
+
+impl CfgParser {
+    /// A parser at the start of `grammar`.
+    pub fn new(grammar: Arc<CfgGrammar>) -> Self {
+        Self {
+            byte_states: vec![grammar.initial_byte_state.clone()],
+            parse_stacks: vec![vec![grammar.start_state]],
+            grammar,
+            stats: RwLock::new(CfgStats {
+                yacc_actions: 0,
+                states_pushed: 0,
+            }),
+        }
+    }
 
     #[allow(dead_code)]
     fn friendly_token_name(&self, lexeme: TIdx<StorageT>) -> &str {
-        if let Some(pidx) = self.tidx_to_pat_idx.get(&lexeme) {
-            &self.friendly_pattern_names[*pidx]
-        } else if self.grm.eof_token_idx() == lexeme {
+        if let Some(pidx) = self.grammar.tidx_to_pat_idx.get(&lexeme) {
+            &self.grammar.friendly_pattern_names[*pidx]
+        } else if self.grammar.grm.eof_token_idx() == lexeme {
             return "<EOF>";
         } else {
             return "<???>";
@@ -278,7 +295,7 @@ impl CfgParser {
         loop {
             let stidx = *pstack.last().unwrap();
 
-            let act = self.stable.action(stidx, lexeme);
+            let act = self.grammar.stable.action(stidx, lexeme);
 
             if LOG_PARSER {
                 println!(
@@ -291,11 +308,11 @@ impl CfgParser {
 
             match act {
                 Action::Reduce(pidx) => {
-                    let ridx = self.grm.prod_to_rule(pidx);
-                    let pop_idx = pstack.len() - self.grm.prod(pidx).len();
+                    let ridx = self.grammar.grm.prod_to_rule(pidx);
+                    let pop_idx = pstack.len() - self.grammar.grm.prod(pidx).len();
                     pstack.drain(pop_idx..);
                     let prior = *pstack.last().unwrap();
-                    pstack.push(self.stable.goto(prior, ridx).unwrap());
+                    pstack.push(self.grammar.stable.goto(prior, ridx).unwrap());
                 }
                 Action::Shift(state_id) => {
                     pstack.push(state_id);
@@ -317,7 +334,7 @@ impl CfgParser {
         println!("viable tokens {}:", lbl);
         for (idx, b) in vob.iter().enumerate() {
             if b {
-                println!("  {}: {}", idx, self.friendly_pattern_names[idx]);
+                println!("  {}: {}", idx, self.grammar.friendly_pattern_names[idx]);
             }
         }
     }
@@ -334,7 +351,7 @@ impl CfgParser {
                 print!("<EOF>")
             }
         }
-        let (info, res) = match self.lexer.advance(top.lexer_state, byte) {
+        let (info, res) = match self.grammar.lexer.advance(top.lexer_state, byte) {
             // Error?
             None => ("lex-err", None),
             // Just new state, no token - the hot path
@@ -377,9 +394,9 @@ impl CfgParser {
             println!();
         }
         let pstack = self.pstack_for(top);
-        if self.skip_patterns[pat_idx] {
+        if self.grammar.skip_patterns[pat_idx] {
             let stidx = *pstack.last().unwrap();
-            let viable = self.viable_vobidx(stidx);
+            let viable = self.grammar.viable_vobidx(stidx);
             //self.print_viable("reset", &viable);
             if LOG_PARSER {
                 println!("parse: {:?} skip", pstack);
@@ -387,13 +404,13 @@ impl CfgParser {
             // reset viable states - they have been narrowed down to SKIP
             self.mk_byte_state(ls, top.parse_stack_idx, viable)
         } else {
-            let tidx = self.pat_idx_to_tidx[pat_idx];
+            let tidx = self.grammar.pat_idx_to_tidx[pat_idx];
             let mut pstack = pstack.clone();
             match self.parse_lexeme(tidx, &mut pstack) {
                 ParseResult::Accept => panic!("accept non EOF?"),
                 ParseResult::Continue => {
                     let stidx = *pstack.last().unwrap();
-                    let viable = self.viable_vobidx(stidx);
+                    let viable = self.grammar.viable_vobidx(stidx);
                     let new_idx = self.push_pstack(top, pstack);
                     self.mk_byte_state(ls, new_idx, viable)
                 }
@@ -405,7 +422,7 @@ impl CfgParser {
     #[allow(dead_code)]
     pub fn viable_now(&self) {
         let v = self.byte_states.last().unwrap().viable;
-        self.print_viable("now", self.vobset.resolve(v))
+        self.print_viable("now", self.grammar.vobset.resolve(v))
     }
 
     pub fn get_stats(&self) -> String {
@@ -426,13 +443,13 @@ impl CfgParser {
             let mut s = self.stats.write().unwrap();
             s.states_pushed += 1;
         }
-        if self.vobset.and_is_zero(viable, ls.reachable) {
+        if self.grammar.vobset.and_is_zero(viable, ls.reachable) {
             None
         } else {
             // print!(
             //     " {:?} {:?} ",
-            //     self.vobset.resolve(viable),
-            //     self.vobset.resolve(ls.reachable)
+            //     self.grammar.vobset.resolve(viable),
+            //     self.grammar.vobset.resolve(ls.reachable)
             // );
             Some(ByteState {
                 lexer_state: ls.state,
@@ -468,7 +485,7 @@ impl Recognizer for CfgParser {
         match tok {
             SpecialToken::EndOfSentence => {
                 if let Some(st) = self.try_push(None) {
-                    let tidx = self.grm.eof_token_idx();
+                    let tidx = self.grammar.grm.eof_token_idx();
                     let mut pstack = self.pstack_for(&st).clone();
                     matches!(self.parse_lexeme(tidx, &mut pstack), ParseResult::Accept)
                 } else {
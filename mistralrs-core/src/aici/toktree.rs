@@ -538,6 +538,20 @@ impl TokTrie {
         Some(n)
     }
 
+    /// The set of tokens whose bytes start with `prefix`, including a token equal to `prefix`.
+    pub fn tokens_with_prefix(&self, prefix: &[u8]) -> SimpleVob {
+        let mut r = self.alloc_token_set();
+        if let Some(n) = self.child_at_bytes(self.root(), prefix) {
+            for node in &self.nodes[self.node_offset(n)..self.next_node(n)] {
+                if let Some(tok) = node.token_id() {
+                    r.allow_token(tok);
+                }
+            }
+        }
+        self.apply_duplicates(&mut r);
+        r
+    }
+
     pub fn compute_bias(&self, r: &mut impl Recognizer, logits: &mut SimpleVob) {
         self.compute_bias_ext(r, logits, &[]);
     }
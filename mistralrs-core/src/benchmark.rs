@@ -0,0 +1,98 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::time::Duration;
+
+#[cfg(feature = "pyo3_macros")]
+use pyo3::{pyclass, pymethods};
+use serde::Serialize;
+
+use crate::Usage;
+
+#[derive(Debug, Clone)]
+/// Configuration of a synthetic benchmark run by [`MistralRs::benchmark`](crate::MistralRs::benchmark).
+pub struct BenchConfig {
+    /// Number of requests to enqueue. The scheduler runs at most `max_seqs` of them at once.
+    pub n_requests: usize,
+    /// Number of prompt tokens per request.
+    pub prompt_len: usize,
+    /// Number of tokens to generate per request. Generation may stop earlier at an EOS token
+    /// unless EOS stopping is disabled with `MistralRsBuilder::with_disable_eos_stop`.
+    pub gen_len: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            n_requests: 16,
+            prompt_len: 512,
+            gen_len: 128,
+        }
+    }
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Aggregated results of a benchmark.
+pub struct BenchStats {
+    pub n_requests: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Wall clock time from enqueueing the first request to receiving the last response.
+    pub total_time_sec: f32,
+    pub requests_per_sec: f32,
+    /// Prompt and completion tokens processed per second of wall clock time.
+    pub tok_per_sec: f32,
+    /// Completion tokens generated per second of wall clock time.
+    pub completion_tok_per_sec: f32,
+    /// Mean of the per-request prompt throughput.
+    pub avg_prompt_tok_per_sec: f32,
+    /// Mean of the per-request completion throughput.
+    pub avg_compl_tok_per_sec: f32,
+    pub latency_p50_sec: f32,
+    pub latency_p90_sec: f32,
+    pub latency_p99_sec: f32,
+}
+
+#[cfg(feature = "pyo3_macros")]
+#[pymethods]
+impl BenchStats {
+    fn __repr__(&self) -> String {
+        format!("{self:#?}")
+    }
+}
+
+impl BenchStats {
+    pub(crate) fn new(usages: &[Usage], elapsed: Duration) -> Self {
+        let n_requests = usages.len();
+        let prompt_tokens = usages.iter().map(|u| u.prompt_tokens).sum::<usize>();
+        let completion_tokens = usages.iter().map(|u| u.completion_tokens).sum::<usize>();
+        let total_time_sec = elapsed.as_secs_f32();
+
+        let mut latencies = usages.iter().map(|u| u.total_time_sec).collect::<Vec<_>>();
+        latencies.sort_by(|a, b| a.partial_cmp(b).expect("No ordering."));
+
+        Self {
+            n_requests,
+            prompt_tokens,
+            completion_tokens,
+            total_time_sec,
+            requests_per_sec: n_requests as f32 / total_time_sec,
+            tok_per_sec: (prompt_tokens + completion_tokens) as f32 / total_time_sec,
+            completion_tok_per_sec: completion_tokens as f32 / total_time_sec,
+            avg_prompt_tok_per_sec: usages.iter().map(|u| u.avg_prompt_tok_per_sec).sum::<f32>()
+                / n_requests as f32,
+            avg_compl_tok_per_sec: usages.iter().map(|u| u.avg_compl_tok_per_sec).sum::<f32>()
+                / n_requests as f32,
+            latency_p50_sec: percentile(&latencies, 0.5),
+            latency_p90_sec: percentile(&latencies, 0.9),
+            latency_p99_sec: percentile(&latencies, 0.99),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[f32], p: f32) -> f32 {
+    let rank = (p * sorted.len() as f32).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
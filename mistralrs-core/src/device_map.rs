@@ -1,11 +1,17 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, path::PathBuf};
 
-use crate::{utils::debug::DeviceRepr, TryIntoDType};
-use candle_core::{DType, Device, Result, Tensor};
+use crate::{
+    utils::{debug::DeviceRepr, memory_usage::MemoryUsage},
+    TryIntoDType,
+};
+use candle_core::{quantized::GgmlDType, DType, Device, Result, Tensor};
 use candle_nn::VarBuilder;
+use mistralrs_quant::IsqType;
 use serde::Deserialize;
 use tracing::info;
 
+const MIB: usize = 1024 * 1024;
+
 #[derive(Debug, Default, Deserialize, Clone)]
 pub struct DeviceLayerMapMetadata {
     pub ordinal: usize,
@@ -17,6 +23,8 @@ pub struct DeviceLayerMapMetadata {
 pub struct DeviceMapMetadata {
     device_layers: Option<Vec<DeviceLayerMapMetadata>>,
     host_layers: Option<usize>,
+    #[serde(default)]
+    auto: bool,
 }
 
 impl DeviceMapMetadata {
@@ -24,6 +32,7 @@ impl DeviceMapMetadata {
         Self {
             device_layers: Some(device_layers),
             host_layers: None,
+            auto: false,
         }
     }
     /// A device mapper to not map device.
@@ -31,16 +40,81 @@ impl DeviceMapMetadata {
         Self {
             device_layers: None,
             host_layers: None,
+            auto: false,
+        }
+    }
+    /// Fit as many layers as possible into the free memory of each visible CUDA device, in order of
+    /// ordinal, and put the remaining layers on the CPU. The split is chosen when the model is loaded.
+    pub fn auto() -> Self {
+        Self {
+            device_layers: None,
+            host_layers: None,
+            auto: true,
         }
     }
     pub fn is_dummy(&self) -> bool {
-        self.device_layers.is_none()
+        self.device_layers.is_none() && !self.auto
+    }
+    pub fn is_auto(&self) -> bool {
+        self.auto
+    }
+    /// Choose the split of an automatic device map, given the weight files of the model and the ISQ type
+    /// they will be quantized to. Other device maps are returned unchanged.
+    ///
+    /// The size of a layer is estimated by spreading the size of the weight files evenly over the
+    /// repeating layers.
+    pub(crate) fn resolve_auto(
+        self,
+        model_layers: usize,
+        weight_files: &[PathBuf],
+        in_situ_quant: Option<IsqType>,
+        device: &Device,
+    ) -> Result<Self> {
+        if !self.auto {
+            return Ok(self);
+        }
+        let device_free = match device {
+            Device::Cpu => {
+                info!("Automatic device mapping is not needed on the CPU.");
+                return Ok(Self::dummy());
+            }
+            Device::Cuda(_) => cuda_free_memory()?,
+            Device::Metal(_) => {
+                candle_core::bail!("Automatic device mapping is only supported on CUDA devices.")
+            }
+        };
+
+        let mut weights_size = 0;
+        for file in weight_files {
+            weights_size += usize::try_from(std::fs::metadata(file)?.len())?;
+        }
+        let layer_size = isq_size(weights_size, in_situ_quant)? / model_layers.max(1);
+        let host_free = MemoryUsage.get_memory_available(&Device::Cpu)?;
+
+        let (device_layers, host_layers) =
+            split_layers(&device_free, host_free, model_layers, layer_size)?;
+        info!(
+            "Automatic device mapping of {model_layers} layers of about {} MiB each: {}{host_layers} on the CPU.",
+            layer_size / MIB,
+            device_layers
+                .iter()
+                .map(|m| format!("{} on GPU {}, ", m.layers, m.ordinal))
+                .collect::<String>()
+        );
+        Ok(Self {
+            device_layers: Some(device_layers),
+            host_layers: Some(host_layers),
+            auto: false,
+        })
     }
     pub fn into_mapper(
         &self,
         model_layers: usize,
         device: &Device,
     ) -> Result<Box<dyn DeviceMapper + Send + Sync>> {
+        if self.auto {
+            candle_core::bail!("Automatic device mapping is not supported for this model.");
+        }
         // How many device layers
         // Clamp to max of model layers
         let n_device_layers = if let Some(layers) = &self.device_layers {
@@ -101,6 +175,70 @@ impl DeviceMapMetadata {
     }
 }
 
+/// The free memory of each visible CUDA device, by ordinal.
+#[cfg(feature = "cuda")]
+fn cuda_free_memory() -> Result<Vec<usize>> {
+    use candle_core::cuda_backend::WrapErr;
+
+    let count = candle_core::cuda::cudarc::driver::result::device::get_count().w()?;
+    (0..usize::try_from(count)?)
+        .map(|ordinal| {
+            // Creating the device makes its context current, which `mem_get_info` queries.
+            let device = Device::new_cuda(ordinal)?;
+            MemoryUsage.get_memory_available(&device)
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "cuda"))]
+fn cuda_free_memory() -> Result<Vec<usize>> {
+    candle_core::bail!("Cannot get memory available for CUDA devices")
+}
+
+/// The size of weights stored with 16 bits each after quantizing them to `isq`.
+fn isq_size(size: usize, isq: Option<IsqType>) -> Result<usize> {
+    let Some(isq) = isq else {
+        return Ok(size);
+    };
+    let (numerator, denominator) = match isq {
+        IsqType::HQQ8 | IsqType::Q8Ch => (1, 2),
+        IsqType::HQQ4 => (1, 4),
+        ggml => {
+            let dtype = GgmlDType::try_from(ggml)?;
+            (dtype.type_size(), dtype.block_size() * 2)
+        }
+    };
+    Ok(size / denominator * numerator)
+}
+
+/// Assign layers to the devices with `device_free` bytes of free memory in order, keeping a tenth of each
+/// free for activations and the KV cache, then put the rest on the host. Devices without layers are left out.
+fn split_layers(
+    device_free: &[usize],
+    host_free: usize,
+    model_layers: usize,
+    layer_size: usize,
+) -> Result<(Vec<DeviceLayerMapMetadata>, usize)> {
+    let mut remaining = model_layers;
+    let mut device_layers = Vec::new();
+    for (ordinal, free) in device_free.iter().enumerate() {
+        let usable = free - free / 10;
+        let layers = (usable / layer_size.max(1)).min(remaining);
+        if layers > 0 {
+            device_layers.push(DeviceLayerMapMetadata { ordinal, layers });
+            remaining -= layers;
+        }
+    }
+    if remaining * layer_size > host_free {
+        candle_core::bail!(
+            "Not enough memory to load the model: {remaining} of {model_layers} layers of about {} MiB each do not fit on the GPUs, and only {} MiB is free on the host.",
+            layer_size / MIB,
+            host_free / MIB
+        );
+    }
+    Ok((device_layers, remaining))
+}
+
 pub trait DeviceMapper: Debug {
     // === DURING RUNTIME ===
     /// Map during runtime
@@ -222,3 +360,41 @@ impl DeviceMapper for DummyDeviceMapper {
             .map_err(|e| candle_core::Error::Msg(format!("{e:?}")))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::split_layers;
+
+    const GIB: usize = 1024 * 1024 * 1024;
+
+    fn layers(device_free: &[usize], host_free: usize) -> (Vec<(usize, usize)>, usize) {
+        let (device_layers, host_layers) =
+            split_layers(device_free, host_free, 32, GIB / 2).unwrap();
+        (
+            device_layers
+                .into_iter()
+                .map(|m| (m.ordinal, m.layers))
+                .collect(),
+            host_layers,
+        )
+    }
+
+    #[test]
+    fn single_gpu_fits_whole_model() {
+        assert_eq!(layers(&[24 * GIB], 0), (vec![(0, 32)], 0));
+    }
+
+    #[test]
+    fn layers_spill_to_next_gpu_then_host() {
+        // 90% of 10 GiB holds 18 layers of 0.5 GiB.
+        assert_eq!(
+            layers(&[10 * GIB, 0, 4 * GIB], 8 * GIB),
+            (vec![(0, 18), (2, 7)], 7)
+        );
+    }
+
+    #[test]
+    fn insufficient_memory_is_an_error() {
+        assert!(split_layers(&[4 * GIB], 2 * GIB, 32, GIB / 2).is_err());
+    }
+}
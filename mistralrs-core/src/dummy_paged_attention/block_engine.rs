@@ -203,6 +203,16 @@ impl BlockEngine {
         }
     }
 
+    /// Number of GPU blocks of the KV cache.
+    pub fn num_gpu_blocks(&self) -> usize {
+        self.num_gpu_blocks
+    }
+
+    /// Number of GPU blocks which are not allocated to any sequence.
+    pub fn num_free_gpu_blocks(&self) -> usize {
+        *self.gpu_allocator.get_num_free_blocks()
+    }
+
     pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
         let num_required_blocks = seq.get_logical_token_blocks();
         let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
@@ -224,6 +234,37 @@ impl BlockEngine {
         self.block_tables.insert(seq.get_id(), block_table.clone());
     }
 
+    /// Give the sequence `child` the blocks of `parent` which hold its first `num_blocks` blocks of
+    /// tokens, so that it reuses their KV cache. All but the last of these blocks are shared. The
+    /// child writes to the last block, so it gets a new one and the returned `(src, dst)` copy
+    /// must be executed before the child runs.
+    ///
+    /// Returns `None` if `parent` has fewer blocks or there is no free block for the copy.
+    pub fn fork(
+        &mut self,
+        parent: SeqID,
+        child: SeqID,
+        num_blocks: usize,
+    ) -> Option<(usize, usize)> {
+        let parent_table = self.block_tables.get(&parent)?;
+        if num_blocks == 0
+            || parent_table.len() < num_blocks
+            || *self.gpu_allocator.get_num_free_blocks() == 0
+        {
+            return None;
+        }
+        let mut table = parent_table[..num_blocks - 1].to_vec();
+        for block in &table {
+            block.deref_mut().refcount += 1;
+        }
+        let src = parent_table[num_blocks - 1].deref_mut().block_id;
+        let last = self.gpu_allocator.allocate();
+        let dst = last.deref_mut().block_id;
+        table.push(last);
+        self.block_tables.insert(child, table);
+        Some((src, dst))
+    }
+
     pub fn can_append_token_to_seq(&self, seq: &impl BlockEngineSequence) -> bool {
         let free_blocks = self.gpu_allocator.get_num_free_blocks();
         // Physical blocks = logical blocks
@@ -376,3 +417,87 @@ impl BlockEngine {
             .collect::<HashMap<_, _>>()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockEngine, BlockEngineSequence};
+
+    struct Seq {
+        id: usize,
+        num_blocks: usize,
+    }
+
+    impl BlockEngineSequence for Seq {
+        fn blocks_to_add_new_tok(&self) -> usize {
+            0
+        }
+        fn get_id(&self) -> usize {
+            self.id
+        }
+        fn get_logical_token_blocks(&self) -> usize {
+            self.num_blocks
+        }
+    }
+
+    fn block_ids(engine: &BlockEngine, id: usize) -> Vec<usize> {
+        engine.block_tables[&id]
+            .iter()
+            .map(|block| block.deref_mut().block_id)
+            .collect()
+    }
+
+    #[test]
+    fn fork_shares_prompt_blocks() {
+        let mut engine = BlockEngine::new(16, 8, 0);
+        let leader = Seq {
+            id: 0,
+            num_blocks: 3,
+        };
+        engine.allocate(&leader);
+        let leader_blocks = block_ids(&engine, 0);
+
+        // A 40 token prompt fills 2 blocks and part of a 3rd.
+        let (src, dst) = engine.fork(0, 1, 3).unwrap();
+        let child_blocks = block_ids(&engine, 1);
+        assert_eq!(child_blocks[..2], leader_blocks[..2]);
+        assert_eq!(src, leader_blocks[2]);
+        assert_eq!(dst, child_blocks[2]);
+        assert!(!leader_blocks.contains(&dst));
+        // 3 blocks for the leader and 1 for the copy instead of 3 more for another prefill.
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 4);
+
+        // The shared blocks are only freed with their last sequence.
+        engine.free_sequence(0);
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 5);
+        engine.free_sequence(1);
+        assert_eq!(*engine.gpu_allocator.get_num_free_blocks(), 8);
+
+        assert!(engine.fork(0, 2, 1).is_none());
+    }
+
+    #[test]
+    fn free_blocks_follow_sequences() {
+        let mut engine = BlockEngine::new(16, 64, 0);
+        assert_eq!(engine.num_gpu_blocks(), 64);
+        assert_eq!(engine.num_free_gpu_blocks(), 64);
+
+        // A 600 token prompt takes 38 blocks.
+        let long = Seq {
+            id: 0,
+            num_blocks: 38,
+        };
+        engine.allocate(&long);
+        assert_eq!(engine.num_free_gpu_blocks(), 26);
+        engine.allocate(&Seq {
+            id: 1,
+            num_blocks: 2,
+        });
+        assert_eq!(engine.num_free_gpu_blocks(), 24);
+
+        engine.free_sequence(0);
+        assert_eq!(engine.num_free_gpu_blocks(), 62);
+        engine.free_sequence(1);
+        assert_eq!(engine.num_free_gpu_blocks(), 64);
+        assert_eq!(engine.num_gpu_blocks(), 64);
+    }
+}
@@ -53,6 +53,8 @@ pub enum MemoryGpuConfig {
     Amount(usize),
     Utilization(f32),
     ContextSize(usize),
+    /// Allocate exactly enough blocks to hold this many KV tokens in total across all sequences.
+    NumTokens(usize),
 }
 
 // See `pagedattention.cu` CALL_V1_LAUNCHER_BLOCK_SIZE
@@ -14,7 +14,7 @@ use std::{
     sync::{atomic::Ordering, Arc, Mutex},
 };
 
-use tracing::warn;
+use tracing::{info, warn};
 
 use crate::{
     get_mut_arcmutex,
@@ -45,6 +45,8 @@ pub struct PagedAttentionScheduler {
     config: PagedAttentionSchedulerConfig,
     pub block_engine: BlockEngine,
     block_size: usize,
+    /// Copies of the blocks of sequences which share a prefill, executed with the next step.
+    pending_blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
 }
 
 impl PagedAttentionScheduler {
@@ -60,6 +62,7 @@ impl PagedAttentionScheduler {
                 cache_config.num_cpu_blocks,
             ),
             block_size: cache_config.block_size,
+            pending_blocks_to_copy: HashMap::new(),
         }
     }
 
@@ -111,7 +114,7 @@ impl PagedAttentionScheduler {
                 return PagedAttentionSchedulerOutput {
                     scheduled: scheduled.into(),
                     blocks_to_swap_in: HashMap::new(),
-                    blocks_to_copy: HashMap::new(),
+                    blocks_to_copy: std::mem::take(&mut self.pending_blocks_to_copy),
                     blocks_to_swap_out: HashMap::new(),
                 };
             }
@@ -119,7 +122,7 @@ impl PagedAttentionScheduler {
 
         let mut blocks_to_swap_out = HashMap::new();
         let mut blocks_to_swap_in = HashMap::new();
-        let mut blocks_to_copy = HashMap::new();
+        let mut blocks_to_copy = std::mem::take(&mut self.pending_blocks_to_copy);
 
         // Reserve token slots for the running sequence groups, preempting the lowest (earliest) first.
         // Preempt lowest priority sequences that are in the running queue, forming a
@@ -339,6 +342,31 @@ impl Scheduler for PagedAttentionScheduler {
     fn add_seq(&mut self, seq: Sequence) {
         self.waiting.push_back(Arc::new(Mutex::new(seq)));
     }
+    fn add_shared_prefill_seq(&mut self, mut seq: Sequence, leader: &Sequence) {
+        // The blocks holding the prompt. The last prompt token is computed again by the first
+        // completion step, so its block is copied rather than shared.
+        let num_blocks = seq.get_toks().len().div_ceil(self.block_size);
+        match self
+            .block_engine
+            .fork(leader.get_id(), seq.get_id(), num_blocks)
+        {
+            Some((src, dst)) => {
+                self.pending_blocks_to_copy
+                    .entry(src)
+                    .or_default()
+                    .push(dst);
+                seq.share_paged_prefill(leader);
+                self.running.push_back(Arc::new(Mutex::new(seq)));
+            }
+            None => {
+                info!(
+                    "Could not share the prefill of sequence {}, running a separate prefill.",
+                    leader.get_id()
+                );
+                self.add_seq(seq);
+            }
+        }
+    }
     fn schedule(&mut self) -> SchedulerOutput<'_> {
         SchedulerOutput::PagedAttention {
             output: self.schedule(),
@@ -347,6 +375,9 @@ impl Scheduler for PagedAttentionScheduler {
     fn waiting_len(&self) -> usize {
         self.waiting.len()
     }
+    fn running_len(&self) -> usize {
+        self.running.len()
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         Some(&self.block_engine.block_tables)
     }
@@ -356,6 +387,25 @@ impl Scheduler for PagedAttentionScheduler {
     fn free_finished_sequence_groups(&mut self) {
         self.free_finished_sequence_groups()
     }
+    fn seq_mut(&mut self, _id: usize) -> Option<&mut Sequence> {
+        None
+    }
+    fn cancel_request(&mut self, request_id: usize) -> bool {
+        let mut found = false;
+        for seq in self
+            .running
+            .iter()
+            .chain(self.waiting.iter())
+            .chain(self.swapped_out.iter())
+        {
+            let mut seq = get_mut_arcmutex!(seq);
+            if seq.request_id() == request_id {
+                seq.cancel();
+                found = true;
+            }
+        }
+        found
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+
+/// Default number of compiled grammars of each kind kept by the engine.
+pub(crate) const GRAMMAR_CACHE_SIZE: usize = 32;
+
+/// A least-recently-used cache of compiled grammars, keyed by the grammar source.
+///
+/// The cache is owned by the engine and only used from the engine thread, so it needs no locking.
+pub(crate) struct GrammarCache<V: Clone> {
+    capacity: usize,
+    // Most recently used first.
+    entries: VecDeque<(String, V)>,
+}
+
+impl<V: Clone> GrammarCache<V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Return the compiled grammar for `source`, only calling `build` if it is not cached.
+    pub(crate) fn get_or_build<E>(
+        &mut self,
+        source: &str,
+        build: impl FnOnce(&str) -> Result<V, E>,
+    ) -> Result<V, E> {
+        if let Some(pos) = self.entries.iter().position(|(key, _)| key == source) {
+            let entry = self.entries.remove(pos).expect("Position is in bounds.");
+            let value = entry.1.clone();
+            self.entries.push_front(entry);
+            return Ok(value);
+        }
+        let value = build(source)?;
+        if self.capacity > 0 {
+            if self.entries.len() == self.capacity {
+                self.entries.pop_back();
+            }
+            self.entries.push_front((source.to_string(), value.clone()));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GrammarCache;
+    use crate::{aici::rx::RecRx, json_schema::json_schema_to_regex};
+
+    #[test]
+    fn reuses_and_evicts_least_recently_used() {
+        let mut cache = GrammarCache::new(2);
+        let mut builds = 0;
+        let mut get = |cache: &mut GrammarCache<usize>, source: &str| {
+            cache
+                .get_or_build(source, |s| {
+                    builds += 1;
+                    Ok::<_, ()>(s.len())
+                })
+                .unwrap()
+        };
+
+        assert_eq!(get(&mut cache, "a"), 1);
+        assert_eq!(get(&mut cache, "a"), 1);
+        assert_eq!(get(&mut cache, "bb"), 2);
+        // `a` was used more recently than `bb`, so `bb` is evicted.
+        assert_eq!(get(&mut cache, "a"), 1);
+        assert_eq!(get(&mut cache, "ccc"), 3);
+        assert_eq!(get(&mut cache, "a"), 1);
+        assert_eq!(get(&mut cache, "bb"), 2);
+        assert_eq!(builds, 4);
+    }
+
+    #[test]
+    fn repeated_regex_skips_compilation() {
+        let rx = json_schema_to_regex("{}").unwrap();
+        let mut cache = GrammarCache::new(1);
+        let mut compilations = 0;
+        for _ in 0..2 {
+            cache
+                .get_or_build(&rx, |rx| {
+                    compilations += 1;
+                    RecRx::from_rx(rx, None)
+                })
+                .unwrap();
+        }
+        assert_eq!(compilations, 1);
+    }
+}
@@ -1,43 +1,94 @@
+mod grammar_cache;
+mod throttle;
+
+use grammar_cache::{GrammarCache, GRAMMAR_CACHE_SIZE};
 use std::{
+    collections::HashSet,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
+use throttle::Throttle;
 use tokio::sync::{mpsc::Receiver, Mutex};
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
+    aici::{
+        cfg::{CfgGrammar, CfgParser},
+        recognizer::StackRecognizer,
+        rx::RecRx,
+        toktree::TokTrie,
+    },
     pipeline::{
         text_models_inputs_processor::PagedAttentionMeta, AdapterInstruction, CacheBackendMetadata,
-        CacheInstruction,
+        CacheInstruction, DryPenalty, ModelCategory, ModelKind, RollingCacheConfig,
     },
-    request::NormalRequest,
-    response::CompletionChoice,
+    request::{EmbeddingPooling, NormalRequest},
+    response::{CompletionChoice, EmbeddingResponse, RawForwardResponse},
     scheduler::{Scheduler, SchedulerOutput},
-    tools::{ToolCallingMatcher, ToolChoice},
+    tools::{forced_tool_call_schema, ToolCallingMatcher, ToolChoice},
+    utils::images::limit_image_edge,
     CompletionResponse, RequestMessage, Response, SchedulerConfig, DEBUG,
 };
+use anyhow::{bail, Context};
+use candle_core::DType;
+use mistralrs_quant::ImatrixData;
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
 use tracing::{info, warn};
 
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
+    json_schema::json_schema_to_regex,
     pipeline::Pipeline,
     prefix_cacher::PrefixCacheManager,
     request::Request,
-    response::{ChatCompletionResponse, Choice, ResponseMessage},
-    sampler::Sampler,
-    sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
-    Constraint, StopTokens,
+    response::{
+        CacheUsageResponse, ChatCompletionResponse, Choice, ModelInfoResponse, PingResponse,
+        ResponseMessage,
+    },
+    sampler::{special_tokens_to_suppress, Sampler, SamplingParams, DEFAULT_TEMPERATURE_FLOOR},
+    seq_state::SeqState,
+    sequence::{heal_prompt, Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
+    Constraint, FimTokens, StopTokens,
 };
 
+/// Default seed of the sampling RNG shared by all sequences.
 const SEED: u64 = 0;
+/// Maximum number of stop sequences per request.
+const MAX_STOP_SEQS: usize = 16;
 /// Terminate all sequences on the next scheduling step. Be sure to reset this.
 pub static TERMINATE_ALL_NEXT_STEP: AtomicBool = AtomicBool::new(false);
 
+/// Describes a request whose prompt, together with the requested number of new tokens,
+/// does not fit in the model's maximum sequence length.
+#[derive(Clone, Debug)]
+pub struct ContextOverflow {
+    pub request_id: usize,
+    pub prompt_tokens: usize,
+    pub max_new_tokens: Option<usize>,
+    pub max_seq_len: usize,
+}
+
+/// What to do with a request which overflows the context window.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContextOverflowAction {
+    /// Use the engine's default behavior: error if the prompt and the requested number of
+    /// new tokens exceed the maximum sequence length, unless `truncate_sequence` or the
+    /// request's `truncate_prompt` is set.
+    Default,
+    /// Drop tokens from the start of the prompt to make space for generation.
+    Truncate,
+    /// Reject the request with the given message.
+    Reject(String),
+}
+
+/// Called when a request overflows the context window, for example to let the client
+/// summarize the conversation instead of truncating it.
+pub type ContextOverflowHandler =
+    Arc<dyn Fn(ContextOverflow) -> ContextOverflowAction + Send + Sync>;
+
 pub struct Engine {
     rx: Receiver<Request>,
     pipeline: Arc<Mutex<dyn Pipeline>>,
@@ -49,6 +100,18 @@ pub struct Engine {
     is_debug: bool,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    nan_checks: bool,
+    context_overflow_handler: Option<ContextOverflowHandler>,
+    regex_cache: GrammarCache<RecRx>,
+    yacc_cache: GrammarCache<Arc<CfgGrammar>>,
+    /// The compiled grammars only depend on bytes, not on tokens, but check that they are always used
+    /// with the same tokenizer.
+    grammar_tok_trie: Arc<TokTrie>,
+    seed: u64,
+    throttle: Option<Throttle>,
+    temperature_floor: f64,
+    fim_tokens: Option<FimTokens>,
+    rolling_cache: Option<RollingCacheConfig>,
 }
 
 impl Engine {
@@ -65,6 +128,7 @@ impl Engine {
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
+        let tok_trie = get_mut_arcmutex!(pipeline).get_metadata().tok_trie.clone();
         // Prefix caching is always disabled if using PagedAttention for now.
         // TODO
         let no_prefix_cache =
@@ -85,6 +149,16 @@ impl Engine {
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled: false,
+            nan_checks: false,
+            context_overflow_handler: None,
+            regex_cache: GrammarCache::new(GRAMMAR_CACHE_SIZE),
+            yacc_cache: GrammarCache::new(GRAMMAR_CACHE_SIZE),
+            grammar_tok_trie: tok_trie,
+            seed: SEED,
+            throttle: None,
+            temperature_floor: DEFAULT_TEMPERATURE_FLOOR,
+            fim_tokens: None,
+            rolling_cache: None,
         }
     }
 
@@ -94,8 +168,52 @@ impl Engine {
         self.throughput_logging_enabled = true;
     }
 
+    /// Fail the requests of a step whose logits are not all finite, naming the step and sequence.
+    pub fn enable_nan_checks(&mut self) {
+        self.nan_checks = true;
+    }
+
+    /// Set the handler consulted when a request overflows the context window.
+    pub fn set_context_overflow_handler(&mut self, handler: ContextOverflowHandler) {
+        self.context_overflow_handler = Some(handler);
+    }
+
+    /// Set how many compiled regex and yacc grammars are kept, each, for reuse by later requests.
+    pub fn set_grammar_cache_size(&mut self, size: usize) {
+        self.regex_cache = GrammarCache::new(size);
+        self.yacc_cache = GrammarCache::new(size);
+    }
+
+    /// Set the seed of the sampling RNG shared by all sequences.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// Keep the aggregate number of generated tokens per second under `max_tokens_per_second`, by
+    /// sleeping between steps. This is a soft cap, measured over a sliding window of one second.
+    pub fn set_max_tokens_per_second(&mut self, max_tokens_per_second: f32) {
+        self.throttle = Some(Throttle::new(max_tokens_per_second));
+    }
+
+    /// Sample greedily when the temperature of a request is at or below `temperature_floor`.
+    pub fn set_temperature_floor(&mut self, temperature_floor: f64) {
+        self.temperature_floor = temperature_floor;
+    }
+
+    /// Lay out fill-in-the-middle prompts with `fim_tokens`, instead of the known FIM tokens found
+    /// in the tokenizer.
+    pub fn set_fim_tokens(&mut self, fim_tokens: FimTokens) {
+        self.fim_tokens = Some(fim_tokens);
+    }
+
+    /// Evict the middle of the context of sequences which reach the model's maximum sequence length,
+    /// instead of stopping them.
+    pub fn set_rolling_cache(&mut self, rolling_cache: RollingCacheConfig) {
+        self.rolling_cache = Some(rolling_cache);
+    }
+
     pub async fn run(&mut self) {
-        let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(SEED)));
+        let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(self.seed)));
         let mut last_completion_ids: Vec<usize> = vec![];
         'lp: loop {
             while let Ok(request) = self.rx.try_recv() {
@@ -103,6 +221,13 @@ impl Engine {
             }
             let run_start = Instant::now();
             let scheduled = self.scheduler.schedule();
+            // Each scheduled sequence generates one token, also in its prompt step.
+            let n_generated = match &scheduled {
+                SchedulerOutput::DefaultScheduler { output } => {
+                    output.prompt.len() + output.completion.len()
+                }
+                SchedulerOutput::PagedAttention { output } => output.scheduled.len(),
+            };
 
             match scheduled {
                 SchedulerOutput::DefaultScheduler {
@@ -110,6 +235,7 @@ impl Engine {
                 } => {
                     let mut prompt_ts = None;
                     let mut completion_ts = None;
+                    let mut shared_prefill_followers = Vec::new();
                     if scheduled.completion.len() > 0 {
                         let throughput_start = Instant::now();
                         let current_completion_ids: Vec<usize> =
@@ -148,6 +274,7 @@ impl Engine {
                                     false,
                                     &mut self.prefix_cacher,
                                     self.disable_eos_stop,
+                                    self.nan_checks,
                                     rng.clone(),
                                     CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
                                 )
@@ -196,22 +323,26 @@ impl Engine {
                                 .map(AdapterInstruction::Activate)
                                 .unwrap_or(AdapterInstruction::None);
 
-                            // Reset non granular state because the old sequence must be dead.
-                            // Technically we don't need to do this but it is better to be safe.
+                            // A rolled context continues from the KV cache which it kept.
+                            let pre_op = if scheduled.prompt[0].rolled_cache_len().is_some() {
+                                CacheInstruction::In(adapter_inst)
+                            } else {
+                                // Reset non granular state because the old sequence must be dead.
+                                // Technically we don't need to do this but it is better to be safe.
+                                CacheInstruction::Reset {
+                                    reset_non_granular: false,
+                                    adapter_inst,
+                                }
+                            };
                             pipeline
                                 .step(
                                     &mut scheduled.prompt,
                                     true,
                                     &mut self.prefix_cacher,
                                     self.disable_eos_stop,
+                                    self.nan_checks,
                                     rng.clone(),
-                                    CacheBackendMetadata::DefaultInstructions {
-                                        pre_op: CacheInstruction::Reset {
-                                            reset_non_granular: false,
-                                            adapter_inst,
-                                        },
-                                        post_op,
-                                    },
+                                    CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
                                 )
                                 .await
                         };
@@ -242,6 +373,10 @@ impl Engine {
 
                         for seq in scheduled.prompt.iter_mut() {
                             seq.set_state(SequenceState::RunningCompletion);
+                            // The prompt step of a rolled context is not the request's prompt.
+                            if seq.prompt_timestamp.is_some() {
+                                continue;
+                            }
                             let now = SystemTime::now()
                                 .duration_since(UNIX_EPOCH)
                                 .expect("Time travel has occurred!")
@@ -252,6 +387,16 @@ impl Engine {
                             seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                             seq.prompt_timestamp = Some(now);
                         }
+                        for seq in scheduled.prompt.iter_mut() {
+                            for mut follower in seq.take_shared_prefill_followers() {
+                                match follower.share_prefill(seq) {
+                                    Ok(true) => (),
+                                    Ok(false) => info!("Could not share the prefill of sequence {}, running a separate prefill.", seq.id()),
+                                    Err(e) => warn!("Sharing the prefill of sequence {} failed, running a separate prefill: {e}", seq.id()),
+                                }
+                                shared_prefill_followers.push(follower);
+                            }
+                        }
                         last_completion_ids = vec![];
                     }
 
@@ -306,6 +451,10 @@ impl Engine {
                             self.handle_request(request).await;
                         }
                     }
+
+                    for seq in shared_prefill_followers {
+                        self.scheduler.add_seq(seq);
+                    }
                 }
                 SchedulerOutput::PagedAttention { mut output } => {
                     if !output.scheduled.is_empty() {
@@ -339,6 +488,7 @@ impl Engine {
                                     is_prompt,
                                     &mut self.prefix_cacher,
                                     self.disable_eos_stop,
+                                    self.nan_checks,
                                     rng.clone(),
                                     CacheBackendMetadata::PagedAttention {
                                         metadata,
@@ -401,6 +551,10 @@ impl Engine {
 
                         if is_prompt {
                             for mut seq in guards {
+                                // The prompt step of a rolled context is not the request's prompt.
+                                if seq.prompt_timestamp.is_some() {
+                                    continue;
+                                }
                                 let now = SystemTime::now()
                                     .duration_since(UNIX_EPOCH)
                                     .expect("Time travel has occurred!")
@@ -410,6 +564,9 @@ impl Engine {
                                     seq.len() as f32 / (now - seq.timestamp()) as f32;
                                 seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                                 seq.prompt_timestamp = Some(now);
+                                for follower in seq.take_shared_prefill_followers() {
+                                    self.scheduler.add_shared_prefill_seq(follower, &seq);
+                                }
                             }
                         }
                     }
@@ -417,15 +574,47 @@ impl Engine {
             }
 
             self.scheduler.free_finished_sequence_groups();
+
+            if let Some(throttle) = &mut self.throttle {
+                if let Some(delay) = throttle.record(run_start, Instant::now(), n_generated) {
+                    tokio::time::sleep(delay).await;
+                }
+            }
         }
     }
 
-    fn build_sequence_recognizer(constraint: &Constraint) -> anyhow::Result<SequenceRecognizer> {
+    fn build_sequence_recognizer(
+        &mut self,
+        constraint: &Constraint,
+    ) -> anyhow::Result<SequenceRecognizer> {
+        assert!(
+            Arc::ptr_eq(
+                &self.grammar_tok_trie,
+                &get_mut_arcmutex!(self.pipeline).get_metadata().tok_trie
+            ),
+            "The tokenizer changed while compiled grammars are cached."
+        );
         let recognizer = match constraint {
             Constraint::Regex(rx) => {
-                SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx, None)?).into())
+                // Building the DFA is the expensive part, so reuse it for repeated regexes.
+                let rx = self
+                    .regex_cache
+                    .get_or_build(rx, |rx| RecRx::from_rx(rx, None))?;
+                SequenceRecognizer::Regex(StackRecognizer::from(rx).into())
+            }
+            Constraint::JsonSchema(schema) => {
+                let rx = json_schema_to_regex(schema)?;
+                let rx = self
+                    .regex_cache
+                    .get_or_build(&rx, |rx| RecRx::from_rx(rx, None))?;
+                SequenceRecognizer::Regex(StackRecognizer::from(rx).into())
+            }
+            Constraint::Yacc(cfg) => {
+                let grammar = self
+                    .yacc_cache
+                    .get_or_build(cfg, |cfg| CfgGrammar::from_yacc(cfg).map(Arc::new))?;
+                SequenceRecognizer::Cfg(CfgParser::new(grammar).into())
             }
-            Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
@@ -439,16 +628,333 @@ impl Engine {
                     Err(e) => warn!("Adapter activation failed: {e:?}"),
                 }
             }
+            Request::ActivateAdaptersWeighted(adapters) => {
+                match get_mut_arcmutex!(self.pipeline).activate_adapters_weighted(adapters) {
+                    Ok(n) => info!("Swapped adapters in {n} LoRA layers."),
+                    Err(e) => warn!("Adapter activation failed: {e:?}"),
+                }
+            }
+            Request::GetAdapters(sender) => {
+                let adapters = get_mut_arcmutex!(self.pipeline).adapters();
+                if sender.send(adapters).await.is_err() {
+                    warn!("Adapters sender was dropped before the engine could respond.");
+                }
+            }
+            Request::LoadAdapter {
+                name,
+                path_or_hf_id,
+                response,
+            } => {
+                let result = {
+                    let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                    pipeline
+                        .load_adapter(name, path_or_hf_id)
+                        .map(|_| pipeline.adapters())
+                };
+                if response.send(result).await.is_err() {
+                    warn!("Load adapter sender was dropped before the engine could respond.");
+                }
+            }
             Request::Normal(request) => self.add_request(request).await,
-            Request::ReIsq(level) => {
-                if let Err(e) = get_mut_arcmutex!(self.pipeline).re_isq_model(level) {
+            Request::ModelInfo(sender) => {
+                let response = {
+                    let pipeline = get_mut_arcmutex!(self.pipeline);
+                    let metadata = pipeline.get_metadata();
+                    ModelInfoResponse {
+                        max_seq_len: metadata.max_seq_len,
+                        num_hidden_layers: metadata.num_hidden_layers,
+                        vocab_size: pipeline.tokenizer().get_vocab_size(true),
+                        eos_tokens: metadata.eos_tok.clone(),
+                        kind: metadata.kind.to_string(),
+                        is_vision: matches!(pipeline.category(), ModelCategory::Vision { .. }),
+                    }
+                };
+                if sender.send(response).await.is_err() {
+                    warn!("Model info sender was dropped before the engine could respond.");
+                }
+            }
+            Request::Ping(sender) => {
+                let response = PingResponse {
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .expect("Time travel has occurred!")
+                        .as_millis(),
+                    queue_depth: self.scheduler.waiting_len(),
+                };
+                if sender.send(response).await.is_err() {
+                    warn!("Ping sender was dropped before the engine could respond.");
+                }
+            }
+            Request::ExportSeqState(id, sender) => {
+                let state = self.export_seq_state(id);
+                if sender.send(state).await.is_err() {
+                    warn!("Sequence state sender was dropped before the engine could respond.");
+                }
+            }
+            Request::ImportSeqState {
+                data,
+                request,
+                response,
+            } => {
+                let id = self.import_seq_state(&data, request).await;
+                if response.send(id).await.is_err() {
+                    warn!("Sequence state sender was dropped before the engine could respond.");
+                }
+            }
+            Request::CacheUsage(sender) => {
+                let running = self.scheduler.running_len();
+                let waiting = self.scheduler.waiting_len();
+                let block_engine = self.scheduler.block_engine();
+                let response = CacheUsageResponse {
+                    free_blocks: block_engine
+                        .as_ref()
+                        .map(|engine| engine.num_free_gpu_blocks()),
+                    total_blocks: block_engine.map(|engine| engine.num_gpu_blocks()),
+                    running,
+                    waiting,
+                };
+                if sender.send(response).await.is_err() {
+                    warn!("Cache usage sender was dropped before the engine could respond.");
+                }
+            }
+            Request::PrefixCacheStats(sender) => {
+                if sender.send(self.prefix_cacher.stats()).await.is_err() {
+                    warn!("Prefix cache stats sender was dropped before the engine could respond.");
+                }
+            }
+            Request::ClearPrefixCache => {
+                let n_cleared = self.prefix_cacher.clear();
+                info!("Cleared {n_cleared} prefixes from the prefix cache.");
+            }
+            Request::Tokenize {
+                text,
+                add_special_tokens,
+                response,
+            } => {
+                let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+                let res = match tokenizer.encode(text, add_special_tokens) {
+                    Ok(encoding) => Response::Tokenize(encoding.get_ids().to_vec()),
+                    Err(e) => Response::ValidationError(e),
+                };
+                if response.send(res).await.is_err() {
+                    warn!("Tokenize sender was dropped before the engine could respond.");
+                }
+            }
+            Request::Detokenize {
+                tokens,
+                skip_special_tokens,
+                response,
+            } => {
+                let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+                let res = match tokenizer.decode(&tokens, skip_special_tokens) {
+                    Ok(text) => Response::Detokenize(text),
+                    Err(e) => Response::ValidationError(e),
+                };
+                if response.send(res).await.is_err() {
+                    warn!("Detokenize sender was dropped before the engine could respond.");
+                }
+            }
+            Request::RenderTemplate {
+                messages,
+                add_generation_prompt,
+                tools,
+                response,
+            } => {
+                let res = {
+                    let pipeline = &*get_mut_arcmutex!(self.pipeline);
+                    if pipeline.get_chat_template().chat_template.is_none() {
+                        Err(anyhow::Error::msg(
+                            "The model does not have a chat template.",
+                        ))
+                    } else {
+                        pipeline.get_processor().render(
+                            pipeline,
+                            messages,
+                            add_generation_prompt,
+                            tools.unwrap_or_default(),
+                        )
+                    }
+                };
+                if response.send(res).await.is_err() {
+                    warn!("Render template sender was dropped before the engine could respond.");
+                }
+            }
+            Request::Terminate(id) => {
+                if !self.scheduler.cancel_request(id) {
+                    warn!("No running or waiting sequence of request {id} to cancel.");
+                }
+            }
+            Request::Embedding {
+                text,
+                pooling,
+                response,
+            } => {
+                let res = self.embed(&text, pooling);
+                if response.send(res).await.is_err() {
+                    warn!("Embedding sender was dropped before the engine could respond.");
+                }
+            }
+            Request::RawForward {
+                tokens,
+                return_hidden_states,
+                all_positions,
+                response,
+            } => {
+                let res = self.raw_forward(&tokens, return_hidden_states, all_positions);
+                if response.send(res).await.is_err() {
+                    warn!("Raw forward sender was dropped before the engine could respond.");
+                }
+            }
+            Request::ReIsq(level, imatrix) => {
+                let imatrix = match imatrix.map(ImatrixData::load).transpose() {
+                    Ok(imatrix) => imatrix,
+                    Err(e) => {
+                        warn!("Loading imatrix for ISQ requantization failed: {e:?}");
+                        return;
+                    }
+                };
+                if let Err(e) =
+                    get_mut_arcmutex!(self.pipeline).re_isq_model(level, imatrix.as_ref())
+                {
                     warn!("ISQ requantization failed: {e:?}");
                 }
             }
         }
     }
 
-    async fn add_request(&mut self, request: NormalRequest) {
+    fn export_seq_state(&mut self, id: usize) -> anyhow::Result<Vec<u8>> {
+        if get_mut_arcmutex!(self.pipeline)
+            .get_metadata()
+            .cache_config
+            .is_some()
+        {
+            bail!("Exporting sequence states is not supported with PagedAttention.");
+        }
+        if self.no_kv_cache {
+            bail!("Exporting sequence states requires the KV cache.");
+        }
+        let seq = self
+            .scheduler
+            .seq_mut(id)
+            .with_context(|| format!("No running or waiting sequence with id {id}."))?;
+        if seq.early_exit_layer().is_some() {
+            bail!("Sequence {id} uses early exit, so its KV cache is incomplete.");
+        }
+        let state = SeqState {
+            toks: seq.get_toks().to_vec(),
+            cache: seq.cache().clone(),
+            xlora_cache: seq.is_xlora().then(|| seq.xlora_cache().clone()),
+        };
+        if state.cache.iter().all(Option::is_none) {
+            bail!("Sequence {id} has not run its prompt yet.");
+        }
+        state.to_bytes()
+    }
+
+    async fn import_seq_state(
+        &mut self,
+        data: &[u8],
+        mut request: NormalRequest,
+    ) -> anyhow::Result<usize> {
+        if !self.prefix_cacher.is_enabled() {
+            bail!("Importing sequence states requires the prefix cache.");
+        }
+        if request.sampling_params.n_choices != 1 {
+            bail!("A sequence state is restored as one sequence, so `n_choices` must be 1.");
+        }
+        if request.early_exit_layer.is_some() {
+            bail!(
+                "A sequence state holds the KV cache of every layer, so it cannot use early exit."
+            );
+        }
+        let state = {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            let metadata = pipeline.get_metadata();
+            SeqState::from_bytes(
+                data,
+                &pipeline.device(),
+                metadata.num_hidden_layers,
+                pipeline
+                    .kv_cache_dtype()
+                    .storage_dtype(metadata.activation_dtype),
+                metadata.is_xlora,
+            )?
+        };
+        // The new sequence finds the restored KV cache in the prefix cache, so it only runs the
+        // tokens which the cache does not cover before generating.
+        self.prefix_cacher
+            .insert(state.toks.clone(), state.cache, state.xlora_cache);
+        request.messages = RequestMessage::CompletionTokens(state.toks);
+        let id = self.id;
+        self.add_request(request).await;
+        if self.id == id {
+            bail!("The request of the restored sequence was rejected, see its responses.");
+        }
+        Ok(id)
+    }
+
+    fn embed(
+        &mut self,
+        text: &str,
+        pooling: EmbeddingPooling,
+    ) -> anyhow::Result<EmbeddingResponse> {
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let toks = pipeline
+            .tokenizer()
+            .encode(text, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        if toks.is_empty() {
+            bail!("Cannot embed an empty text.");
+        }
+        let max_seq_len = pipeline.get_metadata().max_seq_len;
+        if toks.len() > max_seq_len {
+            bail!(
+                "Text of {} tokens exceeds the maximum sequence length {max_seq_len}.",
+                toks.len()
+            );
+        }
+        let hidden = pipeline.forward_embeddings(&toks)?.to_dtype(DType::F32)?;
+        let embedding = match pooling {
+            EmbeddingPooling::LastToken => hidden.get(toks.len() - 1)?,
+            EmbeddingPooling::MeanPooling => hidden.mean(0)?,
+            EmbeddingPooling::Cls => hidden.get(0)?,
+        };
+        Ok(EmbeddingResponse {
+            embedding: embedding.to_vec1()?,
+            prompt_tokens: toks.len(),
+        })
+    }
+
+    fn raw_forward(
+        &mut self,
+        toks: &[u32],
+        return_hidden_states: bool,
+        all_positions: bool,
+    ) -> anyhow::Result<RawForwardResponse> {
+        if toks.is_empty() {
+            bail!("Cannot run a forward pass without tokens.");
+        }
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let max_seq_len = pipeline.get_metadata().max_seq_len;
+        if toks.len() > max_seq_len {
+            bail!(
+                "{} tokens exceed the maximum sequence length {max_seq_len}.",
+                toks.len()
+            );
+        }
+        let (logits, hidden_states) =
+            pipeline.forward_raw(toks, return_hidden_states, all_positions)?;
+        Ok(RawForwardResponse {
+            logits: logits.to_dtype(DType::F32)?.to_vec2()?,
+            hidden_states: hidden_states
+                .map(|hidden| hidden.to_dtype(DType::F32)?.to_vec2())
+                .transpose()?,
+        })
+    }
+
+    async fn add_request(&mut self, mut request: NormalRequest) {
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
@@ -465,8 +971,75 @@ impl Engine {
             RequestMessage::Completion { best_of, .. } => best_of,
             RequestMessage::Chat(_)
             | RequestMessage::CompletionTokens(_)
-            | RequestMessage::VisionChat { .. } => 1,
+            | RequestMessage::FillInMiddle { .. }
+            | RequestMessage::VisionChat { .. } => request.sampling_params.n_choices,
         };
+        if best_of < request.sampling_params.n_choices {
+            request
+                .response
+                .send(Response::ValidationError(
+                    format!(
+                        "`best_of` ({best_of}) must not be smaller than the number of choices ({}).",
+                        request.sampling_params.n_choices
+                    )
+                    .into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        if request.is_streaming && best_of > request.sampling_params.n_choices {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "`best_of` candidates cannot be streamed, `best_of` must equal the number of choices when streaming.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        if let Err(e) = check_sampling_ranges(&request.sampling_params) {
+            request
+                .response
+                .send(Response::ValidationError(e.into()))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        if let Some(tau) = request.sampling_params.mirostat_tau {
+            let eta = request.sampling_params.mirostat_eta;
+            if tau <= 0. || eta <= 0. {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        format!(
+                            "`mirostat_tau` ({tau}) and `mirostat_eta` ({eta}) must be positive."
+                        )
+                        .into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+        if let (Some(min_len), Some(max_len)) = (
+            request.sampling_params.min_len,
+            request.sampling_params.max_len,
+        ) {
+            if min_len > max_len {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        format!(
+                            "`min_len` ({min_len}) must not be larger than `max_len` ({max_len})."
+                        )
+                        .into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
         if is_chat
             && !get_mut_arcmutex!(self.pipeline)
                 .get_chat_template()
@@ -475,7 +1048,7 @@ impl Engine {
             request
                     .response
                     .send(Response::ValidationError(
-                        "Received messages for a model which does not have a chat template. Either use a different model or pass a single string as the prompt".into(),
+                        "Received messages for a model which does not have a chat template. Either specify a chat template, use a different model or pass a single string as the prompt".into(),
                     )).await.expect("Expected receiver.");
             return;
         }
@@ -484,10 +1057,48 @@ impl Engine {
             RequestMessage::VisionChat {
                 ref images,
                 messages: _,
-            } => Some(images.clone()),
+            } => {
+                let max_image_edge = get_mut_arcmutex!(self.pipeline)
+                    .get_metadata()
+                    .max_image_edge;
+                Some(
+                    images
+                        .iter()
+                        .cloned()
+                        .map(|image| match max_image_edge {
+                            Some(max_edge) => limit_image_edge(image, max_edge),
+                            None => image,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            }
             _ => None,
         };
 
+        // Forcing a tool constrains the output to a call of that tool.
+        if let Some(ToolChoice::Tool(ref tool)) = request.tool_choice {
+            let schema = if !matches!(request.constraint, Constraint::None) {
+                Err("A grammar cannot be used when forcing a tool.".to_string())
+            } else {
+                forced_tool_call_schema(
+                    &tool.function.name,
+                    request.tools.as_deref().unwrap_or_default(),
+                )
+                .map_err(|e| e.to_string())
+            };
+            match schema {
+                Ok(schema) => request.constraint = Constraint::JsonSchema(schema),
+                Err(err) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(err.into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            }
+        }
+
         let matcher = if request.tools.is_some() {
             Some(Arc::new(handle_seq_error!(
                 ToolCallingMatcher::new(request.tool_choice.unwrap_or(ToolChoice::Auto),),
@@ -497,6 +1108,7 @@ impl Engine {
             None
         };
 
+        let pre_tokenized = matches!(request.messages, RequestMessage::CompletionTokens(_));
         let mut prompt = match request.messages {
             RequestMessage::Chat(messages)
             | RequestMessage::VisionChat {
@@ -507,7 +1119,7 @@ impl Engine {
                 let template = pipeline.get_processor().process(
                     pipeline,
                     messages,
-                    true,
+                    request.add_generation_prompt,
                     request.tools.unwrap_or_default(),
                 );
                 handle_seq_error!(template, request.response)
@@ -522,6 +1134,20 @@ impl Engine {
                     .to_vec()
             }
             RequestMessage::CompletionTokens(it) => it,
+            RequestMessage::FillInMiddle { prefix, suffix } => {
+                let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+                let prompt = match self
+                    .fim_tokens
+                    .clone()
+                    .or_else(|| FimTokens::from_tokenizer(&tokenizer))
+                {
+                    Some(fim_tokens) => fim_tokens.encode(&tokenizer, &prefix, &suffix),
+                    None => Err(anyhow::Error::msg(
+                        "The tokenizer has no known fill-in-the-middle tokens, set them with `MistralRsBuilder::with_fim_tokens`.",
+                    )),
+                };
+                handle_seq_error!(prompt, request.response)
+            }
         };
         if prompt.is_empty() {
             request
@@ -533,36 +1159,137 @@ impl Engine {
                 .expect("Expected receiver.");
             return;
         }
-
-        if prompt.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
-            if !self.truncate_sequence {
+        if pre_tokenized {
+            let vocab_size = get_mut_arcmutex!(self.pipeline)
+                .tokenizer()
+                .get_vocab_size(true);
+            if let Err(err) = check_prompt_tokens(&prompt, vocab_size) {
                 request
                     .response
-                    .send(Response::ValidationError(
-                        format!("Prompt sequence length is greater than {}, perhaps consider using `truncate_sequence`?", get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len).into(),
-                    )).await.expect("Expected receiver.");
+                    .send(Response::ValidationError(err.into()))
+                    .await
+                    .expect("Expected receiver.");
                 return;
+            }
+        }
+        // The bytes removed by token healing are generated again by a grammar-free first token,
+        // so token healing is not applied to constrained requests.
+        let token_healing =
+            if request.token_healing && matches!(request.constraint, Constraint::None) {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                heal_prompt(&pipeline.get_metadata().tok_trie, &mut prompt)
             } else {
-                let prompt_len = prompt.len();
-                let max_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
-                let currently_over = prompt_len - max_len;
-                let sampling_max = if let Some(sampling_max) = request.sampling_params.max_len {
-                    if currently_over + sampling_max >= prompt_len {
-                        10
-                    } else {
-                        sampling_max
-                    }
-                } else {
-                    10
-                };
-                prompt = prompt[(currently_over + sampling_max)..].to_vec();
-                warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt.len());
+                None
+            };
+
+        if let Some(layer) = request.early_exit_layer {
+            let (num_hidden_layers, supported) = {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                let metadata = pipeline.get_metadata();
+                (
+                    metadata.num_hidden_layers,
+                    matches!(metadata.kind, ModelKind::Normal)
+                        && matches!(pipeline.category(), ModelCategory::Text)
+                        && metadata.cache_config.is_none(),
+                )
+            };
+            let err = if !supported {
+                Some(
+                    "Early exit is only supported for plain text models without PagedAttention."
+                        .to_string(),
+                )
+            } else if layer == 0 || layer > num_hidden_layers {
+                Some(format!("Early exit layer must be between 1 and the number of hidden layers ({num_hidden_layers}), got {layer}."))
+            } else {
+                None
+            };
+            if let Some(err) = err {
+                request
+                    .response
+                    .send(Response::ValidationError(err.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
             }
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt),
-            request.response
-        );
+
+        if let Some(scaling) = request.xlora_global_scaling.filter(|s| !s.is_finite()) {
+            request
+                .response
+                .send(Response::ValidationError(
+                    format!("X-LoRA global scaling must be finite, got {scaling}.").into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
+        let prompt_len = prompt.len();
+        let max_seq_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
+        // With a rolling context, generation continues past the maximum sequence length, so only the
+        // prompt must fit.
+        let max_new_toks = request
+            .sampling_params
+            .max_len
+            .filter(|_| self.rolling_cache.is_none());
+        let action = match &self.context_overflow_handler {
+            Some(handler) if prompt_len + max_new_toks.unwrap_or(0) > max_seq_len => {
+                handler(ContextOverflow {
+                    request_id: request.id,
+                    prompt_tokens: prompt_len,
+                    max_new_tokens: request.sampling_params.max_len,
+                    max_seq_len,
+                })
+            }
+            _ => ContextOverflowAction::Default,
+        };
+        let truncate = match action {
+            // A prompt which does not fit is rejected before wasting a prefill on it.
+            ContextOverflowAction::Default => match check_context_length(
+                prompt_len,
+                max_new_toks,
+                max_seq_len,
+                self.truncate_sequence || request.truncate_prompt,
+            ) {
+                Ok(truncate) => truncate,
+                Err(msg) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(msg.into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            },
+            ContextOverflowAction::Truncate => true,
+            ContextOverflowAction::Reject(msg) => {
+                request
+                    .response
+                    .send(Response::ValidationError(msg.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        };
+        if truncate {
+            prompt = truncate_prompt_left(&prompt, max_new_toks, max_seq_len).to_vec();
+            if prompt.len() < prompt_len {
+                warn!("Prompt for request {} was {} tokens long. The first {} tokens were truncated to make space for generation.", request.id, prompt_len, prompt_len - prompt.len());
+            }
+        }
+        // The logprobs of an echoed prompt are computed during its prefill, which must run for the
+        // whole prompt.
+        let return_prompt_logprobs = echo_prompt && request.return_logprobs;
+        // The KV cache of an early exit sequence only covers its first layers, so it can neither reuse
+        // nor populate the prefix cache.
+        let prefill_cache = if request.early_exit_layer.is_none() && !return_prompt_logprobs {
+            handle_seq_error!(
+                self.prefix_cacher.search_for_matching_cache(&prompt),
+                request.response
+            )
+        } else {
+            None
+        };
 
         let topk = request
             .sampling_params
@@ -571,6 +1298,7 @@ impl Engine {
             .unwrap_or(-1);
         let topp = request.sampling_params.top_p.unwrap_or(1.0);
         let minp = request.sampling_params.min_p.unwrap_or(0.0);
+        let typicalp = request.sampling_params.typical_p.unwrap_or(1.0);
         let num_hidden_layers = get_mut_arcmutex!(self.pipeline)
             .get_metadata()
             .num_hidden_layers;
@@ -598,6 +1326,30 @@ impl Engine {
                 (i.clone(), vec![])
             }
             Some(StopTokens::Seqs(ref s)) => {
+                if s.len() > MAX_STOP_SEQS {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!(
+                                "Received {} stop sequences, but at most {MAX_STOP_SEQS} are allowed.",
+                                s.len()
+                            )
+                            .into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                if s.iter().any(|stop_txt| stop_txt.is_empty()) {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            "Stop sequences must not be empty.".into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
                 let mut stop_toks = Vec::new();
                 let mut stop_strings: Vec<String> = Vec::new();
 
@@ -629,29 +1381,188 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            request.sampling_params.n_choices,
-            request.is_streaming,
-            is_chat,
-            best_of,
-        )));
+        // Extra EOS tokens for this request only, e.g. a chat template's `<|im_end|>`.
+        let mut request_eos_tokens = Vec::new();
+        if let Some(ref ids) = request.sampling_params.stop_token_ids {
+            let vocab_size = get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .tok_trie
+                .vocab_size();
+            if let Some(id) = ids.iter().find(|id| **id as usize >= vocab_size) {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        format!("Stop token id {id} is out of range for a vocabulary of size {vocab_size}.").into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+            request_eos_tokens.extend_from_slice(ids);
+        }
+        if let Some(ref strings) = request.sampling_params.stop_token_strings {
+            let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+            for stop_txt in strings {
+                let encoded = tokenizer.encode(stop_txt.to_string(), false);
+                let toks = handle_seq_error!(encoded, request.response)
+                    .get_ids()
+                    .to_vec();
+                if toks.len() != 1 {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!(
+                                "Stop token string {stop_txt:?} must be a single token, but it is {} tokens.",
+                                toks.len()
+                            )
+                            .into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                request_eos_tokens.push(toks[0]);
+            }
+        }
+
+        let stop_token_seqs = request
+            .sampling_params
+            .stop_token_seqs
+            .clone()
+            .unwrap_or_default();
+        if stop_token_seqs.len() > MAX_STOP_SEQS {
+            request
+                .response
+                .send(Response::ValidationError(
+                    format!(
+                        "Received {} stop token sequences, but at most {MAX_STOP_SEQS} are allowed.",
+                        stop_token_seqs.len()
+                    )
+                    .into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        if stop_token_seqs.iter().any(Vec::is_empty) {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Stop token sequences must not be empty.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        let vocab_size = get_mut_arcmutex!(self.pipeline)
+            .get_metadata()
+            .tok_trie
+            .vocab_size();
+        if let Some(id) = stop_token_seqs
+            .iter()
+            .flatten()
+            .find(|id| **id as usize >= vocab_size)
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    format!(
+                        "Stop token id {id} is out of range for a vocabulary of size {vocab_size}."
+                    )
+                    .into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
+        // The sequence breakers are tokenized once for all choices.
+        let dry_penalty = match request.sampling_params.dry_multiplier {
+            Some(multiplier) if multiplier != 0. => {
+                let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+                let mut sequence_breakers = HashSet::new();
+                for breaker in &request.sampling_params.dry_sequence_breakers {
+                    let encoded = tokenizer.encode(breaker.to_string(), false);
+                    let toks = handle_seq_error!(encoded, request.response)
+                        .get_ids()
+                        .to_vec();
+                    sequence_breakers.extend(toks.last());
+                }
+                Some(Arc::new(DryPenalty {
+                    multiplier,
+                    base: request.sampling_params.dry_base,
+                    allowed_length: request.sampling_params.dry_allowed_length,
+                    sequence_breakers,
+                }))
+            }
+            _ => None,
+        };
+
+        let model = get_mut_arcmutex!(self.pipeline).name();
+        let span = tracing::info_span!(
+            "request",
+            request_id = request.id,
+            model = %model,
+            prompt_tokens = prompt.len(),
+            n_choices = request.sampling_params.n_choices,
+            completion_tokens = tracing::field::Empty,
+            duration_ms = tracing::field::Empty,
+            finish_reason = tracing::field::Empty,
+        );
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!");
 
-        let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+        let tok_trie = get_mut_arcmutex!(self.pipeline)
+            .get_metadata()
+            .tok_trie
+            .clone();
+        let suppressed_tokens = if request.sampling_params.suppress_special_tokens {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            let mut keep = pipeline.get_metadata().eos_tok.clone();
+            keep.extend(&request_eos_tokens);
+            keep.extend(&stop_toks);
+            special_tokens_to_suppress(&pipeline.tokenizer(), &keep)
+        } else {
+            Vec::new()
+        };
 
         let sampler = Sampler::new(
             Some(request.sampling_params.temperature.unwrap_or(1.0)),
             request.sampling_params.top_n_logprobs,
-            tokenizer,
+            tok_trie,
             request.sampling_params.frequency_penalty,
             request.sampling_params.presence_penalty,
             topk,
             topp,
             minp,
+            typicalp,
+            request.sampling_params.temperature_order,
             request.logits_processors.unwrap_or_default(),
-        );
+        )
+        .with_logits_bias(request.sampling_params.logits_bias.clone())
+        .with_repetition_penalty(
+            request.sampling_params.repetition_penalty,
+            request.sampling_params.repetition_penalty_range,
+        )
+        .with_temperature_floor(self.temperature_floor)
+        .with_greedy_tie_break(request.sampling_params.greedy_tie_break_by)
+        .with_suppressed_tokens(suppressed_tokens);
+
+        let group = Arc::new(tokio::sync::Mutex::new(
+            SequenceGroup::new(
+                request.sampling_params.n_choices,
+                request.is_streaming,
+                is_chat,
+                best_of,
+            )
+            .with_span(span)
+            .with_include_usage(request.include_usage)
+            .with_sampling_params_used(sampler.params_used(
+                request.sampling_params.max_len,
+                request.sampling_params.n_choices,
+            )),
+        ));
 
         if request.sampling_params.n_choices == 0 {
             request
@@ -664,9 +1575,26 @@ impl Engine {
             return;
         }
 
+        // With several choices for the same prompt, only the first sequence runs the prefill. The others
+        // then start decoding from a copy of its prompt KV cache, or from its shared KV cache blocks
+        // with PagedAttention.
+        let share_prefill = best_of > 1
+            && prompt.len() > 1
+            && prefill_cache.is_none()
+            && !return_prompt_logprobs
+            && images.is_none()
+            && !self.no_kv_cache
+            && {
+                let metadata = get_mut_arcmutex!(self.pipeline).get_metadata();
+                (metadata.cache_config.is_none() || metadata.sliding_window.is_none())
+                    && !metadata.is_xlora
+                    && !matches!(metadata.kind, ModelKind::Speculative { .. })
+            };
+        let mut prefill_leader: Option<Sequence> = None;
+
         // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
-            let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
+        for response_index in 0..best_of {
+            let recognizer = match self.build_sequence_recognizer(&request.constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
                     request
@@ -708,7 +1636,9 @@ impl Engine {
                         get_mut_arcmutex!(self.pipeline)
                             .tokenizer()
                             .decode(&prompt, false)
-                            .expect("cannot decode completion tokens"),
+                            .expect("cannot decode completion tokens")
+                            // The echoed prompt includes the bytes removed by token healing.
+                            + &String::from_utf8_lossy(token_healing.as_deref().unwrap_or_default()),
                     )
                 } else {
                     None
@@ -718,6 +1648,32 @@ impl Engine {
                 block_size,
                 trie,
                 matcher.clone(),
+            )
+            .with_request_id(request.id)
+            .with_early_exit_layer(request.early_exit_layer)
+            .with_xlora_global_scaling(request.xlora_global_scaling)
+            .with_token_healing(token_healing.clone())
+            .with_dry_penalty(dry_penalty.clone())
+            .with_prompt_logprobs(return_prompt_logprobs)
+            .with_mirostat(
+                request.sampling_params.mirostat_tau,
+                request.sampling_params.mirostat_eta,
+            )
+            .with_request_eos_tokens(request_eos_tokens.clone())
+            .with_stop_token_seqs(stop_token_seqs.clone())
+            .with_rolling_cache(self.rolling_cache, max_seq_len)
+            .with_min_len(
+                request.sampling_params.min_len,
+                get_mut_arcmutex!(self.pipeline)
+                    .get_metadata()
+                    .eos_tok
+                    .clone(),
+            )
+            .with_seed(
+                request
+                    .sampling_params
+                    .seed
+                    .map(|seed| seed.wrapping_add(response_index as u64)),
             );
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
@@ -729,7 +1685,193 @@ impl Engine {
                 seq
             };
             self.id += 1;
-            self.scheduler.add_seq(seq);
+            if !share_prefill {
+                self.scheduler.add_seq(seq);
+            } else if let Some(ref mut leader) = prefill_leader {
+                leader.add_shared_prefill_follower(seq);
+            } else {
+                prefill_leader = Some(seq);
+            }
+        }
+        if let Some(leader) = prefill_leader {
+            self.scheduler.add_seq(leader);
+        }
+    }
+}
+
+/// Check that a prompt of `prompt_len` tokens leaves room for `max_len` new tokens, or at least one
+/// if it is not given, within the model maximum sequence length. Returns whether the prompt must be
+/// truncated, which is only allowed with `truncate`, or the error to return otherwise.
+fn check_context_length(
+    prompt_len: usize,
+    max_len: Option<usize>,
+    max_seq_len: usize,
+    truncate: bool,
+) -> Result<bool, String> {
+    let fits = match max_len {
+        Some(max_len) => prompt_len + max_len <= max_seq_len,
+        None => prompt_len < max_seq_len,
+    };
+    if fits {
+        Ok(false)
+    } else if truncate {
+        Ok(true)
+    } else if let Some(max_len) = max_len {
+        Err(format!("Prompt sequence length ({prompt_len}) plus the maximum number of new tokens ({max_len}) exceeds the model maximum sequence length ({max_seq_len}), perhaps consider using `truncate_prompt`?"))
+    } else {
+        Err(format!("Prompt sequence length ({prompt_len}) leaves no room for generation within the model maximum sequence length ({max_seq_len}), perhaps consider using `truncate_prompt`?"))
+    }
+}
+
+/// Check that every token of a prompt which was tokenized by the caller is in the vocabulary.
+fn check_prompt_tokens(prompt: &[u32], vocab_size: usize) -> Result<(), String> {
+    match prompt.iter().find(|tok| **tok as usize >= vocab_size) {
+        Some(tok) => Err(format!(
+            "Prompt token id {tok} is out of the vocabulary of {vocab_size} tokens."
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check that the sampling parameters are in the ranges of the OpenAI API: the penalties in
+/// `[-2, 2]`, `top_p` and `min_p` in `(0, 1]` and a non-negative `temperature`. `top_k` cannot be
+/// negative.
+fn check_sampling_ranges(params: &SamplingParams) -> Result<(), String> {
+    let penalties = [
+        ("frequency_penalty", params.frequency_penalty),
+        ("presence_penalty", params.presence_penalty),
+    ];
+    for (name, penalty) in penalties {
+        if let Some(penalty) = penalty.filter(|penalty| !(-2.0..=2.0).contains(penalty)) {
+            return Err(format!("`{name}` ({penalty}) must be between -2 and 2."));
+        }
+    }
+    for (name, p) in [("top_p", params.top_p), ("min_p", params.min_p)] {
+        if let Some(p) = p.filter(|p| p.is_nan() || *p <= 0. || *p > 1.) {
+            return Err(format!("`{name}` ({p}) must be in (0, 1]."));
+        }
+    }
+    if let Some(temperature) = params
+        .temperature
+        .filter(|temperature| temperature.is_nan() || *temperature < 0.)
+    {
+        return Err(format!(
+            "`temperature` ({temperature}) must not be negative."
+        ));
+    }
+    Ok(())
+}
+
+/// Drop tokens from the start of `prompt` to leave room for `max_len` new tokens within the model
+/// maximum sequence length, or for 10 tokens if `max_len` is not given or does not fit.
+fn truncate_prompt_left(prompt: &[u32], max_len: Option<usize>, max_seq_len: usize) -> &[u32] {
+    let sampling_max = match max_len {
+        Some(sampling_max) if sampling_max < max_seq_len => sampling_max,
+        _ => 10,
+    };
+    let keep = max_seq_len.saturating_sub(sampling_max);
+    &prompt[prompt.len().saturating_sub(keep)..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_context_length, check_prompt_tokens, check_sampling_ranges, truncate_prompt_left,
+    };
+    use crate::sampler::SamplingParams;
+
+    #[test]
+    fn context_length_exceeded_error() {
+        assert_eq!(check_context_length(100, Some(28), 128, false), Ok(false));
+        assert_eq!(check_context_length(127, None, 128, false), Ok(false));
+
+        let err = check_context_length(100, Some(29), 128, false).unwrap_err();
+        assert!(err.contains("(100)") && err.contains("(29)") && err.contains("(128)"));
+        let err = check_context_length(128, None, 128, false).unwrap_err();
+        assert!(err.contains("(128)"));
+    }
+
+    #[test]
+    fn truncate_prompt_from_left() {
+        assert_eq!(check_context_length(100, Some(29), 128, true), Ok(true));
+        let prompt = (0..100).collect::<Vec<u32>>();
+        let truncated = truncate_prompt_left(&prompt, Some(29), 128);
+        assert_eq!(truncated.len(), 99);
+        assert_eq!(truncated, &prompt[1..]);
+        // Without a usable `max_len`, room is left for 10 tokens.
+        assert_eq!(truncate_prompt_left(&prompt, None, 50), &prompt[60..]);
+        assert_eq!(truncate_prompt_left(&prompt, Some(200), 50), &prompt[60..]);
+    }
+
+    #[test]
+    fn prompt_tokens_in_vocabulary() {
+        assert_eq!(check_prompt_tokens(&[0, 5, 31], 32), Ok(()));
+        let err = check_prompt_tokens(&[0, 32, 5], 32).unwrap_err();
+        assert!(err.contains("32 is out") && err.contains("of 32 tokens"));
+    }
+    #[test]
+    fn sampling_parameter_ranges() {
+        let check = |params: SamplingParams| check_sampling_ranges(&params);
+        assert_eq!(check(SamplingParams::default()), Ok(()));
+
+        for penalty in [-2.0, 0.0, 2.0] {
+            let params = SamplingParams {
+                frequency_penalty: Some(penalty),
+                presence_penalty: Some(penalty),
+                ..Default::default()
+            };
+            assert_eq!(check(params), Ok(()));
+        }
+        for penalty in [-2.01, 2.01, f32::NAN] {
+            let err = check(SamplingParams {
+                frequency_penalty: Some(penalty),
+                ..Default::default()
+            })
+            .unwrap_err();
+            assert!(err.starts_with("`frequency_penalty`"), "{err}");
+            let err = check(SamplingParams {
+                presence_penalty: Some(penalty),
+                ..Default::default()
+            })
+            .unwrap_err();
+            assert!(err.starts_with("`presence_penalty`"), "{err}");
+        }
+
+        for p in [1e-6, 0.5, 1.0] {
+            let params = SamplingParams {
+                top_p: Some(p),
+                min_p: Some(p),
+                ..Default::default()
+            };
+            assert_eq!(check(params), Ok(()));
+        }
+        for p in [0.0, -0.1, 1.01] {
+            let err = check(SamplingParams {
+                top_p: Some(p),
+                ..Default::default()
+            })
+            .unwrap_err();
+            assert!(err.starts_with("`top_p`"), "{err}");
+            let err = check(SamplingParams {
+                min_p: Some(p),
+                ..Default::default()
+            })
+            .unwrap_err();
+            assert!(err.starts_with("`min_p`"), "{err}");
+        }
+
+        for temperature in [0.0, 2.5] {
+            let params = SamplingParams {
+                temperature: Some(temperature),
+                ..Default::default()
+            };
+            assert_eq!(check(params), Ok(()));
         }
+        let err = check(SamplingParams {
+            temperature: Some(-0.1),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(err.starts_with("`temperature`"), "{err}");
     }
 }
@@ -0,0 +1,100 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The window over which the throughput is measured.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Keeps the aggregate number of generated tokens per second under a cap, by telling the engine how
+/// long to sleep after each step.
+///
+/// The throughput is measured over a sliding window of the recent steps, so this is a soft cap: a
+/// single step may generate more tokens than the cap allows, and the engine then waits until the
+/// average over the window is back under it.
+pub(crate) struct Throttle {
+    max_tokens_per_second: f64,
+    // The time each recent step started and the number of tokens it generated, oldest first.
+    steps: VecDeque<(Instant, usize)>,
+}
+
+impl Throttle {
+    pub(crate) fn new(max_tokens_per_second: f32) -> Self {
+        Self {
+            max_tokens_per_second: f64::from(max_tokens_per_second),
+            steps: VecDeque::new(),
+        }
+    }
+
+    /// Record a step which ran from `start` to `now` and generated `n_tokens`, returning how long to
+    /// wait before the next step, if at all.
+    pub(crate) fn record(
+        &mut self,
+        start: Instant,
+        now: Instant,
+        n_tokens: usize,
+    ) -> Option<Duration> {
+        if n_tokens > 0 {
+            self.steps.push_back((start, n_tokens));
+        }
+        while self
+            .steps
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) > WINDOW)
+        {
+            self.steps.pop_front();
+        }
+        let (oldest, _) = self.steps.front()?;
+        let n_tokens = self.steps.iter().map(|(_, n)| n).sum::<usize>();
+        #[allow(clippy::cast_precision_loss)]
+        let min_elapsed = Duration::from_secs_f64(n_tokens as f64 / self.max_tokens_per_second);
+        min_elapsed
+            .checked_sub(now.duration_since(*oldest))
+            .filter(|delay| !delay.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Throttle;
+
+    #[test]
+    fn throughput_stays_under_cap() {
+        // Steps of 4 tokens taking 1ms would run at 4000 T/s without the cap.
+        let mut throttle = Throttle::new(100.);
+        let start = Instant::now();
+        let mut now = start;
+        let mut n_tokens = 0;
+        while now.duration_since(start) < Duration::from_secs(10) {
+            let step_start = now;
+            now += Duration::from_millis(1);
+            n_tokens += 4;
+            if let Some(delay) = throttle.record(step_start, now, 4) {
+                now += delay;
+            }
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let tokens_per_second = n_tokens as f64 / now.duration_since(start).as_secs_f64();
+        assert!(
+            (95. ..=101.).contains(&tokens_per_second),
+            "{tokens_per_second} T/s"
+        );
+    }
+
+    #[test]
+    fn no_delay_after_idle() {
+        let mut throttle = Throttle::new(100.);
+        let start = Instant::now();
+        assert!(throttle
+            .record(start, start + Duration::from_millis(1), 50)
+            .is_some());
+        // A slow step after the window of the burst is not delayed.
+        let start = start + Duration::from_secs(3);
+        assert_eq!(
+            throttle.record(start, start + Duration::from_millis(20), 1),
+            None
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+/// The special tokens of a fill-in-the-middle (FIM) prompt, which code models such as StarCoder2
+/// and CodeLlama were trained with. The prompt is laid out as
+/// `<prefix token> prefix <suffix token> suffix <middle token>`, and the model then generates the
+/// text between the prefix and the suffix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FimTokens {
+    pub prefix: String,
+    pub suffix: String,
+    pub middle: String,
+}
+
+impl FimTokens {
+    pub fn new(prefix: &str, suffix: &str, middle: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+            middle: middle.to_string(),
+        }
+    }
+
+    /// StarCoder and StarCoder2.
+    pub fn starcoder() -> Self {
+        Self::new("<fim_prefix>", "<fim_suffix>", "<fim_middle>")
+    }
+
+    /// CodeLlama.
+    pub fn code_llama() -> Self {
+        Self::new("▁<PRE>", "▁<SUF>", "▁<MID>")
+    }
+
+    /// CodeGemma and Qwen2.5-Coder.
+    pub fn code_gemma() -> Self {
+        Self::new("<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>")
+    }
+
+    /// DeepSeek-Coder.
+    pub fn deepseek_coder() -> Self {
+        Self::new("<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>")
+    }
+
+    /// The first of the known FIM tokens which are all in the vocabulary of `tokenizer`, starting
+    /// with the StarCoder2 ones.
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Option<Self> {
+        [
+            Self::starcoder(),
+            Self::code_llama(),
+            Self::code_gemma(),
+            Self::deepseek_coder(),
+        ]
+        .into_iter()
+        .find(|fim| fim.token_ids(tokenizer).is_some())
+    }
+
+    fn token_ids(&self, tokenizer: &Tokenizer) -> Option<[u32; 3]> {
+        Some([
+            tokenizer.token_to_id(&self.prefix)?,
+            tokenizer.token_to_id(&self.suffix)?,
+            tokenizer.token_to_id(&self.middle)?,
+        ])
+    }
+
+    /// The tokens of the FIM prompt for the text before and after the hole. The texts are
+    /// tokenized without special tokens.
+    pub fn encode(&self, tokenizer: &Tokenizer, prefix: &str, suffix: &str) -> Result<Vec<u32>> {
+        let Some([prefix_id, suffix_id, middle_id]) = self.token_ids(tokenizer) else {
+            anyhow::bail!(
+                "The tokenizer does not have the fill-in-the-middle tokens `{}`, `{}` and `{}`.",
+                self.prefix,
+                self.suffix,
+                self.middle
+            );
+        };
+        let encode = |text: &str| {
+            tokenizer
+                .encode(text, false)
+                .map(|encoding| encoding.get_ids().to_vec())
+                .map_err(anyhow::Error::msg)
+        };
+        let mut toks = vec![prefix_id];
+        toks.extend(encode(prefix)?);
+        toks.push(suffix_id);
+        toks.extend(encode(suffix)?);
+        toks.push(middle_id);
+        Ok(toks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::{
+        models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace, AddedToken, Tokenizer,
+    };
+
+    use super::FimTokens;
+
+    fn word_tokenizer(special_tokens: &[&str]) -> Tokenizer {
+        let vocab = ["<unk>", "def", "add", "return", "a", "+", "b"]
+            .into_iter()
+            .zip(0u32..)
+            .map(|(token, id)| (token.to_string(), id))
+            .collect::<HashMap<_, _>>();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace::default());
+        tokenizer.add_special_tokens(
+            &special_tokens
+                .iter()
+                .map(|token| AddedToken::from(token.to_string(), true))
+                .collect::<Vec<_>>(),
+        );
+        tokenizer
+    }
+
+    #[test]
+    fn starcoder_fim_layout() {
+        let tokenizer = word_tokenizer(&["<fim_prefix>", "<fim_suffix>", "<fim_middle>"]);
+        let fim = FimTokens::from_tokenizer(&tokenizer).unwrap();
+        assert_eq!(fim, FimTokens::starcoder());
+
+        let id = |token| tokenizer.token_to_id(token).unwrap();
+        let toks = fim.encode(&tokenizer, "def add", "return a + b").unwrap();
+        assert_eq!(
+            toks,
+            [
+                id("<fim_prefix>"),
+                id("def"),
+                id("add"),
+                id("<fim_suffix>"),
+                id("return"),
+                id("a"),
+                id("+"),
+                id("b"),
+                id("<fim_middle>"),
+            ]
+        );
+        // An empty suffix still ends with the suffix and middle tokens.
+        let toks = fim.encode(&tokenizer, "def", "").unwrap();
+        assert_eq!(
+            toks,
+            [
+                id("<fim_prefix>"),
+                id("def"),
+                id("<fim_suffix>"),
+                id("<fim_middle>")
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_other_families() {
+        let tokenizer = word_tokenizer(&["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"]);
+        assert_eq!(
+            FimTokens::from_tokenizer(&tokenizer),
+            Some(FimTokens::code_gemma())
+        );
+        assert!(FimTokens::starcoder()
+            .encode(&tokenizer, "def", "")
+            .is_err());
+
+        let tokenizer = word_tokenizer(&["<fim_prefix>", "<fim_suffix>"]);
+        assert_eq!(FimTokens::from_tokenizer(&tokenizer), None);
+    }
+}
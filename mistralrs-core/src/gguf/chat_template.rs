@@ -34,7 +34,7 @@ pub fn get_gguf_chat_template<R: std::io::Seek + std::io::Read>(
     let props = PropsGGUFTemplate::try_from(metadata)?;
     if let Some(ref chat_template) = props.chat_template {
         info!(
-            "Discovered and using GGUF chat template: `{}`",
+            "Discovered GGUF chat template: `{}`",
             chat_template.replace('\n', "\\n")
         );
     }
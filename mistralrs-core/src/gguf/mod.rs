@@ -11,7 +11,7 @@ use std::str::FromStr;
 
 pub const GGUF_MULTI_FILE_DELIMITER: &str = " ";
 
-#[derive(Debug, EnumString, Clone, Copy)]
+#[derive(Debug, EnumString, strum::Display, Clone, Copy)]
 #[strum(serialize_all = "kebab-case")]
 pub enum GGUFArchitecture {
     Llama,
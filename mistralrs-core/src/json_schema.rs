@@ -0,0 +1,463 @@
+//! Lowering of JSON schemas to the regexes used for [`Constraint::JsonSchema`](crate::Constraint::JsonSchema).
+//!
+//! Only the structural subset of JSON Schema which can be expressed as a regular language is
+//! supported: `type`, `properties`/`required`, `items`/`minItems`/`maxItems`, `minLength`/`maxLength`,
+//! `enum`, `const`, `anyOf`/`oneOf` and non-recursive `$ref`s. Properties are generated in a fixed
+//! order, and free-form values are limited to [`FREE_FORM_DEPTH`] levels of nesting.
+//! Everything the regex accepts is valid JSON.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+/// Maximum nesting depth of objects and arrays whose contents are not described by the schema.
+const FREE_FORM_DEPTH: usize = 2;
+
+const WS: &str = " ?";
+const INTEGER: &str = "-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+const BOOLEAN: &str = "(true|false)";
+const NULL: &str = "null";
+/// A character of a JSON string: printable ASCII other than `"` and `\`, an escape sequence which
+/// is not a lone surrogate, or a well-formed multi-byte UTF-8 sequence.
+const STRING_CHAR: &str = concat!(
+    r#"([ !#-\[\]-~]"#,
+    r#"|\\(["\\/bfnrt]|u([0-9a-cA-CeEfF][0-9a-fA-F]{3}|[dD][0-7][0-9a-fA-F]{2}))"#,
+    r"|[\xC2-\xDF][\x80-\xBF]",
+    r"|\xE0[\xA0-\xBF][\x80-\xBF]",
+    r"|[\xE1-\xEC\xEE\xEF][\x80-\xBF]{2}",
+    r"|\xED[\x80-\x9F][\x80-\xBF]",
+    r"|\xF0[\x90-\xBF][\x80-\xBF]{2}",
+    r"|[\xF1-\xF3][\x80-\xBF]{3}",
+    r"|\xF4[\x80-\x8F][\x80-\xBF]{2})",
+);
+
+/// Compile a JSON schema into a regex matching the JSON documents which satisfy it.
+pub(crate) fn json_schema_to_regex(schema: &str) -> Result<String> {
+    let root: Value = serde_json::from_str(schema).context("The JSON schema is not valid JSON.")?;
+    Compiler {
+        root: &root,
+        refs: Vec::new(),
+    }
+    .value(&root)
+}
+
+struct Compiler<'a> {
+    root: &'a Value,
+    /// The `$ref`s being expanded, to detect recursive schemas.
+    refs: Vec<String>,
+}
+
+/// Escape every byte which is not alphanumeric, so the regex matches `s` literally.
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() {
+                char::from(b).to_string()
+            } else {
+                format!(r"\x{b:02X}")
+            }
+        })
+        .collect()
+}
+
+fn literal(value: &Value) -> String {
+    escape(&value.to_string())
+}
+
+fn alternation(alternatives: Vec<String>) -> String {
+    format!("({})", alternatives.join("|"))
+}
+
+fn get_usize(schema: &Map<String, Value>, key: &str) -> Result<Option<usize>> {
+    match schema.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .and_then(|n| usize::try_from(n).ok())
+            .map(Some)
+            .with_context(|| format!("`{key}` must be a non-negative integer.")),
+    }
+}
+
+/// Repeat `item` between `min` and `max` times, separated by `sep`.
+fn repeat(item: &str, sep: &str, min: usize, max: Option<usize>) -> Result<String> {
+    if max.is_some_and(|max| max < min) {
+        bail!("The minimum count {min} is larger than the maximum count {max:?}.");
+    }
+    if max == Some(0) {
+        return Ok(String::new());
+    }
+    let rest = match max {
+        Some(max) => format!("{{{},{}}}", min.saturating_sub(1), max - 1),
+        None => format!("{{{},}}", min.saturating_sub(1)),
+    };
+    let items = format!("{item}({sep}{item}){rest}");
+    Ok(if min == 0 {
+        format!("({items})?")
+    } else {
+        items
+    })
+}
+
+fn string(min_length: usize, max_length: Option<usize>) -> Result<String> {
+    if max_length.is_some_and(|max| max < min_length) {
+        bail!("`minLength` {min_length} is larger than `maxLength` {max_length:?}.");
+    }
+    let count = match max_length {
+        Some(max) => format!("{{{min_length},{max}}}"),
+        None => format!("{{{min_length},}}"),
+    };
+    Ok(format!("\"{STRING_CHAR}{count}\""))
+}
+
+fn free_form(depth: usize) -> String {
+    let mut alternatives = vec![
+        string(0, None).expect("Valid lengths."),
+        NUMBER.to_string(),
+        BOOLEAN.to_string(),
+        NULL.to_string(),
+    ];
+    if depth > 0 {
+        alternatives.push(free_form_array(depth));
+        alternatives.push(free_form_object(depth));
+    }
+    alternation(alternatives)
+}
+
+fn free_form_array(depth: usize) -> String {
+    let items = repeat(&free_form(depth - 1), &format!(",{WS}"), 0, None).expect("Valid counts.");
+    format!(r"\[{WS}{items}{WS}\]")
+}
+
+fn free_form_object(depth: usize) -> String {
+    let member = format!(
+        "{}{WS}:{WS}{}",
+        string(0, None).expect("Valid lengths."),
+        free_form(depth - 1)
+    );
+    let members = repeat(&member, &format!(",{WS}"), 0, None).expect("Valid counts.");
+    format!(r"\{{{WS}{members}{WS}\}}")
+}
+
+impl Compiler<'_> {
+    fn value(&mut self, schema: &Value) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(free_form(FREE_FORM_DEPTH)),
+            Value::Bool(false) => bail!("The schema `false` does not match any value."),
+            Value::Object(schema) => schema,
+            other => bail!("Expected a schema object, got `{other}`."),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values.as_array().context("`enum` must be an array.")?;
+            if values.is_empty() {
+                bail!("`enum` must not be empty.");
+            }
+            return Ok(alternation(values.iter().map(literal).collect()));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key) {
+                let schemas = schemas
+                    .as_array()
+                    .filter(|schemas| !schemas.is_empty())
+                    .with_context(|| format!("`{key}` must be a non-empty array."))?;
+                let alternatives = schemas
+                    .iter()
+                    .map(|schema| self.value(schema))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(alternation(alternatives));
+            }
+        }
+        if let Some(schemas) = schema.get("allOf") {
+            match schemas.as_array().map(Vec::as_slice) {
+                Some([schema]) => return self.value(schema),
+                _ => bail!("`allOf` is only supported with a single schema."),
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(ty)) => self.typed(ty, schema),
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|ty| {
+                        let ty = ty.as_str().context("`type` must contain strings.")?;
+                        self.typed(ty, schema)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(alternation(alternatives))
+            }
+            Some(other) => bail!("Invalid `type` `{other}`."),
+            None if schema.contains_key("properties") => self.object(schema),
+            None if schema.contains_key("items") => self.array(schema),
+            None => Ok(free_form(FREE_FORM_DEPTH)),
+        }
+    }
+
+    fn reference(&mut self, reference: &Value) -> Result<String> {
+        let reference = reference.as_str().context("`$ref` must be a string.")?;
+        let pointer = reference
+            .strip_prefix('#')
+            .with_context(|| format!("Only local `$ref`s are supported, got `{reference}`."))?;
+        if self.refs.iter().any(|r| r == reference) {
+            bail!("Recursive schemas are not supported, `{reference}` refers to itself.");
+        }
+        let target = self
+            .root
+            .pointer(pointer)
+            .with_context(|| format!("Cannot resolve `$ref` `{reference}`."))?;
+        self.refs.push(reference.to_string());
+        let regex = self.value(target);
+        self.refs.pop();
+        regex
+    }
+
+    fn typed(&mut self, ty: &str, schema: &Map<String, Value>) -> Result<String> {
+        match ty {
+            "string" => {
+                if schema.contains_key("pattern") {
+                    bail!("`pattern` is not supported in JSON schemas, use a regex grammar.");
+                }
+                string(
+                    get_usize(schema, "minLength")?.unwrap_or(0),
+                    get_usize(schema, "maxLength")?,
+                )
+            }
+            "integer" => Ok(INTEGER.to_string()),
+            "number" => Ok(NUMBER.to_string()),
+            "boolean" => Ok(BOOLEAN.to_string()),
+            "null" => Ok(NULL.to_string()),
+            "object" => self.object(schema),
+            "array" => self.array(schema),
+            other => bail!("Unknown type `{other}`."),
+        }
+    }
+
+    fn object(&mut self, schema: &Map<String, Value>) -> Result<String> {
+        let Some(properties) = schema.get("properties") else {
+            return Ok(free_form_object(FREE_FORM_DEPTH));
+        };
+        let properties = properties
+            .as_object()
+            .context("`properties` must be an object.")?;
+        let required = match schema.get("required") {
+            None => HashSet::new(),
+            Some(required) => required
+                .as_array()
+                .and_then(|required| required.iter().map(Value::as_str).collect::<Option<_>>())
+                .context("`required` must be an array of strings.")?,
+        };
+        if let Some(missing) = required
+            .iter()
+            .find(|name| !properties.contains_key(**name))
+        {
+            bail!("Required property `{missing}` is not in `properties`.");
+        }
+
+        let mut members = Vec::with_capacity(properties.len());
+        for (name, property) in properties {
+            let member = format!(
+                "{}{WS}:{WS}{}",
+                literal(&Value::String(name.clone())),
+                self.value(property)?
+            );
+            members.push((member, required.contains(name.as_str())));
+        }
+
+        // `after_first[i]` matches the members from `i` on when a member was already written, so
+        // each is preceded by a comma. `from_start[i]` matches them when none was written yet.
+        let sep = format!(",{WS}");
+        let mut after_first = String::new();
+        let mut from_start = String::new();
+        for (member, is_required) in members.iter().rev() {
+            let with_member = format!("{member}{after_first}");
+            from_start = if *is_required {
+                with_member
+            } else {
+                format!("({with_member}|{from_start})")
+            };
+            after_first = if *is_required {
+                format!("{sep}{member}{after_first}")
+            } else {
+                format!("({sep}{member})?{after_first}")
+            };
+        }
+        Ok(format!(r"\{{{WS}{from_start}{WS}\}}"))
+    }
+
+    fn array(&mut self, schema: &Map<String, Value>) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.value(items)?,
+            None => free_form(FREE_FORM_DEPTH - 1),
+        };
+        let items = repeat(
+            &item,
+            &format!(",{WS}"),
+            get_usize(schema, "minItems")?.unwrap_or(0),
+            get_usize(schema, "maxItems")?,
+        )?;
+        Ok(format!(r"\[{WS}{items}{WS}\]"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_schema_to_regex;
+    use crate::aici::{recognizer::FunctionalRecognizer, rx::RecRx, toktree::SpecialToken};
+
+    fn accepts(rx: &RecRx, text: &str) -> bool {
+        let mut state = rx.initial();
+        for byte in text.bytes() {
+            match rx.try_append(state, byte) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        rx.special_allowed(state, SpecialToken::EndOfSentence)
+    }
+
+    fn compile(schema: &str) -> RecRx {
+        RecRx::from_rx(&json_schema_to_regex(schema).unwrap(), None).unwrap()
+    }
+
+    fn check(rx: &RecRx, valid: &[&str], invalid: &[&str]) {
+        for text in valid {
+            assert!(accepts(rx, text), "{text} should be accepted");
+            serde_json::from_str::<serde_json::Value>(text).unwrap();
+        }
+        for text in invalid {
+            assert!(!accepts(rx, text), "{text} should be rejected");
+        }
+    }
+
+    #[test]
+    fn nested_objects() {
+        let rx = compile(
+            r#"{
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string", "maxLength": 8},
+                    "owner": {
+                        "type": "object",
+                        "properties": {
+                            "age": {"type": "integer"},
+                            "email": {"type": ["string", "null"]}
+                        },
+                        "required": ["age"]
+                    }
+                },
+                "required": ["owner"]
+            }"#,
+        );
+        check(
+            &rx,
+            &[
+                r#"{"name": "Fido", "owner": {"age": 42, "email": null}}"#,
+                r#"{"owner":{"age":-3}}"#,
+                r#"{"owner": {"age": 0, "email": "a\"bé ü"}}"#,
+            ],
+            &[
+                r#"{"name": "Fido"}"#,
+                r#"{"owner": {"email": "x"}}"#,
+                r#"{"owner": {"age": 1.5}}"#,
+                r#"{"name": "much too long", "owner": {"age": 1}}"#,
+                r#"{"owner": {"age": 1},}"#,
+                r#"{"owner": {"age": 1, "email": "\ud800"}}"#,
+            ],
+        );
+    }
+
+    #[test]
+    fn arrays_with_min_items() {
+        let rx = compile(
+            r#"{"type": "array", "items": {"type": "number"}, "minItems": 2, "maxItems": 3}"#,
+        );
+        check(
+            &rx,
+            &["[1, 2]", "[1.5,-2e3, 0]"],
+            &["[]", "[1]", "[1, 2, 3, 4]", "[1, 2,]", "[01, 2]"],
+        );
+
+        let rx = compile(
+            r#"{
+                "type": "object",
+                "properties": {"tags": {"type": "array", "items": {"type": "string"}, "minItems": 1}}
+            }"#,
+        );
+        check(
+            &rx,
+            &["{}", r#"{"tags": ["a"]}"#, r#"{"tags": ["a", "b", "c"]}"#],
+            &[r#"{"tags": []}"#, r#"{"tags": [1]}"#],
+        );
+    }
+
+    #[test]
+    fn enums() {
+        let rx = compile(
+            r##"{
+                "$defs": {"color": {"enum": ["red", "green", null, 7]}},
+                "type": "object",
+                "properties": {"color": {"$ref": "#/$defs/color"}, "kind": {"const": "a.b"}},
+                "required": ["color", "kind"]
+            }"##,
+        );
+        check(
+            &rx,
+            &[
+                r#"{"color": "red", "kind": "a.b"}"#,
+                r#"{"color": null, "kind": "a.b"}"#,
+                r#"{"color": 7, "kind": "a.b"}"#,
+            ],
+            &[
+                r#"{"color": "blue", "kind": "a.b"}"#,
+                r#"{"color": "red", "kind": "axb"}"#,
+            ],
+        );
+    }
+
+    #[test]
+    fn empty_schema_rejects_trailing_text() {
+        let rx = compile("{}");
+        check(
+            &rx,
+            &[
+                r#"{"a": [1, "b"], "c": null}"#,
+                "[true, {}]",
+                r#""text""#,
+                "-0.5e3",
+            ],
+            &[
+                r#"{"a": 1} x"#,
+                r#"{"a": 1}}"#,
+                "[1] [2]",
+                "1 2",
+                "null,",
+                r#""text"""#,
+                "{",
+            ],
+        );
+    }
+
+    #[test]
+    fn invalid_schemas() {
+        for schema in [
+            "{not json",
+            r#"{"type": "strin"}"#,
+            r#"{"type": "string", "pattern": "a+"}"#,
+            r#"{"type": "array", "minItems": 3, "maxItems": 2}"#,
+            r#"{"type": "object", "properties": {}, "required": ["a"]}"#,
+            r##"{"$defs": {"a": {"type": "array", "items": {"$ref": "#/$defs/a"}}}, "$ref": "#/$defs/a"}"##,
+            r##"{"$ref": "#/$defs/missing"}"##,
+        ] {
+            assert!(json_schema_to_regex(schema).is_err(), "{schema}");
+        }
+    }
+}
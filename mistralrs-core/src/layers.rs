@@ -265,6 +265,8 @@ pub enum Llama3RotaryEmbedding {
         sin: Tensor,
         cos: Tensor,
         is_gptx: bool,
+        /// Only the first `rot_dim` dimensions of each head are rotated.
+        rot_dim: usize,
     },
     Default(RotaryEmbedding),
 }
@@ -288,10 +290,10 @@ pub struct Llama3RopeConfig {
 }
 
 fn calculate_default_inv_freq(cfg: &llama::Config) -> Vec<f32> {
-    let head_dim = cfg.hidden_size / cfg.num_attention_heads;
-    (0..head_dim)
+    let rot_dim = cfg.rotary_dim();
+    (0..rot_dim)
         .step_by(2)
-        .map(|i| 1f32 / cfg.rope_theta.powf(i as f32 / head_dim as f32))
+        .map(|i| 1f32 / cfg.rope_theta.powf(i as f32 / rot_dim as f32))
         .collect()
 }
 
@@ -303,9 +305,10 @@ impl Llama3RotaryEmbedding {
             | Some(Llama3RopeConfig {
                 rope_type: Llama3RopeType::Default,
                 ..
-            }) => Ok(Self::Default(RotaryEmbedding::new(
+            }) => Ok(Self::Default(RotaryEmbedding::new_partial(
                 cfg.rope_theta,
                 cfg.hidden_size / cfg.num_attention_heads,
+                cfg.rotary_dim(),
                 cfg.max_position_embeddings,
                 dev,
                 is_gpt_neox,
@@ -347,6 +350,7 @@ impl Llama3RotaryEmbedding {
                     sin,
                     cos,
                     is_gptx: is_gpt_neox,
+                    rot_dim: cfg.rotary_dim(),
                 })
             }
         }
@@ -361,7 +365,12 @@ impl Llama3RotaryEmbedding {
         b_sz: usize,
     ) -> Result<()> {
         match self {
-            Self::Llama3 { sin, cos, is_gptx } => {
+            Self::Llama3 {
+                sin,
+                cos,
+                is_gptx,
+                rot_dim,
+            } => {
                 let (b_sz_seq_len, h, n_embd) = q.dims3()?;
                 *q = q
                     .reshape((b_sz, b_sz_seq_len / b_sz, h, n_embd))?
@@ -377,13 +386,23 @@ impl Llama3RotaryEmbedding {
                 for (i, offset) in positions.iter().enumerate() {
                     let cos = cos.narrow(0, *offset, seq_len)?;
                     let sin = sin.narrow(0, *offset, seq_len)?;
-                    let rope = if *is_gptx {
-                        candle_nn::rotary_emb::rope
-                    } else {
-                        candle_nn::rotary_emb::rope_i
+                    let rope = |xs: &Tensor| -> Result<Tensor> {
+                        let rope = if *is_gptx {
+                            candle_nn::rotary_emb::rope
+                        } else {
+                            candle_nn::rotary_emb::rope_i
+                        };
+                        let head_dim = xs.dim(D::Minus1)?;
+                        if *rot_dim == head_dim {
+                            return rope(&xs.contiguous()?, &cos, &sin);
+                        }
+                        // The dimensions past `rot_dim` are passed through unchanged.
+                        let xs_rot = xs.narrow(D::Minus1, 0, *rot_dim)?.contiguous()?;
+                        let xs_pass = xs.narrow(D::Minus1, *rot_dim, head_dim - rot_dim)?;
+                        Tensor::cat(&[rope(&xs_rot, &cos, &sin)?, xs_pass], D::Minus1)
                     };
-                    let q_embed = rope(&q.i(i)?.unsqueeze(0)?.contiguous()?, &cos, &sin)?;
-                    let k_embed = rope(&k.i(i)?.unsqueeze(0)?.contiguous()?, &cos, &sin)?;
+                    let q_embed = rope(&q.i(i)?.unsqueeze(0)?)?;
+                    let k_embed = rope(&k.i(i)?.unsqueeze(0)?)?;
                     q_embeds.push(q_embed);
                     k_embeds.push(k_embed);
                 }
@@ -396,6 +415,29 @@ impl Llama3RotaryEmbedding {
     }
 }
 
+/// Check the attention head configuration of a model when loading it, so that an invalid config is
+/// reported as such instead of as a shape mismatch in attention. `hidden_size` should be `None` for
+/// models which have an explicit `head_dim`.
+pub(crate) fn verify_attention_heads(
+    hidden_size: Option<usize>,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+) -> Result<()> {
+    if num_key_value_heads == 0 || num_attention_heads % num_key_value_heads != 0 {
+        candle_core::bail!(
+            "Invalid model config: `num_attention_heads` ({num_attention_heads}) must be a multiple of `num_key_value_heads` ({num_key_value_heads})."
+        );
+    }
+    if let Some(hidden_size) = hidden_size {
+        if num_attention_heads == 0 || hidden_size % num_attention_heads != 0 {
+            candle_core::bail!(
+                "Invalid model config: `hidden_size` ({hidden_size}) must be a multiple of `num_attention_heads` ({num_attention_heads})."
+            );
+        }
+    }
+    Ok(())
+}
+
 /// Matrix multiplication, configurable to be via f16 (to use the faster GEMM kernels) optionally.
 pub struct MatMul;
 
@@ -558,6 +600,35 @@ impl ScaledDotProductAttention {
             naive_sdpa(q, k, v, head_dim, mask)
         }
     }
+
+    /// Computes non-causal softmax(QK^T*sqrt(d_k))V, as used by the vision encoders.
+    /// `q`, `k` and `v` are (b_sz, n_attn_heads, seq_len, head_dim) and `mask` is additive.
+    ///
+    /// The flash attention kernel is only used if `use_flash_attn == true`, there is no mask
+    /// and the dtype and head dim are supported by the kernel. The kernel cannot apply an
+    /// arbitrary padding mask, so images with a variable number of patches (which are masked)
+    /// always go through the naive SDPA implementation.
+    pub fn run_bidirectional_attention(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        head_dim: usize,
+        mask: Option<&Tensor>,
+        use_flash_attn: bool,
+    ) -> Result<Tensor> {
+        let flash_compatible =
+            matches!(q.dtype(), DType::F16 | DType::BF16) && head_dim % 8 == 0 && head_dim <= 256;
+        if use_flash_attn && mask.is_none() && flash_compatible {
+            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+            let q = q.transpose(1, 2)?;
+            let k = k.transpose(1, 2)?;
+            let v = v.transpose(1, 2)?;
+            let softmax_scale = 1f32 / (head_dim as f32).sqrt();
+            return flash_attn(&q, &k, &v, softmax_scale, false)?.transpose(1, 2);
+        }
+        naive_sdpa(q, k, v, head_dim, mask)
+    }
 }
 
 /// Linear layer with fused bias matmul.
@@ -768,8 +839,126 @@ impl RotaryEmbedding {
     }
 }
 
+/// Multimodal RoPE (M-RoPE), as in Qwen2-VL. The rotary frequencies are split into temporal, height
+/// and width sections, each rotated by the matching component of the 3-D position of the token. When
+/// the three components are equal, as for text, this is the GPT-NeoX style [`RotaryEmbedding`].
+#[derive(Debug, Clone)]
+pub struct MRotaryEmbedding {
+    cos: Tensor,
+    sin: Tensor,
+    mrope_section: [usize; 3],
+}
+
+impl MRotaryEmbedding {
+    /// `mrope_section` holds the number of frequencies of the temporal, height and width sections,
+    /// which must sum to `head_dim / 2`.
+    pub fn new(
+        base: f32,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        mrope_section: [usize; 3],
+        device: &Device,
+        dtype: DType,
+    ) -> Result<Self> {
+        if mrope_section.iter().sum::<usize>() != head_dim / 2 {
+            candle_core::bail!(
+                "The M-RoPE sections {mrope_section:?} must sum to half the head dim {head_dim}."
+            );
+        }
+        let inv_freq: Vec<_> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / base.powf(i as f32 / head_dim as f32))
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
+        let t = Tensor::arange(0u32, max_position_embeddings as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((max_position_embeddings, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            cos: freqs.cos()?.to_dtype(dtype)?,
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            mrope_section,
+        })
+    }
+
+    /// The cos and sin of each token, with shape `(n_tokens, 1, head_dim)`. The frequencies of each
+    /// section are taken at the position of its component. They are computed once per forward pass
+    /// and rotate the queries and keys of every layer by [`Self::apply`].
+    pub fn cos_sin(&self, position_ids: &[[usize; 3]]) -> Result<(Tensor, Tensor)> {
+        let mut cos = Vec::new();
+        let mut sin = Vec::new();
+        let mut start = 0;
+        for (component, len) in self.mrope_section.into_iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let positions = position_ids
+                .iter()
+                .map(|pos| pos[component] as u32)
+                .collect::<Vec<_>>();
+            let positions = Tensor::from_vec(positions, position_ids.len(), self.cos.device())?;
+            cos.push(
+                self.cos
+                    .narrow(1, start, len)?
+                    .index_select(&positions, 0)?,
+            );
+            sin.push(
+                self.sin
+                    .narrow(1, start, len)?
+                    .index_select(&positions, 0)?,
+            );
+            start += len;
+        }
+        let cos = Tensor::cat(&cos, 1)?;
+        let sin = Tensor::cat(&sin, 1)?;
+        Ok((
+            Tensor::cat(&[&cos, &cos], D::Minus1)?.unsqueeze(1)?,
+            Tensor::cat(&[&sin, &sin], D::Minus1)?.unsqueeze(1)?,
+        ))
+    }
+
+    /// Rotate `q` and `k`, with shape `(n_tokens, n_heads, head_dim)` and a batch first order of the
+    /// tokens, by the `cos` and `sin` of [`Self::cos_sin`].
+    pub fn apply(cos: &Tensor, sin: &Tensor, q: &mut Tensor, k: &mut Tensor) -> Result<()> {
+        let rotate = |xs: &Tensor| -> Result<Tensor> {
+            let half = xs.dim(D::Minus1)? / 2;
+            let x1 = xs.narrow(D::Minus1, 0, half)?;
+            let x2 = xs.narrow(D::Minus1, half, half)?;
+            let rotated = Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)?;
+            xs.broadcast_mul(cos)? + rotated.broadcast_mul(sin)?
+        };
+        *q = rotate(q)?;
+        *k = rotate(k)?;
+        Ok(())
+    }
+
+    /// Rotate `q` and `k`, with shape `(n_tokens, n_heads, head_dim)` and a batch first order of the
+    /// tokens, by the 3-D `position_ids` of each token.
+    pub fn forward(
+        &self,
+        position_ids: &[[usize; 3]],
+        q: &mut Tensor,
+        k: &mut Tensor,
+    ) -> Result<()> {
+        let (cos, sin) = self.cos_sin(position_ids)?;
+        Self::apply(&cos, &sin, q, k)
+    }
+}
+
 mod tests {
 
+    #[test]
+    fn verify_attention_heads() {
+        use crate::layers::verify_attention_heads;
+
+        assert!(verify_attention_heads(Some(4096), 32, 8).is_ok());
+        assert!(verify_attention_heads(None, 16, 16).is_ok());
+        assert!(verify_attention_heads(Some(4096), 32, 6).is_err());
+        assert!(verify_attention_heads(Some(4096), 24, 8).is_err());
+        assert!(verify_attention_heads(None, 24, 0).is_err());
+    }
+
     #[test]
     fn fused_bias_linear() {
         use candle_core::{DType, Device, IndexOp, Tensor};
@@ -841,4 +1030,220 @@ mod tests {
             )
         }
     }
+
+    #[test]
+    fn llama3_partial_rotary_passes_tail_through() {
+        use candle_core::{DType, Device, Tensor, D};
+
+        use crate::layers::{Llama3RopeConfig, Llama3RopeType, Llama3RotaryEmbedding};
+        use crate::models::llama;
+
+        const SEQ_LEN: usize = 3;
+        const HEADS: usize = 2;
+        const HEAD_DIM: usize = 8;
+        const ROT_DIM: usize = 4;
+
+        let dev = Device::Cpu;
+        let llama3_scaling = Llama3RopeConfig {
+            factor: 1.,
+            low_freq_factor: 1.,
+            high_freq_factor: 4.,
+            original_max_position_embeddings: 16,
+            rope_type: Llama3RopeType::Llama3,
+        };
+        for rope_scaling in [Some(llama3_scaling), None] {
+            let is_llama3 = rope_scaling.is_some();
+            let cfg = llama::Config {
+                hidden_size: HEADS * HEAD_DIM,
+                num_attention_heads: HEADS,
+                num_key_value_heads: HEADS,
+                rope_theta: 10_000.,
+                max_position_embeddings: 16,
+                rope_scaling,
+                partial_rotary_factor: Some(0.5),
+                ..Default::default()
+            };
+            assert_eq!(cfg.rotary_dim(), ROT_DIM);
+            let rope = Llama3RotaryEmbedding::new(DType::F32, &cfg, &dev, true).unwrap();
+
+            let xs = Tensor::arange(0f32, (SEQ_LEN * HEADS * HEAD_DIM) as f32, &dev)
+                .unwrap()
+                .affine(0.1, -1.)
+                .unwrap()
+                .reshape((SEQ_LEN, HEADS, HEAD_DIM))
+                .unwrap();
+            let mut q = xs.clone();
+            let mut k = xs.clone();
+            let positions_kernel = Tensor::new(&[0u32], &dev).unwrap();
+            rope.forward(&[0], &positions_kernel, &mut q, &mut k, 1)
+                .unwrap();
+
+            // Compare everything as (heads, seq_len, head_dim). The Llama 3 RoPE returns
+            // (batch, heads, seq_len, head_dim) while the default one keeps the input layout.
+            let xs = xs.transpose(0, 1).unwrap();
+            let q = if q.rank() == 4 {
+                q.squeeze(0).unwrap()
+            } else {
+                q.transpose(0, 1).unwrap()
+            };
+            let tail = |t: &Tensor| {
+                t.narrow(D::Minus1, ROT_DIM, HEAD_DIM - ROT_DIM)
+                    .unwrap()
+                    .to_vec3::<f32>()
+                    .unwrap()
+            };
+            assert_eq!(tail(&q), tail(&xs));
+
+            if !is_llama3 {
+                continue;
+            }
+            // Reference GPT-NeoX style rotation of the first `ROT_DIM` dimensions.
+            let xs = xs.to_vec3::<f32>().unwrap();
+            let q = q.to_vec3::<f32>().unwrap();
+            let half = ROT_DIM / 2;
+            for h in 0..HEADS {
+                for pos in 0..SEQ_LEN {
+                    let x = &xs[h][pos];
+                    for j in 0..half {
+                        let inv_freq = 1. / 10_000f32.powf((2 * j) as f32 / ROT_DIM as f32);
+                        let (sin, cos) = (pos as f32 * inv_freq).sin_cos();
+                        let expected = [
+                            x[j] * cos - x[j + half] * sin,
+                            x[j + half] * cos + x[j] * sin,
+                        ];
+                        let actual = [q[h][pos][j], q[h][pos][j + half]];
+                        for (e, a) in expected.into_iter().zip(actual) {
+                            assert!((e - a).abs() < 1e-5, "expected {e}, got {a}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "flash-attn")]
+    #[test]
+    fn bidirectional_flash_attention_matches_naive() {
+        use super::ScaledDotProductAttention;
+        use candle_core::{DType, Device, Tensor};
+
+        const B_SZ: usize = 2;
+        const HEADS: usize = 16;
+        // An odd number of patches, like the 577 tokens of a 336px CLIP image
+        const SEQ_LEN: usize = 577;
+        const HEAD_DIM: usize = 64;
+
+        let dev = Device::new_cuda(0).unwrap();
+        let rand = || {
+            Tensor::randn(0f32, 1f32, (B_SZ, HEADS, SEQ_LEN, HEAD_DIM), &dev)
+                .unwrap()
+                .to_dtype(DType::F16)
+                .unwrap()
+        };
+        let (q, k, v) = (rand(), rand(), rand());
+        let run = |mask: Option<&Tensor>, use_flash_attn| {
+            ScaledDotProductAttention
+                .run_bidirectional_attention(&q, &k, &v, HEAD_DIM, mask, use_flash_attn)
+                .unwrap()
+                .to_dtype(DType::F32)
+                .unwrap()
+        };
+        let max_diff = |a: &Tensor, b: &Tensor| {
+            (a - b)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap()
+        };
+
+        let flash = run(None, true);
+        let naive = run(None, false);
+        assert!(max_diff(&flash, &naive) < 1e-2);
+
+        // Padding patches are masked; this must match regardless of `use_flash_attn`.
+        let mask = Tensor::cat(
+            &[
+                Tensor::zeros((B_SZ, 1, SEQ_LEN, SEQ_LEN - 100), DType::F16, &dev).unwrap(),
+                Tensor::full(f32::NEG_INFINITY, (B_SZ, 1, SEQ_LEN, 100), &dev)
+                    .unwrap()
+                    .to_dtype(DType::F16)
+                    .unwrap(),
+            ],
+            3,
+        )
+        .unwrap();
+        let flash = run(Some(&mask), true);
+        let naive = run(Some(&mask), false);
+        assert!(max_diff(&flash, &naive) < 1e-2);
+    }
+
+    #[test]
+    fn mrope_of_text_is_rope() {
+        use candle_core::{DType, Device, Tensor};
+
+        use crate::layers::{MRotaryEmbedding, RotaryEmbedding};
+
+        const SEQ_LEN: usize = 5;
+        const OFFSET: usize = 3;
+        const HEADS: usize = 2;
+        const HEAD_DIM: usize = 16;
+
+        let dev = Device::Cpu;
+        let rope = RotaryEmbedding::new(10_000., HEAD_DIM, 32, &dev, true, DType::F32).unwrap();
+        let mrope =
+            MRotaryEmbedding::new(10_000., HEAD_DIM, 32, [2, 3, 3], &dev, DType::F32).unwrap();
+        assert!(MRotaryEmbedding::new(10_000., HEAD_DIM, 32, [2, 3, 2], &dev, DType::F32).is_err());
+
+        let xs = Tensor::arange(0f32, (SEQ_LEN * HEADS * HEAD_DIM) as f32, &dev)
+            .unwrap()
+            .affine(0.01, -1.)
+            .unwrap()
+            .reshape((SEQ_LEN, HEADS, HEAD_DIM))
+            .unwrap();
+        let (mut q, mut k) = (xs.clone(), xs.affine(-1., 0.).unwrap());
+        let positions_kernel = Tensor::arange(OFFSET as i64, (OFFSET + SEQ_LEN) as i64, &dev)
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap();
+        rope.forward(&[OFFSET], &positions_kernel, &mut q, &mut k, 1)
+            .unwrap();
+
+        let position_ids = crate::vision_models::text_mrope_position_ids(&[OFFSET], SEQ_LEN);
+        let (mut mq, mut mk) = (xs.clone(), xs.affine(-1., 0.).unwrap());
+        mrope.forward(&position_ids, &mut mq, &mut mk).unwrap();
+        // The single section of the LLaVA decoders, precomputed once for all layers.
+        let line = MRotaryEmbedding::new(
+            10_000.,
+            HEAD_DIM,
+            32,
+            [HEAD_DIM / 2, 0, 0],
+            &dev,
+            DType::F32,
+        )
+        .unwrap();
+        let (cos, sin) = line.cos_sin(&position_ids).unwrap();
+        let (mut lq, mut lk) = (xs.clone(), xs.affine(-1., 0.).unwrap());
+        MRotaryEmbedding::apply(&cos, &sin, &mut lq, &mut lk).unwrap();
+
+        for (expected, actual) in [(&q, mq), (&k, mk), (&q, lq), (&k, lk)] {
+            let diff = (expected - actual)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .max_keepdim(0)
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap();
+            assert!(diff < 1e-5, "{diff}");
+        }
+    }
 }
@@ -24,3 +24,39 @@ pub fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
         Tensor::cat(&vec![&x; n_rep], 2)?.reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))
     }
 }
+
+/// Select the indices of the `k` largest routing weights, in descending order of weight.
+///
+/// Exact ties (which can happen after quantization) are broken in favor of the lower expert
+/// index. This matches the order `torch.topk` returns in the reference implementations.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn topk_experts(routing_weights: &[f32], k: usize) -> Vec<u32> {
+    let mut dst = (0..routing_weights.len() as u32).collect::<Vec<u32>>();
+    dst.sort_by(|&i, &j| {
+        routing_weights[j as usize]
+            .total_cmp(&routing_weights[i as usize])
+            .then(i.cmp(&j))
+    });
+    dst.truncate(k);
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::topk_experts;
+
+    #[test]
+    fn topk_experts_ties_prefer_lower_index() {
+        // Experts 1, 3 and 6 share the top weight, 0 and 5 share the next.
+        let rw = [0.125, 0.25, 0.0, 0.25, 0.0625, 0.125, 0.25, 0.0];
+        assert_eq!(topk_experts(&rw, 2), vec![1, 3]);
+        assert_eq!(topk_experts(&rw, 4), vec![1, 3, 6, 0]);
+        assert_eq!(topk_experts(&rw, 5), vec![1, 3, 6, 0, 5]);
+    }
+
+    #[test]
+    fn topk_experts_all_equal() {
+        let rw = [0.25f32; 4];
+        assert_eq!(topk_experts(&rw, 2), vec![0, 1]);
+    }
+}
@@ -2,7 +2,9 @@
 
 use cublaslt::setup_cublas_lt_wrapper;
 use engine::Engine;
-pub use engine::TERMINATE_ALL_NEXT_STEP;
+pub use engine::{
+    ContextOverflow, ContextOverflowAction, ContextOverflowHandler, TERMINATE_ALL_NEXT_STEP,
+};
 pub use lora::Ordering;
 use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
@@ -10,19 +12,27 @@ pub use pipeline::Pipeline;
 use pyo3::exceptions::PyValueError;
 use std::{
     cell::RefCell,
+    collections::HashMap,
     error::Error,
     fs::OpenOptions,
     io::Write,
     sync::{atomic::AtomicBool, Arc, Mutex, RwLock},
     thread::{self, JoinHandle},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{
+    channel,
+    error::{TryRecvError, TrySendError},
+    Sender,
 };
-use tokio::sync::mpsc::{channel, Sender};
 
 mod aici;
+mod benchmark;
 mod cuda;
 mod device_map;
 mod engine;
+mod fim;
+mod json_schema;
 mod lora;
 mod model_loader;
 mod ops;
@@ -51,6 +61,7 @@ mod request;
 mod response;
 mod sampler;
 mod scheduler;
+mod seq_state;
 mod sequence;
 mod toml_selector;
 mod tools;
@@ -60,32 +71,43 @@ mod vision_models;
 mod xlora_models;
 
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
+pub use benchmark::{BenchConfig, BenchStats};
 pub use device_map::{DeviceLayerMapMetadata, DeviceMapMetadata, LayerDeviceMapper};
+pub use fim::FimTokens;
 pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
 pub use mistralrs_quant::IsqType;
 pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
 pub use pipeline::{
-    chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline, GGMLLoader,
-    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GemmaLoader,
-    Idefics2Loader, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths,
-    MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder,
+    chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline, CohereLoader,
+    GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GemmaLoader,
+    Idefics2Loader, KvCacheDtype, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
+    LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NgramSpeculativeConfig,
+    NgramSpeculativeLoader, NgramSpeculativePipeline, NormalLoader, NormalLoaderBuilder,
     NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader,
-    SpeculativeConfig, SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource,
-    VisionLoader, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
+    Qwen2MoeLoader, RollingCacheConfig, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline,
+    Starcoder2Loader, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
+    VisionSpecificConfig,
+};
+pub use request::{
+    Constraint, EmbeddingPooling, MessageContent, NormalRequest, Request, RequestMessage,
 };
-pub use request::{Constraint, MessageContent, NormalRequest, Request, RequestMessage};
 pub use response::Response;
 pub use response::*;
-pub use sampler::{CustomLogitsProcessor, SamplingParams, StopTokens, TopLogprob};
+pub use sampler::{
+    BadWordsLogitsProcessor, CustomLogitsProcessor, GreedyTieBreak, NoRepeatNGramLogitsProcessor,
+    SamplingParams, StopTokens, TemperatureOrder, TopLogprob, DEFAULT_TEMPERATURE_FLOOR,
+};
 pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
 use serde::Serialize;
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub use tools::{
-    CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
+    CalledFunction, CalledFunctionDelta, Function, Tool, ToolCallDelta, ToolCallResponse,
+    ToolCallType, ToolChoice, ToolType,
 };
 pub use topology::{LayerTopology, Topology};
 pub use utils::debug::initialize_logging;
+pub use utils::images::{load_image, ImageTooLarge, MAX_IMAGE_BYTES};
 pub use utils::memory_usage::MemoryUsage;
 pub use utils::normal::{ModelDType, TryIntoDType};
 pub use utils::paged_attn_supported;
@@ -117,6 +139,14 @@ struct RebootState {
     prefix_cache_n: usize,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    nan_checks: bool,
+    context_overflow_handler: Option<ContextOverflowHandler>,
+    seed: Option<u64>,
+    grammar_cache_size: Option<usize>,
+    max_tokens_per_second: Option<f32>,
+    temperature_floor: Option<f64>,
+    fim_tokens: Option<FimTokens>,
+    rolling_cache: Option<RollingCacheConfig>,
 }
 
 #[derive(Debug)]
@@ -154,6 +184,16 @@ pub struct MistralRsBuilder {
     disable_eos_stop: Option<bool>,
     gemm_full_precision_f16: Option<bool>,
     throughput_logging_enabled: Option<()>,
+    context_overflow_handler: Option<ContextOverflowHandler>,
+    seed: Option<u64>,
+    kv_cache_dtype: Option<KvCacheDtype>,
+    grammar_cache_size: Option<usize>,
+    max_tokens_per_second: Option<f32>,
+    temperature_floor: Option<f64>,
+    fim_tokens: Option<FimTokens>,
+    warmup: Option<bool>,
+    rolling_cache: Option<RollingCacheConfig>,
+    nan_checks: Option<bool>,
 }
 
 impl MistralRsBuilder {
@@ -169,6 +209,16 @@ impl MistralRsBuilder {
             disable_eos_stop: None,
             gemm_full_precision_f16: None,
             throughput_logging_enabled: None,
+            context_overflow_handler: None,
+            seed: None,
+            kv_cache_dtype: None,
+            grammar_cache_size: None,
+            max_tokens_per_second: None,
+            temperature_floor: None,
+            fim_tokens: None,
+            warmup: None,
+            rolling_cache: None,
+            nan_checks: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -207,6 +257,76 @@ impl MistralRsBuilder {
         self.throughput_logging_enabled = Some(());
         self
     }
+    /// Set a handler which decides how to treat a request whose prompt and requested
+    /// number of tokens exceed the model's maximum sequence length.
+    pub fn with_context_overflow_handler(mut self, handler: ContextOverflowHandler) -> Self {
+        self.context_overflow_handler = Some(handler);
+        self
+    }
+    /// Seed the sampling RNG with a fixed value (default 0). The RNG is shared by all
+    /// sequences and advanced in scheduling order, so outputs are only reproducible
+    /// run-to-run if the same requests arrive in the same order and are batched the same way.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+    /// Store the KV cache quantized to fit longer contexts, see [`KvCacheDtype`]. This does not apply
+    /// to PagedAttention, and the setting is shared by all models in the process.
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KvCacheDtype) -> Self {
+        self.kv_cache_dtype = Some(kv_cache_dtype);
+        self
+    }
+    /// Keep up to `grammar_cache_size` compiled regex and yacc grammars each (default 32), so requests
+    /// repeating a constraint skip compiling it. Zero disables the cache.
+    pub fn with_grammar_cache_size(mut self, grammar_cache_size: usize) -> Self {
+        self.grammar_cache_size = Some(grammar_cache_size);
+        self
+    }
+    /// Limit the aggregate number of tokens generated per second by all sequences, for example to
+    /// share a GPU between deployments. The engine sleeps between steps as needed. This is a soft
+    /// cap, measured over a sliding window of one second, so single steps may exceed it. `None`
+    /// (the default) does not limit the throughput.
+    pub fn with_max_tokens_per_second(mut self, max_tokens_per_second: Option<f32>) -> Self {
+        self.max_tokens_per_second = max_tokens_per_second;
+        self
+    }
+    /// Sample greedily when the temperature of a request is at or below `temperature_floor`, as the
+    /// tempered probabilities are not numerically stable for tiny temperatures. Defaults to
+    /// [`DEFAULT_TEMPERATURE_FLOOR`].
+    pub fn with_temperature_floor(mut self, temperature_floor: f64) -> Self {
+        self.temperature_floor = Some(temperature_floor);
+        self
+    }
+    /// Use `fim_tokens` to lay out [`RequestMessage::FillInMiddle`] prompts, instead of the known
+    /// FIM tokens found in the tokenizer.
+    pub fn with_fim_tokens(mut self, fim_tokens: FimTokens) -> Self {
+        self.fim_tokens = Some(fim_tokens);
+        self
+    }
+    /// Run a short dummy request through the engine when building, and discard its output. This
+    /// compiles the kernels and allocates the buffers up front, so the first real request is not
+    /// slowed down by them. Building blocks until the warmup has finished. The warmup prompt is not
+    /// kept in the prefix cache.
+    pub fn with_warmup(mut self, warmup: bool) -> Self {
+        self.warmup = Some(warmup);
+        self
+    }
+    /// When a sequence reaches the maximum sequence length of the model, keep its first
+    /// `rolling_cache.sink` and last `rolling_cache.window` tokens and evict the rest, instead of
+    /// stopping it. This allows unbounded generation at the cost of forgetting the middle of the
+    /// context. It does not apply to vision models.
+    pub fn with_rolling_cache(mut self, rolling_cache: RollingCacheConfig) -> Self {
+        self.rolling_cache = Some(rolling_cache);
+        self
+    }
+    /// Check the logits of each step for NaN or infinite values, which usually come from broken
+    /// weights or quantization. A step which produces them fails its requests with a model error
+    /// naming the step and sequence, rather than sampling garbage. This copies the logits once more
+    /// per step, so it is off by default and meant for debugging.
+    pub fn with_nan_checks(mut self, nan_checks: bool) -> Self {
+        self.nan_checks = Some(nan_checks);
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -263,6 +383,16 @@ impl MistralRs {
             disable_eos_stop,
             gemm_full_precision_f16,
             throughput_logging_enabled,
+            context_overflow_handler,
+            seed,
+            kv_cache_dtype,
+            grammar_cache_size,
+            max_tokens_per_second,
+            temperature_floor,
+            fim_tokens,
+            warmup,
+            rolling_cache,
+            nan_checks,
         } = config;
 
         let model_supports_reduced_gemm = match pipeline.try_lock().unwrap().category() {
@@ -274,11 +404,52 @@ impl MistralRs {
         }
         setup_cublas_lt_wrapper();
 
+        let kv_cache_dtype = kv_cache_dtype.unwrap_or_default();
+        {
+            let pipeline = pipeline.try_lock().unwrap();
+            if kv_cache_dtype != KvCacheDtype::F16 && pipeline.get_metadata().cache_config.is_some()
+            {
+                tracing::warn!(
+                    "The KV cache dtype does not apply to PagedAttention, which is enabled."
+                );
+            }
+            pipeline.set_kv_cache_dtype(kv_cache_dtype);
+        }
+
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
         let no_prefix_cache = no_prefix_cache.unwrap_or(false);
         let prefix_cache_n = prefix_cache_n.unwrap_or(16);
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
+        let nan_checks = nan_checks.unwrap_or(false);
+        let max_tokens_per_second = max_tokens_per_second.filter(|max| {
+            let valid = *max > 0.;
+            if !valid {
+                tracing::warn!(
+                    "Ignoring a maximum of {max} tokens per second, it must be positive."
+                );
+            }
+            valid
+        });
+        let rolling_cache = rolling_cache.filter(|rolling_cache| {
+            let pipeline = pipeline.try_lock().unwrap();
+            let metadata = pipeline.get_metadata();
+            // The image tokens of a prompt may be evicted, so its images cannot be processed again.
+            if matches!(pipeline.category(), ModelCategory::Vision { .. }) {
+                tracing::warn!("Ignoring the rolling cache, it does not apply to vision models.");
+                false
+            } else if !rolling_cache.fits(metadata.max_seq_len) {
+                tracing::warn!(
+                    "Ignoring the rolling cache, a sink of {} and a window of {} tokens do not fit in the maximum sequence length {}.",
+                    rolling_cache.sink,
+                    rolling_cache.window,
+                    metadata.max_seq_len
+                );
+                false
+            } else {
+                true
+            }
+        });
 
         let reboot_state = RebootState {
             pipeline: pipeline.clone(),
@@ -289,6 +460,14 @@ impl MistralRs {
             prefix_cache_n,
             disable_eos_stop,
             throughput_logging_enabled: throughput_logging_enabled.is_some(),
+            nan_checks,
+            context_overflow_handler: context_overflow_handler.clone(),
+            seed,
+            grammar_cache_size,
+            max_tokens_per_second,
+            temperature_floor,
+            fim_tokens: fim_tokens.clone(),
+            rolling_cache,
         };
 
         let (tx, rx) = channel(10_000);
@@ -312,11 +491,35 @@ impl MistralRs {
                 if throughput_logging_enabled.is_some() {
                     engine.enable_throughput_logging();
                 }
+                if nan_checks {
+                    engine.enable_nan_checks();
+                }
+                if let Some(handler) = context_overflow_handler {
+                    engine.set_context_overflow_handler(handler);
+                }
+                if let Some(seed) = seed {
+                    engine.set_seed(seed);
+                }
+                if let Some(size) = grammar_cache_size {
+                    engine.set_grammar_cache_size(size);
+                }
+                if let Some(max) = max_tokens_per_second {
+                    engine.set_max_tokens_per_second(max);
+                }
+                if let Some(floor) = temperature_floor {
+                    engine.set_temperature_floor(floor);
+                }
+                if let Some(fim_tokens) = fim_tokens {
+                    engine.set_fim_tokens(fim_tokens);
+                }
+                if let Some(rolling_cache) = rolling_cache {
+                    engine.set_rolling_cache(rolling_cache);
+                }
                 engine.run().await;
             });
         });
 
-        Arc::new(Self {
+        let this = Arc::new(Self {
             sender,
             log,
             id,
@@ -327,6 +530,36 @@ impl MistralRs {
             next_request_id: Mutex::new(RefCell::new(0)),
             reboot_state,
             engine_handler: RwLock::new(engine_handler),
+        });
+
+        if warmup.unwrap_or(false) {
+            let start = Instant::now();
+            match this.warmup() {
+                Ok(()) => tracing::info!(
+                    "Warmed up the engine in {:.2}s.",
+                    start.elapsed().as_secs_f32()
+                ),
+                Err(e) => tracing::warn!("Engine warmup failed: {e}"),
+            }
+        }
+
+        this
+    }
+
+    /// Send the warmup request and wait for it. This runs on a separate thread, as the builder may
+    /// be called from within an async runtime.
+    fn warmup(&self) -> anyhow::Result<()> {
+        let vocab_size = get_mut_arcmutex!(self.reboot_state.pipeline)
+            .get_metadata()
+            .tok_trie
+            .info()
+            .vocab_size;
+        let sender = self.get_sender()?;
+        let id = self.next_request_id();
+        thread::scope(|s| {
+            s.spawn(|| warmup_engine(&sender, id, vocab_size))
+                .join()
+                .map_err(|_| anyhow::Error::msg("The warmup thread panicked."))?
         })
     }
 
@@ -365,6 +598,30 @@ impl MistralRs {
                     if reboot_state.throughput_logging_enabled {
                         engine.enable_throughput_logging();
                     }
+                    if reboot_state.nan_checks {
+                        engine.enable_nan_checks();
+                    }
+                    if let Some(handler) = reboot_state.context_overflow_handler {
+                        engine.set_context_overflow_handler(handler);
+                    }
+                    if let Some(seed) = reboot_state.seed {
+                        engine.set_seed(seed);
+                    }
+                    if let Some(size) = reboot_state.grammar_cache_size {
+                        engine.set_grammar_cache_size(size);
+                    }
+                    if let Some(max) = reboot_state.max_tokens_per_second {
+                        engine.set_max_tokens_per_second(max);
+                    }
+                    if let Some(floor) = reboot_state.temperature_floor {
+                        engine.set_temperature_floor(floor);
+                    }
+                    if let Some(fim_tokens) = reboot_state.fim_tokens {
+                        engine.set_fim_tokens(fim_tokens);
+                    }
+                    if let Some(rolling_cache) = reboot_state.rolling_cache {
+                        engine.set_rolling_cache(rolling_cache);
+                    }
                     engine.run().await;
                 });
             });
@@ -396,6 +653,19 @@ impl MistralRs {
         }
     }
 
+    /// Whether the engine is alive and answers a [`Request::Ping`] within `timeout`, for liveness
+    /// checks. Unlike [`MistralRs::get_sender`], this does not reboot a dead engine. This blocks, so
+    /// it must not be called from an async context.
+    pub fn is_ready(&self, timeout: Duration) -> bool {
+        if self.engine_dead().unwrap_or(true) {
+            return false;
+        }
+        match self.sender.read() {
+            Ok(sender) => ping_engine(&sender, timeout),
+            Err(_) => false,
+        }
+    }
+
     pub fn get_id(&self) -> String {
         self.id.clone()
     }
@@ -404,6 +674,125 @@ impl MistralRs {
         self.creation_time
     }
 
+    /// The token to bytes mapping of the loaded model's vocabulary, as used for constrained decoding.
+    /// Special tokens do not correspond to any bytes and map to an empty byte string; see
+    /// [`MistralRs::get_special_tokens`].
+    pub fn get_vocab(&self) -> Vec<(u32, Vec<u8>)> {
+        let tok_trie = get_mut_arcmutex!(self.reboot_state.pipeline)
+            .get_metadata()
+            .tok_trie
+            .clone();
+        (0..tok_trie.info().vocab_size)
+            .map(|id| (id, tok_trie.token(id).to_vec()))
+            .collect()
+    }
+
+    /// Tokenize `text` with the loaded model's tokenizer, without adding any special tokens.
+    pub fn tokenize(&self, text: &str) -> anyhow::Result<Vec<u32>> {
+        let tokenizer = get_mut_arcmutex!(self.reboot_state.pipeline).tokenizer();
+        Ok(tokenizer
+            .encode(text, false)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec())
+    }
+
+    /// The special (added) tokens of the loaded model's tokenizer, mapping their content to the token id.
+    pub fn get_special_tokens(&self) -> HashMap<String, u32> {
+        let tokenizer = get_mut_arcmutex!(self.reboot_state.pipeline).tokenizer();
+        tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, tok)| tok.special)
+            .map(|(id, tok)| (tok.content, id))
+            .collect()
+    }
+
+    /// The token ids which are treated as EOS by the loaded model.
+    pub fn get_eos_tokens(&self) -> Vec<u32> {
+        get_mut_arcmutex!(self.reboot_state.pipeline)
+            .get_metadata()
+            .eos_tok
+            .clone()
+    }
+
+    /// Saturate the engine with `config.n_requests` synthetic completion requests and report the
+    /// throughput and latency. This blocks until all requests have finished, so it must not be
+    /// called from within an async runtime.
+    pub fn benchmark(&self, config: BenchConfig) -> anyhow::Result<BenchStats> {
+        if config.n_requests == 0 || config.prompt_len == 0 || config.gen_len == 0 {
+            anyhow::bail!("The number of requests, prompt length and generation length must be greater than 0.");
+        }
+        let vocab_size = get_mut_arcmutex!(self.reboot_state.pipeline)
+            .get_metadata()
+            .tok_trie
+            .info()
+            .vocab_size;
+        let sender = self.get_sender()?;
+        let (tx, mut rx) = channel(10_000);
+
+        let start = Instant::now();
+        for i in 0..config.n_requests {
+            // Offset each prompt so that the prefix cache does not skew the results.
+            let prompt = (1000u32..)
+                .skip(i)
+                .take(config.prompt_len)
+                .map(|tok| tok % vocab_size)
+                .collect();
+            let request = Request::Normal(NormalRequest {
+                id: self.next_request_id(),
+                messages: RequestMessage::CompletionTokens(prompt),
+                sampling_params: SamplingParams {
+                    max_len: Some(config.gen_len),
+                    ..Default::default()
+                },
+                response: tx.clone(),
+                return_logprobs: false,
+                is_streaming: false,
+                constraint: Constraint::None,
+                suffix: None,
+                adapters: None,
+                tools: None,
+                tool_choice: None,
+                logits_processors: None,
+                early_exit_layer: None,
+                xlora_global_scaling: None,
+                include_usage: false,
+                token_healing: false,
+                truncate_prompt: false,
+                add_generation_prompt: true,
+            });
+            sender
+                .blocking_send(request)
+                .map_err(|_| anyhow::Error::msg("The engine stopped receiving requests."))?;
+        }
+
+        let mut usages = Vec::with_capacity(config.n_requests);
+        while usages.len() < config.n_requests {
+            match rx.blocking_recv() {
+                Some(Response::CompletionDone(res)) => usages.push(res.usage),
+                Some(Response::ValidationError(e)) => {
+                    anyhow::bail!("Benchmark request failed validation: {e}")
+                }
+                Some(Response::InternalError(e)) => {
+                    anyhow::bail!("Benchmark request failed: {e}")
+                }
+                Some(Response::CompletionModelError(e, _)) => {
+                    anyhow::bail!("Benchmark request failed: {e}")
+                }
+                Some(Response::Done(_))
+                | Some(Response::ModelError(_, _))
+                | Some(Response::Chunk(_))
+                | Some(Response::CompletionChunk(_))
+                | Some(Response::Tokenize(_))
+                | Some(Response::Detokenize(_)) => unreachable!(),
+                None => anyhow::bail!("The engine stopped before the benchmark finished."),
+            }
+        }
+
+        Ok(BenchStats::new(&usages, start.elapsed()))
+    }
+
     pub fn next_request_id(&self) -> usize {
         let l = self.next_request_id.lock().unwrap();
         let last = &mut *l.borrow_mut();
@@ -452,3 +841,149 @@ impl MistralRs {
         }
     }
 }
+
+/// Number of prompt tokens of the warmup request.
+const WARMUP_PROMPT_LEN: u32 = 8;
+
+/// Run a short completion request through the engine, discarding the output, and then drop the
+/// warmup prompt from the prefix cache. This blocks until the request has finished.
+fn warmup_engine(sender: &Sender<Request>, id: usize, vocab_size: u32) -> anyhow::Result<()> {
+    let (tx, mut rx) = channel(1);
+    let request = Request::Normal(NormalRequest {
+        id,
+        messages: RequestMessage::CompletionTokens(
+            (0..WARMUP_PROMPT_LEN).map(|tok| tok % vocab_size).collect(),
+        ),
+        // Greedy by default, so the seeded sampling RNG is not advanced by the warmup.
+        sampling_params: SamplingParams {
+            max_len: Some(1),
+            ..Default::default()
+        },
+        response: tx,
+        return_logprobs: false,
+        is_streaming: false,
+        constraint: Constraint::None,
+        suffix: None,
+        adapters: None,
+        tools: None,
+        tool_choice: None,
+        logits_processors: None,
+        early_exit_layer: None,
+        xlora_global_scaling: None,
+        include_usage: false,
+        token_healing: false,
+        truncate_prompt: false,
+        add_generation_prompt: true,
+    });
+    sender
+        .blocking_send(request)
+        .map_err(|_| anyhow::Error::msg("The engine stopped receiving requests."))?;
+    match rx.blocking_recv() {
+        Some(Response::CompletionDone(_)) => (),
+        Some(Response::ValidationError(e)) | Some(Response::InternalError(e)) => {
+            anyhow::bail!("Warmup request failed: {e}")
+        }
+        Some(Response::CompletionModelError(e, _)) | Some(Response::ModelError(e, _)) => {
+            anyhow::bail!("Warmup request failed: {e}")
+        }
+        Some(_) => anyhow::bail!("Unexpected response to the warmup request."),
+        None => anyhow::bail!("The engine stopped before the warmup finished."),
+    }
+    sender
+        .blocking_send(Request::ClearPrefixCache)
+        .map_err(|_| anyhow::Error::msg("The engine stopped receiving requests."))
+}
+
+/// Send a [`Request::Ping`] and wait for the answer until `timeout`. Returns false as soon as the
+/// engine's receiver or the response sender is dropped, such as when the engine panicked.
+fn ping_engine(sender: &Sender<Request>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let (tx, mut rx) = channel(1);
+    let mut request = Request::Ping(tx);
+    // The engine may be busy and its queue full, so neither side may block past the deadline.
+    loop {
+        match sender.try_send(request) {
+            Ok(()) => break,
+            Err(TrySendError::Closed(_)) => return false,
+            Err(TrySendError::Full(returned)) => request = returned,
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    loop {
+        match rx.try_recv() {
+            Ok(_) => return true,
+            Err(TryRecvError::Disconnected) => return false,
+            Err(TryRecvError::Empty) => {}
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use tokio::sync::mpsc::channel;
+
+    use super::{ping_engine, warmup_engine, WARMUP_PROMPT_LEN};
+    use crate::{response::PingResponse, Request, RequestMessage, Response};
+
+    #[test]
+    fn ping_fails_once_engine_is_gone() {
+        let (sender, mut rx) = channel(1);
+        let engine = thread::spawn(move || {
+            if let Some(Request::Ping(response)) = rx.blocking_recv() {
+                response
+                    .blocking_send(PingResponse {
+                        timestamp: 0,
+                        queue_depth: 0,
+                    })
+                    .unwrap();
+            }
+        });
+        assert!(ping_engine(&sender, Duration::from_secs(10)));
+        engine.join().unwrap();
+
+        // The engine exited and dropped its receiver.
+        assert!(!ping_engine(&sender, Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn ping_times_out_when_engine_does_not_answer() {
+        let (sender, _rx) = channel(1);
+        assert!(!ping_engine(&sender, Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn warmup_sends_a_short_request_and_reports_failures() {
+        const VOCAB_SIZE: u32 = 5;
+        let (sender, mut rx) = channel(1);
+        let engine = thread::spawn(move || {
+            let Some(Request::Normal(request)) = rx.blocking_recv() else {
+                panic!("Expected a normal request.");
+            };
+            let RequestMessage::CompletionTokens(ref toks) = request.messages else {
+                panic!("Expected a tokenized completion request.");
+            };
+            assert_eq!(toks.len(), WARMUP_PROMPT_LEN as usize);
+            assert!(toks.iter().all(|tok| *tok < VOCAB_SIZE));
+            assert_eq!(request.sampling_params.max_len, Some(1));
+            request
+                .response
+                .blocking_send(Response::ValidationError("out of memory".into()))
+                .unwrap();
+            // The prefix cache is only cleared after a successful warmup.
+            assert!(rx.blocking_recv().is_none());
+        });
+        let err = warmup_engine(&sender, 0, VOCAB_SIZE).unwrap_err();
+        assert!(err.to_string().contains("out of memory"));
+        drop(sender);
+        engine.join().unwrap();
+    }
+}
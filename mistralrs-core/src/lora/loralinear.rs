@@ -18,6 +18,9 @@ pub struct LoraLinear {
     layer_n: usize,
     merged: bool,
     adapters: HashMap<String, Adapter>,
+    linear_config: LoraLinearConfig,
+    a_prefix: String,
+    b_prefix: String,
 }
 
 impl LoraLinear {
@@ -109,6 +112,9 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                linear_config: linear_config.clone(),
+                a_prefix: a_vb.prefix(),
+                b_prefix: b_vb.prefix(),
             })
         } else {
             Ok(LoraLinear {
@@ -121,13 +127,16 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                linear_config: linear_config.clone(),
+                a_prefix: a_vb.prefix(),
+                b_prefix: b_vb.prefix(),
             })
         }
     }
 }
 
 impl AdapterSwapper for LoraLinear {
-    fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()> {
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -137,7 +146,7 @@ impl AdapterSwapper for LoraLinear {
                 a.clear();
                 b.clear();
                 s.clear();
-                for adapter_name in adapter_names {
+                for (adapter_name, weight) in adapters {
                     let Adapter {
                         a: a_w,
                         b: b_w,
@@ -148,7 +157,7 @@ impl AdapterSwapper for LoraLinear {
                     };
                     a.push(a_w.clone());
                     b.push(b_w.clone());
-                    s.push(*scale);
+                    s.push(*scale * *weight);
                 }
             }
             _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
@@ -158,6 +167,28 @@ impl AdapterSwapper for LoraLinear {
     fn can_load(&self) -> bool {
         true
     }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.merged {
+            bail!("Cannot load adapter `{name}`: the LoRA weights of this model are merged, which is the case unless it has preloaded adapters.");
+        }
+        let a_vb = vb.set_prefix(&self.a_prefix);
+        let b_vb = vb.set_prefix(&self.b_prefix);
+        if !a_vb.contains_tensor("weight") || !b_vb.contains_tensor("weight") {
+            bail!(
+                "Adapter `{name}` has no weights for `{}`, it must target the same modules as the adapters of the model.",
+                self.a_prefix.trim_end_matches(".lora_A")
+            );
+        }
+        let adapter = make_adapter(a_vb, b_vb, cfg, &self.linear_config)?;
+        // Adapters are only activated when they are not stacked.
+        if let (Either::Right((_, a)), Either::Right((_, b))) = (&self.a_adapters, &self.b_adapters)
+        {
+            self.a_adapters = Either::Left(a.clone());
+            self.b_adapters = Either::Left(b.clone());
+        }
+        self.adapters.insert(name.to_string(), adapter);
+        Ok(1)
+    }
 }
 
 impl Merge for LoraLinear {
@@ -286,3 +317,111 @@ impl LinearLayerLike for LoraLinear {
         !self.adapters.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{Linear, VarBuilder};
+
+    use super::LoraLinear;
+    use crate::lora::{AdapterSwapper, LinearLayerLike, LoraConfig, LoraLinearConfig};
+
+    #[test]
+    fn zero_weight_reproduces_base_outputs() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let base = Linear::new(Tensor::randn(0f32, 1., (3, 4), &dev)?, None);
+        let tensors = HashMap::from([
+            (
+                "lora_A.weight".to_string(),
+                Tensor::randn(0f32, 1., (2, 4), &dev)?,
+            ),
+            (
+                "lora_B.weight".to_string(),
+                Tensor::randn(0f32, 1., (3, 2), &dev)?,
+            ),
+        ]);
+        let cfg: LoraConfig = serde_json::from_value(serde_json::json!({
+            "r": 2,
+            "lora_alpha": 4.0,
+            "lora_dropout": null,
+            "target_modules": [],
+        }))
+        .unwrap();
+        let preload = Some(HashMap::from([(
+            "adapter".to_string(),
+            (VarBuilder::from_tensors(tensors, DType::F32, &dev), cfg),
+        )]));
+        let vb = VarBuilder::from_tensors(HashMap::new(), DType::F32, &dev);
+        let mut layer =
+            LoraLinear::new(&base, &LoraLinearConfig::new(4, 3), &[], &vb, 0, &preload)?;
+
+        let x = Tensor::randn(0f32, 1., (1, 2, 4), &dev)?;
+        let expected = x.apply(&base)?;
+        let diff = |layer: &LoraLinear| -> candle_core::Result<f32> {
+            (layer.lora_forward(&x, None, 1., None)? - &expected)?
+                .abs()?
+                .max_all()?
+                .to_scalar()
+        };
+
+        layer.activate(&[("adapter".to_string(), 0.)])?;
+        assert!(diff(&layer)? < 1e-6);
+        layer.activate(&[("adapter".to_string(), 1.)])?;
+        let full = diff(&layer)?;
+        assert!(full > 1e-3);
+        // The contribution of the adapter is linear in its weight.
+        layer.activate(&[("adapter".to_string(), 0.5)])?;
+        assert!((diff(&layer)? * 2. - full).abs() < 1e-4 * full.max(1.));
+        Ok(())
+    }
+
+    #[test]
+    fn global_scaling_weight_scales_adapters() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let base = Linear::new(Tensor::randn(0f32, 1., (3, 4), &dev)?, None);
+        let tensors = HashMap::from([
+            (
+                "lora_A.weight".to_string(),
+                Tensor::randn(0f32, 1., (2, 4), &dev)?,
+            ),
+            (
+                "lora_B.weight".to_string(),
+                Tensor::randn(0f32, 1., (3, 2), &dev)?,
+            ),
+        ]);
+        let cfg: LoraConfig = serde_json::from_value(serde_json::json!({
+            "r": 2,
+            "lora_alpha": 4.0,
+            "lora_dropout": null,
+            "target_modules": [],
+        }))
+        .unwrap();
+        let preload = Some(HashMap::from([(
+            "adapter".to_string(),
+            (VarBuilder::from_tensors(tensors, DType::F32, &dev), cfg),
+        )]));
+        let vb = VarBuilder::from_tensors(HashMap::new(), DType::F32, &dev);
+        let mut layer =
+            LoraLinear::new(&base, &LoraLinearConfig::new(4, 3), &[], &vb, 0, &preload)?;
+        layer.activate(&[("adapter".to_string(), 1.)])?;
+
+        let x = Tensor::randn(0f32, 1., (1, 2, 4), &dev)?;
+        let max_diff = |a: &Tensor, b: &Tensor| -> candle_core::Result<f32> {
+            (a - b)?.abs()?.max_all()?.to_scalar()
+        };
+        let base_out = x.apply(&base)?;
+        let full = layer.lora_forward(&x, None, 1., None)?;
+        assert!(max_diff(&full, &base_out)? > 1e-3);
+
+        // A global scaling of 0 runs the base layer, and 1 the adapters at their own weights.
+        assert!(max_diff(&layer.lora_forward(&x, None, 0., None)?, &base_out)? < 1e-6);
+        layer.activate(&[("adapter".to_string(), 0.5)])?;
+        let half = layer.lora_forward(&x, None, 1., None)?;
+        layer.activate(&[("adapter".to_string(), 1.)])?;
+        assert!(max_diff(&layer.lora_forward(&x, None, 0.5, None)?, &half)? < 1e-5);
+        assert!(max_diff(&layer.lora_forward(&x, None, 1., None)?, &full)? < 1e-6);
+        Ok(())
+    }
+}
@@ -118,16 +118,22 @@ pub trait Merge {
 }
 
 pub trait AdapterSwapper {
-    fn activate(&mut self, adapter_names: &[String]) -> Result<usize> {
+    /// Activate the named adapters, scaling the contribution of each by its weight.
+    fn activate(&mut self, adapters: &[(String, f64)]) -> Result<usize> {
         if self.can_load() {
-            self._activate_adapters(adapter_names)?;
+            self._activate_adapters(adapters)?;
             Ok(1)
         } else {
             Ok(0)
         }
     }
-    fn _activate_adapters(&mut self, adapters: &[String]) -> Result<()>;
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()>;
     fn can_load(&self) -> bool;
+    /// Load the adapter `name` from `vb`, which holds the weights of all its layers, so that it can be
+    /// activated. Returns the number of layers which loaded it, which is 0 for non-LoRA layers.
+    fn load_adapter(&mut self, _name: &str, _vb: &VarBuilder, _cfg: &LoraConfig) -> Result<usize> {
+        Ok(0)
+    }
 }
 
 impl Merge for Linear {
@@ -140,7 +146,7 @@ impl Merge for Linear {
 }
 
 impl AdapterSwapper for Linear {
-    fn _activate_adapters(&mut self, _adapter: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, _adapter: &[(String, f64)]) -> Result<()> {
         unreachable!()
     }
     fn can_load(&self) -> bool {
@@ -174,7 +174,7 @@ impl QLoraLinear {
 }
 
 impl AdapterSwapper for QLoraLinear {
-    fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()> {
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -184,7 +184,7 @@ impl AdapterSwapper for QLoraLinear {
                 a.clear();
                 b.clear();
                 s.clear();
-                for adapter_name in adapter_names {
+                for (adapter_name, weight) in adapters {
                     let Adapter {
                         a: a_w,
                         b: b_w,
@@ -195,7 +195,7 @@ impl AdapterSwapper for QLoraLinear {
                     };
                     a.push(a_w.clone());
                     b.push(b_w.clone());
-                    s.push(*scale);
+                    s.push(*scale * *weight);
                 }
             }
             _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
@@ -308,5 +308,9 @@ pub enum ModelSelected {
         /// Path to a topology YAML file.
         #[arg(long)]
         topology: Option<String>,
+
+        /// Downscale images, preserving their aspect ratio, so that neither side is longer than this.
+        #[arg(long)]
+        max_image_edge: Option<u32>,
     },
 }
@@ -13,11 +13,14 @@ use crate::{
     },
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
-    layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention},
+    layers::{
+        repeat_kv, verify_attention_heads, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention,
+    },
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits, text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel,
+        extract_logits, imatrix_layer_names, imatrix_mlp_names,
+        text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel, KvCacheDtype,
         NormalLoadingMetadata, NormalModel,
     },
     utils::progress::NiceProgressBar,
@@ -236,6 +239,7 @@ impl Attention {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         xs: &Tensor,
@@ -243,6 +247,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
@@ -308,7 +313,7 @@ impl Attention {
                 )?
             }
             None => {
-                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+                let (k, v) = Cache::update_kv_cache(kv_cache, kv_cache_dtype, k, v, false)?;
 
                 let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
                 let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
@@ -385,6 +390,7 @@ impl DecoderLayer {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         xs: &Tensor,
@@ -392,6 +398,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let residual = xs;
@@ -402,6 +409,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             metadata,
         )?;
         let xs = (xs + residual)?;
@@ -434,6 +442,7 @@ impl Model {
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Self> {
+        verify_attention_heads(None, cfg.num_attention_heads, cfg.num_key_value_heads)?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization in {} bits.",
@@ -526,11 +535,35 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
+    ) -> Result<Tensor> {
+        let mut xs = self.hidden_states(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            metadata,
+            early_exit_layer,
+        )?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+
+    /// Hidden states of the final layer after the final norm, before the LM head.
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         let xs = self.embed_tokens.forward(input_ids)?;
         let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let attention_mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             metadata
@@ -540,7 +573,8 @@ impl Model {
             xs.dtype(),
             self.layers[0].self_attn.num_heads,
         )?;
-        for (i, layer) in self.layers.iter().enumerate() {
+        let n_layers = early_exit_layer.unwrap_or(self.layers.len());
+        for (i, layer) in self.layers.iter().take(n_layers).enumerate() {
             xs = self.mapper.map(xs, i)?;
             xs = layer.forward(
                 &xs,
@@ -549,19 +583,16 @@ impl Model {
                     .map(|m| m.to_device(xs.device()).unwrap())
                     .as_ref(),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(xs.device())?,
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
             )?;
         }
         let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            xs = xs.to_dtype(t)?;
-        }
-        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+        xs.apply(&self.norm)
     }
 }
 
@@ -590,9 +621,25 @@ impl IsqModel for Model {
         }
         (tensors, &*self.mapper)
     }
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        let mut names = vec![Some("output.weight".to_string())];
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            names.extend(imatrix_layer_names(
+                i,
+                &["attn_q", "attn_k", "attn_v", "attn_output"],
+            ));
+            names.extend(imatrix_mlp_names(
+                i,
+                &mut *layer.mlp,
+                &["ffn_gate", "ffn_up", "ffn_down"],
+            ));
+        }
+        Some(names)
+    }
 }
 
 impl NormalModel for Model {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         input_ids: &Tensor,
@@ -601,6 +648,7 @@ impl NormalModel for Model {
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -608,8 +656,17 @@ impl NormalModel for Model {
             start_offsets_kernel,
             context_lens,
             metadata,
+            early_exit_layer,
         )
     }
+    fn forward_embeddings(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+    ) -> Result<Tensor> {
+        self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, None, None)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -622,6 +679,7 @@ impl NormalModel for Model {
         _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        _global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
@@ -13,10 +13,11 @@ use crate::{
     },
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
-    layers::{repeat_kv, CausalMasker, MatMul, RmsNorm},
+    layers::{repeat_kv, verify_attention_heads, CausalMasker, MatMul, RmsNorm},
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits, text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel,
+        extract_logits, imatrix_layer_names, imatrix_mlp_names,
+        text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel, KvCacheDtype,
         NormalLoadingMetadata, NormalModel,
     },
     utils::progress::NiceProgressBar,
@@ -62,6 +63,18 @@ impl Config {
             (None, None) => candle_core::bail!("none of hidden_act and hidden_activation are set"),
         }
     }
+
+    /// The sliding window of the attention of layer `layer_idx`. The layers alternate between
+    /// sliding window attention, starting with the first layer, and global attention.
+    pub fn layer_sliding_window(&self, layer_idx: usize) -> Option<usize> {
+        (layer_idx % 2 == 0).then_some(self.sliding_window)
+    }
+}
+
+/// Soft-cap `xs` to `(-cap, cap)` with `cap * tanh(xs / cap)`, which leaves small values almost
+/// unchanged.
+fn soft_cap(xs: &Tensor, cap: f64) -> Result<Tensor> {
+    (xs / cap)?.tanh()? * cap
 }
 
 #[derive(Clone)]
@@ -240,13 +253,8 @@ impl Attention {
             rotary_emb,
             query_pre_attn_scalar: cfg.query_pre_attn_scalar,
             attn_logit_softcapping: cfg.attn_logit_softcapping,
-            use_sliding_window: layer_idx % 2 == 0, // Order is SWA, global, SWA
-            sliding_window: if layer_idx % 2 == 0 {
-                // ^ Order is SWA, global, SWA
-                Some(cfg.sliding_window)
-            } else {
-                None
-            },
+            use_sliding_window: cfg.layer_sliding_window(layer_idx).is_some(),
+            sliding_window: cfg.layer_sliding_window(layer_idx),
             paged_attn,
         })
     }
@@ -260,6 +268,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
@@ -323,7 +332,7 @@ impl Attention {
                     &q,
                     &k,
                     &v,
-                    attention_mask,
+                    mask,
                     Some(key_cache),
                     Some(value_cache),
                     input_metadata,
@@ -334,6 +343,7 @@ impl Attention {
                 // self.sliding_window is None if !self.use_sliding_window
                 let (k, v, mask) = Cache::update_kv_cache_sliding_window(
                     kv_cache,
+                    kv_cache_dtype,
                     k,
                     v,
                     mask,
@@ -351,9 +361,7 @@ impl Attention {
                 )?;
 
                 if let Some(attn_logit_softcapping) = self.attn_logit_softcapping {
-                    att = (att / attn_logit_softcapping)?;
-                    att = att.tanh()?;
-                    att = (att * attn_logit_softcapping)?;
+                    att = soft_cap(&att, attn_logit_softcapping)?;
                 }
 
                 let att = match mask {
@@ -448,6 +456,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let residual = xs;
@@ -461,6 +470,7 @@ impl DecoderLayer {
                 seqlen_offsets,
                 start_offsets_kernel,
                 kv_cache,
+                kv_cache_dtype,
                 metadata,
             )?
             .apply(&self.post_attention_layernorm)?;
@@ -497,6 +507,7 @@ impl Model {
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Self> {
+        verify_attention_heads(None, cfg.num_attention_heads, cfg.num_key_value_heads)?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization in {} bits.",
@@ -528,12 +539,7 @@ impl Model {
                 vb.dtype(),
             )?);
             let head_dim = cfg.head_dim;
-            let sliding_window = if layer_idx % 2 == 0 {
-                // ^ Order is SWA, global, SWA
-                Some(cfg.sliding_window)
-            } else {
-                None
-            };
+            let sliding_window = cfg.layer_sliding_window(layer_idx);
             let paged_attn = match &attention_mechanism {
                 AttentionImplementation::Eager => None,
                 AttentionImplementation::PagedAttention => Some(PagedAttention::new(
@@ -598,10 +604,12 @@ impl Model {
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         let xs = self.embed_tokens.forward(input_ids)?;
         let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let attention_mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             &*cache,
@@ -616,7 +624,8 @@ impl Model {
                 xs.dtype(),
                 self.layers[0].self_attn.num_heads,
             )?;
-        for (i, layer) in self.layers.iter().enumerate() {
+        let n_layers = early_exit_layer.unwrap_or(self.layers.len());
+        for (i, layer) in self.layers.iter().take(n_layers).enumerate() {
             xs = self.mapper.map(xs, i)?;
             xs = layer.forward(
                 &xs,
@@ -629,8 +638,9 @@ impl Model {
                     .map(|m| m.to_device(xs.device()).unwrap())
                     .as_ref(),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(xs.device())?,
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
@@ -645,9 +655,7 @@ impl Model {
         let mut xs = MatMul.qmethod_matmul(&xs, &*self.lm_head)?;
 
         if let Some(final_logit_softcapping) = self.final_logit_softcapping {
-            xs = (xs / final_logit_softcapping)?;
-            xs = xs.tanh()?;
-            xs = (xs * final_logit_softcapping)?;
+            xs = soft_cap(&xs, final_logit_softcapping)?;
         }
 
         extract_logits(&xs, context_lens)
@@ -679,9 +687,25 @@ impl IsqModel for Model {
         }
         (tensors, &*self.mapper)
     }
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        let mut names = vec![Some("output.weight".to_string())];
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            names.extend(imatrix_layer_names(
+                i,
+                &["attn_q", "attn_k", "attn_v", "attn_output"],
+            ));
+            names.extend(imatrix_mlp_names(
+                i,
+                &mut *layer.mlp,
+                &["ffn_gate", "ffn_up", "ffn_down"],
+            ));
+        }
+        Some(names)
+    }
 }
 
 impl NormalModel for Model {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         input_ids: &Tensor,
@@ -690,6 +714,7 @@ impl NormalModel for Model {
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -697,6 +722,7 @@ impl NormalModel for Model {
             start_offsets_kernel,
             context_lens,
             metadata,
+            early_exit_layer,
         )
     }
     fn xlora_forward(
@@ -711,6 +737,7 @@ impl NormalModel for Model {
         _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        _global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
@@ -852,3 +879,160 @@ impl AnyMoeBaseModelMixin for Model {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor, D};
+    use candle_nn::Activation;
+
+    use super::{soft_cap, Config, Model};
+    use crate::{
+        layers_masker::CausalMasker,
+        models::test_utils::{build_with_weights, loading_metadata, prompt_positions},
+        paged_attention::AttentionImplementation,
+        pipeline::{KvCacheDtype, LayerCaches},
+    };
+
+    fn config() -> Config {
+        Config {
+            attention_bias: false,
+            head_dim: 4,
+            hidden_act: Some(Activation::GeluPytorchTanh),
+            hidden_activation: None,
+            hidden_size: 16,
+            intermediate_size: 24,
+            num_attention_heads: 4,
+            num_hidden_layers: 4,
+            num_key_value_heads: 2,
+            rms_norm_eps: 1e-6,
+            rope_theta: 10000.,
+            vocab_size: 32,
+            sliding_window: 4,
+            attn_logit_softcapping: Some(50.),
+            final_logit_softcapping: Some(30.),
+            query_pre_attn_scalar: 4,
+            max_position_embeddings: 64,
+            quantization_config: None,
+        }
+    }
+
+    fn model(cfg: &Config) -> Model {
+        build_with_weights(|vb| {
+            Model::new(
+                cfg,
+                vb,
+                false,
+                loading_metadata(cfg.num_hidden_layers),
+                AttentionImplementation::Eager,
+            )
+        })
+    }
+
+    #[test]
+    fn soft_cap_clamps_extreme_logits() {
+        let xs = Tensor::new(&[-1e6f32, -30., -0.5, 0., 0.5, 30., 1e6], &Device::Cpu).unwrap();
+        let capped = soft_cap(&xs, 30.).unwrap().to_vec1::<f32>().unwrap();
+        assert!(capped.iter().all(|x| x.abs() <= 30.), "{capped:?}");
+        assert!((capped[0] + 30.).abs() < 1e-4 && (capped[6] - 30.).abs() < 1e-4);
+        // tanh(1) of the cap.
+        assert!((capped[5] - 22.847_83).abs() < 1e-3);
+        assert!((capped[4] - 0.5).abs() < 1e-3 && capped[3] == 0.);
+    }
+
+    #[test]
+    fn layers_alternate_sliding_window() {
+        let cfg = config();
+        let model = model(&cfg);
+
+        let windows = model
+            .layers
+            .iter()
+            .map(|layer| layer.self_attn.sliding_window)
+            .collect::<Vec<_>>();
+        assert_eq!(windows, [Some(4), None, Some(4), None]);
+        assert!(model
+            .layers
+            .iter()
+            .all(|layer| layer.self_attn.use_sliding_window
+                == layer.self_attn.sliding_window.is_some()
+                && layer.self_attn.attn_logit_softcapping == Some(50.)));
+        assert_eq!(model.final_logit_softcapping, Some(30.));
+    }
+
+    #[test]
+    fn sliding_window_layers_only_attend_to_recent_tokens() {
+        const SEQ_LEN: usize = 8;
+        let cfg = config();
+        let model = model(&cfg);
+
+        // The masks of a prompt, as built by the model.
+        let input_ids = Tensor::zeros((1, SEQ_LEN), DType::U32, &Device::Cpu).unwrap();
+        let no_cache: LayerCaches = vec![None];
+        let mask = CausalMasker
+            .make_causal_mask_as_attn_bias(
+                &input_ids,
+                &no_cache,
+                DType::F32,
+                cfg.num_attention_heads,
+            )
+            .unwrap();
+        let sliding_mask = CausalMasker
+            .make_causal_mask_with_sliding_window_as_attn_bias(
+                &input_ids,
+                &no_cache,
+                Some(cfg.sliding_window),
+                DType::F32,
+                cfg.num_attention_heads,
+            )
+            .unwrap();
+
+        // Two prompts which only differ in their first token.
+        let xs = (Tensor::arange(0i64, 128, &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            * 0.37)
+            .unwrap()
+            .sin()
+            .unwrap()
+            .reshape((1, SEQ_LEN, cfg.hidden_size))
+            .unwrap();
+        let first = (xs.narrow(1, 0, 1).unwrap() + 1.).unwrap();
+        let changed = Tensor::cat(&[&first, &xs.narrow(1, 1, SEQ_LEN - 1).unwrap()], 1).unwrap();
+
+        // How much the attention output of the last token changes with the first token.
+        let last_token_change = |layer: usize| {
+            let attend = |xs: &Tensor| {
+                model.layers[layer]
+                    .self_attn
+                    .forward(
+                        xs,
+                        mask.as_ref(),
+                        sliding_mask.as_ref(),
+                        &[0],
+                        prompt_positions(SEQ_LEN),
+                        &mut None,
+                        KvCacheDtype::F16,
+                        None,
+                    )
+                    .unwrap()
+                    .narrow(1, SEQ_LEN - 1, 1)
+                    .unwrap()
+            };
+            (attend(&xs) - attend(&changed))
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(D::Minus1)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap()
+        };
+        // The first token is outside of the window of the last one, so only the global layers see
+        // it.
+        assert!(last_token_change(0) < 1e-6, "{}", last_token_change(0));
+        assert!(last_token_change(1) > 1e-4, "{}", last_token_change(1));
+    }
+}
@@ -14,13 +14,14 @@ use crate::{
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
     layers::{
-        repeat_kv, CausalMasker, Llama3RopeConfig, Llama3RotaryEmbedding, MatMul, RmsNorm,
-        ScaledDotProductAttention,
+        repeat_kv, verify_attention_heads, CausalMasker, Llama3RopeConfig, Llama3RotaryEmbedding,
+        MatMul, RmsNorm, ScaledDotProductAttention,
     },
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits, text_models_inputs_processor::PagedAttentionInputMetadata, IsqModel,
+        extract_logits, imatrix_layer_names, imatrix_mlp_names,
+        text_models_inputs_processor::PagedAttentionInputMetadata, IsqModel, KvCacheDtype,
         NormalLoadingMetadata, NormalModel,
     },
     utils::progress::NiceProgressBar,
@@ -40,6 +41,19 @@ pub struct Config {
     pub max_position_embeddings: usize,
     pub rope_scaling: Option<Llama3RopeConfig>,
     pub quantization_config: Option<QuantizedConfig>,
+    /// Fraction of each head's dimensions to which RoPE is applied, defaulting to all of them.
+    pub partial_rotary_factor: Option<f32>,
+}
+
+impl Config {
+    /// The number of leading dimensions of each head which are rotated by RoPE.
+    pub fn rotary_dim(&self) -> usize {
+        let head_dim = self.hidden_size / self.num_attention_heads;
+        match self.partial_rotary_factor {
+            Some(factor) => (head_dim as f32 * factor) as usize,
+            None => head_dim,
+        }
+    }
 }
 
 struct CausalSelfAttention {
@@ -66,6 +80,7 @@ impl CausalSelfAttention {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, _) = x.dims3()?;
@@ -131,8 +146,13 @@ impl CausalSelfAttention {
                 )?
             }
             None => {
-                let (k, v) =
-                    crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+                let (k, v) = crate::pipeline::Cache::update_kv_cache(
+                    &mut kv_cache[block_idx],
+                    kv_cache_dtype,
+                    k,
+                    v,
+                    false,
+                )?;
 
                 let k = repeat_kv(k, self.num_attention_heads / self.num_key_value_heads)?
                     .contiguous()?;
@@ -330,6 +350,7 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let residual = x;
@@ -341,6 +362,7 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            kv_cache_dtype,
             metadata,
         )? + residual)?;
         let residual = &x;
@@ -401,10 +423,35 @@ impl Llama {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
+    ) -> Result<Tensor> {
+        let mut x = self.hidden_states(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            metadata,
+            early_exit_layer,
+        )?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            x = x.to_dtype(t)?;
+        }
+        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
+        extract_logits(&xs, context_lens)
+    }
+
+    /// Hidden states of the final layer after the final norm, before the LM head.
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         let mut x = self.wte.forward(input_ids)?;
         let mut cache = self.kv_cache.lock();
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             metadata
@@ -414,27 +461,24 @@ impl Llama {
             x.dtype(),
             self.blocks[0].attn.num_attention_heads,
         )?;
-        for (block_idx, block) in self.blocks.iter().enumerate() {
+        let n_layers = early_exit_layer.unwrap_or(self.blocks.len());
+        for (block_idx, block) in self.blocks.iter().take(n_layers).enumerate() {
             x = self.mapper.map(x, block_idx)?;
             x = block.forward(
                 &x,
                 &mask.clone().map(|m| m.to_device(x.device()).unwrap()),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(x.device())?,
                 block_idx,
                 &mut cache,
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[block_idx].clone(), &mut **metadata)),
             )?;
         }
         let x = x.to_device(&self.device)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if let Some(t) = self.lm_head.quantized_act_type() {
-            x = x.to_dtype(t)?;
-        }
-        let xs = MatMul.qmethod_matmul(&x, &*self.lm_head)?;
-        extract_logits(&xs, context_lens)
+        self.ln_f.forward(&x)
     }
 
     pub fn new(
@@ -444,6 +488,11 @@ impl Llama {
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Self> {
+        verify_attention_heads(
+            Some(cfg.hidden_size),
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+        )?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization in {} bits.",
@@ -553,9 +602,25 @@ impl IsqModel for Llama {
         }
         (tensors, &*self.mapper)
     }
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        let mut names = vec![Some("output.weight".to_string())];
+        for (i, layer) in self.blocks.iter_mut().enumerate() {
+            names.extend(imatrix_layer_names(
+                i,
+                &["attn_q", "attn_k", "attn_v", "attn_output"],
+            ));
+            names.extend(imatrix_mlp_names(
+                i,
+                &mut *layer.mlp,
+                &["ffn_gate", "ffn_up", "ffn_down"],
+            ));
+        }
+        Some(names)
+    }
 }
 
 impl NormalModel for Llama {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         input_ids: &Tensor,
@@ -564,6 +629,7 @@ impl NormalModel for Llama {
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -571,8 +637,17 @@ impl NormalModel for Llama {
             start_offsets_kernel,
             context_lens,
             metadata,
+            early_exit_layer,
         )
     }
+    fn forward_embeddings(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+    ) -> Result<Tensor> {
+        self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, None, None)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -585,6 +660,7 @@ impl NormalModel for Llama {
         _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        _global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
@@ -726,3 +802,68 @@ impl AnyMoeBaseModelMixin for Llama {
         true
     }
 }
+
+#[cfg(all(test, feature = "cuda"))]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{Config, Llama};
+    use crate::{
+        paged_attention::AttentionImplementation, pipeline::NormalLoadingMetadata,
+        DeviceLayerMapMetadata, DeviceMapMetadata,
+    };
+
+    #[test]
+    fn forward_with_host_layers() {
+        let cfg = Config {
+            hidden_size: 16,
+            intermediate_size: 24,
+            vocab_size: 32,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            use_flash_attn: false,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            max_position_embeddings: 64,
+            rope_scaling: None,
+            quantization_config: None,
+            partial_rotary_factor: None,
+        };
+        let device = Device::new_cuda(0).unwrap();
+        // The first layer is on the GPU and the second one on the host.
+        let mapper = DeviceMapMetadata::from_num_device_layers(vec![DeviceLayerMapMetadata {
+            ordinal: 0,
+            layers: 1,
+        }])
+        .into_mapper(cfg.num_hidden_layers, &device)
+        .unwrap();
+        let model = Llama::new(
+            &cfg,
+            VarBuilder::zeros(DType::F32, &device),
+            false,
+            NormalLoadingMetadata {
+                mapper,
+                loading_isq: false,
+                real_device: device.clone(),
+            },
+            AttentionImplementation::Eager,
+        )
+        .unwrap();
+
+        let input_ids = Tensor::new(&[[1u32, 2, 3]], &device).unwrap();
+        let start_offsets_kernel = Tensor::new(&[[0i64, 1, 2]], &device).unwrap();
+        let logits = model
+            .forward(
+                &input_ids,
+                &[0],
+                start_offsets_kernel,
+                vec![(2, 1)],
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(logits.dims(), &[1, 1, cfg.vocab_size]);
+    }
+}
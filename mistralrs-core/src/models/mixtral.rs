@@ -12,11 +12,15 @@ use std::sync::Arc;
 use crate::{
     amoe::AnyMoeBaseModelMixin,
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention},
+    layers::{
+        repeat_kv, verify_attention_heads, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention,
+    },
     layers_masker::PastKvLenCache,
+    layers_utils::topk_experts,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits, text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel,
+        extract_logits, imatrix_layer_names,
+        text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel, KvCacheDtype,
         NormalLoadingMetadata, NormalModel,
     },
     utils::progress::NiceProgressBar,
@@ -109,6 +113,7 @@ impl Attention {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         xs: &Tensor,
@@ -116,6 +121,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
@@ -183,6 +189,7 @@ impl Attention {
             None => {
                 let (k, v, attn_mask) = Cache::update_kv_cache_sliding_window(
                     kv_cache,
+                    kv_cache_dtype,
                     k,
                     v,
                     attention_mask,
@@ -334,16 +341,15 @@ impl Module for SparseMoeBlock {
         let mut top_x = vec![vec![]; self.experts.len()];
         let mut selected_rws = vec![vec![]; self.experts.len()];
         for (row_idx, rw) in routing_weights.iter().enumerate() {
-            let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
-            dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
+            let dst = topk_experts(rw, self.num_experts_per_tok);
             let mut sum_routing_weights = 0f32;
-            for &expert_idx in dst.iter().take(self.num_experts_per_tok) {
+            for &expert_idx in dst.iter() {
                 let expert_idx = expert_idx as usize;
                 let routing_weight = rw[expert_idx];
                 sum_routing_weights += routing_weight;
                 top_x[expert_idx].push(row_idx as u32);
             }
-            for &expert_idx in dst.iter().take(self.num_experts_per_tok) {
+            for &expert_idx in dst.iter() {
                 let expert_idx = expert_idx as usize;
                 let routing_weight = rw[expert_idx];
                 selected_rws[expert_idx].push(routing_weight / sum_routing_weights)
@@ -422,6 +428,7 @@ impl DecoderLayer {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         xs: &Tensor,
@@ -429,6 +436,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let residual = xs;
@@ -439,6 +447,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             metadata,
         )?;
         let xs = (xs + residual)?;
@@ -471,6 +480,11 @@ impl Model {
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Self> {
+        verify_attention_heads(
+            Some(cfg.hidden_size),
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+        )?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization in {} bits.",
@@ -564,9 +578,11 @@ impl Model {
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
             metadata
@@ -577,7 +593,8 @@ impl Model {
             xs.dtype(),
             self.layers[0].self_attn.num_heads,
         )?;
-        for (i, layer) in self.layers.iter().enumerate() {
+        let n_layers = early_exit_layer.unwrap_or(self.layers.len());
+        for (i, layer) in self.layers.iter().take(n_layers).enumerate() {
             xs = self.mapper.map(xs, i)?;
             xs = layer.forward(
                 &xs,
@@ -586,8 +603,9 @@ impl Model {
                     .map(|m| m.to_device(xs.device()).unwrap())
                     .as_ref(),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(xs.device())?,
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
@@ -625,9 +643,22 @@ impl IsqModel for Model {
         }
         (tensors, &*self.mapper)
     }
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        let mut names = vec![Some("output.weight".to_string())];
+        for (i, layer) in self.layers.iter().enumerate() {
+            names.extend(imatrix_layer_names(
+                i,
+                &["attn_q", "attn_k", "attn_v", "attn_output", "ffn_gate_inp"],
+            ));
+            // llama.cpp merges the experts into one tensor, so they have no imatrix entry of their own.
+            names.extend(vec![None; 3 * layer.block_sparse_moe.experts.len()]);
+        }
+        Some(names)
+    }
 }
 
 impl NormalModel for Model {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         input_ids: &Tensor,
@@ -636,6 +667,7 @@ impl NormalModel for Model {
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -643,6 +675,7 @@ impl NormalModel for Model {
             start_offsets_kernel,
             context_lens,
             metadata,
+            early_exit_layer,
         )
     }
     fn xlora_forward(
@@ -657,6 +690,7 @@ impl NormalModel for Model {
         _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        _global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
@@ -1,3 +1,4 @@
+pub(crate) mod cohere;
 pub(crate) mod gemma;
 pub(crate) mod gemma2;
 pub(crate) mod llama;
@@ -10,4 +11,8 @@ pub(crate) mod quantized_phi2;
 pub(crate) mod quantized_phi3;
 pub(crate) mod quantized_starcoder2;
 pub(crate) mod qwen2;
+pub(crate) mod qwen2_moe;
 pub(crate) mod starcoder2;
+
+#[cfg(test)]
+pub(crate) mod test_utils;
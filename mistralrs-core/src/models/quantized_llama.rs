@@ -12,9 +12,10 @@ use crate::device_map::DeviceMapper;
 use crate::gguf::Content;
 use crate::layers::{repeat_kv, CausalMasker, MatMul, QRmsNorm, ScaledDotProductAttention};
 use crate::layers_masker::PastKvLenCache;
+use crate::layers_utils::topk_experts;
 use crate::paged_attention::{AttentionImplementation, PagedAttention};
 use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
-use crate::pipeline::{extract_logits, Cache};
+use crate::pipeline::{extract_logits, Cache, KvCacheDtype};
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::progress::NiceProgressBar;
@@ -67,16 +68,15 @@ impl MlpOrMoe {
                 let mut top_x = vec![vec![]; experts.len()];
                 let mut selected_rws = vec![vec![]; experts.len()];
                 for (row_idx, rw) in routing_weights.iter().enumerate() {
-                    let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
-                    dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
+                    let dst = topk_experts(rw, *n_expert_used);
                     let mut sum_routing_weights = 0f32;
-                    for &expert_idx in dst.iter().take(*n_expert_used) {
+                    for &expert_idx in dst.iter() {
                         let expert_idx = expert_idx as usize;
                         let routing_weight = rw[expert_idx];
                         sum_routing_weights += routing_weight;
                         top_x[expert_idx].push(row_idx as u32);
                     }
-                    for &expert_idx in dst.iter().take(*n_expert_used) {
+                    for &expert_idx in dst.iter() {
                         let expert_idx = expert_idx as usize;
                         let routing_weight = rw[expert_idx];
                         selected_rws[expert_idx].push(routing_weight / sum_routing_weights)
@@ -131,6 +131,7 @@ struct LayerWeights {
 }
 
 impl LayerWeights {
+    #[allow(clippy::too_many_arguments)]
     fn forward_attn(
         &self,
         x: &Tensor,
@@ -138,6 +139,7 @@ impl LayerWeights {
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -193,7 +195,7 @@ impl LayerWeights {
                 )?
             }
             None => {
-                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+                let (k, v) = Cache::update_kv_cache(kv_cache, kv_cache_dtype, k, v, false)?;
 
                 let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
                 let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
@@ -373,7 +375,7 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
             // Strangely this value is generally 1e-6 in GGUF file but used to be 1e-5 by default.
             rms_norm_eps: c.get_value("attention.layer_norm_rms_epsilon")?,
             max_seq_len: c
-                .get_value::<u64>("context_length")
+                .get_u64("context_length")
                 .ok()
                 .unwrap_or(MAX_SEQ_LEN as u64) as usize,
             rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
@@ -632,6 +634,7 @@ impl ModelWeights {
     ) -> Result<Tensor> {
         let mut layer_in = self.tok_embeddings.forward(x)?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             x,
             metadata
@@ -654,8 +657,9 @@ impl ModelWeights {
                     .map(|m| m.to_device(x.device()).unwrap())
                     .as_ref(),
                 start_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(x.device())?,
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
@@ -18,7 +18,7 @@ use crate::layers::{repeat_kv, CausalMasker, QLinear};
 use crate::paged_attention::AttentionImplementation;
 use crate::paged_attention::PagedAttention;
 use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
-use crate::pipeline::{extract_logits, Cache};
+use crate::pipeline::{extract_logits, Cache, KvCacheDtype};
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::progress::NiceProgressBar;
@@ -74,6 +74,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -108,7 +109,7 @@ impl LayerWeights {
                 )?
             }
             None => {
-                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+                let (k, v) = Cache::update_kv_cache(kv_cache, kv_cache_dtype, k, v, false)?;
 
                 let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
                 let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
@@ -215,7 +216,7 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
             rope_dim: c.get_value::<u32>("rope.dimension_count")? as usize,
             ln_eps: c.get_value::<f32>("attention.layer_norm_rms_epsilon")? as f64,
             max_seq_len: c
-                .get_value::<u64>("context_length")
+                .get_u64("context_length")
                 .ok()
                 .unwrap_or(MAX_SEQ_LEN as u64) as usize,
         };
@@ -351,6 +352,7 @@ impl ModelWeights {
     ) -> Result<Tensor> {
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             &*cache,
@@ -368,6 +370,7 @@ impl ModelWeights {
                     .as_ref(),
                 seqlen_offsets,
                 cache.get_mut(i).unwrap(),
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
@@ -8,7 +8,7 @@ use crate::layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAt
 use crate::layers_masker::PastKvLenCache;
 use crate::paged_attention::{AttentionImplementation, PagedAttention};
 use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
-use crate::pipeline::Cache;
+use crate::pipeline::{Cache, KvCacheDtype};
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::progress::NiceProgressBar;
@@ -79,6 +79,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -130,6 +131,7 @@ impl LayerWeights {
             None => {
                 let (k, v, attn_mask) = Cache::update_kv_cache_sliding_window(
                     kv_cache,
+                    kv_cache_dtype,
                     k,
                     v,
                     mask,
@@ -237,7 +239,7 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
             i_size: c.get_value::<u32>("feed_forward_length")? as usize,
             rope_dim: c.get_value::<u32>("rope.dimension_count")? as usize,
             rms_eps: c.get_value::<f32>("attention.layer_norm_rms_epsilon")? as f64,
-            context_window: c.get_value::<u32>("context_length")? as usize,
+            context_window: c.get_u64("context_length")? as usize,
         };
 
         Ok(props)
@@ -376,6 +378,7 @@ impl ModelWeights {
         let (_b_sz, seq_len) = input_ids.dims2()?;
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
             metadata
@@ -399,6 +402,7 @@ impl ModelWeights {
                     .as_ref(),
                 seqlen_offsets,
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
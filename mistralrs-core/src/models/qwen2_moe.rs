@@ -0,0 +1,933 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+/// Qwen2 MoE Model
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/qwen2_moe/modeling_qwen2_moe.py
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, RotaryEmbedding, VarBuilder};
+use mistralrs_quant::{QuantMethod, QuantMethodConfig, QuantizedConfig, UnquantLinear};
+use std::sync::Arc;
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    device_map::DeviceMapper,
+    layers::{
+        repeat_kv, verify_attention_heads, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention,
+    },
+    layers_masker::PastKvLenCache,
+    layers_utils::topk_experts,
+    paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
+    pipeline::{
+        extract_logits, imatrix_layer_names,
+        text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel, KvCacheDtype,
+        NormalLoadingMetadata, NormalModel,
+    },
+    utils::progress::NiceProgressBar,
+};
+
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/qwen2_moe/configuration_qwen2_moe.py
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) num_key_value_heads: usize,
+    pub(crate) hidden_act: Activation,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) rms_norm_eps: f64,
+    pub(crate) rope_theta: f64,
+    /// `None` unless `use_sliding_window` is set.
+    pub(crate) sliding_window: Option<usize>,
+    pub(crate) decoder_sparse_step: usize,
+    pub(crate) moe_intermediate_size: usize,
+    pub(crate) shared_expert_intermediate_size: usize,
+    pub(crate) num_experts_per_tok: usize,
+    pub(crate) num_experts: usize,
+    pub(crate) norm_topk_prob: bool,
+    pub(crate) mlp_only_layers: Vec<usize>,
+    pub(crate) use_flash_attn: bool,
+    pub(crate) quantization_config: Option<QuantizedConfig>,
+}
+
+impl Config {
+    /// Whether the MLP of the layer is a sparse MoE block rather than a dense MLP.
+    fn is_sparse_layer(&self, layer_idx: usize) -> bool {
+        !self.mlp_only_layers.contains(&layer_idx)
+            && self.num_experts > 0
+            && (layer_idx + 1) % self.decoder_sparse_step == 0
+    }
+}
+
+struct Attention {
+    q_proj: Arc<dyn QuantMethod>,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    o_proj: Arc<dyn QuantMethod>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    use_flash_attn: bool,
+    sliding_window: Option<usize>,
+    paged_attn: Option<PagedAttention>,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        paged_attn: Option<PagedAttention>,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let num_kv_groups = num_heads / num_kv_heads;
+        let head_dim = hidden_sz / num_heads;
+        let q_proj = mistralrs_quant::linear(
+            hidden_sz,
+            num_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("q_proj"),
+        )?;
+        let k_proj = mistralrs_quant::linear(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("k_proj"),
+        )?;
+        let v_proj = mistralrs_quant::linear(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            &cfg.quantization_config,
+            vb.pp("v_proj"),
+        )?;
+        let o_proj = mistralrs_quant::linear_no_bias(
+            num_heads * head_dim,
+            hidden_sz,
+            &cfg.quantization_config,
+            vb.pp("o_proj"),
+        )?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups,
+            head_dim,
+            rotary_emb,
+            use_flash_attn: cfg.use_flash_attn,
+            sliding_window: cfg.sliding_window,
+            paged_attn,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
+        metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let mut q = MatMul.qmethod_matmul(&xs, &*self.q_proj)?;
+        let mut k = MatMul.qmethod_matmul(&xs, &*self.k_proj)?;
+        let mut v = MatMul.qmethod_matmul(&xs, &*self.v_proj)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            q = q.to_dtype(original_dtype)?;
+            k = k.to_dtype(original_dtype)?;
+            v = v.to_dtype(original_dtype)?;
+        }
+
+        let mut q = q.reshape((b_sz * q_len, self.num_heads, self.head_dim))?;
+        let mut k = k.reshape((b_sz * q_len, self.num_kv_heads, self.head_dim))?;
+        let v = if q_len != 1 {
+            v.reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+        } else {
+            // Optimization for seqlen = 1, avoid transpose and just modify reshape dims
+            v.reshape((b_sz, self.num_kv_heads, q_len, self.head_dim))?
+        };
+
+        self.rotary_emb
+            .forward(seqlen_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+
+        if q.rank() == 3 && q_len != 1 {
+            q = q
+                .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?;
+        } else if q.rank() == 3 {
+            // Optimization for seqlen = 1, avoid transpose and just modify reshape dims
+            q = q
+                .reshape((b_sz, self.num_heads, q_len, self.head_dim))?
+                .contiguous()?;
+            k = k
+                .reshape((b_sz, self.num_kv_heads, q_len, self.head_dim))?
+                .contiguous()?;
+        }
+
+        let mut attn_output = match &self.paged_attn {
+            Some(paged_attn) => {
+                let ((key_cache, value_cache), input_metadata) = metadata.unwrap();
+                paged_attn.forward(
+                    &q,
+                    &k,
+                    &v,
+                    attention_mask,
+                    Some(key_cache),
+                    Some(value_cache),
+                    input_metadata,
+                    None,
+                )?
+            }
+            None => {
+                let (k, v, attn_mask) = Cache::update_kv_cache_sliding_window(
+                    kv_cache,
+                    kv_cache_dtype,
+                    k,
+                    v,
+                    attention_mask,
+                    self.sliding_window,
+                    false,
+                )?;
+
+                let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+                let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+                ScaledDotProductAttention.run_attention(
+                    &q,
+                    &k,
+                    &v,
+                    self.num_heads,
+                    self.head_dim,
+                    attn_mask.as_ref(),
+                    self.use_flash_attn,
+                    b_sz,
+                    q_len,
+                )?
+            }
+        };
+
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            attn_output = attn_output.to_dtype(t)?;
+        }
+        attn_output = if attention_mask.is_some() {
+            attn_output.transpose(1, 2)?.reshape((b_sz, q_len, ()))?
+        } else {
+            attn_output.reshape((b_sz, q_len, ()))?
+        };
+        let mut res = MatMul.qmethod_matmul(&attn_output, &*self.o_proj)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Arc<dyn QuantMethod>,
+    up_proj: Arc<dyn QuantMethod>,
+    down_proj: Arc<dyn QuantMethod>,
+    act_fn: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, intermediate_sz: usize, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let gate_proj = mistralrs_quant::linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            &cfg.quantization_config,
+            vb.pp("gate_proj"),
+        )?;
+        let up_proj = mistralrs_quant::linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            &cfg.quantization_config,
+            vb.pp("up_proj"),
+        )?;
+        let down_proj = mistralrs_quant::linear_no_bias(
+            intermediate_sz,
+            hidden_sz,
+            &cfg.quantization_config,
+            vb.pp("down_proj"),
+        )?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+        })
+    }
+
+    fn isq_layers(&mut self) -> [&mut Arc<dyn QuantMethod>; 3] {
+        [&mut self.gate_proj, &mut self.up_proj, &mut self.down_proj]
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.gate_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let lhs = MatMul
+            .qmethod_matmul(&xs, &*self.gate_proj)?
+            .apply(&self.act_fn)?;
+        let rhs = MatMul.qmethod_matmul(&xs, &*self.up_proj)?;
+        let mut res = MatMul.qmethod_matmul(&(lhs * rhs)?, &*self.down_proj)?;
+        if self.gate_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+/// Routed experts plus a shared expert, whose output is scaled by a sigmoid gate.
+struct SparseMoeBlock {
+    gate: Arc<dyn QuantMethod>,
+    experts: Vec<MLP>,
+    shared_expert: MLP,
+    shared_expert_gate: Arc<dyn QuantMethod>,
+    num_experts_per_tok: usize,
+    norm_topk_prob: bool,
+}
+
+impl SparseMoeBlock {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let gate = mistralrs_quant::linear_no_bias(
+            cfg.hidden_size,
+            cfg.num_experts,
+            &cfg.quantization_config,
+            vb.pp("gate"),
+        )?;
+        let mut experts = Vec::with_capacity(cfg.num_experts);
+        let vb_e = vb.pp("experts");
+        for idx in 0..cfg.num_experts {
+            experts.push(MLP::new(cfg, cfg.moe_intermediate_size, vb_e.pp(idx))?);
+        }
+        let shared_expert = MLP::new(
+            cfg,
+            cfg.shared_expert_intermediate_size,
+            vb.pp("shared_expert"),
+        )?;
+        let shared_expert_gate = mistralrs_quant::linear_no_bias(
+            cfg.hidden_size,
+            1,
+            &cfg.quantization_config,
+            vb.pp("shared_expert_gate"),
+        )?;
+        Ok(Self {
+            gate,
+            experts,
+            shared_expert,
+            shared_expert_gate,
+            num_experts_per_tok: cfg.num_experts_per_tok,
+            norm_topk_prob: cfg.norm_topk_prob,
+        })
+    }
+}
+
+impl Module for SparseMoeBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, hidden_dim) = xs.dims3()?;
+        let xs = xs.reshape(((), hidden_dim))?;
+
+        let original_dtype = xs.dtype();
+        let mut xs_gate = xs.clone();
+        if let Some(t) = self.gate.quantized_act_type() {
+            xs_gate = xs_gate.to_dtype(t)?;
+        }
+        let mut router_logits = MatMul.qmethod_matmul(&xs_gate, &*self.gate)?;
+        let mut shared_gate_logits = MatMul.qmethod_matmul(&xs_gate, &*self.shared_expert_gate)?;
+        if self.gate.quantized_act_type().is_some() {
+            router_logits = router_logits.to_dtype(original_dtype)?;
+            shared_gate_logits = shared_gate_logits.to_dtype(original_dtype)?;
+        }
+
+        let routing_weights = candle_nn::ops::softmax_last_dim(&router_logits)?;
+        let routing_weights = routing_weights.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+        // top_x contains the row indexes to evaluate for each expert.
+        let mut top_x = vec![vec![]; self.experts.len()];
+        let mut selected_rws = vec![vec![]; self.experts.len()];
+        for (row_idx, rw) in routing_weights.iter().enumerate() {
+            let dst = topk_experts(rw, self.num_experts_per_tok);
+            let norm = if self.norm_topk_prob {
+                dst.iter().map(|&expert_idx| rw[expert_idx as usize]).sum()
+            } else {
+                1f32
+            };
+            for &expert_idx in dst.iter() {
+                let expert_idx = expert_idx as usize;
+                top_x[expert_idx].push(row_idx as u32);
+                selected_rws[expert_idx].push(rw[expert_idx] / norm);
+            }
+        }
+
+        let mut ys = xs.zeros_like()?;
+        for (expert_idx, expert_layer) in self.experts.iter().enumerate() {
+            let top_x = &top_x[expert_idx];
+            if top_x.is_empty() {
+                continue;
+            }
+            let top_x = Tensor::new(top_x.as_slice(), xs.device())?;
+            let selected_rws = Tensor::new(selected_rws[expert_idx].as_slice(), xs.device())?
+                .reshape(((), 1))?
+                .to_dtype(xs.dtype())?;
+            let current_state = xs.index_select(&top_x, 0)?.reshape(((), hidden_dim))?;
+            let current_hidden_states = expert_layer.forward(&current_state)?;
+            let current_hidden_states = current_hidden_states.broadcast_mul(&selected_rws)?;
+            ys = ys.index_add(&top_x, &current_hidden_states, 0)?;
+        }
+
+        let shared = self
+            .shared_expert
+            .forward(&xs)?
+            .broadcast_mul(&candle_nn::ops::sigmoid(&shared_gate_logits)?)?;
+        (ys + shared)?.reshape((b_size, seq_len, hidden_dim))
+    }
+}
+
+enum MoeOrMlp {
+    Moe(SparseMoeBlock),
+    Mlp(MLP),
+}
+
+impl Module for MoeOrMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Moe(moe) => moe.forward(xs),
+            Self::Mlp(mlp) => mlp.forward(xs),
+        }
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MoeOrMlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+        paged_attn: Option<PagedAttention>,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(
+            rotary_emb,
+            cfg,
+            mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
+            paged_attn,
+        )?;
+        let vb_mlp = mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq);
+        let mlp = if cfg.is_sparse_layer(layer_idx) {
+            MoeOrMlp::Moe(SparseMoeBlock::new(cfg, vb_mlp)?)
+        } else {
+            MoeOrMlp::Mlp(MLP::new(cfg, cfg.intermediate_size, vb_mlp)?)
+        };
+        let input_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("input_layernorm"), false),
+        )?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("post_attention_layernorm"), false),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
+        metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(
+            &xs,
+            attention_mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            kv_cache,
+            kv_cache_dtype,
+            metadata,
+        )?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+}
+
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Arc<dyn QuantMethod>,
+    sliding_window: Option<usize>,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    cfg: ModelConfigMetadata,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        is_gptx: bool,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        verify_attention_heads(
+            Some(cfg.hidden_size),
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+        )?;
+        if cfg.decoder_sparse_step == 0 {
+            candle_core::bail!("`decoder_sparse_step` must be positive.");
+        }
+        if let Some(ref quant_cfg) = &cfg.quantization_config {
+            tracing::info!(
+                "Using {} quantization in {} bits.",
+                quant_cfg.quant_method.to_string(),
+                quant_cfg.bits
+            );
+        }
+        let mapper = normal_loading_metadata.mapper;
+        let vb_m = vb.pp("model");
+
+        let embed_tokens = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
+        )?;
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in
+            NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
+        {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            let rotary_emb = Arc::new(RotaryEmbedding::new(
+                cfg.rope_theta as f32,
+                head_dim,
+                cfg.max_position_embeddings,
+                device,
+                is_gptx,
+                vb_m.dtype(),
+            )?);
+            let paged_attn = match &attention_mechanism {
+                AttentionImplementation::Eager => None,
+                AttentionImplementation::PagedAttention => Some(PagedAttention::new(
+                    cfg.num_attention_heads,
+                    head_dim,
+                    (1.0 / (head_dim as f64).sqrt()) as f32,
+                    Some(cfg.num_key_value_heads),
+                    cfg.sliding_window,
+                    device,
+                    None,
+                )?),
+            };
+            let layer = DecoderLayer::new(
+                rotary_emb.clone(),
+                cfg,
+                vb_l.pp(layer_idx),
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+                paged_attn,
+            )?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_nm_device(vb_m.pp("norm"), false),
+        )?;
+        let lm_head = candle_nn::linear_no_bias(
+            cfg.hidden_size,
+            cfg.vocab_size,
+            mapper.set_nm_device(vb.pp("lm_head"), normal_loading_metadata.loading_isq),
+        )?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head: Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(lm_head))?),
+            sliding_window: cfg.sliding_window,
+            device: normal_loading_metadata.real_device,
+            cache: Cache::new(cfg.num_hidden_layers, false),
+            max_seq_len: cfg.max_position_embeddings,
+            mapper,
+            cfg: ModelConfigMetadata {
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_kv_heads: cfg.num_key_value_heads,
+                num_attn_heads: cfg.num_attention_heads,
+                sliding_window: cfg.sliding_window,
+                head_dim: None,
+            },
+        })
+    }
+
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
+    ) -> Result<Tensor> {
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
+        let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
+            input_ids,
+            metadata
+                .as_ref()
+                .map(|(_, _)| &seqlen_offsets as &dyn PastKvLenCache)
+                .unwrap_or(&*cache as &dyn PastKvLenCache),
+            self.sliding_window,
+            xs.dtype(),
+            self.layers[0].self_attn.num_heads,
+        )?;
+        let n_layers = early_exit_layer.unwrap_or(self.layers.len());
+        for (i, layer) in self.layers.iter().take(n_layers).enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(
+                &xs,
+                attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                start_offsets_kernel.to_device(xs.device())?,
+                &mut cache[i],
+                kv_cache_dtype,
+                metadata
+                    .as_mut()
+                    .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
+            )?;
+        }
+        let xs = xs.to_device(&self.device)?;
+        let mut xs = xs.apply(&self.norm)?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+}
+
+impl IsqModel for Model {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((&mut layer.self_attn.q_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.k_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.v_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
+            match &mut layer.mlp {
+                MoeOrMlp::Moe(moe) => {
+                    // The single-output shared expert gate is left unquantized.
+                    tensors.push((&mut moe.gate, Some(i)));
+                    for expert in &mut moe.experts {
+                        tensors.extend(expert.isq_layers().map(|layer| (layer, Some(i))));
+                    }
+                    tensors.extend(moe.shared_expert.isq_layers().map(|layer| (layer, Some(i))));
+                }
+                MoeOrMlp::Mlp(mlp) => {
+                    tensors.extend(mlp.isq_layers().map(|layer| (layer, Some(i))));
+                }
+            }
+        }
+        (tensors, &*self.mapper)
+    }
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        let mut names = vec![Some("output.weight".to_string())];
+        for (i, layer) in self.layers.iter().enumerate() {
+            names.extend(imatrix_layer_names(
+                i,
+                &["attn_q", "attn_k", "attn_v", "attn_output"],
+            ));
+            match &layer.mlp {
+                MoeOrMlp::Moe(moe) => {
+                    names.extend(imatrix_layer_names(i, &["ffn_gate_inp"]));
+                    // llama.cpp merges the experts into one tensor, so they have no imatrix entry of
+                    // their own.
+                    names.extend(vec![None; 3 * moe.experts.len()]);
+                    names.extend(imatrix_layer_names(
+                        i,
+                        &["ffn_gate_shexp", "ffn_up_shexp", "ffn_down_shexp"],
+                    ));
+                }
+                MoeOrMlp::Mlp(_) => {
+                    names.extend(imatrix_layer_names(i, &["ffn_gate", "ffn_up", "ffn_down"]));
+                }
+            }
+        }
+        Some(names)
+    }
+}
+
+impl NormalModel for Model {
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
+    ) -> Result<Tensor> {
+        self.forward(
+            input_ids,
+            seqlen_offsets,
+            start_offsets_kernel,
+            context_lens,
+            metadata,
+            early_exit_layer,
+        )
+    }
+    fn xlora_forward(
+        &self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _global_scaling_weight: Option<f64>,
+    ) -> Result<Tensor> {
+        candle_core::bail!("X-LoRA is not supported for Qwen2-MoE.")
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Module, Tensor};
+    use candle_nn::Activation;
+
+    use super::{Config, Model, MoeOrMlp, SparseMoeBlock};
+    use crate::{
+        layers::MatMul,
+        models::test_utils::{build_with_weights, loading_metadata},
+        paged_attention::AttentionImplementation,
+        pipeline::IsqModel,
+    };
+
+    fn config(norm_topk_prob: bool) -> Config {
+        Config {
+            vocab_size: 32,
+            hidden_size: 16,
+            intermediate_size: 24,
+            num_hidden_layers: 4,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            hidden_act: Activation::Silu,
+            max_position_embeddings: 64,
+            rms_norm_eps: 1e-6,
+            rope_theta: 10000.,
+            sliding_window: None,
+            decoder_sparse_step: 2,
+            moe_intermediate_size: 8,
+            shared_expert_intermediate_size: 12,
+            num_experts_per_tok: 2,
+            num_experts: 4,
+            norm_topk_prob,
+            mlp_only_layers: vec![3],
+            use_flash_attn: false,
+            quantization_config: None,
+        }
+    }
+
+    fn rows(xs: &Tensor) -> Vec<Vec<f32>> {
+        xs.to_vec2::<f32>().unwrap()
+    }
+
+    #[test]
+    fn small_config_builds_sparse_and_dense_layers() {
+        let cfg = config(false);
+        let mut model = build_with_weights(|vb| {
+            Model::new(
+                &cfg,
+                vb,
+                true,
+                loading_metadata(cfg.num_hidden_layers),
+                AttentionImplementation::Eager,
+            )
+        });
+
+        // Only layer 1 is sparse: layer 3 is in `mlp_only_layers`.
+        let sparse = model
+            .layers
+            .iter()
+            .map(|layer| matches!(layer.mlp, MoeOrMlp::Moe(_)))
+            .collect::<Vec<_>>();
+        assert_eq!(sparse, [false, true, false, false]);
+
+        // The head, 4 attention projections per layer, 3 dense MLP projections for 3 layers and,
+        // for the sparse layer, the router and 3 projections for each of the 4 experts and the
+        // shared expert.
+        let (layers, _) = model.get_layers();
+        assert_eq!(layers.len(), 1 + 4 * 4 + 3 * 3 + 1 + 3 * 5);
+    }
+
+    #[test]
+    fn moe_block_mixes_top_k_experts_and_gated_shared_expert() {
+        const NUM_TOKENS: usize = 3;
+        for norm_topk_prob in [false, true] {
+            let cfg = config(norm_topk_prob);
+            let block = build_with_weights(|vb| SparseMoeBlock::new(&cfg, vb));
+            let num_elems = (NUM_TOKENS * cfg.hidden_size) as i64;
+            let xs = (Tensor::arange(0i64, num_elems, &Device::Cpu)
+                .unwrap()
+                .to_dtype(DType::F32)
+                .unwrap()
+                * 0.37)
+                .unwrap()
+                .sin()
+                .unwrap()
+                .reshape((NUM_TOKENS, cfg.hidden_size))
+                .unwrap();
+
+            let ys = block.forward(&xs.unsqueeze(0).unwrap()).unwrap();
+            assert_eq!(ys.dims(), &[1, NUM_TOKENS, cfg.hidden_size]);
+            let ys = rows(&ys.squeeze(0).unwrap());
+
+            let router_logits = rows(&MatMul.qmethod_matmul(&xs, &*block.gate).unwrap());
+            let shared_gate_logits = rows(
+                &MatMul
+                    .qmethod_matmul(&xs, &*block.shared_expert_gate)
+                    .unwrap(),
+            );
+            let experts = block
+                .experts
+                .iter()
+                .map(|expert| rows(&expert.forward(&xs).unwrap()))
+                .collect::<Vec<_>>();
+            let shared = rows(&block.shared_expert.forward(&xs).unwrap());
+
+            for token in 0..NUM_TOKENS {
+                let exps = router_logits[token]
+                    .iter()
+                    .map(|logit| logit.exp())
+                    .collect::<Vec<_>>();
+                let probs = exps
+                    .iter()
+                    .map(|e| e / exps.iter().sum::<f32>())
+                    .collect::<Vec<_>>();
+                let mut ranked = (0..cfg.num_experts).collect::<Vec<_>>();
+                ranked.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+                let top_k = &ranked[..cfg.num_experts_per_tok];
+                let norm = if norm_topk_prob {
+                    top_k.iter().map(|&e| probs[e]).sum()
+                } else {
+                    1.
+                };
+                let shared_gate = 1. / (1. + (-shared_gate_logits[token][0]).exp());
+
+                let mix = |experts_used: &[usize], norm: f32| {
+                    (0..cfg.hidden_size)
+                        .map(|i| {
+                            experts_used
+                                .iter()
+                                .map(|&e| probs[e] / norm * experts[e][token][i])
+                                .sum::<f32>()
+                                + shared_gate * shared[token][i]
+                        })
+                        .collect::<Vec<_>>()
+                };
+                let max_diff = |expected: &[f32]| {
+                    ys[token]
+                        .iter()
+                        .zip(expected)
+                        .map(|(a, b)| (a - b).abs())
+                        .fold(0f32, f32::max)
+                };
+
+                let top_k_diff = max_diff(&mix(top_k, norm));
+                assert!(top_k_diff < 1e-5, "{top_k_diff}");
+                // Routing to every expert would give a different output.
+                let dense_diff = max_diff(&mix(&ranked, 1.));
+                assert!(dense_diff > 1e-4, "{dense_diff}");
+            }
+        }
+    }
+}
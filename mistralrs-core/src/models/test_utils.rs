@@ -0,0 +1,59 @@
+//! Helpers to build small models with deterministic weights in tests.
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+
+use crate::{pipeline::NormalLoadingMetadata, DeviceMapMetadata};
+
+/// The loading metadata of a model with `num_hidden_layers` layers on the CPU, without device
+/// mapping or ISQ.
+pub(crate) fn loading_metadata(num_hidden_layers: usize) -> NormalLoadingMetadata {
+    let device = Device::Cpu;
+    NormalLoadingMetadata {
+        mapper: DeviceMapMetadata::dummy()
+            .into_mapper(num_hidden_layers, &device)
+            .unwrap(),
+        loading_isq: false,
+        real_device: device,
+    }
+}
+
+/// Build a model on the CPU whose weights are a sine of their index, offset by a hash of their
+/// name, so that its outputs depend on its inputs and the weights do not depend on which other
+/// weights the model has.
+///
+/// `build` is called twice: the first call creates the weights with their shapes, which are then
+/// filled in, and the second call builds the model from them.
+pub(crate) fn build_with_weights<M>(build: impl Fn(VarBuilder) -> Result<M>) -> M {
+    let device = Device::Cpu;
+    let var_map = VarMap::new();
+    build(VarBuilder::from_varmap(&var_map, DType::F32, &device)).unwrap();
+    for (name, var) in var_map.data().lock().unwrap().iter() {
+        let offset = name
+            .bytes()
+            .fold(0u32, |hash, b| hash.wrapping_mul(31).wrapping_add(b.into()))
+            % 1000;
+        let weight = ((Tensor::arange(0i64, var.elem_count() as i64, &device)
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            * 0.618)
+            .unwrap()
+            + f64::from(offset))
+        .unwrap()
+        .sin()
+        .unwrap()
+        .reshape(var.shape())
+        .unwrap();
+        var.set(&(weight * 0.2).unwrap()).unwrap();
+    }
+    build(VarBuilder::from_varmap(&var_map, DType::F32, &device)).unwrap()
+}
+
+/// The RoPE positions of a prompt of `seq_len` tokens, as the `start_offsets_kernel` of a model.
+pub(crate) fn prompt_positions(seq_len: usize) -> Tensor {
+    Tensor::arange(0i64, seq_len as i64, &Device::Cpu)
+        .unwrap()
+        .unsqueeze(0)
+        .unwrap()
+}
@@ -1,4 +1,4 @@
-use candle_core::{Device, Result, Tensor};
+use candle_core::{Device, IndexOp, Result, Tensor};
 
 use mistralrs_paged_attn::{paged_attention, reshape_and_cache};
 
@@ -45,6 +45,42 @@ impl PagedAttention {
         })
     }
 
+    /// Read the keys and values of the first `chunk_offset_toks` tokens of each sequence back
+    /// from the cache, as [batch_size, num_kv_heads, chunk_offset_toks, head_size].
+    fn gather_cached_prefix(
+        &self,
+        key_cache: &Tensor,
+        value_cache: &Tensor,
+        input_metadata: &PagedAttentionInputMetadata,
+    ) -> Result<(Tensor, Tensor)> {
+        let n_toks = input_metadata.chunk_offset_toks;
+        let (_, num_kv_heads, _, block_size, _) = key_cache.dims5()?;
+        let n_blocks = n_toks.div_ceil(block_size);
+        let block_tables = input_metadata
+            .block_tables
+            .as_ref()
+            .expect("A prompt chunk must have block tables.");
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for seq in 0..block_tables.dim(0)? {
+            let blocks = block_tables.i(seq)?.narrow(0, 0, n_blocks)?;
+            // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+            let key = key_cache
+                .index_select(&blocks, 0)?
+                .permute((1, 0, 3, 2, 4))?
+                .reshape((num_kv_heads, n_blocks * block_size, self.head_dim))?;
+            // [num_blocks, num_kv_heads, head_size, block_size]
+            let value = value_cache
+                .index_select(&blocks, 0)?
+                .permute((1, 0, 3, 2))?
+                .reshape((num_kv_heads, n_blocks * block_size, self.head_dim))?;
+            keys.push(key.narrow(1, 0, n_toks)?);
+            values.push(value.narrow(1, 0, n_toks)?);
+        }
+        Ok((Tensor::stack(&keys, 0)?, Tensor::stack(&values, 0)?))
+    }
+
     #[allow(clippy::too_many_arguments)]
     #[allow(unused_variables)]
     /// query: shape = [batch_size, seq_len, num_heads * head_size]
@@ -81,14 +117,31 @@ impl PagedAttention {
         let att = match attention_mask {
             None => None,
             Some(mask) => {
+                // A chunk of a chunked prefill also attends to the tokens which the previous
+                // chunks wrote to the cache.
+                let (key, value) = match (&key_cache, &value_cache) {
+                    (Some(key_cache), Some(value_cache))
+                        if input_metadata.chunk_offset_toks > 0 =>
+                    {
+                        let (past_key, past_value) =
+                            self.gather_cached_prefix(key_cache, value_cache, input_metadata)?;
+                        (
+                            Tensor::cat(&[&past_key.to_dtype(key.dtype())?, key], 2)?,
+                            Tensor::cat(&[&past_value.to_dtype(value.dtype())?, value], 2)?,
+                        )
+                    }
+                    _ => (key.clone(), value.clone()),
+                };
+                let kv_len = key.dim(2)?;
+
                 //Only perform key/value repeat in prefiling stage, this will reduce kvcache
                 //and remove redundant repeat_kv in decoding stage
                 let att = if key_value_heads != attention_heads {
                     let key_repeat = if key_value_heads == 1 {
-                        key.broadcast_as((batch_size, attention_heads, seq_len, head_size))?
+                        key.broadcast_as((batch_size, attention_heads, kv_len, head_size))?
                     } else {
                         Tensor::cat(&vec![&key; attention_heads / key_value_heads], 2)?
-                            .reshape((batch_size, attention_heads, seq_len, head_size))?
+                            .reshape((batch_size, attention_heads, kv_len, head_size))?
                     };
                     (query.matmul(&key_repeat.t()?.contiguous()?)? * self.scale as f64)?
                 } else {
@@ -103,10 +156,10 @@ impl PagedAttention {
                 let att = candle_nn::ops::softmax_last_dim(&att)?;
                 if key_value_heads != attention_heads {
                     let value_repeat = if key_value_heads == 1 {
-                        value.broadcast_as((batch_size, attention_heads, seq_len, head_size))?
+                        value.broadcast_as((batch_size, attention_heads, kv_len, head_size))?
                     } else {
                         Tensor::cat(&vec![&value; attention_heads / key_value_heads], 2)?
-                            .reshape((batch_size, attention_heads, seq_len, head_size))?
+                            .reshape((batch_size, attention_heads, kv_len, head_size))?
                     };
                     Some(att.matmul(&value_repeat.contiguous()?)?)
                 } else {
@@ -181,3 +234,116 @@ impl PagedAttention {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::PagedAttention;
+    use crate::{
+        layers::CausalMasker, layers_masker::PastKvLenCache,
+        pipeline::text_models_inputs_processor::PagedAttentionInputMetadata,
+    };
+
+    const HEADS: usize = 4;
+    const KV_HEADS: usize = 2;
+    const HEAD_DIM: usize = 64;
+    const BLOCK_SIZE: usize = 32;
+
+    /// Run the prefill of `q`, `k` and `v` ([1, heads, seq_len, head_dim]) in chunks of
+    /// `chunk_size` tokens, writing to a fresh cache.
+    fn chunked_prefill(
+        attn: &PagedAttention,
+        (q, k, v): (&Tensor, &Tensor, &Tensor),
+        chunk_size: usize,
+    ) -> Tensor {
+        let dev = q.device();
+        let n_toks = q.dim(2).unwrap();
+        let n_blocks = n_toks.div_ceil(BLOCK_SIZE);
+        // x = 16 / size_of::<f32>()
+        let key_cache = Tensor::zeros(
+            (n_blocks, KV_HEADS, HEAD_DIM / 4, BLOCK_SIZE, 4),
+            DType::F32,
+            dev,
+        )
+        .unwrap();
+        let value_cache =
+            Tensor::zeros((n_blocks, KV_HEADS, HEAD_DIM, BLOCK_SIZE), DType::F32, dev).unwrap();
+        let block_tables = Tensor::arange(0u32, n_blocks as u32, dev)
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap();
+
+        let mut outputs = Vec::new();
+        for offset in (0..n_toks).step_by(chunk_size) {
+            let len = chunk_size.min(n_toks - offset);
+            let past: &[usize] = &[offset];
+            let mask = CausalMasker
+                .make_causal_mask_as_attn_bias(
+                    &Tensor::zeros((1, len), DType::U32, dev).unwrap(),
+                    &past as &dyn PastKvLenCache,
+                    DType::F32,
+                    HEADS,
+                )
+                .unwrap();
+            let mut metadata = PagedAttentionInputMetadata {
+                block_tables: Some(block_tables.clone()),
+                context_lens: Some(Tensor::new(&[(offset + len) as u32], dev).unwrap()),
+                slot_mappings: Tensor::arange(offset as i64, (offset + len) as i64, dev)
+                    .unwrap()
+                    .unsqueeze(0)
+                    .unwrap(),
+                max_context_len: Some(offset + len),
+                chunk_offset_toks: offset,
+            };
+            outputs.push(
+                attn.forward(
+                    &q.narrow(2, offset, len).unwrap(),
+                    &k.narrow(2, offset, len).unwrap(),
+                    &v.narrow(2, offset, len).unwrap(),
+                    mask.as_ref(),
+                    Some(key_cache.clone()),
+                    Some(value_cache.clone()),
+                    &mut metadata,
+                    None,
+                )
+                .unwrap(),
+            );
+        }
+        Tensor::cat(&outputs, 2).unwrap()
+    }
+
+    #[test]
+    fn chunked_prefill_matches_unchunked() {
+        const N_TOKS: usize = 2000;
+
+        let dev = Device::new_cuda(0).unwrap();
+        let attn = PagedAttention::new(
+            HEADS,
+            HEAD_DIM,
+            1. / (HEAD_DIM as f32).sqrt(),
+            Some(KV_HEADS),
+            None,
+            &dev,
+            None,
+        )
+        .unwrap();
+        let q = Tensor::randn(0f32, 1., (1, HEADS, N_TOKS, HEAD_DIM), &dev).unwrap();
+        let k = Tensor::randn(0f32, 1., (1, KV_HEADS, N_TOKS, HEAD_DIM), &dev).unwrap();
+        let v = Tensor::randn(0f32, 1., (1, KV_HEADS, N_TOKS, HEAD_DIM), &dev).unwrap();
+
+        let unchunked = chunked_prefill(&attn, (&q, &k, &v), N_TOKS);
+        let chunked = chunked_prefill(&attn, (&q, &k, &v), 512);
+        let max_diff = (unchunked - chunked)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(max_diff < 1e-4, "max difference {max_diff}");
+    }
+}
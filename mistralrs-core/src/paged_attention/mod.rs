@@ -38,6 +38,9 @@ impl PagedAttentionConfig {
         mem_cpu: usize,
         mem_gpu: MemoryGpuConfig,
     ) -> anyhow::Result<Self> {
+        if let MemoryGpuConfig::NumTokens(0) = mem_gpu {
+            anyhow::bail!("The number of KV cache tokens must be positive.");
+        }
         Ok(Self {
             block_size,
             mem_cpu,
@@ -57,6 +60,8 @@ pub enum MemoryGpuConfig {
     Amount(usize),
     Utilization(f32),
     ContextSize(usize),
+    /// Allocate exactly enough blocks to hold this many KV tokens in total across all sequences.
+    NumTokens(usize),
 }
 
 // See `pagedattention.cu` CALL_V1_LAUNCHER_BLOCK_SIZE
@@ -102,22 +107,42 @@ pub fn calculate_cache_config(
     }
     let dtype_size = dtype.size_in_bytes();
 
+    let mb_to_gpu_blocks = |mem_gpu: usize| {
+        info!("Allocating {mem_gpu} MB for PagedAttention KV cache");
+        mb_to_blocks!(mem_gpu * SIZE_IN_MB, dtype_size, block_size, config)
+    };
     #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
-    let mem_gpu = match mem_gpu {
-        MemoryGpuConfig::Amount(v) => v,
+    let num_gpu_blocks = match mem_gpu {
+        MemoryGpuConfig::Amount(v) => mb_to_gpu_blocks(v),
         MemoryGpuConfig::Utilization(f) => {
             let free = MemoryUsage.get_memory_available(device)? as f32 / SIZE_IN_MB as f32;
             let total = MemoryUsage.get_total_memory(device)? as f32 / SIZE_IN_MB as f32;
             let used = total - free;
-            (total * f - used) as usize
+            mb_to_gpu_blocks((total * f - used) as usize)
         }
         MemoryGpuConfig::ContextSize(toks) => {
-            ctxt_to_blocks!(toks, dtype_size, block_size, config) / SIZE_IN_MB
+            mb_to_gpu_blocks(ctxt_to_blocks!(toks, dtype_size, block_size, config) / SIZE_IN_MB)
+        }
+        MemoryGpuConfig::NumTokens(toks) => {
+            // Sized in blocks rather than in MB, so no tokens are lost to rounding.
+            let num_gpu_blocks = toks.div_ceil(block_size);
+            let required =
+                ctxt_to_blocks!(num_gpu_blocks * block_size, dtype_size, block_size, config);
+            let free = MemoryUsage.get_memory_available(device)?;
+            if required > free {
+                anyhow::bail!(
+                    "A KV cache of {toks} tokens needs {} MB, but only {} MB of GPU memory is free. Reduce the number of KV cache tokens.",
+                    required.div_ceil(SIZE_IN_MB),
+                    free / SIZE_IN_MB
+                );
+            }
+            info!(
+                "Allocating {} MB for PagedAttention KV cache",
+                required.div_ceil(SIZE_IN_MB)
+            );
+            num_gpu_blocks
         }
     };
-    info!("Allocating {mem_gpu} MB for PagedAttention KV cache");
-
-    let num_gpu_blocks = mb_to_blocks!(mem_gpu * SIZE_IN_MB, dtype_size, block_size, config);
     let num_cpu_blocks = mb_to_blocks!(mem_cpu * SIZE_IN_MB, dtype_size, block_size, config);
     if num_gpu_blocks == 0 {
         anyhow::bail!("Num GPU blocks is 0. This means there is not enough memory. Either reduce the memory amount/utilization/context size or disable PagedAttention.");
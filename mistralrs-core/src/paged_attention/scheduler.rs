@@ -14,7 +14,7 @@ use std::{
     sync::{atomic::Ordering, Arc, Mutex},
 };
 
-use tracing::warn;
+use tracing::{info, warn};
 
 use crate::{
     get_mut_arcmutex,
@@ -36,6 +36,36 @@ pub struct PagedAttentionSchedulerOutput {
 
 pub struct PagedAttentionSchedulerConfig {
     pub max_num_seqs: usize,
+    /// The maximum number of prompt tokens of a prompt step. A prompt longer than this is prefilled
+    /// alone. If set, prompt steps alternate with completion steps while sequences are running.
+    ///
+    /// Prompts are not split into chunks, and a step never mixes prompts with completions, as a
+    /// batch of the pipeline is either all prompts or all completions.
+    pub max_num_batched_tokens: Option<usize>,
+}
+
+/// The prompt tokens which one prompt step may still process.
+struct TokenBudget {
+    max: Option<usize>,
+    used: usize,
+}
+
+impl TokenBudget {
+    fn new(max: Option<usize>) -> Self {
+        Self { max, used: 0 }
+    }
+
+    /// Take the `n` tokens of a prompt from the budget if they fit. The first prompt always fits,
+    /// so that prompts longer than the budget are still run.
+    fn try_take(&mut self, n: usize) -> bool {
+        let fits = self
+            .max
+            .map_or(true, |max| self.used == 0 || self.used + n <= max);
+        if fits {
+            self.used += n;
+        }
+        fits
+    }
 }
 
 pub struct PagedAttentionScheduler {
@@ -45,6 +75,10 @@ pub struct PagedAttentionScheduler {
     config: PagedAttentionSchedulerConfig,
     pub block_engine: BlockEngine,
     block_size: usize,
+    /// Copies of the blocks of sequences which share a prefill, executed with the next step.
+    pending_blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
+    /// Whether the last step was a prompt step.
+    last_was_prompt: bool,
 }
 
 impl PagedAttentionScheduler {
@@ -60,15 +94,65 @@ impl PagedAttentionScheduler {
                 cache_config.num_cpu_blocks,
             ),
             block_size: cache_config.block_size,
+            pending_blocks_to_copy: HashMap::new(),
+            last_was_prompt: false,
         }
     }
 
     pub fn schedule(&mut self) -> PagedAttentionSchedulerOutput {
+        // Sequences whose context was rolled keep the blocks of the sinks and recompute the rest of
+        // their context before anything else runs.
+        let rolled = self
+            .running
+            .iter()
+            .filter(|seq| get_mut_arcmutex!(seq).rolled_cache_len().is_some())
+            .cloned()
+            .collect::<Vec<_>>();
+        if !rolled.is_empty() {
+            let mut scheduled = Vec::new();
+            for seq in rolled {
+                let reallocated = {
+                    let mut seq_handle = get_mut_arcmutex!(seq);
+                    let num_kept_blocks = seq_handle.rolled_cache_len().unwrap() / self.block_size;
+                    let reallocated = self
+                        .block_engine
+                        .reallocate_tail(&*seq_handle, num_kept_blocks);
+                    if !reallocated {
+                        seq_handle.reset_rolled_cache();
+                    }
+                    reallocated
+                };
+                if reallocated {
+                    scheduled.push(seq);
+                } else {
+                    self.running.retain(|other| !Arc::ptr_eq(other, &seq));
+                    self._preempt_by_recompute(seq);
+                }
+            }
+            if !scheduled.is_empty() {
+                self.last_was_prompt = true;
+                return PagedAttentionSchedulerOutput {
+                    scheduled,
+                    blocks_to_swap_in: HashMap::new(),
+                    blocks_to_copy: std::mem::take(&mut self.pending_blocks_to_copy),
+                    blocks_to_swap_out: HashMap::new(),
+                };
+            }
+        }
+
+        // With a token budget, running sequences get a completion step after each prompt step, so
+        // that a stream of prompts does not stall them.
+        let completion_turn = self.config.max_num_batched_tokens.is_some()
+            && self.last_was_prompt
+            && !self.running.is_empty();
+        self.last_was_prompt = false;
+
         // If there are no swapped seqs (they have higher priority), add seqs that are in the
         // waiting queue to the running queue.
-        if self.swapped_out.is_empty() {
+        if self.swapped_out.is_empty() && !completion_turn {
             let mut scheduled = VecDeque::new();
             let mut did_ignore = false;
+            let mut budget = TokenBudget::new(self.config.max_num_batched_tokens);
             while !self.waiting.is_empty() {
                 let seq = self.waiting.front().unwrap().clone();
 
@@ -90,7 +174,12 @@ impl PagedAttentionScheduler {
                         get_mut_arcmutex!(seq).set_state(SequenceState::FinishedIgnored);
                         did_ignore = true;
                     }
-                    _ => {}
+                    AllocStatus::Ok => {
+                        // The remaining prompts wait for a later prompt step.
+                        if !budget.try_take(get_mut_arcmutex!(seq).get_toks().len()) {
+                            break;
+                        }
+                    }
                 }
 
                 if !did_ignore {
@@ -108,10 +197,11 @@ impl PagedAttentionScheduler {
 
             // If we did schedule, or we ignored sequences.
             if !scheduled.is_empty() || did_ignore {
+                self.last_was_prompt = !scheduled.is_empty();
                 return PagedAttentionSchedulerOutput {
                     scheduled: scheduled.into(),
                     blocks_to_swap_in: HashMap::new(),
-                    blocks_to_copy: HashMap::new(),
+                    blocks_to_copy: std::mem::take(&mut self.pending_blocks_to_copy),
                     blocks_to_swap_out: HashMap::new(),
                 };
             }
@@ -119,7 +209,7 @@ impl PagedAttentionScheduler {
 
         let mut blocks_to_swap_out = HashMap::new();
         let mut blocks_to_swap_in = HashMap::new();
-        let mut blocks_to_copy = HashMap::new();
+        let mut blocks_to_copy = std::mem::take(&mut self.pending_blocks_to_copy);
 
         // Reserve token slots for the running sequence groups, preempting the lowest (earliest) first.
         // Preempt lowest priority sequences that are in the running queue, forming a
@@ -340,6 +430,31 @@ impl Scheduler for PagedAttentionScheduler {
     fn add_seq(&mut self, seq: Sequence) {
         self.waiting.push_back(Arc::new(Mutex::new(seq)));
     }
+    fn add_shared_prefill_seq(&mut self, mut seq: Sequence, leader: &Sequence) {
+        // The blocks holding the prompt. The last prompt token is computed again by the first
+        // completion step, so its block is copied rather than shared.
+        let num_blocks = seq.get_toks().len().div_ceil(self.block_size);
+        match self
+            .block_engine
+            .fork(leader.get_id(), seq.get_id(), num_blocks)
+        {
+            Some((src, dst)) => {
+                self.pending_blocks_to_copy
+                    .entry(src)
+                    .or_default()
+                    .push(dst);
+                seq.share_paged_prefill(leader);
+                self.running.push_back(Arc::new(Mutex::new(seq)));
+            }
+            None => {
+                info!(
+                    "Could not share the prefill of sequence {}, running a separate prefill.",
+                    leader.get_id()
+                );
+                self.add_seq(seq);
+            }
+        }
+    }
     fn schedule(&mut self) -> SchedulerOutput<'_> {
         SchedulerOutput::PagedAttention {
             output: self.schedule(),
@@ -348,6 +463,9 @@ impl Scheduler for PagedAttentionScheduler {
     fn waiting_len(&self) -> usize {
         self.waiting.len()
     }
+    fn running_len(&self) -> usize {
+        self.running.len()
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         Some(&self.block_engine.block_tables)
     }
@@ -357,7 +475,165 @@ impl Scheduler for PagedAttentionScheduler {
     fn free_finished_sequence_groups(&mut self) {
         self.free_finished_sequence_groups()
     }
+    fn seq_mut(&mut self, _id: usize) -> Option<&mut Sequence> {
+        None
+    }
+    fn cancel_request(&mut self, request_id: usize) -> bool {
+        let mut found = false;
+        for seq in self
+            .running
+            .iter()
+            .chain(self.waiting.iter())
+            .chain(self.swapped_out.iter())
+        {
+            let mut seq = get_mut_arcmutex!(seq);
+            if seq.request_id() == request_id {
+                seq.cancel();
+                found = true;
+            }
+        }
+        found
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::{PagedAttentionScheduler, PagedAttentionSchedulerConfig};
+    use crate::{
+        aici::{bytes::TokRxInfo, toktree::TokTrie},
+        get_mut_arcmutex,
+        paged_attention::CacheConfig,
+        sampler::Sampler,
+        scheduler::Scheduler,
+        sequence::{Sequence, SequenceGroup, SequenceRecognizer},
+        TemperatureOrder,
+    };
+
+    const BLOCK_SIZE: usize = 32;
+
+    fn scheduler(max_num_batched_tokens: Option<usize>) -> PagedAttentionScheduler {
+        PagedAttentionScheduler::new(
+            PagedAttentionSchedulerConfig {
+                max_num_seqs: 16,
+                max_num_batched_tokens,
+            },
+            CacheConfig {
+                block_size: BLOCK_SIZE,
+                num_gpu_blocks: 1024,
+                num_cpu_blocks: 0,
+            },
+        )
+    }
+
+    /// A waiting sequence with a prompt of `prompt_len` tokens, which arrived at time `id`.
+    fn sequence(id: usize, prompt_len: usize) -> Sequence {
+        let tok_trie = Arc::new(TokTrie::from(
+            &TokRxInfo {
+                vocab_size: 1,
+                tok_eos: 0,
+            },
+            &[b"a".to_vec()],
+        ));
+        let sampler = Sampler::new(
+            None,
+            0,
+            tok_trie.clone(),
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            TemperatureOrder::default(),
+            vec![],
+        );
+        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, 1,
+        )));
+        Sequence::new_waiting(
+            vec![0; prompt_len],
+            id,
+            id as u128,
+            1,
+            channel(1).0,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            Some(BLOCK_SIZE),
+            (*tok_trie).clone(),
+            None,
+        )
+    }
+
+    /// Schedule the waiting prompts of these lengths until all of them ran, returning whether each
+    /// step was a prompt step and the sorted prompt lengths of its sequences.
+    fn steps(
+        prompt_lens: &[usize],
+        max_num_batched_tokens: Option<usize>,
+    ) -> Vec<(bool, Vec<usize>)> {
+        let mut scheduler = scheduler(max_num_batched_tokens);
+        for (id, len) in prompt_lens.iter().enumerate() {
+            scheduler.add_seq(sequence(id, *len));
+        }
+        let mut steps = Vec::new();
+        while scheduler.waiting_len() > 0 {
+            let output = scheduler.schedule();
+            let is_prompt = get_mut_arcmutex!(output.scheduled[0]).is_prompt();
+            let mut lens = output
+                .scheduled
+                .iter()
+                .map(|seq| {
+                    let seq = get_mut_arcmutex!(seq);
+                    assert_eq!(seq.is_prompt(), is_prompt);
+                    seq.get_toks().len()
+                })
+                .collect::<Vec<_>>();
+            lens.sort_unstable();
+            steps.push((is_prompt, lens));
+        }
+        steps
+    }
+
+    #[test]
+    fn long_prompts_are_prefilled_within_the_token_budget() {
+        let prompt_lens = [6000, 20, 30, 3000, 1500, 10];
+        // Without a budget, all prompts are prefilled together, so the short prompts wait for the
+        // long ones.
+        assert_eq!(
+            steps(&prompt_lens, None),
+            vec![(true, vec![10, 20, 30, 1500, 3000, 6000])]
+        );
+        // With a budget, a prompt longer than it is prefilled alone, and the others are packed into
+        // steps of at most 2048 tokens. The running sequences run a completion step between them.
+        assert_eq!(
+            steps(&prompt_lens, Some(2048)),
+            vec![
+                (true, vec![6000]),
+                (false, vec![6000]),
+                (true, vec![20, 30]),
+                (false, vec![20, 30, 6000]),
+                (true, vec![3000]),
+                (false, vec![20, 30, 3000, 6000]),
+                (true, vec![10, 1500]),
+            ]
+        );
+    }
+}
@@ -12,27 +12,28 @@ use candle_nn::{AdamW, Optimizer, ParamsAdamW};
 use either::Either;
 use image::DynamicImage;
 use indexmap::IndexMap;
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{ImatrixData, IsqType};
 #[cfg(feature = "plotly")]
 use plotly::{layout::Axis, ImageFormat, Plot, Scatter};
 use rand::{seq::SliceRandom, thread_rng};
 use rand_isaac::Isaac64Rng;
 use tracing::{info, warn};
 
+use crate::response::AdaptersResponse;
 use crate::{
     aici::toktree::TokTrie,
     amoe::{AnyMoeConfig, AnyMoeTrainingInputRow, AnyMoeTrainingInputs, AnyMoeTrainingResult},
     get_mut_arcmutex,
     prefix_cacher::PrefixCacheManager,
-    sampler::Sampler,
+    sampler::{Sampler, TemperatureOrder},
     sequence::{Sequence, SequenceGroup, SequenceRecognizer},
-    utils::progress::NiceProgressBar,
+    utils::{images::load_image, progress::NiceProgressBar},
     DeviceMapMetadata, Loader, ModelCategory, ModelKind, ModelPaths, PagedAttentionConfig,
     Pipeline, Response, TokenSource, TryIntoDType,
 };
 
 use super::{
-    AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, IsqPipelineMixin,
+    AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, IsqPipelineMixin, KvCacheDtype,
     MetadataMixin, PreProcessingMixin,
 };
 
@@ -182,8 +183,17 @@ impl AnyMoePipeline {
 }
 
 impl AdapterActivationMixin for AnyMoePipeline {
-    fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
-        get_mut_arcmutex!(self.target).activate_adapters(adapters)
+    fn activate_adapters_weighted(
+        &mut self,
+        adapters: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).activate_adapters_weighted(adapters)
+    }
+    fn load_adapter(&mut self, name: String, path_or_hf_id: String) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).load_adapter(name, path_or_hf_id)
+    }
+    fn adapters(&self) -> AdaptersResponse {
+        get_mut_arcmutex!(self.target).adapters()
     }
 }
 
@@ -200,11 +210,21 @@ impl CacheManagerMixin for AnyMoePipeline {
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
         get_mut_arcmutex!(self.target).set_none_cache(reset_non_granular, modify_draft_cache)
     }
+    fn kv_cache_dtype(&self) -> KvCacheDtype {
+        get_mut_arcmutex!(self.target).kv_cache_dtype()
+    }
+    fn set_kv_cache_dtype(&self, dtype: KvCacheDtype) {
+        get_mut_arcmutex!(self.target).set_kv_cache_dtype(dtype)
+    }
 }
 
 impl IsqPipelineMixin for AnyMoePipeline {
-    fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
-        get_mut_arcmutex!(self.target).re_isq_model(dtype)
+    fn re_isq_model(
+        &mut self,
+        dtype: IsqType,
+        imatrix: Option<&ImatrixData>,
+    ) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).re_isq_model(dtype, imatrix)
     }
 }
 
@@ -244,6 +264,18 @@ impl Pipeline for AnyMoePipeline {
         get_mut_arcmutex!(self.target).forward_inputs(inputs)
     }
 
+    fn forward_embeddings(&self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        get_mut_arcmutex!(self.target).forward_embeddings(toks)
+    }
+    fn forward_raw(
+        &self,
+        toks: &[u32],
+        return_hidden_states: bool,
+        all_positions: bool,
+    ) -> Result<(Tensor, Option<Tensor>), candle_core::Error> {
+        get_mut_arcmutex!(self.target).forward_raw(toks, return_hidden_states, all_positions)
+    }
+
     async fn sample(
         &self,
         seqs: &mut [&mut Sequence],
@@ -354,11 +386,22 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
 
         // Create several dummy objects for the sequences. No custom logits processors.
         let (dummy_sender, _) = tokio::sync::mpsc::channel(10000);
-        let dummy_sampler =
-            Sampler::new(None, 0, tokenizer.clone(), None, None, -1, 0.0, 0.0, vec![]);
+        let dummy_sampler = Sampler::new(
+            None,
+            0,
+            metadata.tok_trie.clone(),
+            None,
+            None,
+            -1,
+            0.0,
+            0.0,
+            1.0,
+            TemperatureOrder::default(),
+            vec![],
+        );
 
         let dummy_group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            1, false, false, 0,
+            1, false, false, 1,
         )));
 
         // Clear KV cache in prep for training
@@ -411,7 +454,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
                                     // Decode with base64
                                     general_purpose::STANDARD.decode(url)?
                                 };
-                                Ok(image::load_from_memory(&bytes)?)
+                                load_image(&bytes)
                             })
                             .collect::<anyhow::Result<Vec<_>>>()
                     });
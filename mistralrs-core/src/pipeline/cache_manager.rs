@@ -1,6 +1,9 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
-use candle_core::{Tensor, D};
+use candle_core::{DType, Tensor, D};
 
 use crate::{get_mut_arcmutex, sequence::Sequence};
 
@@ -19,12 +22,115 @@ pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
 
 pub type LayerCaches = Vec<Option<(Tensor, Tensor)>>;
 
+/// How the keys and values are stored in the (non-PagedAttention) KV cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KvCacheDtype {
+    /// Unquantized, in the model's activation dtype such as f16 or bf16.
+    #[default]
+    F16,
+    /// Quantized to 8 bits with a power of two scale per token and head. The keys and values of the new
+    /// tokens are quantized when they are appended, and the cache is dequantized before attention.
+    Q8,
+}
+
+impl KvCacheDtype {
+    /// The dtype of the cached keys and values of a model with the given activation dtype.
+    pub(crate) fn storage_dtype(&self, activation_dtype: DType) -> DType {
+        match self {
+            Self::F16 => activation_dtype,
+            Self::Q8 => DType::U8,
+        }
+    }
+}
+
+/// Keep generating past the model's maximum sequence length by evicting the middle of the context, as
+/// in StreamingLLM: the first `sink` tokens, which act as attention sinks, and the most recent `window`
+/// tokens are kept.
+///
+/// Once a sequence reaches the maximum sequence length, the middle of its context is evicted from its
+/// tokens and its KV cache. The sinks keep their positions, so their KV cache is kept, either the
+/// tensors of the sequence or its PagedAttention blocks. The window moves to the positions following
+/// the sinks, and its keys were rotated for their old RoPE positions, so a prompt step recomputes its
+/// KV cache after the kept one. The evicted tokens are still part of the output and the usage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RollingCacheConfig {
+    pub sink: usize,
+    pub window: usize,
+}
+
+impl RollingCacheConfig {
+    /// Whether the kept tokens and the next generated token fit in `max_seq_len`.
+    pub(crate) fn fits(&self, max_seq_len: usize) -> bool {
+        self.window > 0 && self.sink + self.window + 1 < max_seq_len
+    }
+
+    /// The range of a context of `n_toks` tokens to evict, if the next generated token does not fit in
+    /// `max_seq_len`.
+    pub(crate) fn eviction_range(&self, n_toks: usize, max_seq_len: usize) -> Option<Range<usize>> {
+        (n_toks + 1 >= max_seq_len && n_toks > self.sink + self.window)
+            .then(|| self.sink..n_toks - self.window)
+    }
+
+    /// Evict everything but the sinks from the KV `cache` of a context whose first `n_cached` tokens
+    /// were computed. Returns whether the sinks were kept, which requires a cache of every position
+    /// and not only of a sliding window. Otherwise, the whole cache is evicted.
+    pub(crate) fn evict(&self, cache: &mut LayerCaches, n_cached: usize) -> bool {
+        let kept = self.sink > 0
+            && cache.iter_mut().all(|layer| match layer {
+                Some((k, v)) if k.dim(2).is_ok_and(|len| len == n_cached) => {
+                    match (k.narrow(2, 0, self.sink), v.narrow(2, 0, self.sink)) {
+                        (Ok(sink_k), Ok(sink_v)) => {
+                            *layer = Some((sink_k, sink_v));
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                _ => false,
+            });
+        if !kept {
+            *cache = vec![None; cache.len()];
+        }
+        kept
+    }
+}
+
+/// Quantize keys or values of shape `(.., head_dim)` to a u8 tensor of shape `(.., head_dim + 1)`. The
+/// values are stored offset by 128, followed by the exponent of their scale, also offset by 128.
+fn quantize_kv(x: &Tensor) -> Result<Tensor, candle_core::Error> {
+    let x = x.to_dtype(DType::F32)?;
+    // The smallest power of two scale which fits the largest value in [-127, 127]. An all-zero row
+    // has a scale of 2^-100.
+    let exponent = ((x.abs()?.max_keepdim(D::Minus1)? / 127.)?.log()? / std::f64::consts::LN_2)?
+        .ceil()?
+        .clamp(-100f32, 100f32)?;
+    let scale = (&exponent * std::f64::consts::LN_2)?.exp()?;
+    let values = (x.broadcast_div(&scale)?.round()?.clamp(-127f32, 127f32)? + 128.)?;
+    Tensor::cat(
+        &[
+            values.to_dtype(DType::U8)?,
+            (exponent + 128.)?.to_dtype(DType::U8)?,
+        ],
+        D::Minus1,
+    )
+}
+
+/// Inverse of [`quantize_kv`], to the given dtype.
+fn dequantize_kv(x: &Tensor, dtype: DType) -> Result<Tensor, candle_core::Error> {
+    let head_dim = x.dim(D::Minus1)? - 1;
+    let values = (x.narrow(D::Minus1, 0, head_dim)?.to_dtype(DType::F32)? - 128.)?;
+    let exponent = (x.narrow(D::Minus1, head_dim, 1)?.to_dtype(DType::F32)? - 128.)?;
+    let scale = (exponent * std::f64::consts::LN_2)?.exp()?;
+    values.broadcast_mul(&scale)?.to_dtype(dtype)
+}
+
 #[derive(Debug, Clone)]
 pub struct Cache {
     cache: Arc<Mutex<LayerCaches>>,
     xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
     draft_cache: Arc<Mutex<LayerCaches>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    kv_cache_dtype: Arc<Mutex<KvCacheDtype>>,
 }
 
 impl Cache {
@@ -42,6 +148,7 @@ impl Cache {
             } else {
                 None
             },
+            kv_cache_dtype: Arc::new(Mutex::new(KvCacheDtype::default())),
         }
     }
 
@@ -72,13 +179,27 @@ impl Cache {
         self.xlora_cache.is_some()
     }
 
+    /// How the keys and values are stored, passed to [`Cache::update_kv_cache`] by the model.
+    pub(crate) fn kv_cache_dtype(&self) -> KvCacheDtype {
+        *get_mut_arcmutex!(self.kv_cache_dtype)
+    }
+
+    pub(crate) fn set_kv_cache_dtype(&self, dtype: KvCacheDtype) {
+        *get_mut_arcmutex!(self.kv_cache_dtype) = dtype;
+    }
+
     /// Update the KV cache and return (k,v)
     pub(crate) fn update_kv_cache(
         cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         k: Tensor,
         v: Tensor,
         slow_cat: bool,
     ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        if kv_cache_dtype == KvCacheDtype::Q8 {
+            let prev = cache.clone();
+            return Self::update_kv_cache_q8(cache, prev, k, v);
+        }
         let (k, v) = match &*cache {
             None => (k, v),
             Some((k_cache, v_cache)) => {
@@ -97,9 +218,32 @@ impl Cache {
         Ok((k, v))
     }
 
+    /// Append the quantized `k` and `v` to the quantized `prev` and store them in the cache, returning
+    /// the dequantized previous keys and values followed by the unquantized new ones.
+    fn update_kv_cache_q8(
+        cache: &mut Option<(Tensor, Tensor)>,
+        prev: Option<(Tensor, Tensor)>,
+        k: Tensor,
+        v: Tensor,
+    ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        let (k_q, v_q) = (quantize_kv(&k)?, quantize_kv(&v)?);
+        let (k, v, k_q, v_q) = match prev {
+            None => (k, v, k_q, v_q),
+            Some((prev_k_q, prev_v_q)) => (
+                Tensor::cat(&[&dequantize_kv(&prev_k_q, k.dtype())?, &k], 2)?,
+                Tensor::cat(&[&dequantize_kv(&prev_v_q, v.dtype())?, &v], 2)?,
+                Tensor::cat(&[prev_k_q, k_q], 2)?,
+                Tensor::cat(&[prev_v_q, v_q], 2)?,
+            ),
+        };
+        *cache = Some((k_q, v_q));
+        Ok((k, v))
+    }
+
     /// Update the KV cache and return (k,v,attn_mask)
     pub(crate) fn update_kv_cache_sliding_window(
         cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         k: Tensor,
         v: Tensor,
         attention_mask: Option<&Tensor>,
@@ -107,6 +251,10 @@ impl Cache {
         slow_cat: bool,
     ) -> Result<(Tensor, Tensor, Option<Tensor>), candle_core::Error> {
         let (k, v, attention_mask) = match cache.clone() {
+            None if kv_cache_dtype == KvCacheDtype::Q8 => {
+                let (k, v) = Self::update_kv_cache_q8(cache, None, k, v)?;
+                return Ok((k, v, attention_mask.cloned()));
+            }
             None => (k, v, attention_mask.cloned()),
             Some((mut prev_k, mut prev_v)) => {
                 let mut mask = attention_mask.cloned();
@@ -137,6 +285,10 @@ impl Cache {
                         }
                     }
                 }
+                if kv_cache_dtype == KvCacheDtype::Q8 {
+                    let (k, v) = Self::update_kv_cache_q8(cache, Some((prev_k, prev_v)), k, v)?;
+                    return Ok((k, v, mask));
+                }
                 let (k, v) = if !slow_cat {
                     let k = candle_nn::ops::kvconcat(&prev_k, &k, 2)?;
                     let v = candle_nn::ops::kvconcat(&prev_v, &v, 2)?;
@@ -319,3 +471,165 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor, D};
+
+    use super::{dequantize_kv, quantize_kv, Cache, RollingCacheConfig};
+
+    #[test]
+    fn q8_roundtrip_error_is_bounded() {
+        let x = (Tensor::randn(0f32, 1., (1, 2, 16, 32), &Device::Cpu).unwrap() * 3.).unwrap();
+        let q = quantize_kv(&x).unwrap();
+        assert_eq!(q.dims(), &[1, 2, 16, 33]);
+        assert_eq!(q.dtype(), DType::U8);
+
+        // The step of each row is at most 2 * absmax / 127.
+        let absmax = x.abs().unwrap().max_keepdim(D::Minus1).unwrap();
+        let err = (dequantize_kv(&q, DType::F32).unwrap() - &x)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .broadcast_div(&absmax)
+            .unwrap()
+            .max_keepdim(D::Minus1)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert!(err.iter().all(|e| *e <= 1. / 127. + 1e-6), "{err:?}");
+
+        let zeros = Tensor::zeros((1, 1, 2, 8), DType::F32, &Device::Cpu).unwrap();
+        let roundtrip = dequantize_kv(&quantize_kv(&zeros).unwrap(), DType::F32).unwrap();
+        assert_eq!(
+            roundtrip.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            vec![0.; 16]
+        );
+    }
+
+    #[test]
+    fn q8_attention_divergence_is_small() {
+        let (heads, prompt_len, decode_len, head_dim) = (4, 48, 16, 64);
+        let device = Device::Cpu;
+        let q = Tensor::randn(0f32, 1., (1, heads, 1, head_dim), &device).unwrap();
+        let k = Tensor::randn(
+            0f32,
+            1.,
+            (1, heads, prompt_len + decode_len, head_dim),
+            &device,
+        )
+        .unwrap();
+        let v = Tensor::randn(
+            0f32,
+            1.,
+            (1, heads, prompt_len + decode_len, head_dim),
+            &device,
+        )
+        .unwrap();
+
+        // Fill the cache with the prompt, then one token at a time.
+        let mut cache = None;
+        let (mut k_q8, mut v_q8) = (None, None);
+        let mut start = 0;
+        for len in std::iter::once(prompt_len).chain(std::iter::repeat(1).take(decode_len)) {
+            let prev = cache.clone();
+            let (k_step, v_step) = Cache::update_kv_cache_q8(
+                &mut cache,
+                prev,
+                k.narrow(2, start, len).unwrap(),
+                v.narrow(2, start, len).unwrap(),
+            )
+            .unwrap();
+            (k_q8, v_q8) = (Some(k_step), Some(v_step));
+            start += len;
+        }
+        let (k_q8, v_q8) = (k_q8.unwrap(), v_q8.unwrap());
+        assert_eq!(k_q8.dims(), k.dims());
+        assert_eq!(
+            cache.unwrap().0.dims(),
+            &[1, heads, prompt_len + decode_len, head_dim + 1]
+        );
+
+        let attention = |k: &Tensor, v: &Tensor| {
+            // Scaled by 1 / sqrt(head_dim).
+            let scores = (q.matmul(&k.t().unwrap()).unwrap() / 8.).unwrap();
+            let probs = candle_nn::ops::softmax_last_dim(&scores).unwrap();
+            let out = probs.matmul(v).unwrap();
+            (probs, out)
+        };
+        let (probs, out) = attention(&k, &v);
+        let (probs_q8, out_q8) = attention(&k_q8, &v_q8);
+
+        let kl = (&probs * (probs.log().unwrap() - probs_q8.log().unwrap()).unwrap())
+            .unwrap()
+            .sum_keepdim(D::Minus1)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert!(kl.iter().all(|kl| *kl < 1e-3), "{kl:?}");
+
+        let rel_err = ((out_q8 - &out).unwrap().sqr().unwrap().sum_all().unwrap()
+            / out.sqr().unwrap().sum_all().unwrap())
+        .unwrap()
+        .sqrt()
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap();
+        assert!(rel_err < 0.02, "{rel_err}");
+    }
+
+    #[test]
+    fn rolling_cache_eviction_range() {
+        const MAX_SEQ_LEN: usize = 16;
+        let rolling = RollingCacheConfig { sink: 4, window: 6 };
+        assert!(rolling.fits(MAX_SEQ_LEN));
+        assert!(!RollingCacheConfig {
+            sink: 4,
+            window: 11
+        }
+        .fits(MAX_SEQ_LEN));
+
+        // A prompt of 8 tokens, followed by 100 generated tokens.
+        let mut context = (0u32..8).collect::<Vec<_>>();
+        for tok in 8u32..108 {
+            // As in a generation step, the next token is checked against the maximum sequence length
+            // before it is added.
+            assert!(context.len() + 1 < MAX_SEQ_LEN);
+            context.push(tok);
+            if let Some(range) = rolling.eviction_range(context.len(), MAX_SEQ_LEN) {
+                context.drain(range);
+            }
+        }
+        // The sinks are kept, followed by the most recent tokens in order.
+        let mut expected = (0u32..4).collect::<Vec<_>>();
+        expected.extend(98u32..108);
+        assert_eq!(context, expected);
+        assert_eq!(rolling.eviction_range(10, MAX_SEQ_LEN), None);
+    }
+
+    #[test]
+    fn rolling_cache_keeps_sink_kv() {
+        let rolling = RollingCacheConfig { sink: 2, window: 3 };
+        // The keys and values of position `i` are `i`.
+        let kv = Tensor::arange(0f32, 14., &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, 14, 1))
+            .unwrap();
+        let mut cache = vec![Some((kv.clone(), kv.clone())); 2];
+        assert!(rolling.evict(&mut cache, 14));
+        for layer in &cache {
+            let (k, v) = layer.as_ref().unwrap();
+            assert_eq!(k.flatten_all().unwrap().to_vec1::<f32>().unwrap(), [0., 1.]);
+            assert_eq!(v.flatten_all().unwrap().to_vec1::<f32>().unwrap(), [0., 1.]);
+        }
+
+        // A sliding window cache no longer holds the sinks, so nothing is kept.
+        let mut cache = vec![Some((kv.clone(), kv)); 2];
+        assert!(!rolling.evict(&mut cache, 20));
+        assert!(cache.iter().all(Option::is_none));
+    }
+}
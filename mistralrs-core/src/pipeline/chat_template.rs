@@ -42,6 +42,31 @@ pub struct ChatTemplateValue(
     #[serde(with = "either::serde_untagged")] pub Either<String, Vec<HashMap<String, String>>>,
 );
 
+impl ChatTemplateValue {
+    /// The template to render. A list of templates, as in some `tokenizer_config.json` files, gives
+    /// the `tool_use` template if `use_tools` and there is one, and the `default` template otherwise.
+    /// Its entries are `{"name": ..., "template": ...}` objects or `{name: template}` maps.
+    pub(crate) fn select(&self, use_tools: bool) -> Result<String> {
+        let templates = match &self.0 {
+            Either::Left(template) => return Ok(template.clone()),
+            Either::Right(templates) => templates,
+        };
+        let find = |name: &str| {
+            templates
+                .iter()
+                .find_map(|t| match (t.get("name"), t.get("template")) {
+                    (Some(t_name), Some(template)) => (t_name == name).then(|| template.clone()),
+                    _ => t.get(name).cloned(),
+                })
+        };
+        use_tools
+            .then(|| find("tool_use"))
+            .flatten()
+            .or_else(|| find("default"))
+            .ok_or_else(|| anyhow::anyhow!("Chat template does not contain a `tool_use` or `default` template. Please ensure it contains at least a `default` template, although `tool_use` should be specified for using tools."))
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Default)]
 /// Template for chat models including bos/eos/unk as well as the chat template.
@@ -97,6 +122,36 @@ impl ChatTemplate {
     }
 }
 
+/// Where the chat template of a model was taken from. These are tried in the order of the variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display)]
+pub(crate) enum ChatTemplateSource {
+    #[strum(to_string = "the specified chat template")]
+    Specified,
+    #[strum(to_string = "`tokenizer_config.json`")]
+    TokenizerConfig,
+    #[strum(to_string = "the GGUF metadata")]
+    Gguf,
+    #[strum(to_string = "the built-in default for the model type")]
+    BuiltIn,
+}
+
+const MISTRAL_CHAT_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token + ' ' }}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}";
+const PHI3_CHAT_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') %}{{'<|user|>' + '\n' + message['content'] + '<|end|>' + '\n' + '<|assistant|>' + '\n'}}{% elif (message['role'] == 'assistant') %}{{message['content'] + '<|end|>' + '\n'}}{% endif %}{% endfor %}";
+const CHATML_CHAT_TEMPLATE: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+const GEMMA_CHAT_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'assistant') %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{'<start_of_turn>model\n'}}{% endif %}";
+
+/// The built-in chat template for a model type, as given by `model_type` in the `config.json` or
+/// `general.architecture` in the GGUF metadata. This is the last resort if a model does not ship a chat template.
+pub(crate) fn builtin_chat_template(model_type: &str) -> Option<&'static str> {
+    match model_type {
+        "llama" | "mistral" | "mixtral" => Some(MISTRAL_CHAT_TEMPLATE),
+        "phi3" => Some(PHI3_CHAT_TEMPLATE),
+        "qwen2" => Some(CHATML_CHAT_TEMPLATE),
+        "gemma" | "gemma2" => Some(GEMMA_CHAT_TEMPLATE),
+        _ => None,
+    }
+}
+
 pub fn calculate_eos_tokens(
     chat_template: &ChatTemplate,
     gen_conf: Option<GenerationConfig>,
@@ -214,6 +269,8 @@ fn tojson(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
     })
 }
 
+/// Render `messages` with the chat `template`. Without `add_generation_prompt`, a final message from
+/// the assistant is left open, so that the model continues it rather than starting a new turn.
 pub fn apply_chat_template_to(
     messages: Vec<IndexMap<String, MessageContent>>,
     add_generation_prompt: bool,
@@ -232,6 +289,12 @@ pub fn apply_chat_template_to(
     env.set_lstrip_blocks(true);
     env.set_trim_blocks(true);
 
+    let continued = if add_generation_prompt {
+        None
+    } else {
+        final_assistant_text(&messages)
+    };
+
     #[derive(Serialize, Deserialize)]
     struct UntaggedContent(#[serde(with = "either::serde_untagged")] MessageContent);
     let mut new_messages = Vec::new();
@@ -243,25 +306,7 @@ pub fn apply_chat_template_to(
         new_messages.push(new_message);
     }
 
-    let template = match &template.0 {
-        Either::Left(x) => x.clone(),
-        Either::Right(map) => {
-            let mut template = "".to_string();
-            for t in map {
-                if t.contains_key("tool_use") && !tools.is_empty() {
-                    template = t["tool_use"].clone();
-                    break;
-                } else if t.contains_key("default") {
-                    template = t["default"].clone();
-                    break;
-                }
-            }
-            if template.is_empty() {
-                anyhow::bail!("Chat template does not contain a `tool_use` or `default` key. Please ensure it contains at least a `default` key, although `tool_use` should be specified for using tools.");
-            }
-            template
-        }
-    };
+    let template = template.select(!tools.is_empty())?;
 
     env.add_template("chat_template", &template)?;
     env.add_function("raise_exception", raise_exception);
@@ -271,17 +316,17 @@ pub fn apply_chat_template_to(
     let date = chrono::Utc::now();
     let date_string = date.format("%d, %B, %Y").to_string();
 
-    if tools.is_empty() {
-        Ok(tmpl.render(context! {
+    let rendered = if tools.is_empty() {
+        tmpl.render(context! {
             messages => new_messages,
             add_generation_prompt => add_generation_prompt,
             bos_token => bos_tok,
             eos_token => eos_tok,
             unk_token => unk_tok,
             date_string => date_string,
-        })?)
+        })?
     } else {
-        Ok(tmpl.render(context! {
+        tmpl.render(context! {
             messages => new_messages,
             add_generation_prompt => add_generation_prompt,
             bos_token => bos_tok,
@@ -289,6 +334,36 @@ pub fn apply_chat_template_to(
             unk_token => unk_tok,
             tools => tools,
             date_string => date_string,
-        })?)
+        })?
+    };
+    Ok(match continued {
+        Some(text) => continue_final_message(rendered, &text),
+        None => rendered,
+    })
+}
+
+/// The text of the last message, if it is from the assistant.
+fn final_assistant_text(messages: &[IndexMap<String, MessageContent>]) -> Option<String> {
+    let message = messages.last()?;
+    if message.get("role")?.as_ref().left()? != "assistant" {
+        return None;
+    }
+    match message.get("content")? {
+        Either::Left(text) => Some(text.clone()),
+        Either::Right(parts) => parts
+            .iter()
+            .rev()
+            .find_map(|part| part.get("text").cloned()),
+    }
+}
+
+/// Cut `rendered` right after the last occurrence of `text`, the content of the final message, to
+/// remove the end of turn which the template adds after it. Templates may trim the content, so it is
+/// matched trimmed.
+fn continue_final_message(mut rendered: String, text: &str) -> String {
+    let text = text.trim();
+    if let Some(start) = rendered.rfind(text).filter(|_| !text.is_empty()) {
+        rendered.truncate(start + text.len());
     }
+    rendered
 }
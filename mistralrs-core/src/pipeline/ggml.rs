@@ -1,13 +1,13 @@
 use super::cache_manager::DefaultCacheManager;
+use super::{
+    adapters_from_paths, AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin,
+    IsqPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
+};
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
     CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, QuantizationKind, TokenSource,
     XLoraPaths,
 };
-use super::{
-    AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, IsqPipelineMixin,
-    MetadataMixin, ModelCategory, PreProcessingMixin,
-};
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
 use crate::lora::Ordering;
@@ -16,6 +16,7 @@ use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::{get_chat_template, Cache};
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::response::AdaptersResponse;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::model_config as ModelConfig;
@@ -33,7 +34,7 @@ use anyhow::Result;
 use candle_core::quantized::ggml_file;
 use candle_core::{DType, Device, Tensor};
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{ImatrixData, IsqType};
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::fs;
@@ -58,6 +59,7 @@ pub struct GGMLPipeline {
     model_id: String,
     non_granular_state: Option<NonGranularState>,
     metadata: Arc<GeneralMetadata>,
+    adapters: AdaptersResponse,
 }
 
 /// A loader for a GGML model.
@@ -243,6 +245,11 @@ impl Loader for GGMLLoader {
                 "You are trying to in-situ quantize a GGML model. This will not do anything."
             );
         }
+        if mapper.is_auto() {
+            anyhow::bail!(
+                "GGML models do not support automatic device mapping. Please consider using a GGUF model."
+            );
+        }
         if !mapper.is_dummy() {
             warn!("GGML models do not support device mapping. Device mapping will not work. Please consider using a GGUF model.");
         }
@@ -322,7 +329,7 @@ impl Loader for GGMLLoader {
         let gen_conf: Option<GenerationConfig> = paths
             .get_gen_conf_filename()
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
-        let chat_template = get_chat_template(paths, &self.chat_template, None);
+        let chat_template = get_chat_template(paths, &self.chat_template, None, Some("llama"))?;
 
         let max_seq_len = match model {
             Model::Llama(ref l) => l.max_seq_len,
@@ -359,7 +366,9 @@ impl Loader for GGMLLoader {
                 cache_config: None,
                 cache_engine: None,
                 prompt_batchsize: self.config.prompt_batchsize,
+                max_image_edge: None,
             }),
+            adapters: adapters_from_paths(paths.as_ref()),
         })))
     }
 
@@ -417,7 +426,7 @@ impl PreProcessingMixin for GGMLPipeline {
 }
 
 impl IsqPipelineMixin for GGMLPipeline {
-    fn re_isq_model(&mut self, _dtype: IsqType) -> Result<()> {
+    fn re_isq_model(&mut self, _dtype: IsqType, _imatrix: Option<&ImatrixData>) -> Result<()> {
         anyhow::bail!(
             "You are trying to in-situ requantize a GGML model. This will not do anything."
         )
@@ -446,18 +455,31 @@ impl CacheManagerMixin for GGMLPipeline {
 }
 
 impl AdapterActivationMixin for GGMLPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_adapters_weighted(
+        &mut self,
+        adapter_names: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
         let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
         if !is_lora {
             anyhow::bail!("Activating adapters is only supported for models fine-tuned with LoRA.")
         }
 
-        match self.model {
+        let n = match self.model {
             Model::XLoraLlama(ref mut model) => model
-                .activate_adapters(adapter_names)
-                .map_err(anyhow::Error::msg),
+                .activate_adapters(adapter_names.clone())
+                .map_err(anyhow::Error::msg)?,
             _ => unreachable!(),
-        }
+        };
+        self.adapters.active = adapter_names.into_iter().map(|(name, _)| name).collect();
+        Ok(n)
+    }
+    fn load_adapter(&mut self, _name: String, _path_or_hf_id: String) -> anyhow::Result<usize> {
+        anyhow::bail!(
+            "Loading adapters is only supported for unquantized models fine-tuned with LoRA."
+        )
+    }
+    fn adapters(&self) -> AdaptersResponse {
+        self.adapters.clone()
     }
 }
 
@@ -496,8 +518,10 @@ impl Pipeline for GGMLPipeline {
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full,
             context_lens,
-            position_ids: _,    // NOTE(EricLBuehler): ignore, it is for phi3
-            paged_attn_meta: _, // NOTE(EricLBuehler): ignore it for ggml
+            position_ids: _,     // NOTE(EricLBuehler): ignore, it is for phi3
+            paged_attn_meta: _,  // NOTE(EricLBuehler): ignore it for ggml
+            early_exit_layer: _, // Only supported by normal models
+            xlora_global_scaling,
         } = *inputs.downcast().expect("Downcast failed.");
         match self.model {
             Model::Llama(ref model) => model.forward(
@@ -517,6 +541,7 @@ impl Pipeline for GGMLPipeline {
                 self.no_kv_cache,
                 &self.non_granular_state,
                 context_lens,
+                xlora_global_scaling,
             ),
         }
     }
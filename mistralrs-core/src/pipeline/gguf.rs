@@ -1,13 +1,13 @@
 use super::cache_manager::DefaultCacheManager;
+use super::{
+    adapters_from_paths, AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin,
+    IsqPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
+};
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
     CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
     TokenSource, XLoraPaths,
 };
-use super::{
-    AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, IsqPipelineMixin,
-    MetadataMixin, ModelCategory, PreProcessingMixin,
-};
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
 use crate::gguf::{
@@ -23,6 +23,7 @@ use crate::pipeline::sampling::sample_and_add_toks;
 use crate::pipeline::ChatTemplate;
 use crate::pipeline::{get_chat_template, Cache};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::response::AdaptersResponse;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::model_config as ModelConfig;
@@ -44,7 +45,7 @@ use anyhow::{bail, Result};
 use candle_core::{DType, Device, Tensor};
 use either::Either;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{ImatrixData, IsqType};
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::fs;
@@ -73,6 +74,7 @@ pub struct GGUFPipeline {
     model_id: String,
     non_granular_state: Option<NonGranularState>,
     metadata: Arc<GeneralMetadata>,
+    adapters: AdaptersResponse,
 }
 
 /// Loader for a GGUF model.
@@ -361,13 +363,8 @@ impl Loader for GGUFLoader {
             }
         };
 
-        // Only load gguf chat template if there is nothing else
-        let gguf_chat_template =
-            if paths.get_template_filename().is_none() && self.chat_template.is_none() {
-                get_gguf_chat_template(&model)?
-            } else {
-                None
-            };
+        // Only used if there is no specified chat template and none in the `tokenizer_config.json`
+        let gguf_chat_template = get_gguf_chat_template(&model)?;
 
         let has_adapter = self.kind.is_adapted();
         let is_xlora = self.kind.is_adapted_and(|a| a.is_x_lora());
@@ -380,6 +377,12 @@ impl Loader for GGUFLoader {
         };
 
         let model_config_metadata: ContentConfig = (&model).into();
+        let mapper = mapper.resolve_auto(
+            model_config_metadata.num_layers,
+            paths.get_weight_filenames(),
+            None,
+            device,
+        )?;
 
         let model_config = {
             // Base config (quantization only):
@@ -448,7 +451,12 @@ impl Loader for GGUFLoader {
         let gen_conf: Option<GenerationConfig> = paths
             .get_gen_conf_filename()
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
-        let mut chat_template = get_chat_template(paths, &self.chat_template, gguf_chat_template);
+        let mut chat_template = get_chat_template(
+            paths,
+            &self.chat_template,
+            gguf_chat_template,
+            Some(&arch.to_string()),
+        )?;
 
         let max_seq_len = match model {
             Model::Llama(ref l) => l.max_seq_len,
@@ -507,7 +515,9 @@ impl Loader for GGUFLoader {
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.prompt_batchsize,
+                max_image_edge: None,
             }),
+            adapters: adapters_from_paths(paths.as_ref()),
         })))
     }
 
@@ -533,7 +543,7 @@ impl PreProcessingMixin for GGUFPipeline {
 }
 
 impl IsqPipelineMixin for GGUFPipeline {
-    fn re_isq_model(&mut self, _dtype: IsqType) -> Result<()> {
+    fn re_isq_model(&mut self, _dtype: IsqType, _imatrix: Option<&ImatrixData>) -> Result<()> {
         anyhow::bail!(
             "You are trying to in-situ requantize a GGML model. This will not do anything."
         )
@@ -566,21 +576,34 @@ impl CacheManagerMixin for GGUFPipeline {
 }
 
 impl AdapterActivationMixin for GGUFPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_adapters_weighted(
+        &mut self,
+        adapter_names: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
         let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
         if !is_lora {
             anyhow::bail!("Activating adapters is only supported for models fine-tuned with LoRA.")
         }
 
-        match self.model {
+        let n = match self.model {
             Model::XLoraLlama(ref mut model) => model
-                .activate_adapters(adapter_names)
-                .map_err(anyhow::Error::msg),
+                .activate_adapters(adapter_names.clone())
+                .map_err(anyhow::Error::msg)?,
             Model::XLoraPhi3(ref mut model) => model
-                .activate_adapters(adapter_names)
-                .map_err(anyhow::Error::msg),
+                .activate_adapters(adapter_names.clone())
+                .map_err(anyhow::Error::msg)?,
             _ => unreachable!(),
-        }
+        };
+        self.adapters.active = adapter_names.into_iter().map(|(name, _)| name).collect();
+        Ok(n)
+    }
+    fn load_adapter(&mut self, _name: String, _path_or_hf_id: String) -> anyhow::Result<usize> {
+        anyhow::bail!(
+            "Loading adapters is only supported for unquantized models fine-tuned with LoRA."
+        )
+    }
+    fn adapters(&self) -> AdaptersResponse {
+        self.adapters.clone()
     }
 }
 
@@ -625,6 +648,8 @@ impl Pipeline for GGUFPipeline {
             context_lens,
             position_ids: _, // NOTE(EricLBuehler): ignore, it is for phi3
             mut paged_attn_meta,
+            early_exit_layer: _, // Only supported by normal models
+            xlora_global_scaling,
         } = *inputs.downcast().expect("Downcast failed.");
         match self.model {
             Model::Llama(ref model) => model.forward(
@@ -660,6 +685,7 @@ impl Pipeline for GGUFPipeline {
                 self.no_kv_cache,
                 &self.non_granular_state,
                 context_lens,
+                xlora_global_scaling,
             ),
             Model::Phi3(ref model) => model.forward(
                 &input_ids,
@@ -681,6 +707,7 @@ impl Pipeline for GGUFPipeline {
                 self.no_kv_cache,
                 &self.non_granular_state,
                 context_lens,
+                xlora_global_scaling,
             ),
             Model::Starcoder2(ref model) => model.forward(
                 &input_ids,
@@ -90,6 +90,9 @@ pub mod text_models_inputs_processor {
         pub context_lens: Option<Tensor>,
         pub slot_mappings: Tensor,
         pub max_context_len: Option<usize>,
+        /// Number of prompt tokens which the previous chunks of a chunked prefill wrote to the
+        /// cache, and which this chunk attends to.
+        pub chunk_offset_toks: usize,
     }
 
     pub struct InputMetadata {
@@ -133,6 +136,8 @@ pub mod text_models_inputs_processor {
         for (seq, mut ctxt) in input_seqs.iter().zip(toks) {
             let prompt_len = ctxt.len();
             let offset = last_n_context_len.unwrap_or_default();
+            // A rolled context continues after the positions kept in its KV cache.
+            let chunk_offset_toks = chunk_offset_toks + seq.rolled_cache_len().unwrap_or(0);
             seqlen_offsets.push(offset.1 + chunk_offset_toks);
 
             position_ids.push(ctxt.len() + chunk_offset_toks);
@@ -190,32 +195,24 @@ pub mod text_models_inputs_processor {
                     let block_offset = i % paged_attn_metadata.block_size;
                     let slot = block_number * paged_attn_metadata.block_size + block_offset;
                     slot_mapping.push(slot.try_into().unwrap());
-                    block_tables.push(table.clone());
                 }
+                block_tables.push(table);
                 slot_mappings.push(slot_mapping);
                 paged_attn_context_lens.push(ctxt_len);
             }
         }
 
+        // The positions of a chunk start after the tokens of the previous chunks.
         let mut tmp = Vec::new();
-        if last_n_context_len.is_some() {
-            for pos in (0..seqs_tensors.len())
-                .map(|i| {
-                    (*seqlen_offsets.get(i).unwrap() as i64
-                        ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>()
-            {
-                tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-            }
-        } else {
-            for pos in (0..seqs_tensors.len())
-                .map(|_| (0..max_len).map(|x| x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>()
-            {
-                tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-            }
+        for pos in (0..seqs_tensors.len())
+            .map(|i| {
+                (*seqlen_offsets.get(i).unwrap() as i64
+                    ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+        {
+            tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
         }
         let positions_kernel = Tensor::cat(&tmp, 0)?;
         let input = Tensor::cat(&seqs_tensors, 0).unwrap();
@@ -266,6 +263,9 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(max_context_len),
+                // The sequences of a prompt step keep the same number of positions when rolled.
+                chunk_offset_toks: chunk_offset_toks
+                    + input_seqs[0].rolled_cache_len().unwrap_or(0),
             })
         } else {
             None
@@ -392,6 +392,7 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(*max_context_len),
+                chunk_offset_toks: 0,
             })
         } else {
             None
@@ -415,7 +416,7 @@ pub mod text_models_inputs_processor {
         mut paged_attn_metadata: Option<&mut PagedAttentionMeta<'_>>,
         prompt_batchsize: Option<NonZeroUsize>,
     ) -> Box<dyn Iterator<Item = Result<InnerInputProcessorOutput>>> {
-        if let (Some(prompt_batchsize), true) = (prompt_batchsize, paged_attn_metadata.is_none()) {
+        if let Some(prompt_batchsize) = prompt_batchsize {
             let mut seq_chunks = Vec::new();
             let mut n_chunks = Vec::new();
             let prompt_batchsize: usize = prompt_batchsize.into();
@@ -457,12 +458,6 @@ pub mod text_models_inputs_processor {
                 .collect::<Vec<_>>();
             Box::new(chunks.into_iter())
         } else {
-            if prompt_batchsize.is_some() {
-                // TODO(EricLBuehler)
-                return Box::new(std::iter::once(Err(anyhow::Error::msg(
-                    "PagedAttention does not yet support prompt batching.",
-                ))));
-            }
             Box::new(std::iter::once(
                 make_prompt_chunk(
                     0,
@@ -521,6 +516,10 @@ pub mod text_models_inputs_processor {
         pub context_lens: Vec<(usize, usize)>,
         pub position_ids: Vec<usize>,
         pub paged_attn_meta: Option<PagedAttentionInputMetadata>,
+        /// *Experimental*: only run this many decoder layers. See `NormalRequest::early_exit_layer`.
+        pub early_exit_layer: Option<usize>,
+        /// X-LoRA global scaling of this batch. See `NormalRequest::xlora_global_scaling`.
+        pub xlora_global_scaling: Option<f64>,
     }
 
     pub struct TextInputsProcessor;
@@ -539,6 +538,12 @@ pub mod text_models_inputs_processor {
             mut paged_attn_metadata: Option<PagedAttentionMeta<'_>>,
             prompt_batchsize: Option<NonZeroUsize>,
         ) -> Box<dyn Iterator<Item = Result<InputProcessorOutput>>> {
+            // Sequences are bucketed by their early exit layer, so it is the same for the whole batch.
+            let early_exit_layer = input_seqs.first().and_then(|seq| seq.early_exit_layer());
+            // The same holds for the X-LoRA global scaling.
+            let xlora_global_scaling = input_seqs
+                .first()
+                .and_then(|seq| seq.xlora_global_scaling());
             if is_xlora && !is_prompt {
                 Box::new(
                     get_prompt_input(
@@ -564,7 +569,7 @@ pub mod text_models_inputs_processor {
                         paged_attn_metadata.as_mut(),
                         prompt_batchsize,
                     ))
-                    .map(|(prompt, completion)| {
+                    .map(move |(prompt, completion)| {
                         let InnerInputProcessorOutput {
                             inputs:
                                 InputMetadata {
@@ -599,6 +604,8 @@ pub mod text_models_inputs_processor {
                             context_lens,
                             position_ids,
                             paged_attn_meta,
+                            early_exit_layer,
+                            xlora_global_scaling,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -619,7 +626,7 @@ pub mod text_models_inputs_processor {
                         paged_attn_metadata.as_mut(),
                         prompt_batchsize,
                     )
-                    .map(|metadata| {
+                    .map(move |metadata| {
                         let InnerInputProcessorOutput {
                             inputs:
                                 InputMetadata {
@@ -642,6 +649,8 @@ pub mod text_models_inputs_processor {
                             context_lens,
                             position_ids,
                             paged_attn_meta,
+                            early_exit_layer,
+                            xlora_global_scaling,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -662,7 +671,7 @@ pub mod text_models_inputs_processor {
                         paged_attn_metadata.as_mut(),
                         prompt_batchsize,
                     )
-                    .map(|metadata| {
+                    .map(move |metadata| {
                         let InnerInputProcessorOutput {
                             inputs:
                                 InputMetadata {
@@ -685,6 +694,8 @@ pub mod text_models_inputs_processor {
                             context_lens,
                             position_ids,
                             paged_attn_meta,
+                            early_exit_layer,
+                            xlora_global_scaling,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
@@ -706,7 +717,7 @@ pub mod text_models_inputs_processor {
                         paged_attn_metadata.as_mut(),
                         prompt_batchsize,
                     )
-                    .map(|metadata| {
+                    .map(move |metadata| {
                         let InnerInputProcessorOutput {
                             inputs:
                                 InputMetadata {
@@ -729,6 +740,8 @@ pub mod text_models_inputs_processor {
                             context_lens,
                             position_ids,
                             paged_attn_meta,
+                            early_exit_layer,
+                            xlora_global_scaling,
                         });
                         Ok(InputProcessorOutput {
                             inputs,
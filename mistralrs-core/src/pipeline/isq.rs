@@ -6,10 +6,10 @@ use std::{
 
 use candle_core::Device;
 use indicatif::{ProgressBar, ProgressStyle};
-use mistralrs_quant::{IsqType, QuantMethod};
-use tracing::info;
+use mistralrs_quant::{ImatrixData, IsqType, QuantMethod};
+use tracing::{info, warn};
 
-use crate::{device_map::DeviceMapper, topology::LayerTopology, Topology};
+use crate::{amoe::MlpLayer, device_map::DeviceMapper, topology::LayerTopology, Topology};
 
 /// Parse ISQ value: one of
 /// - `Q4_0`
@@ -45,10 +45,11 @@ pub fn parse_isq_value(s: &str) -> Result<IsqType, String> {
         "q8k" => IsqType::Q8K,
         "hqq8" => IsqType::HQQ8,
         "hqq4" => IsqType::HQQ4,
+        "q8ch" => IsqType::Q8Ch,
         // "hqq3" => IsqType::HQQ3,
         // "hqq2" => IsqType::HQQ2,
         // "hqq1" => IsqType::HQQ1,
-        _ => return Err(format!("ISQ type {s} unknown, choose one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q8_1`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `Q8K`, `HQQ8`, `HQQ4`.")),
+        _ => return Err(format!("ISQ type {s} unknown, choose one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q8_1`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `Q8K`, `HQQ8`, `HQQ4`, `Q8CH`.")),
     };
     #[cfg(feature = "cuda")]
     {
@@ -65,16 +66,54 @@ pub fn parse_isq_value(s: &str) -> Result<IsqType, String> {
                 | IsqType::Q5K
                 | IsqType::Q6K
                 | IsqType::HQQ8
-                | IsqType::HQQ4 // | IsqType::HQQ3
+                | IsqType::HQQ4
+                | IsqType::Q8Ch // | IsqType::HQQ3
                                 // | IsqType::HQQ2
                                 // | IsqType::HQQ1
         ) {
-            return Err("GGML ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`".to_string());
+            return Err("GGML ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`, `Q8CH`".to_string());
         }
     }
     Ok(tp)
 }
 
+/// The llama.cpp names (`blk.{layer}.{tensor}.weight`) of the given tensors of a layer, see
+/// [`IsqModel::imatrix_names`].
+pub(crate) fn imatrix_layer_names(layer: usize, tensors: &[&str]) -> Vec<Option<String>> {
+    tensors
+        .iter()
+        .map(|tensor| Some(format!("blk.{layer}.{tensor}.weight")))
+        .collect()
+}
+
+/// The llama.cpp names of the tensors returned by [`MlpLayer::get_isq_layers`]. The experts of an
+/// AnyMoE layer have no llama.cpp names, so they are unnamed.
+pub(crate) fn imatrix_mlp_names(
+    layer: usize,
+    mlp: &mut dyn MlpLayer,
+    tensors: &[&str],
+) -> Vec<Option<String>> {
+    let n_tensors = mlp.get_isq_layers().len();
+    if mlp.is_moe_layer() || n_tensors != tensors.len() {
+        vec![None; n_tensors]
+    } else {
+        imatrix_layer_names(layer, tensors)
+    }
+}
+
+/// Look up the imatrix entry of each tensor by its llama.cpp name. Unnamed tensors and tensors
+/// without an entry get no weights.
+fn get_imatrix_weights(imatrix: &ImatrixData, names: &[Option<String>]) -> Vec<Option<Vec<f32>>> {
+    names
+        .iter()
+        .map(|name| {
+            name.as_deref()
+                .and_then(|name| imatrix.get(name))
+                .map(|x| x.to_vec())
+        })
+        .collect()
+}
+
 pub trait IsqModel {
     #[allow(clippy::type_complexity)]
     fn get_layers(
@@ -83,14 +122,27 @@ pub trait IsqModel {
         Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
         &dyn DeviceMapper,
     );
+    /// The llama.cpp name of each tensor returned by [`IsqModel::get_layers`], in the same order,
+    /// such as `blk.0.attn_q.weight` or `output.weight`. These select the imatrix entry of each
+    /// tensor, so unnamed tensors are quantized without an imatrix.
+    ///
+    /// Models which do not name their tensors cannot be quantized with an imatrix.
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        None
+    }
     /// Quantize the model in-situ.
+    ///
+    /// If an `imatrix` is provided, the quantization error is weighted per input channel by the
+    /// importance of that channel. Tensors without a corresponding entry are quantized as usual.
     fn quantize(
         &mut self,
         dtype: Option<IsqType>,
         device: Device,
         topology: Option<&Topology>,
+        imatrix: Option<&ImatrixData>,
     ) -> candle_core::Result<()> {
         {
+            let names = imatrix.and_then(|_| self.imatrix_names());
             let (tensors, mapper) = self.get_layers();
             let total_tensors = tensors.len();
             let n_quantized = AtomicUsize::new(0);
@@ -116,11 +168,19 @@ pub trait IsqModel {
                     .progress_chars("#>-"),
             );
 
-            let layers = topology.map(|x| {
-                x.0.iter()
-                    .filter_map(|topo| topo.as_ref().map(|x| x.isq))
-                    .collect::<Vec<_>>()
-            });
+            let imatrix_weights = match (imatrix, names) {
+                (Some(imatrix), Some(names)) if names.len() == total_tensors => {
+                    let weights = get_imatrix_weights(imatrix, &names);
+                    let n_matched = weights.iter().filter(|x| x.is_some()).count();
+                    info!("Using imatrix for {n_matched} of {total_tensors} tensors.");
+                    weights
+                }
+                (Some(_), _) => {
+                    warn!("The tensors of this model have no llama.cpp names, quantizing without the imatrix.");
+                    vec![None; total_tensors]
+                }
+                (None, _) => vec![None; total_tensors],
+            };
 
             let mut devices_and_dtypes = Vec::new();
             for (_, layer) in &tensors {
@@ -129,14 +189,10 @@ pub trait IsqModel {
                 } else {
                     &device
                 };
-                let dtype = if let Some(ref layers) = layers {
-                    if let Some(layer) = layer {
-                        layers.get(*layer).cloned().unwrap_or(dtype)
-                    } else {
-                        dtype
-                    }
-                } else {
-                    dtype
+                // Layers which the topology does not cover use the global ISQ type
+                let dtype = match (topology, layer) {
+                    (Some(topology), Some(layer)) => topology.layer_isq(*layer).or(dtype),
+                    _ => dtype,
                 };
                 devices_and_dtypes.push((device.clone(), dtype));
             }
@@ -148,8 +204,9 @@ pub trait IsqModel {
                 // Get the MINIMUM of the max isq threads the quant method allows
                 let minimum_max_threads = tensors
                     .iter()
-                    .map(|(q, _)| {
-                        if let Some(dtype) = dtype {
+                    .zip(&devices_and_dtypes)
+                    .map(|((q, _), (_, dtype))| {
+                        if let Some(dtype) = *dtype {
                             q.get_max_isq_cpu_threads(dtype)
                                 .map(usize::from)
                                 .unwrap_or(current_rayon_threads)
@@ -175,11 +232,12 @@ pub trait IsqModel {
                     tensors
                         .into_par_iter()
                         .zip(devices_and_dtypes)
+                        .zip(imatrix_weights)
                         .progress_with(bar)
-                        .for_each(|((tensor, _), (device, dtype))| {
+                        .for_each(|(((tensor, _), (device, dtype)), imatrix_weight)| {
                             *tensor = tensor
                                 .clone()
-                                .apply_isq(dtype, device.clone(), &n_quantized)
+                                .apply_isq(dtype, device.clone(), &n_quantized, imatrix_weight)
                                 .unwrap();
                             device.synchronize().unwrap();
                         });
@@ -192,18 +250,82 @@ pub trait IsqModel {
                 tensors
                     .into_iter()
                     .zip(devices_and_dtypes)
+                    .zip(imatrix_weights)
                     .progress_with(bar)
-                    .for_each(|((tensor, _), (device, dtype))| {
+                    .for_each(|(((tensor, _), (device, dtype)), imatrix_weight)| {
                         *tensor = tensor
                             .clone()
-                            .apply_isq(dtype, device.clone(), &n_quantized)
+                            .apply_isq(dtype, device.clone(), &n_quantized, imatrix_weight)
                             .unwrap();
                         device.synchronize().unwrap();
                     });
             }
             let delta = Instant::now().duration_since(t_start).as_secs_f32();
-            info!("Applied in-situ quantization into {dtype:?} to {n_quantized:?} tensors out of {total_tensors} total tensors. Took {delta:.2}s", );
+            if topology.is_some() {
+                info!("Applied in-situ quantization according to topology to {n_quantized:?} tensors out of {total_tensors} total tensors. Took {delta:.2}s", );
+            } else {
+                info!("Applied in-situ quantization into {dtype:?} to {n_quantized:?} tensors out of {total_tensors} total tensors. Took {delta:.2}s", );
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_quant::ImatrixData;
+
+    use super::{get_imatrix_weights, imatrix_layer_names};
+
+    /// A llama.cpp imatrix file with one call per entry.
+    fn imatrix_bytes(entries: &[(&str, &[f32])]) -> Vec<u8> {
+        let mut bytes = (entries.len() as i32).to_le_bytes().to_vec();
+        for (name, values) in entries {
+            bytes.extend((name.len() as i32).to_le_bytes());
+            bytes.extend(name.as_bytes());
+            bytes.extend(1i32.to_le_bytes());
+            bytes.extend((values.len() as i32).to_le_bytes());
+            bytes.extend(values.iter().flat_map(|x| x.to_le_bytes()));
+        }
+        bytes
+    }
+
+    #[test]
+    fn imatrix_entries_are_matched_by_name() {
+        // `attn_k` has no entry and the experts are merged into one entry in llama.cpp.
+        let imatrix = ImatrixData::from_bytes(&imatrix_bytes(&[
+            ("output.weight", &[1.]),
+            ("blk.0.attn_q.weight", &[2.]),
+            ("blk.0.attn_v.weight", &[3.]),
+            ("blk.0.attn_output.weight", &[4.]),
+            ("blk.0.ffn_gate_exps.weight", &[5., 5.]),
+            ("blk.1.attn_q.weight", &[6.]),
+        ]))
+        .unwrap();
+
+        // The tensors of a model whose `get_layers` order differs from the llama.cpp order, with
+        // two unnamed experts.
+        let mut names = vec![Some("output.weight".to_string())];
+        names.extend(imatrix_layer_names(
+            0,
+            &["attn_output", "attn_v", "attn_k", "attn_q"],
+        ));
+        names.extend([None, None]);
+        names.extend(imatrix_layer_names(1, &["attn_q"]));
+
+        let weights = get_imatrix_weights(&imatrix, &names);
+        assert_eq!(
+            weights,
+            vec![
+                Some(vec![1.]),
+                Some(vec![4.]),
+                Some(vec![3.]),
+                None,
+                Some(vec![2.]),
+                None,
+                None,
+                Some(vec![6.]),
+            ]
+        );
+    }
+}
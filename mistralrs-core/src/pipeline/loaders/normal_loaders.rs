@@ -27,6 +27,9 @@ use crate::{
 };
 
 pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
+    /// `early_exit_layer` is *experimental*: if set, only the first decoder layers are run before the
+    /// final norm and LM head. Models which do not support it ignore it.
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         input_ids: &Tensor,
@@ -35,7 +38,20 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         context_lens: Vec<(usize, usize)>,
         position_ids: Vec<usize>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        early_exit_layer: Option<usize>,
     ) -> candle_core::Result<Tensor>;
+    /// Hidden states of the final layer, after the final norm and before the LM head, with shape
+    /// `(batch, seq_len, hidden_size)`. This uses and updates the KV cache like `forward`.
+    fn forward_embeddings(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Embeddings are not supported for this model.");
+    }
+    /// `global_scaling_weight` overrides the global scaling weight of the X-LoRA config for this
+    /// batch.
     #[allow(clippy::too_many_arguments)]
     fn xlora_forward(
         &self,
@@ -49,17 +65,27 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         non_granular_state: &Option<NonGranularState>,
         context_lens: Vec<(usize, usize)>,
         position_ids: Vec<usize>,
+        global_scaling_weight: Option<f64>,
     ) -> candle_core::Result<Tensor>;
     fn is_xlora(&self) -> bool;
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
     fn max_seq_len(&self) -> usize;
-    fn activate_adapters(&mut self, _: Vec<String>) -> candle_core::Result<usize> {
+    fn activate_adapters(&mut self, _: Vec<(String, f64)>) -> candle_core::Result<usize> {
         // NOTE: While X-LoRA shares a similar name, it is not equivalent. Its adapter set must remain the same.
         candle_core::bail!(
             "Activating adapters is only supported for models fine-tuned with LoRA."
         );
     }
+    /// Load the adapter `name` into the LoRA layers, returning the number of layers which loaded it.
+    fn load_adapter(
+        &mut self,
+        _name: &str,
+        _vb: &VarBuilder,
+        _cfg: &LoraConfig,
+    ) -> candle_core::Result<usize> {
+        candle_core::bail!("Loading adapters is only supported for models fine-tuned with LoRA.");
+    }
     fn config(&self) -> &ModelConfigMetadata;
 }
 
@@ -122,6 +148,10 @@ pub enum NormalLoaderType {
     Gemma2,
     #[serde(rename = "starcoder2")]
     Starcoder2,
+    #[serde(rename = "qwen2moe")]
+    Qwen2Moe,
+    #[serde(rename = "cohere")]
+    Cohere,
 }
 
 impl FromStr for NormalLoaderType {
@@ -137,7 +167,9 @@ impl FromStr for NormalLoaderType {
             "qwen2" => Ok(Self::Qwen2),
             "gemma2" => Ok(Self::Gemma2),
             "starcoder2" => Ok(Self::Starcoder2),
-            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`.")),
+            "qwen2moe" => Ok(Self::Qwen2Moe),
+            "cohere" => Ok(Self::Cohere),
+            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `qwen2moe`, `cohere`.")),
         }
     }
 }
@@ -362,6 +394,7 @@ struct LlamaBasicConfig {
     max_position_embeddings: usize,
     rope_scaling: Option<Llama3RopeConfig>,
     quantization_config: Option<QuantizedConfig>,
+    partial_rotary_factor: Option<f32>,
 }
 
 fn default_rope() -> f32 {
@@ -386,6 +419,7 @@ impl LlamaBasicConfig {
             max_position_embeddings: basic_config.max_position_embeddings,
             rope_scaling: basic_config.rope_scaling,
             quantization_config: basic_config.quantization_config,
+            partial_rotary_factor: basic_config.partial_rotary_factor,
         })
     }
 }
@@ -1004,3 +1038,216 @@ impl NormalModelLoader for Starcoder2Loader {
         Ok(serde_json::from_str::<Starcoder2BasicConfig>(config)?.num_hidden_layers)
     }
 }
+
+// ======================== Qwen2 MoE loader
+
+#[derive(Deserialize)]
+struct Qwen2MoeBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    rms_norm_eps: f64,
+    rope_theta: f64,
+    #[serde(default)]
+    use_sliding_window: bool,
+    sliding_window: Option<usize>,
+    #[serde(default = "default_decoder_sparse_step")]
+    decoder_sparse_step: usize,
+    moe_intermediate_size: usize,
+    shared_expert_intermediate_size: usize,
+    num_experts_per_tok: usize,
+    num_experts: usize,
+    #[serde(default)]
+    norm_topk_prob: bool,
+    #[serde(default)]
+    mlp_only_layers: Vec<usize>,
+    quantization_config: Option<QuantizedConfig>,
+}
+
+fn default_decoder_sparse_step() -> usize {
+    1
+}
+
+impl Qwen2MoeBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::qwen2_moe::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        Ok(models::qwen2_moe::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config.num_key_value_heads,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            rms_norm_eps: basic_config.rms_norm_eps,
+            rope_theta: basic_config.rope_theta,
+            sliding_window: basic_config
+                .sliding_window
+                .filter(|_| basic_config.use_sliding_window),
+            decoder_sparse_step: basic_config.decoder_sparse_step,
+            moe_intermediate_size: basic_config.moe_intermediate_size,
+            shared_expert_intermediate_size: basic_config.shared_expert_intermediate_size,
+            num_experts_per_tok: basic_config.num_experts_per_tok,
+            num_experts: basic_config.num_experts,
+            norm_topk_prob: basic_config.norm_topk_prob,
+            mlp_only_layers: basic_config.mlp_only_layers,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Qwen 2 MoE model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct Qwen2MoeLoader;
+
+impl NormalModelLoader for Qwen2MoeLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::qwen2_moe::Model::new(
+            &Qwen2MoeBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(),
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for Qwen2-MoE.")
+    }
+    fn is_gptx(&self) -> bool {
+        true
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(Qwen2MoeBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(Qwen2MoeBasicConfig::deserialize(config, false)?.num_hidden_layers)
+    }
+}
+
+// ======================== Cohere loader
+
+#[derive(Deserialize)]
+struct CohereBasicConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    hidden_act: Activation,
+    max_position_embeddings: usize,
+    layer_norm_eps: f64,
+    rope_theta: f64,
+    #[serde(default = "default_cohere_logit_scale")]
+    logit_scale: f64,
+    #[serde(default)]
+    attention_bias: bool,
+    #[serde(default)]
+    use_qk_norm: bool,
+    quantization_config: Option<QuantizedConfig>,
+}
+
+fn default_cohere_logit_scale() -> f64 {
+    0.0625
+}
+
+impl CohereBasicConfig {
+    fn deserialize(slice: &str, use_flash_attn: bool) -> Result<models::cohere::Config> {
+        let basic_config: Self = serde_json::from_str(slice)?;
+        Ok(models::cohere::Config {
+            vocab_size: basic_config.vocab_size,
+            hidden_size: basic_config.hidden_size,
+            intermediate_size: basic_config.intermediate_size,
+            num_hidden_layers: basic_config.num_hidden_layers,
+            num_attention_heads: basic_config.num_attention_heads,
+            num_key_value_heads: basic_config.num_key_value_heads,
+            hidden_act: basic_config.hidden_act,
+            max_position_embeddings: basic_config.max_position_embeddings,
+            layer_norm_eps: basic_config.layer_norm_eps,
+            rope_theta: basic_config.rope_theta,
+            logit_scale: basic_config.logit_scale,
+            attention_bias: basic_config.attention_bias,
+            use_qk_norm: basic_config.use_qk_norm,
+            use_flash_attn,
+            quantization_config: basic_config.quantization_config,
+        })
+    }
+}
+
+/// [`NormalLoader`] for a Cohere (Command-R) model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct CohereLoader;
+
+impl NormalModelLoader for CohereLoader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::cohere::Model::new(
+            &CohereBasicConfig::deserialize(config, use_flash_attn)?,
+            vb,
+            self.is_gptx(),
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        anyhow::bail!("X-LoRA is not supported for Cohere.")
+    }
+    /// Cohere rotates interleaved pairs of dimensions.
+    fn is_gptx(&self) -> bool {
+        false
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(CohereBasicConfig::deserialize(
+            config,
+            use_flash_attn,
+        )?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(CohereBasicConfig::deserialize(config, false)?.num_hidden_layers)
+    }
+}
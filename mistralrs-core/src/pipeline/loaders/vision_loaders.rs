@@ -39,6 +39,7 @@ pub trait VisionModel: IsqModel + AnyMoeBaseModelMixin {
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
         position_ids: Vec<usize>,
+        mrope_position_ids: &[[usize; 3]],
         model_specific_args: Box<dyn Any>, // pixel attention mask, or image sizes, or anything else
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> candle_core::Result<Tensor>;
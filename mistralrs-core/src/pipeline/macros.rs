@@ -138,16 +138,19 @@ macro_rules! get_paths {
         } else {
             None
         };
-        let template_filename = if let Some(ref p) = $this.chat_template {
-            info!("Using chat template file at `{p}`");
-            Some(PathBuf::from_str(p)?)
-        } else {
+        // A specified chat template is applied over the one of the `tokenizer_config.json`.
+        let template_filename = if $crate::api_dir_list!(api, model_id)
+            .collect::<Vec<_>>()
+            .contains(&"tokenizer_config.json".to_string())
+        {
             info!("Loading `tokenizer_config.json` at `{}`", $this.model_id);
             Some($crate::api_get_file!(
                 api,
                 "tokenizer_config.json",
                 model_id
             ))
+        } else {
+            None
         };
         Ok(Box::new($path_name {
             tokenizer_filename,
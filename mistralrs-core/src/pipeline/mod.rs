@@ -7,6 +7,7 @@ mod inputs_processor;
 mod isq;
 mod loaders;
 mod macros;
+mod ngram_speculative;
 mod normal;
 mod paths;
 mod processing;
@@ -17,26 +18,36 @@ use crate::aici::toktree::TokTrie;
 use crate::amoe::{AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainingInputs, AnyMoeTrainingResult};
 use crate::paged_attention::{CacheConfig, CacheEngine};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::response::AdaptersResponse;
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
 use chat_template::ChatTemplate;
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFLoader, GGUFLoaderBuilder};
 pub use inputs_processor::InputProcessorOutput;
+pub(crate) use isq::{imatrix_layer_names, imatrix_mlp_names};
 pub use isq::{parse_isq_value, IsqModel};
 pub use loaders::{
-    AdapterKind, Gemma2Loader, GemmaLoader, Idefics2Loader, LLaVALoader, LLaVANextLoader,
-    LlamaLoader, Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
-    NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader,
-    Phi3Loader, Phi3RopeScaling, Phi3VLoader, PrettyName, QuantizationKind, Qwen2Loader,
-    Starcoder2Loader, TokenSource, VisionLoaderType, VisionModel, VisionModelLoader,
+    AdapterKind, CohereLoader, Gemma2Loader, GemmaLoader, Idefics2Loader, LLaVALoader,
+    LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind,
+    ModelPaths, NormalLoaderType, NormalLoadingMetadata, NormalModel, NormalModelLoader,
+    Phi2Loader, Phi3Loader, Phi3RopeScaling, Phi3VLoader, PrettyName, QuantizationKind,
+    Qwen2Loader, Qwen2MoeLoader, Starcoder2Loader, TokenSource, VisionLoaderType, VisionModel,
+    VisionModelLoader,
+};
+use mistralrs_quant::{ImatrixData, IsqType};
+pub use ngram_speculative::{
+    NgramSpeculativeConfig, NgramSpeculativeLoader, NgramSpeculativePipeline,
 };
-use mistralrs_quant::IsqType;
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
-pub(crate) use paths::{get_chat_template, get_model_paths, get_xlora_paths, XLoraPaths};
+pub(crate) use paths::{
+    get_chat_template, get_lora_adapter_paths, get_model_paths, get_model_type, get_xlora_paths,
+    XLoraPaths,
+};
 pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
+pub(crate) use sampling::{DryPenalty, MirostatState};
 pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
 use std::any::Any;
 use std::collections::HashMap;
@@ -50,7 +61,7 @@ use candle_core::{DType, Device, IndexOp, Tensor, Var};
 
 use crate::sequence::Sequence;
 
-pub use self::cache_manager::{Cache, CacheManager, LayerCaches};
+pub use self::cache_manager::{Cache, CacheManager, KvCacheDtype, LayerCaches, RollingCacheConfig};
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
 };
@@ -71,6 +82,8 @@ pub struct GeneralMetadata {
     pub cache_config: Option<CacheConfig>,
     pub cache_engine: Option<CacheEngine>,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    // Images are downscaled so that neither side is longer than this
+    pub max_image_edge: Option<u32>,
 }
 
 pub enum AdapterInstruction {
@@ -97,7 +110,8 @@ pub trait PreProcessingMixin: MetadataMixin {
 }
 
 pub trait IsqPipelineMixin {
-    fn re_isq_model(&mut self, dtype: IsqType) -> Result<()>;
+    /// Requantize the model, optionally weighting the quantization error with an imatrix.
+    fn re_isq_model(&mut self, dtype: IsqType, imatrix: Option<&ImatrixData>) -> Result<()>;
 }
 
 pub trait CacheManagerMixin {
@@ -112,11 +126,47 @@ pub trait CacheManagerMixin {
     /// This may also reset the non granular state if applicable.
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool);
     fn cache(&self) -> &Cache;
+    /// How the model stores its (non-PagedAttention) KV cache.
+    fn kv_cache_dtype(&self) -> KvCacheDtype {
+        self.cache().kv_cache_dtype()
+    }
+    fn set_kv_cache_dtype(&self, dtype: KvCacheDtype) {
+        self.cache().set_kv_cache_dtype(dtype)
+    }
 }
 
 pub trait AdapterActivationMixin {
     /// Returns the number of activated adapters.
-    fn activate_adapters(&mut self, adapters: Vec<String>) -> Result<usize>;
+    fn activate_adapters(&mut self, adapters: Vec<String>) -> Result<usize> {
+        self.activate_adapters_weighted(adapters.into_iter().map(|name| (name, 1.0)).collect())
+    }
+    /// Activate adapters with a weight scaling the contribution of each, where 1.0 is the
+    /// contribution of [`AdapterActivationMixin::activate_adapters`]. Returns the number of
+    /// activated adapters.
+    fn activate_adapters_weighted(&mut self, adapters: Vec<(String, f64)>) -> Result<usize>;
+    /// Load the LoRA adapter `name` from a local directory or Hugging Face model ID, so that it can be
+    /// activated. Returns the number of layers which loaded it.
+    fn load_adapter(&mut self, name: String, path_or_hf_id: String) -> Result<usize>;
+    /// Returns the names of the loaded adapters and of the currently active ones.
+    fn adapters(&self) -> AdaptersResponse;
+}
+
+/// Adapters described by the model paths. All adapters except the preloaded ones start out active.
+pub(crate) fn adapters_from_paths(paths: &dyn ModelPaths) -> AdaptersResponse {
+    let active = paths
+        .get_adapter_configs()
+        .iter()
+        .flatten()
+        .map(|((_, adapter_name), _)| adapter_name.clone())
+        .collect::<Vec<_>>();
+    let mut available = active.clone();
+    available.extend(
+        paths
+            .get_lora_preload_adapter_info()
+            .iter()
+            .flat_map(|preload| preload.keys().cloned()),
+    );
+    AdaptersResponse { available, active }
 }
 
 pub trait MetadataMixin {
@@ -213,6 +263,27 @@ pub trait Pipeline:
 {
     fn forward_inputs(&self, inputs: Box<dyn Any>) -> Result<Tensor, candle_core::Error>;
 
+    /// Run `toks` through the model without the LM head, returning the final hidden states with
+    /// shape `(seq_len, hidden_size)`. The KV cache of the running sequences is left untouched.
+    fn forward_embeddings(&self, _toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!("Embeddings are not supported for this pipeline.");
+    }
+
+    /// Run `toks` through the model in a single forward pass without sampling, returning the
+    /// logits with shape `(n, vocab_size)` and, if `return_hidden_states`, the final hidden states
+    /// with shape `(n, hidden_size)`. `n` is 1 for the last position, or the number of tokens with
+    /// `all_positions`. The KV cache of the running sequences is left untouched.
+    fn forward_raw(
+        &self,
+        _toks: &[u32],
+        _return_hidden_states: bool,
+        _all_positions: bool,
+    ) -> Result<(Tensor, Option<Tensor>), candle_core::Error> {
+        candle_core::bail!("Raw forward passes are not supported for this pipeline.");
+    }
+
+    /// Run a prompt or completion step of `input_seqs` and sample their next tokens. With
+    /// `nan_checks`, the step fails if the logits of a sequence are not all finite.
     #[allow(clippy::too_many_arguments)]
     async fn step(
         &mut self,
@@ -220,11 +291,23 @@ pub trait Pipeline:
         is_prompt: bool,
         prefix_cacher: &mut PrefixCacheManager,
         disable_eos_stop: bool,
+        nan_checks: bool,
         rng: Arc<std::sync::Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<(), candle_core::Error> {
         match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
+                // The logprobs of echoed prompts need the logits of every prompt position. This is
+                // not supported with a prompt batch size, as the prompt is then processed in chunks.
+                let prompt_logprobs_len = if is_prompt
+                    && self.get_metadata().prompt_batchsize.is_none()
+                    && input_seqs.iter().any(|seq| seq.return_prompt_logprobs())
+                {
+                    input_seqs.iter().map(|seq| seq.get_toks().len()).max()
+                } else {
+                    None
+                };
+
                 let inputs_iter = self.get_processor().inputs_processor().process_inputs(
                     self.tokenizer(),
                     input_seqs,
@@ -232,7 +315,7 @@ pub trait Pipeline:
                     self.get_metadata().is_xlora,
                     &self.device(),
                     self.get_metadata().has_no_kv_cache,
-                    None,
+                    prompt_logprobs_len.map(|len| (len, 0)),
                     self.get_input_processor_config(),
                     None,
                     self.get_metadata().prompt_batchsize,
@@ -312,6 +395,29 @@ pub trait Pipeline:
                             .to_device(&Device::Cpu)
                     })
                     .collect::<candle_core::Result<Vec<_>>>()?;
+                if nan_checks {
+                    for (logits, seq) in logits.iter().zip(input_seqs.iter()) {
+                        check_finite_logits(logits, *seq.id(), is_prompt)?;
+                    }
+                }
+
+                // Keep the logits of the last position to sample the next token.
+                let logits = if prompt_logprobs_len.is_some() {
+                    logits
+                        .into_iter()
+                        .zip(input_seqs.iter_mut())
+                        .map(|(logits, seq)| {
+                            if seq.return_prompt_logprobs() {
+                                let logprobs =
+                                    seq.sampler().prompt_logprobs(&logits, seq.get_toks())?;
+                                seq.set_prompt_logprobs(logprobs);
+                            }
+                            logits.narrow(0, logits.dim(0)? - 1, 1)
+                        })
+                        .collect::<candle_core::Result<Vec<_>>>()?
+                } else {
+                    logits
+                };
 
                 match post_op {
                     CacheInstruction::Out => self.clone_out_cache(input_seqs, false),
@@ -374,6 +480,11 @@ pub trait Pipeline:
                             .to_device(&Device::Cpu)
                     })
                     .collect::<candle_core::Result<Vec<_>>>()?;
+                if nan_checks {
+                    for (logits, seq) in logits.iter().zip(input_seqs.iter()) {
+                        check_finite_logits(logits, *seq.id(), is_prompt)?;
+                    }
+                }
 
                 self.sample(input_seqs, logits, prefix_cacher, disable_eos_stop, rng)
                     .await?;
@@ -394,6 +505,29 @@ pub trait Pipeline:
     fn category(&self) -> ModelCategory;
 }
 
+/// Fail with an error naming the step and the sequence if `logits` have a NaN or infinite value, which
+/// usually comes from broken weights or quantization, instead of sampling from them.
+pub(crate) fn check_finite_logits(
+    logits: &Tensor,
+    seq_id: usize,
+    is_prompt: bool,
+) -> candle_core::Result<()> {
+    let vocab_size = logits.dims().last().copied().unwrap_or(1);
+    let values = logits
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    if let Some(pos) = values.iter().position(|value| !value.is_finite()) {
+        candle_core::bail!(
+            "The {} step of sequence {seq_id} produced {} logit for token {}. The model weights or quantization may be broken.",
+            if is_prompt { "prompt" } else { "decode" },
+            if values[pos].is_nan() { "a NaN" } else { "an infinite" },
+            pos % vocab_size,
+        );
+    }
+    Ok(())
+}
+
 pub(crate) fn extract_logits(
     logits: &Tensor,
     context_lens: Vec<(usize, usize)>,
@@ -535,6 +669,81 @@ mod tests {
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
 
+    #[test]
+    fn chat_template_without_generation_prompt() {
+        use super::chat_template::{apply_chat_template_to, ChatTemplateValue};
+
+        // ChatML, as in `test_chat_templates`
+        let template = ChatTemplateValue(Either::Left("{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}".to_string()));
+        let messages = [["system", "You are a helpful assistant"], ["user", "Hello"]]
+            .map(|[role, content]| {
+                IndexMap::from([
+                    ("role".to_string(), Either::Left(role.to_string())),
+                    ("content".to_string(), Either::Left(content.to_string())),
+                ])
+            })
+            .to_vec();
+        let render = |add_generation_prompt| {
+            apply_chat_template_to(
+                messages.clone(),
+                add_generation_prompt,
+                &template,
+                Some("<s>".to_string()),
+                Some("</s>".to_string()),
+                Some("<unk>".to_string()),
+                Vec::new(),
+            )
+            .unwrap()
+        };
+        let expected = "<|im_start|>system\nYou are a helpful assistant<|im_end|>\n<|im_start|>user\nHello<|im_end|>\n";
+        assert_eq!(render(false), expected);
+        assert_eq!(render(true), format!("{expected}<|im_start|>assistant\n"));
+    }
+
+    #[test]
+    fn chat_template_continues_final_assistant_message() {
+        use super::chat_template::{apply_chat_template_to, ChatTemplateValue};
+
+        // ChatML and Gemma, as in `test_chat_templates`
+        let templates = [
+            "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}",
+            "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'assistant') %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{'<start_of_turn>model\n'}}{% endif %}",
+        ];
+        let expected = [
+            "<|im_start|>user\nWrite a haiku<|im_end|>\n<|im_start|>assistant\nAn old silent pond",
+            "<bos><start_of_turn>user\nWrite a haiku<end_of_turn>\n<start_of_turn>model\nAn old silent pond",
+        ];
+        let messages = [
+            ["user", "Write a haiku"],
+            ["assistant", "An old silent pond "],
+        ]
+        .map(|[role, content]| {
+            IndexMap::from([
+                ("role".to_string(), Either::Left(role.to_string())),
+                ("content".to_string(), Either::Left(content.to_string())),
+            ])
+        })
+        .to_vec();
+        for (template, expected) in templates.into_iter().zip(expected) {
+            let template = ChatTemplateValue(Either::Left(template.to_string()));
+            let render = |add_generation_prompt| {
+                apply_chat_template_to(
+                    messages.clone(),
+                    add_generation_prompt,
+                    &template,
+                    Some("<bos>".to_string()),
+                    Some("<eos>".to_string()),
+                    Some("<unk>".to_string()),
+                    Vec::new(),
+                )
+                .unwrap()
+            };
+            // The assistant message is continued: it is not closed, and no new role header follows.
+            assert_eq!(render(false), expected);
+            assert!(render(true).len() > expected.len());
+        }
+    }
+
     #[test]
     /// Generating these cases:
     /// ```py
@@ -649,4 +858,37 @@ mod tests {
 
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
+    #[test]
+    fn nan_logits_are_reported() {
+        use super::check_finite_logits;
+        use candle_core::{DType, Device, Tensor};
+
+        let logits = Tensor::zeros((1, 32), DType::BF16, &Device::Cpu).unwrap();
+        assert!(check_finite_logits(&logits, 3, false).is_ok());
+
+        let mut values = vec![0f32; 32];
+        values[7] = f32::NAN;
+        let logits = Tensor::from_vec(values, (1, 32), &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::BF16)
+            .unwrap();
+        let err = check_finite_logits(&logits, 3, false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("The decode step of sequence 3 produced a NaN logit for token 7."),
+            "{err}"
+        );
+
+        let logits = Tensor::new(&[[0f32, f32::INFINITY]], &Device::Cpu).unwrap();
+        let err = check_finite_logits(&logits, 5, true)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with(
+                "The prompt step of sequence 5 produced an infinite logit for token 1."
+            ),
+            "{err}"
+        );
+    }
 }
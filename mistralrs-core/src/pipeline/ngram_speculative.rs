@@ -0,0 +1,535 @@
+use std::{
+    any::Any,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result as anyhowResult;
+use candle_core::{Device, IndexOp, Result, Tensor};
+use mistralrs_quant::{ImatrixData, IsqType};
+use rand_isaac::Isaac64Rng;
+use tokenizers::Tokenizer;
+use tracing::warn;
+
+use crate::response::AdaptersResponse;
+use crate::{
+    get_mut_arcmutex,
+    pipeline::{
+        sampling::{finish_or_add_toks_to_seq, sample_target_sequence_speculative},
+        AdapterInstruction, Cache, KvCacheDtype,
+    },
+    prefix_cacher::PrefixCacheManager,
+    sequence::{Sequence, SequenceRecognizer, SequenceState},
+    DeviceMapMetadata, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource,
+    TryIntoDType,
+};
+
+use super::{
+    chat_template::ChatTemplate, check_finite_logits, AdapterActivationMixin, AnyMoePipelineMixin,
+    CacheBackendMetadata, CacheInstruction, CacheManagerMixin, GeneralMetadata, IsqPipelineMixin,
+    MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin, Processor,
+};
+
+/// A loader for an n-gram (prompt lookup) speculative pipeline, which only needs the target [`Loader`].
+pub struct NgramSpeculativeLoader {
+    pub target: Box<dyn Loader>,
+    pub config: NgramSpeculativeConfig,
+}
+
+impl Loader for NgramSpeculativeLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let target = self.target.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            NgramSpeculativePipeline::new(target, self.config)?,
+        )))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let target = self.target.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            NgramSpeculativePipeline::new(target, self.config)?,
+        )))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "N-gram speculative: tgt = `{}`, gamma = `{}`, max_ngram = `{}`",
+            self.target.get_id(),
+            self.config.gamma,
+            self.config.max_ngram,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        self.target.get_kind()
+    }
+}
+
+#[derive(Copy, Clone)]
+/// Metadata for an n-gram speculative pipeline
+pub struct NgramSpeculativeConfig {
+    /// Maximum number of tokens to propose per step
+    pub gamma: usize,
+    /// Length of the longest suffix of the sequence to look up in its tokens
+    pub max_ngram: usize,
+}
+
+/// N-gram (prompt lookup) speculative decoding pipeline.
+///
+/// # Algorithm
+/// Instead of running a draft model, the longest suffix of at most `max_ngram` tokens of the sequence
+/// is looked up in its earlier tokens, and the (up to) `gamma` tokens which followed its most recent
+/// occurrence are proposed.
+///
+/// - Run the target model once over the last token and the proposed tokens
+/// - Keep the proposed tokens while they are the same as the target model's samples
+/// - The target model's sample at the first mismatch, or after the last proposed token, is always kept
+///
+/// This does best when the output repeats parts of the context, for example when editing code or
+/// summarizing a document.
+pub struct NgramSpeculativePipeline {
+    target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    gamma: usize,
+    max_ngram: usize,
+    metadata: Arc<GeneralMetadata>,
+    category: ModelCategory,
+}
+
+impl NgramSpeculativePipeline {
+    pub fn new(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: NgramSpeculativeConfig,
+    ) -> Result<Self> {
+        if config.gamma == 0 || config.max_ngram == 0 {
+            candle_core::bail!(
+                "N-gram speculative decoding requires a nonzero `gamma` and `max_ngram`."
+            );
+        }
+        if get_mut_arcmutex!(target).get_metadata().has_no_kv_cache {
+            candle_core::bail!("N-gram speculative decoding requires the KV cache.");
+        }
+        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let category = get_mut_arcmutex!(target).category();
+        Ok(Self {
+            target,
+            gamma: config.gamma,
+            max_ngram: config.max_ngram,
+            metadata,
+            category,
+        })
+    }
+}
+
+/// Propose up to `gamma` tokens to continue `toks`: the tokens which followed the most recent earlier
+/// occurrence of the longest suffix of `toks` with at most `max_ngram` tokens. This is empty if no
+/// suffix occurs earlier.
+pub(crate) fn propose_ngram_continuation(toks: &[u32], max_ngram: usize, gamma: usize) -> &[u32] {
+    for n in (1..=max_ngram.min(toks.len().saturating_sub(1))).rev() {
+        let suffix = &toks[toks.len() - n..];
+        // Leave out the last token so that the suffix does not match itself.
+        if let Some(start) = toks[..toks.len() - 1]
+            .windows(n)
+            .rposition(|window| window == suffix)
+        {
+            let from = start + n;
+            return &toks[from..(from + gamma).min(toks.len())];
+        }
+    }
+    &[]
+}
+
+impl PreProcessingMixin for NgramSpeculativePipeline {
+    fn get_chat_template(&self) -> Arc<ChatTemplate> {
+        get_mut_arcmutex!(self.target).get_chat_template()
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        get_mut_arcmutex!(self.target).get_input_processor_config()
+    }
+    fn get_processor(&self) -> Arc<dyn Processor> {
+        get_mut_arcmutex!(self.target).get_processor()
+    }
+}
+
+impl IsqPipelineMixin for NgramSpeculativePipeline {
+    fn re_isq_model(
+        &mut self,
+        dtype: IsqType,
+        imatrix: Option<&ImatrixData>,
+    ) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).re_isq_model(dtype, imatrix)
+    }
+}
+
+impl CacheManagerMixin for NgramSpeculativePipeline {
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
+        get_mut_arcmutex!(self.target).clone_in_cache(seqs, modify_draft_cache)
+    }
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
+        get_mut_arcmutex!(self.target).clone_out_cache(seqs, modify_draft_cache)
+    }
+    fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
+        get_mut_arcmutex!(self.target).set_none_cache(reset_non_granular, modify_draft_cache)
+    }
+    fn kv_cache_dtype(&self) -> KvCacheDtype {
+        get_mut_arcmutex!(self.target).kv_cache_dtype()
+    }
+    fn set_kv_cache_dtype(&self, dtype: KvCacheDtype) {
+        get_mut_arcmutex!(self.target).set_kv_cache_dtype(dtype)
+    }
+    fn cache(&self) -> &Cache {
+        unreachable!()
+    }
+}
+
+impl AdapterActivationMixin for NgramSpeculativePipeline {
+    /// Returns the number of activated adapters.
+    fn activate_adapters_weighted(
+        &mut self,
+        adapters: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).activate_adapters_weighted(adapters)
+    }
+    fn load_adapter(&mut self, name: String, path_or_hf_id: String) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).load_adapter(name, path_or_hf_id)
+    }
+    fn adapters(&self) -> AdaptersResponse {
+        get_mut_arcmutex!(self.target).adapters()
+    }
+}
+
+impl MetadataMixin for NgramSpeculativePipeline {
+    fn device(&self) -> Device {
+        get_mut_arcmutex!(self.target).device()
+    }
+    fn tokenizer(&self) -> Arc<Tokenizer> {
+        get_mut_arcmutex!(self.target).tokenizer()
+    }
+    fn name(&self) -> String {
+        format!(
+            "N-gram speculative: tgt = `{}`, gamma = `{}`, max_ngram = `{}`",
+            get_mut_arcmutex!(self.target).name(),
+            self.gamma,
+            self.max_ngram,
+        )
+    }
+    fn reset_non_granular_state(&self) {
+        get_mut_arcmutex!(self.target).reset_non_granular_state();
+    }
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for NgramSpeculativePipeline {
+    fn forward_inputs(&self, _inputs: Box<dyn Any>) -> Result<Tensor> {
+        unreachable!()
+    }
+    fn forward_embeddings(&self, toks: &[u32]) -> Result<Tensor> {
+        get_mut_arcmutex!(self.target).forward_embeddings(toks)
+    }
+    fn forward_raw(
+        &self,
+        toks: &[u32],
+        return_hidden_states: bool,
+        all_positions: bool,
+    ) -> Result<(Tensor, Option<Tensor>)> {
+        get_mut_arcmutex!(self.target).forward_raw(toks, return_hidden_states, all_positions)
+    }
+    async fn sample(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _logits: Vec<Tensor>,
+        _prefix_cacher: &mut PrefixCacheManager,
+        _disable_eos_stop: bool,
+        _rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        unreachable!()
+    }
+    async fn step(
+        &mut self,
+        input_seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        nan_checks: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        backend_metadata: CacheBackendMetadata<'_>,
+    ) -> Result<()> {
+        match backend_metadata {
+            CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
+                let adapter_inst = match pre_op {
+                    CacheInstruction::In(adapter_inst) => {
+                        self.clone_in_cache(input_seqs, false);
+                        adapter_inst
+                    }
+                    CacheInstruction::Nothing(adapter_inst) => adapter_inst,
+                    CacheInstruction::Reset {
+                        reset_non_granular,
+                        adapter_inst,
+                    } => {
+                        self.set_none_cache(reset_non_granular, false);
+                        adapter_inst
+                    }
+                    _ => unreachable!("Unreachable PRE cache op."),
+                };
+                if let AdapterInstruction::Activate(adapters) = adapter_inst {
+                    self.activate_adapters(adapters).map_err(|e| {
+                        candle_core::Error::msg(
+                            <anyhow::Error as AsRef<dyn std::error::Error>>::as_ref(&e),
+                        )
+                    })?;
+                }
+
+                if input_seqs.len() != 1 {
+                    candle_core::bail!(
+                        "N-gram speculative decoding runs one sequence at a time, got {}.",
+                        input_seqs.len()
+                    );
+                }
+                let seq = &mut input_seqs[0];
+
+                // ======================= Propose tokens from the sequence itself ============================
+                // The proposals are not checked against a grammar, so do not speculate when constrained.
+                let proposals = match seq.recognizer {
+                    SequenceRecognizer::None => {
+                        propose_ngram_continuation(seq.get_toks(), self.max_ngram, self.gamma)
+                            .to_vec()
+                    }
+                    _ => Vec::new(),
+                };
+
+                let mut prefill_tokens = if is_prompt {
+                    seq.get_toks().to_vec()
+                } else {
+                    vec![*seq.get_toks().last().unwrap()]
+                };
+                prefill_tokens.extend(&proposals);
+                seq.set_prefill_toks(prefill_tokens);
+
+                // ======================= Run the model with all proposed tokens ============================
+                let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
+                    .as_ref()
+                    .map(|(k, _)| k.dims()[2])
+                    .unwrap_or(0);
+
+                let is_xlora = self.metadata.is_xlora;
+                let device = self.device();
+                let inputs = self
+                    .get_processor()
+                    .inputs_processor()
+                    .process_inputs(
+                        self.tokenizer(),
+                        &mut [seq],
+                        true, // use the "prefill" tokens
+                        is_xlora,
+                        &device,
+                        false,
+                        // Logits for each proposed token and the one after the last
+                        Some((proposals.len() + 1, initial_cache_len)),
+                        self.get_input_processor_config(),
+                        None,
+                        None,
+                    )
+                    .nth(0)
+                    .unwrap()
+                    .unwrap();
+
+                let logits = get_mut_arcmutex!(self.target).forward_inputs(inputs.inputs)?;
+                if nan_checks {
+                    check_finite_logits(&logits, *seq.id(), is_prompt)?;
+                }
+
+                seq.reset_prefill_toks();
+
+                // ======================= Verify the proposals ============================
+                let samples = sample_target_sequence_speculative(
+                    logits,
+                    seq,
+                    seq.return_logprobs(),
+                    rng.clone(),
+                    proposals.len() + 1,
+                )
+                .await?;
+
+                let mut accepted_tokens = Vec::new();
+                for (i, target_sample) in samples.into_iter().enumerate() {
+                    let tok = target_sample.sample.token;
+                    accepted_tokens.push(target_sample.sample);
+                    if proposals.get(i) != Some(&tok) {
+                        break;
+                    }
+                }
+
+                // ======================= Narrow the cache to account for rejections ============================
+                // The cache holds the last token and every proposal, but the last accepted token is not in it yet.
+                let n_not_accepted = proposals.len() + 1 - accepted_tokens.len();
+                for (k, v) in get_mut_arcmutex!(self.target)
+                    .cache()
+                    .lock()
+                    .iter_mut()
+                    .flatten()
+                {
+                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                }
+                if is_xlora {
+                    for (k, v) in get_mut_arcmutex!(self.target)
+                        .cache()
+                        .xlora_lock()
+                        .iter_mut()
+                        .flatten()
+                    {
+                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                    }
+                }
+
+                let eos_owned = self.metadata.eos_tok.clone();
+                let eos_tok = if disable_eos_stop {
+                    None
+                } else {
+                    Some(&eos_owned[..])
+                };
+                // Add the tokens to the seq, stopping once it is done
+                for accepted in accepted_tokens {
+                    // Do not use the prefix cacher
+                    finish_or_add_toks_to_seq(
+                        self,
+                        prefix_cacher,
+                        seq,
+                        accepted.clone(),
+                        eos_tok,
+                        false,
+                    )
+                    .await?;
+                    if matches!(seq.getstate(), SequenceState::Done(_)) {
+                        break;
+                    }
+                }
+
+                match post_op {
+                    CacheInstruction::Out => {
+                        self.clone_out_cache(input_seqs, false);
+                    }
+                    CacheInstruction::Nothing(_) => (),
+                    CacheInstruction::Reset {
+                        reset_non_granular,
+                        adapter_inst: _,
+                    } => self.set_none_cache(reset_non_granular, false),
+                    _ => unreachable!("Unreachable pre cache op."),
+                }
+
+                Ok(())
+            }
+            CacheBackendMetadata::PagedAttention {
+                metadata: _,
+                blocks_to_copy: _,
+                blocks_to_swap_in: _,
+                blocks_to_swap_out: _,
+            } => unreachable!(),
+        }
+    }
+    fn category(&self) -> ModelCategory {
+        self.category
+    }
+}
+
+impl AnyMoePipelineMixin for NgramSpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use super::propose_ngram_continuation;
+
+    #[test]
+    fn longest_recent_ngram_is_proposed() {
+        // `2 3` occurs twice, and the suffix `1 2 3` only once before.
+        let toks = [1, 2, 3, 4, 5, 2, 3, 6, 1, 2, 3];
+        assert_eq!(propose_ngram_continuation(&toks, 3, 2), &[4, 5]);
+        // With shorter n-grams, the most recent occurrence of `2 3` wins.
+        assert_eq!(propose_ngram_continuation(&toks, 2, 2), &[6, 1]);
+        // Proposals stop at the end of the sequence.
+        assert_eq!(propose_ngram_continuation(&toks, 1, 8), &[6, 1, 2, 3]);
+        assert!(propose_ngram_continuation(&[1, 2, 3], 3, 4).is_empty());
+    }
+
+    #[test]
+    fn repetitive_text_has_high_acceptance() {
+        let text = [10u32, 11, 12, 13, 14, 15, 16]
+            .iter()
+            .cycle()
+            .copied()
+            .take(200)
+            .collect::<Vec<_>>();
+        let gamma = 4;
+
+        // Greedily decode `text`, as a target model which repeats it would, counting accepted proposals.
+        let mut toks = text[..10].to_vec();
+        let (mut proposed, mut accepted, mut steps) = (0, 0, 0);
+        while toks.len() < text.len() {
+            let proposals = propose_ngram_continuation(&toks, 3, gamma);
+            let n_accepted = proposals
+                .iter()
+                .zip(&text[toks.len()..])
+                .take_while(|(proposal, tok)| proposal == tok)
+                .count();
+            proposed += proposals.len();
+            accepted += n_accepted;
+            steps += 1;
+            let n_new = (n_accepted + 1).min(text.len() - toks.len());
+            toks.extend(&text[toks.len()..toks.len() + n_new]);
+        }
+
+        assert_eq!(toks, text);
+        assert!(accepted as f32 / proposed as f32 > 0.95);
+        // Every step which does not end the text emits `gamma + 1` tokens.
+        assert!(steps <= (text.len() - 10).div_ceil(gamma + 1));
+    }
+}
@@ -1,16 +1,16 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource, XLoraPaths,
+    adapters_from_paths, AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin,
+    IsqPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use super::{
-    AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, IsqPipelineMixin,
-    MetadataMixin, ModelCategory, PreProcessingMixin,
+    get_lora_adapter_paths, get_model_paths, get_xlora_paths,
+    text_models_inputs_processor::ModelInputs, AdapterKind, CacheManager, GeneralMetadata, Loader,
+    ModelKind, ModelPaths, NormalModel, NormalModelLoader, TokenSource, XLoraPaths,
 };
 use super::{
-    Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType,
-    Phi2Loader, Phi3Loader, Qwen2Loader, Starcoder2Loader,
+    CohereLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader,
+    NormalLoaderType, Phi2Loader, Phi3Loader, Qwen2Loader, Qwen2MoeLoader, Starcoder2Loader,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
@@ -19,13 +19,17 @@ use crate::lora::Ordering;
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
 use crate::pipeline::sampling::sample_and_add_toks;
-use crate::pipeline::{get_chat_template, Cache};
+use crate::pipeline::{get_chat_template, get_model_type, Cache};
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::response::AdaptersResponse;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::tokenizer::get_tokenizer;
-use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
+use crate::utils::{
+    tokens::get_token,
+    varbuilder_utils::{from_mmaped_safetensors, load_preload_adapters},
+};
 use crate::xlora_models::NonGranularState;
 use crate::{
     api_dir_list, api_get_file, get_mut_arcmutex, get_paths, lora_model_loader,
@@ -35,10 +39,11 @@ use crate::{
 use anyhow::Result;
 use candle_core::{Device, Tensor, Var};
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{ImatrixData, IsqType};
 use rand_isaac::Isaac64Rng;
 use regex_automata::meta::Regex;
 use std::any::Any;
+use std::collections::HashMap;
 use std::fs;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
@@ -57,6 +62,7 @@ pub struct NormalPipeline {
     model_id: String,
     metadata: Arc<GeneralMetadata>,
     topology: Option<Topology>,
+    adapters: AdaptersResponse,
 }
 
 /// A loader for a "normal" (non-quantized) model.
@@ -171,6 +177,8 @@ impl NormalLoaderBuilder {
             NormalLoaderType::Qwen2 => Box::new(Qwen2Loader),
             NormalLoaderType::Gemma2 => Box::new(Gemma2Loader),
             NormalLoaderType::Starcoder2 => Box::new(Starcoder2Loader),
+            NormalLoaderType::Qwen2Moe => Box::new(Qwen2MoeLoader),
+            NormalLoaderType::Cohere => Box::new(CohereLoader),
         };
         Ok(Box::new(NormalLoader {
             inner: loader,
@@ -232,6 +240,12 @@ impl Loader for NormalLoader {
         mut paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
         let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let mapper = mapper.resolve_auto(
+            self.inner.get_total_device_mapping_num_layers(&config)?,
+            paths.get_weight_filenames(),
+            in_situ_quant,
+            device,
+        )?;
         // Otherwise, the device mapper will print it
         if mapper.is_dummy() {
             info!(
@@ -327,10 +341,20 @@ impl Loader for NormalLoader {
         let gen_conf: Option<GenerationConfig> = paths
             .get_gen_conf_filename()
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
-        let chat_template = get_chat_template(paths, &self.chat_template, None);
+        let chat_template = get_chat_template(
+            paths,
+            &self.chat_template,
+            None,
+            get_model_type(&config).as_deref(),
+        )?;
 
         if in_situ_quant.is_some() || self.config.topology.is_some() {
-            model.quantize(in_situ_quant, device.clone(), self.config.topology.as_ref())?;
+            model.quantize(
+                in_situ_quant,
+                device.clone(),
+                self.config.topology.as_ref(),
+                None,
+            )?;
         }
 
         let paged_attn_config = if matches!(self.kind, ModelKind::Adapter { .. }) {
@@ -385,8 +409,10 @@ impl Loader for NormalLoader {
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                max_image_edge: None,
             }),
             topology: self.config.topology.clone(),
+            adapters: adapters_from_paths(paths.as_ref()),
         })))
     }
 
@@ -412,10 +438,10 @@ impl PreProcessingMixin for NormalPipeline {
 }
 
 impl IsqPipelineMixin for NormalPipeline {
-    fn re_isq_model(&mut self, dtype: IsqType) -> Result<()> {
+    fn re_isq_model(&mut self, dtype: IsqType, imatrix: Option<&ImatrixData>) -> Result<()> {
         let device = self.device().clone();
         self.model
-            .quantize(Some(dtype), device, self.topology.as_ref())
+            .quantize(Some(dtype), device, self.topology.as_ref(), imatrix)
             .map_err(anyhow::Error::msg)
     }
 }
@@ -439,10 +465,45 @@ impl CacheManagerMixin for NormalPipeline {
 }
 
 impl AdapterActivationMixin for NormalPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
-        self.model
-            .activate_adapters(adapter_names)
-            .map_err(anyhow::Error::msg)
+    fn activate_adapters_weighted(
+        &mut self,
+        adapter_names: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
+        let n = self
+            .model
+            .activate_adapters(adapter_names.clone())
+            .map_err(anyhow::Error::msg)?;
+        self.adapters.active = adapter_names.into_iter().map(|(name, _)| name).collect();
+        Ok(n)
+    }
+    fn load_adapter(&mut self, name: String, path_or_hf_id: String) -> anyhow::Result<usize> {
+        if self.adapters.available.contains(&name) {
+            anyhow::bail!("An adapter named `{name}` is already loaded.");
+        }
+        let (path, config) = get_lora_adapter_paths(&path_or_hf_id, false)?;
+        let vbs = load_preload_adapters(
+            &Some(HashMap::from([(name.clone(), (path, config))])),
+            self.metadata.activation_dtype,
+            self.model.device(),
+            false,
+        )?
+        .expect("Adapter paths were given.");
+        let (vb, config) = &vbs[&name];
+        let n = self
+            .model
+            .load_adapter(&name, vb, config)
+            .map_err(anyhow::Error::msg)?;
+        if n == 0 {
+            anyhow::bail!(
+                "Adapter `{name}` was not loaded into any layer, this model has no LoRA layers."
+            );
+        }
+        info!("Loaded adapter `{name}` from `{path_or_hf_id}` into {n} layers.");
+        self.adapters.available.push(name);
+        Ok(n)
+    }
+    fn adapters(&self) -> AdaptersResponse {
+        self.adapters.clone()
     }
 }
 
@@ -480,6 +541,8 @@ impl Pipeline for NormalPipeline {
             context_lens,
             position_ids,
             mut paged_attn_meta,
+            early_exit_layer,
+            xlora_global_scaling,
         } = *inputs.downcast().expect("Downcast failed.");
         match self.model.is_xlora() {
             false => self.model.forward(
@@ -494,6 +557,7 @@ impl Pipeline for NormalPipeline {
                         paged_attn_meta.as_mut().unwrap(),
                     )
                 }),
+                early_exit_layer,
             ),
             true => self.model.xlora_forward(
                 &input_ids,
@@ -506,9 +570,72 @@ impl Pipeline for NormalPipeline {
                 &self.non_granular_state,
                 context_lens,
                 position_ids,
+                xlora_global_scaling,
             ),
         }
     }
+    fn forward_embeddings(&self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        if self.model.is_xlora() {
+            candle_core::bail!("Embeddings are not supported for X-LoRA models.");
+        }
+        let device = self.device();
+        let input_ids = Tensor::new(toks, &device)?.unsqueeze(0)?;
+        let positions = (0..toks.len() as i64).collect::<Vec<_>>();
+        let start_offsets_kernel = Tensor::new(positions, &device)?.unsqueeze(0)?;
+        // Run with an empty cache, then restore the one of the running sequences: the engine does
+        // not clone it in again if the same sequences are scheduled next.
+        let n_layers = self.cache().lock().len();
+        let running_cache = std::mem::replace(&mut *self.cache().lock(), vec![None; n_layers]);
+        let hidden = self
+            .model
+            .forward_embeddings(&input_ids, &[0], start_offsets_kernel);
+        *self.cache().lock() = running_cache;
+        hidden?.squeeze(0)
+    }
+    fn forward_raw(
+        &self,
+        toks: &[u32],
+        return_hidden_states: bool,
+        all_positions: bool,
+    ) -> Result<(Tensor, Option<Tensor>), candle_core::Error> {
+        if self.model.is_xlora() {
+            candle_core::bail!("Raw forward passes are not supported for X-LoRA models.");
+        }
+        let device = self.device();
+        let input_ids = Tensor::new(toks, &device)?.unsqueeze(0)?;
+        let positions = (0..toks.len() as i64).collect::<Vec<_>>();
+        let start_offsets_kernel = Tensor::new(positions, &device)?.unsqueeze(0)?;
+        let (start, len) = if all_positions {
+            (0, toks.len())
+        } else {
+            (toks.len() - 1, 1)
+        };
+        // As for the embeddings, run with an empty cache and restore the one of the running
+        // sequences afterwards.
+        let n_layers = self.cache().lock().len();
+        let running_cache = std::mem::replace(&mut *self.cache().lock(), vec![None; n_layers]);
+        let logits = self.model.forward(
+            &input_ids,
+            &[0],
+            start_offsets_kernel.clone(),
+            vec![(start, len)],
+            vec![toks.len()],
+            None,
+            None,
+        );
+        let hidden = if return_hidden_states && logits.is_ok() {
+            *self.cache().lock() = vec![None; n_layers];
+            Some(
+                self.model
+                    .forward_embeddings(&input_ids, &[0], start_offsets_kernel)
+                    .and_then(|hidden| hidden.narrow(1, start, len)?.squeeze(0)),
+            )
+        } else {
+            None
+        };
+        *self.cache().lock() = running_cache;
+        Ok((logits?.squeeze(0)?, hidden.transpose()?))
+    }
     async fn sample(
         &self,
         seqs: &mut [&mut Sequence],
@@ -4,7 +4,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use either::Either;
 use hf_hub::{
     api::sync::{ApiBuilder, ApiRepo},
@@ -17,7 +17,9 @@ use tracing::{info, warn};
 use crate::{
     api_dir_list, api_get_file,
     lora::LoraConfig,
-    pipeline::chat_template::{ChatTemplate, ChatTemplateValue},
+    pipeline::chat_template::{
+        builtin_chat_template, ChatTemplate, ChatTemplateSource, ChatTemplateValue,
+    },
     utils::tokens::get_token,
     xlora_models::XLoraConfig,
     ModelPaths, Ordering, TokenSource,
@@ -255,6 +257,36 @@ pub fn get_xlora_paths(
     })
 }
 
+/// Get the weights and config of a LoRA adapter to load into a running model, from a local
+/// directory or a Hugging Face model ID holding `adapter_model.safetensors` and `adapter_config.json`.
+pub(crate) fn get_lora_adapter_paths(
+    path_or_hf_id: &str,
+    silent: bool,
+) -> Result<(PathBuf, LoraConfig)> {
+    let get_file = |file: &str| -> Result<PathBuf> {
+        let dir = Path::new(path_or_hf_id);
+        if dir.exists() {
+            let path = dir.join(file);
+            if !path.exists() {
+                anyhow::bail!("File \"{file}\" not found in adapter directory `{path_or_hf_id}`.");
+            }
+            info!("Loading `{file}` locally at `{}`", path.display());
+            Ok(path)
+        } else {
+            let api = ApiBuilder::new()
+                .with_progress(!silent)
+                .with_token(get_token(&TokenSource::CacheToken)?)
+                .build()?;
+            api.model(path_or_hf_id.to_string())
+                .get(file)
+                .with_context(|| format!("Could not get \"{file}\" of adapter `{path_or_hf_id}`."))
+        }
+    };
+    let config = fs::read_to_string(get_file("adapter_config.json")?)?;
+    let config: LoraConfig = serde_json::from_str(&config)?;
+    Ok((get_file("adapter_model.safetensors")?, config))
+}
+
 pub fn get_model_paths(
     revision: String,
     token_source: &TokenSource,
@@ -327,127 +359,115 @@ pub fn get_model_paths(
     }
 }
 
-/// Find and parse the appropriate [`ChatTemplate`], and ensure is has a valid [`ChatTemplate.chat_template`].
-/// If the provided `tokenizer_config.json` from [`ModelPaths.get_template_filename`] does not
-/// have a `chat_template`, use the provided one.
+/// Find and parse the appropriate [`ChatTemplate`]. The chat template is taken from the first of these
+/// sources which provides one (see [`ChatTemplateSource`]), and the source used is logged:
+///
+/// 1) `chat_template_specified`, the user specified chat template. This may be a literal or a `.json` file
+///    containing a `chat_template` and optionally the bos/eos/unk tokens.
+/// 2) The `tokenizer_config.json` from [`ModelPaths.get_template_filename`] (or the `processor_config.json`
+///    for vision models).
+/// 3) `gguf_chat_template`, the GGUF chat template content. *The user must add the bos/unk/eos tokens
+///    manually if there is no `tokenizer_config.json`.*
+/// 4) The built-in default for `model_type`, if there is one.
 ///
-/// - Uses `chat_template_fallback` if `paths` does not contain a chat template file. This may be a literal or .json file.
-/// - `chat_template_ovrd` (GGUF chat template content) causes the usage of that string chat template initially.
-///   Falls back to `chat_template_file` if it is invalid. *The user must add the bos/unk/eos tokens manually if this
-///   is used.*
+/// If none of these provide a chat template, the model will only accept completion requests.
 #[allow(clippy::borrowed_box)]
 pub(crate) fn get_chat_template(
     paths: &Box<dyn ModelPaths>,
-    chat_template_fallback: &Option<String>,
-    chat_template_ovrd: Option<String>,
-) -> ChatTemplate {
-    // Get template content, this may be overridden.
-    let template_content = if let Some(template_filename) = paths.get_template_filename() {
-        if template_filename
-            .extension()
-            .expect("Template filename must be a file")
-            .to_string_lossy()
-            != "json"
-        {
-            panic!("Template filename {template_filename:?} must end with `.json`.");
-        }
-        Some(fs::read_to_string(template_filename).expect("Loading chat template failed."))
-    } else if chat_template_fallback
-        .as_ref()
-        .is_some_and(|f| f.ends_with(".json"))
-    {
-        // User specified a file
-        let template_filename = chat_template_fallback
-            .as_ref()
-            .expect("A tokenizer config or chat template file path must be specified.");
-        Some(fs::read_to_string(template_filename).expect("Loading chat template failed."))
-    } else if chat_template_ovrd.is_some() {
-        None
-    } else {
-        panic!("Expected chat template file to end with .json, or you can specify a tokenizer model ID to load the chat template there. If you are running a GGUF model, it probably does not contain a chat template.");
-    };
-
-    let mut template: ChatTemplate = match chat_template_ovrd {
-        Some(chat_template) => {
-            // In this case the override chat template is being used. The user must add the bos/eos/unk toks themselves.
-            info!("Using literal chat template.");
-            let mut template = ChatTemplate::default();
-            template.chat_template = Some(ChatTemplateValue(Either::Left(chat_template)));
-            template
+    chat_template_specified: &Option<String>,
+    gguf_chat_template: Option<String>,
+    model_type: Option<&str>,
+) -> Result<ChatTemplate> {
+    let mut template: ChatTemplate = match paths.get_template_filename() {
+        Some(template_filename) => {
+            if template_filename.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                anyhow::bail!("Template filename {template_filename:?} must end with `.json`.");
+            }
+            serde_json::from_str(&fs::read_to_string(template_filename).with_context(|| {
+                format!("Loading chat template from {template_filename:?} failed.")
+            })?)?
         }
-        None => serde_json::from_str(&template_content.as_ref().unwrap().clone()).unwrap(),
+        None => ChatTemplate::default(),
     };
 
     let processor_conf: Option<crate::vision_models::processor_config::ProcessorConfig> = paths
         .get_processor_config()
         .as_ref()
-        .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
-    if let Some(processor_conf) = processor_conf {
-        if processor_conf.chat_template.is_some() {
-            template.chat_template = processor_conf
-                .chat_template
-                .map(|x| ChatTemplateValue(Either::Left(x)));
-        }
+        .map(|f| serde_json::from_str(&fs::read_to_string(f)?).map_err(anyhow::Error::from))
+        .transpose()?;
+    if let Some(chat_template) = processor_conf.and_then(|conf| conf.chat_template) {
+        template.chat_template = Some(ChatTemplateValue(Either::Left(chat_template)));
     }
 
-    #[derive(Debug, serde::Deserialize)]
-    struct SpecifiedTemplate {
-        chat_template: String,
-        bos_token: Option<String>,
-        eos_token: Option<String>,
-        unk_token: Option<String>,
-    }
-
-    if template.chat_template.is_some() {
-        return template;
+    let specified = match chat_template_specified {
+        Some(t) if t.ends_with(".json") => {
+            info!("Loading specified chat template file at `{t}`.");
+            let specified: ChatTemplate =
+                serde_json::from_str(&fs::read_to_string(t).with_context(|| {
+                    format!("Loading specified chat template file `{t}` failed.")
+                })?)?;
+            Some(specified)
+        }
+        Some(t) => {
+            let mut specified = ChatTemplate::default();
+            specified.chat_template = Some(ChatTemplateValue(Either::Left(t.clone())));
+            Some(specified)
+        }
+        None => None,
     };
 
-    match &template.chat_template {
-        Some(_) => template,
-        None => {
-            info!("`tokenizer_config.json` does not contain a chat template, attempting to use specified JINJA chat template.");
-            let mut deser: HashMap<String, Value> =
-                serde_json::from_str(&template_content.unwrap()).unwrap();
-
-            match chat_template_fallback.clone() {
-                Some(t) => {
-                    info!("Loading specified loading chat template file at `{t}`.");
-                    let templ: SpecifiedTemplate =
-                        serde_json::from_str(&fs::read_to_string(t.clone()).unwrap()).unwrap();
-                    deser.insert(
-                        "chat_template".to_string(),
-                        Value::String(templ.chat_template),
-                    );
-                    if templ.bos_token.is_some() {
-                        deser.insert(
-                            "bos_token".to_string(),
-                            Value::String(templ.bos_token.unwrap()),
-                        );
-                    }
-                    if templ.eos_token.is_some() {
-                        deser.insert(
-                            "eos_token".to_string(),
-                            Value::String(templ.eos_token.unwrap()),
-                        );
-                    }
-                    if templ.unk_token.is_some() {
-                        deser.insert(
-                            "unk_token".to_string(),
-                            Value::String(templ.unk_token.unwrap()),
-                        );
-                    }
-                }
-                None => {
-                    info!("No specified chat template. No chat template will be used. Only prompts will be accepted, not messages.");
-                    deser.insert("chat_template".to_string(), Value::Null);
-                }
-            }
+    match resolve_chat_template(&mut template, specified, gguf_chat_template, model_type) {
+        Some(source) => info!("Using chat template from {source}."),
+        None => warn!(
+            "No chat template was specified or found in `tokenizer_config.json`, the GGUF metadata or the built-in defaults (model type: {}). Only completion requests will be accepted, not chat messages. Specify a chat template to use chat requests.",
+            model_type.unwrap_or("unknown")
+        ),
+    }
+    Ok(template)
+}
 
-            let ser = serde_json::to_string_pretty(&deser)
-                .expect("Serialization of modified chat template failed.");
-            serde_json::from_str(&ser).unwrap()
+/// Select the chat template for `template` (the parsed `tokenizer_config.json`) from the available sources,
+/// in the order of [`ChatTemplateSource`]. Returns the source used, if any.
+fn resolve_chat_template(
+    template: &mut ChatTemplate,
+    specified: Option<ChatTemplate>,
+    gguf_chat_template: Option<String>,
+    model_type: Option<&str>,
+) -> Option<ChatTemplateSource> {
+    if let Some(specified) = specified.filter(|t| t.chat_template.is_some()) {
+        template.chat_template = specified.chat_template;
+        if specified.bos_token.is_some() {
+            template.bos_token = specified.bos_token;
+        }
+        if specified.eos_token.is_some() {
+            template.eos_token = specified.eos_token;
         }
+        if specified.unk_token.is_some() {
+            template.unk_token = specified.unk_token;
+        }
+        return Some(ChatTemplateSource::Specified);
+    }
+    if template.chat_template.is_some() {
+        return Some(ChatTemplateSource::TokenizerConfig);
+    }
+    if let Some(gguf_chat_template) = gguf_chat_template {
+        template.chat_template = Some(ChatTemplateValue(Either::Left(gguf_chat_template)));
+        return Some(ChatTemplateSource::Gguf);
+    }
+    if let Some(builtin) = model_type.and_then(builtin_chat_template) {
+        template.chat_template = Some(ChatTemplateValue(Either::Left(builtin.to_string())));
+        return Some(ChatTemplateSource::BuiltIn);
     }
+    None
+}
+
+/// The `model_type` of a model's `config.json`, used to select a built-in chat template.
+pub(crate) fn get_model_type(config: &str) -> Option<String> {
+    serde_json::from_str::<Value>(config)
+        .ok()?
+        .get("model_type")?
+        .as_str()
+        .map(ToString::to_string)
 }
 
 mod tests {
@@ -506,4 +526,131 @@ mod tests {
         }
         Ok(())
     }
+
+    #[cfg(test)]
+    fn literal_template(template: &str) -> crate::pipeline::chat_template::ChatTemplate {
+        use crate::pipeline::chat_template::{ChatTemplate, ChatTemplateValue};
+        use either::Either;
+
+        let mut res = ChatTemplate::default();
+        res.chat_template = Some(ChatTemplateValue(Either::Left(template.to_string())));
+        res
+    }
+
+    #[cfg(test)]
+    fn template_content(template: &crate::pipeline::chat_template::ChatTemplate) -> Option<&str> {
+        template
+            .chat_template
+            .as_ref()
+            .map(|t| t.0.as_ref().left().unwrap().as_str())
+    }
+
+    #[test]
+    fn chat_template_specified_first() {
+        use super::resolve_chat_template;
+        use crate::pipeline::chat_template::ChatTemplateSource;
+
+        let mut template = literal_template("tokenizer_config");
+        let source = resolve_chat_template(
+            &mut template,
+            Some(literal_template("specified")),
+            Some("gguf".to_string()),
+            Some("llama"),
+        );
+        assert_eq!(source, Some(ChatTemplateSource::Specified));
+        assert_eq!(template_content(&template), Some("specified"));
+    }
+
+    #[test]
+    fn chat_template_tokenizer_config_before_gguf() {
+        use super::resolve_chat_template;
+        use crate::pipeline::chat_template::ChatTemplateSource;
+
+        let mut template = literal_template("tokenizer_config");
+        let source =
+            resolve_chat_template(&mut template, None, Some("gguf".to_string()), Some("llama"));
+        assert_eq!(source, Some(ChatTemplateSource::TokenizerConfig));
+        assert_eq!(template_content(&template), Some("tokenizer_config"));
+    }
+
+    #[test]
+    fn chat_template_gguf_before_builtin() {
+        use super::resolve_chat_template;
+        use crate::pipeline::chat_template::{ChatTemplate, ChatTemplateSource};
+
+        let mut template = ChatTemplate::default();
+        let source =
+            resolve_chat_template(&mut template, None, Some("gguf".to_string()), Some("llama"));
+        assert_eq!(source, Some(ChatTemplateSource::Gguf));
+        assert_eq!(template_content(&template), Some("gguf"));
+    }
+
+    #[test]
+    fn chat_template_builtin() {
+        use super::resolve_chat_template;
+        use crate::pipeline::chat_template::{
+            builtin_chat_template, ChatTemplate, ChatTemplateSource,
+        };
+
+        let mut template = ChatTemplate::default();
+        let source = resolve_chat_template(&mut template, None, None, Some("phi3"));
+        assert_eq!(source, Some(ChatTemplateSource::BuiltIn));
+        assert_eq!(template_content(&template), builtin_chat_template("phi3"));
+    }
+
+    #[test]
+    fn chat_template_none_found() {
+        use super::resolve_chat_template;
+        use crate::pipeline::chat_template::ChatTemplate;
+
+        let mut template = ChatTemplate::default();
+        assert_eq!(
+            resolve_chat_template(&mut template, None, None, Some("starcoder2")),
+            None
+        );
+        assert!(!template.has_chat_template());
+        assert_eq!(resolve_chat_template(&mut template, None, None, None), None);
+    }
+
+    #[test]
+    fn chat_template_from_tokenizer_config() {
+        use super::resolve_chat_template;
+        use crate::pipeline::chat_template::{ChatTemplate, ChatTemplateSource};
+
+        let tokenizer_config = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/tokenizer_config.json"
+        ))
+        .unwrap();
+        let parse = || serde_json::from_str::<ChatTemplate>(&tokenizer_config).unwrap();
+
+        // The embedded list of templates gives the default template, or the tool use template for
+        // requests with tools.
+        let mut template = parse();
+        let source = resolve_chat_template(&mut template, None, None, Some("llama"));
+        assert_eq!(source, Some(ChatTemplateSource::TokenizerConfig));
+        let value = template.chat_template.as_ref().unwrap();
+        assert_eq!(
+            value.select(false).unwrap(),
+            "{{ bos_token }}{% for message in messages %}{{ '[' + message['role'] + '] ' + message['content'] }}{% endfor %}"
+        );
+        assert!(value
+            .select(true)
+            .unwrap()
+            .starts_with("{{ bos_token }}[TOOLS]"));
+        assert_eq!(template.bos_tok().as_deref(), Some("<s>"));
+        assert_eq!(template.eos_tok().as_deref(), Some("</s>"));
+
+        // An explicit chat template wins, but the tokens of the `tokenizer_config.json` are kept.
+        let mut template = parse();
+        let source = resolve_chat_template(
+            &mut template,
+            Some(literal_template("specified")),
+            None,
+            Some("llama"),
+        );
+        assert_eq!(source, Some(ChatTemplateSource::Specified));
+        assert_eq!(template_content(&template), Some("specified"));
+        assert_eq!(template.eos_tok().as_deref(), Some("</s>"));
+    }
 }
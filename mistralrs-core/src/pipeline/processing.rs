@@ -37,19 +37,29 @@ pub trait Processor {
         add_generation_prompt: bool,
         tools: Vec<Tool>,
     ) -> Result<Vec<u32>> {
-        let prompt = apply_chat_template(
-            pipeline,
-            messages,
-            add_generation_prompt,
-            self.template_action(),
-            tools,
-        )?;
+        let prompt = self.render(pipeline, messages, add_generation_prompt, tools)?;
         let encoding = pipeline
             .tokenizer()
             .encode(prompt, true)
             .map_err(|e| anyhow::Error::msg(e.to_string()))?;
         Ok(encoding.get_ids().to_vec())
     }
+    /// The prompt text of `messages` with the chat template of the pipeline, before tokenization.
+    fn render(
+        &self,
+        pipeline: &dyn Pipeline,
+        messages: Vec<IndexMap<String, MessageContent>>,
+        add_generation_prompt: bool,
+        tools: Vec<Tool>,
+    ) -> Result<String> {
+        apply_chat_template(
+            pipeline,
+            messages,
+            add_generation_prompt,
+            self.template_action(),
+            tools,
+        )
+    }
     fn inputs_processor(&self) -> Arc<dyn InputsProcessor>;
     fn get_special_tokens(&self) -> &[&'static str];
     fn template_action(&self) -> MessagesAction;
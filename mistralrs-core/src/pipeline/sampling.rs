@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use candle_core::{DType, Device, Result, Tensor};
 use rand_isaac::Isaac64Rng;
@@ -7,7 +10,7 @@ use crate::{
     get_bias_if_not_allowed,
     prefix_cacher::PrefixCacheManager,
     sampler::Logprobs,
-    sequence::{Sequence, SequenceRecognizer},
+    sequence::{Sequence, SequenceRecognizer, StopReason},
 };
 
 use super::Pipeline;
@@ -26,8 +29,37 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         this.get_metadata().tok_trie.decode(&[logprobs.token]),
         &is_done,
     );
+    if is_done.is_none() {
+        seq.roll_context();
+    }
     // Handle streaming requests
     if seq.get_mut_group().is_streaming {
+        // An echoed prompt is streamed before the first generated chunk, in chunks of its own.
+        let echo_chunks =
+            crate::handle_seq_error_ok!(seq.take_echo_chunks(&this.tokenizer()), seq.responder());
+        for chunk in echo_chunks {
+            let sent = seq
+                .responder()
+                .send(crate::Response::CompletionChunk(
+                    crate::CompletionChunkResponse {
+                        id: seq.id().to_string(),
+                        choices: vec![chunk],
+                        created: seq.timestamp(),
+                        model: this.name(),
+                        system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                        object: "text_completion".to_string(),
+                    },
+                ))
+                .await;
+            if sent.is_err() {
+                seq.set_state(crate::sequence::SequenceState::Done(
+                    crate::sequence::StopReason::Canceled,
+                ));
+                this.reset_non_granular_state();
+                return Ok(());
+            }
+        }
+
         const STREAMING_RATE_LIMIT: usize = 3;
 
         let token_index = seq.get_toks().len();
@@ -36,23 +68,28 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         if rate_limit_allowed {
             if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
                 if seq.get_mut_group().is_chat {
+                    let (content, tool_calls) =
+                        seq.stream_tool_calls(delta.clone(), is_done.is_some());
                     seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
                         delta: crate::Delta {
-                            content: delta.clone(),
+                            content,
                             role: "assistant".to_string(),
+                            tool_calls,
                         },
                         index: seq.get_response_index(),
-                        finish_reason: is_done.map(|x| x.to_string()),
+                        finish_reason: is_done
+                            .map(|reason| chat_finish_reason(reason, seq.streamed_tool_calls())),
                         logprobs: if seq.return_logprobs() {
                             Some(crate::ResponseLogprob {
                                 token: delta,
-                                bytes: logprobs.bytes.clone().into_bytes(),
+                                bytes: logprobs.bytes.clone(),
                                 logprob: logprobs.logprob,
                                 top_logprobs: logprobs.top_logprobs.unwrap().clone(),
                             })
                         } else {
                             None
                         },
+                        matched_stop: is_done.and_then(|reason| seq.matched_stop(&reason)),
                     });
                 } else {
                     seq.add_streaming_completion_chunk_choice_to_group(
@@ -63,23 +100,26 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             logprobs: if seq.return_logprobs() {
                                 Some(crate::ResponseLogprob {
                                     token: delta,
-                                    bytes: logprobs.bytes.clone().into_bytes(),
+                                    bytes: logprobs.bytes.clone(),
                                     logprob: logprobs.logprob,
                                     top_logprobs: logprobs.top_logprobs.unwrap().clone(),
                                 })
                             } else {
                                 None
                             },
+                            matched_stop: is_done.and_then(|reason| seq.matched_stop(&reason)),
+                            echo: false,
                         },
                     );
                 }
 
                 if let Some(reason) = is_done {
-                    if use_prefix_cacher {
+                    if use_prefix_cacher && seq.early_exit_layer().is_none() {
                         prefix_cacher.add_sequence(seq);
                         prefix_cacher.evict_to_cpu()?;
                     }
                     seq.set_state(crate::sequence::SequenceState::Done(reason));
+                    seq.add_streaming_usage_to_group();
                     this.reset_non_granular_state();
                 }
 
@@ -113,13 +153,15 @@ pub(crate) async fn finish_or_add_toks_to_seq(
 
             let logprobs = if seq.return_logprobs() {
                 let mut logprobs = Vec::new();
-                for logprob in seq.logprobs() {
+                // The echoed prompt precedes the completion.
+                let prompt_logprobs = seq.prompt_logprobs().unwrap_or_default();
+                for logprob in prompt_logprobs.iter().chain(seq.logprobs()) {
                     let resp_logprob = crate::ResponseLogprob {
                         token: crate::handle_seq_error_ok!(
                             tokenizer.decode(&[logprob.token], false),
                             seq.responder()
                         ),
-                        bytes: logprob.bytes.clone().into_bytes(),
+                        bytes: logprob.bytes.clone(),
                         logprob: logprob.logprob,
                         top_logprobs: logprob.top_logprobs.clone().unwrap(),
                     };
@@ -143,9 +185,14 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 crate::sequence::StopReason::StopString {
                     completion_bytes_pos,
                     ..
+                }
+                | crate::sequence::StopReason::StopTokSeq {
+                    completion_bytes_pos,
+                    ..
                 } => {
-                    let txt = String::from_utf8_lossy(seq.completion_bytes());
-                    txt[..completion_bytes_pos].trim_start().to_string()
+                    let txt =
+                        String::from_utf8_lossy(&seq.completion_bytes()[..completion_bytes_pos]);
+                    txt.trim_start().to_string()
                 }
             };
 
@@ -162,7 +209,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     tool_calls = calls;
                 }
                 let choice = crate::Choice {
-                    finish_reason: reason.to_string(),
+                    finish_reason: chat_finish_reason(reason, !tool_calls.is_empty()),
                     index: seq.get_response_index(),
                     message: crate::ResponseMessage {
                         content: text_new,
@@ -170,6 +217,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                         tool_calls,
                     },
                     logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+                    matched_stop: seq.matched_stop(&reason),
                 };
                 seq.add_choice_to_group(choice);
             } else {
@@ -177,12 +225,13 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     finish_reason: reason.to_string(),
                     index: seq.get_response_index(),
                     text,
-                    logprobs: None,
+                    logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+                    matched_stop: seq.matched_stop(&reason),
                 };
                 seq.add_completion_choice_to_group(choice);
             }
 
-            if use_prefix_cacher {
+            if use_prefix_cacher && seq.early_exit_layer().is_none() {
                 prefix_cacher.add_sequence(seq);
                 prefix_cacher.evict_to_cpu()?;
             }
@@ -199,6 +248,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            sampling_params_used: group.get_sampling_params_used(),
                         },
                         seq.responder(),
                     )
@@ -215,6 +265,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            sampling_params_used: group.get_sampling_params_used(),
                         },
                         seq.responder(),
                     )
@@ -228,6 +279,15 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     Ok(())
 }
 
+/// The `finish_reason` of a chat choice, which is `tool_calls` if the completion is a tool call.
+fn chat_finish_reason(reason: StopReason, has_tool_calls: bool) -> String {
+    if has_tool_calls {
+        "tool_calls".to_string()
+    } else {
+        reason.to_string()
+    }
+}
+
 pub async fn sample_and_add_toks(
     this: &dyn Pipeline,
     seqs: &mut [&mut Sequence],
@@ -285,6 +345,50 @@ pub async fn sample_sequence(
     sample_speculative: bool,
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    // A seeded sequence draws from its own RNG, also for the draft and target samples of
+    // speculative decoding, so its output does not depend on the other sequences.
+    let rng = seq.rng().unwrap_or(rng);
+    let n_vocab = logits.dim(0)?;
+    let mut bias: Option<Vec<f32>> = None;
+    if let Some(suppressed) = seq.min_len_suppressed_tokens() {
+        let bias = bias.get_or_insert_with(|| vec![0f32; n_vocab]);
+        for tok in suppressed {
+            if let Some(b) = bias.get_mut(tok as usize) {
+                *b = f32::NEG_INFINITY;
+            }
+        }
+    }
+    if let Some(allowed) = seq.token_healing_set() {
+        let bias = bias.get_or_insert_with(|| vec![0f32; n_vocab]);
+        for (tok, b) in (0u32..).zip(bias.iter_mut()) {
+            if !allowed.is_allowed(tok) {
+                *b = f32::NEG_INFINITY;
+            }
+        }
+    }
+    if let Some(dry) = seq.dry_penalty() {
+        let generated = &seq.get_toks()[seq.prompt_tokens()..];
+        for (tok, penalty) in dry.penalties(generated) {
+            let bias = bias.get_or_insert_with(|| vec![0f32; n_vocab]);
+            if let Some(b) = bias.get_mut(tok as usize) {
+                *b -= penalty;
+            }
+        }
+    }
+    let logits = match bias {
+        Some(bias) => {
+            (logits
+                + Tensor::from_slice(&bias, n_vocab, &Device::Cpu)?.to_device(logits.device())?)?
+        }
+        None => logits,
+    };
+
+    // Mirostat only applies to the tokens which are added to the sequence right away, not to the
+    // draft and target samples of speculative decoding.
+    let mirostat_mu = seq
+        .mirostat()
+        .filter(|_| !sample_speculative)
+        .map(|mirostat| mirostat.mu());
 
     let sampler = seq.sampler();
     let ctx_clone = seq.get_toks().to_vec();
@@ -298,6 +402,7 @@ pub async fn sample_sequence(
                 return_logprobs,
                 rng_clone,
                 sample_speculative,
+                mirostat_mu,
             )
         })
         .await?
@@ -308,6 +413,7 @@ pub async fn sample_sequence(
             return_logprobs,
             rng_clone,
             sample_speculative,
+            mirostat_mu,
         )?
     };
 
@@ -337,6 +443,7 @@ pub async fn sample_sequence(
                         return_logprobs,
                         rng_clone,
                         sample_speculative,
+                        mirostat_mu,
                     )
                 })
                 .await?
@@ -347,6 +454,7 @@ pub async fn sample_sequence(
                     return_logprobs,
                     rng_clone,
                     sample_speculative,
+                    mirostat_mu,
                 )?
             }
         }
@@ -368,9 +476,98 @@ pub async fn sample_sequence(
             SequenceRecognizer::None => {}
         }
     }
+    if mirostat_mu.is_some() {
+        if let Some(mirostat) = seq.mirostat_mut() {
+            // The logprob is in base 10 and the surprise in bits.
+            mirostat.update(-second_logprobs_response.logprob * std::f32::consts::LOG2_10);
+        }
+    }
     Ok(second_logprobs_response)
 }
 
+/// The DRY ("Don't Repeat Yourself") penalty: a token which would extend a repetition of more than
+/// `allowed_length` tokens is penalized by `multiplier * base^(length - allowed_length)`, where
+/// `length` is the length of the repeated sequence it continues. Repetitions do not extend across
+/// the sequence breakers.
+#[derive(Clone, Debug)]
+pub(crate) struct DryPenalty {
+    pub(crate) multiplier: f32,
+    pub(crate) base: f32,
+    pub(crate) allowed_length: usize,
+    pub(crate) sequence_breakers: HashSet<u32>,
+}
+
+impl DryPenalty {
+    /// The penalties of the tokens which would extend a repetition at the end of `toks`.
+    pub(crate) fn penalties(&self, toks: &[u32]) -> HashMap<u32, f32> {
+        let mut match_lengths: HashMap<u32, usize> = HashMap::new();
+        let Some((&last, earlier)) = toks.split_last() else {
+            return HashMap::new();
+        };
+        if self.sequence_breakers.contains(&last) {
+            return HashMap::new();
+        }
+
+        // Each earlier occurrence of the last token ends a candidate repetition, which is extended
+        // backwards for as long as it matches the end of `toks`.
+        for (i, _) in earlier.iter().enumerate().filter(|(_, tok)| **tok == last) {
+            let next = toks[i + 1];
+            if self.sequence_breakers.contains(&next) {
+                continue;
+            }
+            let mut length = 1;
+            while length <= i {
+                let tok = toks[i - length];
+                if tok != toks[toks.len() - 1 - length] || self.sequence_breakers.contains(&tok) {
+                    break;
+                }
+                length += 1;
+            }
+            let entry = match_lengths.entry(next).or_default();
+            *entry = (*entry).max(length);
+        }
+
+        match_lengths
+            .into_iter()
+            .filter(|(_, length)| *length >= self.allowed_length)
+            .map(|(tok, length)| {
+                #[allow(clippy::cast_possible_truncation)]
+                let exponent = (length - self.allowed_length) as i32;
+                (tok, self.multiplier * self.base.powi(exponent))
+            })
+            .collect()
+    }
+}
+
+/// The Mirostat v2 state of a sequence. Only the tokens whose surprise is at most `mu` bits may be
+/// sampled: `mu` starts at `2 * tau` and after each token moves by `eta` times the difference
+/// between the target surprise `tau` and the token's surprise.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct MirostatState {
+    tau: f32,
+    eta: f32,
+    mu: f32,
+}
+
+impl MirostatState {
+    pub(crate) fn new(tau: f32, eta: f32) -> Self {
+        Self {
+            tau,
+            eta,
+            mu: 2. * tau,
+        }
+    }
+
+    pub(crate) fn mu(&self) -> f32 {
+        self.mu
+    }
+
+    /// Update `mu` after sampling a token with `surprise` bits.
+    pub(crate) fn update(&mut self, surprise: f32) {
+        self.mu -= self.eta * (surprise - self.tau);
+    }
+}
+
 #[derive(Clone)]
 pub struct SpeculativeSample {
     pub sample: Logprobs,
@@ -401,3 +598,63 @@ pub async fn sample_target_sequence_speculative(
     }
     Ok(sampled)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{chat_finish_reason, DryPenalty, MirostatState};
+    use crate::sequence::StopReason;
+
+    #[test]
+    fn dry_penalizes_repetitions() {
+        let dry = DryPenalty {
+            multiplier: 0.8,
+            base: 2.,
+            allowed_length: 2,
+            sequence_breakers: HashSet::from([9]),
+        };
+        // `1 2 3` is repeated at the end, so 4 would extend a repetition of length 3.
+        let penalties = dry.penalties(&[1, 2, 3, 4, 5, 1, 2, 3]);
+        assert_eq!(penalties.len(), 1);
+        assert_eq!(penalties[&4], 0.8 * 2.);
+
+        // A repetition of a single token is allowed.
+        assert!(dry.penalties(&[1, 2, 5, 1]).is_empty());
+
+        // The breaker stops the repetition at length 1.
+        assert!(dry.penalties(&[9, 3, 4, 5, 9, 3]).is_empty());
+        assert!(dry.penalties(&[1, 2, 9, 1, 2, 9]).is_empty());
+        assert!(dry.penalties(&[]).is_empty());
+    }
+
+    #[test]
+    fn mirostat_mu_converges() {
+        // In a synthetic stream where the surprise of the sampled token is half of the cutoff `mu`,
+        // as for a flat distribution, `mu` converges to `2 * tau` from any starting point.
+        let (tau, eta) = (3., 0.1);
+        for start in [0., 10.] {
+            let mut state = MirostatState::new(tau, eta);
+            state.mu = start;
+            for _ in 0..300 {
+                let surprise = state.mu() / 2.;
+                state.update(surprise);
+            }
+            assert!((state.mu() - 2. * tau).abs() < 1e-3, "mu = {}", state.mu());
+        }
+
+        // A stream whose surprise is always on target leaves `mu` unchanged.
+        let mut state = MirostatState::new(tau, eta);
+        for _ in 0..10 {
+            state.update(tau);
+        }
+        assert_eq!(state.mu(), 2. * tau);
+    }
+
+    #[test]
+    fn tool_calls_finish_reason() {
+        assert_eq!(chat_finish_reason(StopReason::Eos, true), "tool_calls");
+        assert_eq!(chat_finish_reason(StopReason::Eos, false), "stop");
+        assert_eq!(chat_finish_reason(StopReason::Length(8), false), "length");
+    }
+}
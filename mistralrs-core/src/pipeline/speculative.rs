@@ -6,18 +6,19 @@ use std::{
 
 use anyhow::Result as anyhowResult;
 use candle_core::{Device, IndexOp, Result, Tensor};
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{ImatrixData, IsqType};
 use rand_isaac::Isaac64Rng;
 use tokenizers::Tokenizer;
 use tracing::warn;
 
+use crate::response::AdaptersResponse;
 use crate::{
     get_mut_arcmutex,
     pipeline::{
         sampling::{
             finish_or_add_toks_to_seq, sample_sequence, sample_target_sequence_speculative,
         },
-        AdapterInstruction, Cache,
+        AdapterInstruction, Cache, KvCacheDtype,
     },
     prefix_cacher::PrefixCacheManager,
     sequence::{Sequence, SequenceRecognizer},
@@ -26,10 +27,10 @@ use crate::{
 };
 
 use super::{
-    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, sampling::SpeculativeSample,
-    AdapterActivationMixin, AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction,
-    CacheManager, CacheManagerMixin, GeneralMetadata, IsqPipelineMixin, MetadataMixin,
-    ModelCategory, ModelPaths, PreProcessingMixin,
+    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, check_finite_logits,
+    sampling::SpeculativeSample, AdapterActivationMixin, AnyMoePipelineMixin, CacheBackendMetadata,
+    CacheInstruction, CacheManager, CacheManagerMixin, GeneralMetadata, IsqPipelineMixin,
+    MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
 };
 
 /// A loader for a speculative pipeline using 2 [`Loader`]s.
@@ -222,9 +223,13 @@ impl PreProcessingMixin for SpeculativePipeline {
 }
 
 impl IsqPipelineMixin for SpeculativePipeline {
-    fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
-        get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+    fn re_isq_model(
+        &mut self,
+        dtype: IsqType,
+        imatrix: Option<&ImatrixData>,
+    ) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).re_isq_model(dtype, imatrix)?;
+        get_mut_arcmutex!(self.draft).re_isq_model(dtype, imatrix)
     }
 }
 
@@ -255,16 +260,35 @@ impl CacheManagerMixin for SpeculativePipeline {
     fn cache(&self) -> &Cache {
         unreachable!()
     }
+    fn kv_cache_dtype(&self) -> KvCacheDtype {
+        get_mut_arcmutex!(self.target).kv_cache_dtype()
+    }
+    fn set_kv_cache_dtype(&self, dtype: KvCacheDtype) {
+        get_mut_arcmutex!(self.draft).set_kv_cache_dtype(dtype);
+        get_mut_arcmutex!(self.target).set_kv_cache_dtype(dtype);
+    }
 }
 
 impl AdapterActivationMixin for SpeculativePipeline {
     /// Returns the number of activated adapters.
-    fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_adapters_weighted(
+        &mut self,
+        adapters: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
         let mut res = 0;
-        res += get_mut_arcmutex!(self.draft).activate_adapters(adapters.clone())?;
-        res += get_mut_arcmutex!(self.target).activate_adapters(adapters)?;
+        res += get_mut_arcmutex!(self.draft).activate_adapters_weighted(adapters.clone())?;
+        res += get_mut_arcmutex!(self.target).activate_adapters_weighted(adapters)?;
         Ok(res)
     }
+    fn load_adapter(&mut self, name: String, path_or_hf_id: String) -> anyhow::Result<usize> {
+        let mut res = 0;
+        res += get_mut_arcmutex!(self.draft).load_adapter(name.clone(), path_or_hf_id.clone())?;
+        res += get_mut_arcmutex!(self.target).load_adapter(name, path_or_hf_id)?;
+        Ok(res)
+    }
+    fn adapters(&self) -> AdaptersResponse {
+        get_mut_arcmutex!(self.target).adapters()
+    }
 }
 
 impl MetadataMixin for SpeculativePipeline {
@@ -312,6 +336,7 @@ impl Pipeline for SpeculativePipeline {
         is_prompt: bool,
         prefix_cacher: &mut PrefixCacheManager,
         disable_eos_stop: bool,
+        nan_checks: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<()> {
@@ -462,6 +487,9 @@ impl Pipeline for SpeculativePipeline {
                     .unwrap();
 
                 let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?;
+                if nan_checks {
+                    check_finite_logits(&logits, *seq.id(), is_prompt)?;
+                }
 
                 // Reset the prefill tokens
                 seq.reset_prefill_toks();
@@ -11,8 +11,9 @@ use crate::aici::toktree::TokTrie;
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
 use crate::pipeline::sampling::sample_and_add_toks;
-use crate::pipeline::{get_chat_template, ChatTemplate, LocalModelPaths};
+use crate::pipeline::{get_chat_template, get_model_type, ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::response::AdaptersResponse;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::tokenizer::get_tokenizer;
@@ -27,7 +28,7 @@ use crate::{
 use anyhow::Result;
 use candle_core::{Device, Tensor, Var};
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{ImatrixData, IsqType};
 use rand_isaac::Isaac64Rng;
 use regex_automata::meta::Regex;
 use std::any::Any;
@@ -79,6 +80,8 @@ pub struct VisionSpecificConfig {
     pub use_flash_attn: bool,
     pub prompt_batchsize: Option<NonZeroUsize>,
     pub topology: Option<Topology>,
+    /// Downscale images, preserving their aspect ratio, so that neither side is longer than this.
+    pub max_image_edge: Option<u32>,
 }
 
 impl VisionLoaderBuilder {
@@ -163,6 +166,12 @@ impl Loader for VisionLoader {
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
         let config = std::fs::read_to_string(paths.get_config_filename())?;
 
+        let mapper = mapper.resolve_auto(
+            self.inner.get_total_device_mapping_num_layers(&config)?,
+            paths.get_weight_filenames(),
+            in_situ_quant,
+            device,
+        )?;
         // Otherwise, the device mapper will print it
         if mapper.is_dummy() {
             info!(
@@ -250,10 +259,20 @@ impl Loader for VisionLoader {
         let gen_conf: Option<GenerationConfig> = paths
             .get_gen_conf_filename()
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
-        let chat_template = get_chat_template(paths, &self.chat_template, None);
+        let chat_template = get_chat_template(
+            paths,
+            &self.chat_template,
+            None,
+            get_model_type(&config).as_deref(),
+        )?;
 
         if in_situ_quant.is_some() || self.config.topology.is_some() {
-            model.quantize(in_situ_quant, device.clone(), self.config.topology.as_ref())?;
+            model.quantize(
+                in_situ_quant,
+                device.clone(),
+                self.config.topology.as_ref(),
+                None,
+            )?;
         }
 
         let (cache_config, cache_engine) = if let Some(paged_attn_config) = paged_attn_config {
@@ -298,6 +317,7 @@ impl Loader for VisionLoader {
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                max_image_edge: self.config.max_image_edge,
             }),
             processor,
             preprocessor_config: Arc::new(preprocessor_config),
@@ -327,10 +347,10 @@ impl PreProcessingMixin for VisionPipeline {
 }
 
 impl IsqPipelineMixin for VisionPipeline {
-    fn re_isq_model(&mut self, dtype: IsqType) -> Result<()> {
+    fn re_isq_model(&mut self, dtype: IsqType, imatrix: Option<&ImatrixData>) -> Result<()> {
         let device = self.device().clone();
         self.model
-            .quantize(Some(dtype), device, self.topology.as_ref())
+            .quantize(Some(dtype), device, self.topology.as_ref(), imatrix)
             .map_err(anyhow::Error::msg)
     }
 }
@@ -354,9 +374,15 @@ impl CacheManagerMixin for VisionPipeline {
 }
 
 impl AdapterActivationMixin for VisionPipeline {
-    fn activate_adapters(&mut self, _adapters: Vec<String>) -> Result<usize> {
+    fn activate_adapters_weighted(&mut self, _adapters: Vec<(String, f64)>) -> Result<usize> {
         anyhow::bail!("Vision models do not support adapter activation.");
     }
+    fn load_adapter(&mut self, _name: String, _path_or_hf_id: String) -> Result<usize> {
+        anyhow::bail!("Vision models do not support adapter loading.");
+    }
+    fn adapters(&self) -> AdaptersResponse {
+        AdaptersResponse::default()
+    }
 }
 
 impl MetadataMixin for VisionPipeline {
@@ -384,6 +410,7 @@ impl Pipeline for VisionPipeline {
             seqlen_offsets_kernel,
             context_lens,
             position_ids,
+            mrope_position_ids,
             pixel_values,
             model_specific_args,
             mut paged_attn_meta,
@@ -395,6 +422,7 @@ impl Pipeline for VisionPipeline {
             seqlen_offsets_kernel,
             context_lens,
             position_ids,
+            &mrope_position_ids,
             model_specific_args,
             self.get_metadata().cache_engine.as_ref().map(|engine| {
                 (
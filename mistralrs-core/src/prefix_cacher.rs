@@ -3,7 +3,9 @@ use std::sync::{Arc, Mutex};
 use candle_core::{Device, Result, Tensor};
 use radix_trie::{Trie, TrieCommon, TrieKey};
 
-use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
+use crate::{
+    get_mut_arcmutex, pipeline::LayerCaches, response::PrefixCacheStats, sequence::Sequence,
+};
 
 #[derive(PartialEq, Eq)]
 struct Tokens(Vec<u32>);
@@ -32,6 +34,7 @@ pub struct PrefixCacheManager {
     pub n_on_device: usize,
     no_prefix_cache: bool,
     eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    stats: PrefixCacheStats,
 }
 
 #[derive(Clone)]
@@ -50,24 +53,51 @@ impl PrefixCacheManager {
             n_on_device,
             no_prefix_cache,
             eviction_cache_ptrs: Vec::new(),
+            stats: PrefixCacheStats::default(),
         }
     }
 
+    /// Hit, miss and eviction counts since the engine started.
+    pub fn stats(&self) -> PrefixCacheStats {
+        self.stats
+    }
+
+    /// Drop all cached prefixes, returning how many there were. Sequences which were started from
+    /// a cached prefix keep their own copy of the KV cache, so this is safe while they run.
+    pub fn clear(&mut self) -> usize {
+        let n_cached = self.caches.len();
+        self.caches = Trie::new();
+        if let Some(ref mut xlora_caches) = self.xlora_caches {
+            *xlora_caches = Trie::new();
+        }
+        self.eviction_cache_ptrs.clear();
+        n_cached
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.no_prefix_cache
+    }
+
     /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
     /// some caches will be evicted.
     pub fn add_sequence(&mut self, seq: &mut Sequence) {
+        let xlora_cache = seq.is_xlora().then(|| seq.xlora_cache().clone());
+        self.insert(seq.get_toks().to_vec(), seq.cache().clone(), xlora_cache);
+    }
+
+    /// Add the KV cache of the given tokens, for example one restored from an exported sequence state.
+    pub fn insert(&mut self, toks: Vec<u32>, cache: LayerCaches, xlora_cache: Option<LayerCaches>) {
         if self.no_prefix_cache {
             return;
         }
-        let cache = Arc::new(Mutex::new(seq.cache().clone()));
-        self.caches
-            .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
-            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
+        let cache = Arc::new(Mutex::new(cache));
+        self.caches.insert(toks.clone().into(), cache.clone());
+        if let Some(xlora_cache) = xlora_cache {
+            let xlora_cache = Arc::new(Mutex::new(xlora_cache));
             self.xlora_caches
                 .as_mut()
                 .unwrap()
-                .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
+                .insert(toks.into(), xlora_cache.clone());
             self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
         } else {
             self.eviction_cache_ptrs.push((cache, None));
@@ -129,6 +159,7 @@ impl PrefixCacheManager {
                 n_evicted += 1;
             }
         }
+        self.stats.evictions += n_evicted;
         Ok(self.caches.len().saturating_sub(self.n_on_device))
     }
 
@@ -154,6 +185,7 @@ impl PrefixCacheManager {
                 if let Some(ref mut xlora_cache) = xlora_cache {
                     Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
                 }
+                self.stats.evictions += 1;
             }
         }
         Ok(self.caches.len())
@@ -183,6 +215,7 @@ impl PrefixCacheManager {
                 .key()
                 .expect("Cannot get the key.")
                 .0;
+            self.stats.hits += 1;
             // Know ancestor.len() < toks.len(), and toks[0..ancestor.len()] == toks
             Ok(Some(MatchingCache {
                 normal: cache,
@@ -190,7 +223,30 @@ impl PrefixCacheManager {
                 toks: toks.0[ancestor.len()..].to_vec(),
             }))
         } else {
+            self.stats.misses += 1;
             Ok(None)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::PrefixCacheManager;
+
+    #[test]
+    fn clear_counts_and_drops_prefixes() {
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, 1, false, false);
+        let kv = Tensor::zeros((1, 1, 2, 1), DType::F32, &Device::Cpu).unwrap();
+        cacher.insert(vec![1, 2], vec![Some((kv.clone(), kv))], None);
+
+        assert!(cacher.search_for_matching_cache(&[1, 2]).unwrap().is_some());
+        assert!(cacher.search_for_matching_cache(&[3]).unwrap().is_none());
+        assert_eq!(cacher.clear(), 1);
+        assert!(cacher.search_for_matching_cache(&[1, 2]).unwrap().is_none());
+
+        let stats = cacher.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 2, 0));
+    }
+}
@@ -3,12 +3,15 @@ use indexmap::IndexMap;
 use mistralrs_quant::IsqType;
 
 use crate::{
-    response::Response,
+    response::{
+        AdaptersResponse, CacheUsageResponse, EmbeddingResponse, ModelInfoResponse, PingResponse,
+        PrefixCacheStats, RawForwardResponse, Response,
+    },
     sampler::SamplingParams,
     tools::{Tool, ToolChoice},
     CustomLogitsProcessor,
 };
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
@@ -16,6 +19,10 @@ use tokio::sync::mpsc::Sender;
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    /// A JSON schema. The output is guaranteed to be a JSON document which satisfies it. Only the
+    /// subset of JSON Schema which can be expressed as a regex is supported, and recursive schemas
+    /// are rejected.
+    JsonSchema(String),
     None,
 }
 
@@ -28,9 +35,20 @@ pub enum RequestMessage {
     Completion {
         text: String,
         echo_prompt: bool,
+        /// The number of candidates to generate, at least `n_choices`. The `n_choices` candidates
+        /// with the highest cumulative logprob are returned.
         best_of: usize,
     },
+    /// A prompt tokenized by the caller, used without a chat template or tokenization. Every id
+    /// must be in the vocabulary of the tokenizer.
     CompletionTokens(Vec<u32>),
+    /// Fill in the middle (FIM) for code models: generate the text between `prefix` and `suffix`.
+    /// The prompt is laid out with the FIM tokens of the model, see
+    /// [`FimTokens`](crate::FimTokens).
+    FillInMiddle {
+        prefix: String,
+        suffix: String,
+    },
     VisionChat {
         images: Vec<image::DynamicImage>,
         messages: Vec<IndexMap<String, MessageContent>>,
@@ -44,23 +62,43 @@ pub enum RequestMessage {
 /// - `response`: Object to send the result through
 /// - `return_logprobs`: Whether to return logprobs
 /// - `is_streaming`: Control whether the request is streaming, if so chunk responses will be sent
+/// - `include_usage`: For a streaming chat request, send a final chunk with the token usage of the
+///     request and no choices
 /// - `id`: Request ID
 /// - `constraint`: Constraint to use during generation
 /// - `suffix`: Suffix to add
 /// - `adapters`: Adapters to use in this request
 /// - `tools`: Tools available in this request
-/// - `tool_choice`: Choice of tools
+/// - `tool_choice`: Choice of tools. Forcing a tool with [`ToolChoice::Tool`] constrains the output
+///     to a call of it, so it cannot be combined with a `constraint`.
 /// - `logits_processors`: Custom logits processors. Order of application:
 ///     1) Apply penalties from `sampling_params`
 ///     2) Apply these custom logits processors sequentially
 ///     3) Apply temperature and softmax
 ///     4) Sample the next token (topk, topp, minp, etc)
+/// - `early_exit_layer`: *Experimental*: only run the first `K` decoder layers before the final
+///     norm and LM head, for early exit research. This trades latency for output quality, which
+///     degrades substantially. `K` must be in `1..=num_hidden_layers`. Only supported by text
+///     models without adapters, GGUF/GGML quantization or PagedAttention, and the prefix cache
+///     is not used for these requests.
+/// - `xlora_global_scaling`: For X-LoRA models, scale the adapter outputs by this instead of the
+///     `global_scaling_weight` of the X-LoRA config, for this request only. `0.0` runs the base
+///     model. Ignored by other models.
+/// - `token_healing`: If the last prompt token is a prefix of a longer token, remove it and constrain
+///     the first generated token to start with its text, which is not repeated in the output. Not
+///     applied with a `constraint`.
+/// - `truncate_prompt`: If the prompt and `max_len` new tokens exceed the model's maximum sequence
+///     length, drop tokens from the start of the prompt to fit instead of rejecting the request.
+/// - `add_generation_prompt`: For a chat request, end the prompt with the chat template's generation
+///     prompt, which starts a new assistant turn. If this is `false` and the last message is from the
+///     assistant, the model continues that message instead, to prefill the start of its reply.
 pub struct NormalRequest {
     pub messages: RequestMessage,
     pub sampling_params: SamplingParams,
     pub response: Sender<Response>,
     pub return_logprobs: bool,
     pub is_streaming: bool,
+    pub include_usage: bool,
     pub id: usize,
     pub constraint: Constraint,
     pub suffix: Option<String>,
@@ -68,6 +106,11 @@ pub struct NormalRequest {
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
+    pub early_exit_layer: Option<usize>,
+    pub xlora_global_scaling: Option<f64>,
+    pub token_healing: bool,
+    pub truncate_prompt: bool,
+    pub add_generation_prompt: bool,
 }
 
 impl NormalRequest {
@@ -88,21 +131,131 @@ impl NormalRequest {
             tool_choice,
             return_logprobs: false,
             is_streaming: false,
+            include_usage: false,
+            token_healing: false,
+            truncate_prompt: false,
+            add_generation_prompt: true,
             constraint: Constraint::None,
             suffix: None,
             adapters: None,
             logits_processors: None,
+            early_exit_layer: None,
+            xlora_global_scaling: None,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// How the final hidden states of a [`Request::Embedding`] are pooled into one vector.
+pub enum EmbeddingPooling {
+    /// The hidden state of the last token, which has attended to the whole text.
+    LastToken,
+    /// The mean of the hidden states of all tokens.
+    MeanPooling,
+    /// The hidden state of the first token, usually the BOS token.
+    Cls,
+}
+
 #[derive(Clone)]
 /// A request to the Engine, encapsulating the various parameters as well as
 /// the `mspc` response `Sender` used to return the [`Response`].
 pub enum Request {
     Normal(NormalRequest),
-    ReIsq(IsqType),
+    /// Requantize the model. If an imatrix file (in the llama.cpp format) is given, it is used to
+    /// weight the quantization error per channel.
+    ReIsq(IsqType, Option<PathBuf>),
     ActivateAdapters(Vec<String>),
+    /// Activate adapters, scaling the contribution of each by its weight. A weight of 1.0 is the
+    /// same as [`Request::ActivateAdapters`] and 0.0 disables the adapter. Not supported for
+    /// X-LoRA models.
+    ActivateAdaptersWeighted(Vec<(String, f64)>),
+    /// Query the names of the loaded adapters and which of them are active.
+    GetAdapters(Sender<AdaptersResponse>),
+    /// Load a LoRA adapter into a running LoRA model from a local directory or Hugging Face model
+    /// ID with `adapter_config.json` and `adapter_model.safetensors`, responding with the adapters
+    /// once it can be activated as `name`. The adapter must target the same modules as the
+    /// adapters the model was loaded with. Not supported for X-LoRA and quantized models.
+    LoadAdapter {
+        name: String,
+        path_or_hf_id: String,
+        response: Sender<anyhow::Result<AdaptersResponse>>,
+    },
+    /// Query the limits and kind of the loaded model.
+    ModelInfo(Sender<ModelInfoResponse>),
+    /// Latency probe: the engine immediately acknowledges this with the current timestamp and
+    /// queue depth, without running the model.
+    Ping(Sender<PingResponse>),
+    /// Serialize the tokens and KV cache of the running or waiting sequence with the given id (the
+    /// `id` of its responses), for example to migrate it or to resume it after a client reconnects.
+    /// The state is in the safetensors format and the KV cache is about
+    /// `2 * num_hidden_layers * num_key_value_heads * head_dim * num_tokens` elements of the model's
+    /// dtype. Not supported with PagedAttention.
+    ExportSeqState(usize, Sender<anyhow::Result<Vec<u8>>>),
+    /// Restore a state exported by [`Request::ExportSeqState`] as a new sequence which resumes
+    /// generating after its tokens, and respond with the id of the new sequence (the `id` of its
+    /// responses). The sequence is added for `request`, whose messages are replaced by the tokens
+    /// of the state and which sends the responses as any other completion request. `request` must
+    /// have a single choice and no early exit layer. The state must come from the same model with
+    /// the same dtype; it is loaded onto this model's device. Requires the prefix cache, which the
+    /// restored KV cache goes through and stays in.
+    ImportSeqState {
+        data: Vec<u8>,
+        request: NormalRequest,
+        response: Sender<anyhow::Result<usize>>,
+    },
+    /// Query the free and total blocks of the PagedAttention KV cache and the number of running
+    /// and waiting sequences, as of the last step of the engine.
+    CacheUsage(Sender<CacheUsageResponse>),
+    /// Query the hit, miss and eviction counts of the prefix cache.
+    PrefixCacheStats(Sender<PrefixCacheStats>),
+    /// Drop all prefixes cached by the prefix cache, freeing their KV caches. Running sequences
+    /// are not affected.
+    ClearPrefixCache,
+    /// Tokenize `text` with the model's tokenizer, responding with [`Response::Tokenize`].
+    Tokenize {
+        text: String,
+        add_special_tokens: bool,
+        response: Sender<Response>,
+    },
+    /// Decode `tokens` with the model's tokenizer, responding with [`Response::Detokenize`].
+    Detokenize {
+        tokens: Vec<u32>,
+        skip_special_tokens: bool,
+        response: Sender<Response>,
+    },
+    /// Render `messages` with the chat template of the model, as for a chat request with `tools`,
+    /// and respond with the prompt text without running the model. Useful to check that a chat
+    /// template handles system prompts and tools as expected.
+    RenderTemplate {
+        messages: Vec<IndexMap<String, MessageContent>>,
+        add_generation_prompt: bool,
+        tools: Option<Vec<Tool>>,
+        response: Sender<anyhow::Result<String>>,
+    },
+    /// Cancel the request with the given `id` (the `id` of its [`NormalRequest`]). Its sequences
+    /// finish after their next step with the `canceled` finish reason, sending their final
+    /// response or chunk as for any other stop reason.
+    Terminate(usize),
+    /// Embed `text` with the hidden states of the final layer, before the LM head. The text is
+    /// tokenized with special tokens and run without the KV cache of the other sequences. Only
+    /// supported by some text models (Llama, Mistral and Gemma), without X-LoRA.
+    Embedding {
+        text: String,
+        pooling: EmbeddingPooling,
+        response: Sender<anyhow::Result<EmbeddingResponse>>,
+    },
+    /// *Debugging*: run `tokens` through the model in a single forward pass, bypassing the
+    /// scheduler and sampling, and respond with the raw logits and, if `return_hidden_states`, the
+    /// hidden states before the LM head. Only the last position is returned unless
+    /// `all_positions` is set, which returns `vocab_size` floats per token. The tokens are run
+    /// without the KV cache of the other sequences. Hidden states are only supported by the models
+    /// supporting [`Request::Embedding`], and X-LoRA models are not supported.
+    RawForward {
+        tokens: Vec<u32>,
+        return_hidden_states: bool,
+        all_positions: bool,
+        response: Sender<anyhow::Result<RawForwardResponse>>,
+    },
 }
 
 impl Debug for Request {
@@ -124,8 +277,70 @@ impl Debug for Request {
             Request::ActivateAdapters(adapters) => {
                 write!(f, "Activate Adapters Request {adapters:?}",)
             }
-            Request::ReIsq(tp) => {
-                write!(f, "Re ISQ Request {tp:?}",)
+            Request::ActivateAdaptersWeighted(adapters) => {
+                write!(f, "Activate Weighted Adapters Request {adapters:?}",)
+            }
+            Request::GetAdapters(_) => {
+                write!(f, "Get Adapters Request")
+            }
+            Request::LoadAdapter {
+                name,
+                path_or_hf_id,
+                ..
+            } => {
+                write!(f, "Load Adapter Request `{name}` from `{path_or_hf_id}`")
+            }
+            Request::ModelInfo(_) => {
+                write!(f, "Model Info Request")
+            }
+            Request::Ping(_) => {
+                write!(f, "Ping Request")
+            }
+            Request::ExportSeqState(id, _) => {
+                write!(f, "Export Sequence State Request {id}")
+            }
+            Request::ImportSeqState { data, request, .. } => {
+                write!(
+                    f,
+                    "Import Sequence State Request {} ({} bytes)",
+                    request.id,
+                    data.len()
+                )
+            }
+            Request::CacheUsage(_) => {
+                write!(f, "Cache Usage Request")
+            }
+            Request::PrefixCacheStats(_) => {
+                write!(f, "Prefix Cache Stats Request")
+            }
+            Request::ClearPrefixCache => {
+                write!(f, "Clear Prefix Cache Request")
+            }
+            Request::Tokenize { text, .. } => {
+                write!(f, "Tokenize Request `{text}`")
+            }
+            Request::Detokenize { tokens, .. } => {
+                write!(f, "Detokenize Request {tokens:?}")
+            }
+            Request::RenderTemplate { messages, .. } => {
+                write!(f, "Render Template Request `{messages:?}`")
+            }
+            Request::Terminate(id) => {
+                write!(f, "Terminate Request {id}")
+            }
+            Request::Embedding { text, pooling, .. } => {
+                write!(f, "Embedding Request `{text}`, pooling: {pooling:?}")
+            }
+            Request::RawForward {
+                tokens,
+                return_hidden_states,
+                all_positions,
+                ..
+            } => {
+                write!(f, "Raw Forward Request {tokens:?}, return_hidden_states: {return_hidden_states}, all_positions: {all_positions}")
+            }
+            Request::ReIsq(tp, imatrix) => {
+                write!(f, "Re ISQ Request {tp:?}, imatrix: {imatrix:?}",)
             }
         }
     }
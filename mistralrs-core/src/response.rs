@@ -4,7 +4,10 @@ use std::error::Error;
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::{
+    sampler::TopLogprob,
+    tools::{ToolCallDelta, ToolCallResponse},
+};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -39,6 +42,8 @@ generate_repr!(ResponseMessage);
 pub struct Delta {
     pub content: String,
     pub role: String,
+    /// Parts of the tool calls, whose text is not sent as `content`.
+    pub tool_calls: Vec<ToolCallDelta>,
 }
 
 generate_repr!(Delta);
@@ -75,6 +80,8 @@ pub struct Choice {
     pub index: usize,
     pub message: ResponseMessage,
     pub logprobs: Option<Logprobs>,
+    /// The stop sequence which terminated generation, if any. It is not included in the content.
+    pub matched_stop: Option<String>,
 }
 
 generate_repr!(Choice);
@@ -88,6 +95,8 @@ pub struct ChunkChoice {
     pub index: usize,
     pub delta: Delta,
     pub logprobs: Option<ResponseLogprob>,
+    /// The stop sequence which terminated generation, if any. Only set in the final chunk.
+    pub matched_stop: Option<String>,
 }
 
 generate_repr!(ChunkChoice);
@@ -101,6 +110,11 @@ pub struct CompletionChunkChoice {
     pub index: usize,
     pub logprobs: Option<ResponseLogprob>,
     pub finish_reason: Option<String>,
+    /// The stop sequence which terminated generation, if any. Only set in the final chunk.
+    pub matched_stop: Option<String>,
+    /// Whether this chunk echoes the prompt, rather than being generated. The echoed chunks are
+    /// sent before the generated chunks of the choice.
+    pub echo: bool,
 }
 
 generate_repr!(CompletionChunkChoice);
@@ -119,10 +133,38 @@ pub struct Usage {
     pub total_time_sec: f32,
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
+    /// Wall-clock time from the arrival of the request until its first prompt was processed,
+    /// which is the time to the first token.
+    pub prompt_time_ms: f32,
+    /// Wall-clock time from the end of the prompt processing until the last choice finished.
+    pub completion_time_ms: f32,
+    /// Wall-clock time from the arrival of the request until the last choice finished. Unlike
+    /// `total_time_sec`, this is not summed over the choices.
+    pub total_time_ms: f32,
 }
 
 generate_repr!(Usage);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Default, Serialize)]
+/// The sampling parameters which were actually used, after defaults were applied.
+pub struct SamplingParamsUsed {
+    /// `None` if sampling was greedy.
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub top_p: f64,
+    pub min_p: f64,
+    pub typical_p: f32,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub n: usize,
+}
+
+generate_repr!(SamplingParamsUsed);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -135,6 +177,7 @@ pub struct ChatCompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    pub sampling_params_used: SamplingParamsUsed,
 }
 
 generate_repr!(ChatCompletionResponse);
@@ -150,10 +193,55 @@ pub struct ChatCompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Only set in the final chunk of a request with `include_usage`, which has no choices.
+    pub usage: Option<Usage>,
 }
 
 generate_repr!(ChatCompletionChunkResponse);
 
+impl Delta {
+    /// Append the content and tool call parts of a following `delta`. The argument fragments of a
+    /// tool call are appended to the part with the same index, which keeps its `id`, type and name.
+    pub fn append(&mut self, delta: Delta) {
+        self.content.push_str(&delta.content);
+        for call in delta.tool_calls {
+            match self.tool_calls.iter_mut().find(|c| c.index == call.index) {
+                Some(buffered) => {
+                    buffered.id = buffered.id.take().or(call.id);
+                    buffered.tp = buffered.tp.take().or(call.tp);
+                    buffered.function.name = buffered.function.name.take().or(call.function.name);
+                    buffered
+                        .function
+                        .arguments
+                        .push_str(&call.function.arguments);
+                }
+                None => self.tool_calls.push(call),
+            }
+        }
+    }
+}
+
+impl ChatCompletionChunkResponse {
+    /// Coalesce a following `chunk` into this one, matching the choices by index. The finish reason,
+    /// matched stop and logprobs are those of the latest chunk of each choice.
+    pub fn append(&mut self, chunk: ChatCompletionChunkResponse) {
+        for choice in chunk.choices {
+            match self.choices.iter_mut().find(|c| c.index == choice.index) {
+                Some(buffered) => {
+                    buffered.delta.append(choice.delta);
+                    buffered.finish_reason = choice.finish_reason;
+                    buffered.matched_stop = choice.matched_stop;
+                    buffered.logprobs = choice.logprobs;
+                }
+                None => self.choices.push(choice),
+            }
+        }
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage;
+        }
+    }
+}
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -162,7 +250,10 @@ pub struct CompletionChoice {
     pub finish_reason: String,
     pub index: usize,
     pub text: String,
-    pub logprobs: Option<()>,
+    /// The logprobs of the echoed prompt tokens after the first, then of the generated tokens.
+    pub logprobs: Option<Logprobs>,
+    /// The stop sequence which terminated generation, if any. It is not included in the text.
+    pub matched_stop: Option<String>,
 }
 
 generate_repr!(CompletionChoice);
@@ -179,6 +270,7 @@ pub struct CompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    pub sampling_params_used: SamplingParamsUsed,
 }
 
 generate_repr!(CompletionResponse);
@@ -198,10 +290,106 @@ pub struct CompletionChunkResponse {
 
 generate_repr!(CompletionChunkResponse);
 
-/// The response enum contains 3 types of variants:
+#[derive(Debug, Clone)]
+/// Acknowledgement of a [`Request::Ping`](crate::Request::Ping), sent without running the model.
+pub struct PingResponse {
+    /// Time (ms since the UNIX epoch) at which the engine handled the ping.
+    pub timestamp: u128,
+    /// Number of sequences waiting to be scheduled.
+    pub queue_depth: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+/// Answer to a [`Request::PrefixCacheStats`](crate::Request::PrefixCacheStats). The counts are
+/// cumulative since the engine started and are not reset by
+/// [`Request::ClearPrefixCache`](crate::Request::ClearPrefixCache).
+pub struct PrefixCacheStats {
+    /// Number of prompts whose KV cache was found in the prefix cache.
+    pub hits: usize,
+    /// Number of prompts which were looked up but not found in the prefix cache.
+    pub misses: usize,
+    /// Number of cached prefixes which were moved from the device to the CPU to free device memory.
+    pub evictions: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+/// Answer to a [`Request::CacheUsage`](crate::Request::CacheUsage), for admission control based on
+/// the KV cache pressure.
+pub struct CacheUsageResponse {
+    /// Number of KV cache blocks which are not allocated to a sequence. `None` without
+    /// PagedAttention, where the KV cache is not preallocated.
+    pub free_blocks: Option<usize>,
+    /// Number of KV cache blocks. `None` without PagedAttention.
+    pub total_blocks: Option<usize>,
+    /// Number of sequences which are scheduled.
+    pub running: usize,
+    /// Number of sequences waiting to be scheduled.
+    pub waiting: usize,
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Default, Serialize)]
+/// Answer to a [`Request::GetAdapters`](crate::Request::GetAdapters).
+pub struct AdaptersResponse {
+    /// Names of all adapters loaded by the model.
+    pub available: Vec<String>,
+    /// Names of the adapters which are currently active.
+    pub active: Vec<String>,
+}
+
+generate_repr!(AdaptersResponse);
+
+#[derive(Debug, Clone, Serialize)]
+/// Answer to a [`Request::ModelInfo`](crate::Request::ModelInfo).
+pub struct ModelInfoResponse {
+    /// Maximum number of tokens of a sequence, including the prompt. For GGUF models, this is the
+    /// context length of the GGUF metadata.
+    pub max_seq_len: usize,
+    pub num_hidden_layers: usize,
+    /// Number of tokens of the tokenizer, including the added tokens.
+    pub vocab_size: usize,
+    /// The token ids which are treated as EOS.
+    pub eos_tokens: Vec<u32>,
+    /// The kind of model, such as `normal (no quant, no adapters)` or `quantized from gguf (no adapters)`.
+    pub kind: String,
+    /// Whether the model takes images.
+    pub is_vision: bool,
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Answer to a [`Request::Embedding`](crate::Request::Embedding).
+pub struct EmbeddingResponse {
+    /// The pooled hidden state of the final layer, of length `hidden_size`.
+    pub embedding: Vec<f32>,
+    /// Number of tokens of the embedded text.
+    pub prompt_tokens: usize,
+}
+
+generate_repr!(EmbeddingResponse);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Answer to a [`Request::RawForward`](crate::Request::RawForward), with one row per returned
+/// position: the last one, or all of them.
+pub struct RawForwardResponse {
+    /// The logits of each returned position, of length `vocab_size`.
+    pub logits: Vec<Vec<f32>>,
+    /// The hidden states of the final layer of each returned position, after the final norm and
+    /// before the LM head, of length `hidden_size`. Only set if they were requested.
+    pub hidden_states: Option<Vec<Vec<f32>>>,
+}
+
+generate_repr!(RawForwardResponse);
+
+/// The response enum contains 4 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
 /// - Completion (Completion- prefix)
+/// - Tokenization (`Tokenize` and `Detokenize`)
 pub enum Response {
     InternalError(Box<dyn Error + Send + Sync>),
     ValidationError(Box<dyn Error + Send + Sync>),
@@ -213,4 +401,83 @@ pub enum Response {
     CompletionModelError(String, CompletionResponse),
     CompletionDone(CompletionResponse),
     CompletionChunk(CompletionChunkResponse),
+    // Tokenization
+    Tokenize(Vec<u32>),
+    Detokenize(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatCompletionChunkResponse, ChunkChoice, Delta};
+    use crate::tools::{CalledFunctionDelta, ToolCallDelta, ToolCallType};
+
+    fn tool_call_part(index: usize, name: Option<&str>, arguments: &str) -> ToolCallDelta {
+        ToolCallDelta {
+            index,
+            id: name.map(|name| format!("call-{name}")),
+            tp: name.map(|_| ToolCallType::Function),
+            function: CalledFunctionDelta {
+                name: name.map(ToString::to_string),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    fn chunk(
+        tool_calls: Vec<ToolCallDelta>,
+        finish_reason: Option<&str>,
+    ) -> ChatCompletionChunkResponse {
+        ChatCompletionChunkResponse {
+            id: "0".to_string(),
+            choices: vec![ChunkChoice {
+                finish_reason: finish_reason.map(ToString::to_string),
+                index: 0,
+                delta: Delta {
+                    content: String::new(),
+                    role: "assistant".to_string(),
+                    tool_calls,
+                },
+                logprobs: None,
+                matched_stop: None,
+            }],
+            created: 0,
+            model: "default".to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "chat.completion.chunk".to_string(),
+            usage: None,
+        }
+    }
+
+    #[test]
+    fn coalesced_tool_call_parts_reassemble_arguments() {
+        let mut coalesced = chunk(
+            vec![tool_call_part(0, Some("get_weather"), "{\"city\": ")],
+            None,
+        );
+        coalesced.append(chunk(vec![tool_call_part(0, None, "\"Paris\"")], None));
+        coalesced.append(chunk(
+            vec![
+                tool_call_part(0, None, "}"),
+                tool_call_part(1, Some("get_time"), "{\"tz\": "),
+            ],
+            None,
+        ));
+        coalesced.append(chunk(
+            vec![tool_call_part(1, None, "\"CET\"}")],
+            Some("tool_calls"),
+        ));
+
+        let choice = &coalesced.choices[0];
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        let calls = &choice.delta.tool_calls;
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id.as_deref(), Some("call-get_weather"));
+        assert_eq!(calls[0].function.name.as_deref(), Some("get_weather"));
+        assert_eq!(calls[0].function.arguments, "{\"city\": \"Paris\"}");
+        assert_eq!(calls[1].function.name.as_deref(), Some("get_time"));
+        assert_eq!(calls[1].function.arguments, "{\"tz\": \"CET\"}");
+        for call in calls {
+            serde_json::from_str::<serde_json::Value>(&call.function.arguments).unwrap();
+        }
+    }
 }
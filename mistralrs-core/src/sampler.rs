@@ -6,7 +6,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use candle_core::{Device, Error, Result, Tensor, D};
+use candle_core::{DType, Device, Error, Result, Tensor, D};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
@@ -15,6 +15,8 @@ use rand_isaac::Isaac64Rng;
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
+use crate::{aici::toktree::TokTrie, response::SamplingParamsUsed};
+
 #[derive(Clone, Debug)]
 /// Stop sequences or ids.
 pub enum StopTokens {
@@ -22,6 +24,28 @@ pub enum StopTokens {
     Ids(Vec<u32>),
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// When the temperature is applied relative to the top-k, typical-p, top-p and min-p truncation.
+pub enum TemperatureOrder {
+    /// Scale the logits by the temperature, then truncate the tempered distribution.
+    #[default]
+    BeforeTruncation,
+    /// Truncate the untempered distribution, then sample from the tempered distribution
+    /// restricted to the tokens which were kept.
+    AfterTruncation,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Which token greedy sampling picks when several tokens share the largest logit, which is common
+/// with quantized models. Breaking ties explicitly keeps greedy outputs reproducible across hardware.
+pub enum GreedyTieBreak {
+    /// Pick the tied token with the lowest id.
+    #[default]
+    LowestId,
+    /// Pick the tied token with the highest id.
+    HighestId,
+}
+
 #[derive(Clone, Debug)]
 /// Sampling params are used to control sampling.
 pub struct SamplingParams {
@@ -29,13 +53,59 @@ pub struct SamplingParams {
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
     pub min_p: Option<f64>,
+    /// Locally typical sampling: keep the tokens whose surprisal is closest to the entropy of the
+    /// distribution, up to this cumulative probability. Applied after top-k and before top-p.
+    pub typical_p: Option<f32>,
+    /// Target surprise (in bits) of Mirostat v2 sampling. If set, Mirostat replaces the top-k,
+    /// typical-p, top-p and min-p truncation, while the temperature still applies.
+    pub mirostat_tau: Option<f32>,
+    /// Learning rate of Mirostat v2 sampling.
+    pub mirostat_eta: f32,
+    /// Multiplier of the DRY ("Don't Repeat Yourself") penalty. DRY is disabled if `None` or 0.
+    pub dry_multiplier: Option<f32>,
+    /// The DRY penalty grows exponentially with this base for each repeated token beyond
+    /// `dry_allowed_length`.
+    pub dry_base: f32,
+    /// The length of the longest repetition which is not penalized by DRY.
+    pub dry_allowed_length: usize,
+    /// DRY does not match repetitions across these strings. Each is tokenized, and its last token
+    /// breaks repetitions.
+    pub dry_sequence_breakers: Vec<String>,
+    pub temperature_order: TemperatureOrder,
+    /// Which token greedy sampling picks when several tokens share the largest logit.
+    pub greedy_tie_break_by: GreedyTieBreak,
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    /// Multiplicative penalty of the tokens in the last `repetition_penalty_range` tokens of the
+    /// context: their positive logits are divided by it and their negative logits multiplied by it.
+    /// Applied before the frequency and presence penalties.
+    pub repetition_penalty: Option<f32>,
+    /// The number of most recent context tokens which `repetition_penalty` applies to, or 0 for the
+    /// whole context.
+    pub repetition_penalty_range: usize,
     pub stop_toks: Option<StopTokens>,
+    /// Token ids which end generation like the model's EOS tokens, for this request only. Useful
+    /// for chat templates with custom end tokens such as `<|im_end|>`.
+    pub stop_token_ids: Option<Vec<u32>>,
+    /// Like `stop_token_ids`, but given as text which must tokenize to a single token.
+    pub stop_token_strings: Option<Vec<String>>,
+    /// Token id sequences which end generation once the generated tokens end with one of them. They
+    /// are matched against the ids directly, so they need not round-trip through text, and can be
+    /// combined with the stop sequences of `stop_toks`.
+    pub stop_token_seqs: Option<Vec<Vec<u32>>>,
     pub max_len: Option<usize>,
+    /// Minimum number of tokens to generate. Until it is reached, the EOS and stop tokens are
+    /// masked out and stop strings are ignored.
+    pub min_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
+    /// Seed for sampling the tokens of this request, for reproducible outputs. Choice `i` is
+    /// sampled with seed `seed + i`. If `None`, the engine's shared RNG is used.
+    pub seed: Option<u64>,
+    /// Never sample the special tokens of the tokenizer, such as the control tokens of the chat
+    /// template, except for the EOS and stop tokens.
+    pub suppress_special_tokens: bool,
 }
 
 impl Default for SamplingParams {
@@ -45,13 +115,33 @@ impl Default for SamplingParams {
             top_k: None,
             top_p: None,
             min_p: None,
+            typical_p: None,
+            mirostat_tau: None,
+            mirostat_eta: 0.1,
+            dry_multiplier: None,
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_sequence_breakers: ["\n", ":", "\"", "*"]
+                .into_iter()
+                .map(ToString::to_string)
+                .collect(),
+            temperature_order: TemperatureOrder::default(),
+            greedy_tie_break_by: GreedyTieBreak::default(),
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
+            repetition_penalty: None,
+            repetition_penalty_range: 0,
             stop_toks: None,
+            stop_token_ids: None,
+            stop_token_strings: None,
+            stop_token_seqs: None,
             max_len: None,
+            min_len: None,
             logits_bias: None,
             n_choices: 1,
+            seed: None,
+            suppress_special_tokens: false,
         }
     }
 }
@@ -68,18 +158,115 @@ impl<T: Fn(&Tensor, &[u32]) -> Result<Tensor> + Send + Sync> CustomLogitsProcess
     }
 }
 
+/// Bans the tokens which would complete an n-gram of `ngram_size` tokens which is already in the
+/// context, so that no n-gram is generated twice.
+#[derive(Clone, Debug)]
+pub struct NoRepeatNGramLogitsProcessor {
+    ngram_size: usize,
+}
+
+impl NoRepeatNGramLogitsProcessor {
+    pub fn new(ngram_size: usize) -> Self {
+        Self { ngram_size }
+    }
+
+    fn banned_tokens(&self, context: &[u32]) -> Vec<u32> {
+        let n = self.ngram_size;
+        if n == 0 || context.len() + 1 < n {
+            return Vec::new();
+        }
+        let prefix = &context[context.len() + 1 - n..];
+        context
+            .windows(n)
+            .filter(|ngram| ngram[..n - 1] == *prefix)
+            .map(|ngram| ngram[n - 1])
+            .collect()
+    }
+}
+
+impl CustomLogitsProcessor for NoRepeatNGramLogitsProcessor {
+    fn apply(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        ban_tokens(logits, &self.banned_tokens(context))
+    }
+}
+
+/// Bans each sequence of token ids in `bad_words_ids`: the last token of a sequence is banned when
+/// the context ends with the tokens before it.
+#[derive(Clone, Debug)]
+pub struct BadWordsLogitsProcessor {
+    bad_words_ids: Vec<Vec<u32>>,
+}
+
+impl BadWordsLogitsProcessor {
+    pub fn new(bad_words_ids: Vec<Vec<u32>>) -> Self {
+        Self { bad_words_ids }
+    }
+
+    fn banned_tokens(&self, context: &[u32]) -> Vec<u32> {
+        self.bad_words_ids
+            .iter()
+            .filter_map(|ids| ids.split_last())
+            .filter(|(_, prefix)| context.ends_with(prefix))
+            .map(|(tok, _)| *tok)
+            .collect()
+    }
+}
+
+impl CustomLogitsProcessor for BadWordsLogitsProcessor {
+    fn apply(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        ban_tokens(logits, &self.banned_tokens(context))
+    }
+}
+
+/// Set the logits of the `banned` tokens to negative infinity.
+fn ban_tokens(logits: &Tensor, banned: &[u32]) -> Result<Tensor> {
+    if banned.is_empty() {
+        return Ok(logits.clone());
+    }
+    let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    for tok in banned {
+        if let Some(logit) = values.get_mut(*tok as usize) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+    Tensor::from_vec(values, logits.dims(), logits.device())?.to_dtype(logits.dtype())
+}
+
+/// The ids of the special tokens of `tokenizer` which are not in `keep`, for
+/// [`SamplingParams::suppress_special_tokens`].
+pub(crate) fn special_tokens_to_suppress(tokenizer: &Tokenizer, keep: &[u32]) -> Vec<u32> {
+    tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .filter(|(id, tok)| tok.special && !keep.contains(id))
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// Temperatures at or below this are treated as greedy sampling by default, because dividing the
+/// logits by them overflows.
+pub const DEFAULT_TEMPERATURE_FLOOR: f64 = 1e-6;
+
 /// Sampler for sampling.
 #[derive(Clone)]
 pub struct Sampler {
     temperature: Option<f64>,
+    temperature_floor: f64,
     top_n_logprobs: usize,
-    tokenizer: Arc<Tokenizer>,
+    tok_trie: Arc<TokTrie>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    repetition_penalty: Option<f32>,
+    repetition_penalty_range: usize,
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    typical_p: f32,
+    temperature_order: TemperatureOrder,
+    greedy_tie_break: GreedyTieBreak,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    logits_bias: Option<HashMap<u32, f32>>,
+    suppressed_tokens: Vec<u32>,
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
@@ -89,19 +276,75 @@ pub struct Sampler {
 pub struct TopLogprob {
     pub token: u32,
     pub logprob: f32,
-    pub bytes: String,
+    /// UTF-8 bytes of the token. A multi-byte character may be split over several tokens.
+    pub bytes: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Logprobs {
     pub token: u32,
     pub logprob: f32,
-    pub bytes: String,
+    pub bytes: Vec<u8>,
     pub top_logprobs: Option<Vec<TopLogprob>>,
 }
 
-fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
-    logits.argmax(D::Minus1)
+/// The index of the largest value, breaking ties by `tie_break`. NaN values are only picked if all
+/// values are NaN.
+fn argmax_with_tie_break(values: &[f32], tie_break: GreedyTieBreak) -> usize {
+    let mut best = 0;
+    for (i, &value) in values.iter().enumerate().skip(1) {
+        let is_better = match tie_break {
+            GreedyTieBreak::LowestId => value > values[best],
+            GreedyTieBreak::HighestId => value >= values[best],
+        };
+        if is_better || (values[best].is_nan() && !value.is_nan()) {
+            best = i;
+        }
+    }
+    best
+}
+
+/// The softmax of `logits / temperature`. The logits are shifted by their maximum first, so that a
+/// small temperature sends the other logits to `-inf` rather than the largest to `inf`, which would
+/// make the probabilities NaN.
+fn tempered_softmax(logits: &Tensor, temperature: f64) -> Result<Tensor> {
+    let max = logits.max_keepdim(D::Minus1)?;
+    candle_nn::ops::softmax_last_dim(&(logits.broadcast_sub(&max)? / temperature)?)
+}
+
+/// Locally typical sampling (Meister et al.): clamp the probabilities to zero, except for the smallest
+/// set of tokens whose cumulative probability reaches `typical_p`, taking the tokens in order of how
+/// close their surprisal is to the entropy of the distribution. Probabilities which were already
+/// zero are ignored, and the others need not be normalized.
+fn truncate_typical_p(probs: &mut [f32], typical_p: f32) {
+    if typical_p <= 0.0 || typical_p >= 1.0 {
+        return;
+    }
+    let total: f32 = probs.iter().sum();
+    let surprisal = |p: f32| -(p / total).ln();
+    let entropy: f32 = probs
+        .iter()
+        .filter(|p| **p > 0.0)
+        .map(|p| p / total * surprisal(*p))
+        .sum();
+
+    let mut indices = (0..probs.len())
+        .filter(|i| probs[*i] > 0.0)
+        .collect::<Vec<_>>();
+    indices.sort_unstable_by(|&i, &j| {
+        let deviation_i = (surprisal(probs[i]) - entropy).abs();
+        let deviation_j = (surprisal(probs[j]) - entropy).abs();
+        deviation_i.partial_cmp(&deviation_j).expect("No ordering.")
+    });
+
+    let mut cumsum = 0.;
+    for index in indices {
+        if cumsum >= typical_p * total {
+            probs[index] = 0.0;
+        } else {
+            cumsum += probs[index];
+        }
+    }
 }
 
 impl Sampler {
@@ -109,32 +352,125 @@ impl Sampler {
     pub fn new(
         temperature: Option<f64>,
         top_n_logprobs: usize,
-        tokenizer: Arc<Tokenizer>,
+        tok_trie: Arc<TokTrie>,
         frequency_penalty: Option<f32>,
         presence_penalty: Option<f32>,
         top_k: i64,
         top_p: f64,
         min_p: f64,
+        typical_p: f32,
+        temperature_order: TemperatureOrder,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> Self {
-        let temperature = if temperature.map_or(true, |v| v < 1e-7) {
-            None
-        } else {
-            temperature
-        };
         Self {
             temperature,
+            temperature_floor: DEFAULT_TEMPERATURE_FLOOR,
             top_n_logprobs,
-            tokenizer,
+            tok_trie,
             frequency_penalty,
             presence_penalty,
             top_k,
             top_p,
             min_p,
+            typical_p,
+            temperature_order,
+            greedy_tie_break: GreedyTieBreak::default(),
             logits_processors,
+            logits_bias: None,
+            suppressed_tokens: Vec::new(),
+            repetition_penalty: None,
+            repetition_penalty_range: 0,
         }
     }
 
+    /// Use greedy sampling for temperatures at or below `temperature_floor`, instead of
+    /// [`DEFAULT_TEMPERATURE_FLOOR`].
+    pub fn with_temperature_floor(mut self, temperature_floor: f64) -> Self {
+        self.temperature_floor = temperature_floor;
+        self
+    }
+
+    /// Break ties between the tokens with the largest logit this way in greedy sampling, instead of
+    /// picking the lowest id.
+    pub fn with_greedy_tie_break(mut self, greedy_tie_break: GreedyTieBreak) -> Self {
+        self.greedy_tie_break = greedy_tie_break;
+        self
+    }
+
+    /// The temperature to sample with, or `None` for greedy sampling. This is `None` for NaN
+    /// temperatures too.
+    fn temperature(&self) -> Option<f64> {
+        self.temperature.filter(|t| *t > self.temperature_floor)
+    }
+
+    /// Add a bias to the logits of the given token ids before the penalties are applied. Ids outside of
+    /// the vocabulary are ignored.
+    pub fn with_logits_bias(mut self, logits_bias: Option<HashMap<u32, f32>>) -> Self {
+        self.logits_bias = logits_bias;
+        self
+    }
+
+    /// Never sample the given token ids, by setting their logits to negative infinity.
+    pub fn with_suppressed_tokens(mut self, suppressed_tokens: Vec<u32>) -> Self {
+        self.suppressed_tokens = suppressed_tokens;
+        self
+    }
+
+    /// Penalize the tokens in the last `range` tokens of the context, or in all of it if `range` is
+    /// 0, by dividing their positive logits by `repetition_penalty` and multiplying their negative
+    /// logits by it.
+    pub fn with_repetition_penalty(
+        mut self,
+        repetition_penalty: Option<f32>,
+        range: usize,
+    ) -> Self {
+        self.repetition_penalty = repetition_penalty;
+        self.repetition_penalty_range = range;
+        self
+    }
+
+    /// The resolved sampling parameters, to report in the response.
+    pub(crate) fn params_used(&self, max_len: Option<usize>, n: usize) -> SamplingParamsUsed {
+        SamplingParamsUsed {
+            temperature: self.temperature(),
+            top_k: usize::try_from(self.top_k).ok(),
+            top_p: self.top_p,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            repetition_penalty: self.repetition_penalty,
+            max_tokens: max_len,
+            n,
+        }
+    }
+
+    /// The untempered logprobs of each token of `toks` after the first, given the logits of the
+    /// preceding positions, with shape `(toks.len(), vocab)`.
+    pub(crate) fn prompt_logprobs(&self, logits: &Tensor, toks: &[u32]) -> Result<Vec<Logprobs>> {
+        let probs: Vec<Vec<f32>> =
+            candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)?.to_vec2()?;
+        zip(&probs, toks.iter().skip(1))
+            .map(|(probs, &token)| {
+                let top_logprobs = if self.top_n_logprobs > 0 {
+                    let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+                    argsort_indices.sort_unstable_by(|&i, &j| {
+                        probs[j].partial_cmp(&probs[i]).expect("No ordering.")
+                    });
+                    self.get_top_logprobs(probs, &argsort_indices)?
+                } else {
+                    Vec::new()
+                };
+                Ok(Logprobs {
+                    token,
+                    logprob: probs[token as usize].log(10.0),
+                    bytes: self.tok_trie.token(token).to_vec(),
+                    top_logprobs: Some(top_logprobs),
+                })
+            })
+            .collect()
+    }
+
     fn get_top_logprobs(
         &self,
         probs: &[f32],
@@ -157,27 +493,18 @@ impl Sampler {
             top_n_toks.push(argsort_indices[val]);
         }
 
-        let mut bytes = Vec::new();
-        for tok in &top_n_toks {
-            bytes.push(
-                self.tokenizer
-                    .decode(&[*tok as u32], false)
-                    .map_err(|x| Error::Msg(x.to_string()))?,
-            );
-        }
-        Ok(zip(bytes, zip(top_n_toks, top_n_logprobs))
-            .map(|(bytes, (token, logprob))| TopLogprob {
+        Ok(zip(top_n_toks, top_n_logprobs)
+            .map(|(token, logprob)| TopLogprob {
                 token: token as u32,
                 logprob,
-                bytes,
+                bytes: self.tok_trie.token(token as u32).to_vec(),
             })
             .collect::<Vec<_>>())
     }
 
     fn sample_argmax(&self, logits: Tensor, return_logprobs: bool) -> Result<Logprobs> {
-        let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
-
         let probs: Vec<f32> = logits.to_vec1()?;
+        let next_token = argmax_with_tie_break(&probs, self.greedy_tie_break) as u32;
 
         let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
         let logprob = probs[next_token as usize].log(10.0);
@@ -192,10 +519,7 @@ impl Sampler {
             token: next_token,
             logprob,
             top_logprobs,
-            bytes: self
-                .tokenizer
-                .decode(&[next_token], false)
-                .map_err(|x| Error::Msg(x.to_string()))?,
+            bytes: self.tok_trie.token(next_token).to_vec(),
         })
     }
 
@@ -204,6 +528,7 @@ impl Sampler {
         logits: Tensor,
         return_logprobs: bool,
         top_k: i64,
+        typical_p: f32,
         top_p: f32,
         min_p: f32,
     ) -> Result<Logprobs> {
@@ -223,6 +548,8 @@ impl Sampler {
             }
         }
 
+        truncate_typical_p(&mut probs, typical_p);
+
         // TOP P
 
         // top-p sampling (or "nucleus sampling") samples from the smallest set of
@@ -253,9 +580,7 @@ impl Sampler {
             }
         }
 
-        let logits = Tensor::from_slice(&probs, logits.shape(), &Device::Cpu)?;
-
-        let next_token = argmax_sample_last_dim(&logits)?.to_scalar::<u32>()?;
+        let next_token = argmax_with_tie_break(&probs, self.greedy_tie_break) as u32;
 
         let logprob = probs[next_token as usize].log(10.0);
 
@@ -269,10 +594,7 @@ impl Sampler {
             token: next_token,
             logprob,
             top_logprobs,
-            bytes: self
-                .tokenizer
-                .decode(&[next_token], false)
-                .map_err(|x| Error::Msg(x.to_string()))?,
+            bytes: self.tok_trie.token(next_token).to_vec(),
         })
     }
 
@@ -299,22 +621,19 @@ impl Sampler {
             token: next_token as u32,
             logprob,
             top_logprobs,
-            bytes: self
-                .tokenizer
-                .decode(&[next_token.try_into().unwrap()], false)
-                .map_err(|x| Error::Msg(x.to_string()))?,
+            bytes: self.tok_trie.token(next_token as u32).to_vec(),
         })
     }
 
-    fn sample_top_kp_min_p(
+    /// Clamp the probabilities of the tokens removed by top-k, typical-p, top-p and min-p to zero,
+    /// returning the indices sorted by descending probability.
+    fn truncate_top_kp_min_p(
         &self,
-        probs: &mut Vec<f32>,
+        probs: &mut [f32],
         top_k: i64,
         top_p: f32,
         min_p: f32,
-        return_logprobs: bool,
-        rng: Arc<Mutex<Isaac64Rng>>,
-    ) -> Result<Logprobs> {
+    ) -> Vec<usize> {
         let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
         // Sort by descending probability.
         argsort_indices
@@ -329,8 +648,10 @@ impl Sampler {
             }
         }
 
+        truncate_typical_p(probs, self.typical_p);
+
         if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+            return argsort_indices;
         }
 
         // TOP P
@@ -350,7 +671,7 @@ impl Sampler {
         }
 
         if min_p <= 0.0 || min_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+            return argsort_indices;
         }
 
         let max_p = probs[argsort_indices[0]];
@@ -367,11 +688,110 @@ impl Sampler {
             }
         }
 
+        argsort_indices
+    }
+
+    fn sample_top_kp_min_p(
+        &self,
+        probs: &mut Vec<f32>,
+        top_k: i64,
+        top_p: f32,
+        min_p: f32,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let argsort_indices = self.truncate_top_kp_min_p(probs, top_k, top_p, min_p);
+
         // Sample with clamped probabilities.
         self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
     }
 
+    /// Truncate the untempered distribution, then sample from the tempered probabilities of the
+    /// tokens which were kept.
+    #[allow(clippy::too_many_arguments)]
+    fn sample_top_kp_min_p_after_temperature(
+        &self,
+        logits: &Tensor,
+        temperature: f64,
+        top_k: i64,
+        top_p: f32,
+        min_p: f32,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let mut probs: Vec<f32> = candle_nn::ops::softmax_last_dim(logits)?.to_vec1()?;
+        let argsort_indices = self.truncate_top_kp_min_p(&mut probs, top_k, top_p, min_p);
+
+        let tempered: Vec<f32> = tempered_softmax(logits, temperature)?.to_vec1()?;
+        for (p, t) in probs.iter_mut().zip(tempered) {
+            if *p > 0.0 {
+                *p = t;
+            }
+        }
+
+        self.sample_multinomial(&mut probs, argsort_indices, return_logprobs, rng)
+    }
+
+    /// Mirostat v2: sample from the tempered probabilities of the tokens whose surprise is at most
+    /// `mu` bits, renormalized. The most likely token is always kept.
+    fn sample_mirostat(
+        &self,
+        logits: &Tensor,
+        temperature: f64,
+        mu: f32,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let mut probs: Vec<f32> = tempered_softmax(logits, temperature)?.to_vec1()?;
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        // Sort by descending probability.
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+
+        for index in argsort_indices.iter().skip(1) {
+            if -probs[*index].log2() > mu {
+                probs[*index] = 0.0;
+            }
+        }
+        let total: f32 = probs.iter().sum();
+        for p in probs.iter_mut() {
+            *p /= total;
+        }
+
+        self.sample_multinomial(&mut probs, argsort_indices, return_logprobs, rng)
+    }
+
     fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
+        if let Some(logits_bias) = &self.logits_bias {
+            for (token_id, bias) in logits_bias {
+                if let Some(logit) = logits.get_mut(*token_id as usize) {
+                    *logit += bias;
+                }
+            }
+        }
+        for token_id in &self.suppressed_tokens {
+            if let Some(logit) = logits.get_mut(*token_id as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        if let Some(penalty) = self.repetition_penalty {
+            let window = match self.repetition_penalty_range {
+                0 => context,
+                range => &context[context.len().saturating_sub(range)..],
+            };
+            let mut in_window = vec![false; logits.len()];
+            for ctx in window {
+                in_window[*ctx as usize] = true;
+            }
+
+            for (logit, _) in zip(&mut logits, in_window).filter(|(_, in_window)| *in_window) {
+                *logit = if *logit > 0.0 {
+                    *logit / penalty
+                } else {
+                    *logit * penalty
+                };
+            }
+        }
         if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
             let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
             let presence_penalty = self.presence_penalty.unwrap_or(0.);
@@ -396,8 +816,17 @@ impl Sampler {
 
     /// Sample the provided tokens.
     ///
-    /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
+    /// If the temperature is `None` or at most the temperature floor, argmax sampling is used, which
+    /// breaks ties between the largest logits by the [`GreedyTieBreak`] of the sampler.
+    /// Otherwise, the selected sampling is used. Argmax sampling is also used if no logit is finite
+    /// after the penalties and logits processors, as the probabilities would be NaN.
     /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
+    ///
+    /// The logits may have any dtype: the penalties and sampling are always computed in f32, so that
+    /// small penalties are not rounded away for models running in bf16 or f16.
+    ///
+    /// If `mirostat_mu` is set, the tokens are sampled with Mirostat v2 instead of the top-k, typical-p,
+    /// top-p and min-p truncation. This does not apply to greedy or speculative sampling.
     pub fn sample(
         &self,
         logits: Tensor,
@@ -405,40 +834,67 @@ impl Sampler {
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
         sample_speculative: bool,
+        mirostat_mu: Option<f32>,
     ) -> Result<Logprobs> {
-        let mut logits = self.apply_penalties(logits.to_vec1()?, context)?;
+        let mut logits = self.apply_penalties(logits.to_dtype(DType::F32)?.to_vec1()?, context)?;
         for processor in &self.logits_processors {
             logits = processor.apply(&logits, context)?;
         }
+        let max_logit = logits.max(D::Minus1)?.to_scalar::<f32>()?;
+        let temperature = self.temperature().filter(|_| max_logit.is_finite());
         let next_token = if sample_speculative {
-            match self.temperature {
+            match temperature {
+                // The logits are not probabilities, so typical-p does not apply.
                 None => self.sample_speculative_top_kp_min_p(
                     logits,
                     return_logprobs,
                     self.top_k,
+                    1.0,
                     self.top_p as f32,
                     self.min_p as f32,
                 )?,
                 Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
+                    let probs = match self.temperature_order {
+                        TemperatureOrder::BeforeTruncation => {
+                            tempered_softmax(&logits, temperature)?
+                        }
+                        // The tokens kept and the argmax do not depend on the temperature here.
+                        TemperatureOrder::AfterTruncation => {
+                            candle_nn::ops::softmax_last_dim(&logits)?
+                        }
+                    };
 
                     self.sample_speculative_top_kp_min_p(
                         probs,
                         return_logprobs,
                         self.top_k,
+                        self.typical_p,
                         self.top_p as f32,
                         self.min_p as f32,
                     )?
                 }
             }
         } else {
-            match self.temperature {
-                None => self.sample_argmax(logits, return_logprobs)?,
-                Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
-                    let mut probs: Vec<f32> = probs.to_vec1()?;
+            match (temperature, mirostat_mu) {
+                (None, _) => self.sample_argmax(logits, return_logprobs)?,
+                (Some(temperature), Some(mu)) => {
+                    self.sample_mirostat(&logits, temperature, mu, return_logprobs, rng)?
+                }
+                (Some(temperature), None)
+                    if self.temperature_order == TemperatureOrder::AfterTruncation =>
+                {
+                    self.sample_top_kp_min_p_after_temperature(
+                        &logits,
+                        temperature,
+                        self.top_k,
+                        self.top_p as f32,
+                        self.min_p as f32,
+                        return_logprobs,
+                        rng,
+                    )?
+                }
+                (Some(temperature), None) => {
+                    let mut probs: Vec<f32> = tempered_softmax(&logits, temperature)?.to_vec1()?;
 
                     self.sample_top_kp_min_p(
                         &mut probs,
@@ -472,9 +928,52 @@ mod tests {
         Tokenizer::from_file(tokenizer_filename).unwrap()
     }
 
+    #[test]
+    fn no_repeat_ngram_prevents_repeated_bigrams() {
+        use super::{CustomLogitsProcessor, NoRepeatNGramLogitsProcessor};
+        use candle_core::{Device, Tensor};
+        use std::collections::HashSet;
+
+        let processor = NoRepeatNGramLogitsProcessor::new(2);
+        // Greedy decoding would alternate between tokens 1 and 2 forever without the processor.
+        let mut context = vec![1u32, 2];
+        for _ in 0..10 {
+            let favored = if *context.last().unwrap() == 1 { 2 } else { 1 };
+            let logits = (0..16)
+                .map(|tok| if tok == favored { 10. } else { -(tok as f32) })
+                .collect::<Vec<_>>();
+            let logits = Tensor::from_vec(logits, 16, &Device::Cpu).unwrap();
+            let logits = processor.apply(&logits, &context).unwrap();
+            context.push(logits.argmax(0).unwrap().to_scalar::<u32>().unwrap());
+        }
+        let mut bigrams = HashSet::new();
+        for bigram in context.windows(2) {
+            assert!(bigrams.insert(bigram), "{bigram:?} repeated in {context:?}");
+        }
+    }
+
+    #[test]
+    fn bad_words_are_banned_after_their_prefix() {
+        use super::{BadWordsLogitsProcessor, CustomLogitsProcessor};
+        use candle_core::{Device, Tensor};
+
+        let processor = BadWordsLogitsProcessor::new(vec![vec![3], vec![1, 2], vec![]]);
+        let logits = Tensor::zeros(4, candle_core::DType::F32, &Device::Cpu).unwrap();
+        let banned = |context: &[u32]| {
+            let logits = processor.apply(&logits, context).unwrap();
+            let logits = logits.to_vec1::<f32>().unwrap();
+            (0..4u32)
+                .filter(|tok| logits[*tok as usize].is_infinite())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(banned(&[0]), vec![3]);
+        assert_eq!(banned(&[0, 1]), vec![2, 3]);
+    }
+
     #[test]
     fn test_argmax() {
         use super::Sampler;
+        use crate::aici::bintokens::build_tok_trie;
         use candle_core::{Device, Tensor};
         use rand::SeedableRng;
         use rand_isaac::Isaac64Rng;
@@ -484,18 +983,27 @@ mod tests {
         let sampler = Sampler::new(
             None,
             10,
-            get_tokenizer().into(),
+            Arc::new(build_tok_trie(get_tokenizer())),
             None,
             None,
             32,
             0.1,
             0.05,
+            1.0,
+            super::TemperatureOrder::default(),
             vec![],
         );
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
-            .sample(logits, &(0..1024).collect::<Vec<_>>(), false, rng, false)
+            .sample(
+                logits,
+                &(0..1024).collect::<Vec<_>>(),
+                false,
+                rng,
+                false,
+                None,
+            )
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
@@ -505,6 +1013,7 @@ mod tests {
     #[test]
     fn test_gumbel_speculative() {
         use super::Sampler;
+        use crate::aici::bintokens::build_tok_trie;
         use candle_core::{Device, Tensor};
         use rand::SeedableRng;
         use rand_isaac::Isaac64Rng;
@@ -514,21 +1023,412 @@ mod tests {
         let sampler = Sampler::new(
             None,
             10,
-            get_tokenizer().into(),
+            Arc::new(build_tok_trie(get_tokenizer())),
             None,
             None,
             32,
             0.1,
             0.05,
+            1.0,
+            super::TemperatureOrder::default(),
             vec![],
         );
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
-            .sample(logits, &(0..1024).collect::<Vec<_>>(), false, rng, true)
+            .sample(
+                logits,
+                &(0..1024).collect::<Vec<_>>(),
+                false,
+                rng,
+                true,
+                None,
+            )
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_temperature_order() {
+        use super::{Sampler, TemperatureOrder};
+        use crate::aici::bintokens::build_tok_trie;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // Untempered probs: [0.644, 0.237, 0.087, 0.032], so top-p 0.9 drops token 3.
+        // With temperature 2: [0.455, 0.276, 0.167, 0.102], so top-p 0.9 keeps every token.
+        let count_last_token = |order| {
+            let sampler = Sampler::new(
+                Some(2.0),
+                0,
+                Arc::new(build_tok_trie(get_tokenizer())),
+                None,
+                None,
+                -1,
+                0.9,
+                0.0,
+                1.0,
+                order,
+                vec![],
+            );
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+            (0..1000)
+                .filter(|_| {
+                    let logits = Tensor::new(&[3f32, 2., 1., 0.], &Device::Cpu).unwrap();
+                    let res = sampler
+                        .sample(logits, &[], false, rng.clone(), false, None)
+                        .unwrap();
+                    res.token == 3
+                })
+                .count()
+        };
+
+        assert!(count_last_token(TemperatureOrder::BeforeTruncation) > 0);
+        assert_eq!(count_last_token(TemperatureOrder::AfterTruncation), 0);
+    }
+
+    #[test]
+    fn test_frequency_penalty_bf16() {
+        use super::{Sampler, TemperatureOrder};
+        use crate::aici::bintokens::build_tok_trie;
+        use candle_core::{DType, Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // In bf16, 100.0 - 0.1 rounds back to 100.0, so the penalty would not change anything.
+        let logits = Tensor::new(&[100f32, 100f32], &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::BF16)
+            .unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let sample = |frequency_penalty| {
+            Sampler::new(
+                None,
+                0,
+                Arc::new(build_tok_trie(get_tokenizer())),
+                frequency_penalty,
+                None,
+                -1,
+                1.0,
+                0.0,
+                1.0,
+                TemperatureOrder::default(),
+                vec![],
+            )
+            .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+            .unwrap()
+            .token
+        };
+        assert_eq!(sample(None), 0);
+        assert_eq!(sample(Some(0.1)), 1);
+    }
+
+    #[test]
+    fn test_prompt_logprobs() {
+        use super::{Sampler, TemperatureOrder};
+        use crate::aici::bintokens::build_tok_trie;
+        use candle_core::{Device, Tensor};
+        use std::sync::Arc;
+
+        let sampler = Sampler::new(
+            Some(0.5),
+            2,
+            Arc::new(build_tok_trie(get_tokenizer())),
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            TemperatureOrder::default(),
+            vec![],
+        );
+        // The logits of a forward pass over the prompt `0 1 3`: row `i` predicts token `i + 1`.
+        let rows = [[1f32, 2., 0., 0.], [0., 0., 3., 1.], [0., 0., 0., 0.]];
+        let logits = Tensor::new(&rows, &Device::Cpu).unwrap();
+        let logprobs = sampler.prompt_logprobs(&logits, &[0, 1, 3]).unwrap();
+
+        let log_softmax = |row: &[f32], tok: usize| {
+            let norm = row.iter().map(|x| x.exp()).sum::<f32>().ln();
+            (row[tok] - norm) / 10f32.ln()
+        };
+        assert_eq!(logprobs.len(), 2);
+        assert_eq!(logprobs[0].token, 1);
+        assert_eq!(logprobs[1].token, 3);
+        // The temperature does not apply to the prompt.
+        let sum = logprobs.iter().map(|l| l.logprob).sum::<f32>();
+        let expected = log_softmax(&rows[0], 1) + log_softmax(&rows[1], 3);
+        assert!((sum - expected).abs() < 1e-5);
+
+        let top = logprobs[1].top_logprobs.as_ref().unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].token, 2);
+        assert_eq!(top[1].token, 3);
+    }
+
+    #[test]
+    fn test_logits_bias_reduces_frequency() {
+        use super::Sampler;
+        use super::TemperatureOrder;
+        use crate::aici::bintokens::build_tok_trie;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::collections::HashMap;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let tokenizer = get_tokenizer();
+        let the = tokenizer.encode("the", false).unwrap().get_ids()[0];
+        let vocab_size = tokenizer.get_vocab_size(true);
+        let tok_trie = Arc::new(build_tok_trie(tokenizer));
+        let new_sampler = || {
+            Sampler::new(
+                Some(1.0),
+                0,
+                tok_trie.clone(),
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                1.0,
+                TemperatureOrder::default(),
+                vec![],
+            )
+        };
+        let count_the = |sampler: &Sampler| {
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+            (0..200)
+                .filter(|_| {
+                    let mut logits = vec![0f32; vocab_size];
+                    logits[the as usize] = 10.0;
+                    let logits = Tensor::new(logits, &Device::Cpu).unwrap();
+                    let res = sampler
+                        .sample(logits, &[], false, rng.clone(), false, None)
+                        .unwrap();
+                    res.token == the
+                })
+                .count()
+        };
+
+        let unbiased = count_the(&new_sampler());
+        let biased = count_the(&new_sampler().with_logits_bias(Some(HashMap::from([(the, -5.0)]))));
+        assert!(biased < unbiased, "{biased} >= {unbiased}");
+    }
+
+    #[test]
+    fn test_suppress_special_tokens() {
+        use super::{special_tokens_to_suppress, Sampler, TemperatureOrder};
+        use crate::aici::bintokens::build_tok_trie;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+        use tokenizers::AddedToken;
+
+        let mut tokenizer = get_tokenizer();
+        tokenizer.add_special_tokens(&[AddedToken::from("<|im_start|>", true)]);
+        let im_start = tokenizer.token_to_id("<|im_start|>").unwrap();
+        let eos = tokenizer.token_to_id("</s>").unwrap();
+        let vocab_size = tokenizer.get_vocab_size(true);
+
+        let suppressed = special_tokens_to_suppress(&tokenizer, &[eos]);
+        assert!(suppressed.contains(&im_start));
+        assert!(!suppressed.contains(&eos));
+
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            Arc::new(build_tok_trie(tokenizer.clone())),
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            TemperatureOrder::default(),
+            vec![],
+        )
+        .with_suppressed_tokens(suppressed);
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let mut output = Vec::new();
+        for _ in 0..50 {
+            let mut logits = vec![0f32; vocab_size];
+            logits[im_start as usize] = 20.0;
+            let logits = Tensor::new(logits, &Device::Cpu).unwrap();
+            let res = sampler
+                .sample(logits, &output, false, rng.clone(), false, None)
+                .unwrap();
+            output.push(res.token);
+        }
+        assert!(!output.contains(&im_start));
+        let text = tokenizer.decode(&output, false).unwrap();
+        assert!(!text.contains("<|im_start|>"), "{text}");
+    }
+
+    #[test]
+    fn test_repetition_penalty_range() {
+        use super::{Sampler, TemperatureOrder};
+        use crate::aici::bintokens::build_tok_trie;
+        use std::sync::Arc;
+
+        let tok_trie = Arc::new(build_tok_trie(get_tokenizer()));
+        let new_sampler = |frequency_penalty, range| {
+            Sampler::new(
+                None,
+                0,
+                tok_trie.clone(),
+                frequency_penalty,
+                None,
+                -1,
+                1.0,
+                0.0,
+                1.0,
+                TemperatureOrder::default(),
+                vec![],
+            )
+            .with_repetition_penalty(Some(2.0), range)
+        };
+        let penalized = |sampler: Sampler| {
+            sampler
+                .apply_penalties(vec![1.0, 1.0, -1.0, 1.0], &[0, 1, 2, 2])
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+        };
+
+        // Only the last 3 tokens are in the window, and repeated tokens are penalized once.
+        assert_eq!(penalized(new_sampler(None, 3)), [1.0, 0.5, -2.0, 1.0]);
+        assert_eq!(penalized(new_sampler(None, 0)), [0.5, 0.5, -2.0, 1.0]);
+        // The frequency penalty then applies to the whole context.
+        assert_eq!(penalized(new_sampler(Some(0.5), 3)), [0.5, 0.0, -3.0, 1.0]);
+    }
+
+    #[test]
+    fn test_typical_p() {
+        use super::truncate_typical_p;
+
+        // Surprisals (nats): [0.693, 1.386, 2.079, 2.079], entropy 1.213. Sorted by distance to the
+        // entropy: token 1 (0.173), token 0 (0.520), then tokens 2 and 3 (0.866).
+        let probs = [0.5f32, 0.25, 0.125, 0.125];
+
+        let mut truncated = probs;
+        truncate_typical_p(&mut truncated, 0.5);
+        assert_eq!(truncated, [0.5, 0.25, 0.0, 0.0]);
+
+        // The most likely token is not the most typical one.
+        let mut truncated = probs;
+        truncate_typical_p(&mut truncated, 0.2);
+        assert_eq!(truncated, [0.0, 0.25, 0.0, 0.0]);
+
+        // Zeroed probabilities, e.g. by top-k, are ignored and the rest is not normalized.
+        let mut truncated = [0.0f32, 0.3, 0.1, 0.1];
+        truncate_typical_p(&mut truncated, 0.4);
+        assert_eq!(truncated, [0.0, 0.3, 0.0, 0.0]);
+
+        let mut truncated = probs;
+        truncate_typical_p(&mut truncated, 1.0);
+        assert_eq!(truncated, probs);
+    }
+
+    #[test]
+    fn greedy_ties_are_broken_by_token_id() {
+        use super::{argmax_with_tie_break, GreedyTieBreak};
+
+        let tied = [0.5f32, 2.0, -1.0, 2.0, 2.0, 1.0];
+        assert_eq!(argmax_with_tie_break(&tied, GreedyTieBreak::LowestId), 1);
+        assert_eq!(argmax_with_tie_break(&tied, GreedyTieBreak::HighestId), 4);
+
+        let first_nan = [f32::NAN, 1.0, 1.0];
+        assert_eq!(
+            argmax_with_tie_break(&first_nan, GreedyTieBreak::LowestId),
+            1
+        );
+        assert_eq!(
+            argmax_with_tie_break(&first_nan, GreedyTieBreak::HighestId),
+            2
+        );
+
+        let masked = [f32::NEG_INFINITY; 3];
+        assert_eq!(argmax_with_tie_break(&masked, GreedyTieBreak::LowestId), 0);
+        assert_eq!(argmax_with_tie_break(&masked, GreedyTieBreak::HighestId), 2);
+    }
+
+    #[test]
+    fn test_temperature_floor() {
+        use super::{Sampler, TemperatureOrder};
+        use crate::aici::bintokens::build_tok_trie;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let tok_trie = Arc::new(build_tok_trie(get_tokenizer()));
+        let new_sampler = |temperature| {
+            Sampler::new(
+                Some(temperature),
+                0,
+                tok_trie.clone(),
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                1.0,
+                TemperatureOrder::default(),
+                vec![],
+            )
+        };
+        let sample = |sampler: &Sampler, logits: &[f32], seed| {
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(seed)));
+            let logits = Tensor::new(logits, &Device::Cpu).unwrap();
+            sampler
+                .sample(logits, &[], false, rng, false, None)
+                .unwrap()
+        };
+
+        // A temperature of 0 is greedy.
+        let sampler = new_sampler(0.0);
+        assert_eq!(sampler.params_used(None, 1).temperature, None);
+        assert!((0..10).all(|seed| sample(&sampler, &[1., 3., 2., 0.], seed).token == 1));
+
+        // A tiny temperature above the floor would overflow the scaled logits without the shift.
+        let sampler = new_sampler(1e-5);
+        assert_eq!(sampler.params_used(None, 1).temperature, Some(1e-5));
+        for seed in 0..10 {
+            let res = sample(&sampler, &[1., 3., 2., 0.], seed);
+            assert_eq!(res.token, 1);
+            assert!(res.logprob.is_finite());
+        }
+        // Raising the floor makes it greedy.
+        let sampler = new_sampler(1e-5).with_temperature_floor(1e-4);
+        assert_eq!(sampler.params_used(None, 1).temperature, None);
+
+        // Every token but one is masked.
+        let masked = [
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+            -20.,
+            f32::NEG_INFINITY,
+        ];
+        for temperature in [0.0, 1e-5, 0.7, 2.0] {
+            let sampler = new_sampler(temperature);
+            for seed in 0..10 {
+                let res = sample(&sampler, &masked, seed);
+                assert_eq!(res.token, 2);
+                if temperature > 0.0 {
+                    assert_eq!(res.logprob, 0.0);
+                }
+            }
+        }
+    }
 }
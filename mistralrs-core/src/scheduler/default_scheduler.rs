@@ -11,6 +11,7 @@ use crate::{
 };
 
 use super::{Scheduler, SchedulerOutput};
+use tracing::{info, warn};
 
 pub trait FcfsBacker: Default {
     fn new() -> Self;
@@ -68,9 +69,17 @@ pub trait BucketingManager<Backer: FcfsBacker> {
     ) -> BucketedSeqs<Backer>;
 }
 
-// (adapters, cache length, (has_imgs && is_prompt))
+// (adapters, cache length, (has_imgs && is_prompt), early exit layer, bits of the X-LoRA global scaling,
+// cache length kept by a rolled context)
 // Buckey by that metric for images because if we are not a prompt, then this doesn't apply
-type BucketKey = (Option<Vec<String>>, usize, bool);
+type BucketKey = (
+    Option<Vec<String>>,
+    usize,
+    bool,
+    Option<usize>,
+    Option<u64>,
+    Option<usize>,
+);
 
 struct FixedBucketingManager;
 
@@ -93,6 +102,9 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                 seq.get_adapters(),
                 len,
                 seq.images().is_some() && seq.is_prompt(),
+                seq.early_exit_layer(),
+                seq.xlora_global_scaling().map(f64::to_bits),
+                seq.rolled_cache_len(),
             )) {
                 Some(bucket) => {
                     if !discrete {
@@ -101,6 +113,9 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                                 seq.get_adapters(),
                                 len,
                                 seq.images().is_some() && seq.is_prompt(),
+                                seq.early_exit_layer(),
+                                seq.xlora_global_scaling().map(f64::to_bits),
+                                seq.rolled_cache_len(),
                             ))
                             .unwrap() += seq.compute_priority();
                     }
@@ -113,6 +128,9 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                                 seq.get_adapters(),
                                 len,
                                 seq.images().is_some() && seq.is_prompt(),
+                                seq.early_exit_layer(),
+                                seq.xlora_global_scaling().map(f64::to_bits),
+                                seq.rolled_cache_len(),
                             ),
                             seq.compute_priority(),
                         );
@@ -122,6 +140,9 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                             seq.get_adapters(),
                             len,
                             seq.images().is_some() && seq.is_prompt(),
+                            seq.early_exit_layer(),
+                            seq.xlora_global_scaling().map(f64::to_bits),
+                            seq.rolled_cache_len(),
                         ),
                         vec![seq],
                     );
@@ -140,7 +161,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
             // Allow the min seqs to catch up.
             let min = seq_buckets
                 .keys()
-                .min_by_key(|(_, x, _)| *x)
+                .min_by_key(|(_, x, _, _, _, _)| *x)
                 .expect("No sequence buckets.")
                 .clone();
             let len = if !discrete {
@@ -242,9 +263,12 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                         .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
                     TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
                 }
+                // Sequences whose context was rolled run a new prompt step.
+                let (completion, prompt): (Vec<_>, Vec<_>) =
+                    self.running.iter_mut().partition(|seq| !seq.is_prompt());
                 return DefaultSchedulerOutput {
-                    prompt: vec![].into(),
-                    completion: self.running.iter_mut().collect::<Vec<_>>().into(),
+                    prompt: prompt.into(),
+                    completion: completion.into(),
                 };
             }
             _ => {}
@@ -308,6 +332,9 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn waiting_len(&self) -> usize {
         self.waiting.len()
     }
+    fn running_len(&self) -> usize {
+        self.running.len()
+    }
     fn add_seq(&mut self, seq: Sequence) {
         if seq.is_running() {
             // prefill case
@@ -316,6 +343,20 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
             self.waiting.add(seq);
         }
     }
+    fn add_shared_prefill_seq(&mut self, mut seq: Sequence, leader: &Sequence) {
+        match seq.share_prefill(leader) {
+            Ok(true) => (),
+            Ok(false) => info!(
+                "Could not share the prefill of sequence {}, running a separate prefill.",
+                leader.id()
+            ),
+            Err(e) => warn!(
+                "Sharing the prefill of sequence {} failed, running a separate prefill: {e}",
+                leader.id()
+            ),
+        }
+        self.add_seq(seq);
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         None
     }
@@ -323,6 +364,22 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
         None
     }
     fn free_finished_sequence_groups(&mut self) {}
+    fn seq_mut(&mut self, id: usize) -> Option<&mut Sequence> {
+        self.running
+            .iter_mut()
+            .chain(self.waiting.iter_mut())
+            .find(|seq| *seq.id() == id)
+    }
+    fn cancel_request(&mut self, request_id: usize) -> bool {
+        let mut found = false;
+        for seq in self.running.iter_mut().chain(self.waiting.iter_mut()) {
+            if seq.request_id() == request_id {
+                seq.cancel();
+                found = true;
+            }
+        }
+        found
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         None
     }
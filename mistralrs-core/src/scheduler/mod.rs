@@ -17,6 +17,9 @@ pub enum SchedulerConfig {
     },
     PagedAttentionMeta {
         max_num_seqs: usize,
+        /// The maximum number of prompt tokens of a prompt step, see
+        /// [`PagedAttentionSchedulerConfig::max_num_batched_tokens`].
+        max_num_batched_tokens: Option<usize>,
         config: CacheConfig,
     },
 }
@@ -27,9 +30,13 @@ impl SchedulerConfig {
             Self::DefaultScheduler { method } => Box::new(DefaultScheduler::new(method)),
             Self::PagedAttentionMeta {
                 max_num_seqs,
+                max_num_batched_tokens,
                 config,
             } => Box::new(PagedAttentionScheduler::new(
-                PagedAttentionSchedulerConfig { max_num_seqs },
+                PagedAttentionSchedulerConfig {
+                    max_num_seqs,
+                    max_num_batched_tokens,
+                },
                 config,
             )),
         }
@@ -48,9 +55,20 @@ pub enum SchedulerOutput<'a> {
 pub trait Scheduler {
     fn schedule(&mut self) -> SchedulerOutput<'_>;
     fn waiting_len(&self) -> usize;
+    fn running_len(&self) -> usize;
     fn add_seq(&mut self, seq: Sequence);
+    /// Add `seq`, which has the same prompt as `leader` whose prompt step just ran, so that it
+    /// starts decoding from the KV cache of `leader` instead of running its own prefill if possible.
+    fn add_shared_prefill_seq(&mut self, seq: Sequence, leader: &Sequence);
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
+    /// The running or waiting sequence with the given id. This is `None` for schedulers which do
+    /// not own their sequences' KV caches.
+    fn seq_mut(&mut self, id: usize) -> Option<&mut Sequence>;
+    /// Cancel the running, waiting or swapped out sequences of the request with the given id. They
+    /// finish after their next step and are then freed like any finished sequence. Returns whether
+    /// any sequence was found.
+    fn cancel_request(&mut self, request_id: usize) -> bool;
 
     // PagedAttention metadata
     fn block_tables(&self) -> Option<&BlockTables>;
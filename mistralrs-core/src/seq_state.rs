@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use candle_core::{DType, Device, Tensor};
+
+use crate::pipeline::LayerCaches;
+
+const TOKENS_KEY: &str = "tokens";
+
+/// The tokens and KV cache of a sequence, as exported by
+/// [`Request::ExportSeqState`](crate::Request::ExportSeqState).
+///
+/// It is serialized in the safetensors format: the token ids are stored as a `u32` tensor and each
+/// cached layer as a key and a value tensor, which carry their dtype and shape.
+pub(crate) struct SeqState {
+    pub(crate) toks: Vec<u32>,
+    pub(crate) cache: LayerCaches,
+    pub(crate) xlora_cache: Option<LayerCaches>,
+}
+
+fn cache_keys(prefix: &str, layer: usize) -> (String, String) {
+    (format!("{prefix}.{layer}.k"), format!("{prefix}.{layer}.v"))
+}
+
+fn add_cache(tensors: &mut Vec<(String, Tensor)>, prefix: &str, cache: &LayerCaches) {
+    for (layer, kv) in cache.iter().enumerate() {
+        if let Some((k, v)) = kv {
+            let (k_key, v_key) = cache_keys(prefix, layer);
+            tensors.push((k_key, k.clone()));
+            tensors.push((v_key, v.clone()));
+        }
+    }
+}
+
+fn take_cache(
+    tensors: &mut HashMap<String, Tensor>,
+    prefix: &str,
+    num_layers: usize,
+    dtype: DType,
+) -> Result<LayerCaches> {
+    let mut cache = Vec::with_capacity(num_layers);
+    for layer in 0..num_layers {
+        let (k_key, v_key) = cache_keys(prefix, layer);
+        match (tensors.remove(&k_key), tensors.remove(&v_key)) {
+            (Some(k), Some(v)) => {
+                if k.dtype() != dtype || v.dtype() != dtype {
+                    bail!(
+                        "The KV cache of layer {layer} has dtype {:?}, but the model uses {dtype:?}.",
+                        k.dtype()
+                    );
+                }
+                cache.push(Some((k, v)));
+            }
+            (None, None) => cache.push(None),
+            _ => bail!("The KV cache of layer {layer} is missing its key or value."),
+        }
+    }
+    Ok(cache)
+}
+
+impl SeqState {
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut tensors = vec![(
+            TOKENS_KEY.to_string(),
+            Tensor::new(self.toks.as_slice(), &Device::Cpu)?,
+        )];
+        add_cache(&mut tensors, "cache", &self.cache);
+        if let Some(xlora_cache) = &self.xlora_cache {
+            add_cache(&mut tensors, "xlora_cache", xlora_cache);
+        }
+        Ok(safetensors::serialize(tensors, &None)?)
+    }
+
+    /// Load a state exported from a model with `num_layers` layers whose KV cache has type `dtype`.
+    pub(crate) fn from_bytes(
+        data: &[u8],
+        device: &Device,
+        num_layers: usize,
+        dtype: DType,
+        is_xlora: bool,
+    ) -> Result<Self> {
+        let mut tensors = candle_core::safetensors::load_buffer(data, device)?;
+        let toks = tensors
+            .remove(TOKENS_KEY)
+            .context("The sequence state has no tokens.")?
+            .to_vec1::<u32>()?;
+        let cache = take_cache(&mut tensors, "cache", num_layers, dtype)?;
+        let xlora_cache = if is_xlora {
+            Some(take_cache(&mut tensors, "xlora_cache", num_layers, dtype)?)
+        } else {
+            None
+        };
+        if let Some(name) = tensors.keys().next() {
+            bail!("Unexpected tensor `{name}` in the sequence state, was it exported from a different model?");
+        }
+        if cache.iter().all(Option::is_none) {
+            bail!("The sequence state has no KV cache.");
+        }
+        Ok(Self {
+            toks,
+            cache,
+            xlora_cache,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use crate::pipeline::{Cache, KvCacheDtype};
+
+    use super::SeqState;
+
+    #[test]
+    fn round_trip() {
+        let dev = Device::Cpu;
+        let k = Tensor::arange(0f32, 24., &dev)
+            .unwrap()
+            .reshape((1, 2, 3, 4))
+            .unwrap();
+        let v = (&k * 2.).unwrap();
+        let state = SeqState {
+            toks: vec![1, 2, 3, 4],
+            cache: vec![Some((k.clone(), v.clone())), None],
+            xlora_cache: None,
+        };
+        let bytes = state.to_bytes().unwrap();
+
+        let restored = SeqState::from_bytes(&bytes, &dev, 2, DType::F32, false).unwrap();
+        assert_eq!(restored.toks, state.toks);
+        assert!(restored.cache[1].is_none());
+        let (rk, rv) = restored.cache[0].as_ref().unwrap();
+        assert_eq!(rk.dims(), k.dims());
+        assert_eq!(
+            rv.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            v.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        );
+
+        assert!(SeqState::from_bytes(&bytes, &dev, 2, DType::F16, false).is_err());
+        // A layer beyond the model's layers is left over.
+        assert!(SeqState::from_bytes(&bytes, &dev, 0, DType::F32, false).is_err());
+    }
+
+    #[test]
+    fn round_trip_q8() {
+        let dev = Device::Cpu;
+        let k = Tensor::arange(0f32, 24., &dev)
+            .unwrap()
+            .reshape((1, 2, 3, 4))
+            .unwrap();
+        let v = (&k * -2.).unwrap();
+        let mut layer = None;
+        Cache::update_kv_cache(&mut layer, KvCacheDtype::Q8, k, v, false).unwrap();
+        let (qk, qv) = layer.clone().unwrap();
+        assert_eq!(qk.dtype(), DType::U8);
+        let state = SeqState {
+            toks: vec![1, 2, 3],
+            cache: vec![layer],
+            xlora_cache: None,
+        };
+        let bytes = state.to_bytes().unwrap();
+
+        let dtype = KvCacheDtype::Q8.storage_dtype(DType::F32);
+        let restored = SeqState::from_bytes(&bytes, &dev, 1, dtype, false).unwrap();
+        assert_eq!(restored.toks, state.toks);
+        let (rk, rv) = restored.cache[0].as_ref().unwrap();
+        assert_eq!(rk.dims(), &[1, 2, 3, 5]);
+        for (restored, quantized) in [(rk, &qk), (rv, &qv)] {
+            assert_eq!(
+                restored.flatten_all().unwrap().to_vec1::<u8>().unwrap(),
+                quantized.flatten_all().unwrap().to_vec1::<u8>().unwrap()
+            );
+        }
+
+        // The state of a Q8 cache does not fit a model with an unquantized cache.
+        let dtype = KvCacheDtype::F16.storage_dtype(DType::F32);
+        assert!(SeqState::from_bytes(&bytes, &dev, 1, dtype, false).is_err());
+    }
+}
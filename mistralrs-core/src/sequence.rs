@@ -1,7 +1,7 @@
 use std::{
     fmt::Display,
     sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
     mpsc::{error::SendError, Sender},
@@ -9,21 +9,30 @@ use tokio::sync::{
 };
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
+    aici::{
+        cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, svob::SimpleVob, toktree::TokTrie,
+    },
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
-    response::CompletionChoice,
-    tools::ToolCallingMatcher,
+    response::{CompletionChoice, ResponseLogprob},
+    tools::{ToolCallDelta, ToolCallStream, ToolCallingMatcher},
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse,
 };
 use crate::{
     get_mut_group,
-    pipeline::LayerCaches,
-    response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
+    pipeline::{DryPenalty, LayerCaches, MirostatState, RollingCacheConfig},
+    response::{
+        ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SamplingParamsUsed,
+        SYSTEM_FINGERPRINT,
+    },
     sampler::{Logprobs, Sampler},
     ChatCompletionResponse, Usage,
 };
 use candle_core::Tensor;
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use regex_automata::util::primitives::StateID;
+use tokenizers::Tokenizer;
+use tracing::Span;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum StopReason {
@@ -35,6 +44,10 @@ pub enum StopReason {
         stop_string_idx: usize,
         completion_bytes_pos: usize,
     },
+    StopTokSeq {
+        stop_seq_idx: usize,
+        completion_bytes_pos: usize,
+    },
     Canceled,
 }
 
@@ -43,7 +56,9 @@ impl Display for StopReason {
         match self {
             StopReason::Eos => write!(f, "stop"),
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
-            StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
+            StopReason::StopTok(_)
+            | StopReason::StopString { .. }
+            | StopReason::StopTokSeq { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
         }
     }
@@ -152,6 +167,7 @@ pub struct Sequence {
     sampler: Arc<Sampler>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
+    stop_token_seqs: Vec<Vec<u32>>,
     return_logprobs: bool,
     responder: Sender<Response>,
     response_index: usize,
@@ -159,8 +175,34 @@ pub struct Sequence {
     prefill_prompt_toks: Option<Vec<u32>>,
     suffix: Option<String>,
     prefix: Option<String>,
+    // Whether the echoed `prefix` was already streamed.
+    echo_streamed: bool,
     is_tmp: bool,
     adapters: Option<Vec<String>>,
+    early_exit_layer: Option<usize>,
+    xlora_global_scaling: Option<f64>,
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
+    min_len: Option<usize>,
+    eos_tokens: Vec<u32>,
+    // Tokens which end this sequence like the model's EOS tokens, given by the request.
+    request_eos_tokens: Vec<u32>,
+    // Stop strings are only searched from this position of the completion bytes.
+    stop_strings_from: usize,
+    // The rolling context and the model's maximum sequence length.
+    rolling_cache: Option<(RollingCacheConfig, usize)>,
+    // Tokens which the rolling context evicted from `tokens`. They still count towards the usage.
+    evicted_prompt_toks: usize,
+    evicted_completion_toks: usize,
+    // The number of positions kept in the KV cache by the rolling context, until the rest of the
+    // context is recomputed by a prompt step.
+    rolled_cache_len: Option<usize>,
+    mirostat: Option<MirostatState>,
+    dry_penalty: Option<Arc<DryPenalty>>,
+    return_prompt_logprobs: bool,
+    prompt_logprobs: Option<Vec<Logprobs>>,
+    // The `id` of the `NormalRequest` which created this sequence.
+    request_id: usize,
+    canceled: bool,
     pub(crate) tok_trie: TokTrie,
 
     // Cache
@@ -193,6 +235,14 @@ pub struct Sequence {
 
     // Tool calls
     pub tools: Option<Arc<ToolCallingMatcher>>,
+    tool_call_stream: Option<ToolCallStream>,
+
+    // Shared prefill of `n_choices`
+    shared_prefill_followers: Vec<Sequence>,
+    shares_prefill: bool,
+
+    // Token healing: the bytes of the prompt token removed before generation
+    token_healing: Option<Vec<u8>>,
 }
 
 impl BlockEngineSequence for Sequence {
@@ -280,6 +330,7 @@ impl Sequence {
             sampler: sampler.into(),
             stop_tokens,
             stop_strings,
+            stop_token_seqs: Vec::new(),
             max_len,
             return_logprobs,
             prompt_tok_per_sec: 0.,
@@ -292,6 +343,7 @@ impl Sequence {
             prefill_prompt_toks: None,
             suffix,
             prefix,
+            echo_streamed: false,
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
             stream_idx: 0,
@@ -301,10 +353,140 @@ impl Sequence {
             is_tmp: false,
             scheduling_urgency: 0,
             adapters,
+            early_exit_layer: None,
+            xlora_global_scaling: None,
+            include_usage: false,
+            rng: None,
+            min_len: None,
+            eos_tokens: Vec::new(),
+            request_eos_tokens: Vec::new(),
+            stop_strings_from: 0,
+            rolling_cache: None,
+            evicted_prompt_toks: 0,
+            evicted_completion_toks: 0,
+            rolled_cache_len: None,
+            mirostat: None,
+            dry_penalty: None,
+            return_prompt_logprobs: false,
+            prompt_logprobs: None,
+            request_id: 0,
+            canceled: false,
             input_images,
             custom_metadata,
             tok_trie,
+            tool_call_stream: tools
+                .as_ref()
+                .filter(|tools| tools.calls_allowed())
+                .map(|_| ToolCallStream::default()),
             tools,
+            shared_prefill_followers: Vec::new(),
+            shares_prefill: false,
+            token_healing: None,
+        }
+    }
+
+    /// Only run the first `layer` decoder layers of the model for this sequence.
+    pub(crate) fn with_early_exit_layer(mut self, layer: Option<usize>) -> Self {
+        self.early_exit_layer = layer;
+        self
+    }
+
+    /// Scale the adapter outputs of an X-LoRA model by `scaling` for this sequence, instead of the
+    /// global scaling weight of the X-LoRA config.
+    pub(crate) fn with_xlora_global_scaling(mut self, scaling: Option<f64>) -> Self {
+        self.xlora_global_scaling = scaling;
+        self
+    }
+
+    /// Sample the tokens of this sequence with its own RNG seeded with `seed`, instead of the
+    /// engine's shared RNG.
+    pub(crate) fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.rng =
+            seed.map(|seed| Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(seed))));
+        self
+    }
+
+    /// Generate at least `min_len` tokens before stopping at one of `eos_tokens`, a stop token or a
+    /// stop string.
+    pub(crate) fn with_min_len(mut self, min_len: Option<usize>, eos_tokens: Vec<u32>) -> Self {
+        self.min_len = min_len;
+        self.eos_tokens = eos_tokens;
+        self
+    }
+
+    /// Also stop at `tokens` as if they were EOS tokens of the model, without changing the shared
+    /// metadata.
+    pub(crate) fn with_request_eos_tokens(mut self, tokens: Vec<u32>) -> Self {
+        self.request_eos_tokens = tokens;
+        self
+    }
+
+    /// Evict the middle of the context as configured by `rolling_cache` instead of stopping at the
+    /// model's `max_seq_len`.
+    pub(crate) fn with_rolling_cache(
+        mut self,
+        rolling_cache: Option<RollingCacheConfig>,
+        max_seq_len: usize,
+    ) -> Self {
+        self.rolling_cache = rolling_cache.map(|rolling_cache| (rolling_cache, max_seq_len));
+        self
+    }
+
+    /// Also stop once the generated tokens end with one of `stop_token_seqs`. Like a stop string,
+    /// the matched tokens are removed from the output.
+    pub(crate) fn with_stop_token_seqs(mut self, stop_token_seqs: Vec<Vec<u32>>) -> Self {
+        self.stop_token_seqs = stop_token_seqs;
+        self
+    }
+
+    /// Sample with Mirostat v2 if `tau` is set.
+    pub(crate) fn with_mirostat(mut self, tau: Option<f32>, eta: f32) -> Self {
+        self.mirostat = tau.map(|tau| MirostatState::new(tau, eta));
+        self
+    }
+
+    pub(crate) fn with_dry_penalty(mut self, dry_penalty: Option<Arc<DryPenalty>>) -> Self {
+        self.dry_penalty = dry_penalty;
+        self
+    }
+
+    /// Compute the logprobs of the prompt tokens during the prefill, to return them with an echoed
+    /// prompt.
+    pub(crate) fn with_prompt_logprobs(mut self, return_prompt_logprobs: bool) -> Self {
+        self.return_prompt_logprobs = return_prompt_logprobs;
+        self
+    }
+
+    /// Constrain the first generated token to start with `removed`, the bytes of the prompt token
+    /// removed by [`heal_prompt`]. These bytes are not part of the output.
+    pub(crate) fn with_token_healing(mut self, removed: Option<Vec<u8>>) -> Self {
+        self.token_healing = removed;
+        self
+    }
+
+    /// The tokens allowed as the first generated token with token healing.
+    pub(crate) fn token_healing_set(&self) -> Option<SimpleVob> {
+        self.token_healing
+            .as_ref()
+            .filter(|_| self.tokens.len() == self.prompt_len)
+            .map(|removed| self.tok_trie.tokens_with_prefix(removed))
+    }
+
+    pub(crate) fn with_request_id(mut self, request_id: usize) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
+    /// Finish this sequence and the sequences sharing its prefill with [`StopReason::Canceled`]
+    /// after their next step, through the same path as any other stop reason.
+    pub(crate) fn cancel(&mut self) {
+        self.canceled = true;
+        for follower in &mut self.shared_prefill_followers {
+            follower.cancel();
         }
     }
 
@@ -326,6 +508,55 @@ impl Sequence {
         (self.scheduling_urgency as f64) + (self.len() as f64).log2()
     }
 
+    /// Sequences of the same group with the same prompt which will reuse the prompt KV cache of this
+    /// sequence once it has been prefilled, instead of running their own prefill.
+    pub(crate) fn add_shared_prefill_follower(&mut self, follower: Sequence) {
+        self.shared_prefill_followers.push(follower);
+    }
+
+    pub(crate) fn take_shared_prefill_followers(&mut self) -> Vec<Sequence> {
+        std::mem::take(&mut self.shared_prefill_followers)
+    }
+
+    /// Start decoding from the prompt KV cache of `leader`, which must have the same prompt and have just been
+    /// prefilled. The cache of all but the last prompt token is copied, so the first completion step computes
+    /// the logits of the last prompt token and this sequence samples its first token independently.
+    ///
+    /// Returns `false` (leaving the sequence unchanged) if the cache of `leader` does not cover the whole prompt,
+    /// for example with a sliding window. In that case, this sequence must run its own prefill.
+    pub(crate) fn share_prefill(&mut self, leader: &Sequence) -> candle_core::Result<bool> {
+        let prompt_len = self.prompt_len;
+        if leader.prompt_len != prompt_len
+            || !leader.cache.iter().all(|layer| {
+                layer
+                    .as_ref()
+                    .is_some_and(|(k, _)| k.dims()[2] == prompt_len)
+            })
+        {
+            return Ok(false);
+        }
+        let mut cache = Vec::with_capacity(leader.cache.len());
+        for (k, v) in leader.cache.iter().flatten() {
+            cache.push(Some((
+                k.narrow(2, 0, prompt_len - 1)?,
+                v.narrow(2, 0, prompt_len - 1)?,
+            )));
+        }
+        self.cache = cache;
+        self.share_paged_prefill(leader);
+        Ok(true)
+    }
+
+    /// Start decoding after the prefill of `leader`, whose prompt KV cache blocks the PagedAttention
+    /// scheduler shares with this sequence. As with [`Sequence::share_prefill`], the first completion
+    /// step computes the logits of the last prompt token again.
+    pub(crate) fn share_paged_prefill(&mut self, leader: &Sequence) {
+        self.prompt_tok_per_sec = leader.prompt_tok_per_sec;
+        self.prompt_timestamp = leader.prompt_timestamp;
+        self.shares_prefill = true;
+        self.set_state(SequenceState::RunningCompletion);
+    }
+
     pub fn prefill(
         mut self,
         cache: LayerCaches,
@@ -484,13 +715,20 @@ impl Sequence {
     pub fn add_token(
         &mut self,
         tok: Logprobs,
-        completion_bytes: Vec<u8>,
+        mut completion_bytes: Vec<u8>,
         is_done: &Option<StopReason>,
     ) {
         let stopped_by_token = matches!(
             is_done,
             Some(StopReason::Eos) | Some(StopReason::StopTok(_))
         );
+        if let Some(removed) = self.token_healing.take() {
+            // The first token re-generates the bytes removed from the prompt.
+            completion_bytes = completion_bytes
+                .strip_prefix(removed.as_slice())
+                .map(<[u8]>::to_vec)
+                .unwrap_or(completion_bytes);
+        }
         if !stopped_by_token {
             // Completion bytes is used to check for stop strings, and as the response buffer.
             // We don't need to add stop tokens to the completion bytes to check for stop strings.
@@ -506,8 +744,12 @@ impl Sequence {
 
         self.cumulative_logprob += tok.logprob;
         self.tokens.push(tok.token);
+        if !self.min_len_reached() {
+            self.stop_strings_from = self.completion_bytes.len();
+        }
         self.logprobs.push(tok);
         self.prefill_prompt_toks = None;
+        self.rolled_cache_len = None;
     }
 
     pub fn responder(&self) -> Sender<Response> {
@@ -520,7 +762,14 @@ impl Sequence {
 
     pub fn set_state(&self, state: SequenceState) {
         if matches!(state, SequenceState::Error) {
-            get_mut_group!(self).n_choices -= 1;
+            let mut group = get_mut_group!(self);
+            group.n_choices -= 1;
+            group.maybe_close_span();
+        }
+        if let SequenceState::Done(reason) = state {
+            if !matches!(*self.state.read().unwrap(), SequenceState::Done(_)) {
+                get_mut_group!(self).record_finished_in_span(self.n_generated(), reason);
+            }
         }
         *self.state.write().unwrap() = state;
     }
@@ -535,40 +784,81 @@ impl Sequence {
         eos_tok: Option<&[u32]>,
         max_model_len: usize,
     ) -> Option<StopReason> {
-        let is_eos = match eos_tok {
-            Some(eos_tok) => eos_tok.iter().any(|t| *t == tok),
-            None => false,
-        };
-        if is_eos {
-            Some(StopReason::Eos)
-        } else if matches!(
-            &*self.state.read().unwrap(),
-            SequenceState::Done(StopReason::Canceled)
-        ) {
-            Some(StopReason::Canceled)
-        } else if self.stop_tokens.contains(&tok) {
-            Some(StopReason::StopTok(tok))
-        } else if self.max_len.is_some()
-            && self.tokens.len().saturating_sub(self.prompt_len) == self.max_len.unwrap()
+        if is_eos(tok, eos_tok, &self.request_eos_tokens) {
+            return Some(StopReason::Eos);
+        }
+        if self.canceled
+            || matches!(
+                &*self.state.read().unwrap(),
+                SequenceState::Done(StopReason::Canceled)
+            )
         {
-            // add_token was already called
-            Some(StopReason::Length(self.max_len.unwrap()))
-        } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
-            Some(StopReason::ModelLength(max_model_len))
+            return Some(StopReason::Canceled);
+        }
+        // Checked before the stop tokens: a stop string completed by this token starts before it.
+        if let Some(reason) = self.find_stop_string(tok) {
+            return Some(reason);
+        }
+        if let Some(reason) = self.find_stop_token_seq(tok) {
+            return Some(reason);
+        }
+        if self.stop_tokens.contains(&tok) {
+            Some(StopReason::StopTok(tok))
         } else {
-            if !self.stop_strings.is_empty() {
-                for (idx, s) in self.stop_strings.iter().enumerate() {
-                    if let Some(pos) = galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes())
-                    {
-                        return Some(StopReason::StopString {
-                            stop_string_idx: idx,
-                            completion_bytes_pos: pos,
-                        });
-                    }
-                }
+            // add_token is called after this, so `tok` is not counted yet.
+            length_limit(
+                self.n_generated() + 1,
+                self.tokens.len() + 1,
+                self.max_len,
+                max_model_len,
+            )
+        }
+    }
+
+    /// Find a stop string completed by the bytes of `tok`, which is not added yet. The completion
+    /// before it was searched at the previous step, so only the tail which may overlap a stop string
+    /// ending in these bytes is searched.
+    fn find_stop_string(&self, tok: u32) -> Option<StopReason> {
+        let n_generated = self.n_generated() + 1;
+        if self.stop_strings.is_empty() || self.min_len.is_some_and(|min| n_generated < min) {
+            return None;
+        }
+        let max_stop_len = self.stop_strings.iter().map(String::len).max().unwrap_or(0);
+        let start = self
+            .completion_bytes
+            .len()
+            .saturating_sub(max_stop_len.saturating_sub(1))
+            .max(self.stop_strings_from);
+        let mut tail = self.completion_bytes[start..].to_vec();
+        tail.extend(self.tok_trie.decode(&[tok]));
+        find_earliest_stop_string(&tail, &self.stop_strings).map(|(stop_string_idx, pos)| {
+            StopReason::StopString {
+                stop_string_idx,
+                completion_bytes_pos: start + pos,
             }
-            None
+        })
+    }
+
+    /// Find a stop token sequence completed by `tok`, which is not added yet.
+    fn find_stop_token_seq(&self, tok: u32) -> Option<StopReason> {
+        let generated = &self.tokens[self.prompt_len.min(self.tokens.len())..];
+        if self.stop_token_seqs.is_empty()
+            || self.min_len.is_some_and(|min| self.n_generated() + 1 < min)
+        {
+            return None;
         }
+        let stop_seq_idx = find_stop_token_seq(generated, tok, &self.stop_token_seqs)?;
+        // The completion bytes are the concatenated bytes of each generated token, and `tok` itself
+        // is not added to them.
+        let n_before = self.stop_token_seqs[stop_seq_idx].len() - 1;
+        let stop_bytes_len = self
+            .tok_trie
+            .decode(&generated[generated.len() - n_before..])
+            .len();
+        Some(StopReason::StopTokSeq {
+            stop_seq_idx,
+            completion_bytes_pos: self.completion_bytes.len().saturating_sub(stop_bytes_len),
+        })
     }
 
     pub fn logprobs(&self) -> &[Logprobs] {
@@ -579,25 +869,83 @@ impl Sequence {
         self.return_logprobs
     }
 
+    /// Whether the logprobs of the prompt are returned. The prompt step of a rolled context is not
+    /// the request's prompt, so it does not replace them.
+    pub fn return_prompt_logprobs(&self) -> bool {
+        self.return_prompt_logprobs && self.prompt_timestamp.is_none()
+    }
+
+    /// The logprobs of the prompt tokens after the first, once the prompt was processed.
+    pub fn prompt_logprobs(&self) -> Option<&[Logprobs]> {
+        self.prompt_logprobs.as_deref()
+    }
+
+    pub(crate) fn set_prompt_logprobs(&mut self, logprobs: Vec<Logprobs>) {
+        self.prompt_logprobs = Some(logprobs);
+    }
+
     pub fn prompt_tokens(&self) -> usize {
         self.prompt_len
     }
 
+    /// The stop sequence which caused this sequence to finish with `reason`, if any.
+    pub fn matched_stop(&self, reason: &StopReason) -> Option<String> {
+        match reason {
+            StopReason::StopString {
+                stop_string_idx, ..
+            } => self.stop_strings.get(*stop_string_idx).cloned(),
+            StopReason::StopTokSeq { stop_seq_idx, .. } => self
+                .stop_token_seqs
+                .get(*stop_seq_idx)
+                .map(|seq| String::from_utf8_lossy(&self.tok_trie.decode(seq)).to_string()),
+            StopReason::StopTok(tok) => Some(self.tok_trie.token_str(*tok)),
+            StopReason::Eos
+            | StopReason::Length(_)
+            | StopReason::ModelLength(_)
+            | StopReason::Canceled => None,
+        }
+    }
+
     pub fn stop_strings(&self) -> &[String] {
         &self.stop_strings
     }
 
-    /// Returns the delta between the last two decoded sequences
+    /// Returns the delta between the last two decoded sequences.
+    ///
+    /// Text which may be the start of a stop string is held back until the stop string is either
+    /// completed, in which case the completion is truncated at it, or ruled out. Once the sequence
+    /// is done, the remaining text is always returned, even if it is empty.
     pub fn get_delta(
         &mut self,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
-        // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
-        if new_decoded.ends_with('�') {
-            return Ok(None);
+        let end = match self.last_is_done {
+            Some(StopReason::StopString {
+                completion_bytes_pos,
+                ..
+            })
+            | Some(StopReason::StopTokSeq {
+                completion_bytes_pos,
+                ..
+            }) => completion_bytes_pos,
+            Some(_) => self.completion_bytes.len(),
+            None => {
+                self.completion_bytes.len()
+                    - partial_stop_string_len(
+                        &self.completion_bytes[self.stop_strings_from..],
+                        &self.stop_strings,
+                    )
+            }
         }
-        self.stream_idx = self.completion_bytes.len();
+        .max(self.stream_idx);
+        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..end]);
+        if self.last_is_done.is_none() {
+            // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
+            if new_decoded.is_empty() || new_decoded.ends_with('�') {
+                return Ok(None);
+            }
+        }
+        self.stream_idx = end;
 
         // The first token usually starts with a space. We don't want to add that to the delta.
         // Since we're using the completion_bytes, we need to take care of that ourselves.
@@ -608,6 +956,81 @@ impl Sequence {
         Ok(Some(new_decoded.to_string()))
     }
 
+    /// The chunks which echo the prompt of this streaming completion, to send before its first
+    /// generated chunk. Without logprobs, the whole prompt is one chunk. With logprobs, each prompt
+    /// token has a chunk with its logprob, except the first which has none. Only the first call
+    /// returns chunks.
+    pub(crate) fn take_echo_chunks(
+        &mut self,
+        tokenizer: &Tokenizer,
+    ) -> Result<Vec<CompletionChunkChoice>, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(prefix) = self.prefix.as_deref().filter(|_| !self.echo_streamed) else {
+            return Ok(Vec::new());
+        };
+        let chunk = |text: String, logprobs: Option<ResponseLogprob>| CompletionChunkChoice {
+            text,
+            index: self.response_index,
+            logprobs,
+            finish_reason: None,
+            matched_stop: None,
+            echo: true,
+        };
+        let chunks = match self.prompt_logprobs.as_deref() {
+            Some(prompt_logprobs) if self.return_logprobs => {
+                let toks = self
+                    .tokens
+                    .first()
+                    .into_iter()
+                    .copied()
+                    .chain(prompt_logprobs.iter().map(|logprob| logprob.token))
+                    .collect::<Vec<_>>();
+                let texts = split_echoed_prompt(prefix, &decode_incrementally(tokenizer, &toks)?);
+                let logprobs = [None].into_iter().chain(prompt_logprobs.iter().map(Some));
+                let mut chunks = Vec::with_capacity(texts.len());
+                for (text, logprob) in texts.into_iter().zip(logprobs) {
+                    let logprob = match logprob {
+                        Some(logprob) => Some(ResponseLogprob {
+                            token: tokenizer.decode(&[logprob.token], false)?,
+                            bytes: logprob.bytes.clone(),
+                            logprob: logprob.logprob,
+                            top_logprobs: logprob.top_logprobs.clone().unwrap_or_default(),
+                        }),
+                        None => None,
+                    };
+                    chunks.push(chunk(text, logprob));
+                }
+                chunks
+            }
+            _ => vec![chunk(prefix.to_string(), None)],
+        };
+        self.echo_streamed = true;
+        Ok(chunks)
+    }
+
+    /// Split the streamed `delta` into content and tool call deltas. Text which may be a tool call is
+    /// held back, and sent as content when the sequence is done if it was not one.
+    pub(crate) fn stream_tool_calls(
+        &mut self,
+        delta: String,
+        is_done: bool,
+    ) -> (String, Vec<ToolCallDelta>) {
+        let Some(stream) = &mut self.tool_call_stream else {
+            return (delta, Vec::new());
+        };
+        let (mut content, tool_calls) = stream.push(&delta);
+        if is_done {
+            content.push_str(&stream.finish());
+        }
+        (content, tool_calls)
+    }
+
+    /// Whether tool calls were streamed for this sequence.
+    pub(crate) fn streamed_tool_calls(&self) -> bool {
+        self.tool_call_stream
+            .as_ref()
+            .is_some_and(ToolCallStream::has_tool_calls)
+    }
+
     pub fn timestamp(&self) -> u128 {
         self.timestamp
     }
@@ -622,15 +1045,31 @@ impl Sequence {
             .expect("Time travel has occurred!")
             .as_millis();
 
+        // A sequence which shared the prefill of another in its group did not process the prompt itself.
         if let Some(ts) = self.prompt_timestamp {
             get_mut_group!(self).total_completion_time += now - ts;
-            get_mut_group!(self).total_prompt_time += ts - self.timestamp;
+            if !self.shares_prefill {
+                get_mut_group!(self).total_prompt_time += ts - self.timestamp;
+            }
         }
 
         get_mut_group!(self).total_time += now - self.timestamp;
+        get_mut_group!(self).add_wall_clock_times(self.timestamp, self.prompt_timestamp, now);
+
+        let prompt_toks = if self.shares_prefill {
+            0
+        } else {
+            self.prompt_len + self.evicted_prompt_toks
+        };
+        // `len` is one short while the last token is not in the KV cache, and the echoed prompt is only
+        // text, so count the generated tokens.
+        get_mut_group!(self).add_toks(prompt_toks, self.n_generated());
+    }
 
-        get_mut_group!(self).total_prompt_toks += self.prompt_len;
-        get_mut_group!(self).total_toks += self.len();
+    /// Account for the tokens and timing of this finished streaming sequence in its group.
+    pub(crate) fn add_streaming_usage_to_group(&self) {
+        get_mut_group!(self).n_streams_done += 1;
+        self.update_time_info();
     }
 
     pub fn add_choice_to_group(&self, choice: Choice) {
@@ -671,6 +1110,125 @@ impl Sequence {
         self.adapters.clone()
     }
 
+    pub fn early_exit_layer(&self) -> Option<usize> {
+        self.early_exit_layer
+    }
+
+    pub fn xlora_global_scaling(&self) -> Option<f64> {
+        self.xlora_global_scaling
+    }
+
+    /// The number of generated tokens, including those evicted by the rolling context.
+    fn n_generated(&self) -> usize {
+        self.tokens.len().saturating_sub(self.prompt_len) + self.evicted_completion_toks
+    }
+
+    /// If the rolling context is enabled and the next token would not fit in the maximum sequence
+    /// length, evict the middle of the context. The KV cache of the sinks is kept where possible and
+    /// the rest of the context runs a new prompt step at the following positions. Returns the
+    /// number of evicted tokens.
+    pub(crate) fn roll_context(&mut self) -> usize {
+        let Some((rolling_cache, max_seq_len)) = self.rolling_cache else {
+            return 0;
+        };
+        let Some(range) = rolling_cache.eviction_range(self.tokens.len(), max_seq_len) else {
+            return 0;
+        };
+        // The last token was sampled but not yet run through the model.
+        let n_cached = self.tokens.len() - 1;
+        let n_evicted = range.len();
+        let n_prompt_evicted = self.prompt_len.saturating_sub(range.start).min(n_evicted);
+        self.tokens.drain(range);
+        self.prompt_len -= n_prompt_evicted;
+        self.evicted_prompt_toks += n_prompt_evicted;
+        self.evicted_completion_toks += n_evicted - n_prompt_evicted;
+
+        self.rolled_cache_len = match &mut self.custom_metadata {
+            SequenceCustomMetadata::PagedAttention {
+                logical_token_blocks,
+                block_size,
+            } => {
+                // Only whole blocks of sinks are kept, the block engine frees the others.
+                let kept = rolling_cache.sink / *block_size * *block_size;
+                logical_token_blocks.clear();
+                self.custom_metadata.append_tokens_to_blocks(
+                    self.tokens.iter().map(|x| *x as usize).collect::<Vec<_>>(),
+                );
+                Some(kept)
+            }
+            SequenceCustomMetadata::None => {
+                let kept = self.xlora_cache.is_none()
+                    && self.draft_cache.iter().all(Option::is_none)
+                    && rolling_cache.evict(&mut self.cache, n_cached);
+                if !kept {
+                    self.cache = vec![None; self.cache.len()];
+                    self.draft_cache = vec![None; self.draft_cache.len()];
+                    if let Some(xlora_cache) = &mut self.xlora_cache {
+                        *xlora_cache = vec![None; xlora_cache.len()];
+                    }
+                }
+                self.scaling_cache = None;
+                kept.then_some(rolling_cache.sink)
+            }
+        };
+        if let Some(kept) = self.rolled_cache_len {
+            self.prefill_prompt_toks = Some(self.tokens[kept..].to_vec());
+        }
+        self.set_state(SequenceState::RunningPrompt);
+        n_evicted
+    }
+
+    /// The number of positions kept in the KV cache by the rolling context, which the pending
+    /// prompt step continues from.
+    pub(crate) fn rolled_cache_len(&self) -> Option<usize> {
+        self.rolled_cache_len
+    }
+
+    /// Recompute the whole rolled context, as its kept KV cache was freed.
+    pub(crate) fn reset_rolled_cache(&mut self) {
+        self.rolled_cache_len = None;
+        self.prefill_prompt_toks = None;
+    }
+
+    fn min_len_reached(&self) -> bool {
+        self.min_len
+            .map_or(true, |min_len| self.n_generated() >= min_len)
+    }
+
+    /// The tokens which may not be sampled yet because the minimum length is not reached: the EOS
+    /// tokens, including those of the request, and stop tokens.
+    pub fn min_len_suppressed_tokens(&self) -> Option<Vec<u32>> {
+        if self.min_len_reached() {
+            None
+        } else {
+            Some(
+                self.eos_tokens
+                    .iter()
+                    .chain(&self.request_eos_tokens)
+                    .chain(&self.stop_tokens)
+                    .copied()
+                    .collect(),
+            )
+        }
+    }
+
+    pub(crate) fn dry_penalty(&self) -> Option<&DryPenalty> {
+        self.dry_penalty.as_deref()
+    }
+
+    pub(crate) fn mirostat(&self) -> Option<&MirostatState> {
+        self.mirostat.as_ref()
+    }
+
+    pub(crate) fn mirostat_mut(&mut self) -> Option<&mut MirostatState> {
+        self.mirostat.as_mut()
+    }
+
+    /// The RNG of this sequence, if it was given a seed.
+    pub fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
     pub fn take_images(&mut self) -> Option<Vec<image::DynamicImage>> {
         self.input_images.take()
     }
@@ -681,38 +1239,119 @@ impl Sequence {
 }
 
 pub struct SequenceGroup {
-    n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
-    best_of: usize,   // Top n seqs based on cumulative logprobs.
+    n_choices: usize, // The target number of sequences to finish. Can be decreased if an error is thrown.
+    n_returned: usize, // The number of completion choices returned, those with the highest cumulative logprobs.
     pub total_prompt_toks: usize,
     pub total_toks: usize,
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
+    // Wall-clock timestamps (ms since the UNIX epoch) of the request over all its sequences.
+    arrival_timestamp: Option<u128>,
+    first_prompt_timestamp: Option<u128>,
+    finish_timestamp: Option<u128>,
     choices: Vec<Choice>,
     completion_choices: Vec<(f32, CompletionChoice)>,
     pub chat_streaming_chunks: Vec<ChunkChoice>,
     pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    include_usage: bool,
+    n_streams_done: usize,
+
+    // Tracing span of the request, closed once all choices have finished.
+    span: Span,
+    span_start: Instant,
+    span_completion_toks: usize,
+    span_finish_reasons: Vec<String>,
+
+    sampling_params_used: SamplingParamsUsed,
 }
 
 impl SequenceGroup {
+    /// A group of `best_of` sequences, of which the `n_choices` best completion choices are returned.
+    /// All sequences are returned for chat requests, so `best_of` should equal `n_choices`.
     pub fn new(n_choices: usize, is_streaming: bool, is_chat: bool, best_of: usize) -> Self {
         Self {
             choices: Vec::new(),
             completion_choices: Vec::new(),
-            n_choices,
+            n_choices: best_of,
+            n_returned: n_choices,
             total_prompt_toks: 0,
             total_toks: 0,
             total_prompt_time: 0,
             total_time: 0,
             total_completion_time: 0,
+            arrival_timestamp: None,
+            first_prompt_timestamp: None,
+            finish_timestamp: None,
             chat_streaming_chunks: Vec::new(),
             completion_streaming_chunks: Vec::new(),
             is_streaming,
             is_chat,
-            best_of,
+            include_usage: false,
+            n_streams_done: 0,
+            span: Span::none(),
+            span_start: Instant::now(),
+            span_completion_toks: 0,
+            span_finish_reasons: Vec::new(),
+            sampling_params_used: SamplingParamsUsed::default(),
+        }
+    }
+
+    /// Send a final chunk with the usage of the request once all streamed chat choices have finished.
+    pub(crate) fn with_include_usage(mut self, include_usage: bool) -> Self {
+        self.include_usage = include_usage;
+        self
+    }
+
+    /// Set the resolved sampling parameters which are reported in the responses.
+    pub(crate) fn with_sampling_params_used(mut self, params: SamplingParamsUsed) -> Self {
+        self.sampling_params_used = params;
+        self
+    }
+
+    pub fn get_sampling_params_used(&self) -> SamplingParamsUsed {
+        self.sampling_params_used.clone()
+    }
+
+    /// Set the tracing span of the request. The span should have the empty fields `completion_tokens`,
+    /// `duration_ms` and `finish_reason`, which are recorded when the last choice finishes, after which
+    /// the span is closed.
+    pub(crate) fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self.span_start = Instant::now();
+        self
+    }
+
+    fn record_finished_in_span(&mut self, completion_toks: usize, reason: StopReason) {
+        self.span_completion_toks += completion_toks;
+        self.span_finish_reasons.push(reason.to_string());
+        self.maybe_close_span();
+    }
+
+    fn maybe_close_span(&mut self) {
+        if self.span.is_none() || self.span_finish_reasons.len() < self.n_choices {
+            return;
         }
+        self.span
+            .record("completion_tokens", self.span_completion_toks)
+            .record(
+                "duration_ms",
+                self.span_start.elapsed().as_secs_f64() * 1000.,
+            )
+            .record(
+                "finish_reason",
+                self.span_finish_reasons.join(", ").as_str(),
+            );
+        // Dropping the handle closes the span.
+        self.span = Span::none();
+    }
+
+    /// Account for the prompt and completion tokens of a finished sequence.
+    pub(crate) fn add_toks(&mut self, prompt_toks: usize, completion_toks: usize) {
+        self.total_prompt_toks += prompt_toks;
+        self.total_toks += prompt_toks + completion_toks;
     }
 
     /// This does not apply best_of.
@@ -720,19 +1359,48 @@ impl SequenceGroup {
         &self.choices
     }
 
-    /// This applies the best_of.
+    /// This applies the best_of: the candidates with the highest cumulative logprob are returned,
+    /// and indexed in that order.
     pub fn get_completion_choices(&self) -> Vec<CompletionChoice> {
         let mut choices = self.completion_choices.clone();
         // Sort by descending logprobs
         choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
         choices
             .into_iter()
-            .take(self.best_of)
-            .map(|(_, x)| x)
+            .take(self.n_returned)
+            .enumerate()
+            .map(|(index, (_, x))| CompletionChoice { index, ..x })
             .collect::<Vec<_>>()
     }
 
+    /// Account for a finished sequence which arrived at `arrival`, finished its prompt at
+    /// `prompt_done` and finished at `finish`.
+    pub(crate) fn add_wall_clock_times(
+        &mut self,
+        arrival: u128,
+        prompt_done: Option<u128>,
+        finish: u128,
+    ) {
+        self.arrival_timestamp = Some(self.arrival_timestamp.map_or(arrival, |t| t.min(arrival)));
+        if let Some(prompt_done) = prompt_done {
+            self.first_prompt_timestamp = Some(
+                self.first_prompt_timestamp
+                    .map_or(prompt_done, |t| t.min(prompt_done)),
+            );
+        }
+        self.finish_timestamp = Some(self.finish_timestamp.map_or(finish, |t| t.max(finish)));
+    }
+
     pub fn get_usage(&self) -> Usage {
+        let (arrival, finish) = match (self.arrival_timestamp, self.finish_timestamp) {
+            (Some(arrival), Some(finish)) => (arrival, finish),
+            _ => (0, 0),
+        };
+        let total_time = finish - arrival;
+        // A request which finished before its prompt was processed spent all its time on the prompt.
+        let prompt_time = self
+            .first_prompt_timestamp
+            .map_or(total_time, |t| t.clamp(arrival, finish) - arrival);
         #[allow(clippy::cast_precision_loss)]
         Usage {
             completion_tokens: self.total_toks - self.total_prompt_toks,
@@ -747,6 +1415,9 @@ impl SequenceGroup {
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
+            prompt_time_ms: prompt_time as f32,
+            completion_time_ms: (total_time - prompt_time) as f32,
+            total_time_ms: total_time as f32,
         }
     }
 
@@ -780,8 +1451,23 @@ impl SequenceGroup {
                     model: model.clone(),
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "chat.completion.chunk".to_string(),
+                    usage: None,
                 }))
                 .await?;
+
+            if self.include_usage && self.n_streams_done == self.n_choices {
+                seq.responder()
+                    .send(Response::Chunk(ChatCompletionChunkResponse {
+                        id: seq.id.to_string(),
+                        choices: Vec::new(),
+                        created: seq.timestamp,
+                        model: model.clone(),
+                        system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                        object: "chat.completion.chunk".to_string(),
+                        usage: Some(self.get_usage()),
+                    }))
+                    .await?;
+            }
         } else if self.completion_streaming_chunks.len() == self.n_choices && self.is_streaming {
             let mut swap_streaming_chunks = vec![];
 
@@ -815,3 +1501,601 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+/// The text which each of `toks` adds to the decoded text. A token which ends in an incomplete
+/// UTF-8 character adds no text, and the character is added by the token which completes it.
+fn decode_incrementally(
+    tokenizer: &Tokenizer,
+    toks: &[u32],
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut texts = Vec::with_capacity(toks.len());
+    // Each token is decoded after the previous token, which decides whether it has a leading space.
+    let (mut prefix_offset, mut read_offset) = (0, 0);
+    for i in 0..toks.len() {
+        let prefix_text = tokenizer.decode(&toks[prefix_offset..read_offset], false)?;
+        let new_text = tokenizer.decode(&toks[prefix_offset..=i], false)?;
+        match new_text.get(prefix_text.len()..) {
+            Some(text) if !text.is_empty() && !new_text.ends_with('\u{FFFD}') => {
+                texts.push(text.to_string());
+                prefix_offset = read_offset;
+                read_offset = i + 1;
+            }
+            _ => texts.push(String::new()),
+        }
+    }
+    Ok(texts)
+}
+
+/// Split the echoed `prompt_text` into the chunks of its tokens, whose decoded `token_texts` may not
+/// exactly join to it. A token text which does not continue the prompt text gets an empty chunk,
+/// and the last chunk takes the rest of the prompt text, so the chunks always join to it.
+fn split_echoed_prompt(prompt_text: &str, token_texts: &[String]) -> Vec<String> {
+    let mut start = 0;
+    let mut chunks = token_texts
+        .iter()
+        .map(|text| {
+            if prompt_text[start..].starts_with(text.as_str()) {
+                start += text.len();
+                text.clone()
+            } else {
+                String::new()
+            }
+        })
+        .collect::<Vec<_>>();
+    match chunks.last_mut() {
+        Some(last) => last.push_str(&prompt_text[start..]),
+        None => chunks.push(prompt_text.to_string()),
+    }
+    chunks
+}
+
+/// Token healing: remove the last token of `prompt` if it is a prefix of a longer token, so the model
+/// may choose how to tokenize the end of the prompt. Returns the bytes of the removed token.
+pub(crate) fn heal_prompt(tok_trie: &TokTrie, prompt: &mut Vec<u32>) -> Option<Vec<u8>> {
+    let last = *prompt.last().filter(|_| prompt.len() > 1)?;
+    let bytes = tok_trie.token(last);
+    if bytes.is_empty() || !tok_trie.has_extensions(bytes) {
+        return None;
+    }
+    let bytes = bytes.to_vec();
+    prompt.pop();
+    Some(bytes)
+}
+
+/// Whether `tok` is one of the model's `eos_tok` or the `request_eos_tokens`. `eos_tok` is `None`
+/// when stopping at EOS is disabled, which also applies to the request's EOS tokens.
+fn is_eos(tok: u32, eos_tok: Option<&[u32]>, request_eos_tokens: &[u32]) -> bool {
+    eos_tok.is_some_and(|eos_tok| eos_tok.contains(&tok) || request_eos_tokens.contains(&tok))
+}
+
+/// The index of the first of `stop_token_seqs` which the `generated` tokens followed by `tok` end
+/// with. Empty sequences never match.
+fn find_stop_token_seq(generated: &[u32], tok: u32, stop_token_seqs: &[Vec<u32>]) -> Option<usize> {
+    stop_token_seqs
+        .iter()
+        .position(|seq| match seq.split_last() {
+            Some((last, before)) => *last == tok && generated.ends_with(before),
+            None => false,
+        })
+}
+
+/// The length limit reached with `n_generated` generated tokens, out of `n_total` tokens including the
+/// prompt.
+fn length_limit(
+    n_generated: usize,
+    n_total: usize,
+    max_len: Option<usize>,
+    max_model_len: usize,
+) -> Option<StopReason> {
+    if let Some(max_len) = max_len.filter(|max_len| n_generated >= *max_len) {
+        Some(StopReason::Length(max_len))
+    } else if n_total >= max_model_len {
+        Some(StopReason::ModelLength(max_model_len))
+    } else {
+        None
+    }
+}
+
+/// Find the stop string which occurs earliest in `completion`, returning its index in
+/// `stop_strings` and its byte position. Of several stop strings starting at the same
+/// position, the first listed is chosen.
+fn find_earliest_stop_string(completion: &[u8], stop_strings: &[String]) -> Option<(usize, usize)> {
+    stop_strings
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, s)| {
+            galil_seiferas::gs_find(completion, s.as_bytes()).map(|pos| (idx, pos))
+        })
+        .min_by_key(|&(idx, pos)| (pos, idx))
+}
+
+/// The length of the longest suffix of `completion` which is a proper prefix of one of
+/// `stop_strings`. Streaming holds these bytes back until it is known whether a stop string follows.
+fn partial_stop_string_len(completion: &[u8], stop_strings: &[String]) -> usize {
+    stop_strings
+        .iter()
+        .filter_map(|stop| {
+            let stop = stop.as_bytes();
+            (1..stop.len().min(completion.len() + 1))
+                .rev()
+                .find(|&len| completion.ends_with(&stop[..len]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{Device, Tensor};
+    use tokio::sync::{mpsc::channel, Mutex};
+
+    use super::{
+        find_earliest_stop_string, find_stop_token_seq, heal_prompt, is_eos, length_limit,
+        partial_stop_string_len, split_echoed_prompt, Sequence, SequenceGroup, SequenceRecognizer,
+        SequenceState, StopReason,
+    };
+    use crate::{
+        aici::{bytes::TokRxInfo, toktree::TokTrie},
+        pipeline::RollingCacheConfig,
+        sampler::{Logprobs, Sampler},
+        CompletionChoice, TemperatureOrder,
+    };
+
+    /// A tokenizer whose token `i` is `words[i]`, with `words[0]` as the EOS token.
+    fn tok_trie(words: &[&str]) -> TokTrie {
+        let words = words
+            .iter()
+            .map(|w| w.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        TokTrie::from(
+            &TokRxInfo {
+                vocab_size: words.len() as u32,
+                tok_eos: 0,
+            },
+            &words,
+        )
+    }
+
+    /// A waiting sequence of `prompt` in `group`, with a KV cache of 2 layers.
+    fn sequence(
+        tok_trie: TokTrie,
+        prompt: Vec<u32>,
+        stop_strings: Vec<String>,
+        group: Arc<Mutex<SequenceGroup>>,
+        response_index: usize,
+    ) -> Sequence {
+        let tok_trie = Arc::new(tok_trie);
+        let sampler = Sampler::new(
+            None,
+            0,
+            tok_trie.clone(),
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            TemperatureOrder::default(),
+            vec![],
+        );
+        Sequence::new_waiting(
+            prompt,
+            response_index,
+            0,
+            2,
+            channel(16).0,
+            sampler,
+            vec![],
+            stop_strings,
+            None,
+            false,
+            false,
+            group,
+            response_index,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            (*tok_trie).clone(),
+            None,
+        )
+    }
+
+    /// Sample `tok` for `seq` as the pipeline does, returning why the sequence finished.
+    fn add_token(seq: &mut Sequence, tok: u32) -> Option<StopReason> {
+        let is_done = seq.is_done(tok, Some(&[0]), 4096);
+        let bytes = seq.tok_trie.decode(&[tok]);
+        seq.add_token(
+            Logprobs {
+                token: tok,
+                logprob: 0.,
+                bytes: bytes.clone(),
+                top_logprobs: None,
+            },
+            bytes,
+            &is_done,
+        );
+        is_done
+    }
+
+    #[test]
+    fn streamed_echo_starts_with_prompt() {
+        let texts = |texts: &[&str]| texts.iter().map(ToString::to_string).collect::<Vec<_>>();
+        // The last prompt token was removed by token healing, so its bytes `ld` are only echoed.
+        let prompt = "Hello wor\u{e9}ld";
+        let echoed = split_echoed_prompt(prompt, &texts(&["Hello", " wor", "", "\u{e9}"]));
+        assert_eq!(echoed, texts(&["Hello", " wor", "", "\u{e9}ld"]));
+
+        let generated = [" and", " goodbye"];
+        let streamed = echoed.concat() + &generated.concat();
+        assert!(streamed.starts_with(prompt), "{streamed}");
+        assert_eq!(streamed, "Hello wor\u{e9}ld and goodbye");
+
+        // A token text which does not continue the prompt neither duplicates nor loses text.
+        let echoed = split_echoed_prompt("Hello world", &texts(&["Hello", "  wor", "ld"]));
+        assert_eq!(echoed.concat(), "Hello world");
+        assert_eq!(split_echoed_prompt("Hi", &[]), texts(&["Hi"]));
+    }
+
+    #[test]
+    fn request_stop_token_ids_end_generation() {
+        // `<|im_end|>` (7) is not the model's EOS token (2), but the request stops at it.
+        assert!(is_eos(7, Some(&[2]), &[7]));
+        assert!(!is_eos(7, Some(&[2]), &[]));
+        assert!(is_eos(2, Some(&[2]), &[7]));
+        // Ignoring EOS also ignores the request's EOS tokens.
+        assert!(!is_eos(7, None, &[7]));
+    }
+
+    #[test]
+    fn stop_token_seqs_end_generation() {
+        let stop_token_seqs = [vec![7, 8], vec![1, 2, 3]];
+        let sampled = [5, 1, 2, 4, 1, 2, 3, 6];
+        let mut generated = Vec::new();
+        let mut stopped = None;
+        for tok in sampled {
+            if let Some(idx) = find_stop_token_seq(&generated, tok, &stop_token_seqs) {
+                stopped = Some(idx);
+                break;
+            }
+            generated.push(tok);
+        }
+        // The first `1, 2` is not followed by `3`, so generation stops at the second one.
+        assert_eq!(stopped, Some(1));
+        assert_eq!(generated, [5, 1, 2, 4, 1, 2]);
+        let reason = StopReason::StopTokSeq {
+            stop_seq_idx: 1,
+            completion_bytes_pos: 0,
+        };
+        assert_eq!(reason.to_string(), "stop");
+
+        assert_eq!(find_stop_token_seq(&[], 3, &stop_token_seqs), None);
+        assert_eq!(find_stop_token_seq(&[1, 2], 3, &[vec![]]), None);
+        assert_eq!(find_stop_token_seq(&[], 3, &[vec![3]]), Some(0));
+    }
+
+    #[test]
+    fn shared_prefill_usage() {
+        // With `n_choices=4`, only the first sequence runs the prefill of the prompt and the other three
+        // start from its KV cache.
+        let prompt = vec![1, 2, 3, 4];
+        let group = Arc::new(Mutex::new(SequenceGroup::new(4, true, true, 4)));
+        let mut leader = sequence(
+            tok_trie(&["</s>", "a", "b", "c", "d"]),
+            prompt.clone(),
+            vec![],
+            group.clone(),
+            0,
+        );
+        for i in 1..4 {
+            leader.add_shared_prefill_follower(sequence(
+                tok_trie(&["</s>", "a", "b", "c", "d"]),
+                prompt.clone(),
+                vec![],
+                group.clone(),
+                i,
+            ));
+        }
+
+        // The prefill of the leader.
+        let kv = Tensor::zeros(
+            (1, 2, prompt.len(), 8),
+            candle_core::DType::F32,
+            &Device::Cpu,
+        )
+        .unwrap();
+        leader.cache = vec![Some((kv.clone(), kv)); 2];
+        leader.prompt_timestamp = Some(0);
+        let mut seqs = vec![];
+        for mut follower in leader.take_shared_prefill_followers() {
+            assert!(follower.share_prefill(&leader).unwrap());
+            // The follower skips the prompt step, and its first completion step recomputes the last
+            // prompt token.
+            assert!(!follower.is_prompt());
+            assert!(follower
+                .cache
+                .iter()
+                .all(|layer| layer.as_ref().unwrap().0.dims()[2] == prompt.len() - 1));
+            seqs.push(follower);
+        }
+        seqs.push(leader);
+
+        for seq in &mut seqs {
+            add_token(seq, 1);
+            seq.add_streaming_usage_to_group();
+        }
+        let usage = group.try_lock().unwrap().get_usage();
+        assert_eq!(usage.prompt_tokens, prompt.len());
+        assert_eq!(usage.completion_tokens, 4);
+        assert_eq!(usage.total_tokens, prompt.len() + 4);
+    }
+
+    /// Run the model on the tokens of `seq` which are not in its KV cache, as the pipeline does. The
+    /// keys and values of a position are its token, so the cache shows which tokens it holds.
+    fn forward(seq: &mut Sequence) {
+        let toks = seq.tokens.iter().map(|tok| *tok as f32).collect::<Vec<_>>();
+        let n_cached = seq.cache[0].as_ref().map_or(0, |(k, _)| k.dims()[2]);
+        let new = Tensor::new(&toks[n_cached..], &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, toks.len() - n_cached, 1))
+            .unwrap();
+        for layer in &mut seq.cache {
+            let kv = match layer {
+                Some((k, _)) => Tensor::cat(&[&*k, &new], 2).unwrap(),
+                None => new.clone(),
+            };
+            *layer = Some((kv.clone(), kv));
+        }
+        seq.prompt_timestamp = Some(0);
+    }
+
+    fn cached_toks(seq: &Sequence) -> Vec<u32> {
+        let (k, _) = seq.cache[0].as_ref().unwrap();
+        k.flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap()
+            .into_iter()
+            .map(|tok| tok as u32)
+            .collect()
+    }
+
+    #[test]
+    fn rolling_cache_generates_past_max_seq_len() {
+        const MAX_SEQ_LEN: usize = 16;
+        let rolling = RollingCacheConfig { sink: 4, window: 6 };
+        let words = (0..128).map(|i| format!(" w{i}")).collect::<Vec<_>>();
+        let words = words.iter().map(String::as_str).collect::<Vec<_>>();
+        let group = Arc::new(Mutex::new(SequenceGroup::new(1, false, false, 1)));
+        let prompt = (1..9).collect::<Vec<u32>>();
+        let mut seq = sequence(tok_trie(&words), prompt.clone(), vec![], group.clone(), 0)
+            .with_rolling_cache(Some(rolling), MAX_SEQ_LEN);
+        seq.set_state(SequenceState::RunningPrompt);
+
+        let mut n_rolls = 0;
+        for tok in 9..109 {
+            // A prompt step after a roll continues after the sinks, which stay in the KV cache.
+            if let Some(kept) = seq.rolled_cache_len() {
+                assert_eq!(kept, rolling.sink);
+                assert_eq!(cached_toks(&seq), prompt[..rolling.sink]);
+                assert_eq!(seq.get_toks(), &seq.tokens[rolling.sink..]);
+            }
+            forward(&mut seq);
+            assert_eq!(cached_toks(&seq), seq.tokens);
+
+            assert_eq!(seq.is_done(tok, Some(&[0]), MAX_SEQ_LEN), None);
+            add_token(&mut seq, tok);
+            if seq.roll_context() > 0 {
+                n_rolls += 1;
+                assert!(seq.is_prompt());
+                assert!(seq.tokens.len() < MAX_SEQ_LEN);
+            }
+        }
+        assert!(n_rolls > 1);
+        // The sinks are kept, followed by the most recent tokens.
+        assert_eq!(seq.tokens[..rolling.sink], prompt[..rolling.sink]);
+        assert_eq!(seq.tokens.last(), Some(&108));
+
+        seq.add_streaming_usage_to_group();
+        let usage = group.try_lock().unwrap().get_usage();
+        assert_eq!(usage.prompt_tokens, prompt.len());
+        assert_eq!(usage.completion_tokens, 100);
+    }
+
+    #[test]
+    fn wall_clock_usage_times() {
+        // Two choices of a request which arrived at 1000ms: the prompt was processed at 1200ms and the
+        // choices finished at 1500ms and 1800ms.
+        let mut group = SequenceGroup::new(2, false, false, 2);
+        group.add_wall_clock_times(1000, Some(1200), 1500);
+        group.add_wall_clock_times(1000, Some(1200), 1800);
+        let usage = group.get_usage();
+        assert_eq!(usage.prompt_time_ms, 200.);
+        assert_eq!(usage.completion_time_ms, 600.);
+        assert_eq!(usage.total_time_ms, 800.);
+        assert!(
+            (usage.prompt_time_ms + usage.completion_time_ms - usage.total_time_ms).abs() < 1e-3
+        );
+
+        // A request which was rejected before its prompt was processed.
+        let mut group = SequenceGroup::new(1, false, false, 1);
+        group.add_wall_clock_times(1000, None, 1010);
+        let usage = group.get_usage();
+        assert_eq!(usage.prompt_time_ms, 10.);
+        assert_eq!(usage.completion_time_ms, 0.);
+    }
+
+    #[test]
+    fn best_of_returns_highest_logprob() {
+        let mut group = SequenceGroup::new(1, false, false, 3);
+        for (index, logprob) in [-3., -1., -2.].into_iter().enumerate() {
+            group.completion_choices.push((
+                logprob,
+                CompletionChoice {
+                    finish_reason: "stop".to_string(),
+                    index,
+                    text: index.to_string(),
+                    logprobs: None,
+                    matched_stop: None,
+                },
+            ));
+        }
+        let choices = group.get_completion_choices();
+        assert_eq!(choices.len(), 1);
+        assert_eq!(choices[0].text, "1");
+        assert_eq!(choices[0].index, 0);
+    }
+
+    #[test]
+    fn length_limits() {
+        // The 4th generated token of a request with `max_tokens=4` is the last.
+        assert_eq!(length_limit(3, 13, Some(4), 4096), None);
+        assert_eq!(
+            length_limit(4, 14, Some(4), 4096),
+            Some(StopReason::Length(4))
+        );
+        // Without `max_tokens`, the prompt counts towards the model's length.
+        assert_eq!(length_limit(6, 4095, None, 4096), None);
+        assert_eq!(
+            length_limit(7, 4096, None, 4096),
+            Some(StopReason::ModelLength(4096))
+        );
+        assert_eq!(
+            length_limit(7, 4096, Some(8), 4096).unwrap().to_string(),
+            "length"
+        );
+    }
+
+    #[test]
+    fn stop_reasons_are_openai_finish_reasons() {
+        assert_eq!(StopReason::Eos.to_string(), "stop");
+        assert_eq!(StopReason::StopTok(2).to_string(), "stop");
+        let stop_string = StopReason::StopString {
+            stop_string_idx: 0,
+            completion_bytes_pos: 5,
+        };
+        assert_eq!(stop_string.to_string(), "stop");
+        assert_eq!(StopReason::Length(16).to_string(), "length");
+        assert_eq!(StopReason::ModelLength(4096).to_string(), "length");
+    }
+
+    #[test]
+    fn earliest_stop_string_wins() {
+        let stops = vec!["world".to_string(), "lo".to_string(), "hello".to_string()];
+        // `lo` is listed after `world` but occurs earlier.
+        assert_eq!(
+            find_earliest_stop_string(b"hello world", &stops[..2]),
+            Some((1, 3))
+        );
+        // `hello` overlaps `lo` and starts before it.
+        assert_eq!(
+            find_earliest_stop_string(b"hello world", &stops),
+            Some((2, 0))
+        );
+        assert_eq!(find_earliest_stop_string(b"goodbye", &stops), None);
+
+        // Stop strings starting at the same position resolve to the first listed.
+        let stops = vec!["abc".to_string(), "ab".to_string()];
+        assert_eq!(find_earliest_stop_string(b"xabcd", &stops), Some((0, 1)));
+    }
+
+    #[test]
+    fn stop_string_across_tokens_is_not_streamed() {
+        let stops = vec!["\n\nUser:".to_string()];
+        let words = [
+            "</s>", "Sure", ".\n", "\nUs", "er", ":", " hi", "A", "\n\n", "User", ": B", "Us",
+            "age", ".",
+        ];
+        // Streams the tokens through `get_delta`, returning the streamed text and the stop reason.
+        let stream = |tokens: &[&str]| {
+            let group = Arc::new(Mutex::new(SequenceGroup::new(1, true, true, 1)));
+            let mut seq = sequence(tok_trie(&words), vec![1], stops.clone(), group, 0);
+            let mut streamed = String::new();
+            for tok in tokens {
+                let tok = words.iter().position(|w| w == tok).unwrap() as u32;
+                let is_done = add_token(&mut seq, tok);
+                if let Some(delta) = seq.get_delta().unwrap() {
+                    streamed.push_str(&delta);
+                }
+                if is_done.is_some() {
+                    return (streamed, is_done);
+                }
+                // The held back text is a strict prefix of the stop string.
+                assert!(!streamed.ends_with('\n'));
+            }
+            (streamed, None)
+        };
+
+        // The stop string straddles the token boundaries.
+        assert_eq!(
+            stream(&["Sure", ".\n", "\nUs", "er", ":", " hi"]),
+            (
+                "Sure.".to_string(),
+                Some(StopReason::StopString {
+                    stop_string_idx: 0,
+                    completion_bytes_pos: 5
+                })
+            )
+        );
+        assert_eq!(
+            stream(&["A", "\n\n", "User", ": B"]),
+            (
+                "A".to_string(),
+                Some(StopReason::StopString {
+                    stop_string_idx: 0,
+                    completion_bytes_pos: 1
+                })
+            )
+        );
+        // A partial match is streamed once it is ruled out.
+        assert_eq!(
+            stream(&["A", "\n\n", "Us", "age", "."]),
+            ("A\n\nUsage.".to_string(), None)
+        );
+        assert_eq!(partial_stop_string_len(b"A\n\nUs", &stops), 4);
+        assert_eq!(partial_stop_string_len(b"A\n\nUsage", &stops), 0);
+        assert_eq!(partial_stop_string_len(b"\n\nUser:", &stops), 0);
+    }
+
+    #[test]
+    fn token_healing_completes_word() {
+        let words =
+            ["</s>", "Hello", " wor", " world", " word", "ld", " "].map(|w| w.as_bytes().to_vec());
+        let tok_trie = TokTrie::from(
+            &TokRxInfo {
+                vocab_size: 7,
+                tok_eos: 0,
+            },
+            &words,
+        );
+
+        // "Hello wor": " wor" is a prefix of " world" and " word", so it is removed.
+        let mut prompt = vec![1, 2];
+        let removed = heal_prompt(&tok_trie, &mut prompt);
+        assert_eq!(removed.as_deref(), Some(&b" wor"[..]));
+        assert_eq!(prompt, vec![1]);
+
+        // The first token must start with " wor", and " world" completes the prompt with "ld".
+        let allowed = tok_trie.tokens_with_prefix(b" wor");
+        let allowed = (0..7)
+            .filter(|&tok| allowed.is_allowed(tok))
+            .collect::<Vec<_>>();
+        assert_eq!(allowed, vec![2, 3, 4]);
+        assert_eq!(
+            tok_trie.token(3).strip_prefix(&b" wor"[..]),
+            Some(&b"ld"[..])
+        );
+
+        // "Hello" is not the prefix of a longer token, and a single token prompt is kept.
+        let mut prompt = vec![2, 1];
+        assert_eq!(heal_prompt(&tok_trie, &mut prompt), None);
+        let mut prompt = vec![2];
+        assert_eq!(heal_prompt(&tok_trie, &mut prompt), None);
+        assert_eq!(prompt, vec![2]);
+    }
+}
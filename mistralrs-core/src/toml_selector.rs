@@ -235,6 +235,9 @@ pub enum TomlModelSelected {
 
         /// Path to a topology YAML file.
         topology: Option<String>,
+
+        /// Downscale images, preserving their aspect ratio, so that neither side is longer than this.
+        max_image_edge: Option<u32>,
     },
 }
 
@@ -533,11 +536,13 @@ fn loader_from_selected(
             arch,
             dtype: _,
             topology,
+            max_image_edge,
         } => VisionLoaderBuilder::new(
             VisionSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
                 topology: Topology::from_option_path(topology)?,
+                max_image_edge,
             },
             args.chat_template,
             args.tokenizer_json,
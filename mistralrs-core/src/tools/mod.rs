@@ -1,10 +1,12 @@
 mod request;
 mod response;
+mod stream;
 
 pub use request::*;
 pub use response::*;
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::collections::HashMap;
+pub(crate) use stream::ToolCallStream;
 use uuid::Uuid;
 
 pub struct ToolCallingMatcher {
@@ -30,6 +32,11 @@ impl ToolCallingMatcher {
         Ok(Self { tool_choice })
     }
 
+    /// Whether tool calls may be returned, which is not the case with [`ToolChoice::None`].
+    pub(crate) fn calls_allowed(&self) -> bool {
+        !matches!(self.tool_choice, ToolChoice::None)
+    }
+
     pub fn get_call(&self, message: &str) -> anyhow::Result<Vec<ToolCallResponse>> {
         if matches!(self.tool_choice, ToolChoice::None) {
             return Ok(Vec::new());
@@ -93,3 +100,98 @@ impl ToolCallingMatcher {
         }
     }
 }
+
+/// The JSON schema of a call to the tool named `name`, which must be one of `tools`. It is used to
+/// constrain generation when this tool is forced with [`ToolChoice::Tool`].
+pub(crate) fn forced_tool_call_schema(name: &str, tools: &[Tool]) -> anyhow::Result<String> {
+    let Some(tool) = tools.iter().find(|tool| tool.function.name == name) else {
+        anyhow::bail!("Tool choice `{name}` is not one of the tools of the request.");
+    };
+    let parameters = match &tool.function.parameters {
+        Some(parameters) => serde_json::to_value(parameters)?,
+        None => json!({"type": "object"}),
+    };
+    Ok(json!({
+        "type": "object",
+        "properties": {
+            "name": {"const": name},
+            "parameters": parameters,
+        },
+        "required": ["name", "parameters"],
+    })
+    .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        forced_tool_call_schema, Function, Tool, ToolCallingMatcher, ToolChoice, ToolType,
+    };
+    use crate::{
+        aici::{recognizer::FunctionalRecognizer, rx::RecRx, toktree::SpecialToken},
+        json_schema::json_schema_to_regex,
+    };
+
+    fn tool(name: &str, parameters: &str) -> Tool {
+        Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: None,
+                name: name.to_string(),
+                parameters: serde_json::from_str(parameters).unwrap(),
+            },
+        }
+    }
+
+    fn accepts(rx: &RecRx, text: &str) -> bool {
+        let mut state = rx.initial();
+        for byte in text.bytes() {
+            match rx.try_append(state, byte) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        rx.special_allowed(state, SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn forced_tool_yields_parseable_call() {
+        let tools = vec![
+            tool("get_time", "null"),
+            tool(
+                "get_weather",
+                r#"{
+                    "type": "object",
+                    "properties": {
+                        "city": {"type": "string"},
+                        "unit": {"enum": ["celsius", "fahrenheit"]}
+                    },
+                    "required": ["city"]
+                }"#,
+            ),
+        ];
+        let schema = forced_tool_call_schema("get_weather", &tools).unwrap();
+        let rx = RecRx::from_rx(&json_schema_to_regex(&schema).unwrap(), None).unwrap();
+        let matcher = ToolCallingMatcher::new(ToolChoice::Tool(tools[1].clone())).unwrap();
+
+        for call in [
+            r#"{"name": "get_weather", "parameters": {"city": "Paris"}}"#,
+            r#"{"name":"get_weather","parameters":{"city":"Oslo","unit":"celsius"}}"#,
+        ] {
+            assert!(accepts(&rx, call), "{call} should be accepted");
+            let calls = matcher.get_call(call).unwrap();
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[0].function.name, "get_weather");
+        }
+        for call in [
+            r#"{"name": "get_time", "parameters": {}}"#,
+            r#"{"name": "get_weather", "parameters": {"unit": "celsius"}}"#,
+            r#"{"name": "get_weather"}"#,
+            "The weather is sunny.",
+        ] {
+            assert!(!accepts(&rx, call), "{call} should be rejected");
+        }
+
+        assert!(forced_tool_call_schema("get_news", &tools).is_err());
+    }
+}
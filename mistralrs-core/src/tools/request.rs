@@ -14,6 +14,8 @@ pub enum ToolChoice {
     None,
     #[serde(rename = "auto")]
     Auto,
+    /// Force a call to this tool, which must be one of the tools of the request. Only its name is
+    /// used.
     #[serde(untagged)]
     Tool(Tool),
 }
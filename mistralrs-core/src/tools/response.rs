@@ -23,3 +23,26 @@ pub struct ToolCallResponse {
     pub tp: ToolCallType,
     pub function: CalledFunction,
 }
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CalledFunctionDelta {
+    /// Only set in the first delta of a call.
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+/// A part of a tool call in a streamed [`Delta`](crate::Delta). The first part of a call has its
+/// `id`, type and function name, and the `arguments` of all its parts concatenate to the JSON
+/// arguments of the call.
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub tp: Option<ToolCallType>,
+    pub function: CalledFunctionDelta,
+}
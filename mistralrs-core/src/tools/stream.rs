@@ -0,0 +1,309 @@
+use uuid::Uuid;
+
+use super::{CalledFunctionDelta, ToolCallDelta, ToolCallType};
+
+#[derive(Default, PartialEq)]
+enum Mode {
+    /// Only whitespace was generated so far.
+    #[default]
+    Undecided,
+    Content,
+    ToolCalls,
+}
+
+/// The call object being parsed.
+#[derive(Default)]
+struct CallState {
+    /// The key of the current member, `None` before its key is read.
+    key: Option<String>,
+    /// The raw contents of the string being read at the depth of the members.
+    string: String,
+    name: Option<String>,
+    in_arguments: bool,
+    /// The arguments which were not sent yet. Before the name is known, these are held back.
+    arguments: String,
+    /// The index of this call, once its name was sent.
+    index: Option<usize>,
+}
+
+/// Incremental extraction of tool calls from the streamed text of a chat completion, so that their
+/// arguments are sent as they are generated.
+///
+/// A completion starting with `{` or `[` is parsed as tool calls in the formats accepted by
+/// [`ToolCallingMatcher`](super::ToolCallingMatcher): objects with a `name` and `parameters` or
+/// `arguments`, or an array of them. The first delta of a call carries its id and name, and the
+/// `arguments` of all its deltas concatenate to the JSON arguments.
+#[derive(Default)]
+pub(crate) struct ToolCallStream {
+    mode: Mode,
+    /// All the text of the completion while it is not known to be content.
+    held: String,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// The depth of the members of the call objects: 1 for a single call, 2 for an array of calls.
+    call_depth: usize,
+    call: CallState,
+    n_calls: usize,
+}
+
+impl ToolCallStream {
+    /// Consume the next `text` of the completion, returning the content to send and the tool call
+    /// deltas.
+    pub(crate) fn push(&mut self, text: &str) -> (String, Vec<ToolCallDelta>) {
+        if self.mode == Mode::Content {
+            return (text.to_string(), Vec::new());
+        }
+        let mut start = self.held.len();
+        self.held.push_str(text);
+        if self.mode == Mode::Undecided {
+            self.call_depth = match self.held.trim_start().chars().next() {
+                None => return (String::new(), Vec::new()),
+                Some('{') => 1,
+                Some('[') => 2,
+                Some(_) => {
+                    self.mode = Mode::Content;
+                    return (std::mem::take(&mut self.held), Vec::new());
+                }
+            };
+            self.mode = Mode::ToolCalls;
+            start = 0;
+        }
+
+        let mut deltas = Vec::new();
+        let new = self.held[start..].to_string();
+        for c in new.chars() {
+            self.scan(c, &mut deltas);
+        }
+        self.flush_arguments(&mut deltas);
+        (String::new(), deltas)
+    }
+
+    /// The held back text to send as content once the completion is done, if no tool call was
+    /// found in it.
+    pub(crate) fn finish(&mut self) -> String {
+        if self.n_calls == 0 {
+            std::mem::take(&mut self.held)
+        } else {
+            String::new()
+        }
+    }
+
+    /// Whether a tool call was sent.
+    pub(crate) fn has_tool_calls(&self) -> bool {
+        self.n_calls > 0
+    }
+
+    fn scan(&mut self, c: char, deltas: &mut Vec<ToolCallDelta>) {
+        let at_members = self.depth == self.call_depth;
+        if self.call.in_arguments && at_members && !self.in_string && matches!(c, ',' | '}') {
+            self.call.in_arguments = false;
+        }
+        if self.call.in_arguments {
+            self.track_value(c);
+            if !(self.call.arguments.is_empty() && c.is_whitespace()) {
+                self.call.arguments.push(c);
+            }
+            return;
+        }
+
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+            } else if c == '"' {
+                self.in_string = false;
+                if at_members {
+                    self.end_string(deltas);
+                }
+                return;
+            }
+            if at_members {
+                self.call.string.push(c);
+            }
+            return;
+        }
+        match c {
+            '"' => {
+                self.in_string = true;
+                self.call.string.clear();
+            }
+            '{' | '[' => {
+                self.depth += 1;
+                if c == '{' && self.depth == self.call_depth {
+                    self.call = CallState::default();
+                }
+            }
+            '}' | ']' => {
+                if c == '}' && at_members {
+                    self.flush_arguments(deltas);
+                    self.call = CallState::default();
+                }
+                self.depth = self.depth.saturating_sub(1);
+            }
+            ':' if at_members => {
+                self.call.in_arguments =
+                    matches!(self.call.key.as_deref(), Some("parameters" | "arguments"));
+            }
+            ',' if at_members => self.call.key = None,
+            _ => {}
+        }
+    }
+
+    /// Track the nesting of the arguments value.
+    fn track_value(&mut self, c: char) {
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+            } else if c == '"' {
+                self.in_string = false;
+            }
+            return;
+        }
+        match c {
+            '"' => self.in_string = true,
+            '{' | '[' => self.depth += 1,
+            '}' | ']' => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    /// A string at the depth of the members ended: it is a key, or the value of one.
+    fn end_string(&mut self, deltas: &mut Vec<ToolCallDelta>) {
+        let string = std::mem::take(&mut self.call.string);
+        match self.call.key.as_deref() {
+            None => self.call.key = Some(string),
+            Some("name") if self.call.name.is_none() => {
+                let name = serde_json::from_str(&format!("\"{string}\"")).unwrap_or(string);
+                self.call.name = Some(name);
+                self.start_call(deltas);
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Send the id and name of the call, with the arguments read before its name.
+    fn start_call(&mut self, deltas: &mut Vec<ToolCallDelta>) {
+        let index = self.n_calls;
+        self.n_calls += 1;
+        self.call.index = Some(index);
+        deltas.push(ToolCallDelta {
+            index,
+            id: Some(format!("call-{}", Uuid::new_v4())),
+            tp: Some(ToolCallType::Function),
+            function: CalledFunctionDelta {
+                name: self.call.name.clone(),
+                arguments: std::mem::take(&mut self.call.arguments),
+            },
+        });
+    }
+
+    fn flush_arguments(&mut self, deltas: &mut Vec<ToolCallDelta>) {
+        let Some(index) = self.call.index else {
+            return;
+        };
+        if self.call.arguments.is_empty() {
+            return;
+        }
+        let arguments = std::mem::take(&mut self.call.arguments);
+        match deltas.last_mut() {
+            Some(last) if last.index == index => last.function.arguments.push_str(&arguments),
+            _ => deltas.push(ToolCallDelta {
+                index,
+                id: None,
+                tp: None,
+                function: CalledFunctionDelta {
+                    name: None,
+                    arguments,
+                },
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::ToolCallStream;
+    use crate::tools::ToolCallDelta;
+
+    /// Stream `text` in chunks of `chunk_len` characters.
+    fn stream(text: &str, chunk_len: usize) -> (String, Vec<ToolCallDelta>) {
+        let mut tool_stream = ToolCallStream::default();
+        let chars = text.chars().collect::<Vec<_>>();
+        let mut content = String::new();
+        let mut deltas = Vec::new();
+        for chunk in chars.chunks(chunk_len) {
+            let (text, chunk_deltas) = tool_stream.push(&chunk.iter().collect::<String>());
+            content.push_str(&text);
+            deltas.extend(chunk_deltas);
+        }
+        content.push_str(&tool_stream.finish());
+        (content, deltas)
+    }
+
+    /// The name and concatenated arguments of each call.
+    fn calls(deltas: &[ToolCallDelta]) -> Vec<(String, String)> {
+        let mut calls: Vec<(String, String)> = Vec::new();
+        for delta in deltas {
+            if delta.index == calls.len() {
+                assert!(delta.id.is_some());
+                calls.push((delta.function.name.clone().unwrap(), String::new()));
+            } else {
+                assert!(delta.id.is_none() && delta.function.name.is_none());
+            }
+            calls[delta.index].1.push_str(&delta.function.arguments);
+        }
+        calls
+    }
+
+    #[test]
+    fn argument_fragments_concatenate_to_json() {
+        let text = r#"{"name": "get_weather", "parameters": {"city": "Paris, \"FR\"", "days": [1, {"a": "}"}]}}"#;
+        for chunk_len in [1, 3, 7, text.len()] {
+            let (content, deltas) = stream(text, chunk_len);
+            assert_eq!(content, "");
+            let calls = calls(&deltas);
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[0].0, "get_weather");
+            let arguments: Value = serde_json::from_str(&calls[0].1).unwrap();
+            assert_eq!(
+                arguments,
+                json!({"city": "Paris, \"FR\"", "days": [1, {"a": "}"}]})
+            );
+        }
+        // A delta is sent for each chunk of the arguments.
+        assert!(stream(text, 3).1.len() > 10);
+    }
+
+    #[test]
+    fn array_of_calls_with_arguments_first() {
+        let text = r#" [{"arguments": {"x": 1}, "name": "a"}, {"name": "b", "arguments": {}}]"#;
+        let (content, deltas) = stream(text, 4);
+        assert_eq!(content, "");
+        assert_eq!(
+            calls(&deltas),
+            vec![
+                ("a".to_string(), r#"{"x": 1}"#.to_string()),
+                ("b".to_string(), "{}".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn content_is_not_held_back() {
+        let (content, deltas) = stream("The weather is {sunny}.", 2);
+        assert_eq!(content, "The weather is {sunny}.");
+        assert!(deltas.is_empty());
+
+        // JSON which is not a tool call is sent once the completion is done.
+        let mut tool_stream = ToolCallStream::default();
+        assert_eq!(tool_stream.push(r#"{"answer": 42}"#).0, "");
+        assert!(!tool_stream.has_tool_calls());
+        assert_eq!(tool_stream.finish(), r#"{"answer": 42}"#);
+    }
+}
@@ -64,30 +64,20 @@ impl Topology {
     }
 
     fn add_from_range(&mut self, range: Range<usize>, topo: LayerTopology) {
-        let n_repeat = 0..=range.end - range.start;
-        if range.start == 0 && self.0.is_empty() {
-            // Simple case, starting out
-            self.0
-                .extend(n_repeat.into_iter().map(|_| Some(topo.clone())));
-        } else if range.end >= self.0.len() && range.start > self.0.len() {
-            // Adding new layers. Add Nones to pad
-            self.0.extend(vec![None; range.start - self.0.len()]);
-            self.0
-                .extend(n_repeat.into_iter().map(|_| Some(topo.clone())));
-        } else if range.end >= self.0.len() && range.start < self.0.len() {
-            // Replacing some layers at least but the range exceeds
-            self.0.extend(vec![None; range.end - self.0.len()]);
-            self.0
-                .extend(n_repeat.into_iter().map(|_| Some(topo.clone())));
-        } else {
-            assert!(self.0.len() > range.end);
-            self.0.splice(
-                range.clone(),
-                n_repeat.into_iter().map(|_| Some(topo.clone())),
-            );
+        if self.0.len() < range.end {
+            // Pad any gap with layers which are not covered
+            self.0.resize(range.end, None);
+        }
+        for layer in &mut self.0[range] {
+            *layer = Some(topo.clone());
         }
     }
 
+    /// The ISQ type of `layer`, if the topology covers it and sets one.
+    pub fn layer_isq(&self, layer: usize) -> Option<IsqType> {
+        self.0.get(layer)?.as_ref()?.isq
+    }
+
     #[allow(clippy::should_implement_trait)]
     pub fn from_str(topology: &str) -> anyhow::Result<Self> {
         let deser: DeserTopology = serde_yaml::from_str(topology)?;
@@ -148,3 +138,33 @@ impl Topology {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_quant::IsqType;
+
+    use super::Topology;
+
+    #[test]
+    fn per_layer_isq_ranges() {
+        let topology = Topology::from_str(
+            "
+0-16:
+  isq: q4k
+16-32:
+  isq: q8_0
+# Skip 32-34
+34:
+  isq: Q6K
+",
+        )
+        .unwrap();
+        assert_eq!(topology.0.len(), 35);
+        assert!((0..16).all(|layer| topology.layer_isq(layer) == Some(IsqType::Q4K)));
+        assert!((16..32).all(|layer| topology.layer_isq(layer) == Some(IsqType::Q8_0)));
+        assert_eq!(topology.layer_isq(32), None);
+        assert_eq!(topology.layer_isq(33), None);
+        assert_eq!(topology.layer_isq(34), Some(IsqType::Q6K));
+        assert_eq!(topology.layer_isq(35), None);
+    }
+}
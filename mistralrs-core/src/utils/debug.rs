@@ -8,6 +8,10 @@ static LOGGER: std::sync::OnceLock<()> = std::sync::OnceLock::new();
 
 /// This should be called to initialize the debug flag and logging.
 /// This should not be called in mistralrs-core code due to Rust usage.
+///
+/// Each request is traced with a `request` span containing the `request_id`, `model`, `prompt_tokens`,
+/// `n_choices`, `completion_tokens`, `duration_ms` and `finish_reason`. If a global subscriber was already
+/// set (for example with a `tracing-opentelemetry` layer), it is kept.
 pub fn initialize_logging() {
     let is_debug = std::env::var("MISTRALRS_DEBUG")
         .unwrap_or_default()
@@ -22,7 +26,8 @@ pub fn initialize_logging() {
                 LevelFilter::INFO.into()
             })
             .from_env_lossy();
-        tracing_subscriber::fmt().with_env_filter(filter).init();
+        // Keep a subscriber which was already installed by the user, e.g. one with a `tracing-opentelemetry` layer.
+        let _ = tracing_subscriber::fmt().with_env_filter(filter).try_init();
     });
 }
 
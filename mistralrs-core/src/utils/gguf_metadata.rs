@@ -23,6 +23,12 @@ impl ContentMetadata<'_> {
             .or_else(|e| anyhow::bail!("`{prop_key}` `{e}`"))
     }
 
+    // Retrieve an unsigned integer prop, which GGUF writers store as either a `u32` or a `u64`:
+    pub fn get_u64(&self, field_name: &str) -> Result<u64, anyhow::Error> {
+        self.get_value::<u64>(field_name)
+            .or_else(|_| self.get_value::<u32>(field_name).map(u64::from))
+    }
+
     // Retrieve a prop the struct needs by querying the metadata content:
     pub fn get_option_value<T: TryFromValue>(
         &self,
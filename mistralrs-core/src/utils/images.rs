@@ -0,0 +1,54 @@
+use image::DynamicImage;
+use thiserror::Error;
+
+/// Encoded images larger than this are rejected before they are decoded.
+pub const MAX_IMAGE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+#[error("Image of {0} bytes is larger than the maximum of {MAX_IMAGE_BYTES} bytes.")]
+/// An image sent with a request was larger than [`MAX_IMAGE_BYTES`].
+pub struct ImageTooLarge(pub usize);
+
+/// Decode an image which was sent with a request. Images larger than [`MAX_IMAGE_BYTES`] are
+/// rejected with an [`ImageTooLarge`] error.
+pub fn load_image(bytes: &[u8]) -> anyhow::Result<DynamicImage> {
+    if bytes.len() > MAX_IMAGE_BYTES {
+        return Err(ImageTooLarge(bytes.len()).into());
+    }
+    Ok(image::load_from_memory(bytes)?)
+}
+
+/// Downscale `image`, preserving its aspect ratio, so that neither side is longer than `max_edge`.
+/// Smaller images are returned unchanged.
+pub(crate) fn limit_image_edge(image: DynamicImage, max_edge: u32) -> DynamicImage {
+    if image.width() <= max_edge && image.height() <= max_edge {
+        image
+    } else {
+        image.thumbnail(max_edge, max_edge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::DynamicImage;
+
+    use super::{limit_image_edge, load_image, ImageTooLarge, MAX_IMAGE_BYTES};
+
+    #[test]
+    fn oversized_images_are_downscaled() {
+        let image = limit_image_edge(DynamicImage::new_luma8(10_000, 10_000), 1024);
+        assert_eq!((image.width(), image.height()), (1024, 1024));
+
+        let image = limit_image_edge(DynamicImage::new_rgb8(10_000, 2_500), 1024);
+        assert_eq!((image.width(), image.height()), (1024, 256));
+
+        let image = limit_image_edge(DynamicImage::new_rgb8(640, 480), 1024);
+        assert_eq!((image.width(), image.height()), (640, 480));
+    }
+
+    #[test]
+    fn oversized_encodings_are_rejected() {
+        let err = load_image(&vec![0; MAX_IMAGE_BYTES + 1]).unwrap_err();
+        assert!(err.is::<ImageTooLarge>());
+    }
+}
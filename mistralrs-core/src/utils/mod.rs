@@ -1,5 +1,6 @@
 pub(crate) mod debug;
 pub(crate) mod gguf_metadata;
+pub(crate) mod images;
 pub(crate) mod memory_usage;
 pub(crate) mod model_config;
 pub(crate) mod normal;
@@ -113,6 +114,7 @@ macro_rules! handle_pipeline_forward_error {
                                 tool_calls: Vec::new(),
                             },
                             logprobs: None,
+                            matched_stop: None,
                         };
                         seq.add_choice_to_group(choice);
                     } else {
@@ -121,6 +123,7 @@ macro_rules! handle_pipeline_forward_error {
                             index: seq.get_response_index(),
                             text: res,
                             logprobs: None,
+                            matched_stop: None,
                         };
                         seq.add_completion_choice_to_group(choice);
                     }
@@ -138,6 +141,7 @@ macro_rules! handle_pipeline_forward_error {
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            sampling_params_used: group.get_sampling_params_used(),
                         };
 
                         seq.responder()
@@ -156,6 +160,7 @@ macro_rules! handle_pipeline_forward_error {
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            sampling_params_used: group.get_sampling_params_used(),
                         };
 
                         seq.responder()
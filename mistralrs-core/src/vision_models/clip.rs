@@ -4,7 +4,10 @@
 use candle_core::{IndexOp, Result, Shape, Tensor, D};
 use candle_nn::{Conv2dConfig, Module};
 
-use crate::{layers::FusedBiasLinear, serde_default_fn};
+use crate::{
+    layers::{FusedBiasLinear, ScaledDotProductAttention},
+    serde_default_fn,
+};
 
 #[derive(Debug, Clone, Copy, serde::Deserialize)]
 pub enum Activation {
@@ -46,6 +49,8 @@ pub struct ClipConfig {
     pub patch_size: usize,
     #[serde(default = "d_act")]
     pub hidden_act: Activation,
+    #[serde(default)]
+    pub use_flash_attn: bool,
 }
 
 // https://github.com/huggingface/transformers/blob/f6fa0f0bf0796ac66f201f23bdb8585de1609add/src/transformers/models/clip/modeling_clip.py#L112
@@ -115,8 +120,8 @@ struct ClipAttention {
     q_proj: FusedBiasLinear,
     out_proj: FusedBiasLinear,
     head_dim: usize,
-    scale: f64,
     num_attention_heads: usize,
+    use_flash_attn: bool,
 }
 
 impl ClipAttention {
@@ -128,7 +133,6 @@ impl ClipAttention {
         let q_proj = candle_nn::linear(hidden_size, hidden_size, vs.pp("q_proj"))?;
         let out_proj = candle_nn::linear(hidden_size, hidden_size, vs.pp("out_proj"))?;
         let head_dim = hidden_size / num_attention_heads;
-        let scale = (head_dim as f64).powf(-0.5);
 
         Ok(ClipAttention {
             k_proj: k_proj.try_into()?,
@@ -136,8 +140,8 @@ impl ClipAttention {
             q_proj: q_proj.try_into()?,
             out_proj: out_proj.try_into()?,
             head_dim,
-            scale,
             num_attention_heads,
+            use_flash_attn: c.use_flash_attn,
         })
     }
 
@@ -150,35 +154,19 @@ impl ClipAttention {
     fn forward(&self, xs: &Tensor, causal_attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let (bsz, seq_len, hidden_size) = xs.dims3()?;
 
-        let query_states = (self.q_proj.forward(xs)? * self.scale)?;
-        let proj_shape = (bsz * self.num_attention_heads, seq_len, self.head_dim);
-        let query_states = self
-            .shape(&query_states, seq_len, bsz)?
-            .reshape(proj_shape)?;
-        let key_states = self
-            .shape(&self.k_proj.forward(xs)?, seq_len, bsz)?
-            .reshape(proj_shape)?;
-        let value_states = self
-            .shape(&self.v_proj.forward(xs)?, seq_len, bsz)?
-            .reshape(proj_shape)?;
-        let attn_weights = query_states.matmul(&key_states.transpose(1, 2)?)?;
-
-        let src_len = key_states.dim(1)?;
-
-        let attn_weights = if let Some(causal_attention_mask) = causal_attention_mask {
-            attn_weights
-                .reshape((bsz, self.num_attention_heads, seq_len, src_len))?
-                .broadcast_add(causal_attention_mask)?
-                .reshape((bsz * self.num_attention_heads, seq_len, src_len))?
-        } else {
-            attn_weights
-        };
-
-        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
-
-        let attn_output = attn_weights.matmul(&value_states)?;
+        let query_states = self.shape(&self.q_proj.forward(xs)?, seq_len, bsz)?;
+        let key_states = self.shape(&self.k_proj.forward(xs)?, seq_len, bsz)?;
+        let value_states = self.shape(&self.v_proj.forward(xs)?, seq_len, bsz)?;
+
+        let attn_output = ScaledDotProductAttention.run_bidirectional_attention(
+            &query_states,
+            &key_states,
+            &value_states,
+            self.head_dim,
+            causal_attention_mask,
+            self.use_flash_attn,
+        )?;
         let attn_output = attn_output
-            .reshape((bsz, self.num_attention_heads, seq_len, self.head_dim))?
             .transpose(1, 2)?
             .reshape((bsz, seq_len, hidden_size))?;
         self.out_proj.forward(&attn_output)
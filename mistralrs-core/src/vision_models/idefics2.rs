@@ -11,7 +11,7 @@ use std::{any::Any, ops::Mul};
 use crate::{
     amoe::{AnyMoeBaseModelMixin, MlpLayer},
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, QLinear, RmsNorm},
+    layers::{repeat_kv, CausalMasker, QLinear, RmsNorm, ScaledDotProductAttention},
     models::mistral::Model as Mistral,
     paged_attention::{AttentionImplementation, ModelConfigMetadata},
     pipeline::{
@@ -337,20 +337,18 @@ struct Attention {
     embed_dim: usize,
     num_heads: usize,
     head_dim: usize,
-    scale: f64,
     q_proj: QLinear,
     k_proj: QLinear,
     v_proj: QLinear,
     o_proj: QLinear,
-    neg_inf: Tensor,
+    use_flash_attn: bool,
 }
 
 impl Attention {
-    fn new(config: VisionConfig, vb: VarBuilder) -> Result<Self> {
+    fn new(config: VisionConfig, use_flash_attn: bool, vb: VarBuilder) -> Result<Self> {
         let embed_dim = config.hidden_size;
         let num_heads = config.num_attention_heads;
         let head_dim = embed_dim / num_heads;
-        let scale = 1.0 / (head_dim as f64).sqrt();
 
         let q_proj = linear(embed_dim, embed_dim, vb.pp("q_proj"))?;
         let k_proj = linear(embed_dim, embed_dim, vb.pp("k_proj"))?;
@@ -361,12 +359,11 @@ impl Attention {
             embed_dim,
             num_heads,
             head_dim,
-            scale,
             q_proj: QLinear::from_linear(q_proj),
             k_proj: QLinear::from_linear(k_proj),
             v_proj: QLinear::from_linear(v_proj),
             o_proj: QLinear::from_linear(o_proj),
-            neg_inf: Tensor::new(f32::NEG_INFINITY, vb.device())?.to_dtype(vb.dtype())?,
+            use_flash_attn,
         })
     }
 
@@ -397,16 +394,15 @@ impl Attention {
             .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
             .transpose(1, 2)?;
 
-        let attn_weights =
-            (q.contiguous()?.matmul(&k.transpose(2, 3)?.contiguous()?)? * self.scale)?;
-
-        let attn_weights = CausalMasker.apply_mask_one_and_zero(
-            &attention_mask.map(|x| x.to_dtype(DType::U8).unwrap()),
-            attn_weights,
-            &self.neg_inf,
+        // The mask is additive, with padding patches set to a large negative value.
+        let mut attn_output = ScaledDotProductAttention.run_bidirectional_attention(
+            &q,
+            &k,
+            &v,
+            self.head_dim,
+            attention_mask,
+            self.use_flash_attn,
         )?;
-        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
-        let mut attn_output = attn_weights.matmul(&v.contiguous()?)?;
 
         if self.q_proj.is_quant() {
             attn_output = attn_output.to_dtype(DType::F32)?;
@@ -463,9 +459,9 @@ struct EncoderLayer {
 }
 
 impl EncoderLayer {
-    fn new(config: VisionConfig, vb: VarBuilder) -> Result<Self> {
+    fn new(config: VisionConfig, use_flash_attn: bool, vb: VarBuilder) -> Result<Self> {
         let mlp = VisionMLP::new(config.clone(), vb.pp("mlp"))?;
-        let attn = Attention::new(config.clone(), vb.pp("self_attn"))?;
+        let attn = Attention::new(config.clone(), use_flash_attn, vb.pp("self_attn"))?;
         let layer_norm_1 = layer_norm(
             config.hidden_size,
             config.layer_norm_eps,
@@ -503,11 +499,15 @@ struct Encoder {
 }
 
 impl Encoder {
-    fn new(config: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+    fn new(config: &VisionConfig, use_flash_attn: bool, vb: VarBuilder) -> Result<Self> {
         let mut layers = Vec::new();
         let vb_l = vb.pp("layers");
         for i in 0..config.num_hidden_layers {
-            layers.push(EncoderLayer::new(config.clone(), vb_l.pp(i))?);
+            layers.push(EncoderLayer::new(
+                config.clone(),
+                use_flash_attn,
+                vb_l.pp(i),
+            )?);
         }
         Ok(Self { layers })
     }
@@ -529,14 +529,14 @@ struct VisionTransformer {
 }
 
 impl VisionTransformer {
-    fn new(config: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+    fn new(config: &VisionConfig, use_flash_attn: bool, vb: VarBuilder) -> Result<Self> {
         let embeddings = VisionEmbeddings::new(config, vb.pp("embeddings"))?;
         let post_layernorm = layer_norm(
             config.hidden_size,
             config.layer_norm_eps,
             vb.pp("post_layernorm"),
         )?;
-        let encoder = Encoder::new(config, vb.pp("encoder"))?;
+        let encoder = Encoder::new(config, use_flash_attn, vb.pp("encoder"))?;
         Ok(Self {
             embeddings,
             encoder,
@@ -889,6 +889,7 @@ impl Idefics2 {
         )?;
         let vision_model = VisionTransformer::new(
             &config.vision_config,
+            config.text_config.use_flash_attn,
             vb_m.pp("vision_model")
                 .set_device(text_model.device().clone()),
         )?;
@@ -1059,6 +1060,7 @@ impl Idefics2 {
             start_offsets_kernel,
             context_lens,
             metadata,
+            None,
         )
     }
 }
@@ -1075,6 +1077,9 @@ impl IsqModel for Idefics2 {
     ) {
         self.text_model.get_layers()
     }
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        self.text_model.imatrix_names()
+    }
 }
 
 // AnyMoE is forwarded to the base model
@@ -1116,7 +1121,8 @@ impl VisionModel for Idefics2 {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
-        _: Vec<usize>, // Ignore, it is for phi3
+        _: Vec<usize>,    // Ignore, it is for phi3
+        _: &[[usize; 3]], // Ignore, it is for M-RoPE models
         model_specific_args: Box<dyn Any>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> candle_core::Result<Tensor> {
@@ -18,7 +18,7 @@ use crate::{
         InputProcessorOutput, InputsProcessor, InputsProcessorType, MessagesAction, Processor,
     },
     sequence::Sequence,
-    vision_models::ModelInputs,
+    vision_models::{text_mrope_position_ids, ModelInputs},
     MessageContent, Pipeline, Tool,
 };
 
@@ -57,6 +57,12 @@ impl Processor for Idefics2Processor {
         add_generation_prompt: bool,
         tools: Vec<Tool>,
     ) -> anyhow::Result<Vec<u32>> {
+        let n_images = messages
+            .iter()
+            .filter_map(|message| message.get("content")?.as_ref().right())
+            .flatten()
+            .filter(|item| item.get("type").is_some_and(|tp| tp == "image"))
+            .count();
         let mut prompt = apply_chat_template(
             pipeline,
             messages,
@@ -64,6 +70,13 @@ impl Processor for Idefics2Processor {
             self.template_action(),
             tools,
         )?;
+        let n_placeholders = prompt.matches(self.image_token).count();
+        if n_placeholders != n_images {
+            anyhow::bail!(
+                "The chat template produced {n_placeholders} `{}` placeholders for {n_images} images.",
+                self.image_token
+            );
+        }
 
         let mut image_str = format!(
             "{}{}{}",
@@ -214,12 +227,14 @@ impl InputsProcessor for Idefics2ImageProcessor {
             (None, None)
         };
 
+        let mrope_position_ids = text_mrope_position_ids(&positions, input.dims()[1]);
         let inputs: Box<dyn Any> = Box::new(ModelInputs {
             input_ids: input,
             seqlen_offsets: positions,
             seqlen_offsets_kernel: positions_kernel,
             context_lens,
             position_ids,
+            mrope_position_ids,
             pixel_values,
             model_specific_args: Box::new(pixel_attention_mask),
             paged_attn_meta,
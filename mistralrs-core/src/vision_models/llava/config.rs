@@ -81,6 +81,7 @@ impl Config {
             max_position_embeddings: self.text_config.max_position_embeddings,
             rope_scaling: self.text_config.rope_scaling.clone(),
             quantization_config: None,
+            partial_rotary_factor: None,
         }
     }
 
@@ -113,6 +114,7 @@ impl Config {
             image_size: self.vision_config.image_size,
             patch_size: self.vision_config.patch_size,
             hidden_act: ClipActivation::QuickGelu,
+            use_flash_attn: self.use_flash_attn,
         }
     }
 }
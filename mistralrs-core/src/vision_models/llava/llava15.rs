@@ -222,7 +222,7 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
-        position_ids: Vec<usize>,
+        mrope_position_ids: &[[usize; 3]],
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         if let Some(ref pixel_values) = pixel_values {
@@ -238,15 +238,17 @@ impl Model {
                 seqlen_offsets,
                 start_offsets_kernel,
                 context_lens,
+                mrope_position_ids,
                 metadata,
             )
         } else {
-            self.llm.forward(
+            self.llm.forward_input_embed(
                 input_ids,
+                self.llm.embed(input_ids)?,
                 seqlen_offsets,
                 start_offsets_kernel,
                 context_lens,
-                position_ids,
+                mrope_position_ids,
                 metadata,
             )
         }
@@ -265,6 +267,9 @@ impl IsqModel for Model {
     ) {
         self.llm.get_layers()
     }
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        self.llm.imatrix_names()
+    }
 }
 
 impl VisionModel for Model {
@@ -275,7 +280,8 @@ impl VisionModel for Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
-        position_ids: Vec<usize>,
+        _: Vec<usize>, // Ignore, it is for phi3
+        mrope_position_ids: &[[usize; 3]],
         _model_specific_args: Box<dyn std::any::Any>, // pixel attention mask, or image sizes, or anything else
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> candle_core::Result<Tensor> {
@@ -289,7 +295,7 @@ impl VisionModel for Model {
             seqlen_offsets,
             start_offsets_kernel,
             context_lens,
-            position_ids,
+            mrope_position_ids,
             metadata,
         )
     }
@@ -15,19 +15,24 @@ use crate::{
     amoe::{AnyMoeBaseModelMixin, AnyMoeTrainableLayer, MlpLayer, MoeMlp},
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
-    layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention},
+    layers::{
+        repeat_kv, verify_attention_heads, CausalMasker, MRotaryEmbedding, MatMul, RmsNorm,
+        ScaledDotProductAttention,
+    },
     layers_masker::PastKvLenCache,
     models::llama::Config,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits, text_models_inputs_processor::PagedAttentionInputMetadata, IsqModel,
+        extract_logits, imatrix_layer_names, imatrix_mlp_names,
+        text_models_inputs_processor::PagedAttentionInputMetadata, IsqModel, KvCacheDtype,
         NormalLoadingMetadata, NormalModel,
     },
     utils::progress::NiceProgressBar,
+    vision_models::text_mrope_position_ids,
     AnyMoeConfig, AnyMoeExpertType,
 };
 
-use super::{LLaVALLM, OrdinaryRoPE};
+use super::{rotary_embedding, LLaVALLM};
 
 struct CausalSelfAttention {
     q_proj: Arc<dyn QuantMethod>,
@@ -43,14 +48,16 @@ struct CausalSelfAttention {
 }
 
 impl CausalSelfAttention {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         x: &Tensor,
         attention_mask: &Option<Tensor>,
-        seqlen_offsets: &[usize],
+        _seqlen_offsets: &[usize],
         _start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         rope_parameter: (&Tensor, &Tensor),
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
@@ -70,16 +77,17 @@ impl CausalSelfAttention {
             v = v.to_dtype(original_dtype)?;
         }
 
-        let mut q = q
+        let mut q = q.reshape((b_sz * seq_len, self.num_attention_heads, self.head_dim))?;
+        let mut k = k.reshape((b_sz * seq_len, self.num_key_value_heads, self.head_dim))?;
+        MRotaryEmbedding::apply(rope_parameter.0, rope_parameter.1, &mut q, &mut k)?;
+        let q = q
             .reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?
             .transpose(1, 2)?
             .contiguous()?;
-        let mut k = k
+        let k = k
             .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
             .transpose(1, 2)?
             .contiguous()?;
-        q = OrdinaryRoPE::forward(&q, seqlen_offsets[0], rope_parameter.0, rope_parameter.1)?;
-        k = OrdinaryRoPE::forward(&k, seqlen_offsets[0], rope_parameter.0, rope_parameter.1)?;
         let v = v
             .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
             .transpose(1, 2)?;
@@ -99,8 +107,13 @@ impl CausalSelfAttention {
                 )?
             }
             None => {
-                let (k, v) =
-                    crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+                let (k, v) = crate::pipeline::Cache::update_kv_cache(
+                    &mut kv_cache[block_idx],
+                    kv_cache_dtype,
+                    k,
+                    v,
+                    false,
+                )?;
 
                 let k = repeat_kv(k, self.num_attention_heads / self.num_key_value_heads)?
                     .contiguous()?;
@@ -282,6 +295,7 @@ struct Block {
 }
 
 impl Block {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         x: &Tensor,
@@ -290,6 +304,7 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         rope_parameters: (&Tensor, &Tensor),
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
@@ -302,6 +317,7 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            kv_cache_dtype,
             rope_parameters,
             metadata,
         )? + residual)?;
@@ -351,7 +367,7 @@ pub struct Llama {
     pub kv_cache: crate::pipeline::Cache,
     pub device: Device,
     mapper: Box<dyn DeviceMapper + Send + Sync>,
-    rope_parameters: (Tensor, Tensor),
+    rotary_emb: MRotaryEmbedding,
     cfg: ModelConfigMetadata,
 }
 
@@ -371,6 +387,7 @@ impl Llama {
             seqlen_offsets,
             start_offsets_kernel,
             context_lens,
+            &text_mrope_position_ids(seqlen_offsets, input_ids.dim(1)?),
             metadata,
         )
     }
@@ -382,6 +399,11 @@ impl Llama {
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Self> {
+        verify_attention_heads(
+            Some(cfg.hidden_size),
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+        )?;
         let mapper = normal_loading_metadata.mapper;
         let wte = embedding(
             cfg.vocab_size,
@@ -430,7 +452,7 @@ impl Llama {
                     .expect("Failed to load block.")
                 })
                 .collect();
-        let rope_parameters = OrdinaryRoPE::create_parameters(
+        let rotary_emb = rotary_embedding(
             head_dim,
             cfg.max_position_embeddings,
             cfg.rope_theta,
@@ -445,7 +467,7 @@ impl Llama {
             kv_cache: crate::pipeline::Cache::new(cfg.num_hidden_layers, false),
             device: normal_loading_metadata.real_device,
             mapper,
-            rope_parameters,
+            rotary_emb,
             cfg: ModelConfigMetadata {
                 num_layers: cfg.num_hidden_layers,
                 hidden_size: cfg.hidden_size,
@@ -483,6 +505,21 @@ impl IsqModel for Llama {
         }
         (tensors, &*self.mapper)
     }
+    fn imatrix_names(&mut self) -> Option<Vec<Option<String>>> {
+        let mut names = vec![Some("output.weight".to_string())];
+        for (i, layer) in self.blocks.iter_mut().enumerate() {
+            names.extend(imatrix_layer_names(
+                i,
+                &["attn_q", "attn_k", "attn_v", "attn_output"],
+            ));
+            names.extend(imatrix_mlp_names(
+                i,
+                &mut *layer.mlp,
+                &["ffn_gate", "ffn_up", "ffn_down"],
+            ));
+        }
+        Some(names)
+    }
 }
 
 impl LLaVALLM for Llama {
@@ -496,10 +533,13 @@ impl LLaVALLM for Llama {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        mrope_position_ids: &[[usize; 3]],
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let mut x = input_embed;
+        let (cos, sin) = self.rotary_emb.cos_sin(mrope_position_ids)?;
         let mut cache = self.kv_cache.lock();
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             metadata
@@ -515,10 +555,11 @@ impl LLaVALLM for Llama {
                 &x,
                 &mask.clone().map(|m| m.to_device(x.device()).unwrap()),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(x.device())?,
                 block_idx,
                 &mut cache,
-                (&self.rope_parameters.0, &self.rope_parameters.1),
+                kv_cache_dtype,
+                (&cos.to_device(x.device())?, &sin.to_device(x.device())?),
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[block_idx].clone(), &mut **metadata)),
@@ -535,6 +576,7 @@ impl LLaVALLM for Llama {
 }
 
 impl NormalModel for Llama {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         input_ids: &Tensor,
@@ -543,6 +585,7 @@ impl NormalModel for Llama {
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         self.forward_input(
             input_ids,
@@ -564,6 +607,7 @@ impl NormalModel for Llama {
         _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        _global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
@@ -1,13 +1,15 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 use candle_core::{DType, Device, Result, Tensor};
 
-use crate::pipeline::{
-    text_models_inputs_processor::PagedAttentionInputMetadata, IsqModel, NormalModel,
+use crate::{
+    layers::MRotaryEmbedding,
+    pipeline::{text_models_inputs_processor::PagedAttentionInputMetadata, IsqModel, NormalModel},
 };
 
 pub(crate) trait LLaVALLM: IsqModel + NormalModel + Sync + Send {
     //Normal model without anymoe, but add embed and forward_input_embed. This is only a temporary solution. Finally when the rope problem solved for normal LLM models, we should refactor this.
     fn embed(&self, input_ids: &Tensor) -> Result<Tensor>;
+    #[allow(clippy::too_many_arguments)]
     fn forward_input_embed(
         &self,
         input_ids: &Tensor,  // only for masking
@@ -15,41 +17,30 @@ pub(crate) trait LLaVALLM: IsqModel + NormalModel + Sync + Send {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        mrope_position_ids: &[[usize; 3]],
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor>;
 }
 
-#[derive(Debug)]
-pub(crate) struct OrdinaryRoPE;
-
-impl OrdinaryRoPE {
-    fn create_parameters(
-        n_elem: usize,
-        max_seq_len: usize,
-        rope_theta: f32,
-        dtype: DType,
-        device: &Device,
-    ) -> Result<(Tensor, Tensor)> {
-        let theta: Vec<_> = (0..n_elem)
-            .step_by(2)
-            .map(|i| 1f32 / rope_theta.powf(i as f32 / n_elem as f32))
-            .collect();
-        let theta = Tensor::new(theta.as_slice(), device)?;
-        let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?
-            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-        let cos = idx_theta.cos()?.to_dtype(dtype)?;
-        let sin = idx_theta.sin()?.to_dtype(dtype)?;
-        Result::Ok((cos, sin))
-    }
-    fn forward(x: &Tensor, index_pos: usize, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
-        let (_b_sz, _, seq_len, _hidden_size) = x.dims4()?;
-        let cos = cos.narrow(0, index_pos, seq_len)?;
-        let sin = sin.narrow(0, index_pos, seq_len)?;
-        candle_nn::rotary_emb::rope(x, &cos, &sin)
-    }
+/// The rotary embedding of the LLaVA decoders. They place every token, including the image
+/// features, on a line, so all frequencies follow the temporal component of the position ids.
+fn rotary_embedding(
+    head_dim: usize,
+    max_seq_len: usize,
+    rope_theta: f32,
+    dtype: DType,
+    device: &Device,
+) -> Result<MRotaryEmbedding> {
+    MRotaryEmbedding::new(
+        rope_theta,
+        head_dim,
+        max_seq_len,
+        [head_dim / 2, 0, 0],
+        device,
+        dtype,
+    )
 }
+
 pub(crate) mod llama;
 pub(crate) mod mistral;
 
@@ -22,7 +22,7 @@ use crate::sequence::Sequence;
 use crate::vision_models::image_processor::{self, ImagePreProcessor, PreprocessedImages};
 use crate::vision_models::llava::config::Config as LLaVANextConfig;
 use crate::vision_models::preprocessor_config::{PreProcessorConfig, ToFilter};
-use crate::vision_models::{preprocessor_config, ModelInputs};
+use crate::vision_models::{preprocessor_config, text_mrope_position_ids, ModelInputs};
 
 use super::llava_next::LLaVANextVisionSpecificArgs;
 use super::utils::{
@@ -190,16 +190,21 @@ impl InputsProcessor for LLaVANextInputProcessor {
                             context_lens,
                             position_ids,
                             paged_attn_meta,
+                            early_exit_layer: _,
+                            xlora_global_scaling: _,
                         } = *inputs
                             .downcast::<text_models_inputs_processor::ModelInputs>()
                             .expect("Downcast failed.");
 
+                        let mrope_position_ids =
+                            text_mrope_position_ids(&seqlen_offsets, input_ids.dims()[1]);
                         let inputs: Box<dyn Any> = Box::new(ModelInputs {
                             input_ids,
                             seqlen_offsets,
                             seqlen_offsets_kernel,
                             context_lens,
                             position_ids,
+                            mrope_position_ids,
                             pixel_values: None,
                             model_specific_args: Box::new(LLaVANextVisionSpecificArgs {
                                 image_sizes: None,
@@ -329,12 +334,14 @@ impl InputsProcessor for LLaVANextInputProcessor {
                     },
                 seq_indices,
             } = metadata?;
+            let mrope_position_ids = text_mrope_position_ids(&positions, input.dims()[1]);
             let inputs: Box<dyn Any> = Box::new(ModelInputs {
                 input_ids: input,
                 seqlen_offsets: positions,
                 seqlen_offsets_kernel: positions_kernel,
                 context_lens,
                 position_ids,
+                mrope_position_ids,
                 pixel_values: pixel_values.clone(),
                 model_specific_args: Box::new(LLaVANextVisionSpecificArgs {
                     image_sizes: image_sizes.clone(),
@@ -25,7 +25,20 @@ pub struct ModelInputs {
     pub seqlen_offsets_kernel: Tensor,
     pub context_lens: Vec<(usize, usize)>,
     pub position_ids: Vec<usize>,
+    /// The temporal, height and width position of each token, batch first, for models with
+    /// multimodal RoPE (see [`crate::layers::MRotaryEmbedding`]).
+    pub mrope_position_ids: Vec<[usize; 3]>,
     pub pixel_values: Option<Tensor>,
     pub model_specific_args: Box<dyn Any>,
     pub paged_attn_meta: Option<PagedAttentionInputMetadata>,
 }
+
+/// The multimodal RoPE position ids of text inputs of `seq_len` tokens per sequence, where the
+/// sequences start at `seqlen_offsets`. Every component is the 1-D position of the token, for which
+/// multimodal RoPE is the usual RoPE. Models place their image tokens in 3-D themselves.
+pub(crate) fn text_mrope_position_ids(seqlen_offsets: &[usize], seq_len: usize) -> Vec<[usize; 3]> {
+    seqlen_offsets
+        .iter()
+        .flat_map(|offset| (*offset..offset + seq_len).map(|pos| [pos; 3]))
+        .collect()
+}
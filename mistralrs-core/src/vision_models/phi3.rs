@@ -15,15 +15,15 @@ use crate::{
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
     layers::{
-        repeat_kv, CausalMasker, FusedBiasLinear, MatMul, PhiRopeConfig, PhiRotaryEmbedding,
-        RmsNorm, ScaledDotProductAttention,
+        repeat_kv, verify_attention_heads, CausalMasker, FusedBiasLinear, MatMul, PhiRopeConfig,
+        PhiRotaryEmbedding, RmsNorm, ScaledDotProductAttention,
     },
     layers_masker::PastKvLenCache,
     ops::{BitWiseOp, NonZeroOp},
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
         extract_logits, text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel,
-        NormalLoadingMetadata, Phi3RopeScaling, VisionModel,
+        KvCacheDtype, NormalLoadingMetadata, Phi3RopeScaling, VisionModel,
     },
     serde_default_fn,
     utils::progress::NiceProgressBar,
@@ -191,6 +191,7 @@ impl Attention {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         xs: &Tensor,
@@ -198,6 +199,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         position_ids: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
@@ -259,6 +261,7 @@ impl Attention {
             None => {
                 let (k, v, attn_mask) = Cache::update_kv_cache_sliding_window(
                     kv_cache,
+                    kv_cache_dtype,
                     k,
                     v,
                     attention_mask,
@@ -434,6 +437,7 @@ impl DecoderLayer {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         xs: &Tensor,
@@ -441,6 +445,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         position_ids: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let residual = xs;
@@ -451,6 +456,7 @@ impl DecoderLayer {
             seqlen_offsets,
             position_ids,
             kv_cache,
+            kv_cache_dtype,
             metadata,
         )?;
         let xs = (xs + residual)?;
@@ -526,6 +532,7 @@ impl ImageEmbedding {
                 num_channels: 3,
                 num_hidden_layers: 24,
                 patch_size: 14,
+                use_flash_attn: config.use_flash_attn,
             },
         )?;
 
@@ -805,6 +812,13 @@ impl ImageEmbedding {
         if select {
             match (hd_transform, image_set_tensor) {
                 (Some(output_lens), Some(Either::Left(image_set_tensors))) => {
+                    let n_features = output_lens.iter().sum::<usize>();
+                    if positions.dim(0)? != n_features {
+                        candle_core::bail!(
+                            "Prompt has {} image placeholder tokens but the image crops produce {n_features} image features.",
+                            positions.dim(0)?
+                        );
+                    }
                     let mut idx = 0;
                     for (i, cnt) in output_lens.into_iter().enumerate() {
                         let img_set_tensor = image_set_tensors[i]
@@ -821,6 +835,13 @@ impl ImageEmbedding {
                     }
                 }
                 (None, Some(Either::Right(image_set_tensor))) => {
+                    let n_features = pixel_values.dim(0)? * self.num_img_tokens;
+                    if positions.dim(0)? != n_features {
+                        candle_core::bail!(
+                            "Prompt has {} image placeholder tokens but the images produce {n_features} image features.",
+                            positions.dim(0)?
+                        );
+                    }
                     let mut idx = 0;
                     // Know len(img_embeds) == pixel_values.dim(0) == len(selected_g_values)
                     // https://huggingface.co/microsoft/Phi-3-vision-128k-instruct/blob/dbcdaaacf52c8e40cf8de6d6ffa6ff6860e5f256/image_embedding_phi3_v.py#L259
@@ -872,6 +893,11 @@ impl Model {
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Self> {
+        verify_attention_heads(
+            Some(cfg.hidden_size),
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+        )?;
         let mapper = normal_loading_metadata.mapper;
         let vb_m = vb.pp("model");
 
@@ -968,6 +994,7 @@ impl Model {
             self.embed_tokens.forward(input_ids)?
         };
         let mut cache = self.cache.lock();
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
             metadata
@@ -990,6 +1017,7 @@ impl Model {
                 seqlen_offsets,
                 position_ids,
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
@@ -1042,6 +1070,7 @@ impl VisionModel for Model {
         _start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
         position_ids: Vec<usize>,
+        _: &[[usize; 3]], // Ignore, it is for M-RoPE models
         model_specific_args: Box<dyn Any>,
         metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
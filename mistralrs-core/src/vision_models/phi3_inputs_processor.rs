@@ -26,7 +26,7 @@ use super::{
     phi3::Phi3VisionSpecificArgs,
     preprocessor_config::PreProcessorConfig,
     processor_config::ProcessorConfig,
-    ModelInputs,
+    text_mrope_position_ids, ModelInputs,
 };
 
 // Input processor
@@ -121,9 +121,10 @@ impl InputsProcessor for Phi3InputsProcessor {
                     pixel_attention_mask: _,
                     image_sizes,
                     num_img_tokens,
-                } = self
-                    .preprocess(imgs, config, device)
-                    .expect("Preprocessor failed");
+                } = match self.preprocess(imgs, config, device) {
+                    Ok(preprocessed) => preprocessed,
+                    Err(e) => return Box::new(std::iter::once(Err(anyhow::Error::new(e)))),
+                };
                 let image_sizes = image_sizes.unwrap();
                 pixel_values_accum.push(pixel_values);
                 image_sizes_accum.push(image_sizes);
@@ -166,16 +167,21 @@ impl InputsProcessor for Phi3InputsProcessor {
                             context_lens,
                             position_ids,
                             paged_attn_meta,
+                            early_exit_layer: _,
+                            xlora_global_scaling: _,
                         } = *inputs
                             .downcast::<text_models_inputs_processor::ModelInputs>()
                             .expect("Downcast failed.");
 
+                        let mrope_position_ids =
+                            text_mrope_position_ids(&seqlen_offsets, input_ids.dims()[1]);
                         let inputs: Box<dyn Any> = Box::new(ModelInputs {
                             input_ids,
                             seqlen_offsets,
                             seqlen_offsets_kernel,
                             context_lens,
                             position_ids,
+                            mrope_position_ids,
                             pixel_values: None,
                             model_specific_args: Box::new(Phi3VisionSpecificArgs {
                                 image_sizes: None,
@@ -247,9 +253,10 @@ impl InputsProcessor for Phi3InputsProcessor {
             }
             // Total images must be the same as the number of image tags
             if unique_image_ids.len() != n_images {
-                return Box::new(std::iter::once(Err(anyhow::Error::msg(
-                    "Total images must be the same as the number of image tags.",
-                ))));
+                return Box::new(std::iter::once(Err(anyhow::Error::msg(format!(
+                    "Total images must be the same as the number of image tags, got {n_images} images and {} image tags.",
+                    unique_image_ids.len()
+                )))));
             }
 
             // Use the TryInto + unwrap_or to handle case when id==0
@@ -322,12 +329,14 @@ impl InputsProcessor for Phi3InputsProcessor {
                     },
                 seq_indices,
             } = metadata?;
+            let mrope_position_ids = text_mrope_position_ids(&positions, input.dims()[1]);
             let inputs: Box<dyn Any> = Box::new(ModelInputs {
                 input_ids: input,
                 seqlen_offsets: positions,
                 seqlen_offsets_kernel: positions_kernel,
                 context_lens,
                 position_ids,
+                mrope_position_ids,
                 pixel_values: pixel_values.clone(),
                 model_specific_args: Box::new(Phi3VisionSpecificArgs {
                     image_sizes: image_sizes.clone(),
@@ -494,6 +503,13 @@ impl ImagePreProcessor for Phi3InputsProcessor {
             let global_image = hd_image.unsqueeze(0)?.interpolate2d(336, 336)?;
 
             let (_, h, w) = hd_image.dims3()?;
+            let num_crops = config.num_crops.expect("Need `num_crops`");
+            if (h / 336) * (w / 336) > num_crops {
+                candle_core::bail!(
+                    "Image of size {h}x{w} was split into {} crops, but at most `num_crops` = {num_crops} are supported.",
+                    (h / 336) * (w / 336)
+                );
+            }
             let num_image_tokens = ((h as f32 / 336. * w as f32 / 336. + 1.) * 144.
                 + ((h as f32 / 336.) + 1.) * 12.
                 + 1.) as usize;
@@ -510,10 +526,7 @@ impl ImagePreProcessor for Phi3InputsProcessor {
                 .permute((0, 2, 4, 1, 3, 5))?
                 .reshape(((), 3, 336, 336))?;
             let hd_image_reshape = Tensor::cat(&[global_image, hd_image_reshape], 0)?;
-            let image_transformed = pad_to_max_num_crops_tensor(
-                &hd_image_reshape,
-                config.num_crops.expect("Need `num_crops`") + 1,
-            )?;
+            let image_transformed = pad_to_max_num_crops_tensor(&hd_image_reshape, num_crops + 1)?;
             image_sizes.push((h, w));
             padded_images.push(image_transformed);
             num_img_tokens.push(num_image_tokens);
@@ -4,7 +4,7 @@ use std::{collections::HashMap, sync::Arc};
 
 use crate::{
     amoe::AnyMoeBaseModelMixin,
-    layers::{RmsNorm, ScaledDotProductAttention},
+    layers::{verify_attention_heads, RmsNorm, ScaledDotProductAttention},
     lora::{linear_b as linear, LinearLayerLike, LoraConfig, Ordering},
     paged_attention::ModelConfigMetadata,
     pipeline::{
@@ -22,7 +22,7 @@ use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker},
     models::gemma::Config,
-    pipeline::{extract_logits, Cache, NormalModel},
+    pipeline::{extract_logits, Cache, KvCacheDtype, NormalModel},
 };
 
 use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
@@ -236,6 +236,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -303,7 +304,7 @@ impl Attention {
                 .contiguous()?;
         }
 
-        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+        let (k, v) = Cache::update_kv_cache(kv_cache, kv_cache_dtype, k, v, false)?;
 
         let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
         let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
@@ -407,6 +408,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -419,6 +421,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             scalings.clone(),
             global_scaling_weight,
             is_scaling_pass,
@@ -462,6 +465,7 @@ impl XLoraModel {
         normal_loading_metadata: NormalLoadingMetadata,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
+        verify_attention_heads(None, cfg.num_attention_heads, cfg.num_key_value_heads)?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization in {} bits.",
@@ -591,6 +595,7 @@ impl XLoraModel {
         is_full_pass: bool,
         no_kv_cache: bool,
         is_scaling_pass: Option<f64>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         let mut cache = if is_full_pass {
             if no_kv_cache {
@@ -605,6 +610,7 @@ impl XLoraModel {
         } else {
             self.cache.lock()
         };
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let xs = self.embed_tokens.forward(input_ids)?;
         let attention_mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
@@ -622,12 +628,16 @@ impl XLoraModel {
                     .map(|m| m.to_device(xs.device()).unwrap())
                     .as_ref(),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(xs.device())?,
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
-                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .map(|classifier| {
+                        global_scaling_weight
+                            .unwrap_or_else(|| classifier.get_global_scaling_weight())
+                    })
                     .unwrap_or(1.0),
                 is_scaling_pass,
             )?
@@ -648,6 +658,7 @@ impl XLoraModel {
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
         context_lens: Vec<(usize, usize)>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -672,6 +683,7 @@ impl XLoraModel {
                         true,
                         no_kv_cache,
                         None,
+                        global_scaling_weight,
                     )?
                     .contiguous()?;
                 if let Some(t) = self.lm_head.quantized_act_type() {
@@ -692,6 +704,7 @@ impl XLoraModel {
                         true,
                         no_kv_cache,
                         None,
+                        global_scaling_weight,
                     )?
                     .contiguous()?;
                 if let Some(t) = self.lm_head.quantized_act_type() {
@@ -712,6 +725,7 @@ impl XLoraModel {
                     false,
                     no_kv_cache,
                     None,
+                    global_scaling_weight,
                 )?
                 .contiguous()?;
             if let Some(t) = self.lm_head.quantized_act_type() {
@@ -781,6 +795,7 @@ impl IsqModel for XLoraModel {
 }
 
 impl NormalModel for XLoraModel {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         _input_ids: &Tensor,
@@ -789,6 +804,7 @@ impl NormalModel for XLoraModel {
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         unreachable!()
     }
@@ -804,6 +820,7 @@ impl NormalModel for XLoraModel {
         non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -815,6 +832,7 @@ impl NormalModel for XLoraModel {
             no_kv_cache,
             non_granular_state,
             context_lens,
+            global_scaling_weight,
         )
     }
     fn cache(&self) -> &Cache {
@@ -829,7 +847,7 @@ impl NormalModel for XLoraModel {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -860,6 +878,37 @@ impl NormalModel for XLoraModel {
         }
         Ok(sum)
     }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter loading is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
@@ -894,6 +943,7 @@ impl ScalingsMaker for XLoraModel {
             is_full_pass,
             no_kv_cache,
             is_scaling_pass,
+            None,
         )
     }
 }
@@ -11,13 +11,13 @@ use tracing::info;
 use crate::{
     amoe::AnyMoeBaseModelMixin,
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, MatMul, RmsNorm},
+    layers::{repeat_kv, verify_attention_heads, CausalMasker, MatMul, RmsNorm},
     lora::{linear_b, linear_no_bias, LinearLayerLike, LoraConfig},
     models::gemma2::Config,
     paged_attention::ModelConfigMetadata,
     pipeline::{
         extract_logits, text_models_inputs_processor::PagedAttentionInputMetadata, Cache, IsqModel,
-        NormalLoadingMetadata, NormalModel,
+        KvCacheDtype, NormalLoadingMetadata, NormalModel,
     },
     utils::progress::NiceProgressBar,
     Ordering,
@@ -242,6 +242,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -318,6 +319,7 @@ impl Attention {
         // self.sliding_window is None if !self.use_sliding_window
         let (k, v, mask) = Cache::update_kv_cache_sliding_window(
             kv_cache,
+            kv_cache_dtype,
             k,
             v,
             mask,
@@ -450,6 +452,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -465,6 +468,7 @@ impl DecoderLayer {
                 seqlen_offsets,
                 start_offsets_kernel,
                 kv_cache,
+                kv_cache_dtype,
                 scalings.clone(),
                 global_scaling_weight,
                 is_scaling_pass,
@@ -514,6 +518,7 @@ impl Model {
         normal_loading_metadata: NormalLoadingMetadata,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
+        verify_attention_heads(None, cfg.num_attention_heads, cfg.num_key_value_heads)?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization in {} bits.",
@@ -645,6 +650,7 @@ impl Model {
         is_full_pass: bool,
         no_kv_cache: bool,
         is_scaling_pass: Option<f64>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         let xs = self.embed_tokens.forward(input_ids)?;
         let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
@@ -661,6 +667,7 @@ impl Model {
         } else {
             self.cache.lock()
         };
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let attention_mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             &*cache,
@@ -688,12 +695,16 @@ impl Model {
                     .map(|m| m.to_device(xs.device()).unwrap())
                     .as_ref(),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(xs.device())?,
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
-                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .map(|classifier| {
+                        global_scaling_weight
+                            .unwrap_or_else(|| classifier.get_global_scaling_weight())
+                    })
                     .unwrap_or(1.0),
                 is_scaling_pass,
             )?;
@@ -727,6 +738,7 @@ impl Model {
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
         context_lens: Vec<(usize, usize)>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -751,6 +763,7 @@ impl Model {
                         true,
                         no_kv_cache,
                         None,
+                        global_scaling_weight,
                     )?
                     .contiguous()?;
                 if let Some(t) = self.lm_head.quantized_act_type() {
@@ -771,6 +784,7 @@ impl Model {
                         true,
                         no_kv_cache,
                         None,
+                        global_scaling_weight,
                     )?
                     .contiguous()?;
                 if let Some(t) = self.lm_head.quantized_act_type() {
@@ -791,6 +805,7 @@ impl Model {
                     false,
                     no_kv_cache,
                     None,
+                    global_scaling_weight,
                 )?
                 .contiguous()?;
             if let Some(t) = self.lm_head.quantized_act_type() {
@@ -860,6 +875,7 @@ impl IsqModel for Model {
 }
 
 impl NormalModel for Model {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         _input_ids: &Tensor,
@@ -868,6 +884,7 @@ impl NormalModel for Model {
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         unreachable!()
     }
@@ -883,6 +900,7 @@ impl NormalModel for Model {
         non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -894,6 +912,7 @@ impl NormalModel for Model {
             no_kv_cache,
             non_granular_state,
             context_lens,
+            global_scaling_weight,
         )
     }
     fn cache(&self) -> &Cache {
@@ -908,7 +927,7 @@ impl NormalModel for Model {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -939,6 +958,37 @@ impl NormalModel for Model {
         }
         Ok(sum)
     }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter loading is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
@@ -973,6 +1023,7 @@ impl ScalingsMaker for Model {
             is_full_pass,
             no_kv_cache,
             is_scaling_pass,
+            None,
         )
     }
 }
@@ -2,7 +2,7 @@
 
 use crate::{
     amoe::AnyMoeBaseModelMixin,
-    layers::{Llama3RotaryEmbedding, ScaledDotProductAttention},
+    layers::{verify_attention_heads, Llama3RotaryEmbedding, ScaledDotProductAttention},
     lora::{linear_no_bias as linear, LinearLayerLike, LoraConfig, Ordering},
     paged_attention::ModelConfigMetadata,
     pipeline::{text_models_inputs_processor::PagedAttentionInputMetadata, IsqModel},
@@ -19,7 +19,9 @@ use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker, RmsNorm},
     models::llama::Config,
-    pipeline::{self, extract_logits, LayerCaches, NormalLoadingMetadata, NormalModel},
+    pipeline::{
+        self, extract_logits, KvCacheDtype, LayerCaches, NormalLoadingMetadata, NormalModel,
+    },
 };
 
 use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
@@ -48,6 +50,7 @@ impl CausalSelfAttention {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -115,8 +118,13 @@ impl CausalSelfAttention {
                 .contiguous()?;
         }
 
-        let (k, v) =
-            crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+        let (k, v) = crate::pipeline::Cache::update_kv_cache(
+            &mut kv_cache[block_idx],
+            kv_cache_dtype,
+            k,
+            v,
+            false,
+        )?;
 
         let k = repeat_kv(k, self.num_attention_heads / self.num_key_value_heads)?.contiguous()?;
         let v = repeat_kv(v, self.num_attention_heads / self.num_key_value_heads)?.contiguous()?;
@@ -333,6 +341,7 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut LayerCaches,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -346,6 +355,7 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            kv_cache_dtype,
             scalings.clone(),
             global_scaling_weight,
             is_scaling_pass,
@@ -439,6 +449,7 @@ impl XLoraLlama {
         is_full_pass: bool,
         no_kv_cache: bool,
         is_scaling_pass: Option<f64>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         let mut x = self.wte.forward(input_ids)?;
         let mut cache = if is_full_pass {
@@ -454,6 +465,7 @@ impl XLoraLlama {
         } else {
             self.kv_cache.lock()
         };
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
             &*cache,
@@ -466,13 +478,17 @@ impl XLoraLlama {
                 &x,
                 &mask.clone().map(|m| m.to_device(x.device()).unwrap()),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(x.device())?,
                 block_idx,
                 &mut cache,
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
-                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .map(|classifier| {
+                        global_scaling_weight
+                            .unwrap_or_else(|| classifier.get_global_scaling_weight())
+                    })
                     .unwrap_or(1.0),
                 is_scaling_pass,
             )?;
@@ -493,6 +509,7 @@ impl XLoraLlama {
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
         context_lens: Vec<(usize, usize)>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -517,6 +534,7 @@ impl XLoraLlama {
                         true,
                         no_kv_cache,
                         None,
+                        global_scaling_weight,
                     )?
                     .contiguous()?;
                 if let Some(t) = self.lm_head.quantized_act_type() {
@@ -537,6 +555,7 @@ impl XLoraLlama {
                         true,
                         no_kv_cache,
                         None,
+                        global_scaling_weight,
                     )?
                     .contiguous()?;
                 if let Some(t) = self.lm_head.quantized_act_type() {
@@ -557,6 +576,7 @@ impl XLoraLlama {
                     false,
                     no_kv_cache,
                     None,
+                    global_scaling_weight,
                 )?
                 .contiguous()?;
             if let Some(t) = self.lm_head.quantized_act_type() {
@@ -580,6 +600,11 @@ impl XLoraLlama {
         normal_loading_metadata: NormalLoadingMetadata,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
+        verify_attention_heads(
+            Some(cfg.hidden_size),
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+        )?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization in {} bits.",
@@ -742,6 +767,7 @@ impl IsqModel for XLoraLlama {
 }
 
 impl NormalModel for XLoraLlama {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         _input_ids: &Tensor,
@@ -750,6 +776,7 @@ impl NormalModel for XLoraLlama {
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         unreachable!()
     }
@@ -765,6 +792,7 @@ impl NormalModel for XLoraLlama {
         non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -776,6 +804,7 @@ impl NormalModel for XLoraLlama {
             no_kv_cache,
             non_granular_state,
             context_lens,
+            global_scaling_weight,
         )
     }
     fn cache(&self) -> &super::Cache {
@@ -790,7 +819,7 @@ impl NormalModel for XLoraLlama {
     fn max_seq_len(&self) -> usize {
         self.blocks[0].attn.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -821,6 +850,37 @@ impl NormalModel for XLoraLlama {
         }
         Ok(sum)
     }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter loading is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
@@ -855,6 +915,7 @@ impl ScalingsMaker for XLoraLlama {
             is_full_pass,
             no_kv_cache,
             is_scaling_pass,
+            None,
         )
     }
 }
@@ -22,9 +22,10 @@ use tracing::info;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, RmsNorm},
+    layers::{repeat_kv, verify_attention_heads, CausalMasker, RmsNorm},
+    layers_utils::topk_experts,
     models::mixtral::Config,
-    pipeline::{extract_logits, Cache, NormalModel},
+    pipeline::{extract_logits, Cache, KvCacheDtype, NormalModel},
 };
 
 use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
@@ -126,6 +127,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -195,6 +197,7 @@ impl Attention {
 
         let (k, v, attn_mask) = Cache::update_kv_cache_sliding_window(
             kv_cache,
+            kv_cache_dtype,
             k,
             v,
             attention_mask,
@@ -423,16 +426,15 @@ impl SparseMoeBlock {
         let mut top_x = vec![vec![]; self.experts.len()];
         let mut selected_rws = vec![vec![]; self.experts.len()];
         for (row_idx, rw) in routing_weights.iter().enumerate() {
-            let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
-            dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
+            let dst = topk_experts(rw, self.num_experts_per_tok);
             let mut sum_routing_weights = 0f32;
-            for &expert_idx in dst.iter().take(self.num_experts_per_tok) {
+            for &expert_idx in dst.iter() {
                 let expert_idx = expert_idx as usize;
                 let routing_weight = rw[expert_idx];
                 sum_routing_weights += routing_weight;
                 top_x[expert_idx].push(row_idx as u32);
             }
-            for &expert_idx in dst.iter().take(self.num_experts_per_tok) {
+            for &expert_idx in dst.iter() {
                 let expert_idx = expert_idx as usize;
                 let routing_weight = rw[expert_idx];
                 selected_rws[expert_idx].push(routing_weight / sum_routing_weights)
@@ -542,6 +544,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -554,6 +557,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             scalings.clone(),
             global_scaling_weight,
             is_scaling_pass,
@@ -597,6 +601,11 @@ impl XLoraModel {
         normal_loading_metadata: NormalLoadingMetadata,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
+        verify_attention_heads(
+            Some(cfg.hidden_size),
+            cfg.num_attention_heads,
+            cfg.num_key_value_heads,
+        )?;
         if let Some(ref quant_cfg) = &cfg.quantization_config {
             tracing::info!(
                 "Using {} quantization in {} bits.",
@@ -724,6 +733,7 @@ impl XLoraModel {
         is_full_pass: bool,
         no_kv_cache: bool,
         is_scaling_pass: Option<f64>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         let mut cache = if is_full_pass {
             if no_kv_cache {
@@ -738,6 +748,7 @@ impl XLoraModel {
         } else {
             self.cache.lock()
         };
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
@@ -755,12 +766,16 @@ impl XLoraModel {
                     .map(|m| m.to_device(xs.device()).unwrap())
                     .as_ref(),
                 seqlen_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(xs.device())?,
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
-                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .map(|classifier| {
+                        global_scaling_weight
+                            .unwrap_or_else(|| classifier.get_global_scaling_weight())
+                    })
                     .unwrap_or(1.0),
                 is_scaling_pass,
             )?
@@ -781,6 +796,7 @@ impl XLoraModel {
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
         context_lens: Vec<(usize, usize)>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -805,6 +821,7 @@ impl XLoraModel {
                         true,
                         no_kv_cache,
                         None,
+                        global_scaling_weight,
                     )?
                     .contiguous()?;
                 if let Some(t) = self.lm_head.quantized_act_type() {
@@ -825,6 +842,7 @@ impl XLoraModel {
                         true,
                         no_kv_cache,
                         None,
+                        global_scaling_weight,
                     )?
                     .contiguous()?;
                 if let Some(t) = self.lm_head.quantized_act_type() {
@@ -845,6 +863,7 @@ impl XLoraModel {
                     false,
                     no_kv_cache,
                     None,
+                    global_scaling_weight,
                 )?
                 .contiguous()?;
             if let Some(t) = self.lm_head.quantized_act_type() {
@@ -909,6 +928,7 @@ impl IsqModel for XLoraModel {
 }
 
 impl NormalModel for XLoraModel {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         _input_ids: &Tensor,
@@ -917,6 +937,7 @@ impl NormalModel for XLoraModel {
         _context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
         _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _early_exit_layer: Option<usize>,
     ) -> Result<Tensor> {
         unreachable!()
     }
@@ -932,6 +953,7 @@ impl NormalModel for XLoraModel {
         non_granular_state: &Option<crate::xlora_models::NonGranularState>,
         context_lens: Vec<(usize, usize)>,
         _position_ids: Vec<usize>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -943,6 +965,7 @@ impl NormalModel for XLoraModel {
             no_kv_cache,
             non_granular_state,
             context_lens,
+            global_scaling_weight,
         )
     }
     fn cache(&self) -> &Cache {
@@ -957,7 +980,7 @@ impl NormalModel for XLoraModel {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -993,6 +1016,42 @@ impl NormalModel for XLoraModel {
         }
         Ok(sum)
     }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter loading is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1)
+                    .unwrap()
+                    .load_adapter(name, vb, cfg)?;
+                sum += Arc::get_mut(&mut expert.w2)
+                    .unwrap()
+                    .load_adapter(name, vb, cfg)?;
+                sum += Arc::get_mut(&mut expert.w3)
+                    .unwrap()
+                    .load_adapter(name, vb, cfg)?;
+            }
+        }
+        Ok(sum)
+    }
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
@@ -1027,6 +1086,7 @@ impl ScalingsMaker for XLoraModel {
             is_full_pass,
             no_kv_cache,
             is_scaling_pass,
+            None,
         )
     }
 }
@@ -16,7 +16,8 @@ use tracing::info;
 
 use crate::device_map::DeviceMapper;
 use crate::layers::{repeat_kv, CausalMasker, MatMul, QRmsNorm, ScaledDotProductAttention};
-use crate::pipeline::{extract_logits, Cache};
+use crate::layers_utils::topk_experts;
+use crate::pipeline::{extract_logits, Cache, KvCacheDtype};
 use crate::DeviceMapMetadata;
 
 use super::classifier::XLoraClassifier;
@@ -111,16 +112,15 @@ impl MlpOrMoe {
                 let mut top_x = vec![vec![]; experts.len()];
                 let mut selected_rws = vec![vec![]; experts.len()];
                 for (row_idx, rw) in routing_weights.iter().enumerate() {
-                    let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
-                    dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
+                    let dst = topk_experts(rw, *n_expert_used);
                     let mut sum_routing_weights = 0f32;
-                    for &expert_idx in dst.iter().take(*n_expert_used) {
+                    for &expert_idx in dst.iter() {
                         let expert_idx = expert_idx as usize;
                         let routing_weight = rw[expert_idx];
                         sum_routing_weights += routing_weight;
                         top_x[expert_idx].push(row_idx as u32);
                     }
-                    for &expert_idx in dst.iter().take(*n_expert_used) {
+                    for &expert_idx in dst.iter() {
                         let expert_idx = expert_idx as usize;
                         let routing_weight = rw[expert_idx];
                         selected_rws[expert_idx].push(routing_weight / sum_routing_weights)
@@ -189,6 +189,7 @@ impl LayerWeights {
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -245,7 +246,7 @@ impl LayerWeights {
                 .contiguous()?;
         }
 
-        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+        let (k, v) = Cache::update_kv_cache(kv_cache, kv_cache_dtype, k, v, false)?;
 
         let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
         let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
@@ -749,7 +750,7 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    pub fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -791,6 +792,7 @@ impl ModelWeights {
         is_full_pass: bool,
         no_kv_cache: bool,
         is_scaling_pass: Option<f64>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         let mut layer_in = self.tok_embeddings.forward(x)?;
         let mut cache = if is_full_pass {
@@ -806,6 +808,7 @@ impl ModelWeights {
         } else {
             self.cache.lock()
         };
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             x,
             &*cache,
@@ -823,12 +826,16 @@ impl ModelWeights {
                 &x,
                 &mask.as_ref().map(|m| m.to_device(x.device()).unwrap()),
                 start_offsets,
-                start_offsets_kernel.clone(),
+                start_offsets_kernel.to_device(x.device())?,
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
-                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .map(|classifier| {
+                        global_scaling_weight
+                            .unwrap_or_else(|| classifier.get_global_scaling_weight())
+                    })
                     .unwrap_or(1.0),
                 is_scaling_pass,
             )?;
@@ -842,7 +849,10 @@ impl ModelWeights {
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
-                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .map(|classifier| {
+                        global_scaling_weight
+                            .unwrap_or_else(|| classifier.get_global_scaling_weight())
+                    })
                     .unwrap_or(1.0),
                 is_scaling_pass,
             )?;
@@ -865,6 +875,7 @@ impl ModelWeights {
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
         context_lens: Vec<(usize, usize)>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -891,6 +902,7 @@ impl ModelWeights {
                                 true,
                                 no_kv_cache,
                                 None,
+                                global_scaling_weight,
                             )?
                             .contiguous()?,
                         None,
@@ -912,6 +924,7 @@ impl ModelWeights {
                                 true,
                                 no_kv_cache,
                                 None,
+                                global_scaling_weight,
                             )?
                             .contiguous()?,
                         None,
@@ -933,6 +946,7 @@ impl ModelWeights {
                             false,
                             no_kv_cache,
                             None,
+                            global_scaling_weight,
                         )?
                         .contiguous()?,
                     None,
@@ -974,6 +988,7 @@ impl ScalingsMaker for ModelWeights {
             is_full_pass,
             no_kv_cache,
             is_scaling_pass,
+            None,
         )
     }
 }
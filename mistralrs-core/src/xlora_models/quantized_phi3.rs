@@ -15,7 +15,7 @@ use crate::lora::LoraConfig;
 use crate::lora::Merge;
 use crate::lora::Ordering;
 use crate::lora::QLoraLinear;
-use crate::pipeline::extract_logits;
+use crate::pipeline::{extract_logits, KvCacheDtype};
 use crate::utils::progress::NiceProgressBar;
 use crate::DeviceMapMetadata;
 use candle_core::quantized::QMatMul;
@@ -117,6 +117,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KvCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -161,6 +162,7 @@ impl LayerWeights {
 
         let (k, v, attn_mask) = Cache::update_kv_cache_sliding_window(
             kv_cache,
+            kv_cache_dtype,
             k,
             v,
             mask,
@@ -381,7 +383,7 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    pub fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -395,6 +397,7 @@ impl ModelWeights {
         Ok(sum)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn inner_forward(
         &self,
         input_ids: &Tensor,
@@ -403,6 +406,7 @@ impl ModelWeights {
         is_full_pass: bool,
         no_kv_cache: bool,
         is_scaling_pass: Option<f64>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let mut cache = if is_full_pass {
@@ -418,6 +422,7 @@ impl ModelWeights {
         } else {
             self.cache.lock()
         };
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
             &*cache,
@@ -438,10 +443,14 @@ impl ModelWeights {
                     .as_ref(),
                 seqlen_offsets,
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
-                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .map(|classifier| {
+                        global_scaling_weight
+                            .unwrap_or_else(|| classifier.get_global_scaling_weight())
+                    })
                     .unwrap_or(1.0),
                 is_scaling_pass,
             )?;
@@ -453,7 +462,10 @@ impl ModelWeights {
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
-                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .map(|classifier| {
+                        global_scaling_weight
+                            .unwrap_or_else(|| classifier.get_global_scaling_weight())
+                    })
                     .unwrap_or(1.0),
                 is_scaling_pass,
             )?;
@@ -475,6 +487,7 @@ impl ModelWeights {
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
         context_lens: Vec<(usize, usize)>,
+        global_scaling_weight: Option<f64>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -500,6 +513,7 @@ impl ModelWeights {
                                 true,
                                 no_kv_cache,
                                 None,
+                                global_scaling_weight,
                             )?
                             .contiguous()?,
                         None,
@@ -520,6 +534,7 @@ impl ModelWeights {
                                 true,
                                 no_kv_cache,
                                 None,
+                                global_scaling_weight,
                             )?
                             .contiguous()?,
                         None,
@@ -533,7 +548,15 @@ impl ModelWeights {
             extract_logits(
                 &self.output.lora_forward(
                     &self
-                        .inner_forward(input_ids, seqlen_offsets, None, false, no_kv_cache, None)?
+                        .inner_forward(
+                            input_ids,
+                            seqlen_offsets,
+                            None,
+                            false,
+                            no_kv_cache,
+                            None,
+                            global_scaling_weight,
+                        )?
                         .contiguous()?,
                     None,
                     1.0,
@@ -573,6 +596,7 @@ impl ScalingsMaker for ModelWeights {
             is_full_pass,
             no_kv_cache,
             is_scaling_pass,
+            None,
         )
     }
 }
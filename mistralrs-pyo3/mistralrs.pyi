@@ -1,11 +1,36 @@
 from dataclasses import dataclass
 from enum import Enum
-from typing import Iterator
+from typing import Any, Callable, Iterator
+
+class ToolChoice:
+    class NoTools(ToolChoice):
+        def __init__(self) -> None: ...
+    class Auto(ToolChoice):
+        def __init__(self) -> None: ...
+    class Tool(ToolChoice):
+        """
+        Force a call to the tool with this name, which must be one of the `tool_schemas` of the request.
+        """
+        def __init__(self, name: str) -> None: ...
 
-@dataclass
-class ToolChoice(Enum):
-    NoTools = "None"
-    Auto = "Auto"
+class EmbeddingPooling(Enum):
+    LastToken = "LastToken"
+    MeanPooling = "MeanPooling"
+    Cls = "Cls"
+
+class TemperatureOrder(Enum):
+    """
+    When the temperature is applied relative to the top-k, typical-p, top-p and min-p truncation.
+    """
+
+    BeforeTruncation = "BeforeTruncation"
+    AfterTruncation = "AfterTruncation"
+
+class ChatCompletionStreamer(Iterator[ChatCompletionChunkResponse]):
+    request_id: int
+
+class CompletionStreamer(Iterator[CompletionChunkResponse]):
+    request_id: int
 
 @dataclass
 class ChatCompletionRequest:
@@ -14,6 +39,70 @@ class ChatCompletionRequest:
     about input data, sampling, and how to return the response.
 
     The messages type is as follows: (for normal chat completion, for chat completion with images, pretemplated prompt)
+
+    The content of a message with images is a list of `{"type": "text", "text": ...}` and
+    `{"type": "image_url", "image_url": {"url": ...}}` items in any order, so several images may be interleaved
+    with the text for models which support it.
+
+    If `mirostat_tau` is set, tokens are sampled with Mirostat v2, which targets a surprise of `mirostat_tau`
+    bits per token, learning at the rate `mirostat_eta`. It replaces the `top_k`, `typical_p`, `top_p` and
+    `min_p` truncation, but the `temperature` still applies.
+
+    If `dry_multiplier` is set and nonzero, the DRY penalty discourages repeating generated text: a token which would
+    extend a repetition of `n` > `dry_allowed_length` tokens is penalized by
+    `dry_multiplier * dry_base ** (n - dry_allowed_length)`. Repetitions do not extend across the last token of each
+    of the `dry_sequence_breakers`, which default to newline, `:`, `"` and `*`.
+
+    `repetition_penalty` penalizes the tokens which occur in the last `repetition_penalty_range` tokens of the prompt
+    and output, or anywhere in them if the range is 0: positive logits are divided by it and negative logits
+    multiplied by it, as in llama.cpp. It is applied before the `frequency_penalty` and `presence_penalty`.
+
+    `logit_bias_strings` biases text instead of token ids: each string is tokenized on its own, without special
+    tokens, and its bias is added to every token it is split into. A word is often tokenized differently in
+    context, for example with a leading space, so `" the"` and `"the"` may need to be biased separately. Biases for
+    the same token, from several strings or from `logit_bias`, are summed.
+
+    A `response_format` of `"json_object"` constrains the output to a single JSON value, with objects and arrays
+    nested at most two levels deep, and cannot be combined with `grammar_type`. The default, `"text"`, does not
+    constrain the output.
+
+    If `stream` and `stream_options_include_usage` are set, the stream ends with an extra chunk which has no
+    choices and whose `usage` holds the token counts and throughput of the whole request.
+
+    With `stream` and `stream_interval_ms`, the chunks generated within this many milliseconds are coalesced into
+    one chunk instead of yielding a chunk per token. The last chunk is yielded as soon as generation finishes. It is
+    ignored if `logprobs` is set.
+
+    With `token_healing`, if the last token of the prompt is a prefix of a longer token, it is removed and the first
+    generated token must start with its text, which is not repeated in the output. It is not applied with a
+    `grammar` or `response_format`.
+
+    `stop_token_ids` and `stop_token_strings` end generation like the model's EOS token, for this request only.
+    Each stop token string must tokenize to a single token, such as `"<|im_end|>"`.
+    `stop_token_seqs` are lists of token ids which end generation once the generated ids end with one of them,
+    matched without decoding them to text. Either they or `stop_seqs` can end generation.
+
+    If the prompt and `max_tokens` exceed the model's maximum sequence length, the request is rejected unless
+    `truncate_prompt` is set, which drops tokens from the start of the prompt to fit.
+
+    For an X-LoRA model, `xlora_global_scaling` scales the adapter outputs instead of the `global_scaling_weight`
+    of the X-LoRA config, for this request only: `0.0` runs the base model. Other models ignore it.
+
+    `logits_processors` are built-in logits processors, each a dict with its `name` and parameters, applied in order
+    after the penalties:
+    - `{"name": "no_repeat_ngram", "ngram_size": 2}` bans tokens which would repeat an n-gram of the context.
+    - `{"name": "bad_words", "bad_words_ids": [[1, 2], [3]]}` bans each sequence of token ids.
+
+    `suppress_special_tokens` never samples the special tokens of the tokenizer, such as chat template control
+    tokens, except for the EOS and stop tokens.
+
+    With `temperature_order=TemperatureOrder.BeforeTruncation`, the logits are scaled by the temperature before the
+    top-k, typical-p, top-p and min-p truncation. With `AfterTruncation`, the untempered distribution is truncated and
+    the temperature only reshapes the kept tokens, so the truncated set does not depend on the temperature.
+
+    With `add_generation_prompt`, the prompt ends with the chat template's generation prompt, which starts a new
+    assistant turn. If it is `False` and the last message is from the assistant, the model continues that message
+    instead, which prefills the start of its reply.
     """
 
     messages: (
@@ -36,15 +125,77 @@ class ChatCompletionRequest:
     grammar_type: str | None = None
     adapters: list[str] | None = None
     min_p: float | None = None
-    min_p: float | None = None
+    typical_p: float | None = None
+    dry_multiplier: float | None = None
+    dry_base: float = 1.75
+    dry_allowed_length: int = 2
+    dry_sequence_breakers: list[str] | None = None
+    repetition_penalty: float | None = None
+    repetition_penalty_range: int = 0
+    mirostat_tau: float | None = None
+    mirostat_eta: float = 0.1
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    seed: int | None = None
+    min_tokens: int | None = None
+    logit_bias_strings: dict[str, float] | None = None
+    response_format: str | None = None
+    stream_options_include_usage: bool = False
+    token_healing: bool = False
+    truncate_prompt: bool = False
+    stop_token_ids: list[int] | None = None
+    stop_token_strings: list[str] | None = None
+    xlora_global_scaling: float | None = None
+    stop_token_seqs: list[list[int]] | None = None
+    logits_processors: list[dict[str, str | int | list[list[int]]]] | None = None
+    add_generation_prompt: bool = True
+    suppress_special_tokens: bool = False
+    temperature_order: TemperatureOrder = TemperatureOrder.BeforeTruncation
+    stream_interval_ms: int | None = None
 
 @dataclass
 class CompletionRequest:
     """
     A CompletionRequest represents a request sent to the mistral.rs engine. It encodes information
     about input data, sampling, and how to return the response.
+
+    With `token_healing`, a prompt such as `"Hello wor"` whose last token is a prefix of a longer token has that
+    token removed, and the first generated token must start with its text: the completion continues with `"ld"`
+    rather than a new word. It is not applied with a `grammar`.
+
+    `stop_token_ids` and `stop_token_strings` end generation like the model's EOS token, for this request only.
+    Each stop token string must tokenize to a single token.
+    `stop_token_seqs` are lists of token ids which end generation once the generated ids end with one of them,
+    matched without decoding them to text. Either they or `stop_seqs` can end generation.
+
+    `repetition_penalty` and `repetition_penalty_range` behave as in a `ChatCompletionRequest`.
+
+    With `stream`, the text is returned in chunks as it is generated. `best_of` must then equal `n_choices`.
+
+    With `fill_in_middle`, a code model generates the text between `prompt` and `suffix`, which are laid out with
+    the model's fill-in-the-middle tokens, such as `<fim_prefix>`, `<fim_suffix>` and `<fim_middle>` for StarCoder2.
+    The suffix is then not appended to the completion. `echo_prompt` and `best_of` are not supported with it.
+
+    `prompt_tokens` are token ids to use as the prompt instead of `prompt`, without tokenizing it again, for
+    prompts tokenized by the caller. They are rejected if any id is not in the model's vocabulary.
+
+    If the prompt and `max_tokens` exceed the model's maximum sequence length, the request is rejected unless
+    `truncate_prompt` is set, which drops tokens from the start of the prompt to fit.
+
+    For an X-LoRA model, `xlora_global_scaling` scales the adapter outputs instead of the `global_scaling_weight`
+    of the X-LoRA config, for this request only: `0.0` runs the base model. Other models ignore it.
+
+    `logits_processors` are built-in logits processors, each a dict with its `name` and parameters, applied in order
+    after the penalties:
+    - `{"name": "no_repeat_ngram", "ngram_size": 2}` bans tokens which would repeat an n-gram of the context.
+    - `{"name": "bad_words", "bad_words_ids": [[1, 2], [3]]}` bans each sequence of token ids.
+
+    `suppress_special_tokens` never samples the special tokens of the tokenizer, such as chat template control
+    tokens, except for the EOS and stop tokens.
+
+    With `temperature_order=TemperatureOrder.BeforeTruncation`, the logits are scaled by the temperature before the
+    top-k, typical-p, top-p and min-p truncation. With `AfterTruncation`, the untempered distribution is truncated and
+    the temperature only reshapes the kept tokens, so the truncated set does not depend on the temperature.
     """
 
     prompt: str
@@ -53,7 +204,7 @@ class CompletionRequest:
     logit_bias: dict[int, float] | None = None
     max_tokens: int | None = None
     n_choices: int = 1
-    best_of: int = 1
+    best_of: int | None = None
     presence_penalty: float | None = None
     frequency_penalty: float | None = None
     stop_seqs: list[str] | None = None
@@ -65,8 +216,30 @@ class CompletionRequest:
     grammar_type: str | None = None
     adapters: list[str] | None = None
     min_p: float | None = None
+    typical_p: float | None = None
+    dry_multiplier: float | None = None
+    dry_base: float = 1.75
+    dry_allowed_length: int = 2
+    dry_sequence_breakers: list[str] | None = None
+    repetition_penalty: float | None = None
+    repetition_penalty_range: int = 0
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    seed: int | None = None
+    min_tokens: int | None = None
+    logprobs: int | None = None
+    token_healing: bool = False
+    truncate_prompt: bool = False
+    stop_token_ids: list[int] | None = None
+    stop_token_strings: list[str] | None = None
+    stream: bool = False
+    fill_in_middle: bool = False
+    prompt_tokens: list[int] | None = None
+    xlora_global_scaling: float | None = None
+    stop_token_seqs: list[list[int]] | None = None
+    logits_processors: list[dict[str, str | int | list[list[int]]]] | None = None
+    suppress_special_tokens: bool = False
+    temperature_order: TemperatureOrder = TemperatureOrder.BeforeTruncation
 
 @dataclass
 class Architecture(Enum):
@@ -78,6 +251,8 @@ class Architecture(Enum):
     Qwen2 = "qwen2"
     Gemma2 = "gemma2"
     Starcoder2 = "starcoder2"
+    Qwen2Moe = "qwen2moe"
+    Cohere = "cohere"
 
 @dataclass
 class VisionArchitecture(Enum):
@@ -94,6 +269,13 @@ class Which(Enum):
     >>> Which.Plain(...)
     ```
     """
+    def validate(self) -> None:
+        """
+        Check that the required fields are set and that the local files, such as the adapter ordering file, can
+        be read. Raises a `ValueError` otherwise. This does not access the network and is also done when a
+        `Runner` is created, before any download.
+        """
+
     @dataclass
     class Plain:
         model_id: str
@@ -173,6 +355,8 @@ class Which(Enum):
         model_id: str
         arch: VisionArchitecture
         tokenizer_json: str | None = None
+        topology: str | None = None
+        max_image_edge: int | None = None
 
 class Runner:
     def __init__(
@@ -184,13 +368,20 @@ class Runner:
         token_source: str = "cache",
         speculative_gamma: int = 32,
         which_draft: Which | None = None,
+        speculative_ngram: int | None = None,
         chat_template: str | None = None,
         num_device_layers: list[str] | None = None,
         in_situ_quant: str | None = None,
         anymoe_config: AnyMoeConfig | None = None,
         pa_gpu_mem: int | float | None = None,
+        pa_num_tokens: int | None = None,
         pa_blk_size: int | None = None,
         no_paged_attn: bool = False,
+        context_overflow_handler: Callable[[int, int | None, int], str | None]
+        | None = None,
+        seed: int | None = None,
+        device_ordinal: int | None = None,
+        max_num_batched_tokens: int | None = None,
     ) -> None:
         """
         Load a model.
@@ -202,16 +393,22 @@ class Runner:
         - `token_source` specifies where to load the HF token from.
             The token source follows the following format: "literal:<value>", "env:<value>", "path:<value>", "cache" to use a cached token or "none" to use no token.
         - `speculative_gamma` specifies the `gamma` parameter for specuative decoding, the ratio of draft tokens to generate before calling
-            the target model. If `which_draft` is not specified, this is ignored.
+            the target model. If neither `which_draft` nor `speculative_ngram` is specified, this is ignored.
         - `which_draft` specifies which draft model to load. Setting this parameter will cause a speculative decoding model to be loaded,
             with `which` as the target (higher quality) model and `which_draft` as the draft (lower quality) model.
+        - `speculative_ngram` enables n-gram (prompt lookup) speculative decoding instead of using a draft model. Up to
+            `speculative_gamma` tokens are proposed from the sequence's own tokens, by looking up its last `speculative_ngram`
+            (or fewer) tokens earlier in the sequence. This works best when the output repeats parts of the prompt.
+            It cannot be combined with `which_draft`, and requests are run one at a time.
         - `chat_template` specifies an optional JINJA chat template.
             The JINJA template should have `messages`, `add_generation_prompt`, `bos_token`, `eos_token`, and `unk_token` as inputs.
             It is used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
         - `num_device_layers` sets the number of layers to load and run on each device.
             Each element follows the format ORD:NUM where ORD is the device ordinal and NUM is
-            the corresponding number of layers.
-        - `in_situ_quant` sets the optional in-situ quantization for models that are not quantized (not GGUF or GGML).
+            the corresponding number of layers. `["auto"]` fits as many layers as the free memory of each
+            CUDA device allows, in order of ordinal, and puts the rest on the CPU. GGML models do not support `["auto"]`.
+        - `in_situ_quant` sets the optional in-situ quantization for models that are not quantized (not GGUF or GGML),
+            such as `"Q4K"`, `"HQQ8"` or `"Q8CH"` for int8 weights with one scale per output channel.
         - `anymoe_config` specifies the AnyMoE config. If this is set, then the model will be loaded as an AnyMoE model.
         - `pa_gpu_mem`: GPU memory to allocate for KV cache with PagedAttention in MBs.
             PagedAttention is only supported on CUDA and is always automatically activated.
@@ -223,28 +420,211 @@ class Runner:
         - `pa_ctxt_len`: Total context length to allocate the KV cache for (total number of tokens which the KV cache can hold)
             when using PagedAttention, which is only supported on CUDA and is always automatically activated.
             The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
+        - `pa_num_tokens`: Total number of KV tokens across all sequences to allocate the PagedAttention KV cache for,
+            for example the maximum concurrency times the context budget of each request. The cache is sized in blocks
+            of `pa_blk_size` tokens, so no memory is lost to rounding. This takes priority over the other `pa_*` memory
+            options, and loading fails if the tokens do not fit in the free GPU memory.
         - `pa_blk_size` sets the block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA,
             it will default to 32. PagedAttention is only supported on CUDA and is always automatically activated.
         - `no_paged_attn` disables PagedAttention on CUDA
+        - `context_overflow_handler` is called with `(prompt_tokens, max_new_tokens, max_seq_len)` when a request's prompt
+            and requested number of tokens do not fit in the model's maximum sequence length. It returns `"truncate"` to
+            drop tokens from the start of the prompt, `"reject"` to fail the request (for example, to summarize the
+            conversation and resend it), or `None`/`"default"` to error only if the prompt fills the whole context.
+        - `seed` sets the seed of the sampling RNG (default 0). The RNG is shared by all requests and advanced in
+            scheduling order, so results are only reproducible across runs when the same requests arrive in the same
+            order and are batched the same way. A request with its own `seed` is reproducible regardless.
+        - `device_ordinal` selects the CUDA or Metal device to run on when not using `num_device_layers`, instead of
+            device 0. It must be less than the number of visible devices. The device is shared by all runners of the
+            process, so every runner must select the same ordinal.
+        - `max_num_batched_tokens` sets the maximum number of prompt tokens to prefill in one step with PagedAttention.
+            Prompts are packed into prompt steps within this budget, a longer prompt is prefilled alone, and running
+            sequences get a completion step between prompt steps.
         """
         ...
 
     def send_chat_completion_request(
         self, request: ChatCompletionRequest
-    ) -> ChatCompletionResponse | Iterator[ChatCompletionChunkResponse]:
+    ) -> ChatCompletionResponse | ChatCompletionStreamer:
         """
         Send a chat completion request to the mistral.rs engine, returning the response object or a generator
         over chunk objects.
+
+        If the model fails while streaming, the generator raises a `ValueError` whose message contains the
+        error and the content generated so far by each choice, and then stops. If the generator is dropped before
+        the stream ended, the request is canceled, so an abandoned stream does not keep generating.
+
+        The GIL is released while waiting for the response, so requests sent from several Python threads are
+        batched by the engine and run concurrently.
+        """
+
+    def send_chat_completion_batch(
+        self, requests: list[ChatCompletionRequest]
+    ) -> list[ChatCompletionResponse | str]:
+        """
+        Send several chat completion requests to the mistral.rs engine at once, so that they are batched together,
+        and return their responses in the order of `requests`. The GIL is released while waiting.
+
+        A request which fails, including a streaming request, which is not supported here, returns its error message
+        in place of a response. A `ValueError` is only raised if the engine itself fails.
         """
 
-    def send_completion_request(self, request: CompletionRequest) -> CompletionResponse:
+    def cancel_request(self, id: int) -> None:
         """
-        Send a chat completion request to the mistral.rs engine, returning the response object.
+        Cancel the request with the given id, such as the `request_id` of a `ChatCompletionStreamer`. Its
+        sequences finish after their next step with the `canceled` finish reason, so a stream yields a final
+        chunk and stops.
         """
 
-    def send_re_isq(self, dtype: str) -> CompletionResponse:
+    def send_completion_request(
+        self, request: CompletionRequest
+    ) -> CompletionResponse | CompletionStreamer:
+        """
+        Send a completion request to the mistral.rs engine, returning the response object or, if the request
+        sets `stream`, a generator over chunk objects.
+
+        If the model fails while streaming, the generator raises a `ValueError` whose message contains the
+        error and the text generated so far by each choice, and then stops.
+
+        Like `send_chat_completion_request`, this releases the GIL while waiting for the response.
+        """
+
+    def send_re_isq(self, dtype: str, imatrix: str | None = None) -> CompletionResponse:
         """
         Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML then nothing will happen.
+
+        Optionally, pass the path to a llama.cpp `imatrix` file to weight the quantization error per channel.
+        """
+
+    def ping(self) -> float:
+        """
+        Measure the round-trip time to the engine in milliseconds. This does not run the model, so it
+        distinguishes an unresponsive engine from a slow model.
+        """
+
+    def is_ready(self, timeout: float = 5.0) -> bool:
+        """
+        Whether the engine is alive and answers a ping within `timeout` seconds, for example for Kubernetes
+        liveness or readiness checks. The engine answers between steps, so this succeeds while other requests
+        run. Returns `False` if the engine thread has exited, without restarting it.
+        """
+
+    def tokenize(self, text: str, add_special_tokens: bool = True) -> list[int]:
+        """
+        Tokenize a text with the tokenizer of the loaded model, including tokenizers embedded in GGUF files.
+        """
+
+    def detokenize(self, tokens: list[int], skip_special_tokens: bool = True) -> str:
+        """
+        Decode tokens to a text with the tokenizer of the loaded model.
+        """
+
+    def render_chat_template(
+        self,
+        messages: list[dict[str, str]],
+        add_generation_prompt: bool = True,
+        tool_schemas: list[str] | None = None,
+    ) -> str:
+        """
+        Render text messages with the chat template of the loaded model and return the prompt, without running
+        the model. This is useful to check that a template handles system prompts and tools as expected.
+        """
+
+    def export_seq_state(self, id: int) -> bytes:
+        """
+        Serialize the tokens and KV cache of the running sequence with the given id (the `id` of its responses),
+        for example to resume a long prefill after a client reconnects. The KV cache holds about
+        `2 * num_hidden_layers * num_key_value_heads * head_dim` values of the model's dtype per token.
+        Not supported with PagedAttention.
+        """
+
+    def import_seq_state(
+        self, data: bytes, request: CompletionRequest
+    ) -> CompletionResponse | CompletionStreamer:
+        """
+        Restore a state returned by `export_seq_state` as a new sequence which resumes generating after its tokens,
+        and return its result as `send_completion_request`. The prompt of `request` is replaced by the tokens of the
+        state, while its other parameters apply to the new sequence, which must have a single choice. The `id` of
+        the responses is the id of the new sequence, which can be exported again. The state must have been exported
+        from the same model with the same dtype, and is loaded onto this model's device. Requires the prefix cache.
+        """
+
+    def send_embedding_request(
+        self, text: str, pooling: EmbeddingPooling = EmbeddingPooling.LastToken
+    ) -> EmbeddingResponse:
+        """
+        Embed a text with the hidden states of the final layer, before the LM head, pooled into one vector.
+        Only supported by some text models (Llama, Mistral and Gemma), without X-LoRA.
+        """
+
+    def raw_forward(
+        self,
+        tokens: list[int],
+        return_hidden_states: bool = False,
+        all_positions: bool = False,
+    ) -> RawForwardResponse:
+        """
+        For debugging and research: run `tokens` through the model in a single forward pass, without the scheduler
+        or sampling, and return the raw logits of the last position. With `return_hidden_states`, the hidden states
+        of the final layer before the LM head are returned too, which is only supported by the models supporting
+        embeddings. With `all_positions`, every position is returned instead of the last one, which takes
+        `vocab_size` floats per token. X-LoRA models are not supported.
+        """
+
+    def get_model_info(self) -> dict[str, Any]:
+        """
+        Get the limits and kind of the loaded model, to avoid hardcoding them per model:
+        - `max_seq_len`: the maximum number of tokens of a sequence, including the prompt. For GGUF models, this
+            is the context length from the GGUF metadata.
+        - `num_hidden_layers`: the number of decoder layers.
+        - `vocab_size`: the number of tokens of the tokenizer, including added tokens.
+        - `eos_tokens`: the token ids which are treated as EOS.
+        - `kind`: the kind of model, such as `normal (no quant, no adapters)`.
+        - `is_vision`: whether the model takes images.
+        """
+
+    def cache_usage(self) -> dict[str, int | None]:
+        """
+        Get the usage of the KV cache, answered by the engine between steps, to reject or queue requests
+        before the engine starts preempting sequences:
+        - `free_blocks`: the number of PagedAttention KV cache blocks not allocated to a sequence.
+        - `total_blocks`: the number of PagedAttention KV cache blocks.
+        - `running`: the number of sequences which are scheduled.
+        - `waiting`: the number of sequences waiting to be scheduled.
+
+        The block counts are `None` without PagedAttention.
+        """
+
+    def prefix_cache_stats(self) -> dict[str, int]:
+        """
+        Get the hit, miss and eviction counts of the prefix cache since the model was loaded, to diagnose
+        latency differences between requests which share a prefix:
+        - `hits`: the number of prompts whose KV cache was found in the prefix cache.
+        - `misses`: the number of prompts which were not found in the prefix cache.
+        - `evictions`: the number of cached prefixes moved from the device to the CPU to free device memory.
+        """
+
+    def clear_prefix_cache(self) -> None:
+        """
+        Drop all prefixes cached by the prefix cache, freeing their KV caches. This is handled by the engine
+        between steps, so running requests are not affected. The counts of `prefix_cache_stats` are not reset.
+        """
+
+    def vocab(self) -> list[tuple[int, bytes]]:
+        """
+        Get the vocabulary of the loaded model as a list of `(token id, token bytes)` pairs, for building
+        grammars or custom constrained decoding on the client side. Special tokens map to an empty byte
+        string; use `special_tokens` and `eos_tokens` to identify them.
+        """
+
+    def special_tokens(self) -> dict[str, int]:
+        """
+        Get the special tokens of the loaded model, mapping each token's content to its id.
+        """
+
+    def eos_tokens(self) -> list[int]:
+        """
+        Get the ids of the tokens which are treated as EOS by the loaded model.
         """
 
     def activate_adapters(self, adapter_names: list[str]) -> None:
@@ -252,6 +632,37 @@ class Runner:
         Send a request to make the specified adapters the active adapters for the model.
         """
 
+    def activate_adapters_weighted(self, adapters: list[tuple[str, float] | str]) -> None:
+        """
+        Send a request to make the specified adapters the active adapters for the model, scaling the contribution
+        of each by its weight. An adapter given only by its name has a weight of 1.0, and a weight of 0.0 disables it.
+        Not supported for X-LoRA models.
+        """
+
+    def benchmark(
+        self, n_requests: int = 16, prompt_len: int = 512, gen_len: int = 128
+    ) -> BenchStats:
+        """
+        Saturate the engine with `n_requests` synthetic completion requests of `prompt_len` prompt tokens
+        which generate up to `gen_len` tokens each, and report the throughput and latency. At most `max_seqs`
+        requests run at once. Generation may stop early at an EOS token.
+        """
+
+    def adapters(self) -> AdaptersResponse:
+        """
+        Get the names of the adapters loaded by the model and of the currently active ones. Use this to show
+        the current state or to check that `activate_adapters` took effect.
+        """
+
+    def load_adapter(self, name: str, source: str) -> AdaptersResponse:
+        """
+        Load a LoRA adapter into the running model so that it can be activated as `name` with `activate_adapters`.
+        `source` is a local directory or Hugging Face model ID with `adapter_config.json` and
+        `adapter_model.safetensors`. The adapter must target the same modules as the adapters the model was
+        loaded with, which must be a LoRA model with preloaded adapters. Raises a `ValueError` for X-LoRA,
+        quantized and base models. Returns the adapters after loading.
+        """
+
 class AnyMoeExpertType(Enum):
     """
     Expert type for an AnyMoE model. May be:
@@ -303,6 +714,36 @@ class AnyMoeConfig:
         """
         ...
 
+@dataclass
+class BenchStats:
+    n_requests: int
+    prompt_tokens: int
+    completion_tokens: int
+    total_time_sec: float
+    requests_per_sec: float
+    tok_per_sec: float
+    completion_tok_per_sec: float
+    avg_prompt_tok_per_sec: float
+    avg_compl_tok_per_sec: float
+    latency_p50_sec: float
+    latency_p90_sec: float
+    latency_p99_sec: float
+
+@dataclass
+class AdaptersResponse:
+    available: list[str]
+    active: list[str]
+
+@dataclass
+class EmbeddingResponse:
+    embedding: list[float]
+    prompt_tokens: int
+
+@dataclass
+class RawForwardResponse:
+    logits: list[list[float]]
+    hidden_states: list[list[float]] | None
+
 @dataclass
 class Usage:
     completion_tokens: int
@@ -314,6 +755,9 @@ class Usage:
     total_time_sec: float
     total_prompt_time_sec: float
     total_completion_time_sec: float
+    prompt_time_ms: float
+    completion_time_ms: float
+    total_time_ms: float
 
 @dataclass
 class ToolCallType(Enum):
@@ -330,6 +774,22 @@ class ToolCallResponse:
     type: ToolCallType
     function: CalledFunction
 
+@dataclass
+class CalledFunctionDelta:
+    name: str | None
+    arguments: str
+
+@dataclass
+class ToolCallDelta:
+    """
+    A part of a streamed tool call. The first part of a call has its `id`, `type` and function name, and the
+    `arguments` of all its parts concatenate to the JSON arguments of the call.
+    """
+    index: int
+    id: str | None
+    type: ToolCallType | None
+    function: CalledFunctionDelta
+
 @dataclass
 class ResponseMessage:
     content: str
@@ -340,7 +800,7 @@ class ResponseMessage:
 class TopLogprob:
     token: int
     logprob: float
-    bytes: str
+    bytes: list[int]
 
 @dataclass
 class ResponseLogprob:
@@ -359,6 +819,20 @@ class Choice:
     index: int
     message: ResponseMessage
     logprobs: Logprobs
+    matched_stop: str | None
+
+@dataclass
+class SamplingParamsUsed:
+    temperature: float | None
+    top_k: int | None
+    top_p: float
+    min_p: float
+    typical_p: float
+    frequency_penalty: float | None
+    presence_penalty: float | None
+    repetition_penalty: float | None
+    max_tokens: int | None
+    n: int
 
 @dataclass
 class ChatCompletionResponse:
@@ -369,11 +843,13 @@ class ChatCompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+    sampling_params_used: SamplingParamsUsed
 
 @dataclass
 class Delta:
     content: str
     role: str
+    tool_calls: list[ToolCallDelta]
 
 @dataclass
 class ChunkChoice:
@@ -381,6 +857,7 @@ class ChunkChoice:
     index: int
     delta: Delta
     logprobs: ResponseLogprob | None
+    matched_stop: str | None
 
 @dataclass
 class ChatCompletionChunkResponse:
@@ -390,13 +867,15 @@ class ChatCompletionChunkResponse:
     model: str
     system_fingerprint: str
     object: str
+    usage: Usage | None
 
 @dataclass
 class CompletionChoice:
     finish_reason: str
     index: int
     text: str
-    # NOTE(EricLBuehler): `logprobs` in undocumented
+    logprobs: Logprobs | None
+    matched_stop: str | None
 
 @dataclass
 class CompletionResponse:
@@ -407,3 +886,22 @@ class CompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+    sampling_params_used: SamplingParamsUsed
+
+@dataclass
+class CompletionChunkChoice:
+    text: str
+    index: int
+    logprobs: ResponseLogprob | None
+    finish_reason: str | None
+    matched_stop: str | None
+    echo: bool
+
+@dataclass
+class CompletionChunkResponse:
+    id: str
+    choices: list[CompletionChunkChoice]
+    created: int
+    model: str
+    system_fingerprint: str
+    object: str
@@ -4,51 +4,98 @@ use anymoe::{AnyMoeConfig, AnyMoeExpertType};
 use base64::{engine::general_purpose, Engine};
 use either::Either;
 use indexmap::IndexMap;
-use requests::{ChatCompletionRequest, CompletionRequest, ToolChoice};
+use requests::{
+    build_logits_processors, merge_logit_bias_strings, ChatCompletionRequest, CompletionRequest,
+    EmbeddingPooling, TemperatureOrder, ToolChoice,
+};
 use std::{
+    borrow::Cow,
     cell::RefCell,
     collections::HashMap,
     fs,
     io::Read,
     num::NonZeroUsize,
+    path::PathBuf,
     str::FromStr,
     sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
 };
-use stream::ChatCompletionStreamer;
-use tokio::sync::mpsc::channel;
+use stream::{ChatCompletionStreamer, CompletionStreamer};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use candle_core::Device;
+use image::ImageFormat;
 use mistralrs_core::{
-    initialize_logging, paged_attn_supported, parse_isq_value, AnyMoeLoader,
-    ChatCompletionResponse, CompletionResponse, Constraint, DefaultSchedulerMethod,
-    DeviceLayerMapMetadata, DeviceMapMetadata, GGMLLoaderBuilder, GGMLSpecificConfig,
-    GGUFLoaderBuilder, Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType,
-    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig,
-    Request as _Request, RequestMessage, Response, SamplingParams, SchedulerConfig,
-    SpeculativeConfig, SpeculativeLoader, StopTokens, TokenSource, Tool, Topology,
-    VisionLoaderBuilder, VisionSpecificConfig,
+    initialize_logging, load_image, paged_attn_supported, parse_isq_value, AdaptersResponse,
+    AnyMoeLoader, BenchConfig, BenchStats, ChatCompletionResponse, CompletionResponse, Constraint,
+    ContextOverflow, ContextOverflowAction, ContextOverflowHandler, DefaultSchedulerMethod,
+    DeviceLayerMapMetadata, DeviceMapMetadata, EmbeddingResponse, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFLoaderBuilder, Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder,
+    ModelDType, NgramSpeculativeConfig, NgramSpeculativeLoader, NormalLoaderBuilder, NormalRequest,
+    NormalSpecificConfig, PagedAttentionConfig, RawForwardResponse, Request as _Request,
+    RequestMessage, Response, SchedulerConfig, SpeculativeConfig, SpeculativeLoader, TokenSource,
+    Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
 };
-use pyo3::{exceptions::PyValueError, prelude::*};
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyDict};
 use std::fs::File;
 mod anymoe;
 mod requests;
 mod stream;
 mod which;
-use which::{Architecture, VisionArchitecture, Which};
+use which::{load_ordering, Architecture, VisionArchitecture, Which};
+
+static DEVICE: OnceLock<(usize, Device)> = OnceLock::new();
 
-static DEVICE: OnceLock<Device> = OnceLock::new();
+/// The number of devices which may be selected with `device_ordinal`. Without an accelerator, this is
+/// only the CPU.
+#[cfg(feature = "cuda")]
+fn visible_device_count() -> usize {
+    candle_core::cuda_backend::cudarc::driver::CudaDevice::count()
+        .map(|n| n.max(1) as usize)
+        .unwrap_or(1)
+}
+#[cfg(not(feature = "cuda"))]
+fn visible_device_count() -> usize {
+    1
+}
 
 #[cfg(not(feature = "metal"))]
-fn get_device() -> Device {
-    DEVICE
-        .get_or_init(|| Device::cuda_if_available(0).expect("Failed to create device"))
-        .clone()
+fn new_device(ordinal: usize) -> candle_core::Result<Device> {
+    Device::cuda_if_available(ordinal)
 }
 #[cfg(feature = "metal")]
-fn get_device() -> Device {
-    DEVICE
-        .get_or_init(|| Device::new_metal(0).expect("Failed to create device"))
-        .clone()
+fn new_device(ordinal: usize) -> candle_core::Result<Device> {
+    Device::new_metal(ordinal)
+}
+
+/// Get the device of this process, creating it with `ordinal` (0 by default) on first use. All
+/// runners share one device, so a later runner may not select a different ordinal.
+fn get_device(ordinal: Option<usize>) -> PyResult<Device> {
+    if let Some(ordinal) = ordinal {
+        let count = visible_device_count();
+        if ordinal >= count {
+            return Err(PyValueError::new_err(format!(
+                "`device_ordinal` is {ordinal} but only {count} device(s) are visible."
+            )));
+        }
+    }
+    let (current, device) = match DEVICE.get() {
+        Some(device) => device,
+        None => {
+            let ordinal = ordinal.unwrap_or(0);
+            let device = new_device(ordinal).map_err(|e| {
+                PyValueError::new_err(format!("Failed to create device {ordinal}: {e}"))
+            })?;
+            // Another runner may have created the device meanwhile, in which case it is kept.
+            DEVICE.get_or_init(|| (ordinal, device))
+        }
+    };
+    match ordinal {
+        Some(ordinal) if ordinal != *current => Err(PyValueError::new_err(format!(
+            "`device_ordinal` is {ordinal} but this process already uses device {current}."
+        ))),
+        _ => Ok(device.clone()),
+    }
 }
 
 #[pyclass]
@@ -108,11 +155,7 @@ fn parse_which(
         )
         .with_xlora(
             xlora_model_id,
-            serde_json::from_reader(
-                File::open(order.clone())
-                    .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
-            )
-            .map_err(|e| PyValueError::new_err(e.to_string()))?,
+            load_ordering(&order)?,
             no_kv_cache,
             tgt_non_granular_index,
         )
@@ -135,14 +178,7 @@ fn parse_which(
             tokenizer_json,
             model_id,
         )
-        .with_lora(
-            adapters_model_id,
-            serde_json::from_reader(
-                File::open(order.clone())
-                    .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
-            )
-            .map_err(|e| PyValueError::new_err(e.to_string()))?,
-        )
+        .with_lora(adapters_model_id, load_ordering(&order)?)
         .build(arch.into())
         .map_err(|e| PyValueError::new_err(e.to_string()))?,
         Which::GGUF {
@@ -173,11 +209,7 @@ fn parse_which(
         )
         .with_xlora(
             xlora_model_id,
-            serde_json::from_reader(
-                File::open(order.clone())
-                    .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
-            )
-            .map_err(|e| PyValueError::new_err(e.to_string()))?,
+            load_ordering(&order)?,
             no_kv_cache,
             tgt_non_granular_index,
         )
@@ -195,14 +227,7 @@ fn parse_which(
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             prompt_batchsize,
         )
-        .with_lora(
-            adapters_model_id,
-            serde_json::from_reader(
-                File::open(order.clone())
-                    .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
-            )
-            .map_err(|e| PyValueError::new_err(e.to_string()))?,
-        )
+        .with_lora(adapters_model_id, load_ordering(&order)?)
         .build(),
         Which::GGML {
             tok_model_id,
@@ -244,11 +269,7 @@ fn parse_which(
         )
         .with_xlora(
             xlora_model_id,
-            serde_json::from_reader(
-                File::open(order.clone())
-                    .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
-            )
-            .map_err(|e| PyValueError::new_err(e.to_string()))?,
+            load_ordering(&order)?,
             no_kv_cache,
             tgt_non_granular_index,
         )
@@ -272,25 +293,20 @@ fn parse_which(
             quantized_model_id,
             quantized_filename,
         )
-        .with_lora(
-            adapters_model_id,
-            serde_json::from_reader(
-                File::open(order.clone())
-                    .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
-            )
-            .map_err(|e| PyValueError::new_err(e.to_string()))?,
-        )
+        .with_lora(adapters_model_id, load_ordering(&order)?)
         .build(),
         Which::VisionPlain {
             model_id,
             tokenizer_json,
             arch,
             topology,
+            max_image_edge,
         } => VisionLoaderBuilder::new(
             VisionSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
                 topology: Topology::from_option_path(topology)?,
+                max_image_edge,
             },
             chat_template,
             tokenizer_json,
@@ -300,6 +316,46 @@ fn parse_which(
     })
 }
 
+/// Wrap a Python callable `(prompt_tokens, max_new_tokens, max_seq_len) -> str | None` as a
+/// context overflow handler. It may return `None` or `"default"`, `"truncate"` or `"reject"`.
+fn py_context_overflow_handler(handler: PyObject) -> ContextOverflowHandler {
+    Arc::new(move |overflow: ContextOverflow| {
+        Python::with_gil(|py| {
+            let action = handler
+                .call1(
+                    py,
+                    (
+                        overflow.prompt_tokens,
+                        overflow.max_new_tokens,
+                        overflow.max_seq_len,
+                    ),
+                )
+                .and_then(|res| res.extract::<Option<String>>(py));
+            match action.as_ref().map(|a| a.as_deref()) {
+                Ok(None | Some("default")) => ContextOverflowAction::Default,
+                Ok(Some("truncate")) => ContextOverflowAction::Truncate,
+                Ok(Some("reject")) => ContextOverflowAction::Reject(format!(
+                    "Prompt of {} tokens does not fit in the maximum sequence length of {}.",
+                    overflow.prompt_tokens, overflow.max_seq_len
+                )),
+                Ok(Some(other)) => ContextOverflowAction::Reject(format!(
+                    "Context overflow handler returned unknown action `{other}`."
+                )),
+                Err(e) => {
+                    ContextOverflowAction::Reject(format!("Context overflow handler failed: {e}"))
+                }
+            }
+        })
+    })
+}
+
+/// An adapter name with its weight, which is 1.0 if only the name is given.
+#[derive(FromPyObject)]
+enum WeightedAdapter {
+    Weighted(String, f64),
+    Name(String),
+}
+
 #[pymethods]
 impl Runner {
     #[new]
@@ -311,6 +367,7 @@ impl Runner {
         token_source = "cache",
         speculative_gamma = 32,
         which_draft = None,
+        speculative_ngram = None,
         chat_template = None,
         num_device_layers = None,
         in_situ_quant = None,
@@ -318,9 +375,14 @@ impl Runner {
         pa_gpu_mem = None,
         pa_gpu_mem_usage = None,
         pa_ctxt_len = None,
+        pa_num_tokens = None,
         pa_blk_size = None,
         no_paged_attn = false,
         prompt_batchsize = None,
+        context_overflow_handler = None,
+        seed = None,
+        device_ordinal = None,
+        max_num_batched_tokens = None,
     ))]
     fn new(
         which: Which,
@@ -330,6 +392,7 @@ impl Runner {
         token_source: &str,
         speculative_gamma: usize,
         which_draft: Option<Which>,
+        speculative_ngram: Option<usize>,
         chat_template: Option<String>,
         num_device_layers: Option<Vec<String>>,
         in_situ_quant: Option<String>,
@@ -337,10 +400,21 @@ impl Runner {
         pa_gpu_mem: Option<usize>,
         pa_gpu_mem_usage: Option<f32>,
         pa_ctxt_len: Option<usize>,
+        pa_num_tokens: Option<usize>,
         pa_blk_size: Option<usize>,
         no_paged_attn: bool,
         prompt_batchsize: Option<usize>,
+        context_overflow_handler: Option<PyObject>,
+        seed: Option<u64>,
+        device_ordinal: Option<usize>,
+        max_num_batched_tokens: Option<usize>,
     ) -> PyResult<Self> {
+        which.validate()?;
+        let device = get_device(device_ordinal)?;
+        if let Some(which_draft) = &which_draft {
+            which_draft.validate()?;
+        }
+
         let tgt_non_granular_index = match which {
             Which::Plain { .. }
             | Which::Lora { .. }
@@ -362,12 +436,23 @@ impl Runner {
                 ..
             } => tgt_non_granular_index,
         };
-        let max_seqs = if tgt_non_granular_index.is_some() {
+        if which_draft.is_some() && speculative_ngram.is_some() {
+            return Err(PyValueError::new_err(
+                "Only one of `which_draft` and `speculative_ngram` may be specified.",
+            ));
+        }
+        let max_seqs = if tgt_non_granular_index.is_some() || speculative_ngram.is_some() {
             1
         } else {
             max_seqs
         };
 
+        if max_num_batched_tokens == Some(0) {
+            return Err(PyValueError::new_err(
+                "`max_num_batched_tokens` must be a strictly positive integer, got 0.",
+            ));
+        }
+
         let prompt_batchsize = match prompt_batchsize {
             Some(0) => {
                 return Err(PyValueError::new_err(
@@ -388,6 +473,14 @@ impl Runner {
                     gamma: speculative_gamma,
                 },
             })
+        } else if let Some(max_ngram) = speculative_ngram {
+            Box::new(NgramSpeculativeLoader {
+                target: loader,
+                config: NgramSpeculativeConfig {
+                    gamma: speculative_gamma,
+                    max_ngram,
+                },
+            })
         } else {
             loader
         };
@@ -414,7 +507,6 @@ impl Runner {
             loader
         };
 
-        let device = get_device();
         let isq = if let Some(isq) = in_situ_quant {
             Some(parse_isq_value(&isq).map_err(|e| PyValueError::new_err(e.to_string()))?)
         } else {
@@ -423,7 +515,9 @@ impl Runner {
 
         let mapper = match num_device_layers {
             Some(device_layers) => {
-                if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
+                if device_layers.len() == 1 && device_layers[0] == "auto" {
+                    DeviceMapMetadata::auto()
+                } else if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
                     let layers = device_layers[0].parse::<usize>().unwrap();
                     DeviceMapMetadata::from_num_device_layers(vec![DeviceLayerMapMetadata {
                         ordinal: 0,
@@ -460,44 +554,47 @@ impl Runner {
 
         // Allocate 0.5 GB of CPU memory just as a placeholder.
         // Nothing happens here as we have no `swap_out`, see `_preempt_by_swap`.
-        let cache_config =
-            match (
-                pa_blk_size,
-                pa_gpu_mem,
-                pa_gpu_mem_usage,
-                pa_ctxt_len,
-                paged_attn_supported(),
-                no_paged_attn,
-            ) {
-                (block_size, None, None, None, true, false) => Some(PagedAttentionConfig::new(
+        let cache_config = match (
+            pa_blk_size,
+            pa_gpu_mem,
+            pa_gpu_mem_usage,
+            pa_ctxt_len,
+            pa_num_tokens,
+            paged_attn_supported(),
+            no_paged_attn,
+        ) {
+            (block_size, _, _, _, Some(toks), true, false) => Some(PagedAttentionConfig::new(
+                block_size,
+                512,
+                MemoryGpuConfig::NumTokens(toks),
+            )?),
+            (block_size, None, None, None, None, true, false) => {
+                Some(PagedAttentionConfig::new(
                     block_size,
                     512,
                     MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
-                )?),
-                (block_size, None, None, Some(ctxt), true, false) => Some(
-                    PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::ContextSize(ctxt))?,
-                ),
-                (block_size, None, Some(f), None, true, false) => Some(PagedAttentionConfig::new(
-                    block_size,
-                    512,
-                    MemoryGpuConfig::Utilization(f),
-                )?),
-                (block_size, Some(m), None, None, true, false) => Some(PagedAttentionConfig::new(
-                    block_size,
-                    512,
-                    MemoryGpuConfig::Amount(m),
-                )?),
-                (block_size, Some(_m), Some(f), None, true, false) => Some(
-                    PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::Utilization(f))?,
-                ),
-                (block_size, Some(_m), None, Some(ctxt), true, false) => Some(
-                    PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::ContextSize(ctxt))?,
-                ),
-                (block_size, None, Some(f), Some(_ctxt), true, false) => Some(
-                    PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::Utilization(f))?,
-                ),
-                (_, _, _, _, _, _) => None,
-            };
+                )?)
+            }
+            (block_size, None, None, Some(ctxt), None, true, false) => Some(
+                PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::ContextSize(ctxt))?,
+            ),
+            (block_size, None, Some(f), None, None, true, false) => Some(
+                PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::Utilization(f))?,
+            ),
+            (block_size, Some(m), None, None, None, true, false) => Some(
+                PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::Amount(m))?,
+            ),
+            (block_size, Some(_m), Some(f), None, None, true, false) => Some(
+                PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::Utilization(f))?,
+            ),
+            (block_size, Some(_m), None, Some(ctxt), None, true, false) => Some(
+                PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::ContextSize(ctxt))?,
+            ),
+            (block_size, None, Some(f), Some(_ctxt), None, true, false) => Some(
+                PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::Utilization(f))?,
+            ),
+            (_, _, _, _, _, _, _) => None,
+        };
 
         let pipeline = loader
             .load_model_from_hf(
@@ -518,6 +615,7 @@ impl Runner {
             if let Some(ref cache_config) = pipeline.blocking_lock().get_metadata().cache_config {
                 SchedulerConfig::PagedAttentionMeta {
                     max_num_seqs: max_seqs,
+                    max_num_batched_tokens,
                     config: cache_config.clone(),
                 }
             } else {
@@ -538,416 +636,930 @@ impl Runner {
                 ),
             }
         };
-        let mistralrs = MistralRsBuilder::new(pipeline, scheduler_config)
+        let mut builder = MistralRsBuilder::new(pipeline, scheduler_config)
             .with_no_kv_cache(no_kv_cache)
-            .with_prefix_cache_n(prefix_cache_n)
-            .build();
+            .with_prefix_cache_n(prefix_cache_n);
+        if let Some(handler) = context_overflow_handler {
+            builder = builder.with_context_overflow_handler(py_context_overflow_handler(handler));
+        }
+        if let Some(seed) = seed {
+            builder = builder.with_seed(seed);
+        }
+        let mistralrs = builder.build();
 
         Ok(Self { runner: mistralrs })
     }
 
     /// Send an OpenAI API compatible request, returning the result.
     fn send_chat_completion_request(
-        &mut self,
+        &self,
+        py: Python<'_>,
         request: Py<ChatCompletionRequest>,
     ) -> PyResult<Either<ChatCompletionResponse, ChatCompletionStreamer>> {
-        let (tx, mut rx) = channel(10_000);
-        Python::with_gil(|py| {
+        let request = request.bind(py).borrow();
+        let (id, model_request, mut rx) = self.chat_completion_request(&request)?;
+
+        MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
+        let sender = self.runner.get_sender()?;
+
+        if request.stream {
+            py.allow_threads(|| sender.blocking_send(model_request))
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            Ok(Either::Right(ChatCompletionStreamer::from_rx(
+                rx,
+                id,
+                request.stream_options_include_usage,
+                sender,
+                // Each chunk carries the logprobs of its own token, so they cannot be coalesced.
+                request
+                    .stream_interval_ms
+                    .filter(|_| !request.logprobs)
+                    .map(Duration::from_millis),
+            )))
+        } else {
+            chat_completion_result(send_and_wait(py, &sender, model_request, &mut rx)?)
+                .map(Either::Left)
+                .map_err(PyValueError::new_err)
+        }
+    }
+
+    /// Send OpenAI API compatible requests together, so that the engine schedules them in the same
+    /// batches, and return their results in order. The result of a request which fails is its error
+    /// message, while a failure of the engine raises an error.
+    fn send_chat_completion_batch(
+        &self,
+        py: Python<'_>,
+        requests: Vec<Py<ChatCompletionRequest>>,
+    ) -> PyResult<Vec<Either<ChatCompletionResponse, String>>> {
+        let mut results = Vec::with_capacity(requests.len());
+        let mut model_requests = Vec::new();
+        for request in &requests {
             let request = request.bind(py).borrow();
-            let stop_toks = request
-                .stop_seqs
-                .as_ref()
-                .map(|x| StopTokens::Seqs(x.to_vec()));
-            let constraint = if request.grammar_type == Some("regex".to_string()) {
-                if request.grammar.is_none() {
-                    return Err(PyValueError::new_err(
-                        "Grammar type is specified but not grammar text",
-                    ));
-                }
-                Constraint::Regex(request.grammar.as_ref().unwrap().clone())
-            } else if request.grammar_type == Some("yacc".to_string()) {
-                if request.grammar.is_none() {
-                    return Err(PyValueError::new_err(
-                        "Grammar type is specified but not grammar text",
-                    ));
+            if request.stream {
+                results.push(Some(Either::Right(
+                    "Streaming requests cannot be sent in a batch.".to_string(),
+                )));
+                continue;
+            }
+            match self.chat_completion_request(&request) {
+                Ok((_, model_request, rx)) => {
+                    MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
+                    model_requests.push((model_request, rx));
+                    results.push(None);
                 }
-                Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
-            } else if request.grammar_type.is_some() {
-                return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
-                ));
-            } else {
-                Constraint::None
-            };
+                Err(e) => results.push(Some(Either::Right(e.to_string()))),
+            }
+        }
+
+        let sender = self.runner.get_sender()?;
+        let mut responses = py
+            .allow_threads(|| send_all_and_wait(&sender, model_requests))?
+            .into_iter();
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    match chat_completion_result(
+                        responses.next().expect("One response per request."),
+                    ) {
+                        Ok(response) => Either::Left(response),
+                        Err(e) => Either::Right(e),
+                    }
+                })
+            })
+            .collect())
+    }
+
+    /// Send an OpenAI API compatible request, returning the result.
+    fn send_completion_request(
+        &self,
+        py: Python<'_>,
+        request: Py<CompletionRequest>,
+    ) -> PyResult<Either<CompletionResponse, CompletionStreamer>> {
+        let request = request.bind(py).borrow();
+        let (id, model_request, mut rx) = self.completion_request(&request)?;
+
+        MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
+        let sender = self.runner.get_sender()?;
+        if request.stream {
+            py.allow_threads(|| sender.blocking_send(_Request::Normal(model_request)))
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            return Ok(Either::Right(CompletionStreamer::from_rx(rx, id)));
+        }
+        completion_result(send_and_wait(
+            py,
+            &sender,
+            _Request::Normal(model_request),
+            &mut rx,
+        )?)
+        .map(Either::Left)
+        .map_err(PyValueError::new_err)
+    }
+
+    /// Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML
+    /// then nothing will happen.
+    ///
+    /// Optionally, pass the path to a llama.cpp `imatrix` file to weight the quantization error
+    /// per channel.
+    #[pyo3(signature = (dtype, imatrix = None))]
+    fn send_re_isq(&self, dtype: String, imatrix: Option<String>) -> PyResult<()> {
+        let request = _Request::ReIsq(
+            parse_isq_value(&dtype).map_err(|e| PyValueError::new_err(e.to_string()))?,
+            imatrix.map(PathBuf::from),
+        );
+        self.runner
+            .get_sender()?
+            .blocking_send(request)
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Measure the round-trip time to the engine in milliseconds. This does not run the model, so
+    /// it measures the responsiveness of the engine thread independent of model compute.
+    fn ping(&self, py: Python<'_>) -> PyResult<f32> {
+        let sender = self.runner.get_sender()?;
+        // Release the GIL while waiting, so other Python threads run meanwhile.
+        py.allow_threads(move || {
+            let (tx, mut rx) = channel(1);
+            let start = Instant::now();
+            sender
+                .blocking_send(_Request::Ping(tx))
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            rx.blocking_recv()
+                .ok_or_else(|| PyValueError::new_err("Engine did not respond to ping."))?;
+            Ok(start.elapsed().as_secs_f32() * 1000.)
+        })
+    }
+
+    /// Whether the engine is alive and answers a ping within `timeout` seconds, for liveness and
+    /// readiness checks. Returns `False` if the engine thread exited, without restarting it.
+    #[pyo3(signature = (timeout = 5.0))]
+    fn is_ready(&self, py: Python<'_>, timeout: f32) -> PyResult<bool> {
+        let timeout = Duration::try_from_secs_f32(timeout)
+            .map_err(|e| PyValueError::new_err(format!("Invalid timeout: {e}")))?;
+        let runner = self.runner.clone();
+        Ok(py.allow_threads(move || runner.is_ready(timeout)))
+    }
+
+    /// Get the limits and kind of the loaded model as a dict with the `max_seq_len`, `num_hidden_layers`,
+    /// `vocab_size`, `eos_tokens`, `kind` and `is_vision` keys.
+    fn get_model_info<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::ModelInfo(tx))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let info = py
+            .allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond with the model info."))?;
+        let dict = PyDict::new_bound(py);
+        dict.set_item("max_seq_len", info.max_seq_len)?;
+        dict.set_item("num_hidden_layers", info.num_hidden_layers)?;
+        dict.set_item("vocab_size", info.vocab_size)?;
+        dict.set_item("eos_tokens", info.eos_tokens)?;
+        dict.set_item("kind", info.kind)?;
+        dict.set_item("is_vision", info.is_vision)?;
+        Ok(dict)
+    }
+
+    /// Get the free and total PagedAttention KV cache blocks and the number of running and waiting
+    /// sequences as a dict with the `free_blocks`, `total_blocks`, `running` and `waiting` keys.
+    fn cache_usage<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::CacheUsage(tx))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let usage = py
+            .allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond with the cache usage."))?;
+        let dict = PyDict::new_bound(py);
+        dict.set_item("free_blocks", usage.free_blocks)?;
+        dict.set_item("total_blocks", usage.total_blocks)?;
+        dict.set_item("running", usage.running)?;
+        dict.set_item("waiting", usage.waiting)?;
+        Ok(dict)
+    }
+
+    /// Get the cumulative hit, miss and eviction counts of the prefix cache as a dict with the
+    /// `hits`, `misses` and `evictions` keys.
+    fn prefix_cache_stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::PrefixCacheStats(tx))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let stats = py.allow_threads(|| rx.blocking_recv()).ok_or_else(|| {
+            PyValueError::new_err("Engine did not respond with the prefix cache stats.")
+        })?;
+        let dict = PyDict::new_bound(py);
+        dict.set_item("hits", stats.hits)?;
+        dict.set_item("misses", stats.misses)?;
+        dict.set_item("evictions", stats.evictions)?;
+        Ok(dict)
+    }
+
+    /// Drop all prefixes cached by the prefix cache. Running requests are not affected.
+    fn clear_prefix_cache(&self) -> PyResult<()> {
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::ClearPrefixCache)
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Get the vocabulary of the loaded model as a list of `(token id, token bytes)` pairs. Special
+    /// tokens map to an empty byte string; use `special_tokens` and `eos_tokens` to identify them.
+    fn vocab(&self) -> Vec<(u32, Cow<'static, [u8]>)> {
+        self.runner
+            .get_vocab()
+            .into_iter()
+            .map(|(id, bytes)| (id, Cow::Owned(bytes)))
+            .collect()
+    }
+
+    /// Get the special tokens of the loaded model, mapping each token's content to its id.
+    fn special_tokens(&self) -> HashMap<String, u32> {
+        self.runner.get_special_tokens()
+    }
+
+    /// Get the ids of the tokens which are treated as EOS by the loaded model.
+    fn eos_tokens(&self) -> Vec<u32> {
+        self.runner.get_eos_tokens()
+    }
+
+    /// Cancel the request with the given id, such as the `request_id` of a `ChatCompletionStreamer`.
+    /// Its sequences finish after their next step with the `canceled` finish reason.
+    fn cancel_request(&self, id: usize) -> PyResult<()> {
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::Terminate(id))
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Send a request to make the specified adapters the active adapters for the model.
+    fn activate_adapters(&self, adapter_names: Vec<String>) {
+        let request = _Request::ActivateAdapters(adapter_names);
+        self.runner
+            .get_sender()
+            .unwrap()
+            .blocking_send(request)
+            .unwrap();
+    }
+
+    /// Send a request to make the specified adapters the active adapters for the model, scaling
+    /// the contribution of each by its weight.
+    fn activate_adapters_weighted(&self, adapters: Vec<WeightedAdapter>) -> PyResult<()> {
+        let adapters = adapters
+            .into_iter()
+            .map(|adapter| match adapter {
+                WeightedAdapter::Weighted(name, weight) => (name, weight),
+                WeightedAdapter::Name(name) => (name, 1.0),
+            })
+            .collect();
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::ActivateAdaptersWeighted(adapters))
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Saturate the engine with synthetic completion requests and report the throughput and latency.
+    #[pyo3(signature = (n_requests = 16, prompt_len = 512, gen_len = 128))]
+    fn benchmark(
+        &self,
+        py: Python<'_>,
+        n_requests: usize,
+        prompt_len: usize,
+        gen_len: usize,
+    ) -> PyResult<BenchStats> {
+        let config = BenchConfig {
+            n_requests,
+            prompt_len,
+            gen_len,
+        };
+        let runner = self.runner.clone();
+        py.allow_threads(move || runner.benchmark(config))
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Get the names of the adapters loaded by the model and of the currently active ones.
+    fn adapters(&self, py: Python<'_>) -> PyResult<AdaptersResponse> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::GetAdapters(tx))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        py.allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond with the adapters."))
+    }
+
+    /// Load a LoRA adapter into the running LoRA model so that it can be activated as `name`.
+    fn load_adapter(
+        &self,
+        py: Python<'_>,
+        name: String,
+        source: String,
+    ) -> PyResult<AdaptersResponse> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::LoadAdapter {
+                name,
+                path_or_hf_id: source,
+                response: tx,
+            })
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        py.allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond with the adapters."))?
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Tokenize a text with the tokenizer of the loaded model.
+    #[pyo3(signature = (text, add_special_tokens = true))]
+    fn tokenize(
+        &self,
+        py: Python<'_>,
+        text: String,
+        add_special_tokens: bool,
+    ) -> PyResult<Vec<u32>> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::Tokenize {
+                text,
+                add_special_tokens,
+                response: tx,
+            })
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        match py.allow_threads(|| rx.blocking_recv()) {
+            Some(Response::Tokenize(tokens)) => Ok(tokens),
+            Some(Response::ValidationError(e)) => Err(PyValueError::new_err(e.to_string())),
+            _ => Err(PyValueError::new_err(
+                "Engine did not respond with the tokens.",
+            )),
+        }
+    }
+
+    /// Decode tokens to a text with the tokenizer of the loaded model.
+    #[pyo3(signature = (tokens, skip_special_tokens = true))]
+    fn detokenize(
+        &self,
+        py: Python<'_>,
+        tokens: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> PyResult<String> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::Detokenize {
+                tokens,
+                skip_special_tokens,
+                response: tx,
+            })
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        match py.allow_threads(|| rx.blocking_recv()) {
+            Some(Response::Detokenize(text)) => Ok(text),
+            Some(Response::ValidationError(e)) => Err(PyValueError::new_err(e.to_string())),
+            _ => Err(PyValueError::new_err(
+                "Engine did not respond with the text.",
+            )),
+        }
+    }
+
+    /// Render text messages with the chat template of the loaded model, without running the model.
+    #[pyo3(signature = (messages, add_generation_prompt = true, tool_schemas = None))]
+    fn render_chat_template(
+        &self,
+        py: Python<'_>,
+        messages: Vec<HashMap<String, String>>,
+        add_generation_prompt: bool,
+        tool_schemas: Option<Vec<String>>,
+    ) -> PyResult<String> {
+        let messages = messages
+            .into_iter()
+            .map(|message| {
+                message
+                    .into_iter()
+                    .map(|(k, v)| (k, Either::Left(v)))
+                    .collect::<IndexMap<_, _>>()
+            })
+            .collect();
+        let tools = tool_schemas
+            .map(|schemas| {
+                schemas
+                    .iter()
+                    .map(|schema| serde_json::from_str::<Tool>(schema))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::RenderTemplate {
+                messages,
+                add_generation_prompt,
+                tools,
+                response: tx,
+            })
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        py.allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond with the prompt."))?
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Serialize the tokens and KV cache of the running sequence with the given id.
+    fn export_seq_state(&self, py: Python<'_>, id: usize) -> PyResult<Cow<'static, [u8]>> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::ExportSeqState(id, tx))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        py.allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| {
+                PyValueError::new_err("Engine did not respond with the sequence state.")
+            })?
+            .map(Cow::Owned)
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Restore an exported sequence state as a new sequence which resumes generating after its
+    /// tokens, with the sampling parameters of `request` and returning its result as
+    /// `send_completion_request`. The prompt of `request` is replaced by the tokens of the state.
+    fn import_seq_state(
+        &self,
+        py: Python<'_>,
+        data: &[u8],
+        request: Py<CompletionRequest>,
+    ) -> PyResult<Either<CompletionResponse, CompletionStreamer>> {
+        let request = request.bind(py).borrow();
+        let (id, model_request, mut rx) = self.completion_request(&request)?;
+        let (tx, mut id_rx) = channel(1);
+        MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
+        let sender = self.runner.get_sender()?;
+        let import = _Request::ImportSeqState {
+            data: data.to_vec(),
+            request: model_request,
+            response: tx,
+        };
+        py.allow_threads(|| sender.blocking_send(import))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let imported = py
+            .allow_threads(|| id_rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond to the import."))?;
+        if let Err(e) = imported {
+            // A rejected request has sent its validation error, which explains the rejection.
+            return Err(PyValueError::new_err(match rx.try_recv() {
+                Ok(Response::ValidationError(e)) => e.to_string(),
+                _ => e.to_string(),
+            }));
+        }
+        if request.stream {
+            return Ok(Either::Right(CompletionStreamer::from_rx(rx, id)));
+        }
+        py.allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond to the request."))
+            .and_then(|response| completion_result(response).map_err(PyValueError::new_err))
+            .map(Either::Left)
+    }
+
+    /// Embed a text with the pooled hidden states of the final layer of the model.
+    #[pyo3(signature = (text, pooling = EmbeddingPooling::LastToken))]
+    fn send_embedding_request(
+        &self,
+        py: Python<'_>,
+        text: String,
+        pooling: EmbeddingPooling,
+    ) -> PyResult<EmbeddingResponse> {
+        let pooling = match pooling {
+            EmbeddingPooling::LastToken => mistralrs_core::EmbeddingPooling::LastToken,
+            EmbeddingPooling::MeanPooling => mistralrs_core::EmbeddingPooling::MeanPooling,
+            EmbeddingPooling::Cls => mistralrs_core::EmbeddingPooling::Cls,
+        };
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::Embedding {
+                text,
+                pooling,
+                response: tx,
+            })
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        py.allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond with the embedding."))?
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Run tokens through the model in a single forward pass without sampling, returning the raw
+    /// logits and optionally the hidden states before the LM head.
+    #[pyo3(signature = (tokens, return_hidden_states = false, all_positions = false))]
+    fn raw_forward(
+        &self,
+        py: Python<'_>,
+        tokens: Vec<u32>,
+        return_hidden_states: bool,
+        all_positions: bool,
+    ) -> PyResult<RawForwardResponse> {
+        let (tx, mut rx) = channel(1);
+        self.runner
+            .get_sender()?
+            .blocking_send(_Request::RawForward {
+                tokens,
+                return_hidden_states,
+                all_positions,
+                response: tx,
+            })
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        py.allow_threads(|| rx.blocking_recv())
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond to the forward pass."))?
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+}
 
-            let messages = match request.messages {
-                Either::Left(ref messages) => {
-                    let mut messages_vec = Vec::new();
-                    let mut image_urls = Vec::new();
-                    for message in messages {
-                        match &message["content"] {
-                            Either::Left(content) => {
-                                let mut message_map: IndexMap<
-                                    String,
-                                    Either<String, Vec<IndexMap<String, String>>>,
-                                > = IndexMap::new();
-                                message_map.insert(
-                                    "role".to_string(),
-                                    Either::Left(message["role"].as_ref().left().unwrap().clone()),
-                                );
-                                message_map.insert(
-                                    "content".to_string(),
-                                    Either::Left(content.to_string()),
-                                );
-                                messages_vec.push(message_map);
+impl Runner {
+    /// Build the engine request for an OpenAI API compatible chat completion request, returning its id
+    /// and the receiver of its responses.
+    fn chat_completion_request(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> PyResult<(usize, _Request, Receiver<Response>)> {
+        let (tx, rx) = channel(10_000);
+        let constraint = request.constraint()?;
+
+        let messages = match request.messages {
+            Either::Left(ref messages) => {
+                let mut messages_vec = Vec::new();
+                let mut image_urls = Vec::new();
+                for message in messages {
+                    match &message["content"] {
+                        Either::Left(content) => {
+                            let mut message_map: IndexMap<
+                                String,
+                                Either<String, Vec<IndexMap<String, String>>>,
+                            > = IndexMap::new();
+                            message_map.insert(
+                                "role".to_string(),
+                                Either::Left(message["role"].as_ref().left().unwrap().clone()),
+                            );
+                            message_map
+                                .insert("content".to_string(), Either::Left(content.to_string()));
+                            messages_vec.push(message_map);
+                        }
+                        Either::Right(image_messages) => {
+                            if message["role"].as_ref().left().unwrap() != "user" {
+                                return Err(PyValueError::new_err(format!(
+                                    "Role for an image message must be `user`, but it is {}",
+                                    &message["role"].as_ref().left().unwrap()
+                                )));
                             }
-                            Either::Right(image_messages) => {
-                                if image_messages.len() != 2 {
-                                    return Err(PyValueError::new_err(
-                                    "Expected 2 items for the content of a message with an image."
-                                .to_string()));
-                                }
-                                if message["role"].as_ref().left().unwrap() != "user" {
-                                    return Err(PyValueError::new_err(format!(
-                                        "Role for an image message must be `user`, but it is {}",
-                                        &message["role"].as_ref().left().unwrap()
-                                    )));
-                                }
 
-                                let mut items = Vec::new();
-                                for image_message in image_messages {
-                                    if image_message.len() != 2 {
-                                        return Err(PyValueError::new_err("Expected 2 items for the sub-content of a message with an image.".to_string()));
-                                    }
-                                    if !image_message.contains_key("type") {
-                                        return Err(PyValueError::new_err(
-                                            "Expected `type` key in input message.".to_string(),
-                                        ));
-                                    }
-                                    if image_message["type"].is_right() {
-                                        return Err(PyValueError::new_err(
-                                            "Expected string value in `type`.".to_string(),
-                                        ));
+                            // Text and images may be interleaved in any order. Each image is
+                            // marked in the content so the chat template can place it.
+                            let mut content_map = Vec::new();
+                            for image_message in image_messages {
+                                let Some(Either::Left(tp)) = image_message.get("type") else {
+                                    return Err(PyValueError::new_err(
+                                        "Expected string value in `type`.".to_string(),
+                                    ));
+                                };
+                                match tp.as_str() {
+                                    "text" => {
+                                        let Some(Either::Left(text)) = image_message.get("text")
+                                        else {
+                                            return Err(PyValueError::new_err(
+                                                "Expected string value in `text`.".to_string(),
+                                            ));
+                                        };
+                                        let mut content_text_map = IndexMap::new();
+                                        content_text_map
+                                            .insert("type".to_string(), "text".to_string());
+                                        content_text_map.insert("text".to_string(), text.clone());
+                                        content_map.push(content_text_map);
                                     }
-                                    items.push(image_message["type"].as_ref().unwrap_left().clone())
-                                }
-
-                                #[allow(clippy::type_complexity)]
-                                fn get_content_and_url(
-                                    text_idx: usize,
-                                    url_idx: usize,
-                                    image_messages: &[HashMap<
-                                        String,
-                                        Either<String, HashMap<String, String>>,
-                                    >],
-                                ) -> PyResult<(String, String)> {
-                                    if image_messages[text_idx]["text"].is_right() {
-                                        return Err(PyValueError::new_err(
-                                            "Expected string value in `text`.".to_string(),
-                                        ));
+                                    "image_url" => {
+                                        let Some(url) = image_message
+                                            .get("image_url")
+                                            .and_then(|x| x.as_ref().right())
+                                            .and_then(|x| x.get("url"))
+                                        else {
+                                            return Err(PyValueError::new_err("Expected content of format {{`type`: `image_url`, `image_url`: {{`url`: ...}}}}".to_string()));
+                                        };
+                                        let mut content_image_map = IndexMap::new();
+                                        content_image_map
+                                            .insert("type".to_string(), "image".to_string());
+                                        content_map.push(content_image_map);
+                                        image_urls.push(url.clone());
                                     }
-                                    let content = image_messages[text_idx]["text"]
-                                        .as_ref()
-                                        .unwrap_left()
-                                        .clone();
-                                    if image_messages[url_idx]["image_url"].is_left()
-                                        || !image_messages[url_idx]["image_url"]
-                                            .as_ref()
-                                            .unwrap_right()
-                                            .contains_key("url")
-                                    {
-                                        return Err(PyValueError::new_err("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}".to_string()));
+                                    other => {
+                                        return Err(PyValueError::new_err(format!(
+                                            "Expected content of type `text` or `image_url`, got `{other}`."
+                                        )));
                                     }
-                                    let url = image_messages[url_idx]["image_url"]
-                                        .as_ref()
-                                        .unwrap_right()["url"]
-                                        .clone();
-                                    Ok((content, url))
                                 }
-                                let mut message_map: IndexMap<
-                                    String,
-                                    Either<String, Vec<IndexMap<String, String>>>,
-                                > = IndexMap::new();
-                                message_map.insert(
-                                    "role".to_string(),
-                                    Either::Left(message["role"].as_ref().left().unwrap().clone()),
-                                );
-                                let (content, url) = if items[0] == "text" {
-                                    get_content_and_url(0, 1, image_messages)?
-                                } else {
-                                    get_content_and_url(1, 0, image_messages)?
-                                };
-
-                                let mut content_map = Vec::new();
-                                let mut content_image_map = IndexMap::new();
-                                content_image_map.insert("type".to_string(), "image".to_string());
-                                content_map.push(content_image_map);
-                                let mut content_text_map = IndexMap::new();
-                                content_text_map.insert("type".to_string(), "text".to_string());
-                                content_text_map.insert("text".to_string(), content);
-                                content_map.push(content_text_map);
-
-                                message_map
-                                    .insert("content".to_string(), Either::Right(content_map));
-                                messages_vec.push(message_map);
-                                image_urls.push(url);
                             }
-                        }
-                    }
-                    if !image_urls.is_empty() {
-                        let mut images = Vec::new();
-                        for url in image_urls {
-                            let bytes = if url.contains("http") {
-                                // Read from http
-                                match reqwest::blocking::get(url.clone()) {
-                                    Ok(http_resp) => http_resp
-                                        .bytes()
-                                        .map_err(|e| PyValueError::new_err(e.to_string()))?
-                                        .to_vec(),
-                                    Err(e) => return Err(PyValueError::new_err(format!("{e}"))),
-                                }
-                            } else if let Ok(mut f) = File::open(&url) {
-                                // Read from local file
-                                let metadata = fs::metadata(&url)
-                                    .map_err(|e| PyValueError::new_err(e.to_string()))?;
-                                let mut buffer = vec![0; metadata.len() as usize];
-                                f.read_exact(&mut buffer)?;
-                                buffer
-                            } else {
-                                // Decode with base64
-                                general_purpose::STANDARD
-                                    .decode(url)
-                                    .map_err(|e| PyValueError::new_err(e.to_string()))?
-                            };
-                            images.push(
-                                image::load_from_memory(&bytes)
-                                    .map_err(|e| PyValueError::new_err(e.to_string()))?,
+
+                            let mut message_map: IndexMap<
+                                String,
+                                Either<String, Vec<IndexMap<String, String>>>,
+                            > = IndexMap::new();
+                            message_map.insert(
+                                "role".to_string(),
+                                Either::Left(message["role"].as_ref().left().unwrap().clone()),
                             );
+                            message_map.insert("content".to_string(), Either::Right(content_map));
+                            messages_vec.push(message_map);
                         }
-                        RequestMessage::VisionChat {
-                            messages: messages_vec,
-                            images,
-                        }
-                    } else {
-                        RequestMessage::Chat(messages_vec)
                     }
                 }
-                Either::Right(ref prompt) => {
-                    let mut messages = Vec::new();
-                    let mut message_map: IndexMap<
-                        String,
-                        Either<String, Vec<IndexMap<String, String>>>,
-                    > = IndexMap::new();
-                    message_map.insert("role".to_string(), Either::Left("user".to_string()));
-                    message_map.insert("content".to_string(), Either::Left(prompt.to_string()));
-                    messages.push(message_map);
-                    RequestMessage::Chat(messages)
+                if !image_urls.is_empty() {
+                    let mut images = Vec::new();
+                    for url in image_urls {
+                        let bytes = if url.starts_with("data:") {
+                            decode_image_data_url(&url)?
+                        } else if url.contains("http") {
+                            // Read from http
+                            match reqwest::blocking::get(url.clone()) {
+                                Ok(http_resp) => http_resp
+                                    .bytes()
+                                    .map_err(|e| PyValueError::new_err(e.to_string()))?
+                                    .to_vec(),
+                                Err(e) => return Err(PyValueError::new_err(format!("{e}"))),
+                            }
+                        } else if let Ok(mut f) = File::open(&url) {
+                            // Read from local file
+                            let metadata = fs::metadata(&url)
+                                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+                            let mut buffer = vec![0; metadata.len() as usize];
+                            f.read_exact(&mut buffer)?;
+                            buffer
+                        } else {
+                            // Decode with base64
+                            general_purpose::STANDARD
+                                .decode(url)
+                                .map_err(|e| PyValueError::new_err(e.to_string()))?
+                        };
+                        images.push(
+                            load_image(&bytes).map_err(|e| PyValueError::new_err(e.to_string()))?,
+                        );
+                    }
+                    RequestMessage::VisionChat {
+                        messages: messages_vec,
+                        images,
+                    }
+                } else {
+                    RequestMessage::Chat(messages_vec)
                 }
-            };
+            }
+            Either::Right(ref prompt) => {
+                let mut messages = Vec::new();
+                let mut message_map: IndexMap<
+                    String,
+                    Either<String, Vec<IndexMap<String, String>>>,
+                > = IndexMap::new();
+                message_map.insert("role".to_string(), Either::Left("user".to_string()));
+                message_map.insert("content".to_string(), Either::Left(prompt.to_string()));
+                messages.push(message_map);
+                RequestMessage::Chat(messages)
+            }
+        };
 
-            let tool_choice = request.tool_choice.as_ref().map(|x| match x {
-                ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
-                ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
-            });
-
-            let tools = if let Some(tools) = &request.tool_schemas {
-                let mut new_tools = Vec::new();
-                for schema in tools {
-                    new_tools.push(
-                        serde_json::from_str::<Tool>(schema)
-                            .map_err(|e| PyValueError::new_err(e.to_string()))?,
-                    );
-                }
-                Some(new_tools)
-            } else {
-                None
-            };
+        let tool_choice = request
+            .tool_choice
+            .as_ref()
+            .map(mistralrs_core::ToolChoice::from);
 
-            let model_request = _Request::Normal(NormalRequest {
-                id: {
-                    let l = NEXT_REQUEST_ID.lock().unwrap();
-                    let last = &mut *l.borrow_mut();
-                    let last_v = *last;
-                    *last += 1;
-                    last_v
-                },
-                messages,
-                sampling_params: SamplingParams {
-                    temperature: request.temperature,
-                    top_k: request.top_k,
-                    top_p: request.top_p,
-                    top_n_logprobs: request.top_logprobs.unwrap_or(1),
-                    frequency_penalty: request.frequency_penalty,
-                    presence_penalty: request.presence_penalty,
-                    max_len: request.max_tokens,
-                    stop_toks,
-                    logits_bias: request.logit_bias.clone(),
-                    n_choices: request.n_choices,
-                    min_p: request.min_p,
-                },
-                response: tx,
-                return_logprobs: request.logprobs,
-                is_streaming: request.stream,
-                constraint,
-                suffix: None,
-                adapters: request.adapters.clone(),
-                tool_choice,
-                tools,
-                logits_processors: None,
-            });
+        let tools = if let Some(tools) = &request.tool_schemas {
+            let mut new_tools = Vec::new();
+            for schema in tools {
+                new_tools.push(
+                    serde_json::from_str::<Tool>(schema)
+                        .map_err(|e| PyValueError::new_err(e.to_string()))?,
+                );
+            }
+            Some(new_tools)
+        } else {
+            None
+        };
 
-            MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
-            let sender = self.runner.get_sender()?;
-            sender.blocking_send(model_request).unwrap();
+        let mut sampling_params = request.sampling_params();
+        if let Some(logit_bias_strings) = &request.logit_bias_strings {
+            merge_logit_bias_strings(
+                sampling_params.logits_bias.get_or_insert_with(HashMap::new),
+                logit_bias_strings,
+                |text| {
+                    self.runner
+                        .tokenize(text)
+                        .map_err(|e| PyValueError::new_err(e.to_string()))
+                },
+            )?;
+        }
 
-            if request.stream {
-                Ok(Either::Right(ChatCompletionStreamer::from_rx(rx)))
-            } else {
-                let response = rx.blocking_recv().unwrap();
+        let id = {
+            let l = NEXT_REQUEST_ID.lock().unwrap();
+            let last = &mut *l.borrow_mut();
+            let last_v = *last;
+            *last += 1;
+            last_v
+        };
+        let model_request = _Request::Normal(NormalRequest {
+            id,
+            messages,
+            sampling_params,
+            response: tx,
+            return_logprobs: request.logprobs,
+            is_streaming: request.stream,
+            constraint,
+            suffix: None,
+            adapters: request.adapters.clone(),
+            tool_choice,
+            tools,
+            logits_processors: build_logits_processors(&request.logits_processors),
+            early_exit_layer: None,
+            xlora_global_scaling: request.xlora_global_scaling,
+            include_usage: request.stream_options_include_usage,
+            token_healing: request.token_healing,
+            truncate_prompt: request.truncate_prompt,
+            add_generation_prompt: request.add_generation_prompt,
+        });
 
-                match response {
-                    Response::ValidationError(e) | Response::InternalError(e) => {
-                        Err(PyValueError::new_err(e.to_string()))
-                    }
-                    Response::Done(response) => Ok(Either::Left(response)),
-                    Response::ModelError(msg, _) => Err(PyValueError::new_err(msg.to_string())),
-                    Response::Chunk(_) => unreachable!(),
-                    Response::CompletionDone(_) => unreachable!(),
-                    Response::CompletionModelError(_, _) => unreachable!(),
-                    Response::CompletionChunk(_) => unreachable!(),
-                }
-            }
-        })
+        Ok((id, model_request, rx))
     }
 
-    /// Send an OpenAI API compatible request, returning the result.
-    fn send_completion_request(
-        &mut self,
-        request: Py<CompletionRequest>,
-    ) -> PyResult<CompletionResponse> {
-        let (tx, mut rx) = channel(10_000);
-        Python::with_gil(|py| {
-            let request = request.bind(py).borrow();
-            let stop_toks = request
-                .stop_seqs
-                .as_ref()
-                .map(|x| StopTokens::Seqs(x.to_vec()));
-            let constraint = if request.grammar_type == Some("regex".to_string()) {
-                if request.grammar.is_none() {
-                    return Err(PyValueError::new_err(
-                        "Grammar type is specified but not grammar text",
-                    ));
-                }
-                Constraint::Regex(request.grammar.as_ref().unwrap().clone())
-            } else if request.grammar_type == Some("yacc".to_string()) {
-                if request.grammar.is_none() {
-                    return Err(PyValueError::new_err(
-                        "Grammar type is specified but not grammar text",
-                    ));
-                }
-                Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
-            } else if request.grammar_type.is_some() {
+    /// Build the engine request for an OpenAI API compatible completion request, returning its id and
+    /// the receiver of its responses.
+    fn completion_request(
+        &self,
+        request: &CompletionRequest,
+    ) -> PyResult<(usize, NormalRequest, Receiver<Response>)> {
+        let (tx, rx) = channel(10_000);
+        let constraint = if request.grammar_type == Some("regex".to_string()) {
+            if request.grammar.is_none() {
                 return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but not grammar text",
                 ));
-            } else {
-                Constraint::None
-            };
+            }
+            Constraint::Regex(request.grammar.as_ref().unwrap().clone())
+        } else if request.grammar_type == Some("yacc".to_string()) {
+            if request.grammar.is_none() {
+                return Err(PyValueError::new_err(
+                    "Grammar type is specified but not grammar text",
+                ));
+            }
+            Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+        } else if request.grammar_type == Some("json_schema".to_string()) {
+            if request.grammar.is_none() {
+                return Err(PyValueError::new_err(
+                    "Grammar type is specified but not grammar text",
+                ));
+            }
+            Constraint::JsonSchema(request.grammar.as_ref().unwrap().clone())
+        } else if request.grammar_type.is_some() {
+            return Err(PyValueError::new_err(
+                "Grammar type is specified but is not `regex`, `yacc` or `json_schema`",
+            ));
+        } else {
+            Constraint::None
+        };
 
-            let tool_choice = request.tool_choice.as_ref().map(|x| match x {
-                ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
-                ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
-            });
-
-            let tools = if let Some(tools) = &request.tool_schemas {
-                let mut new_tools = Vec::new();
-                for schema in tools {
-                    new_tools.push(
-                        serde_json::from_str::<Tool>(schema)
-                            .map_err(|e| PyValueError::new_err(e.to_string()))?,
-                    );
-                }
-                Some(new_tools)
-            } else {
-                None
-            };
+        let tool_choice = request
+            .tool_choice
+            .as_ref()
+            .map(mistralrs_core::ToolChoice::from);
 
-            let model_request = _Request::Normal(NormalRequest {
-                id: {
-                    let l = NEXT_REQUEST_ID.lock().unwrap();
-                    let last = &mut *l.borrow_mut();
-                    let last_v = *last;
-                    *last += 1;
-                    last_v
-                },
-                messages: RequestMessage::Completion {
-                    text: request.prompt.clone(),
-                    echo_prompt: request.echo_prompt,
-                    best_of: request.best_of,
-                },
-                sampling_params: SamplingParams {
-                    temperature: request.temperature,
-                    top_k: request.top_k,
-                    top_p: request.top_p,
-                    top_n_logprobs: 1,
-                    frequency_penalty: request.frequency_penalty,
-                    presence_penalty: request.presence_penalty,
-                    max_len: request.max_tokens,
-                    stop_toks,
-                    logits_bias: request.logit_bias.clone(),
-                    n_choices: request.n_choices,
-                    min_p: request.min_p,
-                },
-                response: tx,
-                return_logprobs: false,
-                is_streaming: false,
-                constraint,
-                suffix: request.suffix.clone(),
-                adapters: request.adapters.clone(),
-                tool_choice,
-                tools,
-                logits_processors: None,
-            });
+        let tools = if let Some(tools) = &request.tool_schemas {
+            let mut new_tools = Vec::new();
+            for schema in tools {
+                new_tools.push(
+                    serde_json::from_str::<Tool>(schema)
+                        .map_err(|e| PyValueError::new_err(e.to_string()))?,
+                );
+            }
+            Some(new_tools)
+        } else {
+            None
+        };
 
-            MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
-            let sender = self.runner.get_sender()?;
-            sender.blocking_send(model_request).unwrap();
-            let response = rx.blocking_recv().unwrap();
+        let id = {
+            let l = NEXT_REQUEST_ID.lock().unwrap();
+            let last = &mut *l.borrow_mut();
+            let last_v = *last;
+            *last += 1;
+            last_v
+        };
+        let model_request = NormalRequest {
+            id,
+            messages: request.request_message(),
+            sampling_params: request.sampling_params(),
+            response: tx,
+            return_logprobs: request.logprobs.is_some(),
+            is_streaming: request.stream,
+            constraint,
+            // With fill in the middle, the suffix is part of the prompt.
+            suffix: request.suffix.clone().filter(|_| !request.fill_in_middle),
+            adapters: request.adapters.clone(),
+            tool_choice,
+            tools,
+            logits_processors: build_logits_processors(&request.logits_processors),
+            early_exit_layer: None,
+            xlora_global_scaling: request.xlora_global_scaling,
+            include_usage: false,
+            token_healing: request.token_healing,
+            truncate_prompt: request.truncate_prompt,
+            add_generation_prompt: true,
+        };
 
-            match response {
-                Response::ValidationError(e) | Response::InternalError(e) => {
-                    Err(PyValueError::new_err(e.to_string()))
-                }
-                Response::CompletionDone(response) => Ok(response),
-                Response::CompletionModelError(msg, _) => {
-                    Err(PyValueError::new_err(msg.to_string()))
-                }
-                Response::Chunk(_) => unreachable!(),
-                Response::Done(_) => unreachable!(),
-                Response::ModelError(_, _) => unreachable!(),
-                Response::CompletionChunk(_) => unreachable!(),
-            }
-        })
+        Ok((id, model_request, rx))
     }
+}
 
-    /// Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML
-    /// then nothing will happen.
-    fn send_re_isq(&self, dtype: String) -> PyResult<()> {
-        let request = _Request::ReIsq(
-            parse_isq_value(&dtype).map_err(|e| PyValueError::new_err(e.to_string()))?,
-        );
-        self.runner.get_sender()?.blocking_send(request).unwrap();
-        Ok(())
+/// Decode the image of a `data:<mime>;base64,<data>` URL, checking that the image format of its
+/// MIME type is supported.
+fn decode_image_data_url(url: &str) -> PyResult<Vec<u8>> {
+    let (header, data) = url
+        .strip_prefix("data:")
+        .and_then(|url| url.split_once(','))
+        .ok_or_else(|| {
+            PyValueError::new_err("Data URLs must have the format `data:<mime>;base64,<data>`.")
+        })?;
+    let Some(mime) = header.strip_suffix(";base64") else {
+        return Err(PyValueError::new_err(format!(
+            "Only base64 encoded data URLs are supported, got `data:{header},...`."
+        )));
+    };
+    if !ImageFormat::from_mime_type(mime).is_some_and(|format| format.reading_enabled()) {
+        return Err(PyValueError::new_err(format!(
+            "Unsupported image type `{mime}` in data URL."
+        )));
     }
+    general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| PyValueError::new_err(format!("Invalid base64 in data URL: {e}")))
+}
 
-    /// Send a request to make the specified adapters the active adapters for the model.
-    fn activate_adapters(&self, adapter_names: Vec<String>) {
-        let request = _Request::ActivateAdapters(adapter_names);
-        self.runner
-            .get_sender()
-            .unwrap()
+/// Send `request` to the engine and wait for its response with the GIL released, so that other
+/// Python threads, including those sending their own requests, run in the meantime. The engine may
+/// also need the GIL to call back into Python.
+fn send_and_wait(
+    py: Python<'_>,
+    sender: &Sender<_Request>,
+    request: _Request,
+    rx: &mut Receiver<Response>,
+) -> PyResult<Response> {
+    py.allow_threads(|| {
+        sender
             .blocking_send(request)
-            .unwrap();
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        rx.blocking_recv()
+            .ok_or_else(|| PyValueError::new_err("Engine did not respond to the request."))
+    })
+}
+
+/// Send all `requests` to the engine, then wait for the response of each, in order. The engine
+/// schedules the requests together while the responses are awaited.
+fn send_all_and_wait(
+    sender: &Sender<_Request>,
+    requests: Vec<(_Request, Receiver<Response>)>,
+) -> PyResult<Vec<Response>> {
+    let mut receivers = Vec::with_capacity(requests.len());
+    for (request, rx) in requests {
+        sender
+            .blocking_send(request)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        receivers.push(rx);
+    }
+    receivers
+        .iter_mut()
+        .map(|rx| {
+            rx.blocking_recv()
+                .ok_or_else(|| PyValueError::new_err("Engine did not respond to the request."))
+        })
+        .collect()
+}
+
+/// The response of a non-streaming chat completion request, or its error message.
+fn chat_completion_result(response: Response) -> Result<ChatCompletionResponse, String> {
+    match response {
+        Response::ValidationError(e) | Response::InternalError(e) => Err(e.to_string()),
+        Response::Done(response) => Ok(response),
+        Response::ModelError(msg, _) => Err(msg.to_string()),
+        Response::Chunk(_) => unreachable!(),
+        Response::CompletionDone(_) => unreachable!(),
+        Response::CompletionModelError(_, _) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
+        Response::Tokenize(_) => unreachable!(),
+        Response::Detokenize(_) => unreachable!(),
+    }
+}
+
+fn completion_result(response: Response) -> Result<CompletionResponse, String> {
+    match response {
+        Response::ValidationError(e) | Response::InternalError(e) => Err(e.to_string()),
+        Response::CompletionDone(response) => Ok(response),
+        Response::CompletionModelError(msg, _) => Err(msg.to_string()),
+        Response::Chunk(_) => unreachable!(),
+        Response::Done(_) => unreachable!(),
+        Response::Tokenize(_) => unreachable!(),
+        Response::Detokenize(_) => unreachable!(),
+        Response::ModelError(_, _) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
     }
 }
 
@@ -964,6 +1576,8 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<AnyMoeConfig>()?;
     m.add_class::<AnyMoeExpertType>()?;
     m.add_class::<ToolChoice>()?;
+    m.add_class::<EmbeddingPooling>()?;
+    m.add_class::<TemperatureOrder>()?;
 
     m.add_class::<mistralrs_core::ResponseMessage>()?;
     m.add_class::<mistralrs_core::Delta>()?;
@@ -976,6 +1590,158 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
     m.add_class::<mistralrs_core::CompletionResponse>()?;
+    m.add_class::<mistralrs_core::CompletionChunkChoice>()?;
+    m.add_class::<mistralrs_core::CompletionChunkResponse>()?;
     m.add_class::<mistralrs_core::TopLogprob>()?;
+    m.add_class::<mistralrs_core::SamplingParamsUsed>()?;
+    m.add_class::<mistralrs_core::AdaptersResponse>()?;
+    m.add_class::<mistralrs_core::EmbeddingResponse>()?;
+    m.add_class::<mistralrs_core::RawForwardResponse>()?;
+    m.add_class::<mistralrs_core::BenchStats>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use mistralrs_core::{
+        Constraint, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    };
+    use pyo3::Python;
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use super::{decode_image_data_url, get_device, send_all_and_wait, send_and_wait};
+
+    const PNG_1X1: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==";
+
+    fn completion_request(id: usize) -> (Request, Receiver<Response>) {
+        let (tx, rx) = channel(1);
+        let request = Request::Normal(NormalRequest {
+            id,
+            messages: RequestMessage::Completion {
+                text: format!("Prompt {id}"),
+                echo_prompt: false,
+                best_of: 1,
+            },
+            sampling_params: SamplingParams::default(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            constraint: Constraint::None,
+            suffix: None,
+            adapters: None,
+            tools: None,
+            tool_choice: None,
+            logits_processors: None,
+            early_exit_layer: None,
+            xlora_global_scaling: None,
+            include_usage: false,
+            token_healing: false,
+            truncate_prompt: false,
+            add_generation_prompt: true,
+        });
+        (request, rx)
+    }
+
+    #[test]
+    fn image_data_url() {
+        let bytes = decode_image_data_url(&format!("data:image/png;base64,{PNG_1X1}")).unwrap();
+        let image = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((image.width(), image.height()), (1, 1));
+
+        assert!(decode_image_data_url(&format!("data:image/svg+xml;base64,{PNG_1X1}")).is_err());
+        assert!(decode_image_data_url(&format!("data:image/png,{PNG_1X1}")).is_err());
+        assert!(decode_image_data_url("data:image/png;base64").is_err());
+        assert!(decode_image_data_url("data:image/png;base64,not base64!").is_err());
+    }
+
+    #[test]
+    fn batch_responses_align_with_requests() {
+        let (sender, mut engine_rx) = channel(16);
+        // The engine answers the requests of the batch in reverse order.
+        let engine = thread::spawn(move || {
+            let mut requests = Vec::new();
+            while let Some(Request::Normal(request)) = engine_rx.blocking_recv() {
+                requests.push(request);
+                if requests.len() == 8 {
+                    break;
+                }
+            }
+            for request in requests.into_iter().rev() {
+                let id = request.id;
+                request
+                    .response
+                    .blocking_send(Response::ValidationError(format!("{id}").into()))
+                    .unwrap();
+            }
+        });
+
+        let requests = (0..8).map(completion_request).collect();
+        let responses = send_all_and_wait(&sender, requests).unwrap();
+        engine.join().unwrap();
+
+        assert_eq!(responses.len(), 8);
+        for (id, response) in responses.into_iter().enumerate() {
+            let Response::ValidationError(e) = response else {
+                panic!("Expected the response of request {id}.");
+            };
+            assert_eq!(e.to_string(), id.to_string());
+        }
+    }
+
+    #[test]
+    fn waiting_threads_release_the_gil() {
+        pyo3::prepare_freethreaded_python();
+        let (sender, mut engine_rx) = channel(16);
+        // The engine only answers once both requests arrived, so the first thread must release the
+        // GIL while it waits for the second thread to send its request.
+        let engine = thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let mut requests = Vec::new();
+            while requests.len() < 2 && Instant::now() < deadline {
+                match engine_rx.try_recv() {
+                    Ok(Request::Normal(request)) => requests.push(request),
+                    _ => thread::sleep(Duration::from_millis(1)),
+                }
+            }
+            let received = requests.len();
+            for request in requests {
+                request
+                    .response
+                    .blocking_send(Response::ValidationError(received.to_string().into()))
+                    .unwrap();
+            }
+        });
+
+        let clients = (0..2)
+            .map(|id| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    let (request, mut rx) = completion_request(id);
+                    Python::with_gil(|py| send_and_wait(py, &sender, request, &mut rx))
+                })
+            })
+            .collect::<Vec<_>>();
+        for client in clients {
+            let response = client.join().unwrap();
+            assert!(
+                matches!(&response, Ok(Response::ValidationError(e)) if e.to_string() == "2"),
+                "The requests of the threads did not overlap."
+            );
+        }
+        engine.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "cuda", feature = "metal")))]
+    fn device_ordinal_out_of_range() {
+        let err = get_device(Some(1)).unwrap_err();
+        assert!(err.to_string().contains("only 1 device(s) are visible"));
+        assert!(get_device(Some(0)).unwrap().is_cpu());
+        assert!(get_device(None).unwrap().is_cpu());
+    }
+}
@@ -1,18 +1,66 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use either::Either;
+use mistralrs_core::{
+    BadWordsLogitsProcessor, Constraint, CustomLogitsProcessor, Function, GreedyTieBreak,
+    NoRepeatNGramLogitsProcessor, RequestMessage, SamplingParams, StopTokens, Tool, ToolType,
+};
 use pyo3::{
-    exceptions::PyTypeError,
+    exceptions::{PyTypeError, PyValueError},
     pyclass, pymethods,
-    types::{PyAnyMethods, PyList, PyString},
-    Py, PyAny, PyErr, PyResult, Python,
+    types::{PyAnyMethods, PyDict, PyDictMethods, PyList, PyString},
+    Bound, Py, PyAny, PyErr, PyResult, Python,
 };
 
-#[pyclass(eq, eq_int)]
+#[pyclass(eq)]
 #[derive(PartialEq, Debug, Clone)]
 pub enum ToolChoice {
-    NoTools,
-    Auto,
+    NoTools(),
+    Auto(),
+    /// Force a call to the tool with this name.
+    Tool(String),
+}
+
+impl From<&ToolChoice> for mistralrs_core::ToolChoice {
+    fn from(choice: &ToolChoice) -> Self {
+        match choice {
+            ToolChoice::NoTools() => Self::None,
+            ToolChoice::Auto() => Self::Auto,
+            ToolChoice::Tool(name) => Self::Tool(Tool {
+                tp: ToolType::Function,
+                function: Function {
+                    description: None,
+                    name: name.clone(),
+                    parameters: None,
+                },
+            }),
+        }
+    }
+}
+
+#[pyclass(eq, eq_int)]
+#[derive(PartialEq, Debug, Clone)]
+pub enum EmbeddingPooling {
+    LastToken,
+    MeanPooling,
+    Cls,
+}
+
+#[pyclass(eq, eq_int)]
+#[derive(PartialEq, Debug, Clone, Copy)]
+/// When the temperature is applied relative to the top-k, typical-p, top-p and min-p truncation.
+pub enum TemperatureOrder {
+    BeforeTruncation,
+    AfterTruncation,
+}
+
+impl From<TemperatureOrder> for mistralrs_core::TemperatureOrder {
+    fn from(order: TemperatureOrder) -> Self {
+        match order {
+            TemperatureOrder::BeforeTruncation => Self::BeforeTruncation,
+            TemperatureOrder::AfterTruncation => Self::AfterTruncation,
+        }
+    }
 }
 
 #[pyclass]
@@ -21,7 +69,7 @@ pub enum ToolChoice {
 pub struct CompletionRequest {
     pub(crate) _model: String,
     pub(crate) prompt: String,
-    pub(crate) best_of: usize,
+    pub(crate) best_of: Option<usize>,
     pub(crate) echo_prompt: bool,
     pub(crate) presence_penalty: Option<f32>,
     pub(crate) frequency_penalty: Option<f32>,
@@ -37,8 +85,30 @@ pub struct CompletionRequest {
     pub(crate) grammar_type: Option<String>,
     pub(crate) adapters: Option<Vec<String>>,
     pub(crate) min_p: Option<f64>,
+    pub(crate) typical_p: Option<f32>,
+    pub(crate) dry_multiplier: Option<f32>,
+    pub(crate) dry_base: f32,
+    pub(crate) dry_allowed_length: usize,
+    pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) repetition_penalty_range: usize,
     pub(crate) tool_schemas: Option<Vec<String>>,
     pub(crate) tool_choice: Option<ToolChoice>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) min_tokens: Option<usize>,
+    pub(crate) logprobs: Option<usize>,
+    pub(crate) token_healing: bool,
+    pub(crate) truncate_prompt: bool,
+    pub(crate) stop_token_ids: Option<Vec<u32>>,
+    pub(crate) stop_token_strings: Option<Vec<String>>,
+    pub(crate) stream: bool,
+    pub(crate) fill_in_middle: bool,
+    pub(crate) prompt_tokens: Option<Vec<u32>>,
+    pub(crate) xlora_global_scaling: Option<f64>,
+    pub(crate) stop_token_seqs: Option<Vec<Vec<u32>>>,
+    pub(crate) logits_processors: Option<Vec<NamedLogitsProcessor>>,
+    pub(crate) suppress_special_tokens: bool,
+    pub(crate) temperature_order: TemperatureOrder,
 }
 
 #[pymethods]
@@ -47,7 +117,7 @@ impl CompletionRequest {
     #[pyo3(signature = (
         prompt,
         model,
-        best_of = 1,
+        best_of = None,
         echo_prompt = false,
         presence_penalty=None,
         frequency_penalty=None,
@@ -63,13 +133,35 @@ impl CompletionRequest {
         grammar_type = None,
         adapters = None,
         min_p=None,
+        typical_p=None,
+        dry_multiplier=None,
+        dry_base=1.75,
+        dry_allowed_length=2,
+        dry_sequence_breakers=None,
+        repetition_penalty=None,
+        repetition_penalty_range=0,
         tool_schemas=None,
         tool_choice=None,
+        seed=None,
+        min_tokens=None,
+        logprobs=None,
+        token_healing=false,
+        truncate_prompt=false,
+        stop_token_ids=None,
+        stop_token_strings=None,
+        stream=false,
+        fill_in_middle=false,
+        prompt_tokens=None,
+        xlora_global_scaling=None,
+        stop_token_seqs=None,
+        logits_processors=None,
+        suppress_special_tokens=false,
+        temperature_order=TemperatureOrder::BeforeTruncation,
     ))]
     fn new(
         prompt: String,
         model: String,
-        best_of: usize,
+        best_of: Option<usize>,
         echo_prompt: bool,
         presence_penalty: Option<f32>,
         frequency_penalty: Option<f32>,
@@ -85,9 +177,41 @@ impl CompletionRequest {
         grammar_type: Option<String>,
         adapters: Option<Vec<String>>,
         min_p: Option<f64>,
+        typical_p: Option<f32>,
+        dry_multiplier: Option<f32>,
+        dry_base: f32,
+        dry_allowed_length: usize,
+        dry_sequence_breakers: Option<Vec<String>>,
+        repetition_penalty: Option<f32>,
+        repetition_penalty_range: usize,
         tool_schemas: Option<Vec<String>>,
         tool_choice: Option<ToolChoice>,
+        seed: Option<u64>,
+        min_tokens: Option<usize>,
+        logprobs: Option<usize>,
+        token_healing: bool,
+        truncate_prompt: bool,
+        stop_token_ids: Option<Vec<u32>>,
+        stop_token_strings: Option<Vec<String>>,
+        stream: bool,
+        fill_in_middle: bool,
+        prompt_tokens: Option<Vec<u32>>,
+        xlora_global_scaling: Option<f64>,
+        stop_token_seqs: Option<Vec<Vec<u32>>>,
+        logits_processors: Option<Vec<Bound<'_, PyDict>>>,
+        suppress_special_tokens: bool,
+        temperature_order: TemperatureOrder,
     ) -> PyResult<Self> {
+        if fill_in_middle && (echo_prompt || best_of.is_some_and(|best_of| best_of != n_choices)) {
+            return Err(PyValueError::new_err(
+                "`echo_prompt` and `best_of` are not supported with `fill_in_middle`.",
+            ));
+        }
+        if fill_in_middle && prompt_tokens.is_some() {
+            return Err(PyValueError::new_err(
+                "`prompt_tokens` cannot be combined with `fill_in_middle`.",
+            ));
+        }
         Ok(Self {
             prompt,
             best_of,
@@ -107,12 +231,103 @@ impl CompletionRequest {
             grammar_type,
             adapters,
             min_p,
+            typical_p,
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
+            dry_sequence_breakers,
+            repetition_penalty,
+            repetition_penalty_range,
             tool_schemas,
             tool_choice,
+            seed,
+            min_tokens,
+            logprobs,
+            token_healing,
+            truncate_prompt,
+            stop_token_ids,
+            stop_token_strings,
+            stream,
+            fill_in_middle,
+            prompt_tokens,
+            xlora_global_scaling,
+            stop_token_seqs,
+            logits_processors: logits_processors
+                .map(|processors| {
+                    processors
+                        .iter()
+                        .map(NamedLogitsProcessor::from_dict)
+                        .collect::<PyResult<Vec<_>>>()
+                })
+                .transpose()?,
+            suppress_special_tokens,
+            temperature_order,
         })
     }
 }
 
+impl CompletionRequest {
+    /// The prompt of this request: `prompt_tokens` if given, which are not tokenized again,
+    /// otherwise `prompt`.
+    pub(crate) fn request_message(&self) -> RequestMessage {
+        if let Some(prompt_tokens) = &self.prompt_tokens {
+            RequestMessage::CompletionTokens(prompt_tokens.clone())
+        } else if self.fill_in_middle {
+            RequestMessage::FillInMiddle {
+                prefix: self.prompt.clone(),
+                suffix: self.suffix.clone().unwrap_or_default(),
+            }
+        } else {
+            RequestMessage::Completion {
+                text: self.prompt.clone(),
+                echo_prompt: self.echo_prompt,
+                best_of: self.best_of.unwrap_or(self.n_choices),
+            }
+        }
+    }
+
+    /// The sampling parameters of this request. These are built exactly like those of a
+    /// [`ChatCompletionRequest`], with `logprobs` as the number of top logprobs.
+    pub(crate) fn sampling_params(&self) -> SamplingParams {
+        SamplingParams {
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            top_n_logprobs: self.logprobs.unwrap_or(1),
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            repetition_penalty: self.repetition_penalty,
+            repetition_penalty_range: self.repetition_penalty_range,
+            max_len: self.max_tokens,
+            stop_toks: self
+                .stop_seqs
+                .as_ref()
+                .map(|x| StopTokens::Seqs(x.to_vec())),
+            stop_token_ids: self.stop_token_ids.clone(),
+            stop_token_strings: self.stop_token_strings.clone(),
+            stop_token_seqs: self.stop_token_seqs.clone(),
+            logits_bias: self.logit_bias.clone(),
+            n_choices: self.n_choices,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            dry_multiplier: self.dry_multiplier,
+            dry_base: self.dry_base,
+            dry_allowed_length: self.dry_allowed_length,
+            dry_sequence_breakers: self
+                .dry_sequence_breakers
+                .clone()
+                .unwrap_or_else(|| SamplingParams::default().dry_sequence_breakers),
+            mirostat_tau: None,
+            mirostat_eta: 0.1,
+            temperature_order: self.temperature_order.into(),
+            greedy_tie_break_by: GreedyTieBreak::default(),
+            seed: self.seed,
+            min_len: self.min_tokens,
+            suppress_special_tokens: self.suppress_special_tokens,
+        }
+    }
+}
+
 #[pyclass]
 #[derive(Debug)]
 /// An OpenAI API compatible chat completion request.
@@ -144,8 +359,33 @@ pub struct ChatCompletionRequest {
     pub(crate) grammar_type: Option<String>,
     pub(crate) adapters: Option<Vec<String>>,
     pub(crate) min_p: Option<f64>,
+    pub(crate) typical_p: Option<f32>,
+    pub(crate) dry_multiplier: Option<f32>,
+    pub(crate) dry_base: f32,
+    pub(crate) dry_allowed_length: usize,
+    pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) repetition_penalty_range: usize,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: f32,
     pub(crate) tool_schemas: Option<Vec<String>>,
     pub(crate) tool_choice: Option<ToolChoice>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) min_tokens: Option<usize>,
+    pub(crate) logit_bias_strings: Option<HashMap<String, f32>>,
+    pub(crate) response_format: Option<String>,
+    pub(crate) stream_options_include_usage: bool,
+    pub(crate) token_healing: bool,
+    pub(crate) truncate_prompt: bool,
+    pub(crate) stop_token_ids: Option<Vec<u32>>,
+    pub(crate) stop_token_strings: Option<Vec<String>>,
+    pub(crate) xlora_global_scaling: Option<f64>,
+    pub(crate) stop_token_seqs: Option<Vec<Vec<u32>>>,
+    pub(crate) logits_processors: Option<Vec<NamedLogitsProcessor>>,
+    pub(crate) add_generation_prompt: bool,
+    pub(crate) suppress_special_tokens: bool,
+    pub(crate) temperature_order: TemperatureOrder,
+    pub(crate) stream_interval_ms: Option<u64>,
 }
 
 #[pymethods]
@@ -170,8 +410,33 @@ impl ChatCompletionRequest {
         grammar_type = None,
         adapters = None,
         min_p=None,
+        typical_p=None,
+        dry_multiplier=None,
+        dry_base=1.75,
+        dry_allowed_length=2,
+        dry_sequence_breakers=None,
+        repetition_penalty=None,
+        repetition_penalty_range=0,
+        mirostat_tau=None,
+        mirostat_eta=0.1,
         tool_schemas=None,
         tool_choice=None,
+        seed=None,
+        min_tokens=None,
+        logit_bias_strings=None,
+        response_format=None,
+        stream_options_include_usage=false,
+        token_healing=false,
+        truncate_prompt=false,
+        stop_token_ids=None,
+        stop_token_strings=None,
+        xlora_global_scaling=None,
+        stop_token_seqs=None,
+        logits_processors=None,
+        add_generation_prompt=true,
+        suppress_special_tokens=false,
+        temperature_order=TemperatureOrder::BeforeTruncation,
+        stream_interval_ms=None,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -192,8 +457,33 @@ impl ChatCompletionRequest {
         grammar_type: Option<String>,
         adapters: Option<Vec<String>>,
         min_p: Option<f64>,
+        typical_p: Option<f32>,
+        dry_multiplier: Option<f32>,
+        dry_base: f32,
+        dry_allowed_length: usize,
+        dry_sequence_breakers: Option<Vec<String>>,
+        repetition_penalty: Option<f32>,
+        repetition_penalty_range: usize,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: f32,
         tool_schemas: Option<Vec<String>>,
         tool_choice: Option<ToolChoice>,
+        seed: Option<u64>,
+        min_tokens: Option<usize>,
+        logit_bias_strings: Option<HashMap<String, f32>>,
+        response_format: Option<String>,
+        stream_options_include_usage: bool,
+        token_healing: bool,
+        truncate_prompt: bool,
+        stop_token_ids: Option<Vec<u32>>,
+        stop_token_strings: Option<Vec<String>>,
+        xlora_global_scaling: Option<f64>,
+        stop_token_seqs: Option<Vec<Vec<u32>>>,
+        logits_processors: Option<Vec<Bound<'_, PyDict>>>,
+        add_generation_prompt: bool,
+        suppress_special_tokens: bool,
+        temperature_order: TemperatureOrder,
+        stream_interval_ms: Option<u64>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -262,8 +552,360 @@ impl ChatCompletionRequest {
             grammar_type,
             adapters,
             min_p,
+            typical_p,
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
+            dry_sequence_breakers,
+            repetition_penalty,
+            repetition_penalty_range,
+            mirostat_tau,
+            mirostat_eta,
             tool_choice,
             tool_schemas,
+            seed,
+            min_tokens,
+            logit_bias_strings,
+            response_format,
+            stream_options_include_usage,
+            token_healing,
+            truncate_prompt,
+            stop_token_ids,
+            stop_token_strings,
+            xlora_global_scaling,
+            stop_token_seqs,
+            logits_processors: logits_processors
+                .map(|processors| {
+                    processors
+                        .iter()
+                        .map(NamedLogitsProcessor::from_dict)
+                        .collect::<PyResult<Vec<_>>>()
+                })
+                .transpose()?,
+            add_generation_prompt,
+            suppress_special_tokens,
+            temperature_order,
+            stream_interval_ms,
         })
     }
 }
+
+impl ChatCompletionRequest {
+    /// The sampling parameters of this request.
+    pub(crate) fn sampling_params(&self) -> SamplingParams {
+        SamplingParams {
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            top_n_logprobs: self.top_logprobs.unwrap_or(1),
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            repetition_penalty: self.repetition_penalty,
+            repetition_penalty_range: self.repetition_penalty_range,
+            max_len: self.max_tokens,
+            stop_toks: self
+                .stop_seqs
+                .as_ref()
+                .map(|x| StopTokens::Seqs(x.to_vec())),
+            stop_token_ids: self.stop_token_ids.clone(),
+            stop_token_strings: self.stop_token_strings.clone(),
+            stop_token_seqs: self.stop_token_seqs.clone(),
+            logits_bias: self.logit_bias.clone(),
+            n_choices: self.n_choices,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            dry_multiplier: self.dry_multiplier,
+            dry_base: self.dry_base,
+            dry_allowed_length: self.dry_allowed_length,
+            dry_sequence_breakers: self
+                .dry_sequence_breakers
+                .clone()
+                .unwrap_or_else(|| SamplingParams::default().dry_sequence_breakers),
+            mirostat_tau: self.mirostat_tau,
+            mirostat_eta: self.mirostat_eta,
+            temperature_order: self.temperature_order.into(),
+            greedy_tie_break_by: GreedyTieBreak::default(),
+            seed: self.seed,
+            min_len: self.min_tokens,
+            suppress_special_tokens: self.suppress_special_tokens,
+        }
+    }
+
+    /// The constraint from `grammar_type` and `grammar`, or from `response_format`.
+    pub(crate) fn constraint(&self) -> PyResult<Constraint> {
+        match self.response_format.as_deref() {
+            None | Some("text") => {}
+            Some("json_object") if self.grammar_type.is_some() => {
+                return Err(PyValueError::new_err(
+                    "`response_format` `json_object` cannot be combined with `grammar_type`",
+                ));
+            }
+            // The empty schema matches any JSON value.
+            Some("json_object") => return Ok(Constraint::JsonSchema("{}".to_string())),
+            Some(other) => {
+                return Err(PyValueError::new_err(format!(
+                    "Response format `{other}` is not `text` or `json_object`"
+                )));
+            }
+        }
+        let grammar = || {
+            self.grammar.clone().ok_or_else(|| {
+                PyValueError::new_err("Grammar type is specified but not grammar text")
+            })
+        };
+        match self.grammar_type.as_deref() {
+            Some("regex") => Ok(Constraint::Regex(grammar()?)),
+            Some("yacc") => Ok(Constraint::Yacc(grammar()?)),
+            Some("json_schema") => Ok(Constraint::JsonSchema(grammar()?)),
+            Some(_) => Err(PyValueError::new_err(
+                "Grammar type is specified but is not `regex`, `yacc` or `json_schema`",
+            )),
+            None => Ok(Constraint::None),
+        }
+    }
+}
+
+/// A built-in logits processor, given in the `logits_processors` of a request as a dict with its `name`
+/// and parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum NamedLogitsProcessor {
+    /// `{"name": "no_repeat_ngram", "ngram_size": int}`
+    NoRepeatNGram { ngram_size: usize },
+    /// `{"name": "bad_words", "bad_words_ids": list[list[int]]}`
+    BadWords { bad_words_ids: Vec<Vec<u32>> },
+}
+
+impl NamedLogitsProcessor {
+    fn from_dict(dict: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let param = |key: &str| {
+            dict.get_item(key)?.ok_or_else(|| {
+                PyValueError::new_err(format!("Logits processor is missing `{key}`."))
+            })
+        };
+        let name = param("name")?.extract::<String>()?;
+        match name.as_str() {
+            "no_repeat_ngram" => Ok(Self::NoRepeatNGram {
+                ngram_size: param("ngram_size")?.extract()?,
+            }),
+            "bad_words" => Ok(Self::BadWords {
+                bad_words_ids: param("bad_words_ids")?.extract()?,
+            }),
+            other => Err(PyValueError::new_err(format!(
+                "Unknown logits processor `{other}`, expected `no_repeat_ngram` or `bad_words`."
+            ))),
+        }
+    }
+
+    fn build(&self) -> Arc<dyn CustomLogitsProcessor> {
+        match self {
+            Self::NoRepeatNGram { ngram_size } => {
+                Arc::new(NoRepeatNGramLogitsProcessor::new(*ngram_size))
+            }
+            Self::BadWords { bad_words_ids } => {
+                Arc::new(BadWordsLogitsProcessor::new(bad_words_ids.clone()))
+            }
+        }
+    }
+}
+
+/// The core logits processors of the named `processors` of a request.
+pub(crate) fn build_logits_processors(
+    processors: &Option<Vec<NamedLogitsProcessor>>,
+) -> Option<Vec<Arc<dyn CustomLogitsProcessor>>> {
+    processors
+        .as_ref()
+        .map(|processors| processors.iter().map(NamedLogitsProcessor::build).collect())
+}
+
+/// Add the bias of each string in `logit_bias_strings` to every distinct token which `tokenize` splits
+/// it into. Biases for the same token, from several strings or from `logit_bias`, are summed.
+pub(crate) fn merge_logit_bias_strings(
+    logit_bias: &mut HashMap<u32, f32>,
+    logit_bias_strings: &HashMap<String, f32>,
+    tokenize: impl Fn(&str) -> PyResult<Vec<u32>>,
+) -> PyResult<()> {
+    for (text, bias) in logit_bias_strings {
+        let mut toks = tokenize(text)?;
+        toks.sort_unstable();
+        toks.dedup();
+        for tok in toks {
+            *logit_bias.entry(tok).or_default() += bias;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use either::Either;
+    use mistralrs_core::{Constraint, RequestMessage};
+
+    use super::{
+        merge_logit_bias_strings, ChatCompletionRequest, CompletionRequest, TemperatureOrder,
+    };
+
+    fn chat_request(
+        logit_bias: Option<HashMap<u32, f32>>,
+        stop_seqs: Option<Vec<String>>,
+    ) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            messages: Either::Right("Hello".to_string()),
+            _model: "default".to_string(),
+            logit_bias,
+            logprobs: false,
+            top_logprobs: None,
+            max_tokens: Some(64),
+            n_choices: 2,
+            presence_penalty: Some(0.5),
+            frequency_penalty: Some(0.25),
+            stop_seqs,
+            temperature: Some(0.7),
+            top_p: Some(0.9),
+            stream: false,
+            top_k: Some(40),
+            grammar: None,
+            grammar_type: None,
+            adapters: None,
+            min_p: Some(0.05),
+            typical_p: Some(0.95),
+            dry_multiplier: Some(0.8),
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_sequence_breakers: None,
+            repetition_penalty: Some(1.1),
+            repetition_penalty_range: 64,
+            mirostat_tau: None,
+            mirostat_eta: 0.1,
+            tool_schemas: None,
+            tool_choice: None,
+            seed: Some(42),
+            min_tokens: Some(8),
+            logit_bias_strings: None,
+            response_format: None,
+            stream_options_include_usage: false,
+            token_healing: false,
+            truncate_prompt: false,
+            stop_token_ids: None,
+            stop_token_strings: None,
+            stop_token_seqs: None,
+            logits_processors: None,
+            xlora_global_scaling: None,
+            add_generation_prompt: true,
+            suppress_special_tokens: false,
+            temperature_order: TemperatureOrder::AfterTruncation,
+            stream_interval_ms: None,
+        }
+    }
+
+    fn completion_request(
+        logit_bias: Option<HashMap<u32, f32>>,
+        stop_seqs: Option<Vec<String>>,
+    ) -> CompletionRequest {
+        CompletionRequest {
+            _model: "default".to_string(),
+            prompt: "Hello".to_string(),
+            best_of: None,
+            echo_prompt: false,
+            presence_penalty: Some(0.5),
+            frequency_penalty: Some(0.25),
+            logit_bias,
+            max_tokens: Some(64),
+            n_choices: 2,
+            stop_seqs,
+            temperature: Some(0.7),
+            top_p: Some(0.9),
+            suffix: None,
+            top_k: Some(40),
+            grammar: None,
+            grammar_type: None,
+            adapters: None,
+            min_p: Some(0.05),
+            typical_p: Some(0.95),
+            dry_multiplier: Some(0.8),
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_sequence_breakers: None,
+            repetition_penalty: Some(1.1),
+            repetition_penalty_range: 64,
+            tool_schemas: None,
+            tool_choice: None,
+            seed: Some(42),
+            min_tokens: Some(8),
+            logprobs: None,
+            token_healing: false,
+            truncate_prompt: false,
+            stop_token_ids: None,
+            stop_token_strings: None,
+            stop_token_seqs: None,
+            logits_processors: None,
+            stream: false,
+            fill_in_middle: false,
+            prompt_tokens: None,
+            xlora_global_scaling: None,
+            suppress_special_tokens: false,
+            temperature_order: TemperatureOrder::AfterTruncation,
+        }
+    }
+
+    #[test]
+    fn completion_and_chat_sampling_params_match() {
+        let logit_bias = Some(HashMap::from([(42, -1.5)]));
+        let stop_seqs = Some(vec!["\n\n".to_string()]);
+        let completion = completion_request(logit_bias.clone(), stop_seqs.clone());
+        let chat = chat_request(logit_bias, stop_seqs);
+
+        assert_eq!(
+            format!("{:?}", completion.sampling_params()),
+            format!("{:?}", chat.sampling_params())
+        );
+    }
+
+    #[test]
+    fn prompt_tokens_take_precedence() {
+        let mut completion = completion_request(None, None);
+        assert!(matches!(
+            completion.request_message(),
+            RequestMessage::Completion { text, best_of: 2, .. } if text == "Hello"
+        ));
+        completion.prompt_tokens = Some(vec![1, 15043]);
+        assert!(matches!(
+            completion.request_message(),
+            RequestMessage::CompletionTokens(toks) if toks == [1, 15043]
+        ));
+    }
+
+    #[test]
+    fn logit_bias_strings_are_summed() {
+        let tokenize = |text: &str| {
+            Ok(match text {
+                "the" => vec![1],
+                " the the" => vec![2, 1, 2, 1],
+                _ => vec![3],
+            })
+        };
+        let mut logit_bias = HashMap::from([(1, 0.5)]);
+        let strings = HashMap::from([("the".to_string(), -1.0), (" the the".to_string(), -2.0)]);
+        merge_logit_bias_strings(&mut logit_bias, &strings, tokenize).unwrap();
+
+        assert_eq!(logit_bias, HashMap::from([(1, -2.5), (2, -2.0)]));
+    }
+
+    #[test]
+    fn json_object_response_format() {
+        let mut chat = chat_request(None, None);
+        chat.response_format = Some("json_object".to_string());
+        assert!(matches!(chat.constraint(), Ok(Constraint::JsonSchema(schema)) if schema == "{}"));
+
+        chat.grammar_type = Some("regex".to_string());
+        chat.grammar = Some("a+".to_string());
+        assert!(chat.constraint().is_err());
+
+        chat.response_format = Some("text".to_string());
+        assert!(matches!(chat.constraint(), Ok(Constraint::Regex(rx)) if rx == "a+"));
+
+        chat.response_format = Some("xml".to_string());
+        assert!(chat.constraint().is_err());
+    }
+}
@@ -1,17 +1,145 @@
-use tokio::sync::mpsc::Receiver;
+use std::time::Duration;
 
-use mistralrs_core::{ChatCompletionChunkResponse, Response};
-use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyRef, PyRefMut, PyResult};
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::{Receiver, Sender},
+    time::{timeout_at, Instant},
+};
+
+use mistralrs_core::{
+    ChatCompletionChunkResponse, ChatCompletionResponse, CompletionChunkResponse,
+    CompletionResponse, Request, Response,
+};
+use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyErr, PyRef, PyRefMut, PyResult};
 
 #[pyclass]
+/// Iterator over the chunks of a streamed chat completion.
+///
+/// If the request fails, the iterator raises a `ValueError`. For an error of the model after some
+/// chunks were generated, the message also contains the content generated so far by each choice.
+/// The iterator is exhausted after an error.
+///
+/// Pass `request_id` to `Runner.cancel_request` to stop the generation: the iterator then yields
+/// the final chunk, with the `canceled` finish reason, and stops. If the iterator is dropped before
+/// the stream ended, such as when the consumer stops iterating, the request is canceled as well so
+/// that the engine stops generating and frees its cache.
+///
+/// If the request set `stream_options_include_usage`, the last chunk has no choices and carries the
+/// token usage of the request.
+///
+/// If the request set `stream_interval_ms`, the chunks received within the interval after a chunk
+/// are coalesced into it. An error received meanwhile is raised after the coalesced chunk.
 pub struct ChatCompletionStreamer {
     rx: Receiver<Response>,
     is_done: bool,
+    include_usage: bool,
+    #[pyo3(get)]
+    request_id: usize,
+    engine: Sender<Request>,
+    /// The interval to coalesce chunks in, with the runtime to wait for them.
+    stream_interval: Option<(Duration, Runtime)>,
+    pending_error: Option<PyErr>,
 }
 
 impl ChatCompletionStreamer {
-    pub fn from_rx(rx: Receiver<Response>) -> Self {
-        Self { rx, is_done: false }
+    pub fn from_rx(
+        rx: Receiver<Response>,
+        request_id: usize,
+        include_usage: bool,
+        engine: Sender<Request>,
+        stream_interval: Option<Duration>,
+    ) -> Self {
+        let stream_interval = stream_interval.map(|interval| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .expect("Failed to build the runtime of the stream");
+            (interval, runtime)
+        });
+        Self {
+            rx,
+            is_done: false,
+            include_usage,
+            request_id,
+            engine,
+            stream_interval,
+            pending_error: None,
+        }
+    }
+
+    /// Wait for the next chunk, then coalesce the chunks which follow it within the stream interval.
+    fn recv(&mut self) -> Option<PyResult<ChatCompletionChunkResponse>> {
+        if let Some(e) = self.pending_error.take() {
+            return Some(Err(e));
+        }
+        if self.is_done {
+            return None;
+        }
+        let resp = self.rx.blocking_recv();
+        let mut chunk = match self.handle(resp) {
+            Ok(chunk) => chunk,
+            Err(e) => return Some(Err(e)),
+        };
+        if let Some((interval, runtime)) = &self.stream_interval {
+            let deadline = Instant::now() + *interval;
+            while !self.is_done {
+                let Ok(resp) = runtime.block_on(timeout_at(deadline, self.rx.recv())) else {
+                    break;
+                };
+                match self.handle(resp) {
+                    Ok(next) => chunk.append(next),
+                    Err(e) => {
+                        self.pending_error = Some(e);
+                        break;
+                    }
+                }
+            }
+        }
+        Some(Ok(chunk))
+    }
+
+    /// Turn a response of the engine into a chunk or an error, and record whether the stream ended.
+    fn handle(&mut self, resp: Option<Response>) -> PyResult<ChatCompletionChunkResponse> {
+        match resp {
+            Some(resp) => match resp {
+                Response::ModelError(msg, partial) => {
+                    self.is_done = true;
+                    Err(PyValueError::new_err(model_error_message(&msg, &partial)))
+                }
+                Response::ValidationError(e) | Response::InternalError(e) => {
+                    self.is_done = true;
+                    Err(PyValueError::new_err(e.to_string()))
+                }
+                Response::Chunk(response) => {
+                    if is_last_chunk(&response, self.include_usage) {
+                        self.is_done = true;
+                    }
+                    Ok(response)
+                }
+                Response::Done(_) => unreachable!(),
+                Response::CompletionDone(_) => unreachable!(),
+                Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
+                Response::Tokenize(_) => unreachable!(),
+                Response::Detokenize(_) => unreachable!(),
+            },
+            None => {
+                self.is_done = true;
+                Err(PyValueError::new_err(
+                    "Received none in ChatCompletionStreamer".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+impl Drop for ChatCompletionStreamer {
+    fn drop(&mut self) {
+        if !self.is_done {
+            // Dropping may happen with the GIL held, so do not wait for the engine. If the engine
+            // is gone, there is nothing left to cancel.
+            let _ = self.engine.try_send(Request::Terminate(self.request_id));
+        }
     }
 }
 
@@ -21,28 +149,297 @@ impl ChatCompletionStreamer {
         this
     }
     fn __next__(mut this: PyRefMut<'_, Self>) -> Option<PyResult<ChatCompletionChunkResponse>> {
-        if this.is_done {
+        let py = this.py();
+        let this = &mut *this;
+        // Release the GIL so the engine can call back into Python.
+        py.allow_threads(|| this.recv())
+    }
+}
+
+#[pyclass]
+/// Iterator over the chunks of a streamed completion.
+///
+/// If the request fails, the iterator raises a `ValueError`. For an error of the model after some
+/// chunks were generated, the message also contains the text generated so far by each choice.
+/// The iterator is exhausted after an error.
+pub struct CompletionStreamer {
+    rx: Receiver<Response>,
+    is_done: bool,
+    #[pyo3(get)]
+    request_id: usize,
+}
+
+impl CompletionStreamer {
+    pub fn from_rx(rx: Receiver<Response>, request_id: usize) -> Self {
+        Self {
+            rx,
+            is_done: false,
+            request_id,
+        }
+    }
+
+    /// Wait for the next chunk. The stream ends after the chunk in which every choice finished.
+    fn recv(&mut self) -> Option<PyResult<CompletionChunkResponse>> {
+        if self.is_done {
             return None;
         }
-        match this.rx.blocking_recv() {
+        match self.rx.blocking_recv() {
             Some(resp) => match resp {
-                Response::ModelError(msg, _) => Some(Err(PyValueError::new_err(msg.to_string()))),
-                Response::ValidationError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
-                Response::InternalError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
-                Response::Chunk(response) => {
+                Response::CompletionModelError(msg, partial) => {
+                    self.is_done = true;
+                    Some(Err(PyValueError::new_err(completion_model_error_message(
+                        &msg, &partial,
+                    ))))
+                }
+                Response::ValidationError(e) | Response::InternalError(e) => {
+                    self.is_done = true;
+                    Some(Err(PyValueError::new_err(e.to_string())))
+                }
+                Response::CompletionChunk(response) => {
                     if response.choices.iter().all(|x| x.finish_reason.is_some()) {
-                        this.is_done = true;
+                        self.is_done = true;
                     }
                     Some(Ok(response))
                 }
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
-                Response::CompletionModelError(_, _) => unreachable!(),
-                Response::CompletionChunk(_) => unreachable!(),
+                Response::ModelError(_, _) => unreachable!(),
+                Response::Chunk(_) => unreachable!(),
+                Response::Tokenize(_) => unreachable!(),
+                Response::Detokenize(_) => unreachable!(),
             },
-            None => Some(Err(PyValueError::new_err(
-                "Received none in ChatCompletionStreamer".to_string(),
-            ))),
+            None => {
+                self.is_done = true;
+                Some(Err(PyValueError::new_err(
+                    "Received none in CompletionStreamer".to_string(),
+                )))
+            }
         }
     }
 }
+
+#[pymethods]
+impl CompletionStreamer {
+    fn __iter__(this: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        this
+    }
+    fn __next__(mut this: PyRefMut<'_, Self>) -> Option<PyResult<CompletionChunkResponse>> {
+        let py = this.py();
+        let this = &mut *this;
+        // Release the GIL so the engine can call back into Python.
+        py.allow_threads(|| this.recv())
+    }
+}
+
+/// Whether `chunk` ends the stream: the usage chunk if it was requested, otherwise the chunk in which
+/// every choice finished.
+fn is_last_chunk(chunk: &ChatCompletionChunkResponse, include_usage: bool) -> bool {
+    if include_usage {
+        chunk.usage.is_some()
+    } else {
+        chunk.choices.iter().all(|x| x.finish_reason.is_some())
+    }
+}
+
+/// Describe a model error which interrupted a stream, with the content generated before it.
+fn model_error_message(msg: &str, partial: &ChatCompletionResponse) -> String {
+    let mut message = format!("Model failed during streaming: {msg}");
+    for choice in &partial.choices {
+        let content = choice.message.content.as_deref().unwrap_or_default();
+        message.push_str(&format!(
+            "\nPartial content of choice {}: {content:?}",
+            choice.index
+        ));
+    }
+    message
+}
+
+/// Describe a model error which interrupted a completion stream, with the text generated before it.
+fn completion_model_error_message(msg: &str, partial: &CompletionResponse) -> String {
+    let mut message = format!("Model failed during streaming: {msg}");
+    for choice in &partial.choices {
+        message.push_str(&format!(
+            "\nPartial text of choice {}: {:?}",
+            choice.index, choice.text
+        ));
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::{
+        ChatCompletionChunkResponse, ChunkChoice, CompletionChunkChoice, CompletionChunkResponse,
+        Delta, Request, Response, Usage,
+    };
+    use std::time::{Duration, Instant};
+
+    use tokio::sync::mpsc::channel;
+
+    use super::{is_last_chunk, ChatCompletionStreamer, CompletionStreamer};
+
+    fn chunk(finish_reason: Option<&str>, usage: Option<Usage>) -> ChatCompletionChunkResponse {
+        let choices = if usage.is_some() {
+            Vec::new()
+        } else {
+            vec![ChunkChoice {
+                delta: Delta {
+                    content: "Hi".to_string(),
+                    role: "assistant".to_string(),
+                    tool_calls: Vec::new(),
+                },
+                index: 0,
+                finish_reason: finish_reason.map(ToString::to_string),
+                logprobs: None,
+                matched_stop: None,
+            }]
+        };
+        ChatCompletionChunkResponse {
+            id: "0".to_string(),
+            choices,
+            created: 0,
+            model: "default".to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "chat.completion.chunk".to_string(),
+            usage,
+        }
+    }
+
+    #[test]
+    fn usage_chunk_is_last() {
+        let usage = Usage {
+            completion_tokens: 2,
+            prompt_tokens: 3,
+            total_tokens: 5,
+            avg_tok_per_sec: 0.,
+            avg_prompt_tok_per_sec: 0.,
+            avg_compl_tok_per_sec: 0.,
+            total_time_sec: 0.,
+            total_prompt_time_sec: 0.,
+            total_completion_time_sec: 0.,
+            prompt_time_ms: 0.,
+            completion_time_ms: 0.,
+            total_time_ms: 0.,
+        };
+
+        assert!(!is_last_chunk(&chunk(None, None), false));
+        assert!(is_last_chunk(&chunk(Some("stop"), None), false));
+
+        assert!(!is_last_chunk(&chunk(Some("stop"), None), true));
+        assert!(is_last_chunk(&chunk(None, Some(usage)), true));
+    }
+
+    #[test]
+    fn completion_chunks_reassemble_text() {
+        let (tx, rx) = channel(16);
+        for (text, finish_reason) in [("Hel", None), ("lo", None), (" world", Some("stop"))] {
+            tx.try_send(Response::CompletionChunk(CompletionChunkResponse {
+                id: "0".to_string(),
+                choices: vec![CompletionChunkChoice {
+                    text: text.to_string(),
+                    index: 0,
+                    logprobs: None,
+                    finish_reason: finish_reason.map(ToString::to_string),
+                    matched_stop: None,
+                    echo: false,
+                }],
+                created: 0,
+                model: "default".to_string(),
+                system_fingerprint: "local".to_string(),
+                object: "text_completion".to_string(),
+            }))
+            .unwrap();
+        }
+
+        // The stream ends after the finished chunk, although the sender is still open.
+        let mut streamer = CompletionStreamer::from_rx(rx, 0);
+        let mut text = String::new();
+        while let Some(chunk) = streamer.recv() {
+            text.push_str(&chunk.unwrap().choices[0].text);
+        }
+        assert_eq!(text, "Hello world");
+        drop(tx);
+    }
+
+    #[test]
+    fn dropping_unfinished_stream_cancels_request() {
+        let (engine, mut requests) = channel(16);
+
+        // The consumer stopped iterating before the stream ended.
+        let (_tx, rx) = channel(16);
+        drop(ChatCompletionStreamer::from_rx(
+            rx,
+            3,
+            false,
+            engine.clone(),
+            None,
+        ));
+        assert!(matches!(requests.try_recv(), Ok(Request::Terminate(3))));
+
+        // A stream which ended has nothing to cancel.
+        let (_tx, rx) = channel(16);
+        let mut streamer = ChatCompletionStreamer::from_rx(rx, 4, false, engine, None);
+        streamer.is_done = true;
+        drop(streamer);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn chunks_within_interval_are_coalesced() {
+        let (engine, _requests) = channel(16);
+        let (tx, rx) = channel(16);
+        for finish_reason in [None, None, Some("stop")] {
+            tx.try_send(Response::Chunk(chunk(finish_reason, None)))
+                .unwrap();
+        }
+
+        // The stream ends with the finished chunk without waiting for the interval.
+        let start = Instant::now();
+        let mut streamer =
+            ChatCompletionStreamer::from_rx(rx, 0, false, engine, Some(Duration::from_secs(60)));
+        let coalesced = streamer.recv().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(coalesced.choices.len(), 1);
+        assert_eq!(coalesced.choices[0].delta.content, "HiHiHi");
+        assert_eq!(coalesced.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert!(streamer.recv().is_none());
+        drop(tx);
+    }
+
+    #[test]
+    fn buffered_chunk_is_flushed_after_interval() {
+        let (engine, _requests) = channel(16);
+        let (tx, rx) = channel(16);
+        tx.try_send(Response::Chunk(chunk(None, None))).unwrap();
+        tx.try_send(Response::Chunk(chunk(None, None))).unwrap();
+
+        // No more chunks arrive, so the buffered ones are yielded once the interval elapsed.
+        let mut streamer =
+            ChatCompletionStreamer::from_rx(rx, 0, false, engine, Some(Duration::from_millis(10)));
+        let coalesced = streamer.recv().unwrap().unwrap();
+        assert_eq!(coalesced.choices[0].delta.content, "HiHi");
+        assert!(coalesced.choices[0].finish_reason.is_none());
+
+        tx.try_send(Response::Chunk(chunk(Some("stop"), None)))
+            .unwrap();
+        let last = streamer.recv().unwrap().unwrap();
+        assert_eq!(last.choices[0].delta.content, "Hi");
+        assert!(streamer.recv().is_none());
+    }
+
+    #[test]
+    fn error_is_raised_after_coalesced_chunk() {
+        let (engine, _requests) = channel(16);
+        let (tx, rx) = channel(16);
+        tx.try_send(Response::Chunk(chunk(None, None))).unwrap();
+        tx.try_send(Response::InternalError("boom".into())).unwrap();
+
+        let mut streamer =
+            ChatCompletionStreamer::from_rx(rx, 0, false, engine, Some(Duration::from_secs(60)));
+        let coalesced = streamer.recv().unwrap().unwrap();
+        assert_eq!(coalesced.choices[0].delta.content, "Hi");
+        assert!(streamer.recv().unwrap().is_err());
+        assert!(streamer.recv().is_none());
+        drop(tx);
+    }
+}
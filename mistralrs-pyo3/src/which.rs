@@ -1,6 +1,8 @@
+use std::{fs::File, path::Path};
+
 use either::Either;
-use mistralrs_core::{NormalLoaderType, VisionLoaderType};
-use pyo3::pyclass;
+use mistralrs_core::{NormalLoaderType, Ordering, VisionLoaderType};
+use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyResult};
 
 #[pyclass(eq, eq_int)]
 #[derive(Debug, Clone, PartialEq)]
@@ -14,6 +16,8 @@ pub enum Architecture {
     Qwen2,
     Gemma2,
     Starcoder2,
+    Qwen2Moe,
+    Cohere,
 }
 
 impl From<Architecture> for NormalLoaderType {
@@ -28,6 +32,8 @@ impl From<Architecture> for NormalLoaderType {
             Architecture::Qwen2 => Self::Qwen2,
             Architecture::Gemma2 => Self::Gemma2,
             Architecture::Starcoder2 => Self::Starcoder2,
+            Architecture::Qwen2Moe => Self::Qwen2Moe,
+            Architecture::Cohere => Self::Cohere,
         }
     }
 }
@@ -209,11 +215,164 @@ pub enum Which {
         arch,
         tokenizer_json = None,
         topology = None,
+        max_image_edge = None,
     ))]
     VisionPlain {
         model_id: String,
         arch: VisionArchitecture,
         tokenizer_json: Option<String>,
         topology: Option<String>,
+        max_image_edge: Option<u32>,
     },
 }
+
+/// Load the adapter ordering file of an X-LoRA or LoRA model.
+pub(crate) fn load_ordering(order: &str) -> PyResult<Ordering> {
+    let file = File::open(order).map_err(|e| {
+        PyValueError::new_err(format!("Could not load ordering file at {order}: {e}"))
+    })?;
+    serde_json::from_reader(file)
+        .map_err(|e| PyValueError::new_err(format!("Invalid ordering file at {order}: {e}")))
+}
+
+fn check_id(name: &str, value: &str) -> PyResult<()> {
+    if value.trim().is_empty() {
+        return Err(PyValueError::new_err(format!(
+            "`{name}` must not be empty."
+        )));
+    }
+    Ok(())
+}
+
+fn check_file(name: &str, path: &Option<String>) -> PyResult<()> {
+    match path {
+        Some(path) if !Path::new(path).is_file() => Err(PyValueError::new_err(format!(
+            "`{name}` file {path} does not exist."
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn check_gguf_filenames(filenames: &Either<String, Vec<String>>) -> PyResult<()> {
+    match filenames {
+        Either::Left(filename) => check_id("quantized_filename", filename),
+        Either::Right(filenames) if filenames.is_empty() => Err(PyValueError::new_err(
+            "`quantized_filename` must contain at least one file.",
+        )),
+        Either::Right(filenames) => filenames
+            .iter()
+            .try_for_each(|f| check_id("quantized_filename", f)),
+    }
+}
+
+#[pymethods]
+impl Which {
+    /// Check that the fields required by this variant are set and that the local files it refers
+    /// to can be read. This does not access the network, so it fails before any download.
+    pub fn validate(&self) -> PyResult<()> {
+        match self {
+            Which::Plain {
+                model_id,
+                tokenizer_json,
+                topology,
+                ..
+            }
+            | Which::VisionPlain {
+                model_id,
+                tokenizer_json,
+                topology,
+                ..
+            } => {
+                check_id("model_id", model_id)?;
+                check_file("tokenizer_json", tokenizer_json)?;
+                check_file("topology", topology)?;
+            }
+            Which::XLora {
+                xlora_model_id: adapter_id,
+                order,
+                model_id,
+                tokenizer_json,
+                topology,
+                ..
+            }
+            | Which::Lora {
+                adapters_model_id: adapter_id,
+                order,
+                model_id,
+                tokenizer_json,
+                topology,
+                ..
+            } => {
+                check_id("adapter model id", adapter_id)?;
+                if let Some(model_id) = model_id {
+                    check_id("model_id", model_id)?;
+                }
+                check_file("tokenizer_json", tokenizer_json)?;
+                check_file("topology", topology)?;
+                load_ordering(order)?;
+            }
+            Which::GGUF {
+                quantized_model_id,
+                quantized_filename,
+                ..
+            } => {
+                check_id("quantized_model_id", quantized_model_id)?;
+                check_gguf_filenames(quantized_filename)?;
+            }
+            Which::XLoraGGUF {
+                quantized_model_id,
+                quantized_filename,
+                xlora_model_id: adapter_id,
+                order,
+                ..
+            }
+            | Which::LoraGGUF {
+                quantized_model_id,
+                quantized_filename,
+                adapters_model_id: adapter_id,
+                order,
+                ..
+            } => {
+                check_id("quantized_model_id", quantized_model_id)?;
+                check_gguf_filenames(quantized_filename)?;
+                check_id("adapter model id", adapter_id)?;
+                load_ordering(order)?;
+            }
+            Which::GGML {
+                quantized_model_id,
+                quantized_filename,
+                tok_model_id,
+                tokenizer_json,
+                ..
+            } => {
+                check_id("quantized_model_id", quantized_model_id)?;
+                check_id("quantized_filename", quantized_filename)?;
+                check_id("tok_model_id", tok_model_id)?;
+                check_file("tokenizer_json", tokenizer_json)?;
+            }
+            Which::XLoraGGML {
+                quantized_model_id,
+                quantized_filename,
+                xlora_model_id: adapter_id,
+                order,
+                tokenizer_json,
+                ..
+            }
+            | Which::LoraGGML {
+                quantized_model_id,
+                quantized_filename,
+                adapters_model_id: adapter_id,
+                order,
+                tokenizer_json,
+                ..
+            } => {
+                check_id("quantized_model_id", quantized_model_id)?;
+                check_id("quantized_filename", quantized_filename)?;
+                check_id("adapter model id", adapter_id)?;
+                check_file("tokenizer_json", tokenizer_json)?;
+                load_ordering(order)?;
+            }
+        }
+        Ok(())
+    }
+}
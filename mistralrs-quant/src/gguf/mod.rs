@@ -9,7 +9,7 @@ use candle_core::{
 };
 use candle_nn::Module;
 
-use crate::{generate_isq, IsqType, QuantMethod, QuantMethodConfig};
+use crate::{generate_isq, Int8Layer, IsqType, QuantMethod, QuantMethodConfig};
 
 #[derive(Debug)]
 pub struct GgufMatMul {
@@ -29,7 +29,8 @@ impl QuantMethod for GgufMatMul {
             }),
             QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Unquantized(_)
-            | QuantMethodConfig::Hqq { .. } => unreachable!(),
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Int8 { .. } => unreachable!(),
         }
     }
 
@@ -100,14 +101,25 @@ impl QuantMethod for GgufMatMul {
         dtype: Option<IsqType>,
         device: Device,
         n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
     ) -> Result<Arc<dyn QuantMethod>> {
         if let Some(dtype) = dtype {
             let t = match &self.w {
                 QMatMul::QTensor(q) => q.dequantize(&q.device())?,
                 QMatMul::TensorF16(t) | QMatMul::Tensor(t) => t.clone(),
             };
+            if dtype == IsqType::Q8Ch {
+                n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let res = Int8Layer::quantize(&t, &device)?;
+                return if let Some(ref b) = self.b {
+                    let b = b.to_device(&device)?.to_dtype(res.dtype_and_device().0)?;
+                    Ok(Arc::new(res.with_bias(b)))
+                } else {
+                    Ok(Arc::new(res))
+                };
+            }
             let dtype = dtype.try_into()?;
-            let res = generate_isq!(t, device, dtype, n_quantized);
+            let res = generate_isq!(t, device, dtype, n_quantized, imatrix_weight);
             Ok(Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
                 q_weight: res,
                 b: self.b.clone(),
@@ -25,7 +25,8 @@ impl QuantMethod for GptqLayer {
             } => candle_core::bail!("GPTQ is only supported on CUDA."),
             QuantMethodConfig::Gguf { .. }
             | QuantMethodConfig::Unquantized(_)
-            | QuantMethodConfig::Hqq { .. } => {
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Int8 { .. } => {
                 unreachable!()
             }
         }
@@ -56,6 +57,7 @@ impl QuantMethod for GptqLayer {
         _dtype: Option<IsqType>,
         _device: Device,
         _n_quantized: &AtomicUsize,
+        _imatrix_weight: Option<Vec<f32>>,
     ) -> Result<Arc<dyn QuantMethod>> {
         todo!()
     }
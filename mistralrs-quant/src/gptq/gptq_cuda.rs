@@ -239,7 +239,8 @@ impl QuantMethod for GptqLayer {
             }
             QuantMethodConfig::Gguf { .. }
             | QuantMethodConfig::Unquantized(_)
-            | QuantMethodConfig::Hqq { .. } => {
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Int8 { .. } => {
                 unreachable!()
             }
         }
@@ -287,6 +288,7 @@ impl QuantMethod for GptqLayer {
         _dtype: Option<IsqType>,
         _device: Device,
         _n_quantized: &AtomicUsize,
+        _imatrix_weight: Option<Vec<f32>>,
     ) -> Result<Arc<dyn QuantMethod>> {
         candle_core::bail!("GPTQ quantization does not support ISQ.")
     }
@@ -496,7 +496,8 @@ impl QuantMethod for HqqLayer {
         match method {
             QuantMethodConfig::Gguf { .. }
             | QuantMethodConfig::Unquantized(_)
-            | QuantMethodConfig::Gptq { .. } => {
+            | QuantMethodConfig::Gptq { .. }
+            | QuantMethodConfig::Int8 { .. } => {
                 unreachable!()
             }
             QuantMethodConfig::Hqq {
@@ -559,6 +560,7 @@ impl QuantMethod for HqqLayer {
         dtype: Option<IsqType>,
         device: Device,
         n_quantized: &AtomicUsize,
+        _imatrix_weight: Option<Vec<f32>>,
     ) -> Result<Arc<dyn QuantMethod>> {
         n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         let bits = match dtype {
@@ -0,0 +1,142 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use candle_core::Result;
+
+/// Importance matrix (imatrix) data in the llama.cpp format.
+///
+/// The file is a sequence of little-endian records:
+/// - `i32`: number of entries
+/// - for each entry:
+///     - `i32` name length, followed by the UTF-8 tensor name (e.g. `blk.0.attn_q.weight`)
+///     - `i32` number of calls (chunks) the statistics were accumulated over
+///     - `i32` number of values, followed by that many `f32` values
+///
+/// Each entry holds the accumulated squared activations for every input channel of the tensor.
+/// These are normalized by the number of calls on load, so that [`ImatrixData::get`] returns the
+/// mean importance per input channel. Any trailing data (the chunk count and dataset name written
+/// by newer versions of llama.cpp) is ignored.
+#[derive(Debug, Clone, Default)]
+pub struct ImatrixData {
+    entries: HashMap<String, Vec<f32>>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.pos + n > self.data.len() {
+            candle_core::bail!("Unexpected end of imatrix file at byte {}.", self.pos);
+        }
+        let res = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(res)
+    }
+
+    fn read_i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_len(&mut self) -> Result<usize> {
+        let n = self.read_i32()?;
+        if n < 0 {
+            candle_core::bail!("Invalid negative length {n} in imatrix file.");
+        }
+        Ok(n as usize)
+    }
+}
+
+impl ImatrixData {
+    /// Load a llama.cpp `imatrix` file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = fs::read(path)?;
+        Self::from_bytes(&data)
+    }
+
+    /// Parse the contents of a llama.cpp `imatrix` file.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader { data, pos: 0 };
+        let n_entries = reader.read_len()?;
+        let mut entries = HashMap::with_capacity(n_entries);
+        for _ in 0..n_entries {
+            let name_len = reader.read_len()?;
+            let name = String::from_utf8(reader.take(name_len)?.to_vec())
+                .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+            let ncall = reader.read_i32()?;
+            let nval = reader.read_len()?;
+            let mut values = reader
+                .take(nval * 4)?
+                .chunks_exact(4)
+                .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            if ncall > 0 {
+                let ncall = ncall as f32;
+                values.iter_mut().for_each(|x| *x /= ncall);
+            }
+            entries.insert(name, values);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Per input channel importance for the tensor with the given (llama.cpp) name.
+    pub fn get(&self, name: &str) -> Option<&[f32]> {
+        self.entries.get(name).map(|x| x.as_slice())
+    }
+
+    /// Names of all tensors which have importance data.
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImatrixData;
+
+    fn entry(name: &str, ncall: i32, values: &[f32]) -> Vec<u8> {
+        let mut bytes = (name.len() as i32).to_le_bytes().to_vec();
+        bytes.extend(name.as_bytes());
+        bytes.extend(ncall.to_le_bytes());
+        bytes.extend((values.len() as i32).to_le_bytes());
+        bytes.extend(values.iter().flat_map(|x| x.to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn from_bytes_normalizes_by_calls() {
+        let mut bytes = 2i32.to_le_bytes().to_vec();
+        bytes.extend(entry("blk.0.attn_q.weight", 4, &[4., 8., 2.]));
+        bytes.extend(entry("output.weight", 0, &[1., 3.]));
+        // The chunk count and dataset name of newer llama.cpp versions.
+        bytes.extend(10i32.to_le_bytes());
+        bytes.extend(entry("wiki.train.raw", 0, &[]));
+
+        let imatrix = ImatrixData::from_bytes(&bytes).unwrap();
+        assert_eq!(imatrix.len(), 2);
+        assert_eq!(imatrix.get("blk.0.attn_q.weight"), Some(&[1., 2., 0.5][..]));
+        // Entries without calls are kept as is.
+        assert_eq!(imatrix.get("output.weight"), Some(&[1., 3.][..]));
+        assert_eq!(imatrix.get("blk.0.attn_k.weight"), None);
+    }
+
+    #[test]
+    fn from_bytes_rejects_invalid_files() {
+        let mut bytes = 1i32.to_le_bytes().to_vec();
+        bytes.extend(entry("blk.0.attn_q.weight", 1, &[1., 2.]));
+        // Truncated values.
+        assert!(ImatrixData::from_bytes(&bytes[..bytes.len() - 2]).is_err());
+        // Negative entry count.
+        assert!(ImatrixData::from_bytes(&(-1i32).to_le_bytes()).is_err());
+        assert!(ImatrixData::from_bytes(&[]).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{atomic::AtomicUsize, Arc},
+};
+
+use candle_core::{DType, Device, Result, Tensor, D};
+use candle_nn::Linear;
+
+use crate::{IsqType, QuantMethod, QuantMethodConfig, UnquantLinear};
+
+/// Offset of the stored weights: candle has no `i8` dtype, so `q` in `-127..=127` is stored as the
+/// `u8` value `q + 128`.
+const INT8_OFFSET: f64 = 128.;
+
+/// Weight-only int8 quantization with one symmetric scale per output channel, in the style of EETQ.
+///
+/// Each row `w` of the weight is stored as `round(w / s)` with `s = max(|w|) / 127`. The weights
+/// are dequantized to the dtype of the scales, which is the dtype of the original weight, for each
+/// matmul.
+#[derive(Debug)]
+pub struct Int8Layer {
+    w_q: Tensor,
+    scales: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl Int8Layer {
+    /// Quantize the 2D `weight` onto `device`.
+    pub fn quantize(weight: &Tensor, device: &Device) -> Result<Self> {
+        let dtype = weight.dtype();
+        let w = weight.to_device(device)?.to_dtype(DType::F32)?;
+        // All zero rows get a tiny scale rather than dividing by zero.
+        let scales = (w.abs()?.max_keepdim(D::Minus1)? / 127.)?.clamp(1e-12f32, f32::MAX)?;
+        let w_q = (w.broadcast_div(&scales)?.round()?.clamp(-127f32, 127f32)? + INT8_OFFSET)?
+            .to_dtype(DType::U8)?;
+        Ok(Self {
+            w_q,
+            scales: scales.to_dtype(dtype)?,
+            bias: None,
+        })
+    }
+
+    pub fn dequantize(&self) -> Result<Tensor> {
+        (self.w_q.to_dtype(self.scales.dtype())? - INT8_OFFSET)?.broadcast_mul(&self.scales)
+    }
+
+    fn dequantize_matmul(&self, xs: &Tensor) -> Result<Tensor> {
+        let w = self.dequantize()?;
+        let w = match *xs.dims() {
+            [b1, b2, _, _] => w.broadcast_left((b1, b2))?.t()?,
+            [bsize, _, _] => w.broadcast_left(bsize)?.t()?,
+            _ => w.t()?,
+        };
+        let res = xs.matmul(&w)?;
+        if let Some(ref bias) = self.bias {
+            res.broadcast_add(bias)
+        } else {
+            Ok(res)
+        }
+    }
+
+    pub fn with_bias(mut self, bias: Tensor) -> Self {
+        self.bias = Some(bias);
+        self
+    }
+}
+
+impl QuantMethod for Int8Layer {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Gptq { .. }
+            | QuantMethodConfig::Hqq { .. } => {
+                unreachable!()
+            }
+            QuantMethodConfig::Int8 { weight, bias } => {
+                let this = Self::quantize(&weight, weight.device())?;
+                if let Some(bias) = bias {
+                    Ok(this.with_bias(bias))
+                } else {
+                    Ok(this)
+                }
+            }
+        }
+    }
+
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        self.dequantize_matmul(a)
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        Some(self.scales.dtype())
+    }
+
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        let w = (self.dequantize()? + delta)?;
+        let res = Self::quantize(&w, w.device())?;
+        if let Some(ref bias) = self.bias {
+            Ok(Arc::new(res.with_bias(bias.clone())))
+        } else {
+            Ok(Arc::new(res))
+        }
+    }
+
+    fn dtype_and_device(&self) -> (DType, Device) {
+        (self.scales.dtype(), self.scales.device().clone())
+    }
+
+    fn get_bias_mut(&mut self) -> Option<&mut Tensor> {
+        self.bias.as_mut()
+    }
+
+    fn apply_isq(
+        self: Arc<Self>,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        match dtype {
+            Some(_) => {
+                // Requantize from the dequantized weights, like an unquantized layer.
+                let linear = Linear::new(self.dequantize()?, self.bias.clone());
+                let unquant = UnquantLinear::new(QuantMethodConfig::Unquantized(linear))?;
+                Arc::new(unquant).apply_isq(dtype, device, n_quantized, imatrix_weight)
+            }
+            None => {
+                let bias = if let Some(ref bias) = self.bias {
+                    Some(bias.to_device(&device)?)
+                } else {
+                    None
+                };
+                Ok(Arc::new(Self {
+                    w_q: self.w_q.to_device(&device)?,
+                    scales: self.scales.to_device(&device)?,
+                    bias,
+                }))
+            }
+        }
+    }
+
+    fn get_max_isq_cpu_threads(&self, _dtype: IsqType) -> Option<NonZeroUsize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor, D};
+
+    use super::Int8Layer;
+    use crate::QuantMethod;
+
+    #[test]
+    fn round_trip_error_is_bounded_by_half_a_step() {
+        let device = Device::Cpu;
+        // Rows of growing magnitude, the first of which is all zeros.
+        let w = Tensor::randn(0f32, 1., (64, 128), &device)
+            .unwrap()
+            .broadcast_mul(
+                &Tensor::arange(0f32, 64., &device)
+                    .unwrap()
+                    .unsqueeze(1)
+                    .unwrap(),
+            )
+            .unwrap();
+        let layer = Int8Layer::quantize(&w, &device).unwrap();
+        let dequant = layer.dequantize().unwrap();
+
+        // Each row is rounded to a multiple of its own scale, max(|w|) / 127.
+        let step = (w.abs().unwrap().max_keepdim(D::Minus1).unwrap() / 127.).unwrap();
+        let err = (w - &dequant).unwrap().abs().unwrap();
+        let excess = err
+            .broadcast_sub(&(step / 2.).unwrap())
+            .unwrap()
+            .max_keepdim(D::Minus1)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert!(excess.iter().all(|e| *e <= 1e-4), "{excess:?}");
+        // The zero row stays zero.
+        let zero_row = dequant.get(0).unwrap().abs().unwrap().sum_all().unwrap();
+        assert_eq!(zero_row.to_scalar::<f32>().unwrap(), 0.);
+    }
+
+    #[test]
+    fn forward_matches_dequantized_matmul() {
+        let device = Device::Cpu;
+        let w = Tensor::randn(0f32, 1., (16, 32), &device).unwrap();
+        let bias = Tensor::randn(0f32, 1., 16, &device).unwrap();
+        let xs = Tensor::randn(0f32, 1., (2, 3, 32), &device).unwrap();
+        let layer = Int8Layer::quantize(&w, &device)
+            .unwrap()
+            .with_bias(bias.clone());
+
+        let out = layer.forward(&xs).unwrap();
+        let expected = xs
+            .broadcast_matmul(&layer.dequantize().unwrap().t().unwrap())
+            .unwrap()
+            .broadcast_add(&bias)
+            .unwrap();
+        let diff = (out - expected)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-4, "{diff}");
+    }
+}
@@ -12,12 +12,16 @@ use candle_core::{
 mod gguf;
 mod gptq;
 mod hqq;
+mod imatrix;
+mod int8;
 mod unquantized;
 mod utils;
 
 pub use gguf::GgufMatMul;
 pub use gptq::GptqLayer;
 pub use hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
+pub use imatrix::ImatrixData;
+pub use int8::Int8Layer;
 pub use unquantized::UnquantLinear;
 
 use candle_nn::{Linear, VarBuilder};
@@ -71,6 +75,10 @@ pub enum QuantMethodConfig {
         channel_wise: Option<bool>,
         bias: Option<Tensor>,
     },
+    Int8 {
+        weight: Tensor,
+        bias: Option<Tensor>,
+    },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Hash, Eq)]
@@ -89,6 +97,8 @@ pub enum IsqType {
     Q8K,
     HQQ8,
     HQQ4,
+    /// Weight-only int8 with one symmetric scale per output channel.
+    Q8Ch,
     // HQQ3,
     // HQQ2,
     // HQQ1,
@@ -128,7 +138,7 @@ impl TryFrom<IsqType> for GgmlDType {
                     | GgmlDType::Q5K
                     | GgmlDType::Q6K
             ) {
-                candle_core::bail!("GGML ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`")
+                candle_core::bail!("GGML ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`, `Q8CH`")
             }
         }
         Ok(tp)
@@ -160,11 +170,15 @@ pub trait QuantMethod: Send + Sync + Debug {
     fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>>;
 
     /// If the quant is backed by a qmatmul.
+    ///
+    /// `imatrix_weight` is the optional per input channel importance (see [`ImatrixData`]) used
+    /// to weight the quantization error. Quantization methods which cannot use it ignore it.
     fn apply_isq(
         self: Arc<Self>,
         dtype: Option<IsqType>,
         device: Device,
         n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
     ) -> Result<Arc<dyn QuantMethod>>;
 
     /// If the quant is backed by a qmatmul.
@@ -9,7 +9,7 @@ use candle_nn::{Linear, Module};
 use crate::{
     generate_isq,
     hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer, ISQ_HQQ_DEFAULT_OPT_STEPS, ISQ_HQQ_GROUP_SIZE},
-    GgufMatMul, IsqType, QuantMethod, QuantMethodConfig,
+    GgufMatMul, Int8Layer, IsqType, QuantMethod, QuantMethodConfig,
 };
 
 #[derive(Debug)]
@@ -23,7 +23,8 @@ impl QuantMethod for UnquantLinear {
         match method {
             QuantMethodConfig::Gguf { .. }
             | QuantMethodConfig::Gptq { .. }
-            | QuantMethodConfig::Hqq { .. } => unreachable!(),
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Int8 { .. } => unreachable!(),
             QuantMethodConfig::Unquantized(l) => Ok(Self(l)),
         }
     }
@@ -56,6 +57,7 @@ impl QuantMethod for UnquantLinear {
         dtype: Option<IsqType>,
         device: Device,
         n_quantized: &AtomicUsize,
+        imatrix_weight: Option<Vec<f32>>,
     ) -> Result<Arc<dyn QuantMethod>> {
         match dtype {
             /*Some(IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | */
@@ -87,6 +89,18 @@ impl QuantMethod for UnquantLinear {
                     Ok(Arc::new(res))
                 }
             }
+            Some(IsqType::Q8Ch) => {
+                n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let res = Int8Layer::quantize(self.0.weight(), &device)?;
+                if let Some(bias) = self.0.bias() {
+                    let bias = bias
+                        .to_device(&device)?
+                        .to_dtype(res.dtype_and_device().0)?;
+                    Ok(Arc::new(res.with_bias(bias)))
+                } else {
+                    Ok(Arc::new(res))
+                }
+            }
             Some(
                 IsqType::Q2K
                 | IsqType::Q3K
@@ -102,7 +116,8 @@ impl QuantMethod for UnquantLinear {
                 | IsqType::Q8_1,
             ) => {
                 let dtype: GgmlDType = dtype.unwrap().try_into()?;
-                let res = generate_isq!(self.0.weight(), device, dtype, n_quantized);
+                let res =
+                    generate_isq!(self.0.weight(), device, dtype, n_quantized, imatrix_weight);
                 Ok(Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
                     q_weight: res,
                     b: self
@@ -144,7 +159,8 @@ impl QuantMethod for UnquantLinear {
             | IsqType::Q6K
             | IsqType::Q8K
             | IsqType::Q8_0
-            | IsqType::Q8_1 => None,
+            | IsqType::Q8_1
+            | IsqType::Q8Ch => None,
         }
     }
 }
@@ -1,4 +1,7 @@
-use candle_core::{quantized::GgmlDType, Tensor};
+use candle_core::{
+    quantized::{GgmlDType, QTensor},
+    DType, Device, Result, Tensor, D,
+};
 
 pub enum QuantizationBehaviour {
     Quantize(GgmlDType),
@@ -47,10 +50,81 @@ pub(crate) fn get_quantization_behaviour(
     }
 }
 
+/// Clipping factors (relative to the block's max abs value) tried by the imatrix-weighted search.
+const IMATRIX_CLIP_CANDIDATES: [f64; 6] = [1.0, 0.95, 0.9, 0.85, 0.8, 0.75];
+
+/// Quantize `tensor` into `dtype`, weighting the quantization error per input channel by `imatrix`.
+///
+/// For every quantization block, several clipping ranges are tried and the one which minimizes
+/// `sum_j imatrix[j] * (w_j - dequant(q(w))_j)^2` is kept. Because the GGML quantizers derive the
+/// block scales from the block's values, clipping trades off error on rare outliers for precision
+/// on the channels which matter most for the activations. A clipping factor of 1 (no clipping) is
+/// always a candidate, so the weighted error is never worse than plain quantization.
+///
+/// If the importance vector length does not match the last dimension of `tensor`, this falls back
+/// to plain quantization.
+pub(crate) fn quantize_onto_with_imatrix(
+    tensor: &Tensor,
+    dtype: GgmlDType,
+    device: &Device,
+    imatrix: &[f32],
+) -> Result<QTensor> {
+    let dims = tensor.dims();
+    let cols = dims[dims.len() - 1];
+    if imatrix.len() != cols {
+        tracing::warn!(
+            "Imatrix has {} values but tensor with shape {:?} has {cols} input channels, quantizing without it.",
+            imatrix.len(),
+            tensor.shape()
+        );
+        return QTensor::quantize_onto(tensor, dtype, device);
+    }
+    let block_size = dtype.block_size();
+    let rows = tensor.elem_count() / cols;
+
+    let xs = tensor.to_device(device)?.to_dtype(DType::F32)?.reshape((
+        rows,
+        cols / block_size,
+        block_size,
+    ))?;
+    let weights = Tensor::from_slice(imatrix, (1, cols / block_size, block_size), device)?;
+    let amax = xs.abs()?.max_keepdim(D::Minus1)?;
+
+    let clip = |limit: &Tensor| -> Result<Tensor> {
+        xs.broadcast_minimum(limit)?
+            .broadcast_maximum(&limit.neg()?)?
+            .reshape(tensor.shape())
+    };
+
+    let mut best: Option<(Tensor, Tensor)> = None;
+    for factor in IMATRIX_CLIP_CANDIDATES {
+        let limit = (&amax * factor)?;
+        let dequant = QTensor::quantize_onto(&clip(&limit)?, dtype, device)?
+            .dequantize(device)?
+            .reshape(xs.shape())?;
+        let err = (&xs - dequant)?
+            .sqr()?
+            .broadcast_mul(&weights)?
+            .sum_keepdim(D::Minus1)?;
+        best = Some(match best {
+            None => (err, limit),
+            Some((best_err, best_limit)) => {
+                let better = err.lt(&best_err)?;
+                (
+                    better.where_cond(&err, &best_err)?,
+                    better.where_cond(&limit, &best_limit)?,
+                )
+            }
+        });
+    }
+    let (_, limit) = best.expect("No clip candidates.");
+    QTensor::quantize_onto(&clip(&limit)?, dtype, device)
+}
+
 #[macro_export]
 #[doc(hidden)]
 macro_rules! generate_isq {
-    ($tensor:expr, $device:expr, $dtype:expr, $n_quantized:expr) => {
+    ($tensor:expr, $device:expr, $dtype:expr, $n_quantized:expr, $imatrix_weight:expr) => {
         {
             let quantization_behaviour = $crate::utils::isq::get_quantization_behaviour(&$tensor, $dtype);
             match quantization_behaviour{
@@ -61,9 +135,72 @@ macro_rules! generate_isq {
                 },
                 $crate::utils::isq::QuantizationBehaviour::Quantize(dtype) => {
                     $n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    Arc::new(candle_core::quantized::QTensor::quantize_onto(&$tensor, dtype, &$device)?)
+                    match $imatrix_weight {
+                        Some(ref imatrix) => Arc::new($crate::utils::isq::quantize_onto_with_imatrix(&$tensor, dtype, &$device, imatrix)?),
+                        None => Arc::new(candle_core::quantized::QTensor::quantize_onto(&$tensor, dtype, &$device)?),
+                    }
                 }
             }
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{
+        quantized::{GgmlDType, QTensor},
+        DType, Device, Tensor,
+    };
+
+    use super::quantize_onto_with_imatrix;
+
+    fn weighted_error(w: &Tensor, q: &QTensor, imatrix: &[f32]) -> f32 {
+        let device = Device::Cpu;
+        let imatrix = Tensor::from_slice(imatrix, (1, imatrix.len()), &device).unwrap();
+        (w - q.dequantize(&device).unwrap())
+            .unwrap()
+            .sqr()
+            .unwrap()
+            .broadcast_mul(&imatrix)
+            .unwrap()
+            .sum_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn imatrix_reduces_weighted_error() {
+        let device = Device::Cpu;
+        let (rows, cols) = (8, 64);
+        // Fixed weights in [-1, 1], where the first channel of every block is an outlier which
+        // the activations barely use.
+        let mut values = ((Tensor::arange(0i64, (rows * cols) as i64, &device)
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            * 0.7)
+            .unwrap()
+            .sin()
+            .unwrap()
+            .reshape((rows, cols))
+            .unwrap())
+        .to_vec2::<f32>()
+        .unwrap();
+        for row in &mut values {
+            for block in row.chunks_mut(32) {
+                block[0] = 20.;
+            }
+        }
+        let w = Tensor::new(values, &device).unwrap();
+        let imatrix = (0..cols)
+            .map(|j| if j % 32 == 0 { 1e-3 } else { 1. })
+            .collect::<Vec<f32>>();
+
+        let plain = QTensor::quantize_onto(&w, GgmlDType::Q4_0, &device).unwrap();
+        let weighted = quantize_onto_with_imatrix(&w, GgmlDType::Q4_0, &device, &imatrix).unwrap();
+        let plain_err = weighted_error(&w, &plain, &imatrix);
+        let weighted_err = weighted_error(&w, &weighted, &imatrix);
+        assert!(weighted_err < plain_err, "{weighted_err} >= {plain_err}");
+    }
+}
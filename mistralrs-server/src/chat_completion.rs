@@ -4,12 +4,13 @@ use std::{
     env,
     error::Error,
     fs::{self, File},
+    future::Future,
     io::Read,
     ops::Deref,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
@@ -26,8 +27,9 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, MistralRs, NormalRequest, Request, RequestMessage,
-    Response, SamplingParams, StopTokens as InternalStopTokens,
+    load_image, ChatCompletionChunkResponse, ChatCompletionResponse, Constraint, GreedyTieBreak,
+    ImageTooLarge, MistralRs, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 
@@ -44,12 +46,61 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    /// If set, chunks received within this interval are coalesced into one event.
+    stream_interval: Option<Duration>,
+    buffered: Option<ChatCompletionChunkResponse>,
+    last_flush: Instant,
+    flush_timer: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Streamer {
+    fn new(
+        rx: Receiver<Response>,
+        state: Arc<MistralRs>,
+        stream_interval: Option<Duration>,
+    ) -> Self {
+        Self {
+            rx,
+            is_done: false,
+            state,
+            stream_interval,
+            buffered: None,
+            last_flush: Instant::now(),
+            flush_timer: None,
+        }
+    }
+
+    /// Coalesce `chunk` into the buffered chunk.
+    fn buffer_chunk(&mut self, chunk: ChatCompletionChunkResponse) {
+        match &mut self.buffered {
+            Some(buffered) => buffered.append(chunk),
+            None => self.buffered = Some(chunk),
+        }
+    }
+
+    fn flush(&mut self) -> Option<Result<Event, axum::Error>> {
+        self.flush_timer = None;
+        self.last_flush = Instant::now();
+        self.buffered
+            .take()
+            .map(|chunk| Event::default().json_data(chunk))
+    }
 }
 
 impl futures::Stream for Streamer {
     type Item = Result<Event, axum::Error>;
 
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.buffered.is_some() {
+            let is_done = self.is_done;
+            let due = match self.flush_timer.as_mut() {
+                Some(timer) => is_done || timer.as_mut().poll(cx).is_ready(),
+                None => true,
+            };
+            if due {
+                return Poll::Ready(self.flush());
+            }
+        }
         if self.is_done {
             return Poll::Ready(None);
         }
@@ -74,12 +125,31 @@ impl futures::Stream for Streamer {
                         self.is_done = true;
                     }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
-                    Poll::Ready(Some(Event::default().json_data(response)))
+                    let Some(interval) = self.stream_interval else {
+                        return Poll::Ready(Some(Event::default().json_data(response)));
+                    };
+                    self.buffer_chunk(response);
+                    if self.is_done || self.last_flush.elapsed() >= interval {
+                        return Poll::Ready(self.flush());
+                    }
+                    if self.flush_timer.is_none() {
+                        let deadline = self.last_flush + interval;
+                        let mut timer = Box::pin(tokio::time::sleep_until(deadline.into()));
+                        // Polling the timer registers the waker, so the buffered content is
+                        // flushed even if no further chunks arrive.
+                        if timer.as_mut().poll(cx).is_ready() {
+                            return Poll::Ready(self.flush());
+                        }
+                        self.flush_timer = Some(timer);
+                    }
+                    Poll::Pending
                 }
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
+                Response::Tokenize(_) => unreachable!(),
+                Response::Detokenize(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -276,7 +346,7 @@ async fn parse_request(
                         // Decode with base64
                         general_purpose::STANDARD.decode(url)?
                     };
-                    images.push(image::load_from_memory(&bytes)?);
+                    images.push(load_image(&bytes)?);
                 }
                 RequestMessage::VisionChat { messages, images }
             } else {
@@ -304,13 +374,30 @@ async fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
+                typical_p: oairequest.typical_p,
+                mirostat_tau: None,
+                mirostat_eta: 0.1,
+                dry_multiplier: None,
+                dry_base: 1.75,
+                dry_allowed_length: 2,
+                dry_sequence_breakers: Vec::new(),
+                temperature_order: oairequest.temperature_order.into(),
+                greedy_tie_break_by: GreedyTieBreak::default(),
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                repetition_penalty: None,
+                repetition_penalty_range: 0,
                 max_len: oairequest.max_tokens,
+                min_len: oairequest.min_tokens,
                 stop_toks,
+                stop_token_ids: oairequest.stop_token_ids,
+                stop_token_strings: oairequest.stop_token_strings,
+                stop_token_seqs: None,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
+                seed: oairequest.seed,
+                suppress_special_tokens: oairequest.suppress_special_tokens,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
@@ -319,12 +406,19 @@ async fn parse_request(
             constraint: match oairequest.grammar {
                 Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
                 Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
+                Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema.to_string()),
                 None => Constraint::None,
             },
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
+            early_exit_layer: None,
+            xlora_global_scaling: oairequest.xlora_global_scaling,
+            include_usage: false,
+            token_healing: oairequest.token_healing,
+            truncate_prompt: oairequest.truncate_prompt,
+            add_generation_prompt: oairequest.add_generation_prompt,
         }),
         is_streaming,
     ))
@@ -342,12 +436,22 @@ pub async fn chatcompletions(
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
     let (tx, mut rx) = channel(10_000);
+    // Coalescing would merge the per-token logprobs of the chunks, so it is not done with logprobs.
+    let stream_interval = oairequest
+        .stream_interval_ms
+        .filter(|_| !oairequest.logprobs)
+        .map(Duration::from_millis);
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
         Ok(x) => x,
         Err(e) => {
+            let is_validation_error = e.is::<ImageTooLarge>();
             let e = anyhow::Error::msg(e.to_string());
             MistralRs::maybe_log_error(state, &*e);
-            return ChatCompletionResponder::InternalError(e.into());
+            return if is_validation_error {
+                ChatCompletionResponder::ValidationError(e.into())
+            } else {
+                ChatCompletionResponder::InternalError(e.into())
+            };
         }
     };
     let sender = state.get_sender().unwrap();
@@ -359,11 +463,7 @@ pub async fn chatcompletions(
     }
 
     if is_streaming {
-        let streamer = Streamer {
-            rx,
-            is_done: false,
-            state,
-        };
+        let streamer = Streamer::new(rx, state, stream_interval);
 
         ChatCompletionResponder::Sse(
             Sse::new(streamer).keep_alive(
@@ -405,6 +505,8 @@ pub async fn chatcompletions(
             Response::CompletionDone(_) => unreachable!(),
             Response::CompletionModelError(_, _) => unreachable!(),
             Response::CompletionChunk(_) => unreachable!(),
+            Response::Tokenize(_) => unreachable!(),
+            Response::Detokenize(_) => unreachable!(),
         }
     }
 }
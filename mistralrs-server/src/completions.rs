@@ -18,11 +18,10 @@ use axum::{
     },
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, MistralRs, NormalRequest, Request, RequestMessage, Response,
-    SamplingParams, StopTokens as InternalStopTokens,
+    CompletionResponse, Constraint, GreedyTieBreak, MistralRs, NormalRequest, Request,
+    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
-use tracing::warn;
 
 #[derive(Debug)]
 struct ModelErrorMessage(String);
@@ -73,6 +72,8 @@ impl futures::Stream for Streamer {
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::Chunk(_) => unreachable!(),
+                Response::Tokenize(_) => unreachable!(),
+                Response::Detokenize(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -155,10 +156,6 @@ fn parse_request(
         None => None,
     };
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
-
     let is_streaming = oairequest.stream.unwrap_or(false);
     (
         Request::Normal(NormalRequest {
@@ -166,34 +163,58 @@ fn parse_request(
             messages: RequestMessage::Completion {
                 text: oairequest.prompt,
                 echo_prompt: oairequest.echo_prompt,
-                best_of: oairequest.best_of,
+                best_of: oairequest.best_of.unwrap_or(oairequest.n_choices),
             },
             sampling_params: SamplingParams {
                 temperature: oairequest.temperature,
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
-                top_n_logprobs: 1,
+                typical_p: oairequest.typical_p,
+                mirostat_tau: None,
+                mirostat_eta: 0.1,
+                dry_multiplier: None,
+                dry_base: 1.75,
+                dry_allowed_length: 2,
+                dry_sequence_breakers: Vec::new(),
+                temperature_order: oairequest.temperature_order.into(),
+                greedy_tie_break_by: GreedyTieBreak::default(),
+                top_n_logprobs: oairequest.logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                repetition_penalty: None,
+                repetition_penalty_range: 0,
                 max_len: oairequest.max_tokens,
+                min_len: oairequest.min_tokens,
                 stop_toks,
+                stop_token_ids: oairequest.stop_token_ids,
+                stop_token_strings: oairequest.stop_token_strings,
+                stop_token_seqs: None,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
+                seed: oairequest.seed,
+                suppress_special_tokens: oairequest.suppress_special_tokens,
             },
             response: tx,
-            return_logprobs: false,
+            return_logprobs: oairequest.logprobs.is_some(),
             is_streaming,
             suffix: oairequest.suffix,
             constraint: match oairequest.grammar {
                 Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
                 Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
+                Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema.to_string()),
                 None => Constraint::None,
             },
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
+            early_exit_layer: None,
+            xlora_global_scaling: oairequest.xlora_global_scaling,
+            include_usage: false,
+            token_healing: oairequest.token_healing,
+            truncate_prompt: oairequest.truncate_prompt,
+            add_generation_prompt: true,
         }),
         is_streaming,
     )
@@ -211,12 +232,6 @@ pub async fn completions(
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
     let (tx, mut rx) = channel(10_000);
-    if oairequest.logprobs.is_some() {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support logprobs.".into(),
-        );
-    }
-
     let (request, is_streaming) = parse_request(oairequest, state.clone(), tx);
     let sender = state.get_sender().unwrap();
 
@@ -273,6 +288,8 @@ pub async fn completions(
             Response::Chunk(_) => unreachable!(),
             Response::Done(_) => unreachable!(),
             Response::ModelError(_, _) => unreachable!(),
+            Response::Tokenize(_) => unreachable!(),
+            Response::Detokenize(_) => unreachable!(),
         }
     }
 }
@@ -2,8 +2,8 @@ use base64::{engine::general_purpose, Engine};
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    Constraint, MessageContent, MistralRs, NormalRequest, Request, RequestMessage, Response,
-    SamplingParams, TERMINATE_ALL_NEXT_STEP,
+    load_image, Constraint, GreedyTieBreak, MessageContent, MistralRs, NormalRequest, Request,
+    RequestMessage, Response, SamplingParams, TemperatureOrder, TERMINATE_ALL_NEXT_STEP,
 };
 use once_cell::sync::Lazy;
 use std::{
@@ -36,13 +36,30 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>, vision_chat: bool, thro
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        typical_p: None,
+        mirostat_tau: None,
+        mirostat_eta: 0.1,
+        dry_multiplier: None,
+        dry_base: 1.75,
+        dry_allowed_length: 2,
+        dry_sequence_breakers: Vec::new(),
+        temperature_order: TemperatureOrder::default(),
+        greedy_tie_break_by: GreedyTieBreak::default(),
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_penalty_range: 0,
         max_len: Some(4096),
+        min_len: None,
         stop_toks: None,
+        stop_token_ids: None,
+        stop_token_strings: None,
+        stop_token_seqs: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
+        suppress_special_tokens: false,
     };
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
 
@@ -114,7 +131,7 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>, vision_chat: bool, thro
                     // Decode with base64
                     general_purpose::STANDARD.decode(url).unwrap()
                 };
-                images.push(image::load_from_memory(&bytes).unwrap());
+                images.push(load_image(&bytes).unwrap());
             }
 
             // Set the handler to terminate all seqs, so allowing cancelling running
@@ -145,6 +162,12 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>, vision_chat: bool, thro
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            early_exit_layer: None,
+            xlora_global_scaling: None,
+            include_usage: false,
+            token_healing: false,
+            truncate_prompt: false,
+            add_generation_prompt: true,
         });
         sender.send(req).await.unwrap();
 
@@ -183,6 +206,8 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>, vision_chat: bool, thro
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
+                Response::Tokenize(_) => unreachable!(),
+                Response::Detokenize(_) => unreachable!(),
             }
         }
         if throughput {
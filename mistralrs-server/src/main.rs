@@ -15,7 +15,7 @@ use mistralrs_core::{
 };
 use openai::{ChatCompletionRequest, Message, ModelObjects, StopTokens};
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroUsize, sync::Arc};
+use std::{num::NonZeroUsize, path::PathBuf, sync::Arc};
 mod chat_completion;
 mod completions;
 use crate::{chat_completion::__path_chatcompletions, completions::completions};
@@ -136,6 +136,12 @@ struct Args {
     #[arg(long = "throughput", default_value_t = false)]
     throughput_log: bool,
 
+    /// Maximum number of prompt tokens to prefill in one step with PagedAttention. Prompts are packed into
+    /// prompt steps within this budget, a longer prompt is prefilled alone, and running sequences get a
+    /// completion step between prompt steps.
+    #[arg(long = "max-num-batched-tokens")]
+    max_num_batched_tokens: Option<usize>,
+
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_batchsize: Option<usize>,
@@ -197,6 +203,9 @@ async fn activate_adapters(
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
     ggml_type: String,
+    /// Path to a llama.cpp imatrix file used to weight the quantization error.
+    #[serde(default)]
+    imatrix: Option<String>,
 }
 
 #[utoipa::path(
@@ -210,9 +219,15 @@ async fn re_isq(
     State(state): State<Arc<MistralRs>>,
     Json(request): Json<ReIsqRequest>,
 ) -> Result<String, String> {
-    let repr = format!("Re ISQ: {:?}", request.ggml_type);
+    let repr = format!(
+        "Re ISQ: {:?}, imatrix: {:?}",
+        request.ggml_type, request.imatrix
+    );
     MistralRs::maybe_log_request(state.clone(), repr.clone());
-    let request = Request::ReIsq(parse_isq_value(&request.ggml_type)?);
+    let request = Request::ReIsq(
+        parse_isq_value(&request.ggml_type)?,
+        request.imatrix.map(PathBuf::from),
+    );
     state.get_sender().unwrap().send(request).await.unwrap();
     Ok(repr)
 }
@@ -274,6 +289,13 @@ async fn main() -> Result<()> {
         args.max_seqs = 1;
     }
 
+    let max_num_batched_tokens = match args.max_num_batched_tokens {
+        Some(0) => {
+            anyhow::bail!("`max_num_batched_tokens` must be a strictly positive integer, got 0.",)
+        }
+        x => x,
+    };
+
     let prompt_batchsize = match args.prompt_batchsize {
         Some(0) => {
             anyhow::bail!("`prompt_batchsize` must be a strictly positive integer, got 0.",)
@@ -421,6 +443,7 @@ async fn main() -> Result<()> {
         if let Some(ref cache_config) = pipeline.lock().await.get_metadata().cache_config {
             SchedulerConfig::PagedAttentionMeta {
                 max_num_seqs: args.max_seqs,
+                max_num_batched_tokens,
                 config: cache_config.clone(),
             }
         } else {
@@ -47,6 +47,10 @@ fn default_false() -> bool {
     false
 }
 
+fn default_true() -> bool {
+    true
+}
+
 fn default_1usize() -> usize {
     1
 }
@@ -62,6 +66,25 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    #[serde(rename = "json_schema")]
+    JsonSchema(serde_json::Value),
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureOrder {
+    #[default]
+    BeforeTruncation,
+    AfterTruncation,
+}
+
+impl From<TemperatureOrder> for mistralrs_core::TemperatureOrder {
+    fn from(order: TemperatureOrder) -> Self {
+        match order {
+            TemperatureOrder::BeforeTruncation => Self::BeforeTruncation,
+            TemperatureOrder::AfterTruncation => Self::AfterTruncation,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -102,9 +125,13 @@ pub struct ChatCompletionRequest {
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
+    pub min_tokens: Option<usize>,
+    #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
@@ -112,6 +139,31 @@ pub struct ChatCompletionRequest {
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub typical_p: Option<f32>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub stream_interval_ms: Option<u64>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub token_healing: bool,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub truncate_prompt: bool,
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub stop_token_ids: Option<Vec<u32>>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub stop_token_strings: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub xlora_global_scaling: Option<f64>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub suppress_special_tokens: bool,
+    #[serde(default)]
+    #[schema(example = json!(TemperatureOrder::BeforeTruncation))]
+    pub temperature_order: TemperatureOrder,
+    #[serde(default = "default_true")]
+    #[schema(example = true)]
+    pub add_generation_prompt: bool,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -135,9 +187,8 @@ pub struct CompletionRequest {
     pub model: String,
     #[schema(example = "Say this is a test.")]
     pub prompt: String,
-    #[serde(default = "default_1usize")]
-    #[schema(example = 1)]
-    pub best_of: usize,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub best_of: Option<usize>,
     #[serde(rename = "echo")]
     #[serde(default = "default_false")]
     #[schema(example = false)]
@@ -172,9 +223,13 @@ pub struct CompletionRequest {
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
+    pub min_tokens: Option<usize>,
+    #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
@@ -182,4 +237,24 @@ pub struct CompletionRequest {
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub typical_p: Option<f32>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub token_healing: bool,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub truncate_prompt: bool,
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub stop_token_ids: Option<Vec<u32>>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub stop_token_strings: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub xlora_global_scaling: Option<f64>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub suppress_special_tokens: bool,
+    #[serde(default)]
+    #[schema(example = json!(TemperatureOrder::BeforeTruncation))]
+    pub temperature_order: TemperatureOrder,
 }
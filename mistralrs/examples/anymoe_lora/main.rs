@@ -98,6 +98,12 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        early_exit_layer: None,
+        xlora_global_scaling: None,
+        include_usage: false,
+        token_healing: false,
+        truncate_prompt: false,
+        add_generation_prompt: true,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -49,6 +49,7 @@ async fn setup() -> anyhow::Result<Arc<MistralRs>> {
         pipeline,
         SchedulerConfig::PagedAttentionMeta {
             max_num_seqs: 500,
+            max_num_batched_tokens: None,
             config,
         },
     )
@@ -82,6 +83,12 @@ async fn bench_mistralrs(n_requests: usize) -> anyhow::Result<()> {
             tools: None,
             tool_choice: None,
             logits_processors: None,
+            early_exit_layer: None,
+            xlora_global_scaling: None,
+            include_usage: false,
+            token_healing: false,
+            truncate_prompt: false,
+            add_generation_prompt: true,
         });
         mistralrs.get_sender()?.send(request).await?;
         handles.push(rx);
@@ -96,6 +96,12 @@ fn main() -> anyhow::Result<()> {
             Arc::new(move |logits: &Tensor, _context: &[u32]| logits * random_value),
             Arc::new(ThresholdLogitsProcessor { threshold }),
         ]),
+        early_exit_layer: None,
+        xlora_global_scaling: None,
+        include_usage: false,
+        token_healing: false,
+        truncate_prompt: false,
+        add_generation_prompt: true,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 